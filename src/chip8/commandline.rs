@@ -6,8 +6,13 @@ use std::fs::File;
 
 use clap::{Arg, ArgMatches, Command};
 
+use super::emulator_window::EmulatorWindow;
+use super::{parse_display_size, Chip8Builder, Font, Quirks, Variant};
+use crate::logging;
+use crate::settings::Chip8Settings;
+use crate::window_scale;
+
 pub fn chip_8_subcommand<'a>() -> Command<'a> {
-    use super::Chip8;
     Command::new("chip8")
     .about("A Chip-8 emulator")
     .arg(
@@ -18,35 +23,84 @@ pub fn chip_8_subcommand<'a>() -> Command<'a> {
     )
     .arg(
         Arg::new("display")
-            .help("display dimensions")
+            .help("display dimensions as WIDTHxHEIGHT, each between 8 and 256 for any variant, e.g. 64x32 (CHIP-8), 128x64 (CHIP-10) or 64x128 (HI-RES CHIP-8) [default: 64x32 or from settings]")
             .takes_value(true)
             .long("display")
-            .default_value("64x32")
-            .possible_values(&Chip8::AVAILABLE_DISPLAY_SIZES)
+            .validator(parse_display_size)
     )
     .arg(
         Arg::new("font")
-            .help("font")
+            .help("font [default: chip48 or from settings]")
             .takes_value(true)
             .long("font")
-            .default_value("chip48")
-            .possible_values(&Chip8::AVAILABLE_FONTS)
+            .possible_values(&super::AVAILABLE_FONTS)
     )
     .arg(
         Arg::new("shift-x")
             .help("shift VX instead of VY in 8XY6 and 8XYE (this is what S-CHIP and many other emulators do")
             .long("shift-x")
     )
+    .arg(
+        Arg::new("two-keypads")
+            .help("connect a second keypad on the number pad, selected by bit 4 of VX in EX9E, EXA1 and FX0A")
+            .long("two-keypads")
+    )
+    .arg(
+        Arg::new("scale")
+            .help("show each display pixel as NxN pixels, between 1 and 16 [default: the largest scale at which the window fits a 1280x720 screen, or from settings]")
+            .takes_value(true)
+            .value_name("N")
+            .long("scale")
+            .validator(window_scale::parse_scale)
+    )
+    .arg(
+        Arg::new("no-throttle")
+            .help("run as fast as possible instead of at 60 frames per second")
+            .long("no-throttle")
+    )
 }
 
-pub fn run_chip_8_from_subcommand(subcommand: &ArgMatches) {
-    let display = subcommand.value_of("display").unwrap();
-    let font = subcommand.value_of("font").unwrap();
-    let shift_x = subcommand.is_present("shift-x");
-    let mut chip8 = super::Chip8::new(display, font, shift_x);
+/// Run the CHIP-8 emulator
+///
+/// Command line arguments take precedence over the settings.
+pub fn run_chip_8_from_subcommand(subcommand: &ArgMatches,
+                                  settings: &Chip8Settings) {
+    let display = subcommand.value_of("display").unwrap_or(&settings.display);
+    let font = subcommand.value_of("font").unwrap_or(&settings.font);
+    // Command line arguments have been validated by clap, so errors can
+    // only come from the settings.
+    let (width, height) = parse_display_size(display).unwrap_or_else(|e| {
+        log::warn!("{} Using the CHIP-8 display size.", e);
+        Variant::Chip8.display_size()
+    });
+    let font = font.parse().unwrap_or_else(|e| {
+        log::warn!("{}, using the default font.", e);
+        Font::default()
+    });
+    let quirks = Quirks{
+        shift_x: subcommand.is_present("shift-x") || settings.shift_x,
+        two_keypads: subcommand.is_present("two-keypads")
+                     || settings.two_keypads,
+    };
+    logging::add_crash_context(format!(
+            "CHIP-8: display {}x{}, font {:?}, shift VX {}",
+            width, height, font, quirks.shift_x));
+    let scale = window_scale::choose_scale(subcommand.value_of("scale"),
+                                           settings.scale, width, height);
+    let mut window = EmulatorWindow::new(width, height, scale).unwrap();
+    window.set_palette(settings.palette);
     let filename = subcommand.value_of("rom-file").unwrap();
     println!("loading {}", filename);
     let f = File::open(filename).unwrap();
-    chip8.load_rom(f).unwrap();
+    let mut chip8 = Chip8Builder::new()
+        .use_display_size(width, height).unwrap()
+        .use_font(font)
+        .use_quirks(quirks)
+        .load_rom(f).unwrap()
+        .use_emulator_window(window)
+        .build().unwrap();
+    if subcommand.is_present("no-throttle") {
+        chip8.disable_throttle();
+    }
     chip8.run();
 }
@@ -9,6 +9,7 @@ use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
 use super::display::Display;
+use super::io::IO;
 use super::memory::Memory;
 
 pub struct CPU {
@@ -19,28 +20,68 @@ pub struct CPU {
     sound_timer: u8,
     rng: ChaCha20Rng,
     shift_x: bool,
+    two_keypads: bool,
 }
 
+/// Seed of the random number generator used by CXNN by default
+pub const DEFAULT_SEED: u64 = 42;
+
 impl Default for CPU {
     fn default() -> Self {
+        Self::with_seed(DEFAULT_SEED)
+    }
+}
+
+impl CPU {
+    /// Create a CPU whose random number generator is seeded with `seed`
+    pub fn with_seed(seed: u64) -> Self {
         Self {
             pc: 0x200,
             registers: Registers::default(),
             stack: Vec::new(),
             delay_timer: 0,
             sound_timer: 0,
-            rng: ChaCha20Rng::seed_from_u64(42),
+            rng: ChaCha20Rng::seed_from_u64(seed),
             shift_x: false,
+            two_keypads: false,
         }
     }
-}
 
-impl CPU {
     pub fn activate_shift_quirk(&mut self) {
         self.shift_x = true;
     }
 
-    pub fn tick(&mut self, memory: &mut Memory, display: &mut Display) {
+    /// Use a second hex keypad as on two-player COSMAC VIP games
+    ///
+    /// Bit 4 of VX selects the keypad in EX9E and EXA1, the lower 4 bits
+    /// the key. FX0A waits for a key press on either keypad and sets
+    /// bit 4 of VX if the key was pressed on the second keypad.
+    pub fn activate_second_keypad(&mut self) {
+        self.two_keypads = true;
+    }
+
+    /// Split the value of a register into keypad and key
+    ///
+    /// Like the COSMAC VIP's keypad decoder, only the lower 4 bits select
+    /// the key, the bits above the keypad bit are ignored.
+    fn keypad_and_key(two_keypads: bool, value: u8) -> (u8, u8) {
+        if two_keypads {
+            ((value >> 4) & 1, value & 0x0F)
+        } else {
+            (0, value & 0x0F)
+        }
+    }
+
+    fn get_key_press(two_keypads: bool, io: &impl IO) -> Option<u8> {
+        let key = io.get_key_press(0);
+        if key.is_none() && two_keypads {
+            return io.get_key_press(1).map(|key| 0x10 | key);
+        }
+        key
+    }
+
+    pub fn tick(&mut self, memory: &mut Memory, display: &mut Display,
+                io: &impl IO) {
         let pc = &mut self.pc;
         let opcode: u16 = ((memory[*pc] as u16) << 8) + memory[*pc + 1] as u16;
         // eprintln!("{:0>3X}: {:0>4X}", pc, opcode);
@@ -209,14 +250,16 @@ impl CPU {
             0xE000 => {
                 let x = ((opcode & 0x0F00) >> 8) as u8;
                 let nn = opcode as u8;
+                let (keypad, key) = Self::keypad_and_key(self.two_keypads,
+                                                         self.registers[x]);
                 match nn {
                     0x9E => {
-                        if display.is_key_pressed(self.registers[x]) {
+                        if io.is_key_pressed(keypad, key) {
                             *pc += 2;
                         }
                     }
                     0xA1 => {
-                        if !display.is_key_pressed(self.registers[x]) {
+                        if !io.is_key_pressed(keypad, key) {
                             *pc += 2;
                         }
                     }
@@ -229,7 +272,7 @@ impl CPU {
                 let x = ((opcode & 0x0F00) >> 8) as u8;
                 match opcode & 0x00FF {
                     0x000A => {
-                        let key = display.get_key_press();
+                        let key = Self::get_key_press(self.two_keypads, io);
                         if let Some(key) = key {
                             self.registers[x] = key;
                         } else {
@@ -355,3 +398,100 @@ impl fmt::Display for Registers {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Keypads {
+        pressed: Option<(u8, u8)>,
+    }
+
+    impl IO for Keypads {
+        fn refresh(&mut self, _pixels: &[bool], _width: usize,
+                   _height: usize) {}
+
+        fn is_esc_pressed(&self) -> bool {
+            false
+        }
+
+        fn is_key_pressed(&self, keypad: u8, key: u8) -> bool {
+            assert!(keypad <= 1 && key <= 0xF,
+                    "key {:X} on keypad {} doesn't exist", key, keypad);
+            self.pressed == Some((keypad, key))
+        }
+    }
+
+    /// Run `opcode` with `vx` in V0 and return the CPU afterwards
+    fn run(opcode: u16, vx: u8, two_keypads: bool, io: &Keypads) -> CPU {
+        let mut cpu = CPU::default();
+        if two_keypads {
+            cpu.activate_second_keypad();
+        }
+        cpu.registers[0] = vx;
+        let mut memory = Memory::default();
+        memory.load_program(&opcode.to_be_bytes());
+        let mut display = Display::new(64, 32);
+        cpu.tick(&mut memory, &mut display, io);
+        cpu
+    }
+
+    fn skips(opcode: u16, vx: u8, two_keypads: bool,
+             pressed: Option<(u8, u8)>) -> bool {
+        let cpu = run(opcode, vx, two_keypads, &Keypads{pressed});
+        cpu.pc == 0x204
+    }
+
+    #[test]
+    fn ex9e_skips_if_key_is_pressed() {
+        assert!(skips(0xE09E, 0x05, false, Some((0, 0x5))));
+        assert!(!skips(0xE09E, 0x05, false, Some((0, 0x6))));
+        assert!(!skips(0xE09E, 0x05, false, None));
+        // Only the lower nibble selects the key on a single keypad.
+        assert!(skips(0xE09E, 0x25, false, Some((0, 0x5))));
+        assert!(skips(0xE09E, 0x15, false, Some((0, 0x5))));
+
+        assert!(skips(0xE09E, 0x05, true, Some((0, 0x5))));
+        assert!(!skips(0xE09E, 0x05, true, Some((1, 0x5))));
+        assert!(skips(0xE09E, 0x15, true, Some((1, 0x5))));
+        assert!(!skips(0xE09E, 0x15, true, Some((0, 0x5))));
+        // Bits above the keypad bit are ignored.
+        assert!(skips(0xE09E, 0x25, true, Some((0, 0x5))));
+        assert!(skips(0xE09E, 0xF5, true, Some((1, 0x5))));
+    }
+
+    #[test]
+    fn exa1_skips_if_key_is_not_pressed() {
+        assert!(!skips(0xE0A1, 0x0A, false, Some((0, 0xA))));
+        assert!(skips(0xE0A1, 0x0A, false, None));
+        assert!(!skips(0xE0A1, 0x3A, false, Some((0, 0xA))));
+
+        assert!(skips(0xE0A1, 0x0A, true, Some((1, 0xA))));
+        assert!(!skips(0xE0A1, 0x1A, true, Some((1, 0xA))));
+        assert!(skips(0xE0A1, 0x1A, true, Some((0, 0xA))));
+        assert!(!skips(0xE0A1, 0x2A, true, Some((0, 0xA))));
+        assert!(!skips(0xE0A1, 0x3A, true, Some((1, 0xA))));
+    }
+
+    #[test]
+    fn fx0a_waits_for_a_key_press() {
+        let cpu = run(0xF00A, 0x00, false, &Keypads{pressed: None});
+        assert_eq!(cpu.pc, 0x200);
+
+        let cpu = run(0xF00A, 0x00, false, &Keypads{pressed: Some((0, 0xC))});
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0], 0x0C);
+
+        // A single keypad doesn't look at the second one.
+        let cpu = run(0xF00A, 0x00, false, &Keypads{pressed: Some((1, 0xC))});
+        assert_eq!(cpu.pc, 0x200);
+
+        let cpu = run(0xF00A, 0x00, true, &Keypads{pressed: Some((0, 0xC))});
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0], 0x0C);
+
+        let cpu = run(0xF00A, 0x00, true, &Keypads{pressed: Some((1, 0xC))});
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0], 0x1C);
+    }
+}
@@ -4,74 +4,38 @@
 
 use std::cmp::min;
 use std::fmt;
-use minifb::{Key, Window, WindowOptions};
 
-/// A 64x32 pixel monochrome display
+/// A monochrome display, e.g. of 64x32 pixels
 ///
-/// The corners of the display have the following coordinates:
+/// The corners of a 64x32 display have the following coordinates:
 /// (0, 0) (63, 0)
 /// (0,31) (63,31)
 pub struct Display {
     pixels: Vec<bool>,
-    display_buffer: Vec<u32>,
-    window: Window,
     width: usize,
     height: usize,
 }
 
-const PIXEL_SIZE: usize = 4;
-const SET: u32 = 0xFFFFFF;
-const UNSET: u32 = 0;
-
 impl Display {
-    pub fn new(width: usize, height: usize, refresh_rate: usize) -> Self {
-        let mut window = Window::new(
-            "Chip-8 emulator",
-            width * PIXEL_SIZE,
-            height * PIXEL_SIZE,
-            WindowOptions::default(),
-        ).unwrap();
-        use std::time::Duration;
-        let wait_time = Duration::from_micros((1000000. / refresh_rate as f64)
-                                              as u64);
-        window.limit_update_rate(Some(wait_time));
+    pub fn new(width: usize, height: usize) -> Self {
         Self{
             pixels: vec![false; width * height],
-            display_buffer: vec![UNSET; width * height
-                                        * PIXEL_SIZE * PIXEL_SIZE],
-            window,
             width,
             height,
         }
     }
 
-    pub fn refresh(&mut self) {
-        let buffer_width = self.width * PIXEL_SIZE;
-        for line in 0..self.height {
-            let buffer_line_start = line * PIXEL_SIZE * buffer_width;
-            let buffer_line_range
-                = buffer_line_start..(buffer_line_start + buffer_width);
-            let buffer_line = &mut self.display_buffer[buffer_line_range
-                                                       .clone()];
-            for col in 0..self.width {
-                let color = if self.pixels[line * self.width + col] {
-                    SET
-                } else {
-                    UNSET
-                };
-                buffer_line[col*PIXEL_SIZE..(col+1)*PIXEL_SIZE].fill(color);
-            }
-            for i in 1..PIXEL_SIZE {
-                self.display_buffer.copy_within(
-                    buffer_line_range.clone(),
-                    buffer_line_start + i * buffer_width);
-            }
-        }
-        self.window
-            .update_with_buffer(&self.display_buffer,
-                                self.width * PIXEL_SIZE,
-                                self.height * PIXEL_SIZE)
-            .unwrap();
+    /// The pixels of the display, stored line by line
+    pub fn pixels(&self) -> &[bool] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
     }
 
     pub fn clear(&mut self) {
@@ -80,8 +44,10 @@ impl Display {
 
     pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> u8 {
         // eprint!("({:#X?}, {:#X?})\n{}", x, y, format_sprite(sprite));
-        let x = x as usize;
-        let y = y as usize;
+        // Sprites start at coordinates wrapped around the display, but
+        // are clipped at its right and bottom edge.
+        let x = x as usize % self.width;
+        let y = y as usize % self.height;
         let lines = min(sprite.len(), self.height - y);
         let sprite_width = min(8, self.width - x);
         let mut any_set_pixel_unset = false;
@@ -97,42 +63,6 @@ impl Display {
         }
         any_set_pixel_unset as u8
     }
-
-    pub fn is_key_pressed(&self, key: u8) -> bool {
-        let key = match key {
-            0x0 => Key::Key0,
-            0x1 => Key::Key1,
-            0x2 => Key::Key2,
-            0x3 => Key::Key3,
-            0x4 => Key::Key4,
-            0x5 => Key::Key5,
-            0x6 => Key::Key6,
-            0x7 => Key::Key7,
-            0x8 => Key::Key8,
-            0x9 => Key::Key9,
-            0xA => Key::A,
-            0xB => Key::B,
-            0xC => Key::C,
-            0xD => Key::D,
-            0xE => Key::E,
-            0xF => Key::F,
-            k => panic!("{:#X?} is not a valid key.", k),
-        };
-        self.window.is_key_down(key)
-    }
-
-    pub fn is_esc_pressed(&self) -> bool {
-        self.window.is_key_down(Key::Escape)
-    }
-
-    pub fn get_key_press(&self) -> Option<u8> {
-        for key in 0x0..=0xF {
-            if self.is_key_pressed(key) {
-                return Some(key);
-            }
-        }
-        None
-    }
 }
 
 pub fn format_sprite(sprite: &[u8]) -> String {
@@ -166,3 +96,26 @@ impl fmt::Display for Display {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprites_are_clipped_at_the_edges_of_any_display_size() {
+        let mut display = Display::new(12, 10);
+        assert_eq!(display.draw_sprite(8, 8, &[0xFF, 0x81, 0xFF]), 0);
+        let set: Vec<usize> = display.pixels().iter()
+                                     .enumerate()
+                                     .filter(|(_, &pixel)| pixel)
+                                     .map(|(i, _)| i)
+                                     .collect();
+        assert_eq!(set, [8 * 12 + 8, 8 * 12 + 9, 8 * 12 + 10, 8 * 12 + 11,
+                         9 * 12 + 8]);
+        // Coordinates beyond the display wrap around.
+        assert_eq!(display.draw_sprite(12 + 8, 10 + 8, &[0x80]), 1);
+        assert!(!display.pixels()[8 * 12 + 8]);
+        display.draw_sprite(255, 255, &[0x80]);
+        assert!(display.pixels()[(255 % 10) * 12 + 255 % 12]);
+    }
+}
@@ -0,0 +1,126 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use minifb::{Key, Window, WindowOptions};
+
+use super::io::IO;
+
+/// A window showing the monochrome CHIP-8 display
+pub struct EmulatorWindow {
+    display_buffer: Vec<u32>,
+    window: Window,
+    palette: [u32; 2],
+    /// Width and height of the window pixels showing a display pixel
+    pixel_size: usize,
+}
+
+/// Default colors of unset and set pixels
+const DEFAULT_PALETTE: [u32; 2] = [0, 0xFFFFFF];
+
+impl EmulatorWindow {
+    /// Open a window for a display of `width` x `height` pixels, each
+    /// shown as `scale` x `scale` pixels
+    pub fn new(width: usize, height: usize, scale: usize)
+            -> Result<Self, minifb::Error> {
+        let mut window = Window::new(
+            "Chip-8 emulator",
+            width * scale,
+            height * scale,
+            WindowOptions::default(),
+        )?;
+        // Frames are paced by the main loop.
+        window.limit_update_rate(None);
+        Ok(Self{
+            display_buffer: vec![DEFAULT_PALETTE[0]; width * height
+                                                     * scale * scale],
+            window,
+            palette: DEFAULT_PALETTE,
+            pixel_size: scale,
+        })
+    }
+
+    /// Set the RGB colors of unset and set pixels
+    pub fn set_palette(&mut self, palette: [u32; 2]) {
+        self.palette = palette;
+    }
+}
+
+impl IO for EmulatorWindow {
+    fn refresh(&mut self, pixels: &[bool], width: usize, height: usize) {
+        let pixel_size = self.pixel_size;
+        let buffer_width = width * pixel_size;
+        self.display_buffer.resize(width * height * pixel_size * pixel_size,
+                                   self.palette[0]);
+        for line in 0..height {
+            let buffer_line_start = line * pixel_size * buffer_width;
+            let buffer_line_range
+                = buffer_line_start..(buffer_line_start + buffer_width);
+            let buffer_line = &mut self.display_buffer[buffer_line_range
+                                                       .clone()];
+            for col in 0..width {
+                let color = self.palette[pixels[line * width + col] as usize];
+                buffer_line[col*pixel_size..(col+1)*pixel_size].fill(color);
+            }
+            for i in 1..pixel_size {
+                self.display_buffer.copy_within(
+                    buffer_line_range.clone(),
+                    buffer_line_start + i * buffer_width);
+            }
+        }
+        self.window
+            .update_with_buffer(&self.display_buffer,
+                                width * pixel_size,
+                                height * pixel_size)
+            .unwrap();
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        self.window.is_key_down(Key::Escape)
+    }
+
+    /// Check if a key on one of the two hex keypads is pressed
+    ///
+    /// The first keypad is mapped to the keys 0–9 and A–F.
+    /// The second keypad is mapped to the number pad, with
+    /// A–F on the keys /, *, -, +, Enter and the decimal point.
+    fn is_key_pressed(&self, keypad: u8, key: u8) -> bool {
+        let key = match (keypad, key) {
+            (0, 0x0) => Key::Key0,
+            (0, 0x1) => Key::Key1,
+            (0, 0x2) => Key::Key2,
+            (0, 0x3) => Key::Key3,
+            (0, 0x4) => Key::Key4,
+            (0, 0x5) => Key::Key5,
+            (0, 0x6) => Key::Key6,
+            (0, 0x7) => Key::Key7,
+            (0, 0x8) => Key::Key8,
+            (0, 0x9) => Key::Key9,
+            (0, 0xA) => Key::A,
+            (0, 0xB) => Key::B,
+            (0, 0xC) => Key::C,
+            (0, 0xD) => Key::D,
+            (0, 0xE) => Key::E,
+            (0, 0xF) => Key::F,
+            (1, 0x0) => Key::NumPad0,
+            (1, 0x1) => Key::NumPad1,
+            (1, 0x2) => Key::NumPad2,
+            (1, 0x3) => Key::NumPad3,
+            (1, 0x4) => Key::NumPad4,
+            (1, 0x5) => Key::NumPad5,
+            (1, 0x6) => Key::NumPad6,
+            (1, 0x7) => Key::NumPad7,
+            (1, 0x8) => Key::NumPad8,
+            (1, 0x9) => Key::NumPad9,
+            (1, 0xA) => Key::NumPadSlash,
+            (1, 0xB) => Key::NumPadAsterisk,
+            (1, 0xC) => Key::NumPadMinus,
+            (1, 0xD) => Key::NumPadPlus,
+            (1, 0xE) => Key::NumPadEnter,
+            (1, 0xF) => Key::NumPadDot,
+            (0..=1, k) => panic!("{:#X?} is not a valid key.", k),
+            (p, _) => panic!("{} is not a valid keypad.", p),
+        };
+        self.window.is_key_down(key)
+    }
+}
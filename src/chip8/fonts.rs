@@ -2,6 +2,56 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::str::FromStr;
+
+/// The built-in hexadecimal fonts of different CHIP-8 interpreters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Font {
+    #[default]
+    Chip48,
+    CosmacVip,
+    Dream6800,
+    Eti660,
+    FishNChips,
+}
+
+impl Font {
+    /// Font names as accepted by `from_str`
+    pub const NAMES: [&'static str; 5] = [
+        "chip48",
+        "cosmacvip",
+        "dream6800",
+        "eti660",
+        "fishnchips",
+    ];
+
+    /// The 16 characters 0–F of 5 bytes each
+    pub fn sprites(&self) -> &'static [u8; 16 * 5] {
+        match self {
+            Self::Chip48 => &CHIP48_FONT,
+            Self::CosmacVip => &COSMAC_VIP_FONT,
+            Self::Dream6800 => &DREAM6800_FONT,
+            Self::Eti660 => &ETI660_FONT,
+            Self::FishNChips => &FISH_N_CHIPS_FONT,
+        }
+    }
+}
+
+impl FromStr for Font {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "chip48" => Ok(Self::Chip48),
+            "cosmacvip" => Ok(Self::CosmacVip),
+            "dream6800" => Ok(Self::Dream6800),
+            "eti660" => Ok(Self::Eti660),
+            "fishnchips" => Ok(Self::FishNChips),
+            _ => Err(format!("Unknown CHIP-8 font: {}", name)),
+        }
+    }
+}
+
 pub const CHIP48_FONT: [u8; 16 * 5] = [
     // 0
     0xF0, 0x90, 0x90, 0x90, 0xF0,
@@ -176,3 +226,22 @@ pub const FISH_N_CHIPS_FONT: [u8; 16 * 5] = [
     // F
     0xE0, 0x80, 0xC0, 0x80, 0x80,
     ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_font_name_parses_to_another_font() {
+        let fonts: Vec<Font> = Font::NAMES.iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert_eq!(fonts[0], Font::default());
+        for (i, font) in fonts.iter().enumerate() {
+            for other in &fonts[i + 1..] {
+                assert_ne!(font.sprites(), other.sprites());
+            }
+        }
+        assert!("chip-48".parse::<Font>().is_err());
+    }
+}
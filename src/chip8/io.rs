@@ -0,0 +1,39 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+pub trait IO {
+    /// Show a monochrome frame of `width` x `height` pixels, stored
+    /// line by line
+    fn refresh(&mut self, pixels: &[bool], width: usize, height: usize);
+
+    fn is_esc_pressed(&self) -> bool;
+
+    /// Check if a key on one of the two hex keypads is pressed
+    ///
+    /// `keypad` is 0 or 1 and `key` between 0x0 and 0xF.
+    fn is_key_pressed(&self, keypad: u8, key: u8) -> bool;
+
+    /// Get the lowest key that is pressed on the given keypad
+    fn get_key_press(&self, keypad: u8) -> Option<u8> {
+        (0x0..=0xF).find(|&key| self.is_key_pressed(keypad, key))
+    }
+}
+
+impl<T: IO + ?Sized> IO for Box<T> {
+    fn refresh(&mut self, pixels: &[bool], width: usize, height: usize) {
+        (**self).refresh(pixels, width, height);
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        (**self).is_esc_pressed()
+    }
+
+    fn is_key_pressed(&self, keypad: u8, key: u8) -> bool {
+        (**self).is_key_pressed(keypad, key)
+    }
+
+    fn get_key_press(&self, keypad: u8) -> Option<u8> {
+        (**self).get_key_press(keypad)
+    }
+}
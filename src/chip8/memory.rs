@@ -10,6 +10,26 @@ use std::ops::{Index, IndexMut};
 use super::fonts::CHIP48_FONT;
 
 const FONT_OFFSET: usize = 0x50;
+const PROGRAM_START_ADDRESS: usize = 0x200;
+
+/// Largest program that fits into memory from 0x200 onwards
+pub const MAX_PROGRAM_SIZE: usize = 4096 - PROGRAM_START_ADDRESS;
+
+/// Read a program, checking that it fits into memory
+///
+/// Programs may be shorter than `MAX_PROGRAM_SIZE`, but not longer.
+pub fn read_program(mut reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut program = Vec::new();
+    reader.read_to_end(&mut program)?;
+    if program.len() > MAX_PROGRAM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Program of {} bytes doesn't fit into the {} bytes \
+                     of memory starting at 0x200.",
+                    program.len(), MAX_PROGRAM_SIZE)));
+    }
+    Ok(program)
+}
 
 pub struct Memory([u8; 4096]);
 
@@ -26,9 +46,17 @@ impl Memory {
         Self(memory)
     }
 
-    pub fn load_program_from_file(&mut self, mut f: File) -> io::Result<()> {
-        let program_start_address = 0x200;
-        f.read_exact(&mut self.0[program_start_address..])?;
+    /// Load a program to 0x200
+    ///
+    /// Panics if the program is longer than `MAX_PROGRAM_SIZE`.
+    pub fn load_program(&mut self, program: &[u8]) {
+        self.0[PROGRAM_START_ADDRESS..PROGRAM_START_ADDRESS + program.len()]
+            .copy_from_slice(program);
+    }
+
+    pub fn load_program_from_file(&mut self, f: File) -> io::Result<()> {
+        let program = read_program(f)?;
+        self.load_program(&program);
         Ok(())
     }
 
@@ -2,81 +2,385 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+#[cfg(feature = "window")]
+pub mod commandline;
 pub mod cpu;
 pub mod display;
+#[cfg(feature = "window")]
+pub mod emulator_window;
 pub mod fonts;
+pub mod io;
 pub mod memory;
-pub mod commandline;
 
-use std::io;
 use std::fs::File;
 
-pub struct Chip8 {
-    cpu: cpu::CPU,
-    memory: memory::Memory,
-    display: display::Display,
-}
+use crate::frame_pacer::FramePacer;
+
+pub use fonts::Font;
 
 const FRAMERATE:  usize = 60;
 const CPU_CYCLES_PER_FRAME:  usize = 10;
 
-impl Chip8 {
-    pub const AVAILABLE_DISPLAY_SIZES: [&'static str; 3] = [
-        "64x32",  //< CHIP-8
-        "128x64",  //< CHIP-10
-        "64x128",  //< HI-RES CHIP-8
-    ];
-
-    pub const AVAILABLE_FONTS: [&'static str; 5] = [
-        "chip48",
-        "cosmacvip",
-        "dream6800",
-        "eti660",
-        "fishnchips",
-    ];
-
-    pub fn new(display_size: &str, font: &str, shift_x: bool) -> Self {
-        let (width, height) = match display_size {
-            "64x32" => (64, 32),
-            "128x64" => (128, 64),
-            "64x128" => (64, 128),
-            _ => panic!("Unexpected CHIP-8 display size: {}", display_size),
-        };
-        let font = match font {
-            "chip48" => &fonts::CHIP48_FONT,
-            "cosmacvip" => &fonts::COSMAC_VIP_FONT,
-            "dream6800" => &fonts::DREAM6800_FONT,
-            "eti660" => &fonts::ETI660_FONT,
-            "fishnchips" => &fonts::FISH_N_CHIPS_FONT,
-            _ => panic!("Unknown CHIP-8 font: {}", font),
-        };
-        let mut cpu = cpu::CPU::default();
-        if shift_x {
-            cpu.activate_shift_quirk();
-        }
-        let memory = memory::Memory::with_font(font);
-        let display = display::Display::new(width, height, FRAMERATE);
-        Self {
-            cpu,
-            memory,
-            display,
+/// Display sizes of common CHIP-8 variants
+///
+/// Any other size accepted by `parse_display_size` can be used, too,
+/// whichever variant the program was written for.
+pub const AVAILABLE_DISPLAY_SIZES: [&str; 3] = [
+    "64x32",  //< CHIP-8
+    "128x64",  //< CHIP-10
+    "64x128",  //< HI-RES CHIP-8
+];
+
+/// Smallest display width or height, the width of a sprite
+///
+/// Like `MAX_DISPLAY_DIMENSION`, this applies to all variants alike.
+pub const MIN_DISPLAY_DIMENSION: usize = 8;
+
+/// Largest display width or height
+///
+/// Sprite coordinates are stored in 8 bit registers, so no pixels
+/// beyond 256 could ever be drawn.
+pub const MAX_DISPLAY_DIMENSION: usize = 256;
+
+/// Names of the built-in fonts, see `Font`
+pub const AVAILABLE_FONTS: [&str; 5] = Font::NAMES;
+
+/// Parse a display size of the form "WIDTHxHEIGHT", e.g. "64x48".
+pub fn parse_display_size(display_size: &str)
+        -> Result<(usize, usize), String> {
+    let (width, height) = display_size.split_once('x')
+        .ok_or_else(|| format!("Display size {} is not of the form \
+                                WIDTHxHEIGHT.", display_size))?;
+    let parse = |dimension: &str| -> Result<usize, String> {
+        let value: usize = dimension.parse().map_err(|_| {
+            format!("Display dimension {} is not a number.", dimension)
+        })?;
+        check_display_dimension(value)?;
+        Ok(value)
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn check_display_dimension(value: usize) -> Result<(), String> {
+    if !(MIN_DISPLAY_DIMENSION..=MAX_DISPLAY_DIMENSION).contains(&value) {
+        return Err(format!("Display dimension {} is not between {} and {}.",
+                           value, MIN_DISPLAY_DIMENSION,
+                           MAX_DISPLAY_DIMENSION));
+    }
+    Ok(())
+}
+
+/// CHIP-8 variants with their own display size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Variant {
+    /// The original COSMAC VIP interpreter with 64x32 pixels
+    #[default]
+    Chip8,
+    /// CHIP-10 with 128x64 pixels
+    Chip10,
+    /// HI-RES CHIP-8 with 64x128 pixels
+    HiResChip8,
+}
+
+impl Variant {
+    /// Width and height of the display
+    pub fn display_size(&self) -> (usize, usize) {
+        match self {
+            Self::Chip8 => (64, 32),
+            Self::Chip10 => (128, 64),
+            Self::HiResChip8 => (64, 128),
         }
     }
+}
 
-    pub fn load_rom(&mut self, file: File) -> io::Result<()> {
-        self.memory.load_program_from_file(file)
+/// Behaviors in which CHIP-8 interpreters differ
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Shift VX instead of VY in 8XY6 and 8XYE, like S-CHIP does
+    pub shift_x: bool,
+    /// Connect a second hex keypad for two-player COSMAC VIP games
+    pub two_keypads: bool,
+}
+
+pub struct Chip8<Window: io::IO> {
+    cpu: cpu::CPU,
+    memory: memory::Memory,
+    display: display::Display,
+    window: Window,
+    frame_pacer: FramePacer,
+}
+
+impl<Window: io::IO> Chip8<Window> {
+    /// Run as fast as possible instead of at 60 frames per second
+    ///
+    /// The delay and sound timers count down once per frame, so they
+    /// run faster, too.
+    pub fn disable_throttle(&mut self) {
+        self.frame_pacer.set_throttled(false);
     }
 
     pub fn run(&mut self) {
         loop {
             for _ in 0..CPU_CYCLES_PER_FRAME {
-                self.cpu.tick(&mut self.memory, &mut self.display);
+                self.cpu.tick(&mut self.memory, &mut self.display,
+                              &self.window);
             }
             self.cpu.decrement_timers();
-            self.display.refresh();
-            if self.display.is_esc_pressed() {
+            self.window.refresh(self.display.pixels(),
+                                self.display.width(),
+                                self.display.height());
+            if self.window.is_esc_pressed() {
                 break;
             }
+            self.frame_pacer.wait_for_next_frame();
+        }
+    }
+}
+
+pub struct Chip8Builder<Window: io::IO> {
+    display_size: (usize, usize),
+    font: Font,
+    quirks: Quirks,
+    seed: u64,
+    program: Option<Vec<u8>>,
+    window: Option<Window>,
+}
+
+impl<Window: io::IO> Default for Chip8Builder<Window> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Window: io::IO> Chip8Builder<Window> {
+    /// Start with a 64x32 pixel CHIP-8 using the CHIP-48 font and
+    /// no quirks
+    pub fn new() -> Self {
+        Self {
+            display_size: Variant::default().display_size(),
+            font: Font::default(),
+            quirks: Quirks::default(),
+            seed: cpu::DEFAULT_SEED,
+            program: None,
+            window: None,
+        }
+    }
+
+    pub fn build(self) -> Result<Chip8<Window>, String> {
+        let program = self.program.ok_or("No CHIP-8 program loaded.")?;
+        let window = self.window.ok_or("No emulator window given.")?;
+        let mut cpu = cpu::CPU::with_seed(self.seed);
+        if self.quirks.shift_x {
+            cpu.activate_shift_quirk();
+        }
+        if self.quirks.two_keypads {
+            cpu.activate_second_keypad();
+        }
+        let mut memory = memory::Memory::with_font(self.font.sprites());
+        memory.load_program(&program);
+        let (width, height) = self.display_size;
+        Ok(Chip8 {
+            cpu,
+            memory,
+            display: display::Display::new(width, height),
+            window,
+            frame_pacer: FramePacer::new(FRAMERATE as f64),
+        })
+    }
+
+    /// Use the display size of a CHIP-8 variant
+    pub fn use_variant(mut self, variant: Variant) -> Self {
+        self.display_size = variant.display_size();
+        self
+    }
+
+    /// Use a display of `width` x `height` pixels
+    ///
+    /// Both dimensions have to be between `MIN_DISPLAY_DIMENSION` and
+    /// `MAX_DISPLAY_DIMENSION`, no matter which `Variant` the program was
+    /// written for.
+    pub fn use_display_size(mut self, width: usize, height: usize)
+            -> Result<Self, String> {
+        check_display_dimension(width)?;
+        check_display_dimension(height)?;
+        self.display_size = (width, height);
+        Ok(self)
+    }
+
+    pub fn use_font(mut self, font: Font) -> Self {
+        self.font = font;
+        self
+    }
+
+    pub fn use_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Seed the random number generator used by CXNN
+    ///
+    /// The same seed gives the same sequence of random numbers.
+    pub fn use_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Load a program, which fails if it doesn't fit into memory
+    pub fn load_rom(mut self, file: File) -> std::io::Result<Self> {
+        self.program = Some(memory::read_program(file)?);
+        Ok(self)
+    }
+
+    pub fn use_emulator_window(mut self, window: Window) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Width and height of the display that will be built
+    pub fn display_size(&self) -> (usize, usize) {
+        self.display_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoWindow;
+
+    impl io::IO for NoWindow {
+        fn refresh(&mut self, _pixels: &[bool], _width: usize,
+                   _height: usize) {}
+
+        fn is_esc_pressed(&self) -> bool {
+            true
+        }
+
+        fn is_key_pressed(&self, _keypad: u8, _key: u8) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn display_sizes_between_8_and_256_are_accepted() {
+        assert_eq!(parse_display_size("64x32"), Ok((64, 32)));
+        assert_eq!(parse_display_size("8x256"), Ok((8, 256)));
+        assert_eq!(parse_display_size("48x40"), Ok((48, 40)));
+        for size in AVAILABLE_DISPLAY_SIZES {
+            assert!(parse_display_size(size).is_ok());
+        }
+        for size in ["64", "64x", "x32", "64x32x2", "sixty-fourx32",
+                     "7x32", "64x257", "-8x32"] {
+            assert!(parse_display_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn builder_uses_display_size() {
+        let builder = Chip8Builder::<NoWindow>::new();
+        assert_eq!(builder.display_size(), (64, 32));
+        let builder = builder.use_variant(Variant::HiResChip8);
+        assert_eq!(builder.display_size(), (64, 128));
+        let builder = builder.use_display_size(48, 40).unwrap();
+        assert_eq!(builder.display_size(), (48, 40));
+        assert!(Chip8Builder::<NoWindow>::new()
+                    .use_display_size(300, 40).is_err());
+
+        // Draw a sprite at (250, 250) and loop.
+        let path = std::env::temp_dir().join("emulato-rs-display-size.ch8");
+        std::fs::write(&path, [0x60, 0xFA, 0xD0, 0x01, 0x12, 0x04]).unwrap();
+        let mut chip8 = builder.load_rom(File::open(&path).unwrap()).unwrap()
+                               .use_emulator_window(NoWindow)
+                               .build()
+                               .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        chip8.run();
+        assert_eq!((chip8.display.width(), chip8.display.height()), (48, 40));
+    }
+
+    /// Write a program to a temporary file and open it.
+    fn rom_file(name: &str, program: &[u8]) -> File {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, program).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn builder_needs_program_and_window() {
+        let error = Chip8Builder::<NoWindow>::new()
+            .use_emulator_window(NoWindow)
+            .build().err().unwrap();
+        assert_eq!(error, "No CHIP-8 program loaded.");
+        let error = Chip8Builder::<NoWindow>::new()
+            .load_rom(rom_file("emulato-rs-no-window.ch8", &[0x12, 0x00]))
+            .unwrap()
+            .build().err().unwrap();
+        assert_eq!(error, "No emulator window given.");
+        let too_long = vec![0; memory::MAX_PROGRAM_SIZE + 1];
+        let error = Chip8Builder::<NoWindow>::new()
+            .load_rom(rom_file("emulato-rs-too-long.ch8", &too_long))
+            .err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn builder_uses_font() {
+        // Draw the character 4 at (0, 0) and loop.
+        let program = [
+            0x60, 0x04,  // V0 = 4
+            0xF0, 0x29,  // I = sprite of V0
+            0x61, 0x00,  // V1 = 0
+            0xD1, 0x15,  // draw 5 lines at (V1, V1)
+            0x12, 0x08,  // jump to 0x208
+        ];
+        for font in [Font::Chip48, Font::CosmacVip, Font::Dream6800] {
+            let mut chip8 = Chip8Builder::new()
+                .use_font(font)
+                .load_rom(rom_file("emulato-rs-font.ch8", &program))
+                .unwrap()
+                .use_emulator_window(NoWindow)
+                .build()
+                .unwrap();
+            chip8.run();
+            let pixels = chip8.display.pixels();
+            for (y, &line) in font.sprites()[4 * 5..5 * 5].iter()
+                                                          .enumerate() {
+                for x in 0..8 {
+                    assert_eq!(pixels[y * 64 + x], line & (0x80 >> x) != 0,
+                               "{:?} ({}, {})", font, x, y);
+                }
+            }
+        }
+    }
+
+    /// Pressed keys on the second keypad
+    struct PressedKeys(&'static [u8]);
+
+    impl io::IO for PressedKeys {
+        fn refresh(&mut self, _pixels: &[bool], _width: usize,
+                   _height: usize) {}
+
+        fn is_esc_pressed(&self) -> bool {
+            true
+        }
+
+        fn is_key_pressed(&self, keypad: u8, key: u8) -> bool {
+            keypad == 1 && self.0.contains(&key)
         }
     }
+
+    #[test]
+    fn lowest_pressed_key_is_reported() {
+        use io::IO;
+
+        let window = PressedKeys(&[0xC, 0x3, 0x7]);
+        assert_eq!(window.get_key_press(0), None);
+        assert_eq!(window.get_key_press(1), Some(0x3));
+        let boxed: Box<dyn IO> = Box::new(window);
+        assert_eq!(boxed.get_key_press(1), Some(0x3));
+        assert!(boxed.is_key_pressed(1, 0xC));
+        assert!(!boxed.is_key_pressed(0, 0xC));
+    }
 }
@@ -0,0 +1,151 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Lag after which the pacer stops trying to catch up
+///
+/// If emulation falls behind by more than this, e.g. because the
+/// window was dragged, the missed frames are dropped instead of being
+/// emulated as fast as possible.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Limits the main loop of an emulator to a fixed frame rate
+///
+/// Frame deadlines are counted from the start, so the time spent
+/// sleeping and rendering doesn't accumulate into a drift of the frame
+/// rate.  An unthrottled pacer never waits, which runs the emulator as
+/// fast as possible.
+pub struct FramePacer {
+    /// Frames per second at normal speed
+    framerate: f64,
+    frame_time: Duration,
+    /// Deadline of the next frame, unset until the first wait
+    ///
+    /// Getting the time panics on WebAssembly, where the browser paces
+    /// the frames instead.
+    next_frame: Option<Instant>,
+    throttled: bool,
+}
+
+impl FramePacer {
+    pub fn new(framerate: f64) -> Self {
+        Self{
+            framerate,
+            frame_time: Duration::from_secs_f64(1. / framerate),
+            next_frame: None,
+            throttled: true,
+        }
+    }
+
+    pub fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+        self.next_frame = None;
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    /// Run at the given multiple of the normal frame rate
+    pub fn set_speed(&mut self, speed: f64) {
+        self.frame_time = Duration::from_secs_f64(1. / (self.framerate
+                                                         * speed));
+        self.next_frame = None;
+    }
+
+    /// Sleep until the next frame is due.
+    pub fn wait_for_next_frame(&mut self) {
+        if !self.throttled {
+            return;
+        }
+        let now = Instant::now();
+        let next_frame = self.next_frame.unwrap_or(now) + self.frame_time;
+        self.next_frame = Some(next_frame);
+        if let Some(sleep_duration) = next_frame.checked_duration_since(now) {
+            sleep(sleep_duration);
+        } else if now.duration_since(next_frame) > MAX_LAG {
+            self.next_frame = Some(now);
+        }
+    }
+}
+
+/// Parse an emulation speed, either a positive factor of the normal
+/// speed or `max`, for which None is returned
+pub fn parse_speed(speed: &str) -> Result<Option<f64>, String> {
+    if speed == "max" {
+        return Ok(None);
+    }
+    match speed.parse::<f64>() {
+        Ok(factor) if factor > 0. && factor.is_finite() => Ok(Some(factor)),
+        _ => Err(format!("Speed {} is neither a positive number nor max.",
+                         speed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_scales_the_frame_time() {
+        let mut pacer = FramePacer::new(50.);
+        assert_eq!(pacer.frame_time, Duration::from_millis(20));
+        pacer.set_speed(0.25);
+        assert_eq!(pacer.frame_time, Duration::from_millis(80));
+        pacer.set_speed(2.);
+        assert_eq!(pacer.frame_time, Duration::from_millis(10));
+        assert_eq!(parse_speed("0.5"), Ok(Some(0.5)));
+        assert_eq!(parse_speed("max"), Ok(None));
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn deadlines_are_counted_from_the_first_frame() {
+        let mut pacer = FramePacer::new(1000.);
+        pacer.wait_for_next_frame();
+        let first_frame = pacer.next_frame.unwrap();
+        for _ in 0..9 {
+            // Time spent emulating doesn't delay the following frames.
+            sleep(Duration::from_micros(300));
+            pacer.wait_for_next_frame();
+        }
+        assert!(Instant::now() >= first_frame + 9 * pacer.frame_time);
+        assert_eq!(pacer.next_frame.unwrap(),
+                   first_frame + 9 * pacer.frame_time);
+    }
+
+    #[test]
+    fn frames_missed_by_a_long_lag_are_dropped() {
+        let mut pacer = FramePacer::new(60.);
+        let long_ago = Instant::now() - 10 * MAX_LAG;
+        pacer.next_frame = Some(long_ago);
+        let before = Instant::now();
+        pacer.wait_for_next_frame();
+        assert!(pacer.next_frame.unwrap() >= before);
+        // A short lag is caught up on instead.
+        let recently = Instant::now() - MAX_LAG / 2;
+        pacer.next_frame = Some(recently);
+        pacer.wait_for_next_frame();
+        assert_eq!(pacer.next_frame.unwrap(), recently + pacer.frame_time);
+    }
+
+    #[test]
+    fn unthrottled_pacer_never_waits() {
+        let mut pacer = FramePacer::new(1.);
+        pacer.set_throttled(false);
+        assert!(!pacer.is_throttled());
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait_for_next_frame();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(pacer.next_frame.is_none());
+        // Throttling again starts counting deadlines anew.
+        pacer.set_throttled(true);
+        assert!(pacer.next_frame.is_none());
+    }
+}
@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fmt;
+
+/// Known shortcuts the emulator takes compared to the hardware
+///
+/// They are reported when a game runs into a situation in which the
+/// shortcut could make the emulation differ from the hardware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Approximation {
+    /// The CPU accessed VRAM during mode 3 or OAM during mode 2 or 3,
+    /// which is blocked, but modes only change between instructions
+    BlockedVramAccess,
+    /// STOP was executed, which is only emulated as a low-power mode
+    /// without its corner cases for pressed buttons, pending interrupts
+    /// and the CGB speed switch
+    Stop,
+    /// Wave RAM was accessed while channel 3 is playing, which only
+    /// works in the cycle in which the channel reads a sample
+    WaveRamAccess,
+}
+
+impl fmt::Display for Approximation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::BlockedVramAccess => "VRAM or OAM accessed while blocked",
+            Self::Stop => "STOP executed",
+            Self::WaveRamAccess => "Wave RAM accessed while playing",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// How to react when a game runs into an approximation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Silently run on, as if emulation was exact
+    #[default]
+    Lenient,
+    /// Log a warning the first time each approximation is hit
+    Warn,
+    /// Stop emulation at the first approximation that is hit
+    Error,
+}
+
+impl Strictness {
+    /// Values accepted by `from_name`
+    pub const NAMES: [&'static str; 2] = ["warn", "error"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Collects approximations hit while running an instruction
+#[derive(Default)]
+pub struct ApproximationTracker {
+    /// Approximations hit since the last call to `take_new_hits`
+    new_hits: Vec<Approximation>,
+    /// Approximations that have been reported before
+    reported: Vec<Approximation>,
+}
+
+impl ApproximationTracker {
+    pub fn hit(&mut self, approximation: Approximation) {
+        if !self.reported.contains(&approximation)
+           && !self.new_hits.contains(&approximation) {
+            self.new_hits.push(approximation);
+        }
+    }
+
+    /// Take the approximations hit for the first time since the last
+    /// call
+    pub fn take_new_hits(&mut self) -> Vec<Approximation> {
+        self.reported.extend_from_slice(&self.new_hits);
+        std::mem::take(&mut self.new_hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approximations_are_reported_once() {
+        let mut tracker = ApproximationTracker::default();
+        tracker.hit(Approximation::Stop);
+        tracker.hit(Approximation::Stop);
+        tracker.hit(Approximation::BlockedVramAccess);
+        assert_eq!(tracker.take_new_hits(),
+                   [Approximation::Stop, Approximation::BlockedVramAccess]);
+        tracker.hit(Approximation::Stop);
+        assert!(tracker.take_new_hits().is_empty());
+        tracker.hit(Approximation::WaveRamAccess);
+        assert_eq!(tracker.take_new_hits(), [Approximation::WaveRamAccess]);
+    }
+}
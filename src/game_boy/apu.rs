@@ -0,0 +1,978 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::Cell;
+
+use serde::{Deserialize, Serialize};
+
+use super::CPU_CYCLES_PER_SECOND;
+use super::vgm::VgmRecorder;
+
+/// Sample rate of the generated audio in Hz
+pub const SAMPLE_RATE: usize = 48_000;
+
+/// Number of output channels, the left and right speaker
+pub const CHANNELS: usize = 2;
+
+/// The frame sequencer clocks length counters, sweep and envelopes at 512Hz.
+const FRAME_SEQUENCER_PERIOD: usize = CPU_CYCLES_PER_SECOND / 512;
+
+/// Charge factor of the high-pass filter capacitor per output sample
+///
+/// The capacitor discharges by a factor of 0.999958 per CPU cycle.
+/// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior
+const HIGH_PASS_CHARGE_FACTOR: f32 = 0.996;
+
+/// Bits that always read as 1 for the registers 0xFF10–0xFF26
+///
+/// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Register_Reading
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10–NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21–NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30–NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41–NR44
+    0x00, 0x00, 0x70,             // NR50–NR52
+];
+
+/// The Audio Processing Unit
+///
+/// https://gbdev.io/pandocs/Audio.html
+///
+/// Register layout:
+/// 0xFF10–0xFF14  NR10–NR14  Channel 1 (pulse with period sweep)
+/// 0xFF16–0xFF19  NR21–NR24  Channel 2 (pulse)
+/// 0xFF1A–0xFF1E  NR30–NR34  Channel 3 (wave output)
+/// 0xFF20–0xFF23  NR41–NR44  Channel 4 (noise)
+/// 0xFF24         NR50  Master volume & VIN panning
+/// 0xFF25         NR51  Sound panning
+/// 0xFF26         NR52  Sound on/off
+/// 0xFF30–0xFF3F  Wave pattern RAM
+#[derive(Serialize, Deserialize)]
+pub struct APU {
+    registers: [u8; 0x17],
+    wave_ram: [u8; 0x10],
+    powered_on: bool,
+    pulse1: PulseChannel,
+    pulse2: PulseChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    frame_sequencer_counter: usize,
+    frame_sequencer_step: u8,
+    sample_counter: usize,
+    high_pass_capacitors: [f32; CHANNELS],
+    #[serde(skip)]
+    samples: Vec<i16>,
+    /// CPU cycles since power on, used to time recorded register writes
+    cycles: u64,
+    #[serde(skip)]
+    vgm_recorder: Option<VgmRecorder>,
+    /// Whether the user has been warned about wave RAM accesses while
+    /// channel 3 is playing
+    #[serde(skip)]
+    warned_about_wave_ram: Cell<bool>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self{
+            registers: [0; 0x17],
+            wave_ram: [0; 0x10],
+            powered_on: false,
+            pulse1: PulseChannel::with_sweep(),
+            pulse2: PulseChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            frame_sequencer_counter: 0,
+            frame_sequencer_step: 0,
+            sample_counter: 0,
+            high_pass_capacitors: [0.; CHANNELS],
+            samples: Vec::with_capacity(CHANNELS * SAMPLE_RATE / 30),
+            cycles: 0,
+            vgm_recorder: None,
+            warned_about_wave_ram: Cell::new(false),
+        }
+    }
+}
+
+impl APU {
+    /// Take over the state of an APU loaded from a save state
+    ///
+    /// Samples that haven't been played yet are kept, and a VGM
+    /// recording continues.
+    pub fn restore_state(&mut self, saved: Self) {
+        *self = Self{
+            samples: std::mem::take(&mut self.samples),
+            vgm_recorder: self.vgm_recorder.take(),
+            ..saved
+        };
+    }
+
+    /// Whether channel 3 is playing, which blocks most wave RAM accesses
+    pub fn is_wave_playing(&self) -> bool {
+        self.wave.enabled
+    }
+
+    pub fn read8(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF26 => { // NR52
+                let mut nr52 = READ_MASKS[0xFF26 - 0xFF10];
+                if self.powered_on {
+                    nr52 |= 0x80;
+                }
+                for (bit, active) in [self.pulse1.enabled, self.pulse2.enabled,
+                                      self.wave.enabled, self.noise.enabled]
+                                     .iter().enumerate() {
+                    if *active {
+                        nr52 |= 1 << bit;
+                    }
+                }
+                nr52
+            }
+            0xFF27..=0xFF2F => 0xFF, // unused
+            0xFF30..=0xFF3F => {
+                if self.wave.enabled {
+                    self.warn_about_wave_ram_access();
+                    return 0xFF;
+                }
+                self.wave_ram[(address - 0xFF30) as usize]
+            }
+            _ => panic!("Trying to read non-APU address {:0>4X}.", address),
+        }
+    }
+
+    pub fn write8(&mut self, address: u16, value: u8) {
+        if let Some(recorder) = &mut self.vgm_recorder {
+            recorder.record(self.cycles, address, value);
+        }
+        match address {
+            0xFF10..=0xFF25 => {
+                if !self.powered_on {
+                    // While powered off, only the length counters
+                    // can be written (on the DMG).
+                    match address {
+                        0xFF11 => self.pulse1.length.load(64 - (value & 0x3F) as u16),
+                        0xFF16 => self.pulse2.length.load(64 - (value & 0x3F) as u16),
+                        0xFF1B => self.wave.length.load(256 - value as u16),
+                        0xFF20 => self.noise.length.load(64 - (value & 0x3F) as u16),
+                        _ => {}
+                    }
+                    return;
+                }
+                self.registers[(address - 0xFF10) as usize] = value;
+                match address {
+                    0xFF10..=0xFF14 => {
+                        self.pulse1.write_register(address - 0xFF10, value);
+                    }
+                    0xFF16..=0xFF19 => {
+                        self.pulse2.write_register(address - 0xFF15, value);
+                    }
+                    0xFF1A..=0xFF1E => {
+                        self.wave.write_register(address - 0xFF1A, value);
+                    }
+                    0xFF20..=0xFF23 => {
+                        self.noise.write_register(address - 0xFF1F, value);
+                    }
+                    _ => {
+                        // NR50, NR51 and unused registers only need
+                        // to be stored.
+                    }
+                }
+            }
+            0xFF26 => { // NR52
+                let power = value & 0x80 != 0;
+                if self.powered_on && !power {
+                    self.power_off();
+                } else if !self.powered_on && power {
+                    self.powered_on = true;
+                    self.frame_sequencer_step = 0;
+                }
+            }
+            0xFF27..=0xFF2F => {} // unused
+            0xFF30..=0xFF3F => {
+                if self.wave.enabled {
+                    self.warn_about_wave_ram_access();
+                    return;
+                }
+                self.wave_ram[(address - 0xFF30) as usize] = value;
+            }
+            _ => panic!("Trying to write non-APU address {:0>4X}.", address),
+        }
+    }
+
+    /// Warn once that wave RAM accesses during playback are approximated
+    ///
+    /// While channel 3 is playing, the DMG only allows wave RAM accesses
+    /// in the cycle in which the channel reads a sample; at any other
+    /// time, reads return 0xFF and writes are ignored.  As that cycle is
+    /// not emulated, all accesses behave as if they missed it.
+    fn warn_about_wave_ram_access(&self) {
+        if !self.warned_about_wave_ram.replace(true) {
+            log::warn!("Wave RAM accessed while channel 3 is playing. \
+                        This is only approximated and the game might \
+                        sound wrong.");
+        }
+    }
+
+    fn power_off(&mut self) {
+        self.powered_on = false;
+        self.registers = [0; 0x17];
+        // Length counters are not affected by power on the DMG.
+        let lengths = (self.pulse1.length.counter, self.pulse2.length.counter,
+                       self.wave.length.counter, self.noise.length.counter);
+        self.pulse1 = PulseChannel::with_sweep();
+        self.pulse2 = PulseChannel::default();
+        self.wave = WaveChannel::default();
+        self.noise = NoiseChannel::default();
+        self.pulse1.length.counter = lengths.0;
+        self.pulse2.length.counter = lengths.1;
+        self.wave.length.counter = lengths.2;
+        self.noise.length.counter = lengths.3;
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+        // Step in units of machine cycles, so that samples are taken
+        // at the right point in time even during slow instructions.
+        for _ in (0..cycles).step_by(4) {
+            if self.powered_on {
+                self.pulse1.step(4);
+                self.pulse2.step(4);
+                self.wave.step(4);
+                self.noise.step(4);
+                self.frame_sequencer_counter += 4;
+                if self.frame_sequencer_counter >= FRAME_SEQUENCER_PERIOD {
+                    self.frame_sequencer_counter -= FRAME_SEQUENCER_PERIOD;
+                    self.clock_frame_sequencer();
+                }
+            }
+            self.sample_counter += 4 * SAMPLE_RATE;
+            if self.sample_counter >= CPU_CYCLES_PER_SECOND {
+                self.sample_counter -= CPU_CYCLES_PER_SECOND;
+                let samples = self.mix();
+                self.samples.extend_from_slice(&samples);
+            }
+        }
+    }
+
+    /// Clock length counters, sweep and envelopes
+    ///
+    /// Step  Length  Sweep  Envelope
+    /// 0     clock
+    /// 1
+    /// 2     clock   clock
+    /// 3
+    /// 4     clock
+    /// 5
+    /// 6     clock   clock
+    /// 7                    clock
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step & 1 == 0 {
+            self.pulse1.clock_length();
+            self.pulse2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if step == 7 {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Mix the channels into a stereo sample
+    ///
+    /// NR51 (0xFF25) selects which channels are sent to which output:
+    ///
+    /// Bit  Output
+    /// ---  ------
+    /// 7    channel 4 to left
+    /// 6    channel 3 to left
+    /// 5    channel 2 to left
+    /// 4    channel 1 to left
+    /// 3    channel 4 to right
+    /// 2    channel 3 to right
+    /// 1    channel 2 to right
+    /// 0    channel 1 to right
+    ///
+    /// NR50 (0xFF24) sets the master volume of the left output in
+    /// bits 4–6 and of the right output in bits 0–2.  A volume of
+    /// value v scales the output by (v + 1) / 8.
+    /// https://gbdev.io/pandocs/Audio_Registers.html#global-control-registers
+    fn mix(&mut self) -> [i16; CHANNELS] {
+        let outputs = [self.pulse1.output(), self.pulse2.output(),
+                       self.wave.output(&self.wave_ram), self.noise.output()];
+        let panning = self.registers[(0xFF25 - 0xFF10) as usize];
+        let master_volume = self.registers[(0xFF24 - 0xFF10) as usize];
+        let mut samples = [0; CHANNELS];
+        // Left output uses the upper bits of NR50 and NR51,
+        // right output the lower bits.
+        for (side, shift) in [4, 0].into_iter().enumerate() {
+            let mut mix = 0.;
+            for (channel, output) in outputs.iter().enumerate() {
+                if let Some(output) = output {
+                    if panning & (1 << (shift + channel)) != 0 {
+                        mix += dac(*output);
+                    }
+                }
+            }
+            let volume = ((master_volume >> shift) & 0x07) + 1;
+            let mix = mix / 4. * volume as f32 / 8.;
+            // The Game Boy removes the DC offset of the DACs with a
+            // high-pass filter.
+            let capacitor = &mut self.high_pass_capacitors[side];
+            let filtered = mix - *capacitor;
+            *capacitor = mix - filtered * HIGH_PASS_CHARGE_FACTOR;
+            samples[side] = (filtered * i16::MAX as f32) as i16;
+        }
+        samples
+    }
+
+    /// Take all samples that have been generated since the last call.
+    ///
+    /// The samples of the left and right output are interleaved.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Start recording all writes to the sound registers
+    ///
+    /// If the APU is already powered on, the recording starts with
+    /// writes that restore the current register values, but without
+    /// triggering any channels.
+    pub fn start_vgm_recording(&mut self) {
+        let mut recorder = VgmRecorder::new(self.cycles);
+        if self.powered_on {
+            recorder.record(self.cycles, 0xFF26, 0x80);
+            for (address, value) in (0xFF10..=0xFF25).zip(self.registers) {
+                // Clear the trigger bit of NRx4.
+                let value = match address {
+                    0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => value & 0x7F,
+                    _ => value,
+                };
+                recorder.record(self.cycles, address, value);
+            }
+            for (address, value) in (0xFF30..=0xFF3F).zip(self.wave_ram) {
+                recorder.record(self.cycles, address, value);
+            }
+        }
+        self.vgm_recorder = Some(recorder);
+    }
+
+    /// Stop recording writes to the sound registers
+    ///
+    /// Return the recording if one was running.
+    pub fn stop_vgm_recording(&mut self) -> Option<VgmRecorder> {
+        let mut recorder = self.vgm_recorder.take()?;
+        recorder.finish(self.cycles);
+        Some(recorder)
+    }
+}
+
+/// Convert a digital channel output (0–15) into an analog value in [-1, 1].
+fn dac(value: u8) -> f32 {
+    value as f32 / 7.5 - 1.
+}
+
+#[derive(Serialize, Deserialize)]
+struct LengthCounter {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self{
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = length;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Return whether the length counter expired.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    pace: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    /// Write NRx2 register
+    ///
+    /// Bit 7-4 - Initial volume
+    /// Bit 3   - Direction (0=Decrease, 1=Increase)
+    /// Bit 2-0 - Sweep pace (0=No sweep)
+    fn write_register(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.pace = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.pace;
+    }
+
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.pace;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// DAC is enabled if the upper 5 bits of NRx2 are not all 0.
+fn is_dac_enabled(nrx2: u8) -> bool {
+    nrx2 & 0xF8 != 0
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Sweep {
+    pace: u8,
+    decrease: bool,
+    step: u8,
+    timer: u8,
+    shadow_period: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    /// Write NR10 register
+    ///
+    /// Bit 6-4 - Sweep pace
+    /// Bit 3   - Direction (0=Increase, 1=Decrease)
+    /// Bit 2-0 - Individual step
+    fn write_register(&mut self, value: u8) {
+        self.pace = (value >> 4) & 0x07;
+        self.decrease = value & 0x08 != 0;
+        self.step = value & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        // A sweep pace of 0 is treated as 8 by the timer.
+        self.timer = if self.pace == 0 { 8 } else { self.pace };
+    }
+
+    /// Return the next period or None if it overflows.
+    fn next_period(&self) -> Option<u16> {
+        let delta = self.shadow_period >> self.step;
+        let period = if self.decrease {
+            self.shadow_period - delta
+        } else {
+            self.shadow_period + delta
+        };
+        if period > 0x7FF {
+            None
+        } else {
+            Some(period)
+        }
+    }
+}
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+#[derive(Serialize, Deserialize)]
+struct PulseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    period: u16,
+    timer: usize,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Default for PulseChannel {
+    fn default() -> Self {
+        Self{
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            period: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            sweep: None,
+        }
+    }
+}
+
+impl PulseChannel {
+    fn with_sweep() -> Self {
+        Self{
+            sweep: Some(Sweep::default()),
+            ..Self::default()
+        }
+    }
+
+    /// Write to one of the registers NRx0–NRx4
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.write_register(value);
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(64 - (value & 0x3F) as u16);
+            }
+            2 => {
+                self.envelope.write_register(value);
+                self.dac_enabled = is_dac_enabled(value);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.period = (self.period & 0x700) | value as u16;
+            }
+            4 => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.timer_period();
+        self.envelope.trigger();
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_period = self.period;
+            sweep.reload_timer();
+            sweep.enabled = sweep.pace != 0 || sweep.step != 0;
+            if sweep.step != 0 && sweep.next_period().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn timer_period(&self) -> usize {
+        (2048 - self.period as usize) * 4
+    }
+
+    fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.pace == 0 {
+            return;
+        }
+        match sweep.next_period() {
+            Some(period) if sweep.step != 0 => {
+                sweep.shadow_period = period;
+                self.period = period;
+                // The new period is checked for overflow once more.
+                if sweep.next_period().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => {}
+            None => self.enabled = false,
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if self.enabled {
+            Some(DUTY_CYCLES[self.duty as usize][self.duty_step as usize]
+                 * self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_shift: u8,
+    period: u16,
+    timer: usize,
+    position: u8,
+    length: LengthCounter,
+}
+
+impl Default for WaveChannel {
+    fn default() -> Self {
+        Self{
+            enabled: false,
+            dac_enabled: false,
+            volume_shift: 4,
+            period: 0,
+            timer: 0,
+            position: 0,
+            length: LengthCounter::new(256),
+        }
+    }
+}
+
+impl WaveChannel {
+    /// Write to one of the registers NR30–NR34
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.length.load(256 - value as u16);
+            }
+            2 => {
+                // 0: mute, 1: 100%, 2: 50%, 3: 25%
+                self.volume_shift = match (value >> 5) & 0x03 {
+                    0 => 4,
+                    v => v - 1,
+                };
+            }
+            3 => {
+                self.period = (self.period & 0x700) | value as u16;
+            }
+            4 => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.timer_period();
+        self.position = 0;
+    }
+
+    fn timer_period(&self) -> usize {
+        (2048 - self.period as usize) * 2
+    }
+
+    fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self, wave_ram: &[u8; 0x10]) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        let byte = wave_ram[(self.position / 2) as usize];
+        let sample = if self.position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        Some(sample >> self.volume_shift)
+    }
+}
+
+const NOISE_DIVISORS: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Serialize, Deserialize)]
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    short_mode: bool,
+    divisor_code: u8,
+    timer: usize,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self{
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            timer: 8,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl NoiseChannel {
+    /// Write to one of the registers NR41–NR44
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            1 => {
+                self.length.load(64 - (value & 0x3F) as u16);
+            }
+            2 => {
+                self.envelope.write_register(value);
+                self.dac_enabled = is_dac_enabled(value);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                // Bit 7-4 - Clock shift
+                // Bit 3   - LFSR width (0=15 bits, 1=7 bits)
+                // Bit 2-0 - Clock divider
+                self.clock_shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.timer_period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn timer_period(&self) -> usize {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if self.enabled {
+            Some((!self.lfsr & 1) as u8 * self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A powered-on APU with channel 1 at full volume
+    fn apu_with_pulse1() -> APU {
+        let mut apu = APU::default();
+        apu.write8(0xFF26, 0x80);
+        apu.write8(0xFF12, 0xF0);
+        apu
+    }
+
+    fn is_pulse1_enabled(apu: &APU) -> bool {
+        apu.read8(0xFF26) & 0x01 != 0
+    }
+
+    #[test]
+    fn length_is_clocked_on_every_other_frame_sequencer_step() {
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF11, 0x3E);  // length 2
+        apu.write8(0xFF14, 0xC0);  // trigger with length enabled
+        assert!(is_pulse1_enabled(&apu));
+        // Steps 0 and 2 clock the length counter.
+        apu.step(3 * FRAME_SEQUENCER_PERIOD - 4);
+        assert!(is_pulse1_enabled(&apu));
+        assert_eq!(apu.pulse1.length.counter, 1);
+        apu.step(4);
+        assert!(!is_pulse1_enabled(&apu));
+        assert_eq!(apu.read8(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn length_counter_only_disables_if_enabled() {
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF11, 0x3F);  // length 1
+        apu.write8(0xFF14, 0x80);
+        apu.step(4 * FRAME_SEQUENCER_PERIOD);
+        assert!(is_pulse1_enabled(&apu));
+        apu.write8(0xFF14, 0x40);
+        apu.step(2 * FRAME_SEQUENCER_PERIOD);
+        assert!(!is_pulse1_enabled(&apu));
+        // Triggering reloads an expired counter with the full length.
+        apu.write8(0xFF14, 0xC0);
+        assert!(is_pulse1_enabled(&apu));
+        assert_eq!(apu.pulse1.length.counter, 64);
+    }
+
+    #[test]
+    fn envelope_is_clocked_on_step_seven() {
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF12, 0xF2);  // volume 15, decrease with pace 2
+        apu.write8(0xFF14, 0x80);
+        assert_eq!(apu.pulse1.envelope.volume, 15);
+        apu.step(8 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.pulse1.envelope.volume, 15);
+        apu.step(8 * FRAME_SEQUENCER_PERIOD - 4);
+        assert_eq!(apu.pulse1.envelope.volume, 15);
+        apu.step(4);
+        assert_eq!(apu.pulse1.envelope.volume, 14);
+        // The volume stays in 0–15.
+        apu.step(16 * 15 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.pulse1.envelope.volume, 0);
+        apu.write8(0xFF12, 0xE9);  // volume 14, increase with pace 1
+        apu.write8(0xFF14, 0x80);
+        apu.step(8 * 8 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.pulse1.envelope.volume, 15);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        // Overflow is checked when triggering.
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF10, 0x11);  // pace 1, increase by period / 2
+        apu.write8(0xFF13, 0x00);
+        apu.write8(0xFF14, 0x87);  // trigger with period 0x700
+        assert!(!is_pulse1_enabled(&apu));
+
+        // And after each update of the period, for the next update.
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF10, 0x11);
+        apu.write8(0xFF13, 0x00);
+        apu.write8(0xFF14, 0x85);  // trigger with period 0x500
+        assert!(is_pulse1_enabled(&apu));
+        // Step 2 clocks the sweep.
+        apu.step(3 * FRAME_SEQUENCER_PERIOD - 4);
+        assert!(is_pulse1_enabled(&apu));
+        assert_eq!(apu.pulse1.period, 0x500);
+        apu.step(4);
+        assert_eq!(apu.pulse1.period, 0x780);
+        assert!(!is_pulse1_enabled(&apu));
+    }
+
+    #[test]
+    fn decreasing_sweep_never_overflows() {
+        let mut apu = apu_with_pulse1();
+        apu.write8(0xFF10, 0x19);  // pace 1, decrease by period / 2
+        apu.write8(0xFF13, 0x00);
+        apu.write8(0xFF14, 0x87);
+        apu.step(3 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.pulse1.period, 0x380);
+        apu.step(64 * FRAME_SEQUENCER_PERIOD);
+        assert!(is_pulse1_enabled(&apu));
+    }
+}
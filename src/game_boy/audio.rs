@@ -0,0 +1,479 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use super::apu::{CHANNELS, SAMPLE_RATE};
+
+/// Highest speed at which audio is still resampled instead of muted
+const MAX_RESAMPLED_SPEED: f64 = 4.;
+
+/// Lowest speed at which audio is still resampled instead of muted
+const MIN_RESAMPLED_SPEED: f64 = 0.25;
+
+/// Number of frames over which audio fades out when it gets muted
+const FADE_OUT_SAMPLES: usize = 64;
+
+/// Resamples interleaved stereo frames by linear interpolation
+///
+/// The ratio is the number of input frames per output frame, so a ratio
+/// of 2 halves the number of frames and a ratio of 0.5 doubles it.
+pub struct Resampler {
+    ratio: f64,
+    /// Position of the next output frame relative to the start
+    /// of the next input chunk.  Position -1 refers to `last_frame`.
+    position: f64,
+    last_frame: [i16; CHANNELS],
+}
+
+impl Resampler {
+    pub fn new(ratio: f64) -> Self {
+        Self{
+            ratio,
+            position: 0.,
+            last_frame: [0; CHANNELS],
+        }
+    }
+
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    /// Forget about previous input, e.g. after a gap in the audio
+    pub fn reset(&mut self) {
+        self.position = 0.;
+        self.last_frame = [0; CHANNELS];
+    }
+
+    /// Append the resampled frames of `input` to `output`.
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        let num_frames = input.len() / CHANNELS;
+        if num_frames == 0 || !(self.ratio > 0. && self.ratio.is_finite()) {
+            return;
+        }
+        let mut input_last_frame = [0; CHANNELS];
+        input_last_frame.copy_from_slice(
+            &input[(num_frames - 1) * CHANNELS..num_frames * CHANNELS]);
+        if self.ratio == 1. && self.position == 0. {
+            output.extend_from_slice(&input[..num_frames * CHANNELS]);
+            self.last_frame = input_last_frame;
+            return;
+        }
+        let last_frame = self.last_frame;
+        let sample_at = |i: isize, channel: usize| if i < 0 {
+            last_frame[channel]
+        } else {
+            input[i as usize * CHANNELS + channel]
+        };
+        let mut position = self.position;
+        let end = (num_frames - 1) as f64;
+        while position < end {
+            let index = position.floor();
+            let fraction = position - index;
+            let index = index as isize;
+            for channel in 0..CHANNELS {
+                let a = sample_at(index, channel) as f64;
+                let b = sample_at(index + 1, channel) as f64;
+                output.push((a + (b - a) * fraction) as i16);
+            }
+            position += self.ratio;
+        }
+        self.position = position - num_frames as f64;
+        self.last_frame = input_last_frame;
+    }
+}
+
+/// Adapts the APU's sample stream to the current emulation speed
+///
+/// At normal speed, samples are passed on unchanged.  When running
+/// faster or slower than real time, the samples are resampled, so that
+/// the audio sink receives as many samples as it plays back during the
+/// elapsed wall-clock time.  At extreme speeds and while rewinding,
+/// resampled audio would only be garbled noise, so it is muted instead,
+/// with a short fade-out to avoid a click.
+///
+/// Samples are interleaved stereo frames as produced by the APU.
+pub struct AudioPolicy {
+    speed: f64,
+    rewinding: bool,
+    resampler: Resampler,
+    /// Frame that is faded out once the audio gets muted
+    last_frame: [i16; CHANNELS],
+    fade_out_remaining: usize,
+}
+
+impl Default for AudioPolicy {
+    fn default() -> Self {
+        Self{
+            speed: 1.,
+            rewinding: false,
+            resampler: Resampler::new(1.),
+            last_frame: [0; CHANNELS],
+            fade_out_remaining: 0,
+        }
+    }
+}
+
+impl AudioPolicy {
+    /// Set the emulation speed relative to real time
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.resampler.set_ratio(speed);
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding;
+    }
+
+    fn is_muted(&self) -> bool {
+        self.rewinding
+            || self.speed > MAX_RESAMPLED_SPEED
+            || self.speed < MIN_RESAMPLED_SPEED
+    }
+
+    /// Append the samples that should be played for `input` to `output`.
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        let num_frames = input.len() / CHANNELS;
+        if num_frames == 0 || self.speed <= 0. {
+            return;
+        }
+        if !self.is_muted() {
+            self.resampler.process(input, output);
+            self.last_frame.copy_from_slice(
+                &input[(num_frames - 1) * CHANNELS..num_frames * CHANNELS]);
+            self.fade_out_remaining = FADE_OUT_SAMPLES;
+            return;
+        }
+        // As many frames as would be played at this speed, but faded out
+        let num_output_frames = if self.speed.is_finite() {
+            (num_frames as f64 / self.speed) as usize
+        } else {
+            0
+        };
+        for _ in 0..num_output_frames {
+            let frame = self.fade_out();
+            output.extend_from_slice(&frame);
+        }
+        self.resampler.reset();
+        self.last_frame = [0; CHANNELS];
+    }
+
+    fn fade_out(&mut self) -> [i16; CHANNELS] {
+        if self.fade_out_remaining == 0 {
+            return [0; CHANNELS];
+        }
+        self.fade_out_remaining -= 1;
+        self.last_frame.map(|sample| {
+            (sample as isize * self.fade_out_remaining as isize
+             / FADE_OUT_SAMPLES as isize) as i16
+        })
+    }
+}
+
+/// Receives the samples that the APU generates, e.g. to play or record
+/// them
+///
+/// Unlike `IO::queue_audio`, sinks get every sample at the speed of the
+/// emulated Game Boy, whatever speed it runs at.
+pub trait AudioSink: Send {
+    /// Take interleaved stereo samples at `sample_rate`
+    fn push_samples(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// Number of samples per second and channel that the sink takes
+    fn sample_rate(&self) -> u32;
+}
+
+/// A sink that discards all samples
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push_samples(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE as u32
+    }
+}
+
+/// Passes the APU's samples to a sink, resampled to its sample rate
+pub struct ResampledSink {
+    sink: Box<dyn AudioSink>,
+    resampler: Resampler,
+    buffer: Vec<i16>,
+}
+
+impl ResampledSink {
+    pub fn new(sink: Box<dyn AudioSink>) -> Self {
+        let ratio = SAMPLE_RATE as f64 / sink.sample_rate() as f64;
+        Self{
+            sink,
+            resampler: Resampler::new(ratio),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Pass on samples at `apu::SAMPLE_RATE`
+    pub fn push_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        self.sink.push_samples(&self.buffer)
+    }
+}
+
+/// Writes 16 bit PCM samples into a WAV file
+///
+/// The header is updated after every written chunk of samples, so the
+/// file stays valid even if the emulator doesn't shut down cleanly.
+/// http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    channels: u16,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    const HEADER_SIZE: u32 = 44;
+
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16)
+               -> io::Result<Self> {
+        let block_align = channels * 2;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(Self::HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;  // size of fmt chunk
+        writer.write_all(&1u16.to_le_bytes())?;  // PCM format
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;  // bits per sample
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(Self{
+            writer,
+            sample_rate,
+            channels,
+            data_size: 0,
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Append samples, interleaved if there are multiple channels.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(2 * samples.len());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.writer.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        self.update_header()
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(
+            &(Self::HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek + Send> AudioSink for WavWriter<W> {
+    fn push_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.write_samples(samples)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Output of the policy for a second batch of constant input, after
+    /// a first batch has faded out any previous output
+    fn policy_output(speed: f64, rewinding: bool) -> Vec<i16> {
+        let mut policy = AudioPolicy::default();
+        policy.set_speed(speed);
+        policy.set_rewinding(rewinding);
+        let input = [1000; CHANNELS * 4800];
+        let mut output = Vec::new();
+        policy.process(&input, &mut output);
+        output.clear();
+        policy.process(&input, &mut output);
+        output
+    }
+
+    #[test]
+    fn policy_mutes_extreme_speeds_and_rewinding() {
+        for speed in [0.25, 0.5, 1., 2., 4.] {
+            let output = policy_output(speed, false);
+            let frames = output.len() / CHANNELS;
+            assert!(frames.abs_diff((4800. / speed) as usize) <= 1,
+                    "{} frames at speed {}", frames, speed);
+            assert!(output.iter().all(|&sample| sample == 1000),
+                    "speed {}", speed);
+        }
+        for (speed, rewinding) in [(0.2, false), (4.5, false), (1., true)] {
+            let output = policy_output(speed, rewinding);
+            assert_eq!(output.len() / CHANNELS, (4800. / speed) as usize);
+            assert!(output.iter().all(|&sample| sample == 0),
+                    "speed {}", speed);
+        }
+        // Without a throttle, muted audio takes no time at all.
+        assert!(policy_output(f64::INFINITY, false).is_empty());
+    }
+
+    #[test]
+    fn muting_fades_out_the_last_frame() {
+        let mut policy = AudioPolicy::default();
+        let mut output = Vec::new();
+        policy.process(&[1000, -1000], &mut output);
+        assert_eq!(output, [1000, -1000]);
+        output.clear();
+        policy.set_rewinding(true);
+        policy.process(&[500; CHANNELS * 100], &mut output);
+        assert_eq!(output.len(), CHANNELS * 100);
+        assert_eq!(output[..4], [984, -984, 968, -968]);
+        let faded_out = &output[CHANNELS * FADE_OUT_SAMPLES..];
+        assert!(faded_out.iter().all(|&sample| sample == 0));
+    }
+
+    /// Counts the samples it gets at half the APU's sample rate
+    struct HalfRateSink {
+        samples: Arc<Mutex<usize>>,
+    }
+
+    impl AudioSink for HalfRateSink {
+        fn push_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+            *self.samples.lock().unwrap() += samples.len();
+            Ok(())
+        }
+
+        fn sample_rate(&self) -> u32 {
+            SAMPLE_RATE as u32 / 2
+        }
+    }
+
+    #[test]
+    fn sinks_get_samples_at_their_sample_rate() {
+        let count = Arc::new(Mutex::new(0));
+        let mut sink = ResampledSink::new(Box::new(HalfRateSink{
+            samples: count.clone(),
+        }));
+        for _ in 0..10 {
+            sink.push_samples(&[100; 2 * CHANNELS * 800]).unwrap();
+        }
+        assert_eq!(*count.lock().unwrap(), CHANNELS * 8000);
+
+        let mut wav = ResampledSink::new(Box::new(
+            WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE as u32,
+                           CHANNELS as u16).unwrap()));
+        wav.push_samples(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(wav.buffer, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn wav_header_is_updated_with_every_write() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48_000, 2)
+                                    .unwrap();
+        wav.write_samples(&[1, -1]).unwrap();
+        let read_u32 = |bytes: &[u8], offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let bytes = wav.writer.get_ref().clone();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4), 36 + 4);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(&bytes, 16), 16);
+        // PCM with two channels
+        assert_eq!(bytes[20..24], [1, 0, 2, 0]);
+        assert_eq!(read_u32(&bytes, 24), 48_000);
+        // Bytes per second, bytes per frame and bits per sample
+        assert_eq!(read_u32(&bytes, 28), 4 * 48_000);
+        assert_eq!(bytes[32..36], [4, 0, 16, 0]);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(read_u32(&bytes, 40), 4);
+        assert_eq!(bytes[44..], [1, 0, 0xFF, 0xFF]);
+
+        wav.write_samples(&[]).unwrap();
+        wav.write_samples(&[2, 3, 4, 5]).unwrap();
+        let bytes = wav.writer.get_ref();
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(read_u32(bytes, 4), 36 + 12);
+        assert_eq!(read_u32(bytes, 40), 12);
+        assert_eq!(bytes[52..], [4, 0, 5, 0]);
+    }
+
+    /// Keeps the samples it gets at the given sample rate
+    struct RecordingSink {
+        sample_rate: u32,
+        samples: Arc<Mutex<Vec<i16>>>,
+    }
+
+    impl AudioSink for RecordingSink {
+        fn push_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+            self.samples.lock().unwrap().extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+    }
+
+    fn resample_one_second(sample_rate: u32) -> Vec<i16> {
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = ResampledSink::new(Box::new(RecordingSink{
+            sample_rate,
+            samples: samples.clone(),
+        }));
+        // A ramp on the left and a constant on the right channel
+        let input: Vec<i16> = (0..SAMPLE_RATE / 10)
+            .flat_map(|i| [i as i16, 1000])
+            .collect();
+        for _ in 0..10 {
+            sink.push_samples(&input).unwrap();
+        }
+        let samples = samples.lock().unwrap();
+        samples.clone()
+    }
+
+    #[test]
+    fn low_and_high_sample_rates_are_not_muted() {
+        for sample_rate in [8000, 11025, 384_000] {
+            let samples = resample_one_second(sample_rate);
+            let frames = samples.len() / CHANNELS;
+            assert!(frames.abs_diff(sample_rate as usize) <= 10,
+                    "{} frames at {} Hz", frames, sample_rate);
+            assert!(samples.chunks(CHANNELS).all(|frame| frame[1] == 1000),
+                    "{} Hz", sample_rate);
+        }
+        // At 8 kHz every sixth frame of the ramp is taken.
+        let samples = resample_one_second(8000);
+        let left: Vec<i16> = samples.iter().step_by(CHANNELS).copied()
+                                    .take(4).collect();
+        assert_eq!(left, [0, 6, 12, 18]);
+    }
+}
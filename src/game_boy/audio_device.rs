@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{channel, sync_channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, FromSample, SampleFormat, SizedSample,
+           SupportedBufferSize};
+
+use super::apu::CHANNELS;
+use super::audio::AudioSink;
+
+/// Samples that wait for the sound device
+#[derive(Default)]
+struct Queue {
+    samples: VecDeque<i16>,
+    /// Whether enough samples have been queued to start playing
+    playing: bool,
+}
+
+impl Queue {
+    /// Fill a buffer of the sound device, with silence once the queue
+    /// runs empty
+    fn fill<T: SizedSample + FromSample<i16>>(&mut self, buffer: &mut [T],
+                                              channels: usize) {
+        for frame in buffer.chunks_mut(channels) {
+            if self.samples.len() < CHANNELS {
+                self.playing = false;
+            }
+            let mut samples = [0; CHANNELS];
+            if self.playing {
+                for sample in &mut samples {
+                    *sample = self.samples.pop_front().unwrap();
+                }
+            }
+            if channels == 1 {
+                let sum: i32 = samples.iter().map(|&s| s as i32).sum();
+                frame[0] = T::from_sample((sum / CHANNELS as i32) as i16);
+                continue;
+            }
+            for (i, sample) in frame.iter_mut().enumerate() {
+                *sample = T::from_sample(samples.get(i).copied()
+                                                .unwrap_or(0));
+            }
+        }
+    }
+}
+
+/// Plays samples on the default sound device
+///
+/// The stream of the sound device runs on a thread of its own, as it
+/// can't be moved to the emulation thread on all platforms.  It stops
+/// when the sink is dropped.
+pub struct DeviceSink {
+    queue: Arc<Mutex<Queue>>,
+    sample_rate: u32,
+    /// Samples that are queued before playback starts
+    latency: usize,
+    /// Dropped to stop the stream
+    _stop: Sender<()>,
+}
+
+impl DeviceSink {
+    /// Open the default sound device
+    ///
+    /// Playback starts once `latency` worth of audio has been queued.
+    /// More queued audio survives longer hiccups of the emulation without
+    /// crackling, but delays the sound.  `buffer_size` is the number of
+    /// frames that the device asks for at once, by default chosen by the
+    /// device.  Larger buffers also trade latency for fewer crackles.
+    pub fn open(latency: Duration, buffer_size: Option<u32>)
+            -> io::Result<Self> {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let stream_queue = queue.clone();
+        let (stop, stopped) = channel::<()>();
+        let (opened, result) = sync_channel(1);
+        thread::spawn(move || {
+            match open_stream(stream_queue, buffer_size) {
+                Ok((stream, sample_rate)) => {
+                    let _ = opened.send(Ok(sample_rate));
+                    // Keep playing until the sink is dropped.
+                    let _ = stopped.recv();
+                    drop(stream);
+                }
+                Err(error) => {
+                    let _ = opened.send(Err(error));
+                }
+            }
+        });
+        let sample_rate = result.recv().map_err(io::Error::other)??;
+        // The device has to find at least one buffer in the queue.
+        let frames = ((sample_rate as f64 * latency.as_secs_f64()) as usize)
+                     .max(buffer_size.unwrap_or(0) as usize);
+        Ok(Self{
+            queue,
+            sample_rate,
+            latency: frames * CHANNELS,
+            _stop: stop,
+        })
+    }
+}
+
+impl AudioSink for DeviceSink {
+    /// Queue samples for playback
+    ///
+    /// If the emulation gets ahead of the sound device, the oldest
+    /// samples are dropped to keep the delay of the sound bounded.
+    fn push_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.samples.extend(samples);
+        if queue.samples.len() >= self.latency {
+            queue.playing = true;
+        }
+        let max_queued = 2 * self.latency;
+        if queue.samples.len() > max_queued {
+            let excess = queue.samples.len() - max_queued;
+            queue.samples.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+fn open_stream(queue: Arc<Mutex<Queue>>, buffer_size: Option<u32>)
+        -> io::Result<(cpal::Stream, u32)> {
+    let device = cpal::default_host().default_output_device()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                                      "no sound device found"))?;
+    let supported = device.default_output_config()
+                          .map_err(io::Error::other)?;
+    let mut config = supported.config();
+    if let Some(frames) = buffer_size {
+        if let SupportedBufferSize::Range{min, max}
+                = *supported.buffer_size() {
+            if !(min..=max).contains(&frames) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("buffer size {} is not between {} and {}",
+                            frames, min, max)));
+            }
+        }
+        config.buffer_size = BufferSize::Fixed(frames);
+    }
+    let stream = match supported.sample_format() {
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, queue),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, queue),
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, queue),
+        format => {
+            return Err(io::Error::other(
+                format!("unsupported sample format {}", format)));
+        }
+    }?;
+    stream.play().map_err(io::Error::other)?;
+    Ok((stream, config.sample_rate.0))
+}
+
+fn build_stream<T: SizedSample + FromSample<i16>>(
+        device: &cpal::Device, config: &cpal::StreamConfig,
+        queue: Arc<Mutex<Queue>>) -> io::Result<cpal::Stream> {
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |buffer: &mut [T], _| queue.lock().unwrap().fill(buffer,
+                                                               channels),
+        |error| log::error!("Sound output failed: {}", error),
+        None,
+    ).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_waits_until_it_is_filled() {
+        let mut queue = Queue::default();
+        queue.samples.extend([1000, -1000, 2000, -2000]);
+        let mut buffer = [0.5f32; 4];
+        queue.fill(&mut buffer, 2);
+        assert_eq!(buffer, [0.; 4]);
+        assert_eq!(queue.samples.len(), 4);
+
+        queue.playing = true;
+        let mut buffer = [0i16; 6];
+        queue.fill(&mut buffer, 2);
+        assert_eq!(buffer, [1000, -1000, 2000, -2000, 0, 0]);
+        assert!(!queue.playing);
+
+        // Mono devices get the average of both channels, surround
+        // devices silence on the other speakers.
+        queue.samples.extend([1000, 3000, 1000, 3000]);
+        queue.playing = true;
+        let mut buffer = [0i16; 1];
+        queue.fill(&mut buffer, 1);
+        assert_eq!(buffer, [2000]);
+        let mut buffer = [1i16; 4];
+        queue.fill(&mut buffer, 4);
+        assert_eq!(buffer, [1000, 3000, 0, 0]);
+    }
+}
@@ -4,33 +4,771 @@
 
 use std::io;
 use std::io::Read;
-use std::fs::File;
 
-pub fn load_boot_rom(mut file: File) -> io::Result<[u8; 0x100]> {
-    let mut rom = [0; 0x100];
-    file.read_exact(&mut rom)?;
-    Ok(rom)
+use serde::{Deserialize, Serialize};
+
+use super::cartridge::{CartridgeHeader, ColorCompat};
+use super::colorization;
+
+/// Size of the boot ROMs of DMG, MGB and SGB
+const BOOT_ROM_SIZE: usize = 0x100;
+/// Size of the boot ROMs of CGB and AGB
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+/// A boot ROM that is mapped over the start of the cartridge ROM until
+/// it disables itself
+///
+/// The boot ROMs of the CGB and AGB are 0x900 bytes large, but their
+/// bytes 0x100–0x1FF are never mapped, so that the cartridge header
+/// stays visible.
+#[derive(Serialize, Deserialize)]
+pub struct BootRom {
+    rom: Vec<u8>,
+}
+
+impl From<[u8; BOOT_ROM_SIZE]> for BootRom {
+    fn from(rom: [u8; BOOT_ROM_SIZE]) -> Self {
+        Self{rom: rom.to_vec()}
+    }
+}
+
+impl BootRom {
+    /// Byte of the boot ROM at the given address, or None if the
+    /// cartridge is visible there
+    pub fn read8(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0100..=0x01FF => None,
+            _ => self.rom.get(address as usize).copied(),
+        }
+    }
+}
+
+/// Load a boot ROM of 0x100 bytes or a CGB boot ROM of 0x900 bytes
+///
+/// It can be read from a file, a byte slice or any other reader.
+pub fn load_boot_rom(mut reader: impl Read) -> io::Result<BootRom> {
+    let mut rom = Vec::with_capacity(CGB_BOOT_ROM_SIZE);
+    reader.read_to_end(&mut rom)?;
+    match rom.len() {
+        BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => Ok(BootRom{rom}),
+        size => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Boot ROM has {:#X} bytes instead of 0x100 or 0x900.",
+                    size))),
+    }
+}
+
+/// Hardware models that differ in their state after boot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Model {
+    /// Original Game Boy
+    #[default]
+    DMG,
+    /// Game Boy Pocket
+    MGB,
+    /// Super Game Boy
+    SGB,
+    /// Game Boy Color
+    CGB,
+}
+
+impl Model {
+    /// Whether writing STAT briefly enables all STAT interrupt sources
+    ///
+    /// This can request a STAT interrupt even if the written value
+    /// doesn't enable the condition that currently holds.
+    pub fn has_stat_write_bug(self) -> bool {
+        self != Self::CGB
+    }
+
+    /// Value read from the unused area FEA0-FEFF after OAM
+    ///
+    /// The original models return 0, while the CGB repeats the upper
+    /// nibble of the lower byte of the address.
+    pub fn unused_oam_area_read8(self, address: u16) -> u8 {
+        match self {
+            Self::CGB => {
+                let nibble = (address as u8) & 0xF0;
+                nibble | (nibble >> 4)
+            }
+            _ => 0x00,
+        }
+    }
+}
+
+/// CPU registers after the boot ROM has finished
+///
+/// SP is always 0xFFFE and PC 0x0100.
+/// https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PostBootCpuRegisters {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
 }
 
-pub fn fast_boot_rom() -> [u8; 0x100] {
+/// Register values that the boot ROM of the given model leaves behind
+///
+/// Games check register A to detect the hardware they run on: It is
+/// 0x01 on DMG and SGB, 0xFF on MGB and 0x11 on CGB.
+pub fn post_boot_cpu_registers(model: Model, header: &CartridgeHeader)
+        -> PostBootCpuRegisters {
+    // On DMG and MGB, the half carry and carry flags are set
+    // unless the header checksum is 0.
+    let flags = if header.header_checksum() == 0 { 0x80 } else { 0xB0 };
+    match model {
+        Model::DMG => PostBootCpuRegisters{
+            af: 0x0100 | flags,
+            bc: 0x0013,
+            de: 0x00D8,
+            hl: 0x014D,
+        },
+        Model::MGB => PostBootCpuRegisters{
+            af: 0xFF00 | flags,
+            bc: 0x0013,
+            de: 0x00D8,
+            hl: 0x014D,
+        },
+        Model::SGB => PostBootCpuRegisters{
+            af: 0x0100,
+            bc: 0x0014,
+            de: 0x0000,
+            hl: 0xC060,
+        },
+        Model::CGB => match header.color_compat() {
+            ColorCompat::CGBcompat | ColorCompat::CGBonly
+                    => PostBootCpuRegisters{
+                af: 0x1180,
+                bc: 0x0000,
+                de: 0xFF56,
+                hl: 0x000D,
+            },
+            _ => PostBootCpuRegisters{
+                af: 0x1180,
+                // B holds the title checksum used for colorization.
+                bc: (colorization::title_checksum(header).unwrap_or(0)
+                     as u16) << 8,
+                de: 0x0008,
+                hl: 0x007C,
+            },
+        },
+    }
+}
+
+/// A boot ROM that skips the logo animation
+///
+/// It sets up the CPU and I/O registers like the boot ROM of the given
+/// model, except for the DMA register, as writing it would start an OAM
+/// DMA transfer, and the divider, which can only be reset.
+pub fn fast_boot_rom(model: Model, header: &CartridgeHeader) -> [u8; 0x100] {
+    let mut code = Vec::with_capacity(0x100);
+    // LD SP, 0xFFFE
+    code.extend([0x31, 0xFE, 0xFF]);
+    for (address, value) in post_boot_io_registers(model) {
+        if address == 0xFF46 || address == 0xFF50 {
+            continue;
+        }
+        // LD A, value
+        // LD (address & 0xFF), A
+        code.extend([0x3E, value, 0xE0, address as u8]);
+    }
+    let registers = post_boot_cpu_registers(model, header);
+    // LD BC, AF
+    // PUSH BC
+    // POP AF
+    let [f, a] = registers.af.to_le_bytes();
+    code.extend([0x01, f, a, 0xC5, 0xF1]);
+    // LD BC, BC
+    let [c, b] = registers.bc.to_le_bytes();
+    code.extend([0x01, c, b]);
+    // LD DE, DE
+    let [e, d] = registers.de.to_le_bytes();
+    code.extend([0x11, e, d]);
+    // LD HL, HL
+    let [l, h] = registers.hl.to_le_bytes();
+    code.extend([0x21, l, h]);
+    // JP 0x00FE
+    code.extend([0xC3, 0xFE, 0x00]);
+
     let mut rom = [0; 0x100];
+    rom[..code.len()].copy_from_slice(&code);
+    // Disable boot ROM by writing A to 0xFF50. Bit 0 of A
+    // is set on all models, so this keeps A intact.
+    // LD (0x50), A
+    rom[0xFE] = 0xE0;
+    rom[0xFF] = 0x50;
+
+    rom
+}
+
+/// Offset of a relative jump from the end of the jump instruction at
+/// `end` to `target`
+fn jr_offset(end: usize, target: usize) -> u8 {
+    (target as isize - end as isize) as i8 as u8
+}
+
+/// I/O registers that the logo boot ROM sets up before the logo scrolls
+/// in: sound, palette, scroll position and LCD control
+const LOGO_SETUP_REGISTERS: [(u8, u8); 8] = [
+    (0x26, 0x80), // NR52
+    (0x11, 0x80), // NR11
+    (0x12, 0xF3), // NR12
+    (0x25, 0xF3), // NR51
+    (0x24, 0x77), // NR50
+    (0x47, 0xFC), // BGP
+    (0x42, 0x64), // SCY
+    (0x40, 0x91), // LCDC
+];
+
+/// A boot ROM that shows the logo animation of the original Game Boy
+///
+/// Like the original boot ROM, it clears VRAM, draws the logo from the
+/// cartridge header and scrolls it down the screen before playing the
+/// two tones of the boot sound.  It hangs if the header checksum is
+/// wrong.  Unlike the original, it doesn't compare the logo with a
+/// copy of its own.
+///
+/// Afterwards, the CPU registers are set up like the boot ROM of the
+/// given model.  The I/O registers that the original boot ROM doesn't
+/// touch keep their power on values, except for the divider, which
+/// depends on how long the boot took.
+pub fn logo_boot_rom(model: Model, header: &CartridgeHeader) -> [u8; 0x100] {
+    let mut code = Vec::with_capacity(0x100);
     // LD SP, 0xFFFE
-    rom[0x00] = 0x31;
-    rom[0x01] = 0xFE;
-    rom[0x02] = 0xFF;
-
-    // JP 0x00FC
-    rom[0x03] = 0xC3;
-    rom[0x04] = 0xFC;
-    rom[0x05] = 0x00;
-
-    // Disable boot ROM by writing 0x01 to 0xFF50
-    // LD A, 0x01
-    rom[0xFC] = 0x3E;
-    rom[0xFD] = 0x01;
+    code.extend([0x31, 0xFE, 0xFF]);
+    // JR main, patched below as the subroutines come first
+    code.extend([0x18, 0x00]);
+
+    // Double each of the upper four bits of C into A and write A into
+    // two consecutive lines of the tile at HL.  Entering at
+    // double_upper_nibble sets C to A first.
+    let double_upper_nibble = code.len();
+    // LD C, A
+    code.push(0x4F);
+    let double_lower_nibble = code.len();
+    // LD B, 4
+    code.extend([0x06, 0x04]);
+    let double_bit = code.len();
+    // PUSH BC
+    // RL C
+    // RLA
+    // POP BC
+    // RL C
+    // RLA
+    // DEC B
+    code.extend([0xC5, 0xCB, 0x11, 0x17, 0xC1, 0xCB, 0x11, 0x17, 0x05]);
+    // JR NZ, double_bit
+    code.extend([0x20, jr_offset(code.len() + 2, double_bit)]);
+    // LD (HL+), A
+    // INC HL
+    // LD (HL+), A
+    // INC HL
+    // RET
+    code.extend([0x22, 0x23, 0x22, 0x23, 0xC9]);
+
+    // Wait until E frames have started drawing the VBlank line 144
+    let wait_frames = code.len();
+    // LDH A, (LY)
+    // CP 0x90
+    code.extend([0xF0, 0x44, 0xFE, 0x90]);
+    // JR NZ, wait_frames
+    code.extend([0x20, jr_offset(code.len() + 2, wait_frames)]);
+    let leave_line = code.len();
+    // LDH A, (LY)
+    // CP 0x90
+    code.extend([0xF0, 0x44, 0xFE, 0x90]);
+    // JR Z, leave_line
+    code.extend([0x28, jr_offset(code.len() + 2, leave_line)]);
+    // DEC E
+    code.push(0x1D);
+    // JR NZ, wait_frames
+    code.extend([0x20, jr_offset(code.len() + 2, wait_frames)]);
+    // RET
+    code.push(0xC9);
+
+    // Write the B pairs of I/O register and value at HL
+    let write_registers = code.len();
+    // LD A, (HL+)
+    // LD C, A
+    // LD A, (HL+)
+    // LD (C), A
+    // DEC B
+    code.extend([0x2A, 0x4F, 0x2A, 0xE2, 0x05]);
+    // JR NZ, write_registers
+    code.extend([0x20, jr_offset(code.len() + 2, write_registers)]);
+    // RET
+    code.push(0xC9);
+
+    let trademark_tile = code.len();
+    code.extend(REGISTERED_TRADEMARK_TILE);
+    let setup_registers = code.len();
+    for (register, value) in LOGO_SETUP_REGISTERS {
+        code.extend([register, value]);
+    }
+
+    let main = code.len();
+    code[4] = jr_offset(5, main);
+    // Clear VRAM
+    // XOR A
+    // LD HL, 0x9FFF
+    code.extend([0xAF, 0x21, 0xFF, 0x9F]);
+    let clear_vram = code.len();
+    // LD (HL-), A
+    // BIT 7, H
+    code.extend([0x32, 0xCB, 0x7C]);
+    // JR NZ, clear_vram
+    code.extend([0x20, jr_offset(code.len() + 2, clear_vram)]);
+
+    // Draw the logo from the cartridge header into the tiles 0x01–0x18,
+    // see draw_logo_into_vram.
+    // LD DE, 0x0104
+    // LD HL, 0x8010
+    code.extend([0x11, 0x04, 0x01, 0x21, 0x10, 0x80]);
+    let draw_logo = code.len();
+    // LD A, (DE)
+    // CALL double_upper_nibble
+    // CALL double_lower_nibble
+    // INC DE
+    // LD A, E
+    // CP 0x34
+    code.extend([0x1A, 0xCD, double_upper_nibble as u8, 0x00,
+                 0xCD, double_lower_nibble as u8, 0x00,
+                 0x13, 0x7B, 0xFE, 0x34]);
+    // JR NZ, draw_logo
+    code.extend([0x20, jr_offset(code.len() + 2, draw_logo)]);
+    // HL now points at tile 0x19, which gets the ® symbol.
+    // LD DE, trademark_tile
+    // LD B, 8
+    code.extend([0x11, trademark_tile as u8, 0x00, 0x06, 0x08]);
+    let copy_trademark = code.len();
+    // LD A, (DE)
+    // INC DE
+    // LD (HL+), A
+    // INC HL
+    // DEC B
+    code.extend([0x1A, 0x13, 0x22, 0x23, 0x05]);
+    // JR NZ, copy_trademark
+    code.extend([0x20, jr_offset(code.len() + 2, copy_trademark)]);
+
+    // Fill the tile map from the end of the lower half of the logo.
+    // LD A, 0x19
+    // LD (0x9910), A
+    // LD HL, 0x992F
+    code.extend([0x3E, 0x19, 0xEA, 0x10, 0x99, 0x21, 0x2F, 0x99]);
+    let map_row = code.len();
+    // LD C, 12
+    code.extend([0x0E, 0x0C]);
+    let map_entry = code.len();
+    // DEC A
+    // JR Z, map_done, patched below
+    // LD (HL-), A
+    // DEC C
+    code.extend([0x3D, 0x28, 0x00, 0x32, 0x0D]);
+    // JR NZ, map_entry
+    code.extend([0x20, jr_offset(code.len() + 2, map_entry)]);
+    // LD L, 0x0F
+    code.extend([0x2E, 0x0F]);
+    // JR map_row
+    code.extend([0x18, jr_offset(code.len() + 2, map_row)]);
+    code[map_entry + 2] = jr_offset(map_entry + 3, code.len());
+
+    // LD HL, setup_registers
+    // LD B, number of registers
+    // CALL write_registers
+    code.extend([0x21, setup_registers as u8, 0x00,
+                 0x06, LOGO_SETUP_REGISTERS.len() as u8,
+                 0xCD, write_registers as u8, 0x00]);
+
+    // Scroll the logo down by one line every other frame.
+    let scroll = code.len();
+    // LD E, 2
+    // CALL wait_frames
+    // LDH A, (SCY)
+    // DEC A
+    // LDH (SCY), A
+    code.extend([0x1E, 0x02, 0xCD, wait_frames as u8, 0x00,
+                 0xF0, 0x42, 0x3D, 0xE0, 0x42]);
+    // JR NZ, scroll
+    code.extend([0x20, jr_offset(code.len() + 2, scroll)]);
+
+    // The SGB boot ROM doesn't play a sound.
+    if model != Model::SGB {
+        // LD A, 0x83
+        // LDH (NR13), A
+        // LD A, 0x87
+        // LDH (NR14), A
+        code.extend([0x3E, 0x83, 0xE0, 0x13, 0x3E, 0x87, 0xE0, 0x14]);
+        // LD E, 8
+        // CALL wait_frames
+        code.extend([0x1E, 0x08, 0xCD, wait_frames as u8, 0x00]);
+        // LD A, 0xC1
+        // LDH (NR13), A
+        // LD A, 0x87
+        // LDH (NR14), A
+        code.extend([0x3E, 0xC1, 0xE0, 0x13, 0x3E, 0x87, 0xE0, 0x14]);
+    }
+    // Let the sound fade out.
+    // LD E, 60
+    // CALL wait_frames
+    code.extend([0x1E, 0x3C, 0xCD, wait_frames as u8, 0x00]);
+
+    // Hang unless the header checksum is correct, i.e. 0x19 plus the
+    // sum of the bytes 0x0134–0x014D is 0.
+    // LD HL, 0x0134
+    // LD B, 0x19
+    // LD A, B
+    code.extend([0x21, 0x34, 0x01, 0x06, 0x19, 0x78]);
+    let checksum = code.len();
+    // ADD (HL)
+    // INC L
+    // DEC B
+    code.extend([0x86, 0x2C, 0x05]);
+    // JR NZ, checksum
+    code.extend([0x20, jr_offset(code.len() + 2, checksum)]);
+    // ADD (HL)
+    // JR NZ, 0xFE
+    code.extend([0x86, 0x20, 0xFE]);
+
+    let registers = post_boot_cpu_registers(model, header);
+    // LD BC, AF
+    // PUSH BC
+    // POP AF
+    let [f, a] = registers.af.to_le_bytes();
+    code.extend([0x01, f, a, 0xC5, 0xF1]);
+    // LD BC, BC
+    let [c, b] = registers.bc.to_le_bytes();
+    code.extend([0x01, c, b]);
+    // LD DE, DE
+    let [e, d] = registers.de.to_le_bytes();
+    code.extend([0x11, e, d]);
+    // LD HL, HL
+    let [l, h] = registers.hl.to_le_bytes();
+    code.extend([0x21, l, h]);
+    // JP 0x00FE
+    code.extend([0xC3, 0xFE, 0x00]);
+    assert!(code.len() <= 0xFE, "Logo boot ROM is too large.");
+
+    let mut rom = [0; 0x100];
+    rom[..code.len()].copy_from_slice(&code);
     // LD (0x50), A
     rom[0xFE] = 0xE0;
     rom[0xFF] = 0x50;
 
     rom
 }
+
+/// I/O register values of the given model after its boot ROM has finished
+///
+/// The values are written in this order on a high-level emulated boot.
+pub fn post_boot_io_registers(model: Model) -> Vec<(u16, u8)> {
+    DMG_POST_BOOT_IO_REGISTERS
+        .iter()
+        .copied()
+        // The SGB boot ROM doesn't play a sound, so channel 1 stays off.
+        .filter(|&register| model != Model::SGB
+                            || !matches!(register, (0xFF12, 0x08)
+                                                   | (0xFF14, 0x80)))
+        .map(|(address, value)| match (model, address) {
+            (Model::CGB, 0xFF02) => (address, 0x7F), // SC
+            (Model::CGB, 0xFF46) => (address, 0x00), // DMA
+            _ => (address, value),
+        })
+        .collect()
+}
+
+/// I/O register values of a DMG after its boot ROM has finished
+///
+/// The values are written in this order on a high-level emulated boot.
+/// https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+pub const DMG_POST_BOOT_IO_REGISTERS: [(u16, u8); 38] = [
+    (0xFF01, 0x00), // SB
+    (0xFF02, 0x7E), // SC
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF26, 0x80), // NR52: power on, channel bits are read-only
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    // The boot ROM's sound on channel 1 has faded out by now,
+    // so trigger it with zero volume to leave it enabled but silent.
+    (0xFF12, 0x08), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0x80), // NR14
+    (0xFF12, 0xF3), // NR12
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF46, 0xFF), // DMA
+    (0xFF47, 0xFC), // BGP
+    (0xFF4A, 0x00), // WY
+    (0xFF4B, 0x00), // WX
+    (0xFF50, 0x01), // Boot ROM disabled
+    (0xFFFF, 0x00), // IE
+];
+
+/// Value of the DMG's internal 16 bit divider counter after boot
+///
+/// Its upper 8 bits are visible in the DIV register.  The divider of
+/// the other models depends on the boot duration, which is not
+/// emulated, so they use the same value.
+pub const DMG_POST_BOOT_DIVIDER: u16 = 0xABCC;
+
+/// The ® symbol that the boot ROM draws next to the logo
+const REGISTERED_TRADEMARK_TILE: [u8; 8] = [
+    0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C,
+];
+
+/// Draw the cartridge's logo into VRAM like the boot ROM does
+///
+/// `logo` are the 0x30 logo bytes from the cartridge header and `vram`
+/// is the memory range 0x8000–0x9FFF.
+///
+/// Each nibble of the logo is scaled up to a byte and written into two
+/// consecutive lines of the tiles 0x01–0x18, followed by the ® symbol
+/// as tile 0x19. These tiles are then placed in the middle of the
+/// background map.
+pub fn draw_logo_into_vram(logo: &[u8], vram: &mut [u8]) {
+    fn double_bits(nibble: u8) -> u8 {
+        let mut doubled = 0;
+        for i in 0..4 {
+            if nibble & (1 << i) != 0 {
+                doubled |= 0b11 << (2 * i);
+            }
+        }
+        doubled
+    }
+    let mut address = 0x0010;
+    for byte in logo {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            let line = double_bits(nibble);
+            vram[address] = line;
+            vram[address + 2] = line;
+            address += 4;
+        }
+    }
+    let mut address = 0x0190;
+    for line in REGISTERED_TRADEMARK_TILE {
+        vram[address] = line;
+        address += 2;
+    }
+    // Tile map: upper half of the logo in 0x9904–0x990F,
+    // lower half in 0x9924–0x992F and the ® symbol at 0x9910.
+    for i in 0..12 {
+        vram[0x1904 + i] = 0x01 + i as u8;
+        vram[0x1924 + i] = 0x0D + i as u8;
+    }
+    vram[0x1910] = 0x19;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::cartridge::Cartridge;
+    use crate::game_boy::compat::NoWindow;
+    use crate::game_boy::GameBoy;
+
+    /// A cartridge with a made-up logo that loops at its entry point
+    fn looping_cartridge(header_checksum_offset: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        // JR 0x0100
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        for (i, byte) in rom[0x104..0x134].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(0x3B);
+        }
+        rom[0x134..0x138].copy_from_slice(b"LOGO");
+        let checksum = rom[0x134..0x14D].iter()
+            .fold(0u8, |x, &y| x.wrapping_sub(y).wrapping_sub(1));
+        rom[0x14D] = checksum.wrapping_add(header_checksum_offset);
+        Cartridge::from_rom(rom).unwrap()
+    }
+
+    fn boot_with_logo(model: Model, cartridge: Cartridge) -> GameBoy<NoWindow> {
+        let rom = logo_boot_rom(model, &cartridge.header());
+        let mut game_boy = GameBoy::new(rom.into(), cartridge, NoWindow);
+        game_boy.memory.set_model(model);
+        game_boy.run_frames(300);
+        game_boy
+    }
+
+    #[test]
+    fn cgb_boot_rom_leaves_cartridge_header_visible() {
+        let bytes: Vec<u8> = (0..CGB_BOOT_ROM_SIZE).map(|i| (i >> 8) as u8)
+                                                   .collect();
+        let rom = load_boot_rom(&bytes[..]).unwrap();
+        assert_eq!(rom.read8(0x00FF), Some(0x00));
+        assert_eq!(rom.read8(0x0100), None);
+        assert_eq!(rom.read8(0x01FF), None);
+        assert_eq!(rom.read8(0x0200), Some(0x02));
+        assert_eq!(rom.read8(0x08FF), Some(0x08));
+        assert_eq!(rom.read8(0x0900), None);
+        let rom = BootRom::from([0xAA; BOOT_ROM_SIZE]);
+        assert_eq!(rom.read8(0x00FF), Some(0xAA));
+        assert_eq!(rom.read8(0x0200), None);
+    }
+
+    #[test]
+    fn boot_rom_of_wrong_size_is_rejected() {
+        let error = load_boot_rom(&[0u8; 0x200][..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn logo_boot_rom_leaves_post_boot_state() {
+        let booted = boot_with_logo(Model::DMG, looping_cartridge(0));
+        let hle = GameBoy::with_hle_boot(Model::DMG, looping_cartridge(0),
+                                         NoWindow);
+        assert_eq!(booted.cpu.state(), hle.cpu.state());
+        // The DMA register keeps its power on value.
+        for (address, _) in post_boot_io_registers(Model::DMG).into_iter()
+                                .filter(|&(address, _)| address != 0xFF46) {
+            assert_eq!(booted.memory.read8(address), hle.memory.read8(address),
+                       "{:0>4X}", address);
+        }
+        for address in 0x8000..0xA000 {
+            assert_eq!(booted.memory.ppu_read8(address),
+                       hle.memory.ppu_read8(address),
+                       "{:0>4X}", address);
+        }
+    }
+
+    /// Registers after the boot ROM as documented in the Pan Docs
+    ///
+    /// https://gbdev.io/pandocs/Power_Up_Sequence.html
+    const DMG_POST_BOOT_READS: [(u16, u8); 37] = [
+        (0xFF01, 0x00), (0xFF02, 0x7E), (0xFF04, 0xAB), (0xFF05, 0x00),
+        (0xFF06, 0x00), (0xFF07, 0xF8), (0xFF0F, 0xE1), (0xFF10, 0x80),
+        (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+        (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+        (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF),
+        (0xFF1E, 0xBF), (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00),
+        (0xFF23, 0xBF), (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+        (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+        (0xFF46, 0xFF), (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00),
+        (0xFFFF, 0x00),
+    ];
+
+    /// A cartridge whose header checksum byte is the given value
+    fn cartridge_with_header_checksum(checksum: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = checksum;
+        Cartridge::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn hle_boot_sets_cpu_registers() {
+        let registers = |model, checksum| {
+            let game_boy = GameBoy::with_hle_boot(
+                model, cartridge_with_header_checksum(checksum), NoWindow);
+            let state = game_boy.cpu.state();
+            ([state.a, state.f, state.b, state.c, state.d, state.e, state.h,
+              state.l], state.sp, state.pc)
+        };
+        let dmg = [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D];
+        assert_eq!(registers(Model::DMG, 0x42), (dmg, 0xFFFE, 0x0100));
+        // Half carry and carry are only cleared by a checksum of 0.
+        let mut dmg_zero_checksum = dmg;
+        dmg_zero_checksum[1] = 0x80;
+        assert_eq!(registers(Model::DMG, 0x00).0, dmg_zero_checksum);
+        assert_eq!(registers(Model::MGB, 0x42).0[..2], [0xFF, 0xB0]);
+        assert_eq!(registers(Model::MGB, 0x00).0[..2], [0xFF, 0x80]);
+        assert_eq!(registers(Model::SGB, 0x42).0,
+                   [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60]);
+    }
+
+    #[test]
+    fn cgb_boot_registers_depend_on_color_support() {
+        let cgb_registers = |cgb_flag, licensee| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x134..0x13B].copy_from_slice(b"TETRIS\0");
+            rom[0x143] = cgb_flag;
+            rom[0x14B] = licensee;
+            let cartridge = Cartridge::from_rom(rom).unwrap();
+            post_boot_cpu_registers(Model::CGB, &cartridge.header())
+        };
+        let cgb_game = PostBootCpuRegisters{
+            af: 0x1180, bc: 0x0000, de: 0xFF56, hl: 0x000D,
+        };
+        assert_eq!(cgb_registers(0x80, 0x01), cgb_game);
+        assert_eq!(cgb_registers(0xC0, 0x01), cgb_game);
+        // For DMG games, B holds the title checksum of Nintendo games.
+        let title_checksum = b"TETRIS".iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        assert_eq!(cgb_registers(0x00, 0x01), PostBootCpuRegisters{
+            af: 0x1180, bc: (title_checksum as u16) << 8, de: 0x0008,
+            hl: 0x007C,
+        });
+        assert_eq!(cgb_registers(0x00, 0x33).bc, 0x0000);
+    }
+
+    #[test]
+    fn fast_boot_rom_leaves_post_boot_state() {
+        for model in [Model::DMG, Model::MGB, Model::SGB, Model::CGB] {
+            let cartridge = looping_cartridge(0);
+            let rom = fast_boot_rom(model, &cartridge.header());
+            let mut booted = GameBoy::new(rom.into(), cartridge, NoWindow);
+            booted.memory.set_model(model);
+            booted.run_frames(1);
+            let hle = GameBoy::with_hle_boot(model, looping_cartridge(0),
+                                             NoWindow);
+            assert_eq!(booted.cpu.state(), hle.cpu.state(), "{:?}", model);
+            // The fast boot ROM can't set up the divider and DMA.
+            for (address, _) in post_boot_io_registers(model).into_iter()
+                    .filter(|&(address, _)| address != 0xFF04
+                                            && address != 0xFF46) {
+                assert_eq!(booted.memory.read8(address),
+                           hle.memory.read8(address),
+                           "{:?} {:0>4X}", model, address);
+            }
+        }
+    }
+
+    #[test]
+    fn hle_boot_sets_io_registers() {
+        let game_boy = GameBoy::with_hle_boot(
+            Model::DMG, cartridge_with_header_checksum(0), NoWindow);
+        for (address, value) in DMG_POST_BOOT_READS {
+            assert_eq!(game_boy.memory.read8(address), value,
+                       "{:0>4X}", address);
+        }
+        // The SGB boot ROM doesn't trigger channel 1.
+        let sgb = post_boot_io_registers(Model::SGB);
+        assert!(!sgb.contains(&(0xFF14, 0x80)));
+        assert_eq!(sgb.len(), DMG_POST_BOOT_IO_REGISTERS.len() - 2);
+        let cgb = post_boot_io_registers(Model::CGB);
+        assert!(cgb.contains(&(0xFF02, 0x7F)));
+        assert!(cgb.contains(&(0xFF46, 0x00)));
+        // The APU has to be powered on before its registers can be
+        // written, and the boot ROM is only disabled once it is done.
+        for model in [Model::DMG, Model::SGB, Model::CGB] {
+            let registers = post_boot_io_registers(model);
+            let position = |address| registers.iter()
+                .position(|&(a, _)| a == address).unwrap();
+            assert!(position(0xFF26) < position(0xFF10));
+            assert!(position(0xFF50) > position(0xFF4B));
+        }
+    }
+
+    #[test]
+    fn logo_boot_rom_hangs_on_wrong_header_checksum() {
+        let booted = boot_with_logo(Model::DMG, looping_cartridge(1));
+        assert!(booted.cpu.pc() < 0x100);
+    }
+}
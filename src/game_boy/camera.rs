@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Size of the pictures taken by the Game Boy Camera
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+/// Number of photos that fit into the RAM of the Game Boy Camera
+pub const NUM_PHOTOS: usize = 30;
+
+/// Size of a picture in RAM as 2 bit per pixel tile data
+const PICTURE_SIZE: usize = SENSOR_WIDTH * SENSOR_HEIGHT / 4;
+/// Where captured pictures are written to RAM bank 0
+const CAPTURE_OFFSET: usize = 0x0100;
+
+/// Strength of the edge enhancement, selected by bits 4–6 of A004
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+/// Whatever is in front of the lens of a Game Boy Camera
+///
+/// A webcam can be used by implementing this for its frames.
+pub trait CameraSource: Send {
+    /// Take a picture of `SENSOR_WIDTH` x `SENSOR_HEIGHT` pixels, stored
+    /// line by line, with brightness values from 0 (black) to 255
+    /// (white)
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+impl<T: CameraSource + ?Sized> CameraSource for Box<T> {
+    fn capture(&mut self) -> Vec<u8> {
+        (**self).capture()
+    }
+}
+
+/// A picture that stays in front of the camera
+pub struct StillImage {
+    pixels: Vec<u8>,
+}
+
+impl Default for StillImage {
+    /// A uniformly gray picture
+    fn default() -> Self {
+        Self{
+            pixels: vec![0x80; SENSOR_WIDTH * SENSOR_HEIGHT],
+        }
+    }
+}
+
+impl StillImage {
+    /// Load a PNG file, converted to grayscale
+    ///
+    /// The image is scaled to cover the sensor, cutting off its edges if
+    /// it doesn't have the sensor's aspect ratio.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(
+            png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(invalid_data)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(invalid_data)?;
+        let channels = info.color_type.samples();
+        let gray: Vec<u8> = buffer[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|pixel| match pixel {
+                [y] | [y, _] => *y,
+                [r, g, b, ..] => ((299 * *r as u32 + 587 * *g as u32
+                                   + 114 * *b as u32) / 1000) as u8,
+                [] => unreachable!(),
+            })
+            .collect();
+        Ok(Self::from_grayscale(info.width as usize, info.height as usize,
+                                &gray))
+    }
+
+    /// Scale a grayscale image of the given size to cover the sensor
+    pub fn from_grayscale(width: usize, height: usize, gray: &[u8]) -> Self {
+        // Scale by the smaller factor and center the image.
+        let scale = f32::min(width as f32 / SENSOR_WIDTH as f32,
+                             height as f32 / SENSOR_HEIGHT as f32);
+        let left = (width as f32 - scale * SENSOR_WIDTH as f32) / 2.0;
+        let top = (height as f32 - scale * SENSOR_HEIGHT as f32) / 2.0;
+        let mut pixels = Vec::with_capacity(SENSOR_WIDTH * SENSOR_HEIGHT);
+        for y in 0..SENSOR_HEIGHT {
+            let source_y = ((top + (y as f32 + 0.5) * scale) as usize)
+                           .min(height - 1);
+            for x in 0..SENSOR_WIDTH {
+                let source_x = ((left + (x as f32 + 0.5) * scale) as usize)
+                               .min(width - 1);
+                pixels.push(gray[source_y * width + source_x]);
+            }
+        }
+        Self{pixels}
+    }
+}
+
+impl CameraSource for StillImage {
+    fn capture(&mut self) -> Vec<u8> {
+        self.pixels.clone()
+    }
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static)
+        -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Image sensor of the Game Boy Camera and its registers
+///
+/// Register  Contents
+/// A000      Bit 0: Start capture, reads 1 until the capture is done
+/// A001      Bit 7: N, bits 5–6: VH (edge enhancement directions),
+///           bits 0–4: Gain
+/// A002-A003 Exposure time in steps of 16 µs, high byte first
+/// A004      Bits 4–6: Edge enhancement ratio, bit 3: Invert output,
+///           bits 0–2: Output voltage
+/// A005      Bits 6–7: Zero point calibration, bits 0–5: Output
+///           reference voltage
+/// A006-A035 Dithering matrix: 3 thresholds for each pixel of a 4x4
+///           block, which separate the 4 shades
+///
+/// The registers are mirrored every 0x80 bytes and all but A000 read
+/// as 0x00.  A capture takes the picture when it finishes and stores
+/// it as tile data at A100-AEFF of RAM bank 0.  Of the analog
+/// processing, only exposure, gain, edge enhancement and dithering are
+/// emulated.
+///
+/// https://gbdev.io/pandocs/Gameboy_Camera.html
+#[derive(Serialize, Deserialize)]
+pub struct Camera {
+    #[serde(with = "BigArray")]
+    registers: [u8; 0x36],
+    /// CPU cycles until the running capture finishes
+    capture_cycles: Option<usize>,
+    #[serde(skip, default = "default_source")]
+    source: Box<dyn CameraSource>,
+}
+
+fn default_source() -> Box<dyn CameraSource> {
+    Box::new(StillImage::default())
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self{
+            registers: [0; 0x36],
+            capture_cycles: None,
+            source: Box::new(StillImage::default()),
+        }
+    }
+}
+
+impl Camera {
+    /// Point the camera at a source, replacing the previous one
+    pub fn connect(&mut self, source: Box<dyn CameraSource>) {
+        self.source = source;
+    }
+
+    /// Exchange the sources of two cameras
+    pub fn swap_source(&mut self, other: &mut Self) {
+        std::mem::swap(&mut self.source, &mut other.source);
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address & 0x7F {
+            0x00 => self.capture_cycles.is_some() as u8,
+            _ => 0x00,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        let register = (address & 0x7F) as usize;
+        if register >= self.registers.len() {
+            return;
+        }
+        self.registers[register] = value;
+        if register == 0 && value & 0x01 != 0
+           && self.capture_cycles.is_none() {
+            self.capture_cycles = Some(self.capture_duration());
+        }
+    }
+
+    /// Duration of a capture in CPU cycles
+    fn capture_duration(&self) -> usize {
+        let n = self.registers[1] & 0x80 != 0;
+        let m_cycles = 32446 + if n { 0 } else { 512 }
+                       + 16 * self.exposure() as usize;
+        4 * m_cycles
+    }
+
+    fn exposure(&self) -> u16 {
+        u16::from_be_bytes([self.registers[2], self.registers[3]])
+    }
+
+    /// Advance a running capture, storing the picture in `ram` once it
+    /// is done
+    pub fn step(&mut self, cycles: usize, ram: &mut [u8]) {
+        if let Some(remaining) = self.capture_cycles {
+            if remaining > cycles {
+                self.capture_cycles = Some(remaining - cycles);
+                return;
+            }
+            self.capture_cycles = None;
+            self.registers[0] &= !0x01;
+            let brightness = self.source.capture();
+            let picture = self.process(&brightness);
+            if let Some(destination) = ram.get_mut(CAPTURE_OFFSET
+                                                   ..CAPTURE_OFFSET
+                                                     + PICTURE_SIZE) {
+                destination.copy_from_slice(&picture);
+            }
+        }
+    }
+
+    /// Turn brightness values into dithered tile data
+    fn process(&self, brightness: &[u8]) -> Vec<u8> {
+        let gain = self.registers[1] & 0x1F;
+        // About 0.5 dB per gain step, relative to gain 4
+        let factor = 10f32.powf((gain as f32 - 4.0) * 0.5 / 20.0)
+                     * self.exposure() as f32 / 0x1000 as f32;
+        let signal = |x: usize, y: usize| {
+            brightness[y * SENSOR_WIDTH + x] as f32 * factor
+        };
+        let horizontal = self.registers[1] & 0x20 != 0;
+        let vertical = self.registers[1] & 0x40 != 0;
+        let ratio = EDGE_RATIOS[(self.registers[4] as usize >> 4) & 0x07];
+        let mut tiles = vec![0; PICTURE_SIZE];
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let mut value = signal(x, y);
+                if horizontal {
+                    let left = signal(x.saturating_sub(1), y);
+                    let right = signal((x + 1).min(SENSOR_WIDTH - 1), y);
+                    value += ratio * (2.0 * signal(x, y) - left - right);
+                }
+                if vertical {
+                    let up = signal(x, y.saturating_sub(1));
+                    let down = signal(x, (y + 1).min(SENSOR_HEIGHT - 1));
+                    value += ratio * (2.0 * signal(x, y) - up - down);
+                }
+                let value = value.clamp(0.0, 255.0) as u8;
+                let matrix = 6 + 3 * ((y & 3) * 4 + (x & 3));
+                let thresholds = &self.registers[matrix..matrix + 3];
+                let color = thresholds.iter()
+                                      .filter(|&&threshold| value < threshold)
+                                      .count() as u8;
+                let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+                let line = 16 * tile + 2 * (y % 8);
+                let bit = 7 - (x % 8);
+                tiles[line] |= (color & 1) << bit;
+                tiles[line + 1] |= (color >> 1) << bit;
+            }
+        }
+        tiles
+    }
+}
+
+/// Write the photos stored in the RAM of a Game Boy Camera to PNG files
+/// `photo-NN.png` in the given directory
+///
+/// All photo slots are written, including those of deleted photos.
+pub fn export_photos(ram: &[u8], directory: &Path) -> io::Result<()> {
+    for slot in 0..NUM_PHOTOS {
+        let offset = 0x2000 * (1 + slot / 2) + 0x1000 * (slot % 2);
+        let tiles = ram.get(offset..offset + PICTURE_SIZE).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "save file is too small for Game Boy Camera photos")
+        })?;
+        let path = directory.join(format!("photo-{:0>2}.png", slot + 1));
+        write_png(&path, &tiles_to_gray(tiles))?;
+        log::info!("Exported {}.", path.display());
+    }
+    Ok(())
+}
+
+/// Convert a picture's tile data into grayscale pixels
+fn tiles_to_gray(tiles: &[u8]) -> Vec<u8> {
+    let mut pixels = vec![0u8; SENSOR_WIDTH * SENSOR_HEIGHT];
+    for (tile_index, tile) in tiles.chunks_exact(16).enumerate() {
+        let tile_x = (tile_index % (SENSOR_WIDTH / 8)) * 8;
+        let tile_y = (tile_index / (SENSOR_WIDTH / 8)) * 8;
+        for (y, line) in tile.chunks_exact(2).enumerate() {
+            for x in 0..8 {
+                let bit = 7 - x;
+                let color = ((line[1] >> bit) & 1) << 1
+                            | ((line[0] >> bit) & 1);
+                pixels[(tile_y + y) * SENSOR_WIDTH + tile_x + x]
+                    = 0xFF - color * 0x55;
+            }
+        }
+    }
+    pixels
+}
+
+fn write_png(path: &Path, pixels: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, SENSOR_WIDTH as u32,
+                                        SENSOR_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(invalid_data)?;
+    writer.write_image_data(pixels).map_err(invalid_data)?;
+    writer.finish().map_err(invalid_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_dithers_picture_into_ram_after_exposure() {
+        let mut camera = Camera::default();
+        let mut halves = StillImage::default();
+        for (i, pixel) in halves.pixels.iter_mut().enumerate() {
+            let is_left = i % SENSOR_WIDTH < SENSOR_WIDTH / 2;
+            *pixel = if is_left { 0x20 } else { 0xE0 };
+        }
+        camera.connect(Box::new(halves));
+        camera.write(0xA001, 0x04);
+        camera.write(0xA002, 0x10);
+        camera.write(0xA003, 0x00);
+        for register in 0..16 {
+            for (i, threshold) in [0x40, 0x80, 0xC0].into_iter().enumerate() {
+                camera.write(0xA006 + 3 * register + i as u16, threshold);
+            }
+        }
+        let mut ram = vec![0; 0x2000];
+        camera.write(0xA000, 0x01);
+        camera.step(camera.capture_duration() - 1, &mut ram);
+        assert_eq!(camera.read(0xA000), 0x01);
+        assert!(ram.iter().all(|&byte| byte == 0));
+        camera.step(1, &mut ram);
+        assert_eq!(camera.read(0xA000), 0x00);
+        let pixels = tiles_to_gray(&ram[CAPTURE_OFFSET..][..PICTURE_SIZE]);
+        // The dark half is black, the bright half white.
+        assert_eq!(pixels[0], 0x00);
+        assert_eq!(pixels[SENSOR_WIDTH - 1], 0xFF);
+    }
+
+    #[test]
+    fn exported_photo_loads_as_same_image() {
+        let pixels: Vec<u8> = (0..SENSOR_WIDTH * SENSOR_HEIGHT)
+            .map(|i| 0xFF - (i % 4) as u8 * 0x55)
+            .collect();
+        let path = std::env::temp_dir().join("emulato-rs-camera-test.png");
+        write_png(&path, &pixels).unwrap();
+        let image = StillImage::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.pixels, pixels);
+    }
+}
@@ -2,32 +2,64 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::fs::File;
 use std::io;
 use std::io::Read;
-use std::fs::File;
 use std::str;
 
+use serde::{Deserialize, Serialize};
+
+use super::camera::{Camera, CameraSource};
+use super::eeprom::{Eeprom, EEPROM_WORDS};
+use super::infrared::{Darkness, InfraredDevice};
+use super::rtc::{RealTimeClock, RtcStart};
+use super::sram::Sram;
+
+#[derive(Serialize, Deserialize)]
 pub struct Cartridge {
+    /// The ROM isn't part of save states, which are only loaded into a
+    /// cartridge with the same ROM
+    #[serde(skip)]
     rom: Vec<u8>,
-    ram: Vec<u8>,
+    ram: Sram,
     memory_controller: MemoryController,
+    /// Whether RAM has been written since the last call of
+    /// `take_ram_written`
+    #[serde(skip)]
+    ram_written: bool,
 }
 
 impl Cartridge {
-    pub fn load_from_file(mut file: File) -> io::Result<Self> {
+    /// Load a cartridge ROM from a file, a byte slice or any other
+    /// reader
+    pub fn load(mut reader: impl Read) -> Result<Self, CartridgeError> {
         let mut rom = Vec::new();
-        file.read_to_end(&mut rom)?;
-        let memory_controller = MemoryController::from_cartridge_rom(&rom);
-        let header = CartridgeHeader{rom: &rom};
+        reader.read_to_end(&mut rom)?;
+        Self::from_rom(rom)
+    }
+
+    /// Set up a cartridge with the given ROM
+    ///
+    /// This fails if the header describes hardware that isn't emulated.
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let memory_controller = MemoryController::from_cartridge_rom(&rom)?;
+        let header = CartridgeHeader::of_rom(&rom);
         let ram = if let MemoryController::MBC2(_) = memory_controller {
             vec![0; 512]
+        } else if let MemoryController::MBC7(_) = memory_controller {
+            // The EEPROM is kept in place of cartridge RAM.
+            vec![0xFF; 2 * EEPROM_WORDS]
         } else {
-            vec![0; header.num_ram_banks() as usize * 8 * 1024]
+            vec![0; header.ram_size()?]
         };
         Ok(Self{
             rom,
-            ram,
+            ram: Sram::Heap(ram),
             memory_controller,
+            ram_written: false,
         })
     }
 
@@ -55,6 +87,7 @@ impl Cartridge {
             0xA000..=0xBFFF => {
                 self.memory_controller.ram_write8(&mut self.ram, address,
                                                   value);
+                self.ram_written = true;
             }
             _ => panic!("Trying to write non-Cartridge address {:0>4X}.",
                         address),
@@ -73,8 +106,278 @@ impl Cartridge {
         self.memory_controller.ram_read8(&self.ram, address)
     }
 
+    /// Number of the ROM bank mapped to the given address in 0000-7FFF
+    pub fn rom_bank(&self, address: u16) -> usize {
+        self.memory_controller.rom_bank(address)
+    }
+
+    /// The registers of the memory controller, for serializing them
+    pub fn mapper_state(&self) -> MapperState<'_> {
+        MapperState(&self.memory_controller)
+    }
+
+    /// Number of the RAM bank mapped to A000-BFFF
+    pub fn ram_bank(&self) -> usize {
+        self.memory_controller.ram_bank()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     pub fn header(&self) -> CartridgeHeader {
-        CartridgeHeader{rom: &self.rom}
+        CartridgeHeader::of_rom(&self.rom)
+    }
+
+    /// Whether a battery keeps the contents of the cartridge RAM
+    pub fn has_battery(&self) -> bool {
+        self.header().cartridge_type().has_battery()
+    }
+
+    /// Contents of the cartridge RAM, as stored in `.sav` files
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Advance the real time clock or the camera of the cartridge, if it
+    /// has one
+    pub fn step(&mut self, cycles: usize) {
+        match &mut self.memory_controller {
+            MemoryController::MBC3(mbc3) => {
+                if let Some(rtc) = &mut mbc3.rtc {
+                    rtc.step(cycles);
+                }
+            }
+            MemoryController::PocketCamera(camera) => {
+                camera.camera.step(cycles, &mut self.ram);
+            }
+            _ => {}
+        }
+    }
+
+    /// Restart the real time clock of the cartridge, if it has one, at
+    /// the given time
+    pub fn set_rtc_start(&mut self, start: RtcStart) {
+        if let MemoryController::MBC3(mbc3) = &mut self.memory_controller {
+            if let Some(rtc) = &mut mbc3.rtc {
+                *rtc = RealTimeClock::new(start);
+            }
+        }
+    }
+
+    /// Whether the cartridge has its own IR port, like HuC1 cartridges
+    pub fn has_infrared(&self) -> bool {
+        matches!(self.memory_controller, MemoryController::HuC1(_))
+    }
+
+    /// Point the IR port of the cartridge, if it has one, at a device
+    pub fn connect_infrared_device(&mut self,
+                                   device: Box<dyn InfraredDevice>) {
+        if let MemoryController::HuC1(huc1) = &mut self.memory_controller {
+            huc1.infrared = RefCell::new(device);
+        }
+    }
+
+    /// Point the camera of Game Boy Camera cartridges at a source
+    pub fn connect_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        if let MemoryController::PocketCamera(camera)
+                = &mut self.memory_controller {
+            camera.camera.connect(source);
+        }
+    }
+
+    /// Tilt the cartridge, which is measured by the accelerometer of
+    /// MBC7 cartridges
+    ///
+    /// See `IO::get_tilt` for the axes.
+    pub fn set_tilt(&mut self, tilt: (f32, f32)) {
+        if let MemoryController::MBC7(mbc7) = &mut self.memory_controller {
+            mbc7.tilt = tilt;
+        }
+    }
+
+    /// Whether RAM has been written since the last call
+    pub fn take_ram_written(&mut self) -> bool {
+        std::mem::take(&mut self.ram_written)
+    }
+
+    /// Restore the cartridge RAM from a `.sav` file
+    ///
+    /// Files of the wrong size are loaded as far as they fit.
+    pub fn load_ram(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut ram = Vec::with_capacity(self.ram.len());
+        reader.read_to_end(&mut ram)?;
+        if ram.len() != self.ram.len() {
+            log::warn!("Save file has {} bytes, but cartridge RAM has {}.",
+                       ram.len(), self.ram.len());
+        }
+        let size = ram.len().min(self.ram.len());
+        self.ram[..size].copy_from_slice(&ram[..size]);
+        Ok(())
+    }
+
+    /// Keep the cartridge RAM in a memory-mapped `.sav` file
+    ///
+    /// Writes to the RAM then end up in the file without explicit
+    /// writing.  An empty file is initialized with the current RAM,
+    /// otherwise the RAM is loaded from the file.  Cartridges without
+    /// RAM don't map the file.
+    pub fn map_ram(&mut self, file: &File) -> io::Result<()> {
+        if !self.ram.is_empty() {
+            self.ram = self.ram.map(file)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the cartridge RAM is a memory-mapped file
+    pub fn has_mapped_ram(&self) -> bool {
+        self.ram.is_mapped()
+    }
+
+    /// Wait until the changes of a memory-mapped RAM are written back
+    pub fn flush_ram(&self) -> io::Result<()> {
+        self.ram.flush()
+    }
+
+    /// Take over the RAM and the mapper state of a cartridge loaded from
+    /// a save state
+    ///
+    /// The save state has to be of a cartridge with the same ROM.
+    /// Connected devices are kept, and a memory-mapped RAM stays mapped.
+    pub fn restore_state(&mut self, saved: Self) {
+        self.ram.copy_from_slice(&saved.ram);
+        self.memory_controller.restore_state(saved.memory_controller);
+        self.ram_written = true;
+    }
+
+    /// Set up the cartridge again with a header corrected by the
+    /// overrides
+    ///
+    /// The RAM contents are lost, so this has to happen before loading
+    /// a save file.
+    pub fn with_header_overrides(self, overrides: &HeaderOverrides)
+            -> Result<Self, CartridgeError> {
+        let mut rom = self.rom;
+        overrides.apply(&mut rom)?;
+        Self::from_rom(rom)
+    }
+}
+
+/// Corrections of the cartridge header of misheadered ROMs
+///
+/// Homebrew ROMs sometimes declare ROM ONLY but switch banks, or use RAM
+/// without declaring it.  The overrides are written into the header of
+/// the ROM, which keeps the header checksum valid, so that the game and
+/// the boot ROM see the corrected header as well.  A memory controller
+/// is given battery-backed RAM whenever the cartridge has RAM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeaderOverrides {
+    pub memory_controller: Option<MemoryControllerModel>,
+    /// RAM size in bytes
+    pub ram_size: Option<usize>,
+}
+
+impl HeaderOverrides {
+    pub fn apply(&self, rom: &mut [u8]) -> Result<(), CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "ROM ends within header").into());
+        }
+        if let Some(size) = self.ram_size {
+            rom[0x149] = match size {
+                0 => 0x00,
+                0x800 => 0x01,
+                0x2000 => 0x02,
+                0x8000 => 0x03,
+                0x20000 => 0x04,
+                0x10000 => 0x05,
+                _ => return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unsupported RAM size of {} bytes", size))
+                    .into()),
+            };
+        }
+        if let Some(model) = self.memory_controller {
+            let has_ram = rom[0x149] != 0x00;
+            use MemoryControllerModel::*;
+            rom[0x147] = match model {
+                NoController => if has_ram { 0x09 } else { 0x00 },
+                MBC1 => if has_ram { 0x03 } else { 0x01 },
+                MBC2 => 0x06,
+                MBC3 => if has_ram { 0x13 } else { 0x11 },
+                MBC5 => if has_ram { 0x1B } else { 0x19 },
+                MBC6 => 0x20,
+                MBC7 => 0x22,
+                MMM01 => if has_ram { 0x0D } else { 0x0B },
+                HuC1 => 0xFF,
+                HuC3 => 0xFE,
+                PocketCamera => 0xFC,
+                BandaiTAMA5 => 0xFD,
+            };
+        }
+        rom[0x14D] = rom[0x134..=0x14C].iter().fold(0u8, |x, y| {
+            x.wrapping_sub(*y).wrapping_sub(1)
+        });
+        Ok(())
+    }
+}
+
+/// Why a cartridge can't be loaded
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    /// Cartridge type byte 0x147 that isn't known
+    UnknownCartridgeType(u8),
+    /// RAM size byte 0x149 that isn't known
+    UnknownRamSize(u8),
+    /// Memory controller that isn't emulated
+    UnsupportedMapper(MemoryControllerModel),
+    /// More ROM banks than the memory controller can switch between
+    TooManyRomBanks(MemoryControllerModel, u16),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::UnknownCartridgeType(cartridge_type) => {
+                write!(f, "unknown cartridge type {:0>2X}", cartridge_type)
+            }
+            Self::UnknownRamSize(size) => {
+                write!(f, "unknown RAM size {:0>2X}", size)
+            }
+            Self::UnsupportedMapper(model) => {
+                write!(f, "unsupported mapper {:?}", model)
+            }
+            Self::TooManyRomBanks(model, num_rom_banks) => {
+                write!(f, "unsupported {:?} with {} ROM banks",
+                       model, num_rom_banks)
+            }
+        }
+    }
+}
+
+impl error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<CartridgeError> for io::Error {
+    fn from(error: CartridgeError) -> Self {
+        match error {
+            CartridgeError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
     }
 }
 
@@ -113,9 +416,20 @@ impl Cartridge {
 pub struct CartridgeType(u8);
 
 impl CartridgeType {
-    pub fn memory_controller(self) -> MemoryControllerModel {
+    pub fn has_battery(&self) -> bool {
+        matches!(self.0, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13
+                         | 0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF)
+    }
+
+    /// Whether the cartridge has a real time clock
+    pub fn has_timer(&self) -> bool {
+        matches!(self.0, 0x0F | 0x10)
+    }
+
+    pub fn memory_controller(self)
+            -> Result<MemoryControllerModel, CartridgeError> {
         use MemoryControllerModel::*;
-        match self.0 {
+        Ok(match self.0 {
             0x00 => NoController,
             0x01..=0x03 => MBC1,
             0x05..=0x06 => MBC2,
@@ -130,14 +444,14 @@ impl CartridgeType {
             0xFE => HuC3,
             0xFF => HuC1,
             cartridge_type => {
-                unimplemented!("Unknown cartridge type: {:0>2X}.",
-                               cartridge_type);
+                return Err(CartridgeError::UnknownCartridgeType(
+                    cartridge_type));
             }
-        }
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 pub enum MemoryControllerModel {
     NoController,
     MBC1,
@@ -153,27 +467,90 @@ pub enum MemoryControllerModel {
     BandaiTAMA5,
 }
 
+impl MemoryControllerModel {
+    /// Parse the name of a memory controller, like "mbc5" or "none"
+    pub fn from_name(name: &str) -> Option<Self> {
+        use MemoryControllerModel::*;
+        Some(match name.to_ascii_lowercase().as_str() {
+            "none" => NoController,
+            "mbc1" => MBC1,
+            "mbc2" => MBC2,
+            "mbc3" => MBC3,
+            "mbc5" => MBC5,
+            "mbc6" => MBC6,
+            "mbc7" => MBC7,
+            "mmm01" => MMM01,
+            "huc1" => HuC1,
+            "huc3" => HuC3,
+            "camera" => PocketCamera,
+            "tama5" => BandaiTAMA5,
+            _ => return None,
+        })
+    }
+}
+
+/// Serializes like the memory controller it has been taken from, named
+/// by its model
+pub struct MapperState<'a>(&'a MemoryController);
+
+impl Serialize for MapperState<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S)
+            -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 enum MemoryController {
     NoController,
     MBC1(MBC1),
     MBC2(MBC2),
     MBC3(MBC3),
     MBC5(MBC5),
+    MBC6(MBC6),
+    MBC7(MBC7),
+    MMM01(MMM01),
+    HuC1(HuC1),
+    PocketCamera(PocketCamera),
 }
 
 impl MemoryController {
-    fn from_cartridge_rom(rom: &[u8]) -> Self {
-        let header = CartridgeHeader{rom};
-        let controller_model = header.cartridge_type().memory_controller();
+    fn from_cartridge_rom(rom: &[u8]) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::of_rom(rom);
+        let controller_model = header.cartridge_type().memory_controller()?;
         use MemoryControllerModel as Model;
-        match controller_model {
+        Ok(match controller_model {
             Model::NoController => Self::NoController,
-            Model::MBC1 => Self::MBC1(MBC1::from_cartridge_rom(rom)),
-            Model::MBC2 => Self::MBC2(MBC2::from_cartridge_header(&header)),
-            Model::MBC3 => Self::MBC3(MBC3::from_cartridge_header(&header)),
-            Model::MBC5 => Self::MBC5(MBC5::from_cartridge_header(&header)),
-            _ => unimplemented!("Memory controller {:?} not handled yet.",
-                                controller_model),
+            Model::MBC1 => Self::MBC1(MBC1::from_cartridge_rom(rom)?),
+            Model::MBC2 => Self::MBC2(MBC2::from_cartridge_header(&header)?),
+            Model::MBC3 => Self::MBC3(MBC3::from_cartridge_header(&header)?),
+            Model::MBC5 => Self::MBC5(MBC5::from_cartridge_header(&header)?),
+            Model::MBC6 => Self::MBC6(MBC6::from_cartridge_header(&header)?),
+            Model::MBC7 => Self::MBC7(MBC7::from_cartridge_header(&header)),
+            Model::MMM01 => Self::MMM01(MMM01::from_cartridge_rom(rom)?),
+            Model::HuC1 => Self::HuC1(HuC1::from_cartridge_header(&header)?),
+            Model::PocketCamera => Self::PocketCamera(
+                PocketCamera::from_cartridge_header(&header)?),
+            Model::HuC3 | Model::BandaiTAMA5 => {
+                return Err(CartridgeError::UnsupportedMapper(controller_model));
+            }
+        })
+    }
+
+    /// Take over the state of a saved memory controller of the same
+    /// model, keeping connected devices
+    fn restore_state(&mut self, saved: Self) {
+        use MemoryController::*;
+        match (self, saved) {
+            (HuC1(huc1), HuC1(mut saved)) => {
+                std::mem::swap(&mut huc1.infrared, &mut saved.infrared);
+                *huc1 = saved;
+            }
+            (PocketCamera(camera), PocketCamera(mut saved)) => {
+                saved.camera.swap_source(&mut camera.camera);
+                *camera = saved;
+            }
+            (controller, saved) => *controller = saved,
         }
     }
 
@@ -183,6 +560,9 @@ impl MemoryController {
             MBC1(mbc1) => {
                 rom[address as usize + mbc1.rom0_bank_offset()]
             }
+            MMM01(mmm01) => {
+                rom[address as usize + mmm01.rom0_bank_offset()]
+            }
             _ => rom[address as usize],
         }
     }
@@ -203,20 +583,76 @@ impl MemoryController {
             MBC5(mbc5) => {
                 rom[address as usize - 0x4000 + mbc5.rom_bank_offset()]
             }
+            MBC6(mbc6) => mbc6.romx_read8(rom, address),
+            MBC7(mbc7) => {
+                rom[address as usize - 0x4000 + mbc7.rom_bank_offset()]
+            }
+            MMM01(mmm01) => {
+                rom[address as usize - 0x4000 + mmm01.rom_bank_offset()]
+            }
+            HuC1(huc1) => {
+                rom[address as usize - 0x4000 + huc1.rom_bank_offset()]
+            }
+            PocketCamera(camera) => {
+                rom[address as usize - 0x4000 + camera.rom_bank_offset()]
+            }
         }
     }
 
     fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
         use MemoryController::*;
         match self {
-            NoController => ram[address as usize],
+            // Without RAM, nothing drives the data bus.
+            NoController => read_ram(ram, address as usize - 0xA000),
             MBC1(mbc1) => mbc1.ram_read8(ram, address),
             MBC2(mbc2) => mbc2.ram_read8(ram, address),
             MBC3(mbc3) => mbc3.ram_read8(ram, address),
             MBC5(mbc5) => mbc5.ram_read8(ram, address),
+            MBC6(mbc6) => mbc6.ram_read8(ram, address),
+            MBC7(mbc7) => mbc7.ram_read8(ram, address),
+            MMM01(mmm01) => mmm01.ram_read8(ram, address),
+            HuC1(huc1) => huc1.ram_read8(ram, address),
+            PocketCamera(camera) => camera.ram_read8(ram, address),
         }
     }
 
+    fn rom_bank(&self, address: u16) -> usize {
+        use MemoryController::*;
+        let offset = match (self, address) {
+            (MBC1(mbc1), 0x0000..=0x3FFF) => mbc1.rom0_bank_offset(),
+            (MMM01(mmm01), 0x0000..=0x3FFF) => mmm01.rom0_bank_offset(),
+            (_, 0x0000..=0x3FFF) => 0,
+            (NoController, _) => 0x4000,
+            (MBC1(mbc1), _) => mbc1.rom_bank_offset(),
+            (MBC2(mbc2), _) => mbc2.rom_bank_offset(),
+            (MBC3(mbc3), _) => mbc3.rom_bank_offset(),
+            (MBC5(mbc5), _) => mbc5.rom_bank_offset(),
+            (MBC6(mbc6), _) => mbc6.rom_bank_offset(),
+            (MBC7(mbc7), _) => mbc7.rom_bank_offset(),
+            (MMM01(mmm01), _) => mmm01.rom_bank_offset(),
+            (HuC1(huc1), _) => huc1.rom_bank_offset(),
+            (PocketCamera(camera), _) => camera.rom_bank_offset(),
+        };
+        offset / 0x4000
+    }
+
+    fn ram_bank(&self) -> usize {
+        use MemoryController::*;
+        let offset = match self {
+            NoController => 0,
+            MBC1(mbc1) => mbc1.ram_bank_offset(),
+            MBC2(mbc2) => mbc2.ram_bank_offset(),
+            MBC3(mbc3) => mbc3.ram_bank_offset(),
+            MBC5(mbc5) => mbc5.ram_bank_offset(),
+            MBC6(mbc6) => mbc6.ram_bank_offset(),
+            MBC7(mbc7) => mbc7.ram_bank_offset(),
+            MMM01(mmm01) => mmm01.ram_bank_offset(),
+            HuC1(huc1) => huc1.ram_bank_offset(),
+            PocketCamera(camera) => camera.ram_bank_offset(),
+        };
+        offset / 0x2000
+    }
+
     fn register_write8(&mut self, address: u16, value: u8) {
         use MemoryController::*;
         match self {
@@ -227,10 +663,15 @@ impl MemoryController {
             MBC2(mbc2) => mbc2.register_write8(address, value),
             MBC3(mbc3) => mbc3.register_write8(address, value),
             MBC5(mbc5) => mbc5.register_write8(address, value),
+            MBC6(mbc6) => mbc6.register_write8(address, value),
+            MBC7(mbc7) => mbc7.register_write8(address, value),
+            MMM01(mmm01) => mmm01.register_write8(address, value),
+            HuC1(huc1) => huc1.register_write8(address, value),
+            PocketCamera(camera) => camera.register_write8(address, value),
         }
     }
 
-    fn ram_write8(&self, ram: &mut [u8], address: u16, value: u8) {
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
         use MemoryController::*;
         match self {
             NoController => unimplemented!(
@@ -240,6 +681,11 @@ impl MemoryController {
             MBC2(mbc2) => mbc2.ram_write8(ram, address, value),
             MBC3(mbc3) => mbc3.ram_write8(ram, address, value),
             MBC5(mbc5) => mbc5.ram_write8(ram, address, value),
+            MBC6(mbc6) => mbc6.ram_write8(ram, address, value),
+            MBC7(mbc7) => mbc7.ram_write8(ram, address, value),
+            MMM01(mmm01) => mmm01.ram_write8(ram, address, value),
+            HuC1(huc1) => huc1.ram_write8(ram, address, value),
+            PocketCamera(camera) => camera.ram_write8(ram, address, value),
         }
     }
 }
@@ -255,21 +701,42 @@ trait MemoryControllerRegisters {
 
     fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
         if self.is_ram_enabled() {
-            ram[address as usize - 0xA000 + self.ram_bank_offset()]
+            read_ram(ram, address as usize - 0xA000 + self.ram_bank_offset())
         } else {
             0xFF
         }
     }
 
-    fn ram_write8(&self, ram: &mut [u8], address: u16, value: u8) {
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
         if self.is_ram_enabled() {
-            ram[address as usize - 0xA000 + self.ram_bank_offset()] = value;
+            write_ram(ram, address as usize - 0xA000 + self.ram_bank_offset(),
+                      value);
         }
     }
 }
 
+/// Read a byte of cartridge RAM
+///
+/// RAM that is smaller than the accessed area, like the 2 KiB of RAM
+/// size 0x01, is mirrored.  Without RAM, nothing drives the data bus.
+fn read_ram(ram: &[u8], offset: usize) -> u8 {
+    if ram.is_empty() {
+        0xFF
+    } else {
+        ram[offset % ram.len()]
+    }
+}
+
+/// Write a byte of cartridge RAM, which is mirrored like in `read_ram`
+fn write_ram(ram: &mut [u8], offset: usize, value: u8) {
+    if !ram.is_empty() {
+        let size = ram.len();
+        ram[offset % size] = value;
+    }
+}
+
 #[repr(u8)]
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 enum MBC1BankingMode {
     Simple = 0,
     Advanced = 1,
@@ -285,6 +752,7 @@ impl From<u8> for MBC1BankingMode {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 struct MBC1 {
     rom0_bank: u8,
     rom_bank: u8,
@@ -297,15 +765,13 @@ struct MBC1 {
 }
 
 impl MBC1 {
-    fn from_cartridge_rom(rom: &[u8]) -> Self {
+    fn from_cartridge_rom(rom: &[u8]) -> Result<Self, CartridgeError> {
         let header = CartridgeHeader{rom};
         let num_rom_banks = header.num_rom_banks();
-        let num_ram_banks = header.num_ram_banks();
+        let num_ram_banks = header.num_ram_banks()?;
         if num_rom_banks > 128 {
-            unimplemented!(
-                "MBC1 with {} ROM banks and {} RAM banks not implemented yet.",
-                num_rom_banks, num_ram_banks);
-
+            return Err(CartridgeError::TooManyRomBanks(
+                MemoryControllerModel::MBC1, num_rom_banks));
         }
         let is_multi_cart = if rom.len() >= 0x11 * 0x4000 {
             let bank_10_header = CartridgeHeader{rom: &rom[0x10 * 0x4000..]};
@@ -313,7 +779,7 @@ impl MBC1 {
         } else {
             false
         };
-        Self{
+        Ok(Self{
             rom0_bank: 0,
             rom_bank: 1,
             ram_bank: 0,
@@ -322,7 +788,7 @@ impl MBC1 {
             banking_mode: MBC1BankingMode::Simple,
             ram_enabled: false,
             is_multi_cart,
-        }
+        })
     }
 
     /// Does the Cartridge have >= 1MB ROM?
@@ -347,10 +813,10 @@ impl MemoryControllerRegisters for MBC1 {
                 // 0x00  Disable RAM (default)
                 // 0x0A  Enable RAM
                 if value & 0x0F == 0x0A {
-                    eprintln!("enable cartridge RAM.");
+                    log::debug!("enable cartridge RAM.");
                     self.ram_enabled = true;
                 } else {
-                    eprintln!("disable cartridge RAM.");
+                    log::debug!("disable cartridge RAM.");
                     self.ram_enabled = false;
                 }
             }
@@ -396,7 +862,7 @@ impl MemoryControllerRegisters for MBC1 {
                 }
             }
             0x6000..=0x7FFF => { // Banking Mode Select
-                eprintln!("Select banking mode 0x{:0>2X}", value);
+                log::debug!("Select banking mode 0x{:0>2X}", value);
                 self.banking_mode = value.into();
                 if self.banking_mode == MBC1BankingMode::Simple {
                     self.rom0_bank = 0;
@@ -423,6 +889,7 @@ impl MemoryControllerRegisters for MBC1 {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 struct MBC2 {
     rom_bank: u8,
     num_rom_banks: u16,
@@ -430,21 +897,20 @@ struct MBC2 {
 }
 
 impl MBC2 {
-    fn from_cartridge_header(header: &CartridgeHeader) -> Self {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
         let num_rom_banks = header.num_rom_banks();
-        let num_ram_banks = header.num_ram_banks();
+        let num_ram_banks = header.num_ram_banks()?;
         assert_eq!(num_ram_banks, 0);
         if num_rom_banks > 16 {
-            unimplemented!(
-                "MBC2 with {} ROM banks not implemented yet.",
-                num_rom_banks);
-
+            return Err(CartridgeError::TooManyRomBanks(
+                MemoryControllerModel::MBC2, num_rom_banks));
         }
-        Self{
+        Ok(Self{
             rom_bank: 1,
             num_rom_banks,
             ram_enabled: false,
-        }
+        })
     }
 }
 
@@ -456,10 +922,10 @@ impl MemoryControllerRegisters for MBC2 {
                     // 0x00  Disable RAM (default)
                     // 0x0A  Enable RAM
                     if value & 0x0F == 0x0A {
-                        eprintln!("enable cartridge RAM.");
+                        log::debug!("enable cartridge RAM.");
                         self.ram_enabled = true;
                     } else {
-                        eprintln!("disable cartridge RAM.");
+                        log::debug!("disable cartridge RAM.");
                         self.ram_enabled = false;
                     }
                 } else { // ROM Bank Number
@@ -499,7 +965,7 @@ impl MemoryControllerRegisters for MBC2 {
         }
     }
 
-    fn ram_write8(&self, ram: &mut [u8], address: u16, value: u8) {
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
         if self.is_ram_enabled() {
             let offset = (address & 0x01FF) as usize;
             ram[offset] = value & 0x0F;
@@ -507,25 +973,36 @@ impl MemoryControllerRegisters for MBC2 {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 struct MBC3 {
     rom_bank: u8,
+    /// RAM bank 0x00–0x03 or RTC register 0x08–0x0C mapped to A000-BFFF
     ram_bank: u8,
     num_rom_banks: u16,
     num_ram_banks: u8,
     ram_enabled: bool,
+    rtc: Option<RealTimeClock>,
+    /// Whether 0 has been written to 6000-7FFF, so that writing 1 next
+    /// latches the clock
+    latch_prepared: bool,
 }
 
 impl MBC3 {
-    fn from_cartridge_header(header: &CartridgeHeader) -> Self {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
         let num_rom_banks = header.num_rom_banks();
-        let num_ram_banks = header.num_ram_banks();
-        Self{
+        let num_ram_banks = header.num_ram_banks()?;
+        let rtc = header.cartridge_type().has_timer()
+                        .then(|| RealTimeClock::new(RtcStart::default()));
+        Ok(Self{
             rom_bank: 1,
             ram_bank: 0,
             num_rom_banks,
             num_ram_banks,
             ram_enabled: false,
-        }
+            rtc,
+            latch_prepared: false,
+        })
     }
 }
 
@@ -536,10 +1013,10 @@ impl MemoryControllerRegisters for MBC3 {
                 // 0x00  Disable RAM (default)
                 // 0x0A  Enable RAM
                 if value & 0x0F == 0x0A {
-                    eprintln!("enable cartridge RAM.");
+                    log::debug!("enable cartridge RAM.");
                     self.ram_enabled = true;
                 } else {
-                    eprintln!("disable cartridge RAM.");
+                    log::debug!("disable cartridge RAM.");
                     self.ram_enabled = false;
                 }
             }
@@ -557,16 +1034,20 @@ impl MemoryControllerRegisters for MBC3 {
                     0x00..=0x03 => { // RAM Bank Number
                         self.ram_bank = value;
                     }
-                    0x08..=0x0C => {
-                        unimplemented!("Mapping RTC register {:0>2X}.",
-                                       value);
+                    0x08..=0x0C => { // RTC Register Select
+                        self.ram_bank = value;
                     }
                     _ => panic!("Unexpected RAM Bank/RTC Register: {:0>2X}.",
                                 value),
                 }
             }
             0x6000..=0x7FFF => { // Latch Clock Data
-                unimplemented!("Latching clock data.");
+                if let Some(rtc) = &mut self.rtc {
+                    if self.latch_prepared && value == 0x01 {
+                        rtc.latch();
+                    }
+                }
+                self.latch_prepared = value == 0x00;
             }
             _ => unreachable!("{:0>4X} is not a cartridge register.", address),
         }
@@ -583,8 +1064,31 @@ impl MemoryControllerRegisters for MBC3 {
     fn is_ram_enabled(&self) -> bool {
         self.ram_enabled
     }
+
+    fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
+        match (self.ram_enabled, self.ram_bank, &self.rtc) {
+            (false, _, _) => 0xFF,
+            (true, 0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+            (true, 0x08..=0x0C, None) => 0xFF,
+            (true, _, _) => read_ram(ram, address as usize - 0xA000
+                                          + self.ram_bank_offset()),
+        }
+    }
+
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        match (self.ram_enabled, self.ram_bank, &mut self.rtc) {
+            (false, _, _) => {}
+            (true, 0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+            (true, 0x08..=0x0C, None) => {}
+            (true, _, _) => {
+                write_ram(ram, address as usize - 0xA000
+                               + self.ram_bank_offset(), value);
+            }
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 struct MBC5 {
     rom_bank: u16,
     ram_bank: u8,
@@ -594,16 +1098,17 @@ struct MBC5 {
 }
 
 impl MBC5 {
-    fn from_cartridge_header(header: &CartridgeHeader) -> Self {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
         let num_rom_banks = header.num_rom_banks();
-        let num_ram_banks = header.num_ram_banks();
-        Self{
+        let num_ram_banks = header.num_ram_banks()?;
+        Ok(Self{
             rom_bank: 1,
             ram_bank: 0,
             num_rom_banks,
             num_ram_banks,
             ram_enabled: false,
-        }
+        })
     }
 }
 
@@ -614,10 +1119,10 @@ impl MemoryControllerRegisters for MBC5 {
                 // 0x00  Disable RAM (default)
                 // 0x0A  Enable RAM
                 if value & 0x0F == 0x0A {
-                    eprintln!("enable cartridge RAM.");
+                    log::debug!("enable cartridge RAM.");
                     self.ram_enabled = true;
                 } else {
-                    eprintln!("disable cartridge RAM.");
+                    log::debug!("disable cartridge RAM.");
                     self.ram_enabled = false;
                 }
             }
@@ -655,7 +1160,597 @@ impl MemoryControllerRegisters for MBC5 {
     }
 }
 
-const LOGO: [u8; 0x30] = [
+/// Memory controller of the Game Boy Camera
+///
+/// RAM can always be read, but only written after writing 0x0A to
+/// 0000-1FFF.  Setting bit 4 of the RAM bank number shows the registers
+/// of the camera, see `Camera`, instead of RAM.
+///
+/// Address    Register
+/// 0000-1FFF  RAM Write Enable
+/// 2000-3FFF  ROM Bank Number (6 bits)
+/// 4000-5FFF  RAM Bank Number (4 bits) or camera registers (0x10)
+/// 6000-7FFF  Unused
+///
+/// https://gbdev.io/pandocs/Gameboy_Camera.html
+#[derive(Serialize, Deserialize)]
+struct PocketCamera {
+    rom_bank: u8,
+    ram_bank: u8,
+    num_rom_banks: u16,
+    num_ram_banks: u8,
+    ram_enabled: bool,
+    registers_selected: bool,
+    camera: Camera,
+}
+
+impl PocketCamera {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
+        Ok(Self{
+            rom_bank: 1,
+            ram_bank: 0,
+            num_rom_banks: header.num_rom_banks(),
+            num_ram_banks: header.num_ram_banks()?,
+            ram_enabled: false,
+            registers_selected: false,
+            camera: Camera::default(),
+        })
+    }
+}
+
+impl MemoryControllerRegisters for PocketCamera {
+    fn register_write8(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => { // RAM Write Enable
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => { // ROM Bank Number
+                let mask = (self.num_rom_banks - 1) as u8;
+                self.rom_bank = value & 0x3F & mask;
+            }
+            0x4000..=0x5FFF => { // RAM Bank Number
+                self.registers_selected = value & 0x10 != 0;
+                self.ram_bank = value & 0x0F
+                                & self.num_ram_banks.saturating_sub(1);
+            }
+            0x6000..=0x7FFF => {}
+            _ => unreachable!("{:0>4X} is not a cartridge register.", address),
+        }
+    }
+
+    fn rom_bank_offset(&self) -> usize {
+        0x4000 * self.rom_bank as usize
+    }
+
+    fn ram_bank_offset(&self) -> usize {
+        0x2000 * self.ram_bank as usize
+    }
+
+    fn is_ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
+        if self.registers_selected {
+            self.camera.read(address)
+        } else {
+            read_ram(ram, address as usize - 0xA000 + self.ram_bank_offset())
+        }
+    }
+
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if self.registers_selected {
+            self.camera.write(address, value);
+        } else if self.ram_enabled {
+            write_ram(ram, address as usize - 0xA000 + self.ram_bank_offset(),
+                      value);
+        }
+    }
+}
+
+/// HuC1 of Hudson cartridges with an IR port
+///
+/// Its RAM is always enabled.  Instead, writing 0x0E to 0000-1FFF shows
+/// the IR port at A000-BFFF, and any other value shows the RAM again.
+/// The IR port reads 0xC1 while receiving light and 0xC0 otherwise, and
+/// bit 0 written to it switches the LED on or off.
+///
+/// Address    Register
+/// 0000-1FFF  IR Select
+/// 2000-3FFF  ROM Bank Number (6 bits)
+/// 4000-5FFF  RAM Bank Number (2 bits)
+/// 6000-7FFF  Unused
+///
+/// https://gbdev.io/pandocs/HuC1.html
+#[derive(Serialize, Deserialize)]
+struct HuC1 {
+    rom_bank: u8,
+    ram_bank: u8,
+    num_rom_banks: u16,
+    num_ram_banks: u8,
+    infrared_selected: bool,
+    /// Reading the IR port asks the device whether it sends light
+    #[serde(skip, default = "default_infrared_device")]
+    infrared: RefCell<Box<dyn InfraredDevice>>,
+}
+
+fn default_infrared_device() -> RefCell<Box<dyn InfraredDevice>> {
+    RefCell::new(Box::new(Darkness))
+}
+
+impl HuC1 {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
+        Ok(Self{
+            rom_bank: 1,
+            ram_bank: 0,
+            num_rom_banks: header.num_rom_banks(),
+            num_ram_banks: header.num_ram_banks()?,
+            infrared_selected: false,
+            infrared: default_infrared_device(),
+        })
+    }
+}
+
+impl MemoryControllerRegisters for HuC1 {
+    fn register_write8(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => { // IR Select
+                self.infrared_selected = value & 0x0F == 0x0E;
+            }
+            0x2000..=0x3FFF => { // ROM Bank Number
+                let mask = (self.num_rom_banks - 1) as u8;
+                self.rom_bank = value & 0x3F & mask;
+            }
+            0x4000..=0x5FFF => { // RAM Bank Number
+                self.ram_bank = value & 0x03
+                                & self.num_ram_banks.saturating_sub(1);
+            }
+            0x6000..=0x7FFF => {}
+            _ => unreachable!("{:0>4X} is not a cartridge register.", address),
+        }
+    }
+
+    fn rom_bank_offset(&self) -> usize {
+        0x4000 * self.rom_bank as usize
+    }
+
+    fn ram_bank_offset(&self) -> usize {
+        0x2000 * self.ram_bank as usize
+    }
+
+    fn is_ram_enabled(&self) -> bool {
+        self.num_ram_banks > 0
+    }
+
+    fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
+        if self.infrared_selected {
+            0xC0 | self.infrared.borrow_mut().is_receiving() as u8
+        } else if self.is_ram_enabled() {
+            read_ram(ram, address as usize - 0xA000 + self.ram_bank_offset())
+        } else {
+            0xFF
+        }
+    }
+
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if self.infrared_selected {
+            self.infrared.get_mut().set_led(value & 0x01 != 0);
+        } else if self.is_ram_enabled() {
+            write_ram(ram, address as usize - 0xA000 + self.ram_bank_offset(),
+                      value);
+        }
+    }
+}
+
+/// MMM01 of multi-game compilation cartridges
+///
+/// After power on, the MMM01 is unmapped and shows the last 32 KiB of
+/// the ROM, which contain the menu.  The menu chooses a game by setting
+/// the upper bits of the ROM and RAM bank numbers and masks that keep
+/// them fixed, then sets the Map Enable bit.  From then on, the MMM01
+/// behaves like an MBC1 confined to the banks of that game.
+///
+/// Address    Bits  Register
+/// 0000-1FFF  6     Map Enable (unmapped only)
+///            5-4   RAM Bank Mask (unmapped only)
+///            3-0   RAM Enable (0x0A)
+/// 2000-3FFF  6-5   ROM Bank Number, bits 5–6 (unmapped only)
+///            4-0   ROM Bank Number, bits 0–4
+/// 4000-5FFF  6     Lock Banking Mode (unmapped only)
+///            5-4   ROM Bank Number, bits 7–8 (unmapped only)
+///            3-2   RAM Bank Number, bits 2–3 (unmapped only)
+///            1-0   RAM Bank Number, bits 0–1
+/// 6000-7FFF  5-2   ROM Bank Mask of bits 1–4 (unmapped only)
+///            0     Banking Mode
+///
+/// Bits set in the masks can no longer be changed once mapped.
+///
+/// https://gbdev.io/pandocs/MMM01.html
+#[derive(Serialize, Deserialize)]
+struct MMM01 {
+    rom_bank: u16,
+    ram_bank: u8,
+    num_rom_banks: u16,
+    num_ram_banks: u8,
+    rom_bank_mask: u8,
+    ram_bank_mask: u8,
+    mapped: bool,
+    banking_mode: MBC1BankingMode,
+    banking_mode_locked: bool,
+    ram_enabled: bool,
+}
+
+impl MMM01 {
+    fn from_cartridge_rom(rom: &[u8]) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::of_rom(rom);
+        Ok(Self{
+            rom_bank: 0,
+            ram_bank: 0,
+            num_rom_banks: (rom.len() / 0x4000).max(2) as u16,
+            num_ram_banks: header.num_ram_banks()?,
+            rom_bank_mask: 0,
+            ram_bank_mask: 0,
+            mapped: false,
+            banking_mode: MBC1BankingMode::Simple,
+            banking_mode_locked: false,
+            ram_enabled: false,
+        })
+    }
+
+    /// Bits of the ROM bank number that the game can change
+    fn rom_bank_writable(&self) -> u16 {
+        if self.mapped {
+            0x1F & !((self.rom_bank_mask as u16) << 1)
+        } else {
+            0x7F
+        }
+    }
+
+    /// Bits of the RAM bank number that the game can change
+    fn ram_bank_writable(&self) -> u8 {
+        if self.mapped {
+            0x03 & !self.ram_bank_mask
+        } else {
+            0x0F
+        }
+    }
+
+    fn rom0_bank_offset(&self) -> usize {
+        let bank = if self.mapped {
+            self.rom_bank & !self.rom_bank_writable()
+        } else {
+            // The menu at the end of the ROM
+            self.num_rom_banks - 2
+        };
+        0x4000 * (bank % self.num_rom_banks) as usize
+    }
+}
+
+impl MemoryControllerRegisters for MMM01 {
+    fn register_write8(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => { // RAM Enable and Map Enable
+                self.ram_enabled = value & 0x0F == 0x0A;
+                if !self.mapped {
+                    self.ram_bank_mask = (value >> 4) & 0x03;
+                    self.mapped = value & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3FFF => { // ROM Bank Number
+                let writable = self.rom_bank_writable();
+                self.rom_bank = (self.rom_bank & !writable)
+                              | (value as u16 & writable);
+            }
+            0x4000..=0x5FFF => { // RAM Bank Number
+                let writable = self.ram_bank_writable();
+                self.ram_bank = (self.ram_bank & !writable) | (value & writable);
+                if !self.mapped {
+                    self.rom_bank = (self.rom_bank & 0x7F)
+                                  | ((value as u16 >> 4) & 0x03) << 7;
+                    self.banking_mode_locked = value & 0x40 != 0;
+                }
+            }
+            0x6000..=0x7FFF => { // Banking Mode and ROM Bank Mask
+                if !self.mapped {
+                    self.rom_bank_mask = (value >> 2) & 0x0F;
+                }
+                if !self.banking_mode_locked {
+                    self.banking_mode = (value & 0x01).into();
+                }
+            }
+            _ => unreachable!("{:0>4X} is not a cartridge register.", address),
+        }
+    }
+
+    fn rom_bank_offset(&self) -> usize {
+        let bank = if self.mapped {
+            // Like on the MBC1, bank 0 can't be mapped to 4000-7FFF.
+            let writable = self.rom_bank_writable();
+            if self.rom_bank & writable == 0 {
+                self.rom_bank | 1
+            } else {
+                self.rom_bank
+            }
+        } else {
+            self.num_rom_banks - 1
+        };
+        0x4000 * (bank % self.num_rom_banks) as usize
+    }
+
+    fn ram_bank_offset(&self) -> usize {
+        let bank = match self.banking_mode {
+            MBC1BankingMode::Simple => self.ram_bank & !self.ram_bank_writable(),
+            MBC1BankingMode::Advanced => self.ram_bank,
+        };
+        0x2000 * (bank % self.num_ram_banks.max(1)) as usize
+    }
+
+    fn is_ram_enabled(&self) -> bool {
+        self.ram_enabled && self.num_ram_banks > 0
+    }
+}
+
+/// MBC6 with two independently switchable halves of ROM and RAM
+///
+/// 4000-5FFF and 6000-7FFF each show an 8 KiB bank of ROM, A000-AFFF and
+/// B000-BFFF each a 4 KiB bank of RAM.  Instead of ROM, each half of the
+/// ROM area can also show the flash memory of the cartridge, which isn't
+/// emulated, so that it reads as 0xFF.
+///
+/// Address    Register
+/// 0000-03FF  RAM Enable (0x0A)
+/// 0400-07FF  RAM Bank Number A (A000-AFFF)
+/// 0800-0BFF  RAM Bank Number B (B000-BFFF)
+/// 0C00-0FFF  Flash Enable
+/// 1000       Flash Write Enable
+/// 2000-27FF  ROM/Flash Bank Number A (4000-5FFF)
+/// 2800-2FFF  ROM/Flash Select A (0x00 = ROM, 0x08 = Flash)
+/// 3000-37FF  ROM/Flash Bank Number B (6000-7FFF)
+/// 3800-3FFF  ROM/Flash Select B (0x00 = ROM, 0x08 = Flash)
+///
+/// https://gbdev.io/pandocs/MBC6.html
+#[derive(Serialize, Deserialize)]
+struct MBC6 {
+    /// 8 KiB ROM banks of 4000-5FFF and 6000-7FFF
+    rom_banks: [u8; 2],
+    /// 4 KiB RAM banks of A000-AFFF and B000-BFFF
+    ram_banks: [u8; 2],
+    flash_selected: [bool; 2],
+    /// Number of 8 KiB ROM banks
+    num_rom_banks: u16,
+    /// Number of 4 KiB RAM banks
+    num_ram_banks: u8,
+    ram_enabled: bool,
+}
+
+impl MBC6 {
+    fn from_cartridge_header(header: &CartridgeHeader)
+            -> Result<Self, CartridgeError> {
+        Ok(Self{
+            rom_banks: [2, 3],
+            ram_banks: [0, 1],
+            flash_selected: [false; 2],
+            num_rom_banks: 2 * header.num_rom_banks(),
+            num_ram_banks: 2 * header.num_ram_banks()?,
+            ram_enabled: false,
+        })
+    }
+
+    fn romx_read8(&self, rom: &[u8], address: u16) -> u8 {
+        let half = (address as usize >> 13) & 1;
+        if self.flash_selected[half] {
+            return 0xFF;
+        }
+        rom[0x2000 * self.rom_banks[half] as usize
+            + (address as usize & 0x1FFF)]
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let half = (address as usize >> 12) & 1;
+        0x1000 * self.ram_banks[half] as usize + (address as usize & 0x0FFF)
+    }
+
+    fn rom_bank_number(&self, value: u8) -> u8 {
+        value & (self.num_rom_banks - 1) as u8
+    }
+
+    fn ram_bank_number(&self, value: u8) -> u8 {
+        value & self.num_ram_banks.saturating_sub(1)
+    }
+}
+
+impl MemoryControllerRegisters for MBC6 {
+    fn register_write8(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x03FF => { // RAM Enable
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x0400..=0x07FF => { // RAM Bank Number A
+                self.ram_banks[0] = self.ram_bank_number(value);
+            }
+            0x0800..=0x0BFF => { // RAM Bank Number B
+                self.ram_banks[1] = self.ram_bank_number(value);
+            }
+            0x0C00..=0x1FFF => { // Flash Enable and Write Enable
+                log::debug!("Ignoring write of {:0>2X} to MBC6 flash \
+                             register {:0>4X}.", value, address);
+            }
+            0x2000..=0x27FF => { // ROM/Flash Bank Number A
+                self.rom_banks[0] = self.rom_bank_number(value);
+            }
+            0x2800..=0x2FFF => { // ROM/Flash Select A
+                self.flash_selected[0] = value == 0x08;
+            }
+            0x3000..=0x37FF => { // ROM/Flash Bank Number B
+                self.rom_banks[1] = self.rom_bank_number(value);
+            }
+            0x3800..=0x3FFF => { // ROM/Flash Select B
+                self.flash_selected[1] = value == 0x08;
+            }
+            0x4000..=0x7FFF => { // Flash programming
+                log::debug!("Ignoring write of {:0>2X} to MBC6 flash at \
+                             {:0>4X}.", value, address);
+            }
+            _ => unreachable!("{:0>4X} is not a cartridge register.", address),
+        }
+    }
+
+    /// Offset of the ROM bank shown at 4000-5FFF
+    fn rom_bank_offset(&self) -> usize {
+        0x2000 * self.rom_banks[0] as usize
+    }
+
+    /// Offset of the RAM bank shown at A000-AFFF
+    fn ram_bank_offset(&self) -> usize {
+        0x1000 * self.ram_banks[0] as usize
+    }
+
+    fn is_ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn ram_read8(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        read_ram(ram, self.ram_offset(address))
+    }
+
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if self.ram_enabled {
+            write_ram(ram, self.ram_offset(address), value);
+        }
+    }
+}
+
+/// Accelerometer value of a level cartridge
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+/// Change of the accelerometer values by tilting by 1 g
+const ACCELEROMETER_PER_G: f32 = 0x70 as f32;
+
+/// MBC7 with a 2-axis accelerometer and a 93LC56 EEPROM
+///
+/// Instead of RAM, A000-AFFF holds registers, which are selected by
+/// bits 4–7 of the address, once 0x0A has been written to 0000-1FFF and
+/// 0x40 to 4000-5FFF.
+///
+/// Register  Contents
+/// Ax0x      Write 0x55 to erase the latched accelerometer values
+/// Ax1x      Write 0xAA to latch the accelerometer values after erasing
+/// Ax2x      Accelerometer X, low byte
+/// Ax3x      Accelerometer X, high byte
+/// Ax4x      Accelerometer Y, low byte
+/// Ax5x      Accelerometer Y, high byte
+/// Ax8x      EEPROM pins, see `Eeprom`
+///
+/// https://gbdev.io/pandocs/MBC7.html
+#[derive(Serialize, Deserialize)]
+struct MBC7 {
+    rom_bank: u8,
+    num_rom_banks: u16,
+    ram_enabled: bool,
+    registers_enabled: bool,
+    /// Tilt to the right and towards the player in g
+    tilt: (f32, f32),
+    /// Latched accelerometer X and Y values
+    accelerometer: (u16, u16),
+    accelerometer_erased: bool,
+    eeprom: Eeprom,
+}
+
+impl MBC7 {
+    fn from_cartridge_header(header: &CartridgeHeader) -> Self {
+        Self{
+            rom_bank: 1,
+            num_rom_banks: header.num_rom_banks(),
+            ram_enabled: false,
+            registers_enabled: false,
+            tilt: (0.0, 0.0),
+            accelerometer: (0x8000, 0x8000),
+            accelerometer_erased: false,
+            eeprom: Eeprom::default(),
+        }
+    }
+
+    fn latch_accelerometer(&mut self) {
+        // Both axes decrease when tilting towards them.
+        let value = |tilt: f32| {
+            (ACCELEROMETER_CENTER - ACCELEROMETER_PER_G * tilt.clamp(-1.0, 1.0))
+                as u16
+        };
+        self.accelerometer = (value(self.tilt.0), value(self.tilt.1));
+    }
+}
+
+impl MemoryControllerRegisters for MBC7 {
+    fn register_write8(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => { // RAM Enable 1
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => { // ROM Bank Number
+                let mask = (self.num_rom_banks - 1) as u8;
+                self.rom_bank = value & mask;
+            }
+            0x4000..=0x5FFF => { // RAM Enable 2
+                self.registers_enabled = value == 0x40;
+            }
+            0x6000..=0x7FFF => {}
+            _ => unreachable!("{:0>4X} is not a cartridge register.", address),
+        }
+    }
+
+    fn rom_bank_offset(&self) -> usize {
+        0x4000 * self.rom_bank as usize
+    }
+
+    fn ram_bank_offset(&self) -> usize {
+        0
+    }
+
+    fn is_ram_enabled(&self) -> bool {
+        self.ram_enabled && self.registers_enabled
+    }
+
+    fn ram_read8(&self, _ram: &[u8], address: u16) -> u8 {
+        if !self.is_ram_enabled() || address >= 0xB000 {
+            return 0xFF;
+        }
+        let (x, y) = self.accelerometer;
+        match (address >> 4) & 0x0F {
+            0x2 => x as u8,
+            0x3 => (x >> 8) as u8,
+            0x4 => y as u8,
+            0x5 => (y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn ram_write8(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.is_ram_enabled() || address >= 0xB000 {
+            return;
+        }
+        match (address >> 4) & 0x0F {
+            0x0 if value == 0x55 => {
+                self.accelerometer = (0x8000, 0x8000);
+                self.accelerometer_erased = true;
+            }
+            0x1 if value == 0xAA && self.accelerometer_erased => {
+                self.latch_accelerometer();
+                self.accelerometer_erased = false;
+            }
+            0x8 => self.eeprom.write(value, ram),
+            _ => {}
+        }
+    }
+}
+
+/// Nintendo logo, which the boot ROM compares against the header
+pub const LOGO: [u8; 0x30] = [
      0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B,
      0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
      0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
@@ -669,6 +1764,26 @@ pub struct CartridgeHeader<'a> {
 }
 
 impl<'a> CartridgeHeader<'a> {
+    pub fn logo(&self) -> &'a [u8] {
+        &self.rom[0x104..=0x133]
+    }
+
+    /// Header of a ROM, which is that of the menu for MMM01 cartridges
+    ///
+    /// The menu of MMM01 cartridges is in the last 32 KiB of the ROM, so
+    /// that the header at the start of the ROM belongs to the first game.
+    pub fn of_rom(rom: &'a [u8]) -> Self {
+        if rom.len() > 0x8000 {
+            let menu = CartridgeHeader{rom: &rom[rom.len() - 0x8000..]};
+            if menu.is_logo_correct()
+               && matches!(menu.cartridge_type().memory_controller(),
+                           Ok(MemoryControllerModel::MMM01)) {
+                return menu;
+            }
+        }
+        CartridgeHeader{rom}
+    }
+
     pub fn is_logo_correct(&self) -> bool {
         self.rom[0x104..=0x133] == LOGO
     }
@@ -713,17 +1828,26 @@ impl<'a> CartridgeHeader<'a> {
     }
 
     /// Number of RAM banks of 8KB each
-    pub fn num_ram_banks(&self) -> u8 {
-        match self.rom[0x0149] {
+    pub fn num_ram_banks(&self) -> Result<u8, CartridgeError> {
+        Ok(match self.rom[0x0149] {
             0x00 => 0,
-            // 0x01 is used by some public domain ROMs
+            // 0x01 is used by some public domain ROMs for a single
+            // bank of which only 2 KiB exist.
             // https://gbdev.io/pandocs/#_0149-ram-size
-            0x01 => unimplemented!("Unknown RAM size 0x01"),
+            0x01 => 1,
             0x02 => 1,
             0x03 => 4,
             0x04 => 16,
             0x05 => 8,
-            num => unimplemented!("Unknown RAM size 0x{:0>2X}", num),
+            num => return Err(CartridgeError::UnknownRamSize(num)),
+        })
+    }
+
+    /// Size of the cartridge RAM in bytes
+    pub fn ram_size(&self) -> Result<usize, CartridgeError> {
+        match self.rom[0x0149] {
+            0x01 => Ok(2 * 1024),
+            _ => Ok(self.num_ram_banks()? as usize * 8 * 1024),
         }
     }
 
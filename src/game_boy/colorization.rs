@@ -0,0 +1,183 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use super::cartridge::CartridgeHeader;
+
+/// Colors of the background and the two object palettes of a DMG game
+///
+/// Each palette maps the four shades from lightest to darkest to
+/// RGB colors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorPalettes {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl ColorPalettes {
+    /// Use the same colors for background and objects.
+    pub const fn monochrome(colors: [u32; 4]) -> Self {
+        Self{
+            bg: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+
+    /// Colors indexed by the pixel values passed to `IO::refresh`
+    pub fn lookup_table(&self) -> [u32; 12] {
+        let mut table = [0; 12];
+        table[0..4].copy_from_slice(&self.bg);
+        table[4..8].copy_from_slice(&self.obj0);
+        table[8..12].copy_from_slice(&self.obj1);
+        table
+    }
+}
+
+const BROWN: [u32; 4] = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const RED: [u32; 4] = [0xFFFFFF, 0xFF8584, 0x943A3A, 0x000000];
+const DARK_BROWN: [u32; 4] = [0xFFE7C5, 0xCE9C85, 0x846B29, 0x5A3108];
+const BLUE: [u32; 4] = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+const DARK_BLUE: [u32; 4] = [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000];
+const GRAY: [u32; 4] = [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000];
+const PASTEL: [u32; 4] = [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000];
+const ORANGE: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000];
+const YELLOW: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000];
+const GREEN: [u32; 4] = [0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000];
+const DARK_GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+const OBJ_RED: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const OBJ_GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const INVERTED: [u32; 4] = [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF];
+
+/// Palettes that can be selected by holding a button combination
+/// during the boot animation of a Game Boy Color
+///
+/// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+pub const MANUAL_PALETTES: [(&str, ColorPalettes); 12] = [
+    ("up", ColorPalettes::monochrome(BROWN)),
+    ("up-a", ColorPalettes::monochrome(RED)),
+    ("up-b", ColorPalettes::monochrome(DARK_BROWN)),
+    ("left", ColorPalettes{bg: BLUE, obj0: OBJ_RED, obj1: BLUE}),
+    ("left-a", ColorPalettes{bg: DARK_BLUE, obj0: OBJ_RED, obj1: BROWN}),
+    ("left-b", ColorPalettes::monochrome(GRAY)),
+    ("down", ColorPalettes::monochrome(PASTEL)),
+    ("down-a", ColorPalettes::monochrome(ORANGE)),
+    ("down-b", ColorPalettes{bg: YELLOW, obj0: BLUE, obj1: OBJ_GREEN}),
+    ("right", ColorPalettes::monochrome(GREEN)),
+    ("right-a", ColorPalettes{bg: DARK_GREEN, obj0: OBJ_RED, obj1: OBJ_RED}),
+    ("right-b", ColorPalettes::monochrome(INVERTED)),
+];
+
+/// Palettes of games that the Game Boy Color doesn't recognize
+pub const DEFAULT_PALETTES: ColorPalettes
+    = ColorPalettes{bg: DARK_GREEN, obj0: OBJ_RED, obj1: OBJ_RED};
+
+/// Palettes chosen for specific games by their title checksum
+///
+/// Entries are (title checksum, 4th letter of the title, palettes).  The
+/// 4th letter is only checked for checksums that are shared by multiple
+/// titles.  The CGB boot ROM knows about 90 titles; this table only
+/// covers some of them, other titles get the default palettes.
+const TITLE_PALETTES: [(u8, Option<u8>, ColorPalettes); 3] = [
+    (0x14, None, ColorPalettes::monochrome(RED)),  // POKEMON RED
+    (0x61, Some(b'E'),  // POKEMON BLUE
+     ColorPalettes{bg: BLUE, obj0: OBJ_RED, obj1: BLUE}),
+    (0xAA, None, ColorPalettes::monochrome(GREEN)),  // POKEMON GREEN
+];
+
+/// Look up manually selectable palettes by name, e.g. "left-b".
+pub fn manual_palettes(name: &str) -> Option<ColorPalettes> {
+    MANUAL_PALETTES.iter()
+                   .find(|(n, _)| *n == name)
+                   .map(|(_, palettes)| *palettes)
+}
+
+/// Checksum of the cartridge title as computed by the CGB boot ROM
+///
+/// Only games published by Nintendo are colorized by title, so
+/// return None for games of other publishers.
+pub fn title_checksum(header: &CartridgeHeader) -> Option<u8> {
+    let is_nintendo = if header.uses_new_licensee_code() {
+        header.new_licensee_code() == Some("01")
+    } else {
+        header.old_licensee_code() == 0x01
+    };
+    if !is_nintendo {
+        return None;
+    }
+    Some(header.title().iter()
+               .fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+/// Choose palettes for a DMG game like the CGB boot ROM does
+pub fn automatic_palettes(header: &CartridgeHeader) -> ColorPalettes {
+    let checksum = match title_checksum(header) {
+        Some(checksum) => checksum,
+        None => return DEFAULT_PALETTES,
+    };
+    let fourth_letter = header.title()[3];
+    TITLE_PALETTES.iter()
+                  .find(|(c, letter, _)| {
+                      *c == checksum
+                      && letter.is_none_or(|l| l == fourth_letter)
+                  })
+                  .map(|(_, _, palettes)| *palettes)
+                  .unwrap_or(DEFAULT_PALETTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a ROM with the given title and old licensee code
+    fn rom_with_title(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn nintendo_games_are_colorized_by_title() {
+        let palettes = |title: &[u8]| {
+            automatic_palettes(&CartridgeHeader::of_rom(
+                &rom_with_title(title, 0x01)))
+        };
+        assert_eq!(palettes(b"POKEMON RED"), ColorPalettes::monochrome(RED));
+        assert_eq!(palettes(b"POKEMON GREEN"),
+                   ColorPalettes::monochrome(GREEN));
+        assert_eq!(palettes(b"POKEMON BLUE").bg, BLUE);
+        // Same checksum as POKEMON BLUE, but another 4th letter
+        assert_eq!(palettes(b"POKFMON BLUD"), DEFAULT_PALETTES);
+        assert_eq!(palettes(b"TETRIS"), DEFAULT_PALETTES);
+    }
+
+    #[test]
+    fn only_nintendo_games_have_a_title_checksum() {
+        let rom = rom_with_title(b"POKEMON RED", 0x01);
+        assert_eq!(title_checksum(&CartridgeHeader::of_rom(&rom)), Some(0x14));
+        let rom = rom_with_title(b"POKEMON RED", 0x08);
+        let header = CartridgeHeader::of_rom(&rom);
+        assert_eq!(title_checksum(&header), None);
+        assert_eq!(automatic_palettes(&header), DEFAULT_PALETTES);
+        // New licensee codes are checked for "01" instead.
+        let mut rom = rom_with_title(b"POKEMON RED", 0x33);
+        rom[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(title_checksum(&CartridgeHeader::of_rom(&rom)), Some(0x14));
+        rom[0x144..0x146].copy_from_slice(b"08");
+        assert_eq!(title_checksum(&CartridgeHeader::of_rom(&rom)), None);
+    }
+
+    #[test]
+    fn manual_palettes_are_found_by_name() {
+        assert_eq!(manual_palettes("right-b"),
+                   Some(ColorPalettes::monochrome(INVERTED)));
+        assert_eq!(manual_palettes("left").map(|p| p.obj0), Some(OBJ_RED));
+        assert_eq!(manual_palettes("auto"), None);
+        let table = manual_palettes("down-b").unwrap().lookup_table();
+        assert_eq!(table[..4], YELLOW);
+        assert_eq!(table[4..8], BLUE);
+        assert_eq!(table[8..], OBJ_GREEN);
+    }
+}
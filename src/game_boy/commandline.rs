@@ -2,17 +2,106 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::fmt::Display;
 use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use clap::{Arg, ArgMatches, Command};
 
-use super::cartridge::CartridgeHeader;
+use super::accuracy::Strictness;
+#[cfg(feature = "sound")]
+use super::audio_device::DeviceSink;
+use super::boot_rom::Model;
+use super::camera::{self, StillImage};
+use super::cartridge::{CartridgeHeader, HeaderOverrides,
+                       MemoryControllerModel};
+use super::colorization::{self, ColorPalettes};
+use super::compat;
+use super::debugger::{Breakpoint, Watchpoint};
 use super::emulator_window::EmulatorWindow;
-use super::GameBoy;
+#[cfg(feature = "wgpu")]
+use super::gpu_window::{GpuWindow, ScreenShader};
+use super::infrared::{InfraredSocket, Loopback};
+use super::io::{IO, HEIGHT, SGB_HEIGHT, SGB_WIDTH, WIDTH};
+use super::link::LinkCable;
+use super::ppu::ScrollLatch;
+use super::recording;
+use super::rom_database::{RomDatabase, RomEntry};
+use super::rtc::RtcStart;
+use super::save_state::{self, StateInfo};
+use super::scripting::Script;
+use super::serial::{self, TestOutcome};
+use super::sgb;
+use super::terminal::TerminalWindow;
+use super::threaded::{self, WindowThread};
+use super::viewer::View;
+use super::{GameBoy, GameBoyBuilder};
+use crate::frame_pacer;
+use crate::logging;
+use crate::settings::GameBoySettings;
+use crate::window_scale;
+
+/// Values of `--frontend`, of which wgpu needs the feature of the same
+/// name
+#[cfg(feature = "wgpu")]
+const FRONTENDS: [&str; 3] = ["window", "terminal", "wgpu"];
+#[cfg(not(feature = "wgpu"))]
+const FRONTENDS: [&str; 2] = ["window", "terminal"];
 
 pub fn game_boy_subcommand<'a>() -> Command<'a> {
     Command::new("gameboy")
     .about("A Game Boy emulator")
+    .args_conflicts_with_subcommands(true)
+    .subcommand_negates_reqs(true)
+    .subcommand(
+        Command::new("test-rom")
+        .about("run a test ROM headlessly, print its serial output and exit with 0 if it passes, 1 if it fails or 2 without result")
+        .arg(
+            Arg::new("rom")
+                .help("test ROM that reports \"Passed\" or \"Failed\" over the link port like Blargg's, or executes LD B,B with the Fibonacci numbers in its registers when passing like mooneye's")
+                .index(1)
+                .required(true),
+        )
+        .arg(
+            Arg::new("frames")
+                .help("maximum number of frames to wait for the result")
+                .takes_value(true)
+                .long("frames")
+                .default_value("10000")
+        )
+    )
+    .subcommand(
+        Command::new("compat")
+        .about("run all ROMs in a directory headlessly and report which crash")
+        .arg(
+            Arg::new("rom-dir")
+                .help("directory containing .gb and .gbc files")
+                .index(1)
+                .required(true),
+        )
+        .arg(
+            Arg::new("frames")
+                .help("number of frames to run each ROM for")
+                .takes_value(true)
+                .long("frames")
+                .default_value("300")
+        )
+        .arg(
+            Arg::new("format")
+                .help("format of the compatibility report")
+                .takes_value(true)
+                .long("format")
+                .default_value("markdown")
+                .possible_values(["markdown", "json"])
+        )
+        .arg(
+            Arg::new("output")
+                .help("write report to file instead of stdout")
+                .takes_value(true)
+                .long("output")
+        )
+    )
     .arg(
         Arg::new("cartridge-file")
             .help("a ROM file to load into the emulator")
@@ -25,35 +114,953 @@ pub fn game_boy_subcommand<'a>() -> Command<'a> {
             .takes_value(true)
             .long("boot-rom")
     )
+    .arg(
+        Arg::new("skip-boot")
+            .help("start the cartridge right away with the register values that the boot ROM would leave behind")
+            .long("skip-boot")
+            .conflicts_with("boot-rom")
+    )
+    .arg(
+        Arg::new("model")
+            .help("hardware model whose post-boot register values are set up when not using a boot ROM, sgb also enables SGB borders and palettes")
+            .takes_value(true)
+            .long("model")
+            .default_value("dmg")
+            .possible_values(["dmg", "mgb", "sgb", "cgb"])
+    )
+    .arg(
+        Arg::new("rtc")
+            .help("time that the real time clock of MBC3 cartridges starts at: host for the current time, fixed for the UNIX epoch or a UNIX timestamp")
+            .takes_value(true)
+            .long("rtc")
+            .default_value("host")
+            .validator(|name| RtcStart::from_name(name)
+                                       .ok_or("expected host, fixed or a UNIX timestamp"))
+    )
+    .arg(
+        Arg::new("colorize")
+            .help("colorize the game like a Game Boy Color, either with palettes chosen by title or with the palettes of a button combination")
+            .takes_value(true)
+            .long("colorize")
+            .possible_values(colorization_choices())
+    )
+    .arg(
+        Arg::new("serial")
+            .help("device connected to the link port, the printer saves its printouts into the current directory")
+            .takes_value(true)
+            .long("serial")
+            .default_value("none")
+            .possible_values(["none", "console", "printer"])
+    )
+    .arg(
+        Arg::new("link")
+            .help("connect the link port to another emulator at host:port or unix:path, waiting for it to connect if it isn't running yet")
+            .takes_value(true)
+            .long("link")
+    )
+    .arg(
+        Arg::new("infrared")
+            .help("what the IR port of the CGB or of HuC1 cartridges faces: loopback for a mirror, or another emulator at host:port or unix:path")
+            .takes_value(true)
+            .long("infrared")
+    )
+    .arg(
+        Arg::new("show-input")
+            .help("draw the pressed buttons into the bottom left corner of the screen")
+            .long("show-input")
+    )
+    .arg(
+        Arg::new("vram-viewer")
+            .help("show the tiles, both BG maps and the objects in OAM in extra windows")
+            .long("vram-viewer")
+    )
+    .arg(
+        Arg::new("dump-vram-after")
+            .help("write the tiles, BG maps and OAM as PNG files next to the ROM after the given number of frames, as F12 does at any time")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("dump-vram-after")
+    )
+    .arg(
+        Arg::new("clip-format")
+            .help("format of the gameplay clips that F9 starts and stops recording, written next to the ROM")
+            .takes_value(true)
+            .long("clip-format")
+            .possible_values(recording::AVAILABLE_CLIP_FORMATS)
+            .default_value("gif")
+    )
+    .arg(
+        Arg::new("log-input")
+            .help("print the pressed buttons with their frame number whenever they change")
+            .long("log-input")
+    )
+    .arg(
+        Arg::new("frontend")
+            .help("where to show the screen and read the buttons from: a window, the terminal for running over SSH, or a window drawn by the GPU with --shader (if built with the wgpu feature)")
+            .takes_value(true)
+            .long("frontend")
+            .possible_values(FRONTENDS)
+            .default_value("window")
+    )
+    .arg(
+        Arg::new("scale")
+            .help("show each screen pixel as NxN pixels, between 1 and 16 [default: the largest scale at which the window fits a 1280x720 screen, or from settings]")
+            .takes_value(true)
+            .value_name("N")
+            .long("scale")
+            .validator(window_scale::parse_scale)
+    )
+    .arg(
+        Arg::new("ghosting")
+            .help("blend each frame with the one before, like the slow LCD of the original Game Boy, which makes objects that flicker every other frame look transparent")
+            .long("ghosting")
+    )
+    .arg(
+        Arg::new("shader")
+            .help("WGSL shader that draws the screen with --frontend wgpu: one of none, scanlines, lcd-grid and crt, or a file defining fs_main")
+            .takes_value(true)
+            .value_name("name|file")
+            .long("shader")
+            .default_value("none")
+    )
+    .arg(
+        Arg::new("no-throttle")
+            .help("run as fast as possible instead of at 60 frames per second")
+            .long("no-throttle")
+    )
+    .arg(
+        Arg::new("speed")
+            .help("run at this multiple of the real speed, e.g. 0.25 for slow motion, or as fast as possible with max")
+            .takes_value(true)
+            .value_name("factor|max")
+            .long("speed")
+            .validator(frame_pacer::parse_speed)
+            .conflicts_with("no-throttle")
+    )
+    .arg(
+        Arg::new("headless")
+            .help("run without a window or any other frontend and as fast as possible, e.g. to take screenshots in automated checks")
+            .long("headless")
+            .requires("frames")
+    )
+    .arg(
+        Arg::new("frames")
+            .help("quit after emulating this many frames")
+            .takes_value(true)
+            .value_name("N")
+            .long("frames")
+    )
+    .arg(
+        Arg::new("screenshot")
+            .help("write the screen as PNG image when the emulator quits")
+            .takes_value(true)
+            .value_name("out.png")
+            .long("screenshot")
+    )
+    .arg(
+        Arg::new("rewind-seconds")
+            .help("how many seconds can be rewound by holding Backspace (0 disables rewinding)")
+            .takes_value(true)
+            .long("rewind-seconds")
+            .default_value("30")
+    )
+    .arg(
+        Arg::new("per-fetch-scroll")
+            .help("read the scroll registers on every background tile fetch instead of once per line (slower, but renders mid-line scroll effects)")
+            .long("per-fetch-scroll")
+    )
+    .arg(
+        Arg::new("fast-render")
+            .help("draw frames from a cache of decoded tiles, falling back to drawing line by line once a frame changes VRAM, palettes or scrolling mid-frame (faster, but mode 3 timing is only estimated)")
+            .long("fast-render")
+            .conflicts_with("per-fetch-scroll")
+    )
+    .arg(
+        Arg::new("track-uninitialized-reads")
+            .help("report reads of WRAM and HRAM bytes that have never been written")
+            .long("track-uninitialized-reads")
+    )
+    .arg(
+        Arg::new("strict")
+            .help("report the first time the game runs into each known inaccuracy of the emulator, either as a warning or by stopping with an error")
+            .takes_value(true)
+            .long("strict")
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("warn")
+            .possible_values(Strictness::NAMES)
+    )
+    .arg(
+        Arg::new("trace")
+            .help("log registers and the next 4 bytes at PC before each instruction in the format of Game Boy Doctor")
+            .takes_value(true)
+            .value_name("log.txt")
+            .long("trace")
+    )
+    .arg(
+        Arg::new("compare-trace")
+            .help("compare the registers before each instruction with a trace of another emulator, e.g. in the format of Game Boy Doctor, and stop at the first difference or break into the debugger with --debug")
+            .takes_value(true)
+            .value_name("log.txt")
+            .long("compare-trace")
+    )
+    .arg(
+        Arg::new("dump-state")
+            .help("write the CPU and I/O registers, the timer and the memory controller as JSON into a file when the emulator exits, even by a crash")
+            .takes_value(true)
+            .value_name("state.json")
+            .long("dump-state")
+    )
+    .arg(
+        Arg::new("sym")
+            .help("show labels from an RGBDS symbol file in traces and logs, defaults to the ROM file name with a .sym extension if it exists")
+            .takes_value(true)
+            .value_name("game.sym")
+            .long("sym")
+    )
+    .arg(
+        Arg::new("debug")
+            .help("break into the debugger on stdin after the first instruction")
+            .long("debug")
+    )
+    .arg(
+        Arg::new("profile")
+            .help("count the cycles spent at each ROM bank and address, and print the hottest routines when the emulator exits")
+            .long("profile")
+    )
+    .arg(
+        Arg::new("coverage")
+            .help("record which ROM bytes get executed and write them as a code/data log with one byte of flags per ROM byte when the emulator exits (1: opcode, 2: operand)")
+            .takes_value(true)
+            .long("coverage")
+    )
+    .arg(
+        Arg::new("script")
+            .help("run a Rhai script that hooks functions to frames, PC hits and memory accesses, e.g. \"on_write(0xD0A2, |address, level| print(level));\"")
+            .takes_value(true)
+            .long("script")
+    )
+    .arg(
+        Arg::new("break")
+            .help("break into the debugger before the instruction at a hex address, optionally only if a condition holds, e.g. \"0150 if A==0x3C && C>0x10\"")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("break")
+    )
+    .arg(
+        Arg::new("watch")
+            .help("break into the debugger when the CPU accesses an address, e.g. w:C0A0, r:FF44 or rw:C000-C0FF")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("watch")
+    )
+    .arg(
+        Arg::new("dump-audio")
+            .help("record audio output to a WAV file")
+            .takes_value(true)
+            .value_name("out.wav")
+            .long("dump-audio")
+    )
+    .arg(
+        Arg::new("mute")
+            .help("don't play the audio on the sound card, which is only done if the emulator has been built with the sound feature")
+            .long("mute")
+    )
+    .arg(
+        Arg::new("audio-latency-ms")
+            .help("milliseconds of audio that are queued before they are played, higher values avoid crackling sound on slow machines [default: 50, or from settings]")
+            .takes_value(true)
+            .value_name("ms")
+            .long("audio-latency-ms")
+            .validator(|ms| ms.parse::<u32>()
+                              .map_err(|_| format!("{} is not a number of milliseconds.", ms)))
+    )
+    .arg(
+        Arg::new("audio-buffer-size")
+            .help("number of frames that the sound card plays per buffer, larger buffers avoid crackling sound but increase the latency [default: chosen by the sound card, or from settings]")
+            .takes_value(true)
+            .value_name("frames")
+            .long("audio-buffer-size")
+            .validator(|frames| frames.parse::<u32>()
+                                      .map_err(|_| format!("{} is not a number of frames.", frames)))
+    )
+    .arg(
+        Arg::new("record-vgm")
+            .help("record sound register writes to a VGM file")
+            .takes_value(true)
+            .value_name("out.vgm")
+            .long("record-vgm")
+    )
+    .arg(
+        Arg::new("record-movie")
+            .help("record the input of every frame into a movie file")
+            .takes_value(true)
+            .value_name("out.gbm")
+            .long("record-movie")
+            .conflicts_with("play-movie")
+    )
+    .arg(
+        Arg::new("play-movie")
+            .help("replay the input recorded with --record-movie, then continue with keyboard input")
+            .takes_value(true)
+            .value_name("in.gbm")
+            .long("play-movie")
+    )
+    .arg(
+        Arg::new("write-frame-hashes")
+            .help("write a hash of the screen, CPU and memory after every frame into a file")
+            .takes_value(true)
+            .long("write-frame-hashes")
+            .conflicts_with("compare-frame-hashes")
+    )
+    .arg(
+        Arg::new("compare-frame-hashes")
+            .help("compare the hash of every frame with a file written by --write-frame-hashes and stop after its last frame")
+            .takes_value(true)
+            .long("compare-frame-hashes")
+    )
+    .arg(
+        Arg::new("camera-image")
+            .help("PNG image that the Game Boy Camera takes pictures of")
+            .takes_value(true)
+            .long("camera-image")
+    )
+    .arg(
+        Arg::new("mmap-save")
+            .help("memory-map the save file, so that every write to the cartridge RAM goes straight into it")
+            .long("mmap-save")
+    )
+    .arg(
+        Arg::new("force-mbc")
+            .help("use the given memory controller instead of the one declared in the cartridge header")
+            .takes_value(true)
+            .long("force-mbc")
+            .possible_values(["none", "mbc1", "mbc2", "mbc3", "mbc5", "mbc6",
+                              "mbc7", "mmm01", "huc1", "camera"])
+    )
+    .arg(
+        Arg::new("force-ram-size")
+            .help("use the given cartridge RAM size in KiB instead of the one declared in the cartridge header")
+            .takes_value(true)
+            .long("force-ram-size")
+            .possible_values(["0", "2", "8", "32", "64", "128"])
+    )
     .arg(
         Arg::new("dump-header")
             .help("print cartridge header")
             .long("dump-header")
     )
+    .arg(
+        Arg::new("rom-database")
+            .help("TOML file with titles, mappers and regions of games in addition to the built-in ROM database")
+            .takes_value(true)
+            .long("rom-database")
+    )
+    .arg(
+        Arg::new("export-photos")
+            .help("write the photos in the save file of the Game Boy Camera as PNG files into the given directory")
+            .takes_value(true)
+            .long("export-photos")
+    )
+    .arg(
+        Arg::new("list-states")
+            .help("list the save state slots of the ROM with the time and frame at which they were saved")
+            .long("list-states")
+    )
 }
 
-pub fn run_game_boy_from_subcommand(subcommand: &ArgMatches) {
-    let mut builder = GameBoy::<EmulatorWindow>::builder();
+/// Run the Game Boy emulator
+///
+/// Command line arguments take precedence over the settings.
+pub fn run_game_boy_from_subcommand(subcommand: &ArgMatches,
+                                    settings: &GameBoySettings) {
+    match subcommand.subcommand() {
+        Some(("compat", subcommand)) => {
+            run_compatibility_check_from_subcommand(subcommand);
+            return;
+        }
+        Some(("test-rom", subcommand)) => {
+            run_test_rom_from_subcommand(subcommand);
+            return;
+        }
+        _ => {}
+    }
+    if subcommand.occurrences_of("shader") > 0
+            && subcommand.value_of("frontend") != Some("wgpu") {
+        eprintln!("--shader only applies to --frontend wgpu");
+        std::process::exit(1);
+    }
+    let mut builder = GameBoy::<Box<dyn IO + Send>>::builder();
     let filename = subcommand.value_of("cartridge-file").unwrap();
     let f = File::open(filename).unwrap();
-    builder = builder.load_cartridge(f).unwrap();
-    if let Some(boot_rom) = subcommand.value_of("boot-rom") {
+    builder = match builder.load_cartridge(f) {
+        Ok(builder) => builder,
+        Err(error) => {
+            eprintln!("Can't load {}: {}", filename, error);
+            std::process::exit(1);
+        }
+    };
+    let overrides = HeaderOverrides{
+        memory_controller: subcommand.value_of("force-mbc").map(|name| {
+            MemoryControllerModel::from_name(name).unwrap()
+        }),
+        ram_size: subcommand.value_of("force-ram-size").map(|kib| {
+            kib.parse::<usize>().unwrap() * 1024
+        }),
+    };
+    if overrides != HeaderOverrides::default() {
+        builder = match builder.override_cartridge_header(&overrides) {
+            Ok(builder) => builder,
+            Err(error) => {
+                eprintln!("Can't override header of {}: {}", filename, error);
+                std::process::exit(1);
+            }
+        };
+    }
+    let header = builder.get_cartridge_header().unwrap();
+    logging::add_crash_context(format!(
+            "Game Boy cartridge: title {:?}, type {:?}, global checksum {:0>4X}",
+            String::from_utf8_lossy(header.title()).trim_end_matches('\0'),
+            header.cartridge_type(), header.global_checksum()));
+    let mut rom_database = RomDatabase::default();
+    if let Some(database_file) = subcommand.value_of("rom-database") {
+        if let Err(e) = rom_database.load(Path::new(database_file)) {
+            log::warn!("Not using ROM database {}: {}", database_file, e);
+        }
+    }
+    let game = rom_database.lookup(builder.get_cartridge_rom().unwrap());
+    if let Some(game) = game {
+        log::info!("Found {} in ROM database.", game.title);
+        if !game.matches_header(&header) {
+            log::warn!("Header declares cartridge type {:?}, but {} uses {:?}.",
+                       header.cartridge_type(), game.title, game.mapper);
+        }
+    }
+    let model = match subcommand.value_of("model").unwrap() {
+        "mgb" => Model::MGB,
+        "sgb" => Model::SGB,
+        "cgb" => Model::CGB,
+        _ => Model::DMG,
+    };
+    builder = builder.use_model(model);
+    let rtc_start = RtcStart::from_name(subcommand.value_of("rtc").unwrap())
+                             .unwrap();
+    builder = builder.use_rtc_start(rtc_start);
+    logging::add_crash_context(format!("Game Boy model: {:?}", model));
+    builder = choose_boot_rom(builder, subcommand, settings);
+    let sym_file = match subcommand.value_of("sym") {
+        Some(sym_file) => Some(PathBuf::from(sym_file)),
+        None => Some(Path::new(filename).with_extension("sym"))
+                    .filter(|path| path.is_file()),
+    };
+    if let Some(sym_file) = sym_file {
+        let action = format!("Can't load symbols from {}",
+                             sym_file.display());
+        let f = or_exit(File::open(&sym_file), &action);
+        builder = or_exit(builder.load_symbols(f), &action);
+        log::info!("Loaded symbols from {}.", sym_file.display());
+    }
+    if let Some(wav_file) = subcommand.value_of("dump-audio") {
+        let f = or_exit(File::create(wav_file),
+                        &format!("Can't create {}", wav_file));
+        builder = or_exit(builder.dump_audio(f),
+                          &format!("Can't write {}", wav_file));
+    }
+    if subcommand.is_present("dump-header") {
+        print_cartridge_header(builder.get_cartridge_header().unwrap(),
+                               game)
+    } else if let Some(directory) = subcommand.value_of("export-photos") {
+        let save_file = Path::new(filename).with_extension("sav");
+        let ram = or_exit(std::fs::read(&save_file),
+                          &format!("Can't read {}", save_file.display()));
+        or_exit(camera::export_photos(&ram, Path::new(directory)),
+                &format!("Can't export photos to {}", directory));
+    } else if subcommand.is_present("list-states") {
+        list_save_states(Path::new(filename));
+    } else {
+        let colorization = subcommand.value_of("colorize")
+                                     .or(settings.colorization.as_deref());
+        let palettes = colorization.map(|name| {
+            let header = builder.get_cartridge_header().unwrap();
+            color_palettes(name, &header)
+        });
+        let sgb_border = model == Model::SGB
+            && sgb::is_enhanced(&builder.get_cartridge_header().unwrap());
+        let headless = subcommand.is_present("headless");
+        let (frontend, window) = if headless {
+            (Box::new(compat::NoWindow) as Box<dyn IO + Send>, None)
+        } else if subcommand.value_of("frontend") == Some("terminal") {
+            (open_terminal(settings), None)
+        } else {
+            let scale = subcommand.value_of("scale");
+            let ghosting = subcommand.is_present("ghosting")
+                           || settings.ghosting;
+            #[cfg(feature = "wgpu")]
+            if subcommand.value_of("frontend") == Some("wgpu") {
+                let shader = subcommand.value_of("shader").unwrap();
+                open_gpu_window(settings, palettes.as_ref(), sgb_border,
+                                scale, ghosting, shader)
+            } else {
+                open_emulator_window(settings, palettes.as_ref(),
+                                     sgb_border, scale, ghosting)
+            }
+            #[cfg(not(feature = "wgpu"))]
+            open_emulator_window(settings, palettes.as_ref(), sgb_border,
+                                 scale, ghosting)
+        };
+        #[cfg(feature = "sound")]
+        if !headless && !subcommand.is_present("mute") {
+            let latency_ms = subcommand.value_of("audio-latency-ms")
+                                       .map(|ms| ms.parse().unwrap())
+                                       .unwrap_or(settings.audio_latency_ms);
+            let buffer_size = subcommand.value_of("audio-buffer-size")
+                                        .map(|frames| frames.parse().unwrap())
+                                        .or(settings.audio_buffer_size);
+            let latency = std::time::Duration::from_millis(latency_ms as u64);
+            match DeviceSink::open(latency, buffer_size) {
+                Ok(sink) => builder = builder.play_audio(Box::new(sink)),
+                Err(error) => log::warn!("Can't play audio: {}", error),
+            }
+        }
+        let mut game_boy = match builder.use_emulator_window(frontend)
+                                        .build() {
+            Ok(game_boy) => game_boy,
+            Err(error) => {
+                eprintln!("Can't run {}: {}", filename, error);
+                std::process::exit(1);
+            }
+        };
+        game_boy.use_state_slots(PathBuf::from(filename));
+        let palettes = palettes.unwrap_or(ColorPalettes::monochrome(
+                                              settings.palette));
+        game_boy.use_vram_dumps(PathBuf::from(filename), &palettes);
+        let clip_format = subcommand.value_of("clip-format").unwrap()
+                                    .parse().unwrap();
+        game_boy.use_clip_recording(PathBuf::from(filename), &palettes,
+                                    clip_format);
+        let save_file = Path::new(filename).with_extension("sav");
+        let action = format!("Can't use {}", save_file.display());
+        if subcommand.is_present("mmap-save") {
+            or_exit(game_boy.map_save_file(save_file), &action);
+        } else {
+            or_exit(game_boy.use_save_file(save_file), &action);
+        }
+        if let Some(image) = subcommand.value_of("camera-image") {
+            let source = or_exit(StillImage::load(Path::new(image)),
+                                 &format!("Can't load {}", image));
+            game_boy.connect_camera_source(Box::new(source));
+        }
+        if subcommand.is_present("track-uninitialized-reads") {
+            game_boy.track_uninitialized_reads();
+        }
+        if let Some(trace_file) = subcommand.value_of("trace") {
+            let f = or_exit(File::create(trace_file),
+                            &format!("Can't create {}", trace_file));
+            game_boy.start_trace(Box::new(BufWriter::new(f)));
+        }
+        if let Some(json_file) = subcommand.value_of("dump-state") {
+            game_boy.dump_state_on_exit(PathBuf::from(json_file));
+        }
+        if let Some(trace_file) = subcommand.value_of("compare-trace") {
+            let f = or_exit(File::open(trace_file),
+                            &format!("Can't open {}", trace_file));
+            game_boy.compare_trace(Box::new(std::io::BufReader::new(f)));
+        }
+        if subcommand.is_present("debug") {
+            game_boy.break_into_debugger();
+        }
+        if subcommand.is_present("profile") {
+            game_boy.start_profiling();
+        }
+        let cdl_file = subcommand.value_of("coverage").map(|cdl_file| {
+            let f = or_exit(File::create(cdl_file),
+                            &format!("Can't create {}", cdl_file));
+            game_boy.start_coverage();
+            (cdl_file, f)
+        });
+        if let Some(script_file) = subcommand.value_of("script") {
+            let script = std::fs::read_to_string(script_file)
+                             .map_err(|e| e.to_string())
+                             .and_then(|text| Script::load(&text));
+            match script {
+                Ok(script) => game_boy.load_script(script),
+                Err(message) => {
+                    eprintln!("Invalid script {}: {}", script_file, message);
+                    std::process::exit(1);
+                }
+            }
+        }
+        for spec in subcommand.values_of("break").into_iter().flatten() {
+            match Breakpoint::from_spec(spec) {
+                Ok(breakpoint) => game_boy.add_breakpoint(breakpoint),
+                Err(message) => {
+                    eprintln!("Invalid breakpoint {:?}: {}", spec, message);
+                    std::process::exit(1);
+                }
+            }
+        }
+        for spec in subcommand.values_of("watch").into_iter().flatten() {
+            match Watchpoint::from_spec(spec) {
+                Some(watchpoint) => game_boy.add_watchpoint(watchpoint),
+                None => {
+                    eprintln!("Invalid watchpoint {:?}", spec);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(strictness) = subcommand.value_of("strict") {
+            game_boy.set_strictness(Strictness::from_name(strictness)
+                                                .unwrap());
+        }
+        match subcommand.value_of("serial").unwrap() {
+            "console" => game_boy.connect_serial_device(
+                             Box::new(serial::DebugConsole)),
+            "printer" => game_boy.connect_serial_device(
+                             Box::new(serial::Printer::new(PathBuf::from(".")))),
+            _ => {}
+        }
+        if let Some(address) = subcommand.value_of("link") {
+            let cable = or_exit(LinkCable::connect(address), &format!(
+                "Can't connect the link cable to {}", address));
+            game_boy.connect_serial_device(Box::new(cable));
+        }
+        match subcommand.value_of("infrared") {
+            Some("loopback") => game_boy.connect_infrared_device(
+                                    Box::new(Loopback::default())),
+            Some(address) => {
+                let socket = or_exit(InfraredSocket::connect(address),
+                                     &format!("Can't connect the IR port to {}",
+                                              address));
+                game_boy.connect_infrared_device(Box::new(socket));
+            }
+            None => {}
+        }
+        if subcommand.is_present("show-input") {
+            game_boy.show_input_overlay();
+        }
+        if subcommand.is_present("vram-viewer") {
+            game_boy.show_vram_viewer();
+        }
+        for frame in subcommand.values_of("dump-vram-after")
+                               .into_iter()
+                               .flatten() {
+            game_boy.dump_vram_after(frame.parse()
+                                          .expect("frame must be a number"));
+        }
+        if subcommand.is_present("log-input") {
+            game_boy.log_input();
+        }
+        let speed = subcommand.value_of("speed")
+                              .map_or(Ok(Some(1.)), frame_pacer::parse_speed)
+                              .unwrap();
+        match speed {
+            _ if headless || subcommand.is_present("no-throttle") => {
+                game_boy.disable_throttle();
+            }
+            Some(speed) => game_boy.set_speed(speed),
+            None => game_boy.disable_throttle(),
+        }
+        if let Some(frames) = subcommand.value_of("frames") {
+            game_boy.quit_after(frames.parse()
+                                      .expect("frames must be a number"));
+        }
+        let rewind_seconds = subcommand.value_of("rewind-seconds")
+                                       .unwrap()
+                                       .parse()
+                                       .expect("seconds must be a number");
+        if rewind_seconds != 0 {
+            game_boy.enable_rewind(rewind_seconds);
+        }
+        if subcommand.is_present("per-fetch-scroll") {
+            game_boy.set_scroll_latch(ScrollLatch::PerFetch);
+        }
+        if subcommand.is_present("fast-render") {
+            game_boy.set_scroll_latch(ScrollLatch::PerFrame);
+        }
+        let vgm_file = subcommand.value_of("record-vgm").map(|vgm_file| {
+            let f = or_exit(File::create(vgm_file),
+                            &format!("Can't create {}", vgm_file));
+            (vgm_file, f)
+        });
+        if vgm_file.is_some() {
+            game_boy.start_vgm_recording();
+        }
+        let screenshot = subcommand.value_of("screenshot").map(|png_file| {
+            let f = or_exit(File::create(png_file),
+                            &format!("Can't create {}", png_file));
+            (png_file, f)
+        });
+        if let Some(movie_file) = subcommand.value_of("play-movie") {
+            let f = or_exit(File::open(movie_file),
+                            &format!("Can't open {}", movie_file));
+            or_exit(game_boy.play_movie(&mut std::io::BufReader::new(f)),
+                    &format!("Can't play {}", movie_file));
+        }
+        if let Some(movie_file) = subcommand.value_of("record-movie") {
+            let f = or_exit(File::create(movie_file),
+                            &format!("Can't create {}", movie_file));
+            or_exit(game_boy.record_movie(Box::new(BufWriter::new(f))),
+                    &format!("Can't record {}", movie_file));
+        }
+        if let Some(hash_file) = subcommand.value_of("write-frame-hashes") {
+            let f = or_exit(File::create(hash_file),
+                            &format!("Can't create {}", hash_file));
+            game_boy.write_frame_hashes(Box::new(BufWriter::new(f)));
+        }
+        if let Some(hash_file) = subcommand.value_of("compare-frame-hashes") {
+            let f = or_exit(File::open(hash_file),
+                            &format!("Can't open {}", hash_file));
+            or_exit(game_boy.compare_frame_hashes(std::io::BufReader::new(f)),
+                    &format!("Can't compare with {}", hash_file));
+        }
+        match window {
+            // The window stays on the main thread, as not every platform
+            // can use windows on other threads.
+            Some((mut window, window_thread)) => std::thread::scope(|s| {
+                s.spawn(|| game_boy.run());
+                window_thread.run(&mut window);
+            }),
+            None => game_boy.run(),
+        }
+        if let Some((png_file, f)) = screenshot {
+            let screen = View{
+                width: WIDTH,
+                height: HEIGHT,
+                pixels: game_boy.screen().to_vec(),
+            };
+            or_exit(screen.write_png(BufWriter::new(f),
+                                     &palettes.lookup_table()),
+                    &format!("Can't write {}", png_file));
+        }
+        if let Some(report) = game_boy.profile_report(20) {
+            println!("{}", report);
+        }
+        if let Some((cdl_file, f)) = cdl_file {
+            let coverage = game_boy.coverage().unwrap();
+            or_exit(coverage.write_cdl(BufWriter::new(f)),
+                    &format!("Can't write {}", cdl_file));
+            println!("{}", coverage.report());
+        }
+        if let Some((vgm_file, f)) = vgm_file {
+            let recording = game_boy.stop_vgm_recording().unwrap();
+            or_exit(recording.write_vgm(BufWriter::new(f)),
+                    &format!("Can't write {}", vgm_file));
+        }
+        if subcommand.is_present("compare-frame-hashes") {
+            let (compared, mismatches) = game_boy.frame_hash_mismatches()
+                                                 .unwrap_or_default();
+            println!("{} of {} frames differ from the reference.",
+                     mismatches, compared);
+            if mismatches != 0 {
+                std::process::exit(1);
+            }
+        }
+        if game_boy.trace_divergence().is_some() {
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Return the value of a result that depends on the command line, e.g.
+/// on a file given there, or print the error and exit
+///
+/// `action` describes what failed, like "Can't open game.gb".
+fn or_exit<T, E: Display>(result: Result<T, E>, action: &str) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}: {}", action, error);
+        std::process::exit(1);
+    })
+}
+
+fn run_compatibility_check_from_subcommand(subcommand: &ArgMatches) {
+    let rom_dir = Path::new(subcommand.value_of("rom-dir").unwrap());
+    let frames = subcommand.value_of("frames").unwrap()
+                           .parse().expect("frames must be a number");
+    let output_name = subcommand.value_of("output").unwrap_or("stdout");
+    let output: Box<dyn Write> = match subcommand.value_of("output") {
+        Some(path) => {
+            let f = or_exit(File::create(path),
+                            &format!("Can't create {}", path));
+            Box::new(BufWriter::new(f))
+        }
+        None => Box::new(stdout()),
+    };
+    let results = or_exit(compat::run_compatibility_check(rom_dir, frames),
+                          &format!("Can't check ROMs in {}",
+                                   rom_dir.display()));
+    let written = match subcommand.value_of("format").unwrap() {
+        "json" => compat::write_json(output, &results),
+        _ => compat::write_markdown(output, &results),
+    };
+    or_exit(written, &format!("Can't write the report to {}", output_name));
+}
+
+fn run_test_rom_from_subcommand(subcommand: &ArgMatches) {
+    let rom = subcommand.value_of("rom").unwrap();
+    let f = or_exit(File::open(rom), &format!("Can't open {}", rom));
+    let frames = subcommand.value_of("frames").unwrap()
+                           .parse().expect("frames must be a number");
+    let result = or_exit(compat::run_test_rom(f, frames, true),
+                         &format!("Can't run {}", rom));
+    println!();
+    match result.outcome {
+        Some(TestOutcome::Passed) => {
+            println!("Passed after {} frames.", result.frames);
+        }
+        Some(TestOutcome::Failed) => {
+            println!("Failed after {} frames.", result.frames);
+            std::process::exit(1);
+        }
+        None => {
+            println!("No result after {} frames.", result.frames);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Skip the boot ROM for --skip-boot, otherwise run the boot ROM given
+/// on the command line or in the settings, or the built-in logo boot ROM
+fn choose_boot_rom<Window: IO>(builder: GameBoyBuilder<Window>,
+                               subcommand: &ArgMatches,
+                               settings: &GameBoySettings)
+        -> GameBoyBuilder<Window> {
+    let boot_rom = subcommand.value_of("boot-rom")
+                             .map(Path::new)
+                             .or(settings.boot_rom.as_deref());
+    if subcommand.is_present("skip-boot") {
+        builder.use_hle_boot()
+    } else if let Some(boot_rom) = boot_rom {
         let f = File::open(boot_rom).unwrap();
-        builder = builder.load_boot_rom(f).unwrap();
+        builder.load_boot_rom(f).unwrap()
     } else {
-        builder = builder.use_fast_boot_rom();
+        builder.use_logo_boot_rom()
     }
-    if subcommand.is_present("dump-header") {
-        print_cartridge_header(builder.get_cartridge_header().unwrap())
+}
+
+fn colorization_choices() -> Vec<&'static str> {
+    let mut choices = vec!["auto"];
+    choices.extend(colorization::MANUAL_PALETTES.iter().map(|(name, _)| name));
+    choices
+}
+
+/// Look up colorization palettes by name or by the cartridge title for "auto"
+fn color_palettes(name: &str, header: &CartridgeHeader) -> ColorPalettes {
+    if name == "auto" {
+        return colorization::automatic_palettes(header);
+    }
+    colorization::manual_palettes(name).unwrap_or_else(|| {
+        log::warn!("Unknown colorization {:?}, using automatic palettes.",
+                   name);
+        colorization::automatic_palettes(header)
+    })
+}
+
+/// The frontend for the emulation thread and, if a window could be
+/// opened, the window with the `WindowThread` that shows the frames
+type Frontends = (Box<dyn IO + Send>, Option<(Box<dyn IO>, WindowThread)>);
+
+/// Open a window or fall back to drawing into the terminal
+fn open_emulator_window(settings: &GameBoySettings,
+                        palettes: Option<&ColorPalettes>,
+                        sgb_border: bool, scale: Option<&str>,
+                        ghosting: bool)
+        -> Frontends {
+    let window = if sgb_border {
+        EmulatorWindow::with_sgb_border(window_scale::choose_scale(
+            scale, settings.scale, SGB_WIDTH, SGB_HEIGHT))
+    } else {
+        EmulatorWindow::new(window_scale::choose_scale(
+            scale, settings.scale, WIDTH, HEIGHT))
+    };
+    match window {
+        Ok(mut window) => {
+            match palettes {
+                Some(palettes) => window.set_color_palettes(palettes),
+                None => window.set_palette(settings.palette),
+            }
+            window.set_key_bindings(&settings.key_bindings);
+            window.set_ghosting(ghosting);
+            let (frontend, window_thread) = threaded::split(true);
+            (Box::new(frontend), Some((Box::new(window), window_thread)))
+        }
+        Err(e) => {
+            log::warn!("Could not open window: {}", e);
+            log::warn!("Falling back to terminal output.");
+            (open_terminal(settings), None)
+        }
+    }
+}
+
+/// Open a window drawn with wgpu, or fall back to the other window
+///
+/// Exit if the shader doesn't compile.
+#[cfg(feature = "wgpu")]
+fn open_gpu_window(settings: &GameBoySettings,
+                   palettes: Option<&ColorPalettes>,
+                   sgb_border: bool, scale: Option<&str>,
+                   ghosting: bool, shader: &str)
+        -> Frontends {
+    let shader = ScreenShader::load(shader).unwrap_or_else(|message| {
+        eprintln!("Invalid shader {}: {}", shader, message);
+        std::process::exit(1);
+    });
+    let window = if sgb_border {
+        GpuWindow::with_sgb_border(window_scale::choose_scale(
+            scale, settings.scale, SGB_WIDTH, SGB_HEIGHT), &shader)
     } else {
-        let mut game_boy
-            = builder.use_emulator_window(EmulatorWindow::default())
-                     .build();
-        game_boy.run();
+        GpuWindow::new(window_scale::choose_scale(
+            scale, settings.scale, WIDTH, HEIGHT), &shader)
+    };
+    match window {
+        Ok(mut window) => {
+            match palettes {
+                Some(palettes) => window.set_color_palettes(palettes),
+                None => window.set_palette(settings.palette),
+            }
+            window.set_key_bindings(&settings.key_bindings);
+            window.set_ghosting(ghosting);
+            let (frontend, window_thread) = threaded::split(true);
+            (Box::new(frontend), Some((Box::new(window), window_thread)))
+        }
+        Err(e) => {
+            log::warn!("Could not open wgpu window: {}", e);
+            log::warn!("Falling back to a window without shaders.");
+            open_emulator_window(settings, palettes, sgb_border, scale,
+                                 ghosting)
+        }
+    }
+}
+
+/// Draw into the terminal and read the keyboard if stdin is a terminal
+fn open_terminal(settings: &GameBoySettings) -> Box<dyn IO + Send> {
+    match TerminalWindow::with_keyboard(&settings.key_bindings) {
+        Ok(terminal) => Box::new(terminal),
+        Err(e) => {
+            log::warn!("Could not read the keyboard: {}", e);
+            Box::new(TerminalWindow::default())
+        }
+    }
+}
+
+fn list_save_states(rom_file: &Path) {
+    for slot in 0..save_state::NUM_SLOTS {
+        let path = save_state::slot_path(rom_file, slot);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        match StateInfo::read(&mut file) {
+            Ok(info) => println!("Slot {}: saved {} at frame {} \
+                                  ({} bytes thumbnail)",
+                                 slot, info.saved_at(), info.frame,
+                                 info.thumbnail.len()),
+            Err(e) => println!("Slot {}: cannot read {}: {}",
+                               slot, path.display(), e),
+        }
     }
 }
 
-fn print_cartridge_header(header: CartridgeHeader) {
+fn print_cartridge_header(header: CartridgeHeader, game: Option<&RomEntry>) {
     if let Some(title) = std::str::from_utf8(header.title()).ok() {
         println!("Title: {}", title);
     } else {
@@ -62,15 +1069,18 @@ fn print_cartridge_header(header: CartridgeHeader) {
     if let Some(code) = header.manufacturer_code() {
         println!("Manufacturer code: {}", code);
     }
+    // The cartridge has been loaded, so all its header fields are known.
     println!("Cartridge type: {:?}", header.cartridge_type());
     println!("Memory Controller: {:?}", header.cartridge_type()
-                                              .memory_controller());
+                                              .memory_controller()
+                                              .unwrap());
 
     println!("Color compat: {:?}", header.color_compat());
     println!("Supports SGB function: {}", header.supports_sgb_function());
 
     println!("ROM banks: {}", header.num_rom_banks());
-    println!("RAM banks: {}", header.num_ram_banks());
+    println!("RAM banks: {}", header.num_ram_banks().unwrap());
+    println!("RAM size: {} KiB", header.ram_size().unwrap() / 1024);
 
     println!("ROM version: {}", header.rom_version());
     print!("Licensee code: ");
@@ -89,4 +1099,63 @@ fn print_cartridge_header(header: CartridgeHeader) {
              } else {
                  "wrong"
              });
+    match game {
+        Some(game) => {
+            println!("ROM database: {} ({}), {:?}",
+                     game.title, game.region, game.mapper);
+            if !game.matches_header(&header) {
+                println!("Warning: declared memory controller differs from \
+                          ROM database.");
+            }
+        }
+        None => println!("ROM database: unknown ROM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::compat::NoWindow;
+
+    fn boot_builder(arguments: &[&str], settings: &GameBoySettings)
+            -> GameBoyBuilder<NoWindow> {
+        let subcommand = game_boy_subcommand()
+            .try_get_matches_from(arguments).unwrap();
+        choose_boot_rom(GameBoy::builder(), &subcommand, settings)
+    }
+
+    #[test]
+    fn skip_boot_overrides_boot_rom_of_settings() {
+        let settings = GameBoySettings{
+            boot_rom: Some(PathBuf::from("/nonexistent/dmg_boot.bin")),
+            ..GameBoySettings::default()
+        };
+        let builder = boot_builder(&["gameboy", "--skip-boot", "game.gb"],
+                                   &settings);
+        assert!(builder.hle_boot);
+        assert!(builder.boot_rom.is_none());
+
+        let builder = boot_builder(&["gameboy", "game.gb"],
+                                   &GameBoySettings::default());
+        assert!(!builder.hle_boot);
+        assert!(builder.logo_boot);
+
+        let path = std::env::temp_dir().join("emulato-rs-boot.bin");
+        std::fs::write(&path, [0x00; 0x100]).unwrap();
+        let settings = GameBoySettings{
+            boot_rom: Some(path.clone()),
+            ..GameBoySettings::default()
+        };
+        let builder = boot_builder(&["gameboy", "game.gb"], &settings);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!builder.hle_boot);
+        assert!(builder.boot_rom.is_some());
+    }
+
+    #[test]
+    fn skip_boot_conflicts_with_boot_rom() {
+        let result = game_boy_subcommand().try_get_matches_from(
+            ["gameboy", "--skip-boot", "--boot-rom", "boot.bin", "game.gb"]);
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,344 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::cartridge::CartridgeError;
+use super::cpu::CpuState;
+use super::determinism::fnv1a;
+use super::io::IO;
+use super::rtc::RtcStart;
+use super::serial::{SerialLog, TestOutcome};
+use super::{BuildError, GameBoy};
+
+/// Start of the real time clock of MBC3 cartridges, fixed so that runs
+/// are reproducible
+const FIXED_RTC_START: RtcStart = RtcStart::Timestamp(0);
+
+/// How far a ROM got when running it headlessly
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Ran for all frames without crashing
+    Ok,
+    /// Executed an illegal instruction, which hangs the CPU
+    #[serde(rename = "locked_up")]
+    LockedUp,
+    /// Hit a feature that is not implemented in the emulator yet
+    Unimplemented,
+    /// Crashed for any other reason
+    Crashed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CompatibilityResult {
+    pub rom: String,
+    pub title: Option<String>,
+    pub memory_controller: Option<String>,
+    pub status: Status,
+    /// Number of frames that were completed
+    pub frames: usize,
+    pub message: Option<String>,
+    /// FNV-1a hash of the last completed frame
+    pub screen_hash: Option<String>,
+    pub blank_screen: bool,
+}
+
+/// A frontend that discards all output
+pub struct NoWindow;
+
+impl IO for NoWindow {
+    fn refresh(&mut self, _pixels: &[u8]) {}
+
+    fn is_esc_pressed(&self) -> bool {
+        false
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        0
+    }
+}
+
+/// Registers B, C, D, E, H and L of mooneye's test ROMs when they pass
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+/// Registers B, C, D, E, H and L of mooneye's test ROMs when they fail
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+
+/// Result of a mooneye test ROM, given the registers at an LD B,B
+///
+/// Return None if the registers hold neither signature, e.g. because
+/// another test ROM executes LD B,B to test the instruction itself.
+pub fn mooneye_outcome(cpu: &CpuState) -> Option<TestOutcome> {
+    match [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l] {
+        MOONEYE_PASSED => Some(TestOutcome::Passed),
+        MOONEYE_FAILED => Some(TestOutcome::Failed),
+        _ => None,
+    }
+}
+
+/// Result of a test ROM that reports it over the link port or by
+/// executing LD B,B
+#[derive(Clone, Debug)]
+pub struct TestRomResult {
+    /// None if the ROM hasn't reported a result in time
+    pub outcome: Option<TestOutcome>,
+    /// All bytes sent over the link port
+    pub output: String,
+    /// Number of frames until the result was reported
+    pub frames: usize,
+}
+
+/// Run a test ROM like Blargg's cpu_instrs headlessly until it reports
+/// "Passed" or "Failed" over the link port, or until a mooneye test ROM
+/// executes LD B,B
+///
+/// The ROM is started without boot ROM and run for at most the given
+/// number of frames.  With `echo`, the output is printed to stdout while
+/// it is sent.
+pub fn run_test_rom(file: File, max_frames: usize, echo: bool)
+        -> io::Result<TestRomResult> {
+    let mut game_boy = GameBoy::builder().load_cartridge(file)?
+                                         .use_hle_boot()
+                                         .use_rtc_start(FIXED_RTC_START)
+                                         .use_emulator_window(NoWindow)
+                                         .build()
+                                         .map_err(io::Error::other)?;
+    let log = if echo {
+        SerialLog::with_echo()
+    } else {
+        SerialLog::default()
+    };
+    game_boy.connect_serial_device(Box::new(log.clone()));
+    let mut frames = 0;
+    let mut mooneye_result = None;
+    while frames < max_frames && log.outcome().is_none()
+          && mooneye_result.is_none() && !game_boy.is_locked_up() {
+        game_boy.run_frames(1);
+        mooneye_result = game_boy.take_magic_breakpoint()
+                                 .and_then(|cpu| mooneye_outcome(&cpu));
+        frames += 1;
+    }
+    Ok(TestRomResult{
+        outcome: mooneye_result.or_else(|| log.outcome()),
+        output: log.text(),
+        frames,
+    })
+}
+
+/// Boot every ROM in a directory headlessly and record how far it gets
+///
+/// Each ROM is started without boot ROM and run for the given number
+/// of frames as fast as possible.  Panics of the emulator are caught
+/// and recorded in the results.
+pub fn run_compatibility_check(rom_dir: &Path, frames: usize)
+        -> io::Result<Vec<CompatibilityResult>> {
+    let roms = find_roms(rom_dir)?;
+    roms.iter()
+        .map(|rom| check_rom(rom, frames))
+        .collect()
+}
+
+fn find_roms(rom_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(rom_dir)? {
+        let path = entry?.path();
+        let is_rom = path.extension()
+                         .and_then(|e| e.to_str())
+                         .map(|e| e.eq_ignore_ascii_case("gb")
+                                  || e.eq_ignore_ascii_case("gbc"))
+                         .unwrap_or(false);
+        if is_rom && path.is_file() {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+fn check_rom(path: &Path, frames: usize) -> io::Result<CompatibilityResult> {
+    let file = File::open(path)?;
+    let mut result = CompatibilityResult{
+        rom: path.file_name().unwrap().to_string_lossy().into_owned(),
+        title: None,
+        memory_controller: None,
+        status: Status::Ok,
+        frames: 0,
+        message: None,
+        screen_hash: None,
+        blank_screen: false,
+    };
+    let builder = match GameBoy::builder().load_cartridge(file) {
+        Ok(builder) => builder,
+        Err(CartridgeError::Io(error)) => return Err(error),
+        Err(error) => {
+            result.status = Status::Unimplemented;
+            result.message = Some(error.to_string());
+            return Ok(result);
+        }
+    };
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        let header = builder.get_cartridge_header().unwrap();
+        let title = String::from_utf8_lossy(header.title());
+        result.title = Some(title.trim_end_matches('\0').to_string());
+        result.memory_controller = header.cartridge_type()
+                                         .memory_controller()
+                                         .ok()
+                                         .map(|model| format!("{:?}", model));
+        let game_boy = builder.use_hle_boot()
+                              .use_rtc_start(FIXED_RTC_START)
+                              .use_emulator_window(NoWindow)
+                              .build();
+        let mut game_boy = match game_boy {
+            Ok(game_boy) => game_boy,
+            Err(error) => {
+                result.status = match error {
+                    BuildError::UnsupportedMapper(_) => Status::Unimplemented,
+                    _ => Status::Crashed,
+                };
+                result.message = Some(error.to_string());
+                return;
+            }
+        };
+        for _ in 0..frames {
+            game_boy.run_frames(1);
+            result.frames += 1;
+            let screen = game_boy.screen();
+            result.screen_hash = Some(format!("{:016x}", fnv1a(screen)));
+            result.blank_screen = screen.iter().all(|&p| p == screen[0]);
+            if game_boy.is_locked_up() {
+                result.status = Status::LockedUp;
+                break;
+            }
+        }
+    }));
+    if let Err(payload) = run {
+        let message = panic_message(payload.as_ref());
+        // The message of `unimplemented!`
+        result.status = if message.starts_with("not implemented") {
+            Status::Unimplemented
+        } else {
+            Status::Crashed
+        };
+        result.message = Some(message);
+    }
+    Ok(result)
+}
+
+/// Message of a panic caught with `panic::catch_unwind`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+pub fn write_json<W: Write>(mut writer: W, results: &[CompatibilityResult])
+        -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut writer, results)?;
+    writeln!(writer)
+}
+
+pub fn write_markdown<W: Write>(mut writer: W,
+                                results: &[CompatibilityResult])
+        -> io::Result<()> {
+    let escape = |s: &str| s.replace('|', "\\|").replace('\n', " ");
+    let num_ok = results.iter().filter(|r| r.status == Status::Ok).count();
+    writeln!(writer, "{} of {} ROMs ran without crashing.\n",
+             num_ok, results.len())?;
+    writeln!(writer, "| ROM | Title | MBC | Status | Frames | Screen | Message |")?;
+    writeln!(writer, "|-----|-------|-----|--------|-------:|--------|---------|")?;
+    for r in results {
+        let status = match r.status {
+            Status::Ok => "ok",
+            Status::LockedUp => "locked up",
+            Status::Unimplemented => "unimplemented",
+            Status::Crashed => "crashed",
+        };
+        let screen = match &r.screen_hash {
+            Some(_) if r.blank_screen => "blank".to_string(),
+            Some(hash) => format!("`{}`", hash),
+            None => String::new(),
+        };
+        writeln!(writer, "| {} | {} | {} | {} | {} | {} | {} |",
+                 escape(&r.rom),
+                 escape(r.title.as_deref().unwrap_or("")),
+                 r.memory_controller.as_deref().unwrap_or(""),
+                 status,
+                 r.frames,
+                 screen,
+                 escape(r.message.as_deref().unwrap_or("")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::cartridge::LOGO;
+
+    /// Run a ROM-only cartridge that executes `program` at 0x0100
+    fn check_program(name: &str, cartridge_type: u8, program: &[u8])
+            -> CompatibilityResult {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom[0x104..0x134].copy_from_slice(&LOGO);
+        rom[0x134..0x134 + name.len()].copy_from_slice(name.as_bytes());
+        rom[0x147] = cartridge_type;
+        let path = std::env::temp_dir()
+            .join(format!("emulato-rs-compat-{}.gb", name));
+        fs::write(&path, rom).unwrap();
+        let result = check_rom(&path, 3).unwrap();
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn running_rom_is_ok() {
+        let result = check_program("LOOP", 0x00, &[0x18, 0xFE]);  // JR -2
+        assert_eq!(result.status, Status::Ok);
+        assert_eq!(result.title.as_deref(), Some("LOOP"));
+        assert_eq!(result.memory_controller.as_deref(), Some("NoController"));
+        assert_eq!(result.frames, 3);
+        assert!(result.message.is_none());
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up() {
+        let result = check_program("ILLEGAL", 0x00, &[0xD3]);
+        assert_eq!(result.status, Status::LockedUp);
+        assert_eq!(result.frames, 1);
+    }
+
+    #[test]
+    fn missing_features_are_unimplemented() {
+        // LD (0x2000), A switches banks without a memory controller.
+        let result = check_program("NOMBC", 0x00, &[0xEA, 0x00, 0x20]);
+        assert_eq!(result.status, Status::Unimplemented);
+        assert!(result.message.unwrap().contains("without memory controller"));
+
+        let result = check_program("HUC3", 0xFE, &[0x18, 0xFE]);
+        assert_eq!(result.status, Status::Unimplemented);
+        assert_eq!(result.title.as_deref(), Some("HUC3"));
+        assert_eq!(result.memory_controller.as_deref(), Some("HuC3"));
+        assert_eq!(result.message.as_deref(), Some("unsupported mapper HuC3"));
+    }
+
+    #[test]
+    fn other_panics_are_crashes() {
+        // LDH (LY), A
+        let result = check_program("LY", 0x00, &[0xE0, 0x44]);
+        assert_eq!(result.status, Status::Crashed);
+        assert_eq!(result.message.as_deref(), Some("Trying to write to LY"));
+        assert_eq!(result.frames, 0);
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Write};
+
+use super::cpu::instruction_length;
+use super::profiler::Location;
+
+/// Flag of ROM bytes that held the opcode of an executed instruction
+pub const OPCODE: u8 = 0x01;
+/// Flag of ROM bytes that held an operand of an executed instruction
+pub const OPERAND: u8 = 0x02;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Records which bytes of the cartridge ROM have been executed
+///
+/// Instructions in the boot ROM or in RAM aren't recorded.
+pub struct Coverage {
+    /// Flags of each ROM byte
+    flags: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Self {
+        Self{
+            flags: vec![0; rom_size],
+        }
+    }
+
+    /// Offset in the ROM of a location in the cartridge ROM
+    fn rom_offset(&self, location: Location) -> Option<usize> {
+        let bank = location.bank?;
+        if location.address >= 0x8000 {
+            return None;
+        }
+        let offset = bank * BANK_SIZE + location.address as usize % BANK_SIZE;
+        (offset < self.flags.len()).then_some(offset)
+    }
+
+    /// Record the execution of the instruction at a location of the
+    /// given ROM
+    pub fn add(&mut self, location: Location, rom: &[u8]) {
+        if let Some(offset) = self.rom_offset(location) {
+            self.flags[offset] |= OPCODE;
+            let length = instruction_length(rom[offset]) as usize;
+            let end = (offset + length).min(self.flags.len());
+            for flags in &mut self.flags[offset + 1..end] {
+                *flags |= OPERAND;
+            }
+        }
+    }
+
+    pub fn is_executed(&self, location: Location) -> bool {
+        self.rom_offset(location)
+            .is_some_and(|offset| self.flags[offset] != 0)
+    }
+
+    /// Write a code/data log with the flags of each ROM byte
+    ///
+    /// The file holds one byte for each byte of the ROM, in which the
+    /// bits `OPCODE` and `OPERAND` are set like in the CDL files of
+    /// FCEUX or BizHawk.  Bytes without flags haven't been executed and
+    /// are either data or code that the game didn't reach.
+    pub fn write_cdl(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.flags)?;
+        writer.flush()
+    }
+
+    /// Number of executed bytes in each ROM bank, and in total
+    pub fn report(&self) -> String {
+        let executed = |flags: &[u8]| flags.iter()
+                                           .filter(|&&flags| flags != 0)
+                                           .count();
+        let mut report = format!("{:>4}  {:>6}  {:>6}", "bank", "bytes",
+                                 "share");
+        for (bank, flags) in self.flags.chunks(BANK_SIZE).enumerate() {
+            let count = executed(flags);
+            if count != 0 {
+                report.push_str(&format!(
+                    "\n{:>4X}  {:>6}  {:>5.1}%", bank, count,
+                    100.0 * count as f64 / flags.len() as f64));
+            }
+        }
+        let count = executed(&self.flags);
+        report.push_str(&format!("\nExecuted {} of {} ROM bytes ({:.1}%).",
+                                 count, self.flags.len(),
+                                 100.0 * count as f64
+                                 / self.flags.len() as f64));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_are_recorded_at_their_rom_offset() {
+        let at = |bank, address| Location{bank, address};
+        let mut rom = vec![0; 4 * BANK_SIZE];
+        rom[0x8000] = 0xC3;  // JP
+        rom[0xFFFF] = 0xCB;
+        // LD A,3E over and over
+        rom[0x6000..0x8000].fill(0x3E);
+        let mut coverage = Coverage::new(rom.len());
+        coverage.add(at(Some(0), 0x0100), &rom);
+        coverage.add(at(Some(2), 0x4000), &rom);
+        coverage.add(at(None, 0x0000), &rom);
+        coverage.add(at(Some(0), 0xC000), &rom);
+        coverage.add(at(Some(3), 0x7FFF), &rom);
+        for address in (0x6000..0x8000).step_by(2) {
+            coverage.add(at(Some(1), address), &rom);
+        }
+        assert!(coverage.is_executed(at(Some(0), 0x0100)));
+        assert!(coverage.is_executed(at(Some(2), 0x4002)));
+        assert!(!coverage.is_executed(at(Some(1), 0x4000)));
+
+        let mut cdl = Vec::new();
+        coverage.write_cdl(&mut cdl).unwrap();
+        assert_eq!(cdl.len(), 4 * BANK_SIZE);
+        assert_eq!(cdl[0x0000..0x0002], [0, 0]);
+        assert_eq!(cdl[0x00FF..0x0102], [0, OPCODE, 0]);
+        assert_eq!(cdl[0x8000..0x8004], [OPCODE, OPERAND, OPERAND, 0]);
+        assert_eq!(cdl[0xFFFF], OPCODE);
+        assert_eq!(coverage.report().lines().collect::<Vec<_>>(), [
+            "bank   bytes   share",
+            "   0       1    0.0%",
+            "   1    8192   50.0%",
+            "   2       3    0.0%",
+            "   3       1    0.0%",
+            "Executed 8197 of 65536 ROM bytes (12.5%).",
+        ]);
+    }
+}
@@ -3,18 +3,191 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::fmt;
+use std::io::{BufRead, Write};
 
+use serde::{Deserialize, Serialize};
+
+use super::accuracy::Approximation;
+use super::boot_rom::PostBootCpuRegisters;
+use super::flags::{self, Carries};
 use super::memory::{InterruptAddress, MemoryBus};
+use super::trace_diff::{Comparison, Divergence, TraceDiff};
+
+/// Cycles needed to dispatch an interrupt
+///
+/// Two wait states, pushing PC takes two more M-cycles and the jump to
+/// the interrupt handler takes one.
+/// https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+const INTERRUPT_DISPATCH_CYCLES: usize = 5 * 4;
+
+/// Calls kept in the call stack before the outermost ones are dropped
+///
+/// Games that leave routines by resetting SP instead of returning would
+/// otherwise grow the stack forever.
+const MAX_CALL_DEPTH: usize = 256;
+
+/// The memory bus as seen by the CPU
+///
+/// On the Game Boy, this is `MemoryBus`.  Tests can drive the CPU with
+/// a simpler bus.
+pub trait Bus {
+    fn read8(&self, address: u16) -> u8;
+
+    fn write8(&mut self, address: u16, value: u8);
+
+    /// Let the rest of the system run for the given number of cycles
+    fn step(&mut self, cycles: usize);
+
+    /// Enter STOP mode
+    fn stop(&mut self) {}
+
+    /// Return true if the CPU should leave STOP mode
+    fn wake_from_stop(&mut self) -> bool {
+        true
+    }
+
+    /// Interrupts that are both requested in IF and enabled in IE
+    fn get_requested_interrupts(&self) -> u8 {
+        self.read8(0xFF0F) & self.read8(0xFFFF) & 0x1F
+    }
+
+    /// Acknowledge the requested interrupt of highest priority
+    ///
+    /// Return the address of its handler.
+    fn handle_interrupts(&mut self) -> Option<InterruptAddress>;
+
+    /// Check a CPU access for approximations that it could run into
+    fn check_cpu_access(&mut self, _address: u16) {}
+
+    /// Record an approximation taken by the CPU
+    fn hit_approximation(&mut self, _approximation: Approximation) {}
+
+    /// The symbol describing an address, if symbols have been loaded
+    fn label(&self, _address: u16) -> Option<String> {
+        None
+    }
+}
+
+impl Bus for MemoryBus {
+    fn read8(&self, address: u16) -> u8 {
+        MemoryBus::read8(self, address)
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        MemoryBus::write8(self, address, value);
+    }
+
+    fn step(&mut self, cycles: usize) {
+        MemoryBus::step(self, cycles);
+    }
+
+    fn stop(&mut self) {
+        MemoryBus::stop(self);
+    }
+
+    fn wake_from_stop(&mut self) -> bool {
+        MemoryBus::wake_from_stop(self)
+    }
+
+    fn get_requested_interrupts(&self) -> u8 {
+        MemoryBus::get_requested_interrupts(self)
+    }
+
+    fn handle_interrupts(&mut self) -> Option<InterruptAddress> {
+        MemoryBus::handle_interrupts(self)
+    }
+
+    fn check_cpu_access(&mut self, address: u16) {
+        MemoryBus::check_cpu_access(self, address);
+    }
+
+    fn hit_approximation(&mut self, approximation: Approximation) {
+        MemoryBus::hit_approximation(self, approximation);
+    }
+
+    fn label(&self, address: u16) -> Option<String> {
+        MemoryBus::label(self, address)
+    }
+}
+
+/// The registers of the CPU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    /// EI has been executed, IME gets set after the next instruction.
+    pub ime_scheduled: bool,
+}
+
+/// How a call in the call stack has been entered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// A call that hasn't returned yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the CALL or RST instruction, or of the instruction
+    /// before which an interrupt has been dispatched
+    pub caller: u16,
+    /// Address of the called routine
+    pub target: u16,
+    /// SP after pushing the return address
+    pub sp: u16,
+}
 
 /// A Sharp LR35902 CPU.
 ///
 /// This one is similar to the Intel 8080 and Zilog Z80.
+#[derive(Serialize, Deserialize)]
 pub struct CPU {
     registers: Registers,
     sp: u16, //< stack pointer
     pc: u16, //< program counter
     ime: bool,
+    /// EI has been executed, IME gets set after the next instruction.
+    ime_scheduled: bool,
     halt: bool,
+    stop: bool,
+    /// An illegal instruction has hung the CPU until the next reset.
+    locked_up: bool,
+    /// Cycles of the current step for which the memory bus has already
+    /// been stepped
+    bus_cycles: usize,
+    /// Log of the state before each instruction, see `start_trace`
+    #[serde(skip)]
+    trace: Option<Box<dyn Write + Send>>,
+    /// Reference trace compared with each instruction, see
+    /// `compare_trace`
+    #[serde(skip)]
+    trace_diff: Option<TraceDiff>,
+    #[serde(skip)]
+    trace_divergence: Option<Divergence>,
+    /// Whether LD B,B has been executed, see `take_magic_breakpoint`
+    #[serde(skip)]
+    magic_breakpoint: bool,
+    /// Calls that haven't returned yet, the innermost last
+    ///
+    /// It isn't kept in save states, so it starts out empty after
+    /// loading one.
+    #[serde(skip)]
+    call_stack: Vec<CallFrame>,
+    /// Whether the last step executed an instruction
+    #[serde(skip)]
+    executed_instruction: bool,
 }
 
 impl CPU {
@@ -24,32 +197,302 @@ impl CPU {
             sp: 0xFFFE,
             pc: 0,
             ime: false,
+            ime_scheduled: false,
             halt: false,
+            stop: false,
+            locked_up: false,
+            bus_cycles: 0,
+            trace: None,
+            trace_diff: None,
+            trace_divergence: None,
+            magic_breakpoint: false,
+            call_stack: Vec::new(),
+            executed_instruction: false,
+        }
+    }
+
+    /// Create a CPU in the state that a boot ROM leaves it in
+    ///
+    /// See `boot_rom::post_boot_cpu_registers` for the register values.
+    pub fn with_hle_boot(post_boot: &PostBootCpuRegisters) -> Self {
+        let mut registers = Registers::new();
+        registers.write16(U16Register::AF, post_boot.af);
+        registers.write16(U16Register::BC, post_boot.bc);
+        registers.write16(U16Register::DE, post_boot.de);
+        registers.write16(U16Register::HL, post_boot.hl);
+        Self{
+            registers,
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ime: false,
+            ime_scheduled: false,
+            halt: false,
+            stop: false,
+            locked_up: false,
+            bus_cycles: 0,
+            trace: None,
+            trace_diff: None,
+            trace_divergence: None,
+            magic_breakpoint: false,
+            call_stack: Vec::new(),
+            executed_instruction: false,
+        }
+    }
+
+    /// Take over the state of a CPU loaded from a save state
+    ///
+    /// A running trace and trace comparison continue.
+    pub fn restore_state(&mut self, saved: Self) {
+        *self = Self{
+            trace: self.trace.take(),
+            trace_diff: self.trace_diff.take(),
+            ..saved
+        };
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Whether LD B,B has been executed since the last call
+    ///
+    /// Test ROMs like mooneye's use this instruction, which does
+    /// nothing, as a breakpoint in emulators.
+    pub fn take_magic_breakpoint(&mut self) -> bool {
+        std::mem::take(&mut self.magic_breakpoint)
+    }
+
+    /// Calls that haven't returned yet, the innermost last
+    ///
+    /// Calls are removed when SP moves above their return address,
+    /// so routines that drop their return address from the stack and
+    /// jump back don't stay in it.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    /// List the calls in the call stack, the innermost first, with the
+    /// labels that the memory bus knows for their addresses
+    pub fn describe_call_stack(&self, memory: &impl Bus) -> String {
+        if self.call_stack.is_empty() {
+            return "No calls".to_string();
+        }
+        let describe = |address: u16| match memory.label(address) {
+            Some(label) => format!("{:0>4X} ({})", address, label),
+            None => format!("{:0>4X}", address),
+        };
+        self.call_stack
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let how = match frame.kind {
+                    CallKind::Call => "called from",
+                    CallKind::Rst => "called by RST from",
+                    CallKind::Interrupt => "interrupted",
+                };
+                format!("#{} {} {} {}", depth, describe(frame.target), how,
+                        describe(frame.caller))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Remember a call after its return address has been pushed
+    fn enter_call(&mut self, kind: CallKind, caller: u16) {
+        // Calls at or below SP belong to a stack that has been abandoned.
+        let sp = self.sp;
+        self.call_stack.retain(|frame| frame.sp > sp);
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(CallFrame{kind, caller, target: self.pc, sp});
+    }
+
+    /// Forget the calls whose return address has been popped
+    fn leave_calls(&mut self) {
+        let sp = self.sp;
+        self.call_stack.retain(|frame| frame.sp >= sp);
+    }
+
+    /// Whether the last step executed the instruction at the PC it
+    /// started with, rather than dispatching an interrupt or waiting
+    pub fn executed_instruction(&self) -> bool {
+        self.executed_instruction
+    }
+
+    /// Whether the CPU waits for an interrupt after HALT
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    pub fn state(&self) -> CpuState {
+        let r = &self.registers;
+        CpuState{
+            a: r.a,
+            f: r.f,
+            b: r.b,
+            c: r.c,
+            d: r.d,
+            e: r.e,
+            h: r.h,
+            l: r.l,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+            ime_scheduled: self.ime_scheduled,
         }
     }
 
-    pub fn step(&mut self, memory: &mut MemoryBus) -> usize {
+    /// Overwrite the registers
+    ///
+    /// The lower nibble of F always reads as 0, so it is cleared.
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.registers = Registers{
+            a: state.a,
+            f: state.f & 0xF0,
+            b: state.b,
+            c: state.c,
+            d: state.d,
+            e: state.e,
+            h: state.h,
+            l: state.l,
+        };
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.ime = state.ime;
+        self.ime_scheduled = state.ime_scheduled;
+    }
+
+    /// Log the state before each executed instruction
+    ///
+    /// Each line holds the registers and the 4 bytes at PC in the
+    /// format of Game Boy Doctor, so that traces can be compared with
+    /// those of other emulators:
+    ///
+    /// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    ///
+    /// Interrupt dispatches and steps while halted aren't logged.
+    /// If the memory bus knows a label for PC, it is appended as a
+    /// comment like ` ; Main::loop+3`, which has to be stripped before
+    /// comparing with Game Boy Doctor.
+    pub fn start_trace(&mut self, trace: Box<dyn Write + Send>) {
+        self.trace = Some(trace);
+    }
+
+    /// Compare the registers before each instruction with a reference
+    /// trace, see `trace_diff::TraceDiff`
+    ///
+    /// Comparing stops at the first difference, which can be taken with
+    /// `take_trace_divergence`.
+    pub fn compare_trace(&mut self, reference: Box<dyn BufRead + Send>) {
+        self.trace_diff = Some(TraceDiff::new(reference));
+    }
+
+    /// The difference from the reference trace if it has been found
+    /// since the last call
+    pub fn take_trace_divergence(&mut self) -> Option<Divergence> {
+        self.trace_divergence.take()
+    }
+
+    /// The registers and the next bytes at PC in the format of Game Boy
+    /// Doctor
+    fn trace_line(&self, memory: &impl Bus) -> String {
+        let r = &self.registers;
+        let pc = self.pc;
+        let pc_mem = |offset| memory.read8(pc.wrapping_add(offset));
+        format!("A:{:0>2X} F:{:0>2X} B:{:0>2X} C:{:0>2X} D:{:0>2X} \
+                 E:{:0>2X} H:{:0>2X} L:{:0>2X} SP:{:0>4X} PC:{:0>4X} \
+                 PCMEM:{:0>2X},{:0>2X},{:0>2X},{:0>2X}",
+                r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, self.sp, pc,
+                pc_mem(0), pc_mem(1), pc_mem(2), pc_mem(3))
+    }
+
+    fn trace_instruction(&mut self, memory: &impl Bus) {
+        if self.trace.is_none() && self.trace_diff.is_none() {
+            return;
+        }
+        let line = self.trace_line(memory);
+        if let Some(trace) = &mut self.trace {
+            let result = match memory.label(self.pc) {
+                Some(label) => writeln!(trace, "{} ; {}", line, label),
+                None => writeln!(trace, "{}", line),
+            };
+            if let Err(e) = result {
+                log::warn!("Stopping trace: {}", e);
+                self.trace = None;
+            }
+        }
+        let state = self.state();
+        if let Some(trace_diff) = &mut self.trace_diff {
+            match trace_diff.check(&state, &line) {
+                Ok(Comparison::Matched) => return,
+                Ok(Comparison::Diverged(divergence)) => {
+                    self.trace_divergence = Some(divergence);
+                }
+                Ok(Comparison::Finished) => {
+                    log::info!("Reference trace ended after {} lines \
+                                without a difference.",
+                               trace_diff.line_number());
+                }
+                Err(e) => log::warn!("Stopping trace comparison: {}", e),
+            }
+            self.trace_diff = None;
+        }
+    }
+
+    /// Whether an illegal instruction has hung the CPU
+    pub fn is_locked_up(&self) -> bool {
+        self.locked_up
+    }
+
+    /// Execute the next instruction or dispatch a pending interrupt
+    ///
+    /// The memory bus is stepped along, one M-cycle before each memory
+    /// access, so that timer, DMA, etc. see the accesses on the right
+    /// cycle within the instruction.  Return the number of cycles taken.
+    pub fn step(&mut self, memory: &mut impl Bus) -> usize {
+        self.bus_cycles = 0;
+        self.executed_instruction = false;
+        let cycles = self.step_without_remaining_cycles(memory);
+        debug_assert!(self.bus_cycles <= cycles,
+                      "{} cycles of memory accesses in a {} cycle step",
+                      self.bus_cycles, cycles);
+        // Internal cycles at the end of the instruction
+        memory.step(cycles - self.bus_cycles);
+        cycles
+    }
+
+    /// Execute the next instruction or dispatch a pending interrupt,
+    /// but only step the memory bus up to the last memory access
+    fn step_without_remaining_cycles(&mut self, memory: &mut impl Bus)
+            -> usize {
+        if self.locked_up {
+            return 4
+        }
+        if self.stop {
+            if !memory.wake_from_stop() {
+                return 4
+            }
+            self.stop = false;
+        }
+        if self.dispatch_interrupt(memory) {
+            return INTERRUPT_DISPATCH_CYCLES
+        }
         if self.halt {
             return 4
         }
+        self.trace_instruction(memory);
+        self.executed_instruction = true;
         let instruction = {
-            let mut instruction_byte = memory.read8(self.pc);
+            let mut instruction_byte = self.read8(memory, self.pc);
             let prefixed = instruction_byte == 0xCB;
             if prefixed {
-                instruction_byte = memory.read8(self.pc + 1);
-            }
-            match Instruction::from_byte(instruction_byte, prefixed) {
-                Some(instruction) => instruction,
-                None => {
-                    let instr = if prefixed {
-                        format!("CB{:0>2X}", instruction_byte)
-                    } else {
-                        format!("{:0>2X}", instruction_byte)
-                    };
-                    panic!("Could not decode Instruction {} at {:0>4X}.",
-                           instr, self.pc)
-                }
+                instruction_byte = self.read8(memory, self.pc + 1);
+            } else if instruction_byte == 0x40 {
+                self.magic_breakpoint = true;
             }
+            Instruction::from_byte(instruction_byte, prefixed)
         };
         // let mut instruction_bytes: u64 = 0;
         // for i in self.pc..self.pc+instruction.len() {
@@ -57,14 +500,21 @@ impl CPU {
         //     instruction_bytes += memory.read8(i) as u64;
         // }
         // eprintln!("{}", self.registers);
-        // self.print_stack(memory);
+        // self.print_stack(memory, 8);
         // eprintln!("{:0>4X}: {1:0>2$X} {3:?}", self.pc, instruction_bytes,
         //           2*instruction.len() as usize,
         //           instruction);
-        self.execute(memory, instruction)
+        // EI only takes effect after the instruction following it.
+        let enables_ime = self.ime_scheduled;
+        let cycles = self.execute(memory, instruction);
+        if enables_ime && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        cycles
     }
 
-    fn execute(&mut self, memory: &mut MemoryBus,
+    fn execute(&mut self, memory: &mut impl Bus,
                instruction: Instruction) -> usize {
         use Instruction::*;
         match instruction {
@@ -76,74 +526,42 @@ impl CPU {
                 self.pc += 1;
                 let cycles = operand.cycles() + 4;
                 let operand = self.load_arithmetic_operand(memory, operand);
-                let a = self.registers.a;
-                let (new_a, carry) = a.overflowing_add(operand);
-                let half_carry = (a & 0xF) + (operand & 0xF) > 0xF;
+                let (new_a, carries)
+                    = flags::add8(self.registers.a, operand, false);
                 self.registers.a = new_a;
-                let mut f = 0;
+                let mut f = carry_flags(carries);
                 if new_a == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
                 self.registers.f = f;
                 cycles
             }
             ADC(operand) => {
                 self.pc += 1;
+                let cycles = operand.cycles() + 4;
                 let operand = self.load_arithmetic_operand(memory, operand);
-                let a = self.registers.a;
                 let old_carry = self.registers.f & Flag::Carry as u8 != 0;
-                let (new_a, carry) = {
-                    let (new_a, carry) = a.overflowing_add(operand);
-                    if !old_carry {
-                        (new_a, carry)
-                    } else {
-                        let (new_a, carry2) = new_a.overflowing_add(1);
-                        (new_a, carry || carry2)
-                    }
-                };
-                let half_carry
-                    = (a & 0xF) + (operand & 0xF) + (old_carry as u8) > 0xF;
+                let (new_a, carries)
+                    = flags::add8(self.registers.a, operand, old_carry);
                 self.registers.a = new_a;
-                let mut f = 0;
+                let mut f = carry_flags(carries);
                 if new_a == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
                 self.registers.f = f;
-                // TODO: The number of cycles might be 4 for adding from
-                //       registers.  Not sure if the number of cycles that
-                //       I found in an opcode table is correct.
-                8
+                cycles
             }
             SUB(operand) => {
                 self.pc += 1;
                 let cycles = operand.cycles() + 4;
                 let operand = self.load_arithmetic_operand(memory, operand);
-                let a = self.registers.a;
-                let (new_a, carry) = a.overflowing_sub(operand);
-                let (_, half_carry) = (a & 0xF).overflowing_sub(operand & 0xF);
+                let (new_a, carries)
+                    = flags::sub8(self.registers.a, operand, false);
                 self.registers.a = new_a;
-                let mut f = Flag::Subtract as u8;
+                let mut f = carry_flags(carries) | Flag::Subtract as u8;
                 if new_a == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
                 self.registers.f = f;
                 cycles
             }
@@ -151,38 +569,14 @@ impl CPU {
                 self.pc += 1;
                 let cycles = operand.cycles() + 4;
                 let operand = self.load_arithmetic_operand(memory, operand);
-                let a = self.registers.a;
                 let old_carry = self.registers.f & Flag::Carry as u8 != 0;
-                let (new_a, carry) = {
-                    let (new_a, carry) = a.overflowing_sub(operand);
-                    if !old_carry {
-                        (new_a, carry)
-                    } else {
-                        let (new_a, carry2) = new_a.overflowing_sub(1);
-                        (new_a, carry || carry2)
-                    }
-                };
-                let half_carry = {
-                    let (a2, half_carry)
-                        = (a & 0xF).overflowing_sub(operand & 0xF);
-                    if !old_carry {
-                        half_carry
-                    } else {
-                        let (_, carry2) = a2.overflowing_sub(1);
-                        half_carry || carry2
-                    }
-                };
+                let (new_a, carries)
+                    = flags::sub8(self.registers.a, operand, old_carry);
                 self.registers.a = new_a;
-                let mut f = Flag::Subtract as u8;
+                let mut f = carry_flags(carries) | Flag::Subtract as u8;
                 if new_a == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
                 self.registers.f = f;
                 cycles
             }
@@ -245,59 +639,28 @@ impl CPU {
                 self.pc += 1;
                 let hl = self.registers.read16(U16Register::HL);
                 let operand = self.load_arithmetic_word_source(source);
-                let (new_hl, carry) = hl.overflowing_add(operand);
+                let (new_hl, carries) = flags::add16(hl, operand);
                 self.registers.write16(U16Register::HL, new_hl);
-                let half_carry = (hl & 0xFFF) + (operand & 0xFFF) > 0xFFF;
-                let mut f = self.registers.f & Flag::Zero as u8;
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
-                self.registers.f = f;
+                self.registers.f = self.registers.f & Flag::Zero as u8
+                                   | carry_flags(carries);
                 8
             }
             ADD16SP => {
                 self.pc += 1;
-                let s = memory.read8(self.pc);
+                let s = self.read8(memory, self.pc);
                 self.pc += 1;
-                let (new_sp, carry) = {
-                    let (_, carry) = (self.sp as u8).overflowing_add(s);
-                    let (new_sp, _)
-                        = (self.sp as i16).overflowing_add(s as i8 as i16);
-                    (new_sp as u16, carry)
-                };
-                let mut f = 0;
-                if (self.sp as u8 & 0xF) + (s as u8 & 0xF) > 0xF {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
+                let (new_sp, carries) = flags::add_signed_offset(self.sp, s);
                 self.sp = new_sp;
-                self.registers.f = f;
+                self.registers.f = carry_flags(carries);
                 16
             }
             ADD16SPinHL => {
                 self.pc += 1;
-                let s = memory.read8(self.pc);
+                let s = self.read8(memory, self.pc);
                 self.pc += 1;
-                let (new_sp, carry) = {
-                    let (_, carry) = (self.sp as u8).overflowing_add(s);
-                    let (new_sp, _)
-                        = (self.sp as i16).overflowing_add(s as i8 as i16);
-                    (new_sp as u16, carry)
-                };
+                let (new_sp, carries) = flags::add_signed_offset(self.sp, s);
                 self.registers.write16(U16Register::HL, new_sp);
-                let mut f = 0;
-                if (self.sp as u8 & 0xF) + (s as u8 & 0xF) > 0xF {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
-                self.registers.f = f;
+                self.registers.f = carry_flags(carries);
                 12
             }
             AND(operand) => {
@@ -340,19 +703,12 @@ impl CPU {
                 self.pc += 1;
                 let cycles = operand.cycles() + 4;
                 let operand = self.load_arithmetic_operand(memory, operand);
-                let a = self.registers.a;
-                let (cp, carry) = a.overflowing_sub(operand);
-                let (_, half_carry) = (a & 0xF).overflowing_sub(operand & 0xF);
-                let mut f = Flag::Subtract as u8;
+                let (cp, carries)
+                    = flags::sub8(self.registers.a, operand, false);
+                let mut f = carry_flags(carries) | Flag::Subtract as u8;
                 if cp == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if half_carry {
-                    f |= Flag::HalfCarry as u8;
-                }
-                if carry {
-                    f |= Flag::Carry as u8;
-                }
                 self.registers.f = f;
                 cycles
             }
@@ -365,13 +721,13 @@ impl CPU {
                             self.registers.read8(reg)
                         }
                         LoadByteSource::D8 => {
-                            let d8 = memory.read8(self.pc);
+                            let d8 = self.read8(memory, self.pc);
                             self.pc += 1;
                             d8
                         }
                         LoadByteSource::HLI => {
                             let hl = self.registers.read16(U16Register::HL);
-                            memory.read8(hl)
+                            self.read8(memory, hl)
                         }
                     };
                     match to {
@@ -380,7 +736,7 @@ impl CPU {
                         }
                         LoadByteTarget::HLI => {
                             let hl = self.registers.read16(U16Register::HL);
-                            memory.write8(hl, from);
+                            self.write8(memory, hl, from);
                         }
                     }
                     cycles
@@ -390,7 +746,7 @@ impl CPU {
                     let cycles = from.cycles() + 8;
                     let from = match from {
                         LoadWordSource::D16 => {
-                            let d16 = memory.read16(self.pc);
+                            let d16 = self.read16(memory, self.pc);
                             self.pc += 2;
                             d16
                         }
@@ -428,12 +784,12 @@ impl CPU {
                             hl
                         }
                         Address => {
-                            let address = memory.read16(self.pc);
+                            let address = self.read16(memory, self.pc);
                             self.pc += 2;
                             address
                         }
                     };
-                    memory.write8(address, from);
+                    self.write8(memory, address, from);
                     to.cycles() + 8
                 }
                 LoadType::IndirectByteToA(from) => {
@@ -452,19 +808,19 @@ impl CPU {
                             hl
                         }
                         Address => {
-                            let address = memory.read16(self.pc);
+                            let address = self.read16(memory, self.pc);
                             self.pc += 2;
                             address
                         }
                     };
-                    self.registers.a = memory.read8(address);
+                    self.registers.a = self.read8(memory, address);
                     from.cycles() + 8
                 }
                 LoadType::IndirectWordFromSP => {
                     self.pc += 1;
-                    let address = memory.read16(self.pc);
+                    let address = self.read16(memory, self.pc);
                     self.pc += 2;
-                    memory.write16(address, self.sp);
+                    self.write16(memory, address, self.sp);
                     20
                 }
             }
@@ -472,7 +828,7 @@ impl CPU {
                 self.pc += 1;
                 let address = match load_type {
                     LdhOperand::I8 => {
-                        let d8 = memory.read8(self.pc);
+                        let d8 = self.read8(memory, self.pc);
                         self.pc += 1;
                         d8
                     }
@@ -482,10 +838,10 @@ impl CPU {
                 } as u16 + 0xFF00;
                 match load_direction {
                     LdhDirection::FromA => {
-                        memory.write8(address, self.registers.a);
+                        self.write8(memory, address, self.registers.a);
                     }
                     LdhDirection::ToA => {
-                        self.registers.a = memory.read8(address);
+                        self.registers.a = self.read8(memory, address);
                     }
                 }
                 load_type.cycles() + 4
@@ -692,7 +1048,12 @@ impl CPU {
                 let mask: u8 = Flag::Subtract as u8 | Flag::HalfCarry as u8;
                 f = (f & !mask) | Flag::HalfCarry as u8;
                 self.registers.f = f;
-                r.cycles() + 8
+                // BIT only reads (HL), so it needs one cycle less
+                // than other prefixed instructions.
+                match r {
+                    NonDirectArithmeticOperand::HLI => 12,
+                    _ => 8,
+                }
             }
             RES(bit, r) => {
                 self.pc += 2;
@@ -710,34 +1071,18 @@ impl CPU {
             }
             DAA => {
                 self.pc += 1;
-                let mut adjustment = 0;
                 let f = self.registers.f;
-                if f & Flag::HalfCarry as u8 != 0 {
-                    adjustment |= 0x06;
-                }
-                if f & Flag::Carry as u8 != 0 {
-                    adjustment |= 0x60;
-                }
-                if f & Flag::Subtract as u8 != 0 {
-                    let (new_a, _)
-                        = self.registers.a.overflowing_sub(adjustment);
-                    self.registers.a = new_a;
-                } else {
-                    if self.registers.a & 0x0F > 0x09 {
-                        adjustment |= 0x06;
-                    }
-                    if self.registers.a > 0x99 {
-                        adjustment |= 0x60;
-                    }
-                    let (new_a, _)
-                        = self.registers.a.overflowing_add(adjustment);
-                    self.registers.a = new_a;
-                }
+                let (new_a, carry) = flags::decimal_adjust(
+                    self.registers.a,
+                    f & Flag::Subtract as u8 != 0,
+                    f & Flag::HalfCarry as u8 != 0,
+                    f & Flag::Carry as u8 != 0);
+                self.registers.a = new_a;
                 let mut f = f & Flag::Subtract as u8;
-                if self.registers.a == 0 {
+                if new_a == 0 {
                     f |= Flag::Zero as u8;
                 }
-                if adjustment & 0x60 != 0 {
+                if carry {
                     f |= Flag::Carry as u8;
                 }
                 self.registers.f = f;
@@ -765,7 +1110,7 @@ impl CPU {
                 4
             }
             JP(condition) => {
-                let nn = memory.read16(self.pc + 1);
+                let nn = self.read16(memory, self.pc + 1);
                 self.pc += 3;
                 if self.test_jump_condition(condition) {
                     self.pc = nn;
@@ -779,7 +1124,7 @@ impl CPU {
                 4
             }
             JR(condition) => {
-                let e = memory.read8(self.pc + 1);
+                let e = self.read8(memory, self.pc + 1);
                 let e = e as i8;
                 self.pc += 2;
                 if self.test_jump_condition(condition) {
@@ -790,28 +1135,46 @@ impl CPU {
                 }
             }
             CALL(condition) => {
-                let nn = memory.read16(self.pc + 1);
+                let caller = self.pc;
+                let nn = self.read16(memory, self.pc + 1);
                 self.pc += 3;
                 if self.test_jump_condition(condition) {
+                    self.idle(memory);
                     self.push(memory, self.pc);
                     self.pc = nn;
+                    self.enter_call(CallKind::Call, caller);
                     6 * 4
                 } else {
                     3 * 4
                 }
             }
             RST(n) => {
+                let caller = self.pc;
                 self.pc += 1;
+                self.idle(memory);
                 self.push(memory, self.pc);
                 self.pc = n as u16;
+                self.enter_call(CallKind::Rst, caller);
                 16
             }
             RET(condition) => {
+                let unconditional
+                    = matches!(condition, JumpCondition::Unconditional);
+                if !unconditional {
+                    // Checking the condition takes a cycle.
+                    self.idle(memory);
+                }
                 if self.test_jump_condition(condition) {
                     let address = self.pop(memory);
                     self.pc = address;
-                    // TODO: This might only be 4 * 4 for unconditional RET
-                    5 * 4
+                    self.leave_calls();
+                    // Conditional RET needs an extra cycle to check
+                    // the condition.
+                    if unconditional {
+                        4 * 4
+                    } else {
+                        5 * 4
+                    }
                 } else {
                     self.pc += 1;
                     2 * 4
@@ -820,11 +1183,13 @@ impl CPU {
             RETI => {
                 let address = self.pop(memory);
                 self.pc = address;
+                self.leave_calls();
                 self.ime = true;
                 16
             }
             PUSH(register) => {
                 self.pc += 1;
+                self.idle(memory);
                 self.push(memory, self.registers.read16(register));
                 16
             }
@@ -836,12 +1201,14 @@ impl CPU {
             }
             DI => {
                 self.pc += 1;
+                // DI right after EI cancels the pending enable.
                 self.ime = false;
+                self.ime_scheduled = false;
                 4
             }
             EI => {
                 self.pc += 1;
-                self.ime = true;
+                self.ime_scheduled = true;
                 4
             }
             HALT => {
@@ -849,19 +1216,39 @@ impl CPU {
                 self.halt = true;
                 4
             }
+            STOP => {
+                // STOP is followed by an ignored byte.
+                self.pc += 2;
+                self.stop = true;
+                memory.hit_approximation(Approximation::Stop);
+                memory.stop();
+                4
+            }
+            Illegal(opcode) => {
+                // The CPU hangs and doesn't even react to interrupts.
+                let label = memory.label(self.pc)
+                                  .map(|label| format!(" ({})", label))
+                                  .unwrap_or_default();
+                log::warn!("Illegal instruction {:0>2X} at {:0>4X}{} locked \
+                           up the CPU.", opcode, self.pc, label);
+                log::warn!("Call stack:\n{}",
+                           self.describe_call_stack(memory));
+                self.locked_up = true;
+                4
+            }
         }
     }
 
-    fn load_arithmetic_operand(&mut self, memory: &MemoryBus,
+    fn load_arithmetic_operand(&mut self, memory: &mut impl Bus,
                                operand: ArithmeticOperand) -> u8 {
         match operand {
             ArithmeticOperand::Register(r) => self.registers.read8(r),
             ArithmeticOperand::HLI => {
                 let hl = self.registers.read16(U16Register::HL);
-                memory.read8(hl)
+                self.read8(memory, hl)
             }
             ArithmeticOperand::D8 => {
-                let d8 = memory.read8(self.pc);
+                let d8 = self.read8(memory, self.pc);
                 self.pc += 1;
                 d8
             }
@@ -869,21 +1256,21 @@ impl CPU {
     }
 
     fn load_non_direct_arithmetic_operand(
-            &self,
-            memory: &MemoryBus,
+            &mut self,
+            memory: &mut impl Bus,
             operand: NonDirectArithmeticOperand) -> u8 {
         match operand {
             NonDirectArithmeticOperand::Register(r) => self.registers.read8(r),
             NonDirectArithmeticOperand::HLI => {
                 let hl = self.registers.read16(U16Register::HL);
-                memory.read8(hl)
+                self.read8(memory, hl)
             }
         }
     }
 
     fn write_non_direct_arithmetic_operand(
             &mut self,
-            memory: &mut MemoryBus,
+            memory: &mut impl Bus,
             operand: NonDirectArithmeticOperand,
             value: u8) {
         match operand {
@@ -892,7 +1279,7 @@ impl CPU {
             }
             NonDirectArithmeticOperand::HLI => {
                 let hl = self.registers.read16(U16Register::HL);
-                memory.write8(hl, value);
+                self.write8(memory, hl, value);
             }
         }
     }
@@ -934,27 +1321,66 @@ impl CPU {
         }
     }
 
-    fn push(&mut self, memory: &mut MemoryBus, value: u16) {
-        self.sp -= 2;
-        memory.write16(self.sp, value);
+    fn push(&mut self, memory: &mut impl Bus, value: u16) {
+        // The high byte gets written first.
+        self.sp -= 1;
+        self.write8(memory, self.sp, (value >> 8) as u8);
+        self.sp -= 1;
+        self.write8(memory, self.sp, value as u8);
     }
 
-    fn pop(&mut self, memory: &MemoryBus) -> u16 {
-        let value = memory.read16(self.sp);
+    fn pop(&mut self, memory: &mut impl Bus) -> u16 {
+        let value = self.read16(memory, self.sp);
         self.sp += 2;
         value
     }
 
-    fn print_stack(&self, memory: &MemoryBus) {
+    /// Step the memory bus by an M-cycle without a memory access
+    fn idle(&mut self, memory: &mut impl Bus) {
+        memory.step(4);
+        self.bus_cycles += 4;
+    }
+
+    fn read8(&mut self, memory: &mut impl Bus, address: u16) -> u8 {
+        self.idle(memory);
+        memory.check_cpu_access(address);
+        memory.read8(address)
+    }
+
+    fn read16(&mut self, memory: &mut impl Bus, address: u16) -> u16 {
+        let low = self.read8(memory, address);
+        let high = self.read8(memory, address.wrapping_add(1));
+        u16::from_le_bytes([low, high])
+    }
+
+    fn write8(&mut self, memory: &mut impl Bus, address: u16, value: u8) {
+        self.idle(memory);
+        memory.check_cpu_access(address);
+        memory.write8(address, value);
+    }
+
+    fn write16(&mut self, memory: &mut impl Bus, address: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write8(memory, address, low);
+        self.write8(memory, address.wrapping_add(1), high);
+    }
+
+    /// Print the given number of words at the top of the stack to stderr
+    pub fn print_stack(&self, memory: &impl Bus, count: usize) {
         eprintln!("Stack: SP = {:0>4X}", self.sp);
         let sp = self.sp;
-        if sp == 0xFFFE {
+        if sp >= 0xFFFE {
             eprintln!("<empty>");
         } else {
-            let mut p = 0xFFFE;
-            while sp < p {
-                p -= 2;
-                eprintln!("{:0>4X}: {:0>4X}", p, memory.read16(p));
+            let mut p = sp;
+            for _ in 0..count {
+                if p >= 0xFFFE {
+                    break;
+                }
+                let value = u16::from_le_bytes([memory.read8(p),
+                                                memory.read8(p + 1)]);
+                eprintln!("{:0>4X}: {:0>4X}", p, value);
+                p += 2;
             }
         }
     }
@@ -963,14 +1389,23 @@ impl CPU {
         self.ime
     }
 
-    fn call_interrupt(&mut self, memory: &mut MemoryBus,
+    fn call_interrupt(&mut self, memory: &mut impl Bus,
                       interrupt: InterruptAddress) {
         self.ime = false;
+        // Two wait states before PC gets pushed
+        self.idle(memory);
+        self.idle(memory);
+        let caller = self.pc;
         self.push(memory, self.pc);
         self.pc = interrupt as u16;
+        self.enter_call(CallKind::Interrupt, caller);
     }
 
-    pub fn handle_interrupts(&mut self, memory: &mut MemoryBus) -> bool {
+    /// Leave HALT if an interrupt is pending and call its handler if
+    /// interrupts are enabled
+    ///
+    /// Return whether an interrupt handler has been called.
+    fn dispatch_interrupt(&mut self, memory: &mut impl Bus) -> bool {
         if self.halt && memory.get_requested_interrupts() != 0 {
             self.halt = false;
         }
@@ -1015,7 +1450,7 @@ pub enum U16Register {
 /// BC    B    C    BC
 /// DE    D    E    DE
 /// HL    H    L    HL
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Registers {
     a: u8,
     f: u8,
@@ -1134,6 +1569,18 @@ enum Flag {
     Carry = 1 << 4,
 }
 
+/// Flag bits for the carries of an addition or subtraction
+fn carry_flags(carries: Carries) -> u8 {
+    let mut f = 0;
+    if carries.half_carry {
+        f |= Flag::HalfCarry as u8;
+    }
+    if carries.carry {
+        f |= Flag::Carry as u8;
+    }
+    f
+}
+
 trait FetchCycles {
     fn cycles(&self) -> usize;
 }
@@ -1145,8 +1592,9 @@ enum ArithmeticOperand {
     D8,
 }
 
-impl From<u8> for ArithmeticOperand {
-    fn from(v: u8) -> Self {
+impl ArithmeticOperand {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use ArithmeticOperand::*;
         use U8Register::*;
         match v {
@@ -1158,7 +1606,7 @@ impl From<u8> for ArithmeticOperand {
             0b101 => Register(L),
             0b110 => HLI,
             0b111 => Register(A),
-            _ => panic!("{:X} is not a valid ArithmeticOperand.", v),
+            _ => panic!("Not a valid ArithmeticOperand."),
         }
     }
 }
@@ -1182,15 +1630,16 @@ enum ArithmeticWordSource {
     SP,
 }
 
-impl From<u8> for ArithmeticWordSource {
-    fn from(v: u8) -> Self {
+impl ArithmeticWordSource {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use ArithmeticWordSource::*;
         match v {
             0b000 => BC,
             0b001 => DE,
             0b010 => HL,
             0b011 => SP,
-            _ => panic!("{:X} is not a valid ArithmeticWordSource.", v),
+            _ => panic!("Not a valid ArithmeticWordSource."),
         }
     }
 }
@@ -1201,8 +1650,9 @@ enum NonDirectArithmeticOperand {
     HLI,
 }
 
-impl From<u8> for NonDirectArithmeticOperand {
-    fn from(v: u8) -> Self {
+impl NonDirectArithmeticOperand {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use NonDirectArithmeticOperand::*;
         use U8Register::*;
         match v {
@@ -1214,7 +1664,7 @@ impl From<u8> for NonDirectArithmeticOperand {
             0b101 => Register(L),
             0b110 => HLI,
             0b111 => Register(A),
-            _ => panic!("{:X} is not a valid NonDirectArithmeticOperand.", v),
+            _ => panic!("Not a valid NonDirectArithmeticOperand."),
         }
     }
 }
@@ -1229,14 +1679,15 @@ impl FetchCycles for NonDirectArithmeticOperand {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadByteTarget {
     Register(U8Register),
     HLI,
 }
 
-impl From<u8> for LoadByteTarget {
-    fn from(v: u8) -> Self {
+impl LoadByteTarget {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use LoadByteTarget::*;
         use U8Register::*;
         match v {
@@ -1248,7 +1699,7 @@ impl From<u8> for LoadByteTarget {
             0b101 => Register(L),
             0b110 => HLI,
             0b111 => Register(A),
-            _ => panic!("{:X} is not a valid LoadByteTarget.", v),
+            _ => panic!("Not a valid LoadByteTarget."),
         }
     }
 }
@@ -1263,14 +1714,15 @@ impl FetchCycles for LoadByteTarget {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadWordTarget {
     Register(U16Register),
     SP,
 }
 
-impl From<u8> for LoadWordTarget {
-    fn from(v: u8) -> Self {
+impl LoadWordTarget {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use LoadWordTarget::*;
         use U16Register::*;
         match v {
@@ -1278,20 +1730,21 @@ impl From<u8> for LoadWordTarget {
             0b01 => Register(DE),
             0b10 => Register(HL),
             0b11 => SP,
-            _ => panic!("{:X} is not a valid LoadWordTarget.", v),
+            _ => panic!("Not a valid LoadWordTarget."),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadByteSource {
     Register(U8Register),
     D8,
     HLI,
 }
 
-impl From<u8> for LoadByteSource {
-    fn from(v: u8) -> Self {
+impl LoadByteSource {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use LoadByteSource::*;
         use U8Register::*;
         match v {
@@ -1303,7 +1756,7 @@ impl From<u8> for LoadByteSource {
             0b101 => Register(L),
             0b110 => HLI,
             0b111 => Register(A),
-            _ => panic!("{:X} is not a valid LoadByteSource.", v),
+            _ => panic!("Not a valid LoadByteSource."),
         }
     }
 }
@@ -1319,7 +1772,7 @@ impl FetchCycles for LoadByteSource {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadWordSource {
     D16,
     SP,
@@ -1337,7 +1790,7 @@ impl FetchCycles for LoadWordSource {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadIndirectByteOperand {
     Register(U16Register),
     HLI_incrementing,
@@ -1345,8 +1798,9 @@ enum LoadIndirectByteOperand {
     Address,
 }
 
-impl From<u8> for LoadIndirectByteOperand {
-    fn from(v: u8) -> Self {
+impl LoadIndirectByteOperand {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use LoadIndirectByteOperand::*;
         use U16Register::*;
         match v {
@@ -1354,7 +1808,7 @@ impl From<u8> for LoadIndirectByteOperand {
             0b01 => Register(DE),
             0b10 => HLI_incrementing,
             0b11 => HLI_decrementing,
-            _ => panic!("{:X} is not a valid LoadIndirectByteOperand.", v),
+            _ => panic!("Not a valid LoadIndirectByteOperand."),
         }
     }
 }
@@ -1371,7 +1825,7 @@ impl FetchCycles for LoadIndirectByteOperand {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LoadType {
     Byte(LoadByteTarget, LoadByteSource),
     Word(LoadWordTarget, LoadWordSource),
@@ -1380,7 +1834,7 @@ enum LoadType {
     IndirectWordFromSP,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LdhOperand {
     I8,
     Ci,
@@ -1396,13 +1850,13 @@ impl FetchCycles for LdhOperand {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum LdhDirection {
     ToA,
     FromA,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum IncDecType {
     IncDec8(NonDirectArithmeticOperand),
     IncDec16(IncDec16Operand),
@@ -1414,21 +1868,22 @@ enum IncDec16Operand {
     SP,
 }
 
-impl From<u8> for IncDec16Operand {
-    fn from(v: u8) -> Self {
+impl IncDec16Operand {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use IncDec16Operand::*;
         match v {
             0 => Register(U16Register::BC),
             1 => Register(U16Register::DE),
             2 => Register(U16Register::HL),
             3 => SP,
-            _ => panic!("{:X} is not a valid IncDec16Operand.", v),
+            _ => panic!("Not a valid IncDec16Operand."),
         }
     }
 }
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum Bit {
     B0 = 1,
     B1 = 2,
@@ -1440,8 +1895,9 @@ enum Bit {
     B7 = 128,
 }
 
-impl From<u8> for Bit {
-    fn from(v: u8) -> Self {
+impl Bit {
+    /// Decode from the bits of an opcode that select the operand
+    const fn decode(v: u8) -> Self {
         use Bit::*;
         match v {
             0 => B0,
@@ -1452,12 +1908,12 @@ impl From<u8> for Bit {
             5 => B5,
             6 => B6,
             7 => B7,
-            _ => panic!("{:X} is not a valid Bit.", v),
+            _ => panic!("Not a valid Bit."),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 enum JumpCondition {
     Unconditional,
     NZ,
@@ -1466,7 +1922,13 @@ enum JumpCondition {
     C,
 }
 
-#[derive(Debug)]
+/// Instructions indexed by their opcode, decoded at compile time
+static INSTRUCTIONS: [Instruction; 256] = Instruction::decode_all(false);
+/// Instructions indexed by the byte following the CB prefix
+static PREFIXED_INSTRUCTIONS: [Instruction; 256]
+    = Instruction::decode_all(true);
+
+#[derive(Copy, Clone, Debug)]
 enum Instruction {
     NOP,
     ADD(ArithmeticOperand),
@@ -1515,341 +1977,392 @@ enum Instruction {
     DI,
     EI,
     HALT,
-    // TODO: Add STOP
+    STOP,
+    /// One of the opcodes D3, DB, DD, E3, E4, EB, EC, ED, F4, FC and FD,
+    /// which have no mnemonic
+    Illegal(u8),
+}
+
+/// Length in bytes of the instruction starting with the given opcode
+pub fn instruction_length(opcode: u8) -> u16 {
+    match opcode {
+        0xCB => 2,
+        _ => Instruction::from_byte(opcode, false).len(),
+    }
 }
 
 impl Instruction {
-    fn from_byte(instruction_byte: u8, prefixed: bool) -> Option<Self> {
+    fn from_byte(instruction_byte: u8, prefixed: bool) -> Self {
         if prefixed {
-            Self::from_byte_prefixed(instruction_byte)
+            PREFIXED_INSTRUCTIONS[instruction_byte as usize]
         } else {
-            Self::from_byte_nonprefixed(instruction_byte)
+            INSTRUCTIONS[instruction_byte as usize]
         }
     }
 
-    fn from_byte_prefixed(instruction_byte: u8) -> Option<Self> {
+    /// Decode all 256 opcodes, with or without the CB prefix
+    ///
+    /// This is done at compile time, where a missing opcode fails the
+    /// build.
+    const fn decode_all(prefixed: bool) -> [Self; 256] {
+        let mut instructions = [Instruction::NOP; 256];
+        let mut i = 0;
+        while i < 256 {
+            instructions[i] = if prefixed {
+                Self::decode_prefixed(i as u8)
+            } else {
+                Self::decode_nonprefixed(i as u8)
+            };
+            i += 1;
+        }
+        instructions
+    }
+
+    const fn decode_prefixed(instruction_byte: u8) -> Self {
         match instruction_byte {
             0x00..=0x07 => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::RLC(r.into()))
+                Instruction::RLC(NonDirectArithmeticOperand::decode(r))
             }
             0x08..=0x0F => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::RRC(r.into()))
+                Instruction::RRC(NonDirectArithmeticOperand::decode(r))
             }
             0x10..=0x17 => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::RL(r.into()))
+                Instruction::RL(NonDirectArithmeticOperand::decode(r))
             }
             0x18..=0x1F => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::RR(r.into()))
+                Instruction::RR(NonDirectArithmeticOperand::decode(r))
             }
             0x20..=0x27 => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::SLA(r.into()))
+                Instruction::SLA(NonDirectArithmeticOperand::decode(r))
             }
             0x28..=0x2F => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::SRA(r.into()))
+                Instruction::SRA(NonDirectArithmeticOperand::decode(r))
             }
             0x30..=0x37 => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::SWAP(r.into()))
+                Instruction::SWAP(NonDirectArithmeticOperand::decode(r))
             }
             0x38..=0x3F => {
                 let r = instruction_byte & 0b111;
-                Some(Instruction::SRL(r.into()))
+                Instruction::SRL(NonDirectArithmeticOperand::decode(r))
             }
             0x40..=0x7F => {
                 let bit = (instruction_byte & 0b0011_1000) >> 3;
                 let r = instruction_byte & 0b111;
-                Some(Instruction::BIT(bit.into(), r.into()))
+                Instruction::BIT(Bit::decode(bit),
+                                 NonDirectArithmeticOperand::decode(r))
             }
             0x80..=0xBF => {
                 let bit = (instruction_byte & 0b0011_1000) >> 3;
                 let r = instruction_byte & 0b111;
-                Some(Instruction::RES(bit.into(), r.into()))
+                Instruction::RES(Bit::decode(bit),
+                                 NonDirectArithmeticOperand::decode(r))
             }
             0xC0..=0xFF => {
                 let bit = (instruction_byte & 0b0011_1000) >> 3;
                 let r = instruction_byte & 0b111;
-                Some(Instruction::SET(bit.into(), r.into()))
+                Instruction::SET(Bit::decode(bit),
+                                 NonDirectArithmeticOperand::decode(r))
             }
         }
     }
 
-    fn from_byte_nonprefixed(instruction_byte: u8) -> Option<Self> {
+    const fn decode_nonprefixed(instruction_byte: u8) -> Self {
         match instruction_byte {
-            0x00 => Some(Instruction::NOP),
+            0x00 => Instruction::NOP,
             0b0000_0001..=0b0011_1111
                     if instruction_byte & 0b111 == 0b110 => {
                 let to = (instruction_byte & 0b11_1000) >> 3;
-                Some(Instruction::LD(LoadType::Byte(
-                            to.into(), LoadByteSource::D8)))
+                Instruction::LD(LoadType::Byte(
+                            LoadByteTarget::decode(to), LoadByteSource::D8))
             }
             0b0000_0001..=0b0011_1111
                     if instruction_byte & 0b1111 == 0b0001 => {
                 let to = (instruction_byte & 0b11_0000) >> 4;
-                Some(Instruction::LD(LoadType::Word(
-                            to.into(), LoadWordSource::D16)))
+                Instruction::LD(LoadType::Word(
+                            LoadWordTarget::decode(to), LoadWordSource::D16))
             }
             0b0000_0011..=0b0011_0011
                     if instruction_byte & 0b1111 == 0b0011 => {
                 let r = (instruction_byte & 0b11_0000) >> 4;
-                Some(Instruction::INC(IncDecType::IncDec16(r.into())))
+                Instruction::INC(IncDecType::IncDec16(
+                            IncDec16Operand::decode(r)))
             }
             0b0000_1011..=0b0011_1011
                     if instruction_byte & 0b1111 == 0b1011 => {
                 let r = (instruction_byte & 0b11_0000) >> 4;
-                Some(Instruction::DEC(IncDecType::IncDec16(r.into())))
+                Instruction::DEC(IncDecType::IncDec16(
+                            IncDec16Operand::decode(r)))
             }
             0b0000_0100..=0b0011_1100
                     if instruction_byte & 0b111 == 0b100 => {
                 let r = (instruction_byte & 0b11_1000) >> 3;
-                Some(Instruction::INC(IncDecType::IncDec8(r.into())))
+                Instruction::INC(IncDecType::IncDec8(
+                            NonDirectArithmeticOperand::decode(r)))
             }
             0b0000_0101..=0b0011_1101
                     if instruction_byte & 0b111 == 0b101 => {
                 let r = (instruction_byte & 0b11_1000) >> 3;
-                Some(Instruction::DEC(IncDecType::IncDec8(r.into())))
+                Instruction::DEC(IncDecType::IncDec8(
+                            NonDirectArithmeticOperand::decode(r)))
             }
             0b0000_1001..=0b0011_1001
                     if instruction_byte & 0b1111 == 0b1001 => {
                 let r = (instruction_byte & 0b11_0000) >> 4;
-                Some(Instruction::ADD16(r.into()))
+                Instruction::ADD16(ArithmeticWordSource::decode(r))
             }
             0x76 => {
-                Some(Instruction::HALT)
+                Instruction::HALT
+            }
+            0x10 => {
+                Instruction::STOP
             }
             0b0100_0000..=0b0111_1111
                 if instruction_byte != 0x76 => {
                 let from = instruction_byte & 0b111;
                 let to = (instruction_byte & 0b11_1000) >> 3;
-                Some(Instruction::LD(LoadType::Byte(to.into(), from.into())))
+                Instruction::LD(LoadType::Byte(LoadByteTarget::decode(to),
+                                                LoadByteSource::decode(from)))
             }
             0x02 | 0x12 | 0x22 | 0x32 => {
                 let to = (instruction_byte & 0b11_0000) >> 4;
-                Some(Instruction::LD(LoadType::IndirectByteFromA(to.into())))
+                Instruction::LD(LoadType::IndirectByteFromA(
+                            LoadIndirectByteOperand::decode(to)))
             }
             0x08 => {
-                Some(Instruction::LD(LoadType::IndirectWordFromSP))
+                Instruction::LD(LoadType::IndirectWordFromSP)
             }
             0x0A | 0x1A | 0x2A | 0x3A => {
                 let to = (instruction_byte & 0b110_000) >> 4;
-                Some(Instruction::LD(LoadType::IndirectByteToA(to.into())))
+                Instruction::LD(LoadType::IndirectByteToA(
+                            LoadIndirectByteOperand::decode(to)))
             }
             0x07 => {
-                Some(Instruction::RLCA)
+                Instruction::RLCA
             }
             0x17 => {
-                Some(Instruction::RLA)
+                Instruction::RLA
             }
             0x0F => {
-                Some(Instruction::RRCA)
+                Instruction::RRCA
             }
             0x1F => {
-                Some(Instruction::RRA)
+                Instruction::RRA
             }
             0x18 => {
-                Some(Instruction::JR(JumpCondition::Unconditional))
+                Instruction::JR(JumpCondition::Unconditional)
             }
             0x20 => {
-                Some(Instruction::JR(JumpCondition::NZ))
+                Instruction::JR(JumpCondition::NZ)
             }
             0x28 => {
-                Some(Instruction::JR(JumpCondition::Z))
+                Instruction::JR(JumpCondition::Z)
             }
             0x30 => {
-                Some(Instruction::JR(JumpCondition::NC))
+                Instruction::JR(JumpCondition::NC)
             }
             0x38 => {
-                Some(Instruction::JR(JumpCondition::C))
+                Instruction::JR(JumpCondition::C)
             }
             0x27 => {
-                Some(Instruction::DAA)
+                Instruction::DAA
             }
             0x2F => {
-                Some(Instruction::CPL)
+                Instruction::CPL
             }
             0x37 => {
-                Some(Instruction::SCF)
+                Instruction::SCF
             }
             0x3F => {
-                Some(Instruction::CCF)
+                Instruction::CCF
             }
             0x80..=0x87 => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::ADD(operand.into()))
+                Instruction::ADD(ArithmeticOperand::decode(operand))
             }
             0x88..=0x8F => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::ADC(operand.into()))
+                Instruction::ADC(ArithmeticOperand::decode(operand))
             }
             0x90..=0x97 => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::SUB(operand.into()))
+                Instruction::SUB(ArithmeticOperand::decode(operand))
             }
             0x98..=0x9F => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::SBC(operand.into()))
+                Instruction::SBC(ArithmeticOperand::decode(operand))
             }
             0xA0..=0xA7 => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::AND(operand.into()))
+                Instruction::AND(ArithmeticOperand::decode(operand))
             }
             0xA8..=0xAF => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::XOR(operand.into()))
+                Instruction::XOR(ArithmeticOperand::decode(operand))
             }
             0xB0..=0xB7 => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::OR(operand.into()))
+                Instruction::OR(ArithmeticOperand::decode(operand))
             }
             0xB8..=0xBF => {
                 let operand = instruction_byte & 0b111;
-                Some(Instruction::CP(operand.into()))
+                Instruction::CP(ArithmeticOperand::decode(operand))
             }
             0xC1 => {
-                Some(Instruction::POP(U16Register::BC))
+                Instruction::POP(U16Register::BC)
             }
             0xD1 => {
-                Some(Instruction::POP(U16Register::DE))
+                Instruction::POP(U16Register::DE)
             }
             0xE1 => {
-                Some(Instruction::POP(U16Register::HL))
+                Instruction::POP(U16Register::HL)
             }
             0xF1 => {
-                Some(Instruction::POP(U16Register::AF))
+                Instruction::POP(U16Register::AF)
             }
             0xC5 => {
-                Some(Instruction::PUSH(U16Register::BC))
+                Instruction::PUSH(U16Register::BC)
             }
             0xD5 => {
-                Some(Instruction::PUSH(U16Register::DE))
+                Instruction::PUSH(U16Register::DE)
             }
             0xE5 => {
-                Some(Instruction::PUSH(U16Register::HL))
+                Instruction::PUSH(U16Register::HL)
             }
             0xF5 => {
-                Some(Instruction::PUSH(U16Register::AF))
+                Instruction::PUSH(U16Register::AF)
             }
             0xC2 => {
-                Some(Instruction::JP(JumpCondition::NZ))
+                Instruction::JP(JumpCondition::NZ)
             }
             0xC3 => {
-                Some(Instruction::JP(JumpCondition::Unconditional))
+                Instruction::JP(JumpCondition::Unconditional)
             }
             0xCA => {
-                Some(Instruction::JP(JumpCondition::Z))
+                Instruction::JP(JumpCondition::Z)
             }
             0xD2 => {
-                Some(Instruction::JP(JumpCondition::NC))
+                Instruction::JP(JumpCondition::NC)
             }
             0xDA => {
-                Some(Instruction::JP(JumpCondition::C))
+                Instruction::JP(JumpCondition::C)
             }
             0xCD => {
-                Some(Instruction::CALL(JumpCondition::Unconditional))
+                Instruction::CALL(JumpCondition::Unconditional)
             }
             0xC4 => {
-                Some(Instruction::CALL(JumpCondition::NZ))
+                Instruction::CALL(JumpCondition::NZ)
             }
             0xCC => {
-                Some(Instruction::CALL(JumpCondition::Z))
+                Instruction::CALL(JumpCondition::Z)
             }
             0xD4 => {
-                Some(Instruction::CALL(JumpCondition::NC))
+                Instruction::CALL(JumpCondition::NC)
             }
             0xDC => {
-                Some(Instruction::CALL(JumpCondition::C))
+                Instruction::CALL(JumpCondition::C)
             }
             0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
                 let n = instruction_byte & 0x38;
-                Some(Instruction::RST(n))
+                Instruction::RST(n)
             }
             0xC9 => {
-                Some(Instruction::RET(JumpCondition::Unconditional))
+                Instruction::RET(JumpCondition::Unconditional)
             }
             0xC0 => {
-                Some(Instruction::RET(JumpCondition::NZ))
+                Instruction::RET(JumpCondition::NZ)
             }
             0xC8 => {
-                Some(Instruction::RET(JumpCondition::Z))
+                Instruction::RET(JumpCondition::Z)
             }
             0xD0 => {
-                Some(Instruction::RET(JumpCondition::NC))
+                Instruction::RET(JumpCondition::NC)
             }
             0xD8 => {
-                Some(Instruction::RET(JumpCondition::C))
+                Instruction::RET(JumpCondition::C)
             }
             0xD9 => {
-                Some(Instruction::RETI)
+                Instruction::RETI
             }
             0xE0 => {
-                Some(Instruction::LDH(LdhOperand::I8, LdhDirection::FromA))
+                Instruction::LDH(LdhOperand::I8, LdhDirection::FromA)
             }
             0xF0 => {
-                Some(Instruction::LDH(LdhOperand::I8, LdhDirection::ToA))
+                Instruction::LDH(LdhOperand::I8, LdhDirection::ToA)
             }
             0xE2 => {
-                Some(Instruction::LDH(LdhOperand::Ci, LdhDirection::FromA))
+                Instruction::LDH(LdhOperand::Ci, LdhDirection::FromA)
             }
             0xF2 => {
-                Some(Instruction::LDH(LdhOperand::Ci, LdhDirection::ToA))
+                Instruction::LDH(LdhOperand::Ci, LdhDirection::ToA)
             }
             0xF3 => {
-                Some(Instruction::DI)
+                Instruction::DI
             }
             0xF8 => {
-                Some(Instruction::ADD16SPinHL)
+                Instruction::ADD16SPinHL
             }
             0xF9 => {
-                Some(Instruction::LD(LoadType::Word(
-                            LoadWordTarget::SP, LoadWordSource::HL)))
+                Instruction::LD(LoadType::Word(
+                            LoadWordTarget::SP, LoadWordSource::HL))
             }
             0xFB => {
-                Some(Instruction::EI)
+                Instruction::EI
             }
             0xC6 => {
-                Some(Instruction::ADD(ArithmeticOperand::D8))
+                Instruction::ADD(ArithmeticOperand::D8)
             }
             0xCE => {
-                Some(Instruction::ADC(ArithmeticOperand::D8))
+                Instruction::ADC(ArithmeticOperand::D8)
             }
             0xD6 => {
-                Some(Instruction::SUB(ArithmeticOperand::D8))
+                Instruction::SUB(ArithmeticOperand::D8)
             }
             0xDE => {
-                Some(Instruction::SBC(ArithmeticOperand::D8))
+                Instruction::SBC(ArithmeticOperand::D8)
             }
             0xE6 => {
-                Some(Instruction::AND(ArithmeticOperand::D8))
+                Instruction::AND(ArithmeticOperand::D8)
             }
             0xEE => {
-                Some(Instruction::XOR(ArithmeticOperand::D8))
+                Instruction::XOR(ArithmeticOperand::D8)
             }
             0xF6 => {
-                Some(Instruction::OR(ArithmeticOperand::D8))
+                Instruction::OR(ArithmeticOperand::D8)
             }
             0xFE => {
-                Some(Instruction::CP(ArithmeticOperand::D8))
+                Instruction::CP(ArithmeticOperand::D8)
             }
             0xE8 => {
-                Some(Instruction::ADD16SP)
+                Instruction::ADD16SP
             }
             0xE9 => {
-                Some(Instruction::JPHL)
+                Instruction::JPHL
             }
             0xEA => {
-                Some(Instruction::LD(LoadType::IndirectByteFromA(
-                            LoadIndirectByteOperand::Address)))
+                Instruction::LD(LoadType::IndirectByteFromA(
+                            LoadIndirectByteOperand::Address))
             }
             0xFA => {
-                Some(Instruction::LD(LoadType::IndirectByteToA(
-                            LoadIndirectByteOperand::Address)))
+                Instruction::LD(LoadType::IndirectByteToA(
+                            LoadIndirectByteOperand::Address))
+            }
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4
+            | 0xFC | 0xFD => {
+                Instruction::Illegal(instruction_byte)
+            }
+            0xCB => {
+                // Never executed, as the CB prefix is decoded together with
+                // the following byte from PREFIXED_INSTRUCTIONS.
+                Instruction::NOP
             }
-            _ => None,
+            _ => panic!("Opcode missing from the decoder."),
         }
     }
 
@@ -1931,6 +2444,484 @@ impl Instruction {
             DI => 1,
             EI => 1,
             HALT => 1,
+            STOP => 2,
+            Illegal(_opcode) => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::game_boy::cartridge::Cartridge;
+    use crate::game_boy::io::Buttons;
+
+    /// Cycles of all non-prefixed opcodes, for conditional jumps, calls
+    /// and returns if the condition is false
+    ///
+    /// Entries of 0 are the CB prefix and illegal opcodes.
+    /// https://gbdev.io/gb-opcodes/optables/
+    const CYCLES: [usize; 0x100] = [
+     // x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+         4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+         4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+         8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+         8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+         8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+         8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  0, 12, 24,  8, 16, // Cx
+         8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // Dx
+        12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // Ex
+        12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // Fx
+    ];
+
+    /// Cycles of conditional jumps, calls and returns if the condition
+    /// is true
+    const TAKEN_CYCLES: [(u8, usize); 16] = [
+        (0x20, 12), (0x28, 12), (0x30, 12), (0x38, 12),
+        (0xC0, 20), (0xC8, 20), (0xD0, 20), (0xD8, 20),
+        (0xC2, 16), (0xCA, 16), (0xD2, 16), (0xDA, 16),
+        (0xC4, 24), (0xCC, 24), (0xD4, 24), (0xDC, 24),
+    ];
+
+    /// Cycles of a CB-prefixed opcode including the prefix
+    fn prefixed_cycles(opcode: u8) -> usize {
+        let reads_hl = opcode & 0x07 == 0x06;
+        let is_bit = (0x40..0x80).contains(&opcode);
+        match (reads_hl, is_bit) {
+            (false, _) => 8,
+            (true, true) => 12,
+            (true, false) => 16,
+        }
+    }
+
+    /// Whether the condition of a conditional opcode holds for flags F
+    fn condition_holds(opcode: u8, f: u8) -> bool {
+        let flag = if opcode & 0x10 == 0 {
+            Flag::Zero
+        } else {
+            Flag::Carry
+        };
+        let set = f & flag as u8 != 0;
+        set == (opcode & 0x08 != 0)
+    }
+
+    /// Execute a single instruction in WRAM and return its cycles.
+    ///
+    /// All operand bytes are 0xC1, so that loads, jumps and calls stay
+    /// in WRAM, and BC, DE and HL point to WRAM, too.
+    fn execute_once(bytes: &[u8], f: u8) -> usize {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        let mut cpu = CPU::new();
+        for (i, byte) in bytes.iter().chain([0xC1; 2].iter()).enumerate() {
+            memory.write8(0xC000 + i as u16, *byte);
+        }
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        cpu.registers.write16(U16Register::BC, 0xC200);
+        cpu.registers.write16(U16Register::DE, 0xC200);
+        cpu.registers.write16(U16Register::HL, 0xC200);
+        cpu.registers.f = f;
+        cpu.step(&mut memory)
+    }
+
+    #[test]
+    fn unprefixed_cycles_match_reference() {
+        let mut mismatches = Vec::new();
+        for opcode in 0..=0xFF {
+            if CYCLES[opcode as usize] == 0 {
+                continue;
+            }
+            for f in [0x00, 0xF0] {
+                let taken = TAKEN_CYCLES.iter()
+                                        .find(|(o, _)| *o == opcode)
+                                        .filter(|_| condition_holds(opcode, f))
+                                        .map(|(_, cycles)| *cycles);
+                let expected = taken.unwrap_or(CYCLES[opcode as usize]);
+                let cycles = execute_once(&[opcode], f);
+                if cycles != expected {
+                    mismatches.push(format!("{:0>2X} with F = {:0>2X}: {} \
+                                             instead of {}", opcode, f,
+                                            cycles, expected));
+                }
+            }
+        }
+        assert!(mismatches.is_empty(), "{:#?}", mismatches);
+    }
+
+    #[test]
+    fn prefixed_cycles_match_reference() {
+        let mut mismatches = Vec::new();
+        for opcode in 0..=0xFF {
+            let cycles = execute_once(&[0xCB, opcode], 0);
+            let expected = prefixed_cycles(opcode);
+            if cycles != expected {
+                mismatches.push(format!("CB {:0>2X}: {} instead of {}",
+                                        opcode, cycles, expected));
+            }
+        }
+        assert!(mismatches.is_empty(), "{:#?}", mismatches);
+    }
+
+    #[test]
+    fn interrupt_dispatch_is_a_step_of_five_m_cycles() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        let mut cpu = CPU::new();
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        cpu.ime = true;
+        memory.write8(0xFFFF, 0x04);
+        memory.write8(0xFF0F, 0x04);
+        assert_eq!(cpu.step(&mut memory), INTERRUPT_DISPATCH_CYCLES);
+        assert_eq!(cpu.pc, InterruptAddress::TIMER as u16);
+        assert_eq!(memory.read16(0xCFFE), 0xC000);
+        assert_eq!(memory.read8(0xFF0F) & 0x1F, 0);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn oam_is_blocked_on_the_second_m_cycle_after_starting_dma() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        let program = [
+            0x3E, 0x80,        // LD A, 0x80
+            0x21, 0x00, 0xFE,  // LD HL, 0xFE00
+            0xE0, 0x46,        // LDH (DMA), A
+            0x7E,              // LD A, (HL)
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.write8(0xC000 + i as u16, *byte);
+        }
+        memory.write8(0x8000, 0x99);
+        memory.poke8(0xFE00, 0x42);
+        let mut cpu = CPU::new();
+        cpu.pc = 0xC000;
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+        // LDH writes 0xFF46 in its last M-cycle.
+        assert_eq!(memory.peek8(0xFE00), 0x42);
+        // LD A, (HL) reads OAM in its second M-cycle, two M-cycles after
+        // the write, when the first byte has been copied.
+        cpu.step(&mut memory);
+        assert_eq!(cpu.registers.read8(U8Register::A), 0xFF);
+        assert_eq!(memory.peek8(0xFE00), 0x99);
+    }
+
+    /// Run a program from WRAM for the given number of steps.
+    fn run_program(bytes: &[u8], steps: usize) -> CPU {
+        run_program_on(CPU::new(), bytes, steps)
+    }
+
+    fn run_program_on(mut cpu: CPU, bytes: &[u8], steps: usize) -> CPU {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        for (i, byte) in bytes.iter().enumerate() {
+            memory.write8(0xC000 + i as u16, *byte);
+        }
+        cpu.pc = 0xC000;
+        for _ in 0..steps {
+            cpu.step(&mut memory);
+        }
+        cpu
+    }
+
+    #[test]
+    fn call_stack_follows_calls_interrupts_and_returns() {
+        let program = [
+            0xFB,              // EI
+            0xCD, 0x10, 0xC0,  // CALL C010
+            0x00,              // NOP
+        ];
+        let mut cpu = run_program(&program, 2);
+        assert_eq!(cpu.call_stack(), [CallFrame{kind: CallKind::Call,
+                                                caller: 0xC001,
+                                                target: 0xC010,
+                                                sp: 0xFFFC}]);
+
+        // The timer interrupt handler calls RST 10.
+        let mut rom = vec![0; 0x8000];
+        rom[0x0010] = 0xC9;  // RET
+        rom[0x0050] = 0xD7;  // RST 10
+        rom[0x0051] = 0xD9;  // RETI
+        let cartridge = Cartridge::from_rom(rom).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF50, 1);
+        memory.write8(0xC010, 0xC9);  // RET
+        // Return address of the CALL
+        memory.write8(0xFFFC, 0x04);
+        memory.write8(0xFFFD, 0xC0);
+        memory.write8(0xFFFF, 0x04);
+        memory.write8(0xFF0F, 0x04);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.describe_call_stack(&memory),
+                   "#0 0010 called by RST from 0050\n\
+                    #1 0050 interrupted C010\n\
+                    #2 C010 called from C001");
+        cpu.step(&mut memory);
+        assert_eq!(cpu.call_stack().len(), 2);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC010);
+        assert_eq!(cpu.call_stack().len(), 1);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC004);
+        assert_eq!(cpu.describe_call_stack(&memory), "No calls");
+    }
+
+    /// Load a program into WRAM and request a timer interrupt
+    fn program_with_pending_interrupt(bytes: &[u8]) -> (CPU, MemoryBus) {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        for (i, byte) in bytes.iter().enumerate() {
+            memory.write8(0xC000 + i as u16, *byte);
+        }
+        memory.write8(0xFFFF, 0x04);
+        memory.write8(0xFF0F, 0x04);
+        let mut cpu = CPU::new();
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        (cpu, memory)
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        let program = [
+            0xFB,  // EI
+            0x00,  // NOP
+            0x00,  // NOP
+        ];
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC001);
+        // The instruction after EI still runs before the interrupt.
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC002);
+        assert!(cpu.ime);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, InterruptAddress::TIMER as u16);
+        assert_eq!(memory.read16(0xCFFE), 0xC002);
+    }
+
+    #[test]
+    fn di_right_after_ei_keeps_interrupts_disabled() {
+        let program = [
+            0xFB,  // EI
+            0xF3,  // DI
+            0x00,  // NOP
+            0x00,  // NOP
+        ];
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        for _ in 0..4 {
+            cpu.step(&mut memory);
+            assert!(!cpu.ime);
+        }
+        assert_eq!(cpu.pc, 0xC004);
+        assert_eq!(memory.read8(0xFF0F) & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn halt_right_after_ei_is_woken_by_an_interrupt() {
+        let program = [
+            0xFB,  // EI
+            0x76,  // HALT
+            0x00,  // NOP
+        ];
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        memory.write8(0xFF0F, 0x00);
+        for _ in 0..4 {
+            cpu.step(&mut memory);
+        }
+        assert!(cpu.halt);
+        assert!(cpu.ime);
+        assert_eq!(cpu.pc, 0xC002);
+        // The interrupt returns to the instruction after HALT.
+        memory.write8(0xFF0F, 0x04);
+        cpu.step(&mut memory);
+        assert!(!cpu.halt);
+        assert_eq!(cpu.pc, InterruptAddress::TIMER as u16);
+        assert_eq!(memory.read16(0xCFFE), 0xC002);
+
+        // With the interrupt already pending, HALT ends right away and
+        // the interrupt is taken after it.
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert!(!cpu.halt);
+        assert_eq!(cpu.pc, InterruptAddress::TIMER as u16);
+        assert_eq!(memory.read16(0xCFFE), 0xC002);
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up_the_cpu_for_good() {
+        let program = [
+            0xFB,  // EI
+            0x00,  // NOP
+            0xD3,  // illegal
+        ];
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        memory.write8(0xFF0F, 0x00);
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+        assert!(cpu.is_locked_up());
+        assert!(cpu.ime);
+        let pc = cpu.pc;
+        // Not even a pending interrupt gets the CPU going again.
+        memory.write8(0xFF0F, 0x04);
+        for _ in 0..10 {
+            cpu.step(&mut memory);
+            assert_eq!(cpu.pc, pc);
+        }
+        assert!(cpu.is_locked_up());
+        assert_eq!(memory.read8(0xFF0F) & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn memory_accesses_happen_in_their_m_cycle() {
+        // Enable the timer with TIMA incrementing every 16 cycles,
+        // reset DIV and read TIMA with the given instruction.
+        let tima_after_div_reset = |read: &[u8]| {
+            let mut program = vec![
+                0x3E, 0x05,  // LD A,05
+                0xE0, 0x07,  // LDH (07),A
+                0xE0, 0x04,  // LDH (04),A
+            ];
+            program.extend_from_slice(read);
+            run_program(&program, 4).registers.a
+        };
+        // TIMA increments 16 cycles after the reset, so it is read
+        // before the increment in the 3rd M-cycle of LDH and after it
+        // in the 4th M-cycle of LD.
+        let ldh = tima_after_div_reset(&[0xF0, 0x05]);
+        let ld = tima_after_div_reset(&[0xFA, 0x05, 0xFF]);
+        assert_eq!(ld, ldh + 1);
+    }
+
+    /// A writer whose output can still be read after handing it over
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_has_game_boy_doctor_format() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = CPU::new();
+        cpu.start_trace(Box::new(buffer.clone()));
+        let program = [
+            0x3E, 0x12,  // LD A,12
+            0x00,        // NOP
+            0x76,        // HALT
+        ];
+        run_program_on(cpu, &program, 5);
+        let trace = buffer.0.lock().unwrap().clone();
+        let trace = String::from_utf8(trace).unwrap();
+        assert_eq!(trace.lines().collect::<Vec<_>>(), [
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C000 \
+             PCMEM:3E,12,00,76",
+            "A:12 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C002 \
+             PCMEM:00,76,00,00",
+            "A:12 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C003 \
+             PCMEM:76,00,00,00",
+        ]);
+    }
+
+    #[test]
+    fn stop_pauses_the_divider_until_a_button_is_pressed() {
+        let program = [
+            0x3E, 0x10,  // LD A,10
+            0xE0, 0x00,  // LDH (00),A
+            0x10, 0x00,  // STOP
+            0x00,        // NOP
+        ];
+        let (mut cpu, mut memory) = program_with_pending_interrupt(&program);
+        memory.write8(0xFFFF, 0x00);
+        memory.step(0x1000);
+        assert_ne!(memory.read8(0xFF04), 0x00);
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+        assert_eq!(cpu.pc, 0xC006);
+        for _ in 0..1000 {
+            cpu.step(&mut memory);
+        }
+        assert_eq!(cpu.pc, 0xC006);
+        assert_eq!(memory.read8(0xFF04), 0x00);
+        // Only buttons of the selected action row wake the CPU.
+        memory.set_key_presses(Buttons::RIGHT.bits());
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC006);
+        memory.set_key_presses(Buttons::A.bits());
+        cpu.step(&mut memory);
+        assert_eq!(cpu.pc, 0xC007);
+        memory.step(0x1000);
+        assert_ne!(memory.read8(0xFF04), 0x00);
+    }
+
+    /// Lengths of all unprefixed instructions by opcode as listed in
+    /// https://gbdev.io/gb-opcodes/optables/, with STOP taking 2 bytes
+    /// and the illegal opcodes 1 byte
+    const INSTRUCTION_LENGTHS: [&str; 16] = [
+        "1311112131111121", "2311112121111121",
+        "2311112121111121", "2311112121111121",
+        "1111111111111111", "1111111111111111",
+        "1111111111111111", "1111111111111111",
+        "1111111111111111", "1111111111111111",
+        "1111111111111111", "1111111111111111",
+        "1133312111323321", "1131312111313121",
+        "2111112121311121", "2111112121311121",
+    ];
+
+    #[test]
+    fn decode_tables_cover_all_opcodes() {
+        for opcode in 0..=0xFFu8 {
+            let expected = INSTRUCTION_LENGTHS[opcode as usize >> 4]
+                .as_bytes()[opcode as usize & 0xF] - b'0';
+            assert_eq!(instruction_length(opcode), expected as u16,
+                       "{:0>2X}", opcode);
+            let is_illegal = matches!(Instruction::from_byte(opcode, false),
+                                      Instruction::Illegal(o) if o == opcode);
+            assert_eq!(is_illegal,
+                       [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4,
+                        0xFC, 0xFD].contains(&opcode),
+                       "{:0>2X}", opcode);
+        }
+        let operations = ["RLC", "RRC", "RL(", "RR(", "SLA", "SRA", "SWAP",
+                          "SRL"];
+        for opcode in 0..=0xFFu8 {
+            let instruction = Instruction::from_byte(opcode, true);
+            assert_eq!(instruction.len(), 2);
+            let name = format!("{:?}", instruction);
+            let operation = match opcode >> 6 {
+                0 => operations[opcode as usize >> 3],
+                1 => "BIT",
+                2 => "RES",
+                _ => "SET",
+            };
+            assert!(name.starts_with(operation), "CB{:0>2X}", opcode);
+            // The lowest three bits select B, C, D, E, H, L, (HL) or A.
+            assert_eq!(name.contains("HLI"), opcode & 0b111 == 0b110,
+                       "CB{:0>2X}", opcode);
         }
     }
 }
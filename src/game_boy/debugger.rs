@@ -0,0 +1,658 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+
+use super::cpu::CpuState;
+
+/// Kind of an access of the CPU to memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Break when the CPU accesses any address in a range
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addresses: RangeInclusive<u16>,
+    pub on_read: bool,
+    pub on_write: bool,
+}
+
+impl Watchpoint {
+    /// Parse "r:ADDRESS", "w:ADDRESS" or "rw:ADDRESS" with a hex address
+    /// or a range of them like "C000-C0FF"
+    ///
+    /// Without a prefix, the watchpoint breaks on writes.
+    pub fn from_spec(spec: &str) -> Option<Self> {
+        let (access, addresses) = spec.split_once(':')
+                                      .unwrap_or(("w", spec));
+        let (on_read, on_write) = match access {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return None,
+        };
+        let parse = |address| u16::from_str_radix(address, 16).ok();
+        let addresses = match addresses.split_once('-') {
+            Some((start, end)) => parse(start)?..=parse(end)?,
+            None => parse(addresses)?..=parse(addresses)?,
+        };
+        if addresses.is_empty() {
+            return None;
+        }
+        Some(Self{addresses, on_read, on_write})
+    }
+
+    pub fn matches(&self, address: u16, access: Access) -> bool {
+        let on_access = match access {
+            Access::Read => self.on_read,
+            Access::Write => self.on_write,
+        };
+        on_access && self.addresses.contains(&address)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.on_read {
+            write!(f, "r")?;
+        }
+        if self.on_write {
+            write!(f, "w")?;
+        }
+        write!(f, ":{:0>4X}", self.addresses.start())?;
+        if self.addresses.start() != self.addresses.end() {
+            write!(f, "-{:0>4X}", self.addresses.end())?;
+        }
+        Ok(())
+    }
+}
+
+/// An access that triggered a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u16,
+    /// Value read or written
+    pub value: u8,
+    pub access: Access,
+}
+
+/// Watchpoints that the memory bus checks on every access of the CPU
+///
+/// Hits are collected until the end of the instruction, when the
+/// debugger takes them.
+#[derive(Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    hits: RefCell<Vec<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len())
+            .then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    pub fn check(&self, address: u16, value: u8, access: Access) {
+        if self.watchpoints.iter().any(|w| w.matches(address, access)) {
+            self.hits.borrow_mut().push(WatchHit{address, value, access});
+        }
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(self.hits.get_mut())
+    }
+}
+
+/// A register of the CPU that a condition can look at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+impl Register {
+    const ALL: [Self; 14] = [
+        Self::A, Self::F, Self::B, Self::C, Self::D, Self::E, Self::H,
+        Self::L, Self::AF, Self::BC, Self::DE, Self::HL, Self::SP, Self::PC,
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter()
+                 .find(|register| format!("{:?}", register)
+                                      .eq_ignore_ascii_case(name))
+    }
+
+    fn value(self, cpu: &CpuState) -> u16 {
+        let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+        match self {
+            Self::A => cpu.a as u16,
+            Self::F => cpu.f as u16,
+            Self::B => cpu.b as u16,
+            Self::C => cpu.c as u16,
+            Self::D => cpu.d as u16,
+            Self::E => cpu.e as u16,
+            Self::H => cpu.h as u16,
+            Self::L => cpu.l as u16,
+            Self::AF => pair(cpu.a, cpu.f),
+            Self::BC => pair(cpu.b, cpu.c),
+            Self::DE => pair(cpu.d, cpu.e),
+            Self::HL => pair(cpu.h, cpu.l),
+            Self::SP => cpu.sp,
+            Self::PC => cpu.pc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    /// The byte at an address
+    Memory(u16),
+    Value(u16),
+}
+
+impl Operand {
+    /// Parse a register name, a hex address in brackets like [C0A0] or
+    /// a number like 60, 0x3C or $3C
+    pub fn parse(word: &str) -> Option<Self> {
+        if let Some(register) = Register::from_name(word) {
+            return Some(Self::Register(register));
+        }
+        if let Some(address) = word.strip_prefix('[')
+                                   .and_then(|word| word.strip_suffix(']')) {
+            let address = u16::from_str_radix(address, 16).ok()?;
+            return Some(Self::Memory(address));
+        }
+        let value = match word.strip_prefix("0x")
+                              .or_else(|| word.strip_prefix('$')) {
+            Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+            None => word.parse().ok()?,
+        };
+        Some(Self::Value(value))
+    }
+
+    /// Value of the operand, with `memory` being the address space
+    pub fn value(self, cpu: &CpuState, memory: &[u8]) -> u16 {
+        match self {
+            Self::Register(register) => register.value(cpu),
+            Self::Memory(address) => memory[address as usize] as u16,
+            Self::Value(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Register(register) => write!(f, "{:?}", register),
+            Self::Memory(address) => write!(f, "[{:0>4X}]", address),
+            Self::Value(value) => write!(f, "0x{:X}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    const OPERATORS: [(&'static str, Self); 6] = [
+        ("==", Self::Equal),
+        ("!=", Self::NotEqual),
+        ("<=", Self::LessOrEqual),
+        (">=", Self::GreaterOrEqual),
+        ("<", Self::Less),
+        (">", Self::Greater),
+    ];
+
+    fn operator(self) -> &'static str {
+        Self::OPERATORS.iter().find(|(_, c)| *c == self).unwrap().0
+    }
+}
+
+/// Condition of a breakpoint like `A==0x3C && [C0A0]>0x10`
+///
+/// `&&` binds stronger than `||`, and parentheses group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut tokens = &tokens[..];
+        let condition = Self::parse_or(&mut tokens)?;
+        match tokens.first() {
+            None => Ok(condition),
+            Some(token) => Err(format!("unexpected {:?} in condition",
+                                       token)),
+        }
+    }
+
+    fn parse_or(tokens: &mut &[String]) -> Result<Self, String> {
+        let mut condition = Self::parse_and(tokens)?;
+        while tokens.first().is_some_and(|token| token == "||") {
+            *tokens = &tokens[1..];
+            let right = Self::parse_and(tokens)?;
+            condition = Self::Or(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_and(tokens: &mut &[String]) -> Result<Self, String> {
+        let mut condition = Self::parse_comparison(tokens)?;
+        while tokens.first().is_some_and(|token| token == "&&") {
+            *tokens = &tokens[1..];
+            let right = Self::parse_comparison(tokens)?;
+            condition = Self::And(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_comparison(tokens: &mut &[String]) -> Result<Self, String> {
+        if tokens.first().is_some_and(|token| token == "(") {
+            *tokens = &tokens[1..];
+            let condition = Self::parse_or(tokens)?;
+            if tokens.first().is_none_or(|token| token != ")") {
+                return Err("missing ) in condition".to_string());
+            }
+            *tokens = &tokens[1..];
+            return Ok(condition);
+        }
+        let (left, operator, right) = match tokens {
+            [left, operator, right, ..] => (left, operator, right),
+            _ => return Err("incomplete comparison in condition"
+                                .to_string()),
+        };
+        *tokens = &tokens[3..];
+        let operand = |word: &String| Operand::parse(word).ok_or_else(|| {
+            format!("{:?} is neither a register nor a number", word)
+        });
+        let comparison = Comparison::OPERATORS
+            .iter()
+            .find(|(op, _)| op == operator)
+            .map(|(_, comparison)| *comparison)
+            .ok_or_else(|| format!("unknown comparison {:?}", operator))?;
+        Ok(Self::Compare(operand(left)?, comparison, operand(right)?))
+    }
+
+    /// Whether the condition holds, with `memory` being the address space
+    pub fn evaluate(&self, cpu: &CpuState, memory: &[u8]) -> bool {
+        match self {
+            Self::Compare(left, comparison, right) => {
+                let (left, right) = (left.value(cpu, memory),
+                                     right.value(cpu, memory));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+            Self::And(left, right) => {
+                left.evaluate(cpu, memory) && right.evaluate(cpu, memory)
+            }
+            Self::Or(left, right) => {
+                left.evaluate(cpu, memory) || right.evaluate(cpu, memory)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compare(left, comparison, right) => {
+                write!(f, "{}{}{}", left, comparison.operator(), right)
+            }
+            Self::And(left, right) => {
+                // Parentheses keep an `||` inside an `&&` together.
+                let group = |c: &Condition| match c {
+                    Self::Or(..) => format!("({})", c),
+                    _ => c.to_string(),
+                };
+                write!(f, "{} && {}", group(left), group(right))
+            }
+            Self::Or(left, right) => write!(f, "{} || {}", left, right),
+        }
+    }
+}
+
+/// Split a condition into operands, operators and parentheses
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '[' {
+            let mut word = String::new();
+            for c in chars.by_ref() {
+                word.push(c);
+                if c == ']' {
+                    break;
+                }
+            }
+            if !word.ends_with(']') {
+                return Err("missing ] in condition".to_string());
+            }
+            tokens.push(word);
+        } else if c.is_ascii_alphanumeric() || c == '$' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_alphanumeric() && c != '$' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else {
+            let mut operator = String::new();
+            while let Some(&c) = chars.peek() {
+                if !"=!<>&|".contains(c) {
+                    break;
+                }
+                operator.push(c);
+                chars.next();
+            }
+            if operator.is_empty() {
+                return Err(format!("unexpected {:?} in condition", c));
+            }
+            tokens.push(operator);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Break before the CPU executes the instruction at an address,
+/// possibly only if a condition holds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    /// Parse a hex address, optionally followed by `if` and a condition,
+    /// e.g. "0150 if A==0x3C && C>0x10"
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (address, condition) = match spec.split_once(char::is_whitespace) {
+            Some((address, rest)) => {
+                let condition = rest.trim_start()
+                                    .strip_prefix("if")
+                                    .ok_or("expected if after the address")?;
+                (address, Some(Condition::parse(condition)?))
+            }
+            None => (spec, None),
+        };
+        let address = u16::from_str_radix(address, 16)
+                          .map_err(|_| format!("invalid address {:?}",
+                                               address))?;
+        Ok(Self{address, condition})
+    }
+
+    pub fn is_hit(&self, cpu: &CpuState, memory: &[u8]) -> bool {
+        cpu.pc == self.address
+            && self.condition.as_ref()
+                             .is_none_or(|condition| {
+                                 condition.evaluate(cpu, memory)
+                             })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0>4X}", self.address)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// A command entered at the debugger prompt
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    /// Execute one instruction and break again
+    Step,
+    Registers,
+    /// Show the calls that haven't returned yet
+    Backtrace,
+    /// Show the given number of bytes starting at an address
+    Examine{address: u16, length: u16},
+    /// Show the objects in OAM
+    Objects,
+    /// Show the given number of routines that took the most cycles
+    Profile(usize),
+    Watch(Watchpoint),
+    ListWatchpoints,
+    /// Remove the watchpoint with the given number
+    Unwatch(usize),
+    Break(Breakpoint),
+    ListBreakpoints,
+    /// Remove the breakpoint with the given number
+    Delete(usize),
+    Help,
+    /// Stop the emulation
+    Quit,
+}
+
+pub const HELP: &str = "\
+c, continue         continue until the next break
+s, step             execute one instruction
+r, registers        show the CPU registers
+bt, backtrace       show the calls that haven't returned yet
+x ADDRESS [LENGTH]  show the memory at a hex address
+oam                 show the objects in OAM
+profile [COUNT]     show the routines that took the most cycles so far
+watch [SPEC]        break on accesses like r:FF44, w:C0A0 or rw:C000-C0FF,
+                    or list the watchpoints without SPEC
+unwatch NUMBER      remove a watchpoint
+break [ADDRESS [if CONDITION]]
+                    break at a hex address, e.g. only if the condition
+                    A==0x3C && [C0A0]>0x10 holds, or list the breakpoints
+delete NUMBER       remove a breakpoint
+q, quit             stop the emulation";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let hex = |word: Option<&str>| {
+            word.and_then(|word| u16::from_str_radix(word, 16).ok())
+        };
+        let command = match (command, argument) {
+            ("c" | "continue", None) => Self::Continue,
+            ("s" | "step", None) => Self::Step,
+            ("r" | "registers", None) => Self::Registers,
+            ("bt" | "backtrace", None) => Self::Backtrace,
+            ("x", Some(_)) => Self::Examine{
+                address: hex(argument).ok_or("invalid address")?,
+                length: match words.next() {
+                    Some(length) => hex(Some(length)).ok_or("invalid length")?,
+                    None => 16,
+                },
+            },
+            ("oam", None) => Self::Objects,
+            ("profile", None) => Self::Profile(20),
+            ("profile", Some(count)) => Self::Profile(
+                count.parse().map_err(|_| "invalid count")?),
+            ("watch", Some(spec)) => Self::Watch(
+                Watchpoint::from_spec(spec).ok_or("invalid watchpoint")?),
+            ("watch", None) => Self::ListWatchpoints,
+            ("unwatch", Some(number)) => Self::Unwatch(
+                number.parse().map_err(|_| "invalid watchpoint number")?),
+            ("b" | "break", Some(_)) => {
+                let spec = line.trim_start()
+                               .split_once(char::is_whitespace)
+                               .unwrap().1;
+                return Breakpoint::from_spec(spec).map(Self::Break);
+            }
+            ("b" | "break", None) => Self::ListBreakpoints,
+            ("d" | "delete", Some(number)) => Self::Delete(
+                number.parse().map_err(|_| "invalid breakpoint number")?),
+            ("h" | "help", None) => Self::Help,
+            ("q" | "quit", None) => Self::Quit,
+            _ => return Err(format!("unknown command {:?}, try help",
+                                    line.trim())),
+        };
+        Ok(command)
+    }
+}
+
+/// Prompt of the debugger, which reads commands when the emulation
+/// breaks
+pub struct Debugger {
+    input: Box<dyn BufRead + Send>,
+    output: Box<dyn Write + Send>,
+    /// Whether to break after the next instruction
+    pub stepping: bool,
+    pub breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    pub fn new(input: Box<dyn BufRead + Send>,
+               output: Box<dyn Write + Send>) -> Self {
+        Self{
+            input,
+            output,
+            stepping: false,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Read commands until a valid one is entered
+    ///
+    /// The end of the input quits.
+    pub fn read_command(&mut self) -> io::Result<Command> {
+        loop {
+            write!(self.output, "(gb) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(Command::Quit);
+            }
+            match Command::parse(&line) {
+                Ok(command) => return Ok(command),
+                Err(message) => writeln!(self.output, "{}", message)?,
+            }
+        }
+    }
+
+    /// Index of the first breakpoint hit by the instruction that the
+    /// CPU executes next
+    pub fn breakpoint_hit(&self, cpu: &CpuState, memory: &[u8])
+            -> Option<usize> {
+        self.breakpoints.iter().position(|b| b.is_hit(cpu, memory))
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+}
+
+/// The debugger reads from stdin and writes to stdout
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new(Box::new(io::BufReader::new(io::stdin())),
+                  Box::new(io::stdout()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchpoints_and_commands_are_parsed() {
+        let watchpoint = Watchpoint::from_spec("rw:C000-C0FF").unwrap();
+        assert!(watchpoint.matches(0xC0A0, Access::Read));
+        assert!(!watchpoint.matches(0xC100, Access::Write));
+        assert_eq!(watchpoint.to_string(), "rw:C000-C0FF");
+        let watchpoint = Watchpoint::from_spec("C0A0").unwrap();
+        assert!(watchpoint.matches(0xC0A0, Access::Write));
+        assert!(!watchpoint.matches(0xC0A0, Access::Read));
+        assert_eq!(watchpoint.to_string(), "w:C0A0");
+        assert_eq!(Watchpoint::from_spec("x:C000"), None);
+        assert_eq!(Watchpoint::from_spec("w:C0FF-C000"), None);
+
+        assert_eq!(Command::parse("x ff40 4\n"),
+                   Ok(Command::Examine{address: 0xFF40, length: 4}));
+        assert_eq!(Command::parse("watch r:FF44"),
+                   Ok(Command::Watch(Watchpoint{addresses: 0xFF44..=0xFF44,
+                                                on_read: true,
+                                                on_write: false})));
+        assert_eq!(Command::parse("unwatch 1"), Ok(Command::Unwatch(1)));
+        assert_eq!(Command::parse("bt"), Ok(Command::Backtrace));
+        assert!(Command::parse("step 2").is_err());
+    }
+
+    #[test]
+    fn breakpoint_conditions_look_at_registers() {
+        let breakpoint = Breakpoint::from_spec(
+            "0150 if a==0x3C && (C>16 || HL==$C000)").unwrap();
+        assert_eq!(breakpoint.to_string(),
+                   "0150 if A==0x3C && (C>0x10 || HL==0xC000)");
+        let mut cpu = CpuState{a: 0x3C, c: 0x11, pc: 0x150,
+                               ..CpuState::default()};
+        let mut memory = vec![0; 0x10000];
+        assert!(breakpoint.is_hit(&cpu, &memory));
+        cpu.c = 0x10;
+        assert!(!breakpoint.is_hit(&cpu, &memory));
+        cpu.h = 0xC0;
+        assert!(breakpoint.is_hit(&cpu, &memory));
+        cpu.pc = 0x151;
+        assert!(!breakpoint.is_hit(&cpu, &memory));
+        let breakpoint = Breakpoint::from_spec("0150 if [c0a0]>=2").unwrap();
+        assert_eq!(breakpoint.to_string(), "0150 if [C0A0]>=0x2");
+        cpu.pc = 0x150;
+        assert!(!breakpoint.is_hit(&cpu, &memory));
+        memory[0xC0A0] = 2;
+        assert!(breakpoint.is_hit(&cpu, &memory));
+        assert_eq!(Command::parse("break 0150"),
+                   Ok(Command::Break(Breakpoint{address: 0x150,
+                                                condition: None})));
+        assert!(Breakpoint::from_spec("0150 if A=0x3C").is_err());
+        assert!(Breakpoint::from_spec("0150 if A==").is_err());
+        assert!(Breakpoint::from_spec("0150 if (A==1").is_err());
+        assert!(Breakpoint::from_spec("0150 when A==1").is_err());
+        assert!(Breakpoint::from_spec("0150 if [C0A0==1").is_err());
+    }
+}
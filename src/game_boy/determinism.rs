@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, BufRead, Write};
+
+/// 64 bit FNV-1a hash, which unlike `DefaultHasher` is stable
+/// across Rust versions.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn write(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Checks that the emulation is deterministic by hashing every frame
+///
+/// The hashes are written into a file with a line per frame, holding
+/// the frame number and the hash in hex, and can be compared with such
+/// a file from a reference run, e.g. of the same movie before a
+/// refactoring.
+pub struct FrameHashes {
+    output: Option<Box<dyn Write + Send>>,
+    /// Hashes of the reference run, in reverse order
+    reference: Option<Vec<(u64, u64)>>,
+    mismatches: usize,
+    compared: usize,
+}
+
+impl FrameHashes {
+    /// Write the hashes of all following frames
+    pub fn write_to(output: Box<dyn Write + Send>) -> Self {
+        Self{
+            output: Some(output),
+            reference: None,
+            mismatches: 0,
+            compared: 0,
+        }
+    }
+
+    /// Compare the hashes of the following frames with the reference
+    pub fn compare_with(reference: impl BufRead) -> io::Result<Self> {
+        let mut hashes = Vec::new();
+        for line in reference.lines() {
+            let line = line?;
+            let hash = line.split_once(' ').and_then(|(frame, hash)| {
+                Some((frame.parse().ok()?,
+                      u64::from_str_radix(hash, 16).ok()?))
+            });
+            match hash {
+                Some(hash) => hashes.push(hash),
+                None => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid frame hash {:?}", line))),
+            }
+        }
+        hashes.reverse();
+        Ok(Self{
+            output: None,
+            reference: Some(hashes),
+            mismatches: 0,
+            compared: 0,
+        })
+    }
+
+    /// Add the hash of the given frame
+    ///
+    /// The first mismatch with the reference is logged as an error.
+    pub fn add(&mut self, frame: u64, hash: u64) -> io::Result<()> {
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{} {:016x}", frame, hash)?;
+        }
+        let reference = match &mut self.reference {
+            Some(reference) => reference,
+            None => return Ok(()),
+        };
+        // Frames before the start of the reference are not compared.
+        if reference.last().is_some_and(|&(first, _)| frame < first) {
+            return Ok(());
+        }
+        match reference.pop() {
+            Some((expected_frame, expected)) if expected_frame == frame => {
+                self.compared += 1;
+                if hash != expected {
+                    if self.mismatches == 0 {
+                        log::error!("Frame {} has hash {:016x} instead of \
+                                     {:016x} as in the reference.",
+                                    frame, hash, expected);
+                    }
+                    self.mismatches += 1;
+                }
+            }
+            Some((expected_frame, _)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("reference skips from frame {} to {}",
+                            frame, expected_frame)));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Whether all frames of the reference have been compared
+    pub fn is_finished(&self) -> bool {
+        self.reference.as_ref().is_some_and(|reference| reference.is_empty())
+    }
+
+    /// Number of frames whose hash differs from the reference
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+
+    /// Number of frames compared with the reference
+    pub fn compared(&self) -> usize {
+        self.compared
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_hashes_are_compared_with_reference() {
+        let reference = b"3 00000000000000aa\n4 00000000000000bb\n\
+                          5 00000000000000cc\n";
+        let mut hashes = FrameHashes::compare_with(&reference[..]).unwrap();
+        for (frame, hash) in [(2, 0x12), (3, 0xAA), (4, 0xBC), (5, 0xCD)] {
+            assert!(!hashes.is_finished());
+            hashes.add(frame, hash).unwrap();
+        }
+        assert!(hashes.is_finished());
+        assert_eq!((hashes.compared(), hashes.mismatches()), (3, 2));
+        assert_eq!(fnv1a(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+}
@@ -2,9 +2,12 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use serde::{Deserialize, Serialize};
+
 use super::io::{IO, HEIGHT, WIDTH};
 
 /// A 160x144 pixel display with 4 shades of gray
+#[derive(Serialize, Deserialize)]
 pub struct Display {
     pixels: Vec<u8>,
 }
@@ -20,6 +23,10 @@ impl Display {
         &mut self.pixels[y as usize * WIDTH..((y + 1) as usize * WIDTH)]
     }
 
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
     pub fn refresh<Window: IO>(&self, window: &mut Window) {
         window.refresh(&self.pixels);
     }
@@ -0,0 +1,234 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::{Deserialize, Serialize};
+
+/// Number of 16 bit words of the 93LC56
+pub const EEPROM_WORDS: usize = 128;
+
+/// Serial EEPROM 93LC56 of MBC7 cartridges
+///
+/// The game drives its pins through a register, bit-banging commands
+/// that consist of a start bit, a 2 bit opcode and an 8 bit address,
+/// of which only the lower 7 bits select one of the 128 words.  Data is
+/// shifted in and out on each rising edge of the clock, most
+/// significant bit first.
+///
+/// Bit  Pin
+/// ---  ---
+/// 7    CS   Chip select
+/// 6    CLK  Clock
+/// 1    DI   Data in
+/// 0    DO   Data out (read only)
+///
+/// The contents are passed in as bytes, storing each word in little
+/// endian, so that they can be kept in `.sav` files like cartridge RAM.
+/// Writes finish immediately, so the chip always signals ready.
+///
+/// https://gbdev.io/pandocs/MBC7.html#ax8x---eeprom
+#[derive(Serialize, Deserialize)]
+pub struct Eeprom {
+    chip_select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+    writes_enabled: bool,
+    state: EepromState,
+}
+
+#[derive(Serialize, Deserialize)]
+enum EepromState {
+    /// Waiting for a start bit
+    Idle,
+    /// Receiving opcode and address
+    Command{bits: u8, value: u16},
+    /// Shifting out words, starting at the given address
+    Reading{address: u8, bit: u8},
+    /// Receiving the word to write to the given address, or to all
+    /// addresses if None
+    Writing{address: Option<u8>, bits: u8, value: u16},
+}
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Self{
+            chip_select: false,
+            clock: false,
+            data_in: false,
+            data_out: true,
+            writes_enabled: false,
+            state: EepromState::Idle,
+        }
+    }
+}
+
+impl Eeprom {
+    pub fn read(&self) -> u8 {
+        (self.chip_select as u8) << 7
+            | (self.clock as u8) << 6
+            | (self.data_in as u8) << 1
+            | self.data_out as u8
+    }
+
+    pub fn write(&mut self, value: u8, memory: &mut [u8]) {
+        let rising_edge = !self.clock && value & 0x40 != 0;
+        self.chip_select = value & 0x80 != 0;
+        self.clock = value & 0x40 != 0;
+        self.data_in = value & 0x02 != 0;
+        if !self.chip_select {
+            self.state = EepromState::Idle;
+            self.data_out = true;
+        } else if rising_edge {
+            self.clock_in(memory);
+        }
+    }
+
+    fn clock_in(&mut self, memory: &mut [u8]) {
+        let bit = self.data_in as u16;
+        self.state = match self.state {
+            EepromState::Idle if self.data_in => {
+                EepromState::Command{bits: 0, value: 0}
+            }
+            EepromState::Idle => EepromState::Idle,
+            EepromState::Command{bits: 9, value} => {
+                self.execute(value << 1 | bit, memory)
+            }
+            EepromState::Command{bits, value} => {
+                EepromState::Command{bits: bits + 1, value: value << 1 | bit}
+            }
+            EepromState::Reading{address, bit: position} => {
+                let word = read_word(memory, address);
+                self.data_out = (word >> (15 - position)) & 1 != 0;
+                if position == 15 {
+                    EepromState::Reading{address: (address + 1) & 0x7F,
+                                         bit: 0}
+                } else {
+                    EepromState::Reading{address, bit: position + 1}
+                }
+            }
+            EepromState::Writing{address, bits: 15, value} => {
+                let value = value << 1 | bit;
+                if self.writes_enabled {
+                    match address {
+                        Some(address) => write_word(memory, address, value),
+                        None => for address in 0..EEPROM_WORDS as u8 {
+                            write_word(memory, address, value);
+                        }
+                    }
+                }
+                EepromState::Idle
+            }
+            EepromState::Writing{address, bits, value} => {
+                EepromState::Writing{address, bits: bits + 1,
+                                     value: value << 1 | bit}
+            }
+        };
+    }
+
+    /// Start the command with the given opcode and address
+    fn execute(&mut self, command: u16, memory: &mut [u8]) -> EepromState {
+        let address = command as u8 & 0x7F;
+        match (command >> 8, (command >> 6) & 0b11) {
+            (0b10, _) => { // READ
+                // A dummy 0 precedes the data.
+                self.data_out = false;
+                return EepromState::Reading{address, bit: 0};
+            }
+            (0b01, _) => { // WRITE
+                return EepromState::Writing{address: Some(address), bits: 0,
+                                            value: 0};
+            }
+            (0b11, _) => { // ERASE
+                if self.writes_enabled {
+                    write_word(memory, address, 0xFFFF);
+                }
+            }
+            (_, 0b00) => { // EWDS
+                self.writes_enabled = false;
+            }
+            (_, 0b01) => { // WRAL
+                return EepromState::Writing{address: None, bits: 0,
+                                            value: 0};
+            }
+            (_, 0b10) => { // ERAL
+                if self.writes_enabled {
+                    memory[..2 * EEPROM_WORDS].fill(0xFF);
+                }
+            }
+            _ => { // EWEN
+                self.writes_enabled = true;
+            }
+        }
+        EepromState::Idle
+    }
+}
+
+fn read_word(memory: &[u8], address: u8) -> u16 {
+    let offset = 2 * address as usize;
+    u16::from_le_bytes([memory[offset], memory[offset + 1]])
+}
+
+fn write_word(memory: &mut [u8], address: u8, value: u16) {
+    let offset = 2 * address as usize;
+    memory[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CS: u8 = 0x80;
+    const CLK: u8 = 0x40;
+    const DI: u8 = 0x02;
+
+    /// Clock in bits, most significant first, and return DO after each
+    fn send(eeprom: &mut Eeprom, memory: &mut [u8], bits: u32, count: u8)
+            -> u32 {
+        let mut output = 0;
+        for i in (0..count).rev() {
+            let data = if bits >> i & 1 != 0 { DI } else { 0 };
+            eeprom.write(CS | data, memory);
+            eeprom.write(CS | CLK | data, memory);
+            output = output << 1 | (eeprom.read() & 1) as u32;
+        }
+        output
+    }
+
+    fn deselect(eeprom: &mut Eeprom, memory: &mut [u8]) {
+        eeprom.write(0, memory);
+    }
+
+    #[test]
+    fn words_are_written_only_after_enabling_writes() {
+        let mut eeprom = Eeprom::default();
+        let mut memory = vec![0xFF; 2 * EEPROM_WORDS];
+        // WRITE 0x1234 to word 5
+        send(&mut eeprom, &mut memory, 0b101_0000_0101, 11);
+        send(&mut eeprom, &mut memory, 0x1234, 16);
+        deselect(&mut eeprom, &mut memory);
+        assert_eq!(read_word(&memory, 5), 0xFFFF);
+        // EWEN
+        send(&mut eeprom, &mut memory, 0b100_1100_0000, 11);
+        deselect(&mut eeprom, &mut memory);
+        send(&mut eeprom, &mut memory, 0b101_0000_0101, 11);
+        send(&mut eeprom, &mut memory, 0x1234, 16);
+        deselect(&mut eeprom, &mut memory);
+        assert_eq!(&memory[10..12], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn read_shifts_out_dummy_zero_and_consecutive_words() {
+        let mut eeprom = Eeprom::default();
+        let mut memory = vec![0xFF; 2 * EEPROM_WORDS];
+        write_word(&mut memory, 0x7F, 0xBEEF);
+        write_word(&mut memory, 0x00, 0x0F0F);
+        // READ word 0x7F
+        let output = send(&mut eeprom, &mut memory, 0b110_0111_1111, 11);
+        assert_eq!(output & 1, 0);
+        assert_eq!(send(&mut eeprom, &mut memory, 0, 16), 0xBEEF);
+        assert_eq!(send(&mut eeprom, &mut memory, 0, 16), 0x0F0F);
+        deselect(&mut eeprom, &mut memory);
+        assert_eq!(eeprom.read() & 1, 1);
+    }
+}
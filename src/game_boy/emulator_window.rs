@@ -2,59 +2,364 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use minifb::{Key, Window, WindowOptions};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-use super::io::{IO, HEIGHT, WIDTH};
+use minifb::{InputCallback, Key, KeyRepeat, Scale, ScaleMode, Window,
+             WindowOptions};
+
+use super::colorization::ColorPalettes;
+use super::io::{Hotkey, IO, HEIGHT, SGB_HEIGHT, SGB_WIDTH, WIDTH};
+use super::viewer::{View, VramViews, MARKER, MARKER_COLOR};
+use crate::settings::GameBoyKeyBindings;
+use crate::window_scale::default_scale;
 
 /// A 160x144 pixel display with 4 shades of gray
 pub struct EmulatorWindow {
-    display_buffer: Vec<u32>,
+    screen: ScreenBuffer,
     window: Window,
+    /// Colors of the BG, OBP0 and OBP1 shades, indexed by pixel value
+    palette: [u32; 12],
+    /// Keys of the JoyPad buttons in the bit order of `get_key_presses`
+    key_bindings: [Key; 8],
+    /// Keys that tilt right, left, up and down
+    tilt_bindings: [Key; 4],
+    /// Keys pressed since the last call of `get_key_presses`, so that
+    /// short taps are seen even if the key is already released again
+    tapped_keys: Rc<RefCell<Vec<Key>>>,
+    /// Windows of the VRAM viewer, opened with its first views
+    ///
+    /// Windows that the user closes are not opened again.
+    viewer_windows: Option<[Option<Window>; 3]>,
+    /// Width and height of the window pixels showing a screen pixel when
+    /// the window is opened
+    pixel_size: usize,
 }
 
-const PIXEL_SIZE: usize = 4;
+/// Frames scaled up by whole pixels, with effects applied
+///
+/// This is kept apart from the window, which only shows the result.
+#[derive(Default)]
+pub struct ScreenBuffer {
+    pixels: Vec<u32>,
+    /// Whether to blend each frame with the one before, see
+    /// `EmulatorWindow::set_ghosting`
+    ghosting: bool,
+    /// Colors of the last frame before blending
+    previous_frame: Vec<u32>,
+}
 
-const COLORS: [u32; 4] = [0xFFFFFF, 0x808080, 0x404040, 0];
+impl ScreenBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-impl Default for EmulatorWindow {
-    fn default() -> Self {
-        let window = Window::new(
+    pub fn set_ghosting(&mut self, ghosting: bool) {
+        self.ghosting = ghosting;
+        self.previous_frame.clear();
+    }
+
+    /// Draw a frame of the given size, scaled up by `pixel_size`
+    ///
+    /// `color` gives the color of each pixel by its index in the frame.
+    pub fn draw(&mut self, width: usize, height: usize, pixel_size: usize,
+                color: impl Fn(usize) -> u32) -> &[u32] {
+        // There is nothing to blend with after the frame size changed.
+        let blends = self.ghosting
+                     && self.previous_frame.len() == width * height;
+        if self.ghosting {
+            self.previous_frame.resize(width * height, 0);
+        }
+        let buffer_width = width * pixel_size;
+        self.pixels.resize(width * height * pixel_size * pixel_size, 0);
+        for line in 0..height {
+            let buffer_line_start = line * pixel_size * buffer_width;
+            let buffer_line_range
+                = buffer_line_start..(buffer_line_start + buffer_width);
+            let buffer_line = &mut self.pixels[buffer_line_range.clone()];
+            for col in 0..width {
+                let i = line * width + col;
+                let mut color = color(i);
+                if self.ghosting {
+                    let previous = std::mem::replace(
+                        &mut self.previous_frame[i], color);
+                    if blends {
+                        color = blend(color, previous);
+                    }
+                }
+                buffer_line[col*pixel_size..(col+1)*pixel_size].fill(color);
+            }
+            for i in 1..pixel_size {
+                self.pixels.copy_within(buffer_line_range.clone(),
+                                        buffer_line_start + i * buffer_width);
+            }
+        }
+        &self.pixels
+    }
+}
+
+
+const DEFAULT_PALETTE: [u32; 4] = [0xFFFFFF, 0x808080, 0x404040, 0];
+
+const DEFAULT_KEY_BINDINGS: [Key; 8] = [
+    Key::Right,
+    Key::Left,
+    Key::Up,
+    Key::Down,
+    Key::X,  // A
+    Key::Z,  // B
+    Key::Q,  // Select
+    Key::W,  // Start
+];
+
+const DEFAULT_TILT_BINDINGS: [Key; 4] = [Key::L, Key::J, Key::I, Key::K];
+
+/// Collects the keys that the window reports as pressed while it
+/// handles its events
+struct KeyTaps {
+    keys: Rc<RefCell<Vec<Key>>>,
+}
+
+impl InputCallback for KeyTaps {
+    fn add_char(&mut self, _uni_char: u32) {}
+
+    fn set_key_state(&mut self, key: Key, pressed: bool) {
+        if pressed {
+            self.keys.borrow_mut().push(key);
+        }
+    }
+}
+
+/// Titles and scales of the windows showing the `VramViews`
+const VIEWER_WINDOWS: [(&str, Scale); 3] = [
+    ("Tiles", Scale::X2),
+    ("BG maps", Scale::X2),
+    ("OAM", Scale::X4),
+];
+
+impl EmulatorWindow {
+    /// Open a new emulator window showing each pixel of the screen as
+    /// `scale` x `scale` pixels
+    ///
+    /// This fails if no window can be created, e.g. when running
+    /// over SSH without X forwarding.
+    pub fn new(scale: usize) -> Result<Self, minifb::Error> {
+        Self::open(WIDTH, HEIGHT, scale)
+    }
+
+    /// Open a window large enough for Super Game Boy frames
+    ///
+    /// The window shows the Game Boy screen inside the SGB border.
+    pub fn with_sgb_border(scale: usize) -> Result<Self, minifb::Error> {
+        Self::open(SGB_WIDTH, SGB_HEIGHT, scale)
+    }
+
+    fn open(width: usize, height: usize, scale: usize)
+            -> Result<Self, minifb::Error> {
+        // minifb crashes instead of returning an error if it can't
+        // connect to a display server, so check for one beforehand.
+        if cfg!(all(unix, not(target_os = "macos")))
+           && std::env::var_os("DISPLAY").is_none()
+           && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            return Err(minifb::Error::WindowCreate(
+                "no display server found".to_string()));
+        }
+        let mut window = Window::new(
             "Game Boy emulator",
-            WIDTH * PIXEL_SIZE,
-            HEIGHT * PIXEL_SIZE,
-            WindowOptions::default(),
-        ).unwrap();
-        Self{
-            display_buffer: vec![0; WIDTH * HEIGHT * PIXEL_SIZE * PIXEL_SIZE],
+            width * scale,
+            height * scale,
+            WindowOptions{
+                resize: true,
+                scale_mode: ScaleMode::AspectRatioStretch,
+                ..WindowOptions::default()
+            },
+        )?;
+        let tapped_keys = Rc::new(RefCell::new(Vec::new()));
+        window.set_input_callback(Box::new(KeyTaps{
+            keys: tapped_keys.clone(),
+        }));
+        Ok(Self{
+            screen: ScreenBuffer::new(),
             window,
+            palette: ColorPalettes::monochrome(DEFAULT_PALETTE)
+                         .lookup_table(),
+            key_bindings: DEFAULT_KEY_BINDINGS,
+            tilt_bindings: DEFAULT_TILT_BINDINGS,
+            tapped_keys,
+            viewer_windows: None,
+            pixel_size: scale,
+        })
+    }
+
+    /// Blend each frame half and half with the frame before
+    ///
+    /// This imitates the slow response of the DMG's LCD, on which
+    /// objects that games only draw in every other frame look
+    /// transparent instead of flickering.
+    pub fn set_ghosting(&mut self, ghosting: bool) {
+        self.screen.set_ghosting(ghosting);
+    }
+
+    /// Scale up a frame of the given size and show it
+    ///
+    /// The frame is scaled by whole pixels to fit the window, which
+    /// the user can resize.  minifb stretches it the rest of the way,
+    /// keeping its aspect ratio by adding black bars at the sides.
+    fn show(&mut self, width: usize, height: usize,
+            color: impl Fn(usize) -> u32) {
+        let pixel_size = fitting_pixel_size(self.window.get_size(),
+                                            (width, height),
+                                            self.pixel_size);
+        let pixels = self.screen.draw(width, height, pixel_size, color);
+        self.window
+            .update_with_buffer(pixels, width * pixel_size,
+                                height * pixel_size)
+            .unwrap();
+    }
+
+    /// Show a view in a window of the VRAM viewer
+    ///
+    /// Return false if the window has been closed.
+    fn show_view(window: &mut Window, view: &View, palette: &[u32; 12])
+            -> bool {
+        if !window.is_open() {
+            return false;
+        }
+        let buffer: Vec<u32> = view.pixels
+                                   .iter()
+                                   .map(|&pixel| match pixel {
+                                       MARKER => MARKER_COLOR,
+                                       _ => palette[pixel as usize],
+                                   })
+                                   .collect();
+        if let Err(e) = window.update_with_buffer(&buffer, view.width,
+                                                  view.height) {
+            log::warn!("Closing VRAM viewer window: {}", e);
+            return false;
         }
+        true
+    }
+
+    /// Set the RGB colors of the four shades from lightest to darkest
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = ColorPalettes::monochrome(palette).lookup_table();
+    }
+
+    /// Use separate colors for the BG and the two object palettes
+    pub fn set_color_palettes(&mut self, palettes: &ColorPalettes) {
+        self.palette = palettes.lookup_table();
+    }
+
+    /// Bind the JoyPad buttons to the keys with the given names
+    ///
+    /// Keys are named like the variants of `minifb::Key`.  Buttons with
+    /// unknown key names keep their previous binding.
+    pub fn set_key_bindings(&mut self, bindings: &GameBoyKeyBindings) {
+        for (binding, name) in self.key_bindings
+                                   .iter_mut()
+                                   .chain(self.tilt_bindings.iter_mut())
+                                   .zip(bindings.in_joypad_order()
+                                                .into_iter()
+                                                .chain(bindings.tilt_keys())) {
+            match key_from_name(name) {
+                Some(key) => *binding = key,
+                None => log::warn!("Ignoring unknown key name {:?}.", name),
+            }
+        }
+    }
+}
+
+/// Look up a key by the name of its `minifb::Key` variant.
+fn key_from_name(name: &str) -> Option<Key> {
+    use Key::*;
+    const KEYS: [Key; 89] = [
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        A, B, C, D, E, F, G, H, I, J, K, L, M,
+        N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        Down, Left, Right, Up,
+        Apostrophe, Backquote, Backslash, Comma, Equal, LeftBracket, Minus,
+        Period, RightBracket, Semicolon, Slash,
+        Backspace, Delete, End, Enter, Home, Insert, PageDown, PageUp,
+        Space, Tab,
+        LeftShift, RightShift, LeftCtrl, RightCtrl, LeftAlt, RightAlt,
+        NumPad0, NumPad1, NumPad2, NumPad3, NumPad4,
+        NumPad5, NumPad6, NumPad7, NumPad8, NumPad9,
+    ];
+    KEYS.into_iter().find(|key| format!("{:?}", key) == name)
+}
+
+/// Largest integer scale at which a frame of `frame_size` fits into a
+/// window of `window_size`, but at least 1
+///
+/// A window without size keeps the scale `pixel_size`.
+fn fitting_pixel_size(window_size: (usize, usize), frame_size: (usize, usize),
+                      pixel_size: usize) -> usize {
+    let (window_width, window_height) = window_size;
+    if window_width == 0 || window_height == 0 {
+        // The window is minimized or hasn't been mapped yet.
+        return pixel_size;
+    }
+    let (width, height) = frame_size;
+    (window_width / width).min(window_height / height).max(1)
+}
+
+/// Average of two RGB colors
+fn blend(a: u32, b: u32) -> u32 {
+    // Halve each channel before adding, so that none carries over.
+    ((a >> 1) & 0x7F7F7F) + ((b >> 1) & 0x7F7F7F) + (a & b & 0x010101)
+}
+
+impl Default for EmulatorWindow {
+    fn default() -> Self {
+        Self::new(default_scale(WIDTH, HEIGHT)).unwrap()
     }
 }
 
 impl IO for EmulatorWindow {
     fn refresh(&mut self, pixels: &[u8]) {
-        let buffer_width = WIDTH * PIXEL_SIZE;
-        for line in 0..HEIGHT {
-            let buffer_line_start = line * PIXEL_SIZE * buffer_width;
-            let buffer_line_range
-                = buffer_line_start..(buffer_line_start + buffer_width);
-            let buffer_line = &mut self.display_buffer[buffer_line_range
-                                                       .clone()];
-            for col in 0..WIDTH {
-                let color = COLORS[pixels[line * WIDTH + col] as usize];
-                buffer_line[col*PIXEL_SIZE..(col+1)*PIXEL_SIZE].fill(color);
-            }
-            for i in 1..PIXEL_SIZE {
-                self.display_buffer.copy_within(
-                    buffer_line_range.clone(),
-                    buffer_line_start + i * buffer_width);
+        let palette = self.palette;
+        self.show(WIDTH, HEIGHT, |i| palette[pixels[i] as usize]);
+    }
+
+    fn refresh_sgb(&mut self, frame: &[u32]) -> bool {
+        self.show(SGB_WIDTH, SGB_HEIGHT, |i| frame[i]);
+        true
+    }
+
+    /// Show the views in three windows next to the emulator window
+    fn show_vram(&mut self, views: &VramViews) {
+        let windows = self.viewer_windows.get_or_insert_with(|| {
+            [&views.tiles, &views.bg_maps, &views.objects]
+                .into_iter()
+                .zip(VIEWER_WINDOWS)
+                .map(|(view, (title, scale))| {
+                    let options = WindowOptions{scale, ..Default::default()};
+                    match Window::new(title, view.width, view.height,
+                                      options) {
+                        Ok(mut window) => {
+                            window.limit_update_rate(None);
+                            Some(window)
+                        }
+                        Err(e) => {
+                            log::warn!("Cannot open {} window: {}", title, e);
+                            None
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap()
+        });
+        for (window, view) in windows.iter_mut()
+                                     .zip([&views.tiles, &views.bg_maps,
+                                           &views.objects]) {
+            if let Some(open_window) = window {
+                if !Self::show_view(open_window, view, &self.palette) {
+                    *window = None;
+                }
             }
         }
-        self.window
-            .update_with_buffer(&self.display_buffer,
-                                WIDTH * PIXEL_SIZE,
-                                HEIGHT * PIXEL_SIZE)
-            .unwrap();
     }
 
     fn is_esc_pressed(&self) -> bool {
@@ -63,8 +368,8 @@ impl IO for EmulatorWindow {
 
     /// Get pressed JoyPad keys
     ///
-    /// Arrows are mapped to arrow keys, B to Z, A to X, SELECT to Q and
-    /// START to W.
+    /// By default, arrows are mapped to arrow keys, B to Z, A to X,
+    /// SELECT to Q and START to W.
     ///
     /// Return a bitmap with 1 bit per button, which is 1 if pressed
     /// and 0 if unpressed.
@@ -79,35 +384,122 @@ impl IO for EmulatorWindow {
     /// 5    B
     /// 6    Select
     /// 7    Start
+    ///
+    /// Buttons whose keys have been tapped since the last call count as
+    /// pressed, even if the window only saw them released again.
     fn get_key_presses(&self) -> u8 {
+        let tapped = self.tapped_keys.take();
         let mut presses = 0x00;
-        if self.window.is_key_down(Key::Right) {
-            presses |= 0x01;
-        }
-        if self.window.is_key_down(Key::Left) {
-            presses |= 0x02;
-        }
-        if self.window.is_key_down(Key::Up) {
-            presses |= 0x04;
-        }
-        if self.window.is_key_down(Key::Down) {
-            presses |= 0x08;
-        }
-        if self.window.is_key_down(Key::X) { // A
-            presses |= 0x10;
-        }
-        if self.window.is_key_down(Key::Z) { // B
-            presses |= 0x20;
-        }
-        if self.window.is_key_down(Key::Q) { // Select
-            presses |= 0x40;
-        }
-        if self.window.is_key_down(Key::W) { // Start
-            presses |= 0x80;
+        for (i, key) in self.key_bindings.iter().enumerate() {
+            if self.window.is_key_down(*key) || tapped.contains(key) {
+                presses |= 1 << i;
+            }
         }
         if presses != 0 {
-            eprintln!("Keypresses: {:0>2X}", presses);
+            log::debug!("Keypresses: {:0>2X}", presses);
         }
         presses
     }
+
+    /// F5 saves the state and F8 loads it, the number keys select the
+    /// slot.  Holding Backspace rewinds.  F12 dumps the VRAM views.
+    /// P pauses and N advances by a frame.  F9 starts and stops
+    /// recording a clip.
+    fn get_hotkeys(&self) -> Vec<Hotkey> {
+        use Key::*;
+        let rewind = self.window.is_key_down(Backspace)
+                                .then_some(Hotkey::Rewind);
+        self.window.get_keys_pressed(KeyRepeat::No)
+                   .into_iter()
+                   .filter_map(|key| match key {
+                       F5 => Some(Hotkey::SaveState),
+                       F8 => Some(Hotkey::LoadState),
+                       F9 => Some(Hotkey::RecordClip),
+                       F12 => Some(Hotkey::DumpVram),
+                       P => Some(Hotkey::Pause),
+                       N => Some(Hotkey::FrameAdvance),
+                       Key0 | Key1 | Key2 | Key3 | Key4 | Key5 | Key6
+                       | Key7 | Key8 | Key9 => {
+                           Some(Hotkey::SelectSlot(key as u8 - Key0 as u8))
+                       }
+                       _ => None,
+                   })
+                   .chain(rewind)
+                   .collect()
+    }
+
+    /// Tilt fully in the direction of the pressed tilt keys
+    fn get_tilt(&self) -> (f32, f32) {
+        let [right, left, up, down] = self.tilt_bindings
+                                          .map(|key| self.window.is_key_down(key)
+                                                     as i8 as f32);
+        (right - left, down - up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resized_window_is_filled_by_whole_pixels() {
+        let screen = (WIDTH, HEIGHT);
+        assert_eq!(fitting_pixel_size((WIDTH * 3, HEIGHT * 3), screen, 2), 3);
+        // The smaller dimension decides, the rest are black bars.
+        assert_eq!(fitting_pixel_size((1920, 1080), screen, 2), 7);
+        assert_eq!(fitting_pixel_size((WIDTH * 4, HEIGHT * 2), screen, 3),
+                   2);
+        assert_eq!(fitting_pixel_size((WIDTH * 4 - 1, HEIGHT * 4), screen,
+                                      1),
+                   3);
+        // Windows smaller than the screen are stretched by minifb.
+        assert_eq!(fitting_pixel_size((100, 100), screen, 2), 1);
+        assert_eq!(fitting_pixel_size((0, 0), screen, 2), 2);
+        assert_eq!(fitting_pixel_size((SGB_WIDTH * 2, SGB_HEIGHT * 2),
+                                      (SGB_WIDTH, SGB_HEIGHT), 1),
+                   2);
+    }
+
+    #[test]
+    fn frame_is_scaled_up_by_whole_pixels() {
+        let mut screen = ScreenBuffer::new();
+        let pixels = screen.draw(2, 2, 3, |i| i as u32);
+        assert_eq!(pixels, [
+            0, 0, 0, 1, 1, 1,
+            0, 0, 0, 1, 1, 1,
+            0, 0, 0, 1, 1, 1,
+            2, 2, 2, 3, 3, 3,
+            2, 2, 2, 3, 3, 3,
+            2, 2, 2, 3, 3, 3,
+        ]);
+    }
+
+    #[test]
+    fn ghosting_blends_each_frame_with_the_one_before() {
+        let mut screen = ScreenBuffer::new();
+        screen.set_ghosting(true);
+        // The first frame has nothing to blend with.
+        assert_eq!(screen.draw(2, 1, 1, |_| 0xFFFFFF), [0xFFFFFF; 2]);
+        assert_eq!(screen.draw(2, 1, 1, |i| [0x000000, 0xFFFFFF][i]),
+                   [0x7F7F7F, 0xFFFFFF]);
+        // Blending uses the unblended colors of the frame before.
+        assert_eq!(screen.draw(2, 1, 1, |_| 0x000000), [0x000000, 0x7F7F7F]);
+        // A frame of another size, e.g. with SGB border, starts over.
+        assert_eq!(screen.draw(1, 1, 1, |_| 0x204060), [0x204060]);
+
+        screen.set_ghosting(false);
+        screen.draw(1, 1, 1, |_| 0xFFFFFF);
+        assert_eq!(screen.draw(1, 1, 1, |_| 0x000000), [0x000000]);
+        // Switching ghosting on again forgets the frames before.
+        screen.set_ghosting(true);
+        assert_eq!(screen.draw(1, 1, 1, |_| 0x102030), [0x102030]);
+    }
+
+    #[test]
+    fn blending_averages_each_channel() {
+        assert_eq!(blend(0xFF0000, 0x00FF00), 0x7F7F00);
+        assert_eq!(blend(0x0A1B2C, 0x0A1B2C), 0x0A1B2C);
+        assert_eq!(blend(0x010101, 0x030303), 0x020202);
+        assert_eq!(blend(0xFFFFFF, 0xFFFFFF), 0xFFFFFF);
+    }
 }
@@ -0,0 +1,214 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/// Carry and half carry of an addition or subtraction
+///
+/// For subtractions, both flags signal a borrow instead of a carry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Carries {
+    /// Carry from bit 3 into bit 4, or from bit 11 into bit 12 for
+    /// 16 bit additions
+    pub half_carry: bool,
+    /// Carry out of the most significant bit
+    pub carry: bool,
+}
+
+/// Add two bytes and an incoming carry, as done by ADD and ADC.
+pub fn add8(a: u8, b: u8, carry: bool) -> (u8, Carries) {
+    let sum = a as u16 + b as u16 + carry as u16;
+    let half_sum = (a & 0xF) + (b & 0xF) + carry as u8;
+    (sum as u8, Carries{
+        half_carry: half_sum > 0xF,
+        carry: sum > 0xFF,
+    })
+}
+
+/// Subtract a byte and an incoming borrow, as done by SUB, SBC and CP.
+pub fn sub8(a: u8, b: u8, borrow: bool) -> (u8, Carries) {
+    let difference = a as i16 - b as i16 - borrow as i16;
+    let half_difference = (a & 0xF) as i8 - (b & 0xF) as i8 - borrow as i8;
+    (difference as u8, Carries{
+        half_carry: half_difference < 0,
+        carry: difference < 0,
+    })
+}
+
+/// Add two words, as done by ADD HL,rr.
+pub fn add16(a: u16, b: u16) -> (u16, Carries) {
+    let (sum, carry) = a.overflowing_add(b);
+    (sum, Carries{
+        half_carry: (a & 0xFFF) + (b & 0xFFF) > 0xFFF,
+        carry,
+    })
+}
+
+/// Add a signed byte to a word, as done by ADD SP,e8 and LD HL,SP+e8.
+///
+/// The flags are those of an unsigned addition of the offset to the
+/// low byte of the word.
+pub fn add_signed_offset(value: u16, offset: u8) -> (u16, Carries) {
+    let (_, carries) = add8(value as u8, offset, false);
+    (value.wrapping_add(offset as i8 as u16), carries)
+}
+
+/// Adjust the result of a BCD addition or subtraction, as done by DAA.
+///
+/// The flags are those left by the preceding addition or subtraction.
+/// Return the adjusted value and the new carry flag.
+pub fn decimal_adjust(a: u8, subtract: bool, half_carry: bool, carry: bool)
+        -> (u8, bool) {
+    let mut adjustment = 0;
+    if half_carry || (!subtract && a & 0x0F > 0x09) {
+        adjustment |= 0x06;
+    }
+    if carry || (!subtract && a > 0x99) {
+        adjustment |= 0x60;
+    }
+    let adjusted = if subtract {
+        a.wrapping_sub(adjustment)
+    } else {
+        a.wrapping_add(adjustment)
+    };
+    (adjusted, adjustment & 0x60 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Add bit by bit and return the sum and the carry out of each bit.
+    fn ripple_add(a: u16, b: u16, carry: bool, bits: usize)
+            -> (u16, Vec<bool>) {
+        let mut sum = 0;
+        let mut carry = carry;
+        let mut carries = Vec::with_capacity(bits);
+        for bit in 0..bits {
+            let x = (a >> bit) & 1 != 0;
+            let y = (b >> bit) & 1 != 0;
+            if x ^ y ^ carry {
+                sum |= 1 << bit;
+            }
+            carry = (x && y) || (carry && (x ^ y));
+            carries.push(carry);
+        }
+        (sum, carries)
+    }
+
+    fn to_bcd(value: u8) -> u8 {
+        (value / 10) << 4 | (value % 10)
+    }
+
+    #[test]
+    fn add8_matches_ripple_carry_adder() {
+        for a in 0..=0xFF {
+            for b in 0..=0xFF {
+                for carry in [false, true] {
+                    let (sum, carries) = ripple_add(a, b, carry, 8);
+                    assert_eq!(add8(a as u8, b as u8, carry),
+                               (sum as u8, Carries{
+                                   half_carry: carries[3],
+                                   carry: carries[7],
+                               }),
+                               "{:0>2X} + {:0>2X} + {}", a, b, carry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sub8_matches_ripple_carry_adder() {
+        // a - b - borrow = a + !b + !borrow, where a borrow is the
+        // absence of a carry.
+        for a in 0..=0xFF {
+            for b in 0..=0xFF {
+                for borrow in [false, true] {
+                    let (difference, carries)
+                        = ripple_add(a, !b & 0xFF, !borrow, 8);
+                    assert_eq!(sub8(a as u8, b as u8, borrow),
+                               (difference as u8, Carries{
+                                   half_carry: !carries[3],
+                                   carry: !carries[7],
+                               }),
+                               "{:0>2X} - {:0>2X} - {}", a, b, borrow);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn add16_matches_ripple_carry_adder() {
+        let values = (0..=0xFFFF).step_by(0x0FF)
+                                 .chain([0x0FFF, 0x1000, 0x7FFF, 0x8000,
+                                         0xF000, 0xFFFF]);
+        for a in values.clone() {
+            for b in values.clone() {
+                let (sum, carries) = ripple_add(a, b, false, 16);
+                assert_eq!(add16(a, b),
+                           (sum, Carries{
+                               half_carry: carries[11],
+                               carry: carries[15],
+                           }),
+                           "{:0>4X} + {:0>4X}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn add_signed_offset_uses_flags_of_low_byte() {
+        for high in [0x00, 0x7F, 0xFF] {
+            for low in 0..=0xFF {
+                for offset in 0..=0xFF {
+                    let value = high << 8 | low;
+                    let (_, carries) = ripple_add(low, offset, false, 8);
+                    let expected = (value as i32 + offset as i8 as i32) as u16;
+                    assert_eq!(add_signed_offset(value, offset as u8),
+                               (expected, Carries{
+                                   half_carry: carries[3],
+                                   carry: carries[7],
+                               }),
+                               "{:0>4X} + {:0>2X}", value, offset);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_adjust_after_bcd_addition() {
+        for x in 0..100 {
+            for y in 0..100 {
+                for carry in [false, true] {
+                    let (sum, carries) = add8(to_bcd(x), to_bcd(y), carry);
+                    let (adjusted, carry_out)
+                        = decimal_adjust(sum, false, carries.half_carry,
+                                         carries.carry);
+                    let expected = x as u16 + y as u16 + carry as u16;
+                    assert_eq!((adjusted, carry_out),
+                               (to_bcd((expected % 100) as u8),
+                                expected >= 100),
+                               "{} + {} + {}", x, y, carry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_adjust_after_bcd_subtraction() {
+        for x in 0..100 {
+            for y in 0..100 {
+                for borrow in [false, true] {
+                    let (difference, carries)
+                        = sub8(to_bcd(x), to_bcd(y), borrow);
+                    let (adjusted, borrow_out)
+                        = decimal_adjust(difference, true, carries.half_carry,
+                                         carries.carry);
+                    let expected = x as i16 - y as i16 - borrow as i16;
+                    assert_eq!((adjusted, borrow_out),
+                               (to_bcd(expected.rem_euclid(100) as u8),
+                                expected < 0),
+                               "{} - {} - {}", x, y, borrow);
+                }
+            }
+        }
+    }
+}
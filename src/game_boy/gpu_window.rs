@@ -0,0 +1,700 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use pixels::wgpu;
+use pixels::{Pixels, PixelsBuilder, PixelsContext, SurfaceTexture};
+use winit::dpi::PhysicalSize;
+use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
+use winit::event_loop::EventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
+use winit::window::{Window, WindowBuilder};
+
+use super::colorization::ColorPalettes;
+use super::emulator_window::ScreenBuffer;
+use super::io::{Hotkey, IO, HEIGHT, SGB_HEIGHT, SGB_WIDTH, WIDTH};
+use crate::settings::GameBoyKeyBindings;
+
+pub const AVAILABLE_SHADERS: [&str; 4] = ["none", "scanlines", "lcd-grid",
+                                          "crt"];
+
+/// Declarations that the screen shaders are compiled with
+const SHADER_PRELUDE: &str = include_str!("shaders/prelude.wgsl");
+
+const BUILT_IN_SHADERS: [(&str, &str); 4] = [
+    ("none", include_str!("shaders/none.wgsl")),
+    ("scanlines", include_str!("shaders/scanlines.wgsl")),
+    ("lcd-grid", include_str!("shaders/lcd_grid.wgsl")),
+    ("crt", include_str!("shaders/crt.wgsl")),
+];
+
+const DEFAULT_PALETTE: [u32; 4] = [0xFFFFFF, 0x808080, 0x404040, 0];
+
+/// A WGSL shader that draws the frame into the window
+///
+/// The shader defines a fragment shader `fs_main`, which gets the
+/// declarations of `shaders/prelude.wgsl` to sample the frame with.
+pub struct ScreenShader {
+    /// The shader together with the prelude
+    source: String,
+}
+
+impl ScreenShader {
+    /// Load one of the `AVAILABLE_SHADERS` or a WGSL file
+    pub fn load(name_or_path: &str) -> Result<Self, String> {
+        match BUILT_IN_SHADERS.iter().find(|(name, _)| *name == name_or_path) {
+            Some((_, shader)) => Self::from_wgsl(shader),
+            None => {
+                let path = Path::new(name_or_path);
+                let shader = std::fs::read_to_string(path).map_err(|e| {
+                    format!("Can't read {}: {}", path.display(), e)
+                })?;
+                Self::from_wgsl(&shader)
+            }
+        }
+    }
+
+    /// Check that a shader compiles and defines `fs_main`
+    ///
+    /// This happens before a window is opened, so that mistakes are
+    /// reported as readable errors instead of crashing the GPU driver.
+    pub fn from_wgsl(shader: &str) -> Result<Self, String> {
+        let source = format!("{}\n{}", SHADER_PRELUDE, shader);
+        let module = naga::front::wgsl::parse_str(&source)
+                         .map_err(|e| e.emit_to_string(&source))?;
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(),
+                                    naga::valid::Capabilities::empty())
+            .validate(&module)
+            .map_err(|e| e.emit_to_string(&source))?;
+        let has_fs_main = module.entry_points.iter().any(|entry_point| {
+            entry_point.name == "fs_main"
+            && entry_point.stage == naga::ShaderStage::Fragment
+        });
+        if !has_fs_main {
+            return Err("The shader doesn't define a @fragment function \
+                        fs_main.".to_string());
+        }
+        Ok(Self{source})
+    }
+}
+
+/// Render pipeline that runs a `ScreenShader` on the frame texture
+struct ScreenRenderer {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// Sizes of the frame and of the viewport, see `Screen` in the
+    /// prelude
+    uniforms: wgpu::Buffer,
+}
+
+impl ScreenRenderer {
+    fn new(pixels: &Pixels, shader: &ScreenShader) -> Self {
+        let device = pixels.device();
+        let module = device.create_shader_module(
+            wgpu::ShaderModuleDescriptor{
+                label: Some("screen shader"),
+                source: wgpu::ShaderSource::Wgsl(shader.source.as_str()
+                                                              .into()),
+            });
+        let bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor{
+                label: Some("screen bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry{
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture{
+                            sample_type: wgpu::TextureSampleType::Float{
+                                filterable: true,
+                            },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry{
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(
+                                wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry{
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                        ty: wgpu::BindingType::Buffer{
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor{
+                label: Some("screen pipeline layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+        let pipeline = device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor{
+                label: Some("screen pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState{
+                    module: &module,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState{
+                    module: &module,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState{
+                        format: pixels.render_texture_format(),
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor{
+            label: Some("screen sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..wgpu::SamplerDescriptor::default()
+        });
+        let uniforms = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("screen uniforms"),
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self{pipeline, bind_group_layout, sampler, uniforms}
+    }
+
+    /// Draw the frame texture into the `viewport` of the window
+    fn render(&self, encoder: &mut wgpu::CommandEncoder,
+              target: &wgpu::TextureView, context: &PixelsContext,
+              viewport: Viewport) {
+        let frame_size = context.texture_extent;
+        let sizes = [frame_size.width as f32, frame_size.height as f32,
+                     viewport.width, viewport.height];
+        let bytes: Vec<u8> = sizes.iter()
+                                  .flat_map(|size| size.to_ne_bytes())
+                                  .collect();
+        context.queue.write_buffer(&self.uniforms, 0, &bytes);
+        // The texture is replaced when the frame size changes, so the
+        // bind group is created anew for every frame.
+        let texture_view = context.texture.create_view(
+            &wgpu::TextureViewDescriptor::default());
+        let bind_group = context.device.create_bind_group(
+            &wgpu::BindGroupDescriptor{
+                label: Some("screen bind group"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry{
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(
+                                      &texture_view),
+                    },
+                    wgpu::BindGroupEntry{
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(
+                                      &self.sampler),
+                    },
+                    wgpu::BindGroupEntry{
+                        binding: 2,
+                        resource: self.uniforms.as_entire_binding(),
+                    },
+                ],
+            });
+        let mut pass = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor{
+                label: Some("screen pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment{
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations{
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.set_viewport(viewport.x, viewport.y, viewport.width,
+                          viewport.height, 0.0, 1.0);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+/// Part of the window that shows the frame, in window pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Viewport {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Viewport {
+    /// Center the frame in the window, scaled by whole pixels as far as
+    /// it fits
+    ///
+    /// Frames larger than the window are shrunk to fit, keeping their
+    /// aspect ratio.
+    fn fit(window_size: (u32, u32), frame_size: (usize, usize)) -> Self {
+        let (window_width, window_height) = (window_size.0 as f32,
+                                             window_size.1 as f32);
+        let (width, height) = (frame_size.0 as f32, frame_size.1 as f32);
+        let scale = (window_width / width).min(window_height / height);
+        let scale = if scale >= 1.0 { scale.floor() } else { scale };
+        let (width, height) = (width * scale, height * scale);
+        Self{
+            x: ((window_width - width) / 2.0).floor(),
+            y: ((window_height - height) / 2.0).floor(),
+            width,
+            height,
+        }
+    }
+}
+
+/// Keys of the window's keyboard, as reported by its events
+#[derive(Default)]
+struct Keys {
+    held: HashSet<KeyCode>,
+    /// Keys pressed since the last call of `buttons`, so that short
+    /// taps are seen even if the key is already released again
+    tapped: Vec<KeyCode>,
+    /// Keys pressed since the last call of `hotkeys`, without repeats
+    pressed: Vec<KeyCode>,
+}
+
+impl Keys {
+    fn handle_key(&mut self, code: KeyCode, state: ElementState,
+                  repeat: bool) {
+        match state {
+            ElementState::Pressed => {
+                self.held.insert(code);
+                self.tapped.push(code);
+                if !repeat {
+                    self.pressed.push(code);
+                }
+            }
+            ElementState::Released => {
+                self.held.remove(&code);
+            }
+        }
+    }
+
+    fn is_down(&self, key: KeyCode) -> bool {
+        self.held.contains(&key)
+    }
+
+    /// Bitmap of the buttons whose keys are held or have been tapped
+    fn buttons(&mut self, bindings: &[KeyCode; 8]) -> u8 {
+        let tapped = std::mem::take(&mut self.tapped);
+        let mut presses = 0;
+        for (i, key) in bindings.iter().enumerate() {
+            if self.is_down(*key) || tapped.contains(key) {
+                presses |= 1 << i;
+            }
+        }
+        presses
+    }
+
+    /// The same hotkeys as in the `EmulatorWindow`
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        use KeyCode::*;
+        let rewind = self.is_down(Backspace).then_some(Hotkey::Rewind);
+        const DIGITS: [KeyCode; 10] = [Digit0, Digit1, Digit2, Digit3,
+                                       Digit4, Digit5, Digit6, Digit7,
+                                       Digit8, Digit9];
+        std::mem::take(&mut self.pressed)
+            .into_iter()
+            .filter_map(|key| match key {
+                F5 => Some(Hotkey::SaveState),
+                F8 => Some(Hotkey::LoadState),
+                F9 => Some(Hotkey::RecordClip),
+                F12 => Some(Hotkey::DumpVram),
+                KeyP => Some(Hotkey::Pause),
+                KeyN => Some(Hotkey::FrameAdvance),
+                _ => DIGITS.iter()
+                           .position(|&digit| digit == key)
+                           .map(|slot| Hotkey::SelectSlot(slot as u8)),
+            })
+            .chain(rewind)
+            .collect()
+    }
+}
+
+/// Events of the window since they were last handled
+#[derive(Default)]
+struct WindowInput {
+    keys: Keys,
+    close_requested: bool,
+    /// New size of the window, which the surface has to be resized to
+    resized: Option<PhysicalSize<u32>>,
+}
+
+/// A window that draws the display with wgpu, through a `ScreenShader`
+///
+/// Like the `EmulatorWindow`, it is driven by the emulator, which
+/// handles the window's events when it asks for the pressed keys.
+pub struct GpuWindow {
+    // The surface of `pixels` has to be dropped before the window.
+    pixels: Pixels,
+    renderer: ScreenRenderer,
+    window: Window,
+    event_loop: RefCell<EventLoop<()>>,
+    input: RefCell<WindowInput>,
+    screen: ScreenBuffer,
+    /// Size of the frames in the texture of `pixels`
+    frame_size: (usize, usize),
+    /// Colors of the BG, OBP0 and OBP1 shades, indexed by pixel value
+    palette: [u32; 12],
+    /// Keys of the JoyPad buttons in the bit order of `get_key_presses`
+    key_bindings: [KeyCode; 8],
+    /// Keys that tilt right, left, up and down
+    tilt_bindings: [KeyCode; 4],
+}
+
+impl GpuWindow {
+    /// Open a window showing each pixel of the screen as `scale` x
+    /// `scale` pixels, drawn by the given shader
+    ///
+    /// This fails if no window can be created or no GPU adapter is
+    /// available.
+    pub fn new(scale: usize, shader: &ScreenShader)
+            -> Result<Self, Box<dyn Error>> {
+        Self::open(WIDTH, HEIGHT, scale, shader)
+    }
+
+    /// Open a window large enough for Super Game Boy frames
+    pub fn with_sgb_border(scale: usize, shader: &ScreenShader)
+            -> Result<Self, Box<dyn Error>> {
+        Self::open(SGB_WIDTH, SGB_HEIGHT, scale, shader)
+    }
+
+    fn open(width: usize, height: usize, scale: usize, shader: &ScreenShader)
+            -> Result<Self, Box<dyn Error>> {
+        let event_loop = EventLoop::new()?;
+        let window = WindowBuilder::new()
+            .with_title("Game Boy emulator")
+            .with_inner_size(PhysicalSize::new((width * scale) as u32,
+                                               (height * scale) as u32))
+            .build(&event_loop)?;
+        let size = window.inner_size();
+        let surface = SurfaceTexture::new(size.width, size.height, &window);
+        let pixels = PixelsBuilder::new(width as u32, height as u32, surface)
+                                   .build()?;
+        let renderer = ScreenRenderer::new(&pixels, shader);
+        Ok(Self{
+            pixels,
+            renderer,
+            window,
+            event_loop: RefCell::new(event_loop),
+            input: RefCell::new(WindowInput::default()),
+            screen: ScreenBuffer::new(),
+            frame_size: (width, height),
+            palette: ColorPalettes::monochrome(DEFAULT_PALETTE)
+                         .lookup_table(),
+            key_bindings: [KeyCode::ArrowRight, KeyCode::ArrowLeft,
+                           KeyCode::ArrowUp, KeyCode::ArrowDown,
+                           KeyCode::KeyX, KeyCode::KeyZ, KeyCode::KeyQ,
+                           KeyCode::KeyW],
+            tilt_bindings: [KeyCode::KeyL, KeyCode::KeyJ, KeyCode::KeyI,
+                            KeyCode::KeyK],
+        })
+    }
+
+    /// Blend each frame half and half with the frame before, see
+    /// `EmulatorWindow::set_ghosting`
+    pub fn set_ghosting(&mut self, ghosting: bool) {
+        self.screen.set_ghosting(ghosting);
+    }
+
+    /// Set the RGB colors of the four shades from lightest to darkest
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = ColorPalettes::monochrome(palette).lookup_table();
+    }
+
+    /// Use separate colors for the BG and the two object palettes
+    pub fn set_color_palettes(&mut self, palettes: &ColorPalettes) {
+        self.palette = palettes.lookup_table();
+    }
+
+    /// Bind the JoyPad buttons to the keys with the given names
+    ///
+    /// Keys are named like the variants of `minifb::Key`, as in the
+    /// `EmulatorWindow`.  Buttons with unknown key names keep their
+    /// previous binding.
+    pub fn set_key_bindings(&mut self, bindings: &GameBoyKeyBindings) {
+        for (binding, name) in self.key_bindings
+                                   .iter_mut()
+                                   .chain(self.tilt_bindings.iter_mut())
+                                   .zip(bindings.in_joypad_order()
+                                                .into_iter()
+                                                .chain(bindings.tilt_keys())) {
+            match key_from_name(name) {
+                Some(key) => *binding = key,
+                None => log::warn!("Ignoring unknown key name {:?}.", name),
+            }
+        }
+    }
+
+    /// Handle the events that the window has received since the last
+    /// call
+    fn poll_events(&self) {
+        let mut input = self.input.borrow_mut();
+        let status = self.event_loop.borrow_mut().pump_events(
+            Some(Duration::ZERO),
+            |event, _| {
+                let Event::WindowEvent{event, ..} = event else {
+                    return;
+                };
+                match event {
+                    WindowEvent::CloseRequested => {
+                        input.close_requested = true;
+                    }
+                    WindowEvent::Resized(size) => input.resized = Some(size),
+                    WindowEvent::KeyboardInput{
+                        event: KeyEvent{
+                            physical_key: PhysicalKey::Code(code),
+                            state,
+                            repeat,
+                            ..
+                        },
+                        ..
+                    } => input.keys.handle_key(code, state, repeat),
+                    _ => {}
+                }
+            });
+        if let PumpStatus::Exit(_) = status {
+            input.close_requested = true;
+        }
+    }
+
+    /// Upload a frame of the given size and draw it with the shader
+    fn show(&mut self, width: usize, height: usize,
+            color: impl Fn(usize) -> u32) {
+        if let Some(size) = self.input.get_mut().resized.take() {
+            if size.width > 0 && size.height > 0 {
+                if let Err(e) = self.pixels.resize_surface(size.width,
+                                                           size.height) {
+                    log::warn!("Can't resize the window surface: {}", e);
+                }
+            }
+        }
+        if (width, height) != self.frame_size {
+            if let Err(e) = self.pixels.resize_buffer(width as u32,
+                                                      height as u32) {
+                log::warn!("Can't resize the frame texture: {}", e);
+                return;
+            }
+            self.frame_size = (width, height);
+        }
+        let colors = self.screen.draw(width, height, 1, color);
+        for (rgba, color) in self.pixels.frame_mut()
+                                        .chunks_exact_mut(4)
+                                        .zip(colors) {
+            let [_, r, g, b] = color.to_be_bytes();
+            rgba.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+        let size = self.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            // The window is minimized.
+            return;
+        }
+        let viewport = Viewport::fit((size.width, size.height),
+                                     (width, height));
+        let renderer = &self.renderer;
+        let result = self.pixels.render_with(|encoder, target, context| {
+            renderer.render(encoder, target, context, viewport);
+            Ok(())
+        });
+        if let Err(e) = result {
+            log::warn!("Can't draw the frame: {}", e);
+        }
+    }
+}
+
+/// Look up a key by the name of its `minifb::Key` variant
+fn key_from_name(name: &str) -> Option<KeyCode> {
+    use KeyCode::*;
+    const KEYS: [(&str, KeyCode); 89] = [
+        ("Key0", Digit0), ("Key1", Digit1), ("Key2", Digit2),
+        ("Key3", Digit3), ("Key4", Digit4), ("Key5", Digit5),
+        ("Key6", Digit6), ("Key7", Digit7), ("Key8", Digit8),
+        ("Key9", Digit9),
+        ("A", KeyA), ("B", KeyB), ("C", KeyC), ("D", KeyD), ("E", KeyE),
+        ("F", KeyF), ("G", KeyG), ("H", KeyH), ("I", KeyI), ("J", KeyJ),
+        ("K", KeyK), ("L", KeyL), ("M", KeyM), ("N", KeyN), ("O", KeyO),
+        ("P", KeyP), ("Q", KeyQ), ("R", KeyR), ("S", KeyS), ("T", KeyT),
+        ("U", KeyU), ("V", KeyV), ("W", KeyW), ("X", KeyX), ("Y", KeyY),
+        ("Z", KeyZ),
+        ("F1", F1), ("F2", F2), ("F3", F3), ("F4", F4), ("F5", F5),
+        ("F6", F6), ("F7", F7), ("F8", F8), ("F9", F9), ("F10", F10),
+        ("F11", F11), ("F12", F12),
+        ("Down", ArrowDown), ("Left", ArrowLeft), ("Right", ArrowRight),
+        ("Up", ArrowUp),
+        ("Apostrophe", Quote), ("Backquote", Backquote),
+        ("Backslash", Backslash), ("Comma", Comma), ("Equal", Equal),
+        ("LeftBracket", BracketLeft), ("Minus", Minus), ("Period", Period),
+        ("RightBracket", BracketRight), ("Semicolon", Semicolon),
+        ("Slash", Slash),
+        ("Backspace", Backspace), ("Delete", Delete), ("End", End),
+        ("Enter", Enter), ("Home", Home), ("Insert", Insert),
+        ("PageDown", PageDown), ("PageUp", PageUp), ("Space", Space),
+        ("Tab", Tab),
+        ("LeftShift", ShiftLeft), ("RightShift", ShiftRight),
+        ("LeftCtrl", ControlLeft), ("RightCtrl", ControlRight),
+        ("LeftAlt", AltLeft), ("RightAlt", AltRight),
+        ("NumPad0", Numpad0), ("NumPad1", Numpad1), ("NumPad2", Numpad2),
+        ("NumPad3", Numpad3), ("NumPad4", Numpad4), ("NumPad5", Numpad5),
+        ("NumPad6", Numpad6), ("NumPad7", Numpad7), ("NumPad8", Numpad8),
+        ("NumPad9", Numpad9),
+    ];
+    KEYS.into_iter().find(|(key_name, _)| *key_name == name)
+                    .map(|(_, key)| key)
+}
+
+impl IO for GpuWindow {
+    fn refresh(&mut self, pixels: &[u8]) {
+        let palette = self.palette;
+        self.show(WIDTH, HEIGHT, |i| palette[pixels[i] as usize]);
+    }
+
+    fn refresh_sgb(&mut self, frame: &[u32]) -> bool {
+        self.show(SGB_WIDTH, SGB_HEIGHT, |i| frame[i]);
+        true
+    }
+
+    /// Whether Escape is held or the window has been closed
+    fn is_esc_pressed(&self) -> bool {
+        self.poll_events();
+        let input = self.input.borrow();
+        input.close_requested || input.keys.is_down(KeyCode::Escape)
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        self.input.borrow_mut().keys.buttons(&self.key_bindings)
+    }
+
+    fn get_hotkeys(&self) -> Vec<Hotkey> {
+        self.input.borrow_mut().keys.hotkeys()
+    }
+
+    /// Tilt fully in the direction of the pressed tilt keys
+    fn get_tilt(&self) -> (f32, f32) {
+        let input = self.input.borrow();
+        let [right, left, up, down] = self.tilt_bindings
+                                          .map(|key| input.keys.is_down(key)
+                                                     as i8 as f32);
+        (right - left, down - up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_shaders_compile() {
+        for name in AVAILABLE_SHADERS {
+            if let Err(e) = ScreenShader::load(name) {
+                panic!("Shader {} doesn't compile:\n{}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn shader_files_are_checked_before_use() {
+        let path = std::env::temp_dir().join("emulato-rs-shader-test.wgsl");
+        std::fs::write(&path, "\
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let inverted = textureSample(screen_texture, screen_sampler,
+                                 in.tex_coord);
+    return vec4<f32>(1.0 - inverted.rgb, 1.0);
+}
+").unwrap();
+        let shader = ScreenShader::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(shader.is_ok(), "{}", shader.err().unwrap());
+
+        let error = ScreenShader::from_wgsl("fn fs_main() -> f32 { 1 }")
+                                    .err().unwrap();
+        assert!(error.contains("error"), "{}", error);
+        let error = ScreenShader::from_wgsl("\
+@fragment
+fn main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}
+").err().unwrap();
+        assert!(error.contains("fs_main"), "{}", error);
+        assert!(ScreenShader::load("no-such-shader.wgsl").is_err());
+    }
+
+    #[test]
+    fn frame_is_centered_at_whole_pixel_scales() {
+        let screen = (WIDTH, HEIGHT);
+        assert_eq!(Viewport::fit((WIDTH as u32 * 3, HEIGHT as u32 * 3),
+                                 screen),
+                   Viewport{x: 0.0, y: 0.0, width: 480.0, height: 432.0});
+        assert_eq!(Viewport::fit((1920, 1080), screen),
+                   Viewport{x: 400.0, y: 36.0, width: 1120.0,
+                            height: 1008.0});
+        // Windows smaller than the frame shrink it.
+        assert_eq!(Viewport::fit((80, 144), screen),
+                   Viewport{x: 0.0, y: 36.0, width: 80.0, height: 72.0});
+    }
+
+    #[test]
+    fn taps_and_held_keys_press_buttons() {
+        use ElementState::*;
+        let bindings = [KeyCode::ArrowRight, KeyCode::ArrowLeft,
+                        KeyCode::ArrowUp, KeyCode::ArrowDown,
+                        key_from_name("X").unwrap(), KeyCode::KeyZ,
+                        KeyCode::KeyQ, KeyCode::KeyW];
+        let mut keys = Keys::default();
+        keys.handle_key(KeyCode::KeyX, Pressed, false);
+        keys.handle_key(KeyCode::ArrowUp, Pressed, false);
+        keys.handle_key(KeyCode::ArrowUp, Released, false);
+        assert_eq!(keys.buttons(&bindings), 0x14);
+        assert_eq!(keys.buttons(&bindings), 0x10);
+        keys.handle_key(KeyCode::KeyX, Released, false);
+        assert_eq!(keys.buttons(&bindings), 0);
+    }
+
+    #[test]
+    fn hotkeys_ignore_key_repeats() {
+        use ElementState::*;
+        let mut keys = Keys::default();
+        keys.handle_key(KeyCode::F5, Pressed, false);
+        keys.handle_key(KeyCode::F5, Pressed, true);
+        keys.handle_key(KeyCode::Digit3, Pressed, false);
+        keys.handle_key(KeyCode::Backspace, Pressed, false);
+        assert_eq!(keys.hotkeys(), [Hotkey::SaveState, Hotkey::SelectSlot(3),
+                                    Hotkey::Rewind]);
+        // Rewinding goes on while Backspace is held.
+        assert_eq!(keys.hotkeys(), [Hotkey::Rewind]);
+        keys.handle_key(KeyCode::Backspace, Released, false);
+        assert_eq!(keys.hotkeys(), []);
+        assert_eq!(key_from_name("Key7"), Some(KeyCode::Digit7));
+        assert_eq!(key_from_name("NumPad9"), Some(KeyCode::Numpad9));
+        assert_eq!(key_from_name("Escape"), None);
+    }
+}
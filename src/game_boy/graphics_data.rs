@@ -19,6 +19,12 @@ impl From<u8> for MonochromePalette {
     }
 }
 
+impl From<MonochromePalette> for u8 {
+    fn from(palette: MonochromePalette) -> Self {
+        palette.palette
+    }
+}
+
 impl MonochromePalette {
     pub fn color(self, index: u8) -> u8 {
         (self.palette >> (2*index)) & 3
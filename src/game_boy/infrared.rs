@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use super::link::Connection;
+
+/// Whatever the infrared LED of the Game Boy shines at
+///
+/// Both the IR port of the CGB and the one of HuC1 cartridges consist
+/// of an LED and a photodiode, so that a device only sees whether the
+/// LED is on and decides whether light is received.
+pub trait InfraredDevice: Send {
+    fn set_led(&mut self, on: bool);
+
+    /// Whether the photodiode receives light
+    fn is_receiving(&mut self) -> bool;
+}
+
+impl<T: InfraredDevice + ?Sized> InfraredDevice for Box<T> {
+    fn set_led(&mut self, on: bool) {
+        (**self).set_led(on)
+    }
+
+    fn is_receiving(&mut self) -> bool {
+        (**self).is_receiving()
+    }
+}
+
+/// Nothing in front of the IR port
+pub struct Darkness;
+
+impl InfraredDevice for Darkness {
+    fn set_led(&mut self, _on: bool) {}
+
+    fn is_receiving(&mut self) -> bool {
+        false
+    }
+}
+
+/// A mirror in front of the IR port, which receives its own LED
+#[derive(Default)]
+pub struct Loopback {
+    led: bool,
+}
+
+impl InfraredDevice for Loopback {
+    fn set_led(&mut self, on: bool) {
+        self.led = on;
+    }
+
+    fn is_receiving(&mut self) -> bool {
+        self.led
+    }
+}
+
+/// Message with the new state of the LED
+const LED: u8 = 0x03;
+
+/// IR port of another instance of the emulator facing this one
+///
+/// Each change of the LED is sent to the other emulator.  Signals that
+/// depend on the exact timing of pulses, as used by some games, can get
+/// distorted by the latency of the connection.
+pub struct InfraredSocket {
+    connection: Connection,
+    led: bool,
+    remote_led: bool,
+}
+
+impl InfraredSocket {
+    pub fn new(connection: Connection) -> Self {
+        Self{
+            connection,
+            led: false,
+            remote_led: false,
+        }
+    }
+
+    /// Connect to another emulator, see `Connection::open`
+    pub fn connect(address: &str) -> io::Result<Self> {
+        Ok(Self::new(Connection::open(address)?))
+    }
+}
+
+impl InfraredDevice for InfraredSocket {
+    fn set_led(&mut self, on: bool) {
+        if on != self.led {
+            self.led = on;
+            self.connection.send(LED, on as u8);
+        }
+    }
+
+    fn is_receiving(&mut self) -> bool {
+        while let Some((kind, value)) = self.connection.receive() {
+            if kind == LED {
+                self.remote_led = value != 0;
+            }
+        }
+        self.remote_led
+    }
+}
+
+/// IR communication port of the CGB, register RP (FF56)
+///
+/// Bit  RP – Infrared Communications Port
+/// ---  ---------------------------------
+/// 7-6  Data Read Enable (0=Disable, 3=Enable)
+/// 1    Read Data (0=Receiving IR Signal, 1=Normal) (Read Only)
+/// 0    Write Data (0=LED Off, 1=LED On)
+///
+/// https://gbdev.io/pandocs/CGB_Registers.html#ff56--rp-cgb-mode-only-infrared-communications-port
+#[derive(Serialize, Deserialize)]
+pub struct InfraredPort {
+    control: u8,
+    /// Reading RP asks the device whether it sends light
+    #[serde(skip, default = "default_device")]
+    device: RefCell<Box<dyn InfraredDevice>>,
+}
+
+fn default_device() -> RefCell<Box<dyn InfraredDevice>> {
+    RefCell::new(Box::new(Darkness))
+}
+
+impl Default for InfraredPort {
+    fn default() -> Self {
+        Self{
+            control: 0,
+            device: RefCell::new(Box::new(Darkness)),
+        }
+    }
+}
+
+impl InfraredPort {
+    /// Point the port at a device, replacing the previous one
+    pub fn connect(&mut self, device: Box<dyn InfraredDevice>) {
+        self.device = RefCell::new(device);
+    }
+
+    /// Take over the state of a port loaded from a save state, keeping
+    /// the connected device
+    pub fn restore_state(&mut self, mut saved: Self) {
+        std::mem::swap(&mut self.device, &mut saved.device);
+        *self = saved;
+    }
+
+    pub fn read(&self) -> u8 {
+        let read_enabled = self.control & 0xC0 == 0xC0;
+        let signal = if read_enabled
+                        && self.device.borrow_mut().is_receiving() {
+            0
+        } else {
+            2
+        };
+        // Unused bits are always 1.
+        0x3C | (self.control & 0xC1) | signal
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.control = value & 0xC1;
+        self.device.get_mut().set_led(value & 1 != 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_receives_own_led_when_reading_is_enabled() {
+        let mut port = InfraredPort::default();
+        port.connect(Box::new(Loopback::default()));
+        port.write(0x01);
+        assert_eq!(port.read(), 0x3F);
+        port.write(0xC1);
+        assert_eq!(port.read(), 0xFD);
+        port.write(0xC0);
+        assert_eq!(port.read(), 0xFE);
+    }
+}
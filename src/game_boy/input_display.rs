@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use super::io::{HEIGHT, WIDTH};
+
+/// Letters of the buttons in the bit order of `IO::get_key_presses`
+const BUTTON_LETTERS: [char; 8] = ['R', 'L', 'U', 'D', 'A', 'B', 's', 'S'];
+
+/// Cells of the buttons in the overlay in the bit order of
+/// `IO::get_key_presses`
+///
+/// The D-pad is drawn as a cross on the left, Select and Start in the
+/// middle and B and A on the right:
+///
+/// ```text
+///  U
+/// L R  B A
+///  D s S
+/// ```
+const BUTTON_CELLS: [(usize, usize); 8] = [
+    (2, 1),  // Right
+    (0, 1),  // Left
+    (1, 0),  // Up
+    (1, 2),  // Down
+    (6, 1),  // A
+    (5, 1),  // B
+    (3, 2),  // Select
+    (4, 2),  // Start
+];
+
+const CELL_SIZE: usize = 4;
+const BUTTON_SIZE: usize = 3;
+const OVERLAY_WIDTH: usize = 7 * CELL_SIZE + 1;
+const OVERLAY_HEIGHT: usize = 3 * CELL_SIZE + 1;
+/// Distance of the overlay from the bottom left corner of the screen
+const MARGIN: usize = 2;
+
+const BACKGROUND_SHADE: u8 = 0;
+const RELEASED_SHADE: u8 = 1;
+const PRESSED_SHADE: u8 = 3;
+
+/// Format pressed buttons as one letter per button or '.' if unpressed
+///
+/// The letters are in the bit order of `IO::get_key_presses`, i.e.
+/// `RLUDABsS`, where `s` is Select and `S` is Start.
+pub fn format_buttons(keys: u8) -> String {
+    BUTTON_LETTERS.iter()
+                  .enumerate()
+                  .map(|(i, &letter)| if keys & (1 << i) != 0 {
+                      letter
+                  } else {
+                      '.'
+                  })
+                  .collect()
+}
+
+/// Draw the pressed buttons into the bottom left corner of a frame
+///
+/// `pixels` is a frame as passed to `IO::refresh`.
+pub fn draw_overlay(pixels: &mut [u8], keys: u8) {
+    let top = HEIGHT - MARGIN - OVERLAY_HEIGHT;
+    for line in pixels.chunks_exact_mut(WIDTH)
+                      .skip(top)
+                      .take(OVERLAY_HEIGHT) {
+        line[MARGIN..MARGIN + OVERLAY_WIDTH].fill(BACKGROUND_SHADE);
+    }
+    for (i, (col, row)) in BUTTON_CELLS.iter().enumerate() {
+        let shade = if keys & (1 << i) != 0 {
+            PRESSED_SHADE
+        } else {
+            RELEASED_SHADE
+        };
+        let x = MARGIN + col * CELL_SIZE + 1;
+        let y = top + row * CELL_SIZE + 1;
+        for line in pixels.chunks_exact_mut(WIDTH)
+                          .skip(y)
+                          .take(BUTTON_SIZE) {
+            line[x..x + BUTTON_SIZE].fill(shade);
+        }
+    }
+}
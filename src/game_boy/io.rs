@@ -2,12 +2,108 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::ops::{BitOr, BitOrAssign};
+
+use super::viewer::VramViews;
+
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
+/// Size of a Super Game Boy frame including the border
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+/// Emulator functions that the user triggers in the frontend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    /// Save and load states in the given slot from now on
+    SelectSlot(u8),
+    /// Go back in time; reported in every frame while it is held
+    Rewind,
+    /// Write the tiles, BG maps and OAM as PNG files
+    DumpVram,
+    /// Stop or continue the emulation
+    Pause,
+    /// Emulate a single frame and pause
+    FrameAdvance,
+    /// Start recording a clip of the screen, or stop and write it
+    RecordClip,
+}
+
+/// A set of JoyPad buttons in the bit order of `IO::get_key_presses`
+///
+/// Buttons are combined with `|`, e.g. `Buttons::A | Buttons::START`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub const RIGHT: Self = Self(0x01);
+    pub const LEFT: Self = Self(0x02);
+    pub const UP: Self = Self(0x04);
+    pub const DOWN: Self = Self(0x08);
+    pub const A: Self = Self(0x10);
+    pub const B: Self = Self(0x20);
+    pub const SELECT: Self = Self(0x40);
+    pub const START: Self = Self(0x80);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all of the given buttons are in the set
+    pub const fn contains(self, buttons: Self) -> bool {
+        self.0 & buttons.0 == buttons.0
+    }
+}
+
+impl BitOr for Buttons {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Buttons {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
 
 pub trait IO {
+    /// Show a frame of `WIDTH` x `HEIGHT` pixels, stored line by line
+    ///
+    /// Each pixel holds its shade in bits 0–1, from 0 (lightest) to
+    /// 3 (darkest), and the palette it has been drawn with in bits 2–3.
+    ///
+    /// Bits 2–3  Palette
+    /// --------  -------
+    /// 0         BGP (background and window)
+    /// 1         OBP0
+    /// 2         OBP1
     fn refresh(&mut self, pixels: &[u8]);
 
+    /// Show a Super Game Boy frame of `SGB_WIDTH` x `SGB_HEIGHT` RGB colors
+    ///
+    /// Return false if the frontend can't show SGB frames, in which case
+    /// the Game Boy screen is passed to `refresh` instead.
+    fn refresh_sgb(&mut self, _frame: &[u32]) -> bool {
+        false
+    }
+
     fn is_esc_pressed(&self) -> bool;
 
     /// Get pressed JoyPad keys
@@ -26,4 +122,82 @@ pub trait IO {
     /// 6    Select
     /// 7    Start
     fn get_key_presses(&self) -> u8;
+
+    /// Get the JoyPad keys pressed right now, in the bit order of
+    /// `get_key_presses`
+    ///
+    /// The Game Boy polls this on every scanline, so that the game sees
+    /// a button change as soon as the frontend does, instead of at the
+    /// next VBlank.  Frontends that only read their input once per frame
+    /// return None.
+    fn poll_key_presses(&self) -> Option<u8> {
+        None
+    }
+
+    /// Get the tilt of the Game Boy for the accelerometer of MBC7
+    /// cartridges
+    ///
+    /// Return the tilt to the right and towards the player, each from
+    /// -1.0 to 1.0.  Frontends without tilt input keep it level.
+    fn get_tilt(&self) -> (f32, f32) {
+        (0.0, 0.0)
+    }
+
+    /// Get the hotkeys that have been pressed since the last call
+    ///
+    /// Frontends without hotkeys never report any.
+    fn get_hotkeys(&self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+
+    /// Queue stereo audio samples for playback
+    ///
+    /// Samples of the left and right channel are interleaved and given
+    /// at the sample rate `apu::SAMPLE_RATE`.
+    /// Frontends without audio support can ignore them.
+    fn queue_audio(&mut self, _samples: &[i16]) {}
+
+    /// Show the tiles, BG maps and objects that the PPU draws from
+    ///
+    /// This is only called after `GameBoy::show_vram_viewer` and once
+    /// per frame.  Frontends without auxiliary windows ignore it.
+    fn show_vram(&mut self, _views: &VramViews) {}
+}
+
+impl<T: IO + ?Sized> IO for Box<T> {
+    fn refresh(&mut self, pixels: &[u8]) {
+        (**self).refresh(pixels);
+    }
+
+    fn refresh_sgb(&mut self, frame: &[u32]) -> bool {
+        (**self).refresh_sgb(frame)
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        (**self).is_esc_pressed()
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        (**self).get_key_presses()
+    }
+
+    fn poll_key_presses(&self) -> Option<u8> {
+        (**self).poll_key_presses()
+    }
+
+    fn get_tilt(&self) -> (f32, f32) {
+        (**self).get_tilt()
+    }
+
+    fn get_hotkeys(&self) -> Vec<Hotkey> {
+        (**self).get_hotkeys()
+    }
+
+    fn queue_audio(&mut self, samples: &[i16]) {
+        (**self).queue_audio(samples);
+    }
+
+    fn show_vram(&mut self, views: &VramViews) {
+        (**self).show_vram(views);
+    }
 }
@@ -0,0 +1,235 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::serial::SerialDevice;
+
+/// Message sent by the Game Boy that clocks a transfer
+const TRANSFER: u8 = 0x01;
+/// Answer of the clocked Game Boy
+const REPLY: u8 = 0x02;
+
+/// How long to wait for the other Game Boy to answer a transfer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A connection to another emulator that can send and receive bytes
+/// without blocking
+pub trait LinkSocket: Read + Write + Send {}
+
+impl LinkSocket for TcpStream {}
+
+#[cfg(unix)]
+impl LinkSocket for UnixStream {}
+
+/// Connection to another instance of the emulator
+///
+/// Messages consist of two bytes, their kind followed by their data.
+pub struct Connection {
+    socket: Box<dyn LinkSocket>,
+    /// Received bytes that don't form a whole message yet
+    received: Vec<u8>,
+    /// Socket file to remove once the connection is closed
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
+}
+
+impl Connection {
+    /// Connect to another emulator at the given address
+    ///
+    /// The address is either `host:port` for TCP or `unix:path` for a
+    /// Unix domain socket.  If no other emulator listens at the address
+    /// yet, wait for one to connect.
+    pub fn open(address: &str) -> io::Result<Self> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Self::open_unix(path);
+        }
+        let socket = match TcpStream::connect(address) {
+            Ok(socket) => socket,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                let listener = TcpListener::bind(address)?;
+                log::info!("Waiting for link partner on {}.", address);
+                listener.accept()?.0
+            }
+            Err(e) => return Err(e),
+        };
+        socket.set_nodelay(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::new(Box::new(socket)))
+    }
+
+    #[cfg(unix)]
+    fn open_unix(path: &str) -> io::Result<Self> {
+        match UnixStream::connect(path) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                Ok(Self::new(Box::new(socket)))
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound
+                                         | io::ErrorKind::ConnectionRefused) => {
+                let listener = UnixListener::bind(path)?;
+                log::info!("Waiting for link partner on {}.", path);
+                let socket = listener.accept()?.0;
+                socket.set_nonblocking(true)?;
+                let mut connection = Self::new(Box::new(socket));
+                connection.socket_path = Some(PathBuf::from(path));
+                Ok(connection)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn open_unix(_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported,
+                           "Unix domain sockets are not supported."))
+    }
+
+    /// Use an already connected, non-blocking socket
+    pub fn new(socket: Box<dyn LinkSocket>) -> Self {
+        Self{
+            socket,
+            received: Vec::new(),
+            #[cfg(unix)]
+            socket_path: None,
+        }
+    }
+
+    pub fn send(&mut self, kind: u8, byte: u8) {
+        if let Err(e) = self.socket.write_all(&[kind, byte]) {
+            log::warn!("Link partner disconnected: {}", e);
+        }
+    }
+
+    /// Take the next whole message, if one has arrived
+    pub fn receive(&mut self) -> Option<(u8, u8)> {
+        let mut buffer = [0; 64];
+        loop {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::warn!("Link partner disconnected: {}", e);
+                    break;
+                }
+            }
+        }
+        if self.received.len() < 2 {
+            return None;
+        }
+        let message = (self.received[0], self.received[1]);
+        self.received.drain(..2);
+        Some(message)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Link cable to another instance of the emulator
+///
+/// The Game Boy that starts a transfer with internal clock sends its
+/// byte and waits for the other Game Boy to answer with the byte in its
+/// SB.  The answer completes the transfer on the other side if it is
+/// waiting for a transfer with external clock, otherwise it answers
+/// with 0xFF like a disconnected link port.
+///
+/// Each message is either a TRANSFER or a REPLY of one byte.
+pub struct LinkCable {
+    connection: Connection,
+}
+
+impl LinkCable {
+    pub fn new(connection: Connection) -> Self {
+        Self{connection}
+    }
+
+    /// Connect to another emulator, see `Connection::open`
+    pub fn connect(address: &str) -> io::Result<Self> {
+        Ok(Self::new(Connection::open(address)?))
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.connection.send(TRANSFER, byte);
+        let start = Instant::now();
+        while start.elapsed() < REPLY_TIMEOUT {
+            match self.connection.receive() {
+                Some((REPLY, incoming)) => return incoming,
+                // Both Game Boys started a transfer at the same time, so
+                // that each one gets the byte of the other.
+                Some((TRANSFER, incoming)) => {
+                    self.connection.send(REPLY, byte);
+                    return incoming;
+                }
+                Some(_) => {}
+                None => thread::sleep(Duration::from_micros(50)),
+            }
+        }
+        log::warn!("Link partner didn't answer transfer.");
+        0xFF
+    }
+
+    fn external_clock(&mut self, byte: Option<u8>) -> Option<u8> {
+        // Late answers to transfers that timed out are dropped.
+        while let Some((kind, incoming)) = self.connection.receive() {
+            if kind == TRANSFER {
+                self.connection.send(REPLY, byte.unwrap_or(0xFF));
+                return Some(incoming);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::game_boy::serial::SerialPort;
+
+    fn linked_port(socket: UnixStream) -> SerialPort {
+        socket.set_nonblocking(true).unwrap();
+        let mut port = SerialPort::default();
+        let connection = Connection::new(Box::new(socket));
+        port.connect(Box::new(LinkCable::new(connection)));
+        port
+    }
+
+    #[test]
+    fn bytes_are_exchanged_between_master_and_slave() {
+        let (a, b) = UnixStream::pair().unwrap();
+        // Like two emulators, both Game Boys run at the same time.
+        let slave = thread::spawn(move || {
+            let mut slave = linked_port(b);
+            slave.set_data(0x5A);
+            slave.set_control(0x80);
+            // The transfer completes as soon as the clock arrives.
+            while !slave.step(512) {}
+            (slave.get_data(), slave.get_control())
+        });
+        let mut master = linked_port(a);
+        master.set_data(0x42);
+        master.set_control(0x81);
+        assert!(!master.step(8 * 512 - 4));
+        assert!(master.step(4));
+        assert_eq!(master.get_data(), 0x5A);
+        assert_eq!(slave.join().unwrap(), (0x42, 0x7E));
+    }
+}
@@ -2,10 +2,24 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use serde::{Deserialize, Serialize};
+
+use super::accuracy::{Approximation, ApproximationTracker};
+use super::apu::APU;
+use super::boot_rom;
+use super::camera::CameraSource;
 use super::cartridge::Cartridge;
+use super::debugger::{Access, WatchHit, Watchpoint, Watchpoints};
 use super::graphics_data::MonochromePalette;
+use super::infrared::{InfraredDevice, InfraredPort};
 use super::ppu::LcdMode;
+use super::serial::{SerialDevice, SerialPort};
+use super::sgb::SuperGameBoy;
+use super::symbols::SymbolTable;
 use super::timer::Timer;
+use super::tile_cache::VramChanges;
+use super::uninitialized::{UninitializedRead, UninitializedReadTracker};
+use super::vgm::VgmRecorder;
 
 /// The memory bus of a Game Boy
 ///
@@ -25,47 +39,120 @@ use super::timer::Timer;
 /// 0xFF00–0xFF7F  I/O Registers
 /// 0xFF80–0xFFFE  HRAM  High RAM Area (targetted by special load instructions)
 /// 0xFFFF         IE Register  Interrupt Enabled Register
+#[derive(Serialize, Deserialize)]
 pub struct MemoryBus {
     memory: Memory,
     dma_transfer: Option<OamDmaTransfer>,
+    #[serde(skip)]
+    symbols: Option<SymbolTable>,
+    /// Watchpoints of the debugger, if there are any
+    #[serde(skip)]
+    watchpoints: Option<Watchpoints>,
+    /// Accesses that a script reacts to, kept apart from the debugger's
+    #[serde(skip)]
+    script_watchpoints: Option<Watchpoints>,
 }
 
+#[derive(Serialize, Deserialize)]
 struct Memory {
-    memory: [u8; 0x10000],
+    /// The whole address space of 0x10000 bytes
+    memory: Box<[u8]>,
     cartridge: Cartridge,
-    boot_rom: Option<[u8; 0x100]>,
+    boot_rom: Option<boot_rom::BootRom>,
     joypad: u8,
     timer: Timer,
+    apu: APU,
+    serial: SerialPort,
+    infrared: InfraredPort,
+    sgb: Option<SuperGameBoy>,
+    #[serde(skip)]
+    uninitialized_reads: Option<UninitializedReadTracker>,
+    #[serde(skip)]
+    approximations: Option<ApproximationTracker>,
+    /// Combined level of the STAT interrupt conditions
+    stat_line: bool,
+    model: boot_rom::Model,
+    /// VRAM writes since the last update of the tile cache
+    #[serde(skip, default = "VramChanges::all")]
+    vram_changes: VramChanges,
+    /// Whether registers that affect rendering or VRAM have been written
+    /// while the PPU was drawing the frame
+    raster_write: bool,
 }
 
 impl MemoryBus {
-    pub fn new(cartridge: Cartridge, boot_rom: [u8; 0x100]) -> Self {
+    pub fn new(cartridge: Cartridge, boot_rom: boot_rom::BootRom) -> Self {
         Self{
-            memory: Memory::new(cartridge, boot_rom),
+            memory: Memory::new(cartridge, Some(boot_rom)),
             dma_transfer: None,
+            symbols: None,
+            watchpoints: None,
+            script_watchpoints: None,
         }
     }
 
+    /// Create a memory bus in the state that the boot ROM of the given
+    /// model leaves it in
+    ///
+    /// This skips the boot ROM entirely, the I/O registers are initialized
+    /// from a table of post-boot values and the logo is drawn into VRAM.
+    pub fn with_hle_boot(cartridge: Cartridge, model: boot_rom::Model) -> Self {
+        let mut memory = Memory::new(cartridge, None);
+        memory.model = model;
+        for (address, value) in boot_rom::post_boot_io_registers(model) {
+            memory.write8(address, value);
+        }
+        memory.timer.set_divider_clock(boot_rom::DMG_POST_BOOT_DIVIDER);
+        let logo = memory.cartridge.header().logo();
+        boot_rom::draw_logo_into_vram(logo, &mut memory.memory[0x8000..0xA000]);
+        Self{
+            memory,
+            dma_transfer: None,
+            symbols: None,
+            watchpoints: None,
+            script_watchpoints: None,
+        }
+    }
+
+    /// Take over the state of a memory bus loaded from a save state
+    ///
+    /// Connected devices, symbols and trackers are kept.
+    pub fn restore_state(&mut self, saved: Self) {
+        self.memory.restore_state(saved.memory);
+        self.dma_transfer = saved.dma_transfer;
+    }
+
     pub fn read8(&self, address: u16) -> u8 {
-        if self.dma_transfer.is_active()
-           && address < 0xFF80 && address != 0xFF46 {
-            return 0xFF;
+        let value = match self.dma_transfer.conflict(address) {
+            Some(value) => value,
+            None => self.memory.read8(address),
+        };
+        if let Some(watchpoints) = &self.watchpoints {
+            watchpoints.check(address, value, Access::Read);
         }
-        self.memory.read8(address)
+        if let Some(watchpoints) = &self.script_watchpoints {
+            watchpoints.check(address, value, Access::Read);
+        }
+        value
     }
 
     pub fn write8(&mut self, address: u16, value: u8) {
+        if let Some(watchpoints) = &self.watchpoints {
+            watchpoints.check(address, value, Access::Write);
+        }
+        if let Some(watchpoints) = &self.script_watchpoints {
+            watchpoints.check(address, value, Access::Write);
+        }
         if address == 0xFF46 {
             // Object Attribute Memory (OAM) DMA Control Register
             // This will take 160 cycles during which the CPU
-            // continues execution but only has access to HRAM.
+            // continues execution but can't access the bus that DMA
+            // reads from.
             self.memory.write8(address, value);
             self.dma_transfer
                 = Some(OamDmaTransfer::new(value,
                                            self.dma_transfer.is_some()));
-        } else if !self.dma_transfer.is_active() || address >= 0xFF80 {
-            // OAM DMA transfer block all memory access except for 0xFF46
-            // and HRAM.
+        } else if self.dma_transfer.conflict(address).is_none() {
             self.memory.write8(address, value);
         }
     }
@@ -74,6 +161,39 @@ impl MemoryBus {
         self.read8(address) as u16 + ((self.read8(address+1) as u16) << 8)
     }
 
+    /// Read a byte without being blocked by OAM DMA or the PPU and
+    /// without triggering watchpoints, e.g. for tools observing the game
+    pub fn peek8(&self, address: u16) -> u8 {
+        self.memory.peek8(address)
+    }
+
+    /// Write a byte without being blocked by OAM DMA or the PPU and
+    /// without triggering watchpoints
+    ///
+    /// Writes to the cartridge and the I/O registers have the same
+    /// effects as writes by the CPU, except that writing 0xFF46 doesn't
+    /// start an OAM DMA transfer.
+    pub fn poke8(&mut self, address: u16, value: u8) {
+        self.memory.poke8(address, value);
+    }
+
+    /// Read VRAM or OAM like the PPU, which isn't blocked by the LCD
+    /// mode
+    ///
+    /// OAM can't be read while OAM DMA is writing it.
+    pub fn ppu_read8(&self, address: u16) -> u8 {
+        if self.dma_transfer.is_active()
+           && (0xFE00..0xFEA0).contains(&address) {
+            return 0xFF;
+        }
+        self.memory.memory[address as usize]
+    }
+
+    pub fn ppu_read16(&self, address: u16) -> u16 {
+        self.ppu_read8(address) as u16
+            + ((self.ppu_read8(address+1) as u16) << 8)
+    }
+
     pub fn write16(&mut self, address: u16, value: u16) {
         self.write8(address, value as u8);
         self.write8(address+1, (value >> 8) as u8);
@@ -83,7 +203,7 @@ impl MemoryBus {
         if let Some(dma_transfer) = self.dma_transfer.as_mut() {
             for _ in (0..cycles).step_by(4) {
                 if dma_transfer.step(&mut self.memory) {
-                    eprintln!("Stopping DMA transfer.");
+                    log::debug!("Stopping DMA transfer.");
                     self.dma_transfer = None;
                     break
                 }
@@ -93,6 +213,279 @@ impl MemoryBus {
             // request Timer interrupt
             self.memory.memory[0xFF0F] |= 4;
         }
+        if self.memory.serial.step(cycles) {
+            // request Serial interrupt
+            self.memory.memory[0xFF0F] |= 1 << 3;
+        }
+        self.memory.apu.step(cycles);
+        self.memory.cartridge.step(cycles);
+    }
+
+    /// Enter STOP mode, which stops the divider and timer
+    ///
+    /// The speed switch of the CGB, which is also done by STOP, is not
+    /// supported yet.
+    pub fn stop(&mut self) {
+        if self.memory.timer.stop_clock() {
+            // request Timer interrupt
+            self.memory.memory[0xFF0F] |= 4;
+        }
+    }
+
+    /// Leave STOP mode if a button of a selected row is pressed
+    ///
+    /// Return true if the CPU should resume.
+    pub fn wake_from_stop(&mut self) -> bool {
+        if self.memory.memory[0xFF00] & 0x0F == 0x0F {
+            return false;
+        }
+        self.memory.timer.start_clock();
+        true
+    }
+
+    /// Start reporting reads of never-written WRAM and HRAM bytes
+    pub fn track_uninitialized_reads(&mut self) {
+        self.memory.uninitialized_reads
+            = Some(UninitializedReadTracker::default());
+    }
+
+    /// Attribute uninitialized reads since the last call to an instruction
+    ///
+    /// Return the reads that have been found.
+    pub fn report_uninitialized_reads(&mut self, pc: u16, frame: u64)
+            -> &[UninitializedRead] {
+        match &mut self.memory.uninitialized_reads {
+            Some(tracker) => tracker.report(pc, frame),
+            None => &[],
+        }
+    }
+
+    /// All uninitialized reads found so far
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead] {
+        match &self.memory.uninitialized_reads {
+            Some(tracker) => tracker.reads(),
+            None => &[],
+        }
+    }
+
+    /// Start collecting the approximations that the game runs into
+    pub fn track_approximations(&mut self) {
+        self.memory.approximations = Some(ApproximationTracker::default());
+    }
+
+    /// Take the approximations hit for the first time since the last call
+    pub fn take_new_approximations(&mut self) -> Vec<Approximation> {
+        match &mut self.memory.approximations {
+            Some(tracker) => tracker.take_new_hits(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Record an approximation taken outside of the memory bus
+    pub fn hit_approximation(&mut self, approximation: Approximation) {
+        if let Some(tracker) = &mut self.memory.approximations {
+            tracker.hit(approximation);
+        }
+    }
+
+    /// Check a CPU access for approximations that it could run into
+    ///
+    /// Accesses of the PPU and of OAM DMA are not checked.
+    pub fn check_cpu_access(&mut self, address: u16) {
+        if self.memory.approximations.is_none() {
+            return;
+        }
+        let lcd_on = self.memory.lcdc().are_lcd_and_ppu_enabled();
+        let mode = self.memory.lcd_status().mode();
+        let approximation = match (address, mode) {
+            (0x8000..=0x9FFF, LcdMode::TransferringDataToLcdController)
+            | (0xFE00..=0xFE9F, LcdMode::SearchingOAM
+                                | LcdMode::TransferringDataToLcdController)
+                    if lcd_on => {
+                Approximation::BlockedVramAccess
+            }
+            (0xFF30..=0xFF3F, _) if self.memory.apu.is_wave_playing() => {
+                Approximation::WaveRamAccess
+            }
+            _ => return,
+        };
+        self.hit_approximation(approximation);
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.memory.cartridge
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.memory.timer
+    }
+
+    /// Values of the I/O registers at 0xFF00–0xFF7F and of IE as the
+    /// CPU would read them, without triggering watchpoints
+    pub fn io_registers(&self) -> Vec<(u16, u8)> {
+        (0xFF00..0xFF80).chain([0xFFFF])
+                        .map(|address| (address, self.memory.read8(address)))
+                        .collect()
+    }
+
+    /// The bytes of the address space that aren't mapped to devices
+    /// like the cartridge, i.e. VRAM, WRAM, OAM, HRAM and the I/O
+    /// registers
+    pub fn address_space(&self) -> &[u8] {
+        &self.memory.memory
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.memory.cartridge
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.get_or_insert_with(Watchpoints::default)
+                        .add(watchpoint);
+    }
+
+    /// Remove the watchpoint with the given index in `watchpoints`
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        let watchpoints = self.watchpoints.as_mut()?;
+        let watchpoint = watchpoints.remove(index);
+        if watchpoints.is_empty() {
+            // Accesses aren't checked at all without watchpoints.
+            self.watchpoints = None;
+        }
+        watchpoint
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        match &self.watchpoints {
+            Some(watchpoints) => watchpoints.list(),
+            None => &[],
+        }
+    }
+
+    /// Accesses that hit a watchpoint since the last call
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        match &mut self.watchpoints {
+            Some(watchpoints) => watchpoints.take_hits(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Report accesses to the given ranges to a script, replacing the
+    /// ranges of the previous script
+    pub fn watch_for_script(&mut self, watchpoints: Vec<Watchpoint>) {
+        self.script_watchpoints = match watchpoints.is_empty() {
+            true => None,
+            false => {
+                let mut script_watchpoints = Watchpoints::default();
+                for watchpoint in watchpoints {
+                    script_watchpoints.add(watchpoint);
+                }
+                Some(script_watchpoints)
+            }
+        };
+    }
+
+    /// Accesses that the script watches since the last call
+    pub fn take_script_watch_hits(&mut self) -> Vec<WatchHit> {
+        match &mut self.script_watchpoints {
+            Some(watchpoints) => watchpoints.take_hits(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Use the labels of a `.sym` file to describe addresses
+    pub fn load_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    /// The label at or before an address in the currently mapped bank
+    pub fn label(&self, address: u16) -> Option<String> {
+        self.symbols.as_ref()?.lookup(self.bank(address)?, address)
+    }
+
+    /// Number of the bank mapped to an address, as in `.sym` files
+    ///
+    /// Return None for addresses of the boot ROM while it is mapped.
+    pub fn bank(&self, address: u16) -> Option<usize> {
+        let cartridge = &self.memory.cartridge;
+        let bank = match address {
+            0x0000..=0x08FF if self.memory.boot_rom.as_ref()
+                    .is_some_and(|rom| rom.read8(address).is_some()) => {
+                return None;
+            }
+            0x0000..=0x7FFF => cartridge.rom_bank(address),
+            0xA000..=0xBFFF => cartridge.ram_bank(),
+            // Without WRAM banking on the DMG, WRAMX is always bank 1.
+            0xD000..=0xDFFF => 1,
+            _ => 0,
+        };
+        Some(bank)
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_ref()
+    }
+
+    /// Choose the hardware model whose quirks are emulated
+    ///
+    /// Defaults to the DMG, or the model passed to `with_hle_boot`.
+    pub fn set_model(&mut self, model: boot_rom::Model) {
+        self.memory.model = model;
+    }
+
+    /// Accept Super Game Boy commands of the cartridge
+    pub fn enable_super_game_boy(&mut self) {
+        self.memory.sgb = Some(SuperGameBoy::default());
+    }
+
+    pub fn super_game_boy_mut(&mut self) -> Option<&mut SuperGameBoy> {
+        self.memory.sgb.as_mut()
+    }
+
+    /// Connect a device to the link port
+    pub fn connect_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.memory.serial.connect(device);
+    }
+
+    /// Point the IR port of the cartridge, if it has one, or else the
+    /// one of the CGB at a device
+    pub fn connect_infrared_device(&mut self,
+                                   device: Box<dyn InfraredDevice>) {
+        if self.memory.cartridge.has_infrared() {
+            self.memory.cartridge.connect_infrared_device(device);
+        } else {
+            self.memory.infrared.connect(device);
+        }
+    }
+
+    /// Point the camera of Game Boy Camera cartridges at a source
+    pub fn connect_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        self.memory.cartridge.connect_camera_source(source);
+    }
+
+    pub fn start_vgm_recording(&mut self) {
+        self.memory.apu.start_vgm_recording();
+    }
+
+    pub fn stop_vgm_recording(&mut self) -> Option<VgmRecorder> {
+        self.memory.apu.stop_vgm_recording()
+    }
+
+    /// Take all audio samples generated since the last call.
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.memory.apu.take_samples()
+    }
+
+    /// Take the VRAM writes since the last call
+    pub fn take_vram_changes(&mut self) -> VramChanges {
+        std::mem::replace(&mut self.memory.vram_changes, VramChanges::none())
+    }
+
+    /// Whether VRAM, the palettes or the LCD control, scroll or window
+    /// position registers have been written while drawing the frame
+    /// since the last call
+    pub fn take_raster_write(&mut self) -> bool {
+        std::mem::take(&mut self.memory.raster_write)
     }
 
     pub fn lcdc(&self) -> LcdControl {
@@ -166,71 +559,8 @@ impl MemoryBus {
         self.memory.set_key_presses(presses)
     }
 
-    pub fn dump_tile_data<W: std::io::Write>(
-            &self, buffer: &mut W) -> std::io::Result<()> {
-        let tile_size = 2 * 8;
-        let num_tiles = (0x9800 - 0x8000) / tile_size;
-        // print tile data in rows of 16 tiles each
-        let tiles_per_row = 16;
-        let num_rows = num_tiles / tiles_per_row;
-        writeln!(buffer, "P2")?;
-        writeln!(buffer, "{} {}", tiles_per_row*8, num_rows*8)?;
-        writeln!(buffer, "3")?;
-        let mut row_start = 0x8000;
-        for _tile_row in 0..num_rows {
-            for row in 0..8 {
-                for tile_col in 0..tiles_per_row {
-                    let tile = self.read16(row_start + 2 * row
-                                           + tile_size * tile_col);
-                    let p = ((tile >> 14) & 0b10) | ((tile >> 7) & 1);
-                    write!(buffer, "{}", 3 - p)?;
-                    for i in 1..8 {
-                        let p = ((tile >> 14-i) & 0b10) | ((tile >> 7-i) & 1);
-                        write!(buffer, " {}", 3 - p)?;
-                    }
-                    writeln!(buffer)?;
-                }
-            }
-            row_start += tiles_per_row * tile_size;
-        }
-        Ok(())
-    }
-
-    pub fn dump_bg<W: std::io::Write>(&self,
-                                      buffer: &mut W) -> std::io::Result<()> {
-        let lcdc = self.lcdc();
-        let palette = self.bg_palette().as_array();
-        let tile_map_start = lcdc.bg_tilemap_start();
-        writeln!(buffer, "P2")?;
-        writeln!(buffer, "256 256")?;
-        writeln!(buffer, "3")?;
-        let mut tiles: [u8; 32] = [0; 32];
-        for tile_row in 0..32 {
-            for tile_col in 0..32u16 {
-                let tile
-                    = self.read8(tile_map_start + 32 * tile_row + tile_col);
-                tiles[tile_col as usize] = tile;
-            }
-            for row in 0..8 {
-                for tile_col in 0..32 {
-                    let tile_address
-                        = lcdc.get_bg_or_window_tile_address(tiles[tile_col]);
-                    let tile = self.read16(tile_address + 2 * row);
-                    let index = ((tile >> 14) & 0b10)
-                              | ((tile >> 7) & 1);
-                    let p = palette[index as usize];
-                    write!(buffer, "{}", 3 - p)?;
-                    for i in 1..8 {
-                        let index = ((tile >> 14-i) & 0b10)
-                                  | ((tile >> 7-i) & 1);
-                        let p = palette[index as usize];
-                        write!(buffer, " {}", 3 - p)?;
-                    }
-                    writeln!(buffer)?;
-                }
-            }
-        }
-        Ok(())
+    pub fn set_tilt(&mut self, tilt: (f32, f32)) {
+        self.cartridge_mut().set_tilt(tilt);
     }
 
     pub fn get_requested_interrupts(&self) -> u8 {
@@ -243,30 +573,83 @@ impl MemoryBus {
 }
 
 impl Memory {
-    fn new(cartridge: Cartridge, boot_rom: [u8; 0x100]) -> Self {
-        let mut memory = [0; 0x10000];
+    fn new(cartridge: Cartridge, boot_rom: Option<boot_rom::BootRom>)
+            -> Self {
+        let mut memory = vec![0; 0x10000].into_boxed_slice();
         memory[0xFF00] = 0xCF;  // upper two bits of JoyPad always 1
         memory[0xFF0F] = 0xE0;  // highest three bits of IF always 1
         memory[0xFF41] = 0x80;  // highest bit of LCD Status always 1
         Self{
             memory,
             cartridge,
-            boot_rom: Some(boot_rom),
+            boot_rom,
             joypad: 0,
             timer: Timer::default(),
+            apu: APU::default(),
+            serial: SerialPort::default(),
+            infrared: InfraredPort::default(),
+            sgb: None,
+            uninitialized_reads: None,
+            approximations: None,
+            stat_line: false,
+            model: boot_rom::Model::default(),
+            vram_changes: VramChanges::all(),
+            raster_write: false,
+        }
+    }
+
+    fn restore_state(&mut self, saved: Self) {
+        let Self{memory, cartridge, boot_rom, joypad, timer, apu, serial,
+                 infrared, sgb, stat_line, model, raster_write, ..} = saved;
+        self.memory = memory;
+        self.cartridge.restore_state(cartridge);
+        self.boot_rom = boot_rom;
+        self.joypad = joypad;
+        self.timer = timer;
+        self.apu.restore_state(apu);
+        self.serial.restore_state(serial);
+        self.infrared.restore_state(infrared);
+        self.sgb = sgb;
+        self.stat_line = stat_line;
+        self.model = model;
+        // The tile cache has to decode all of the restored VRAM.
+        self.vram_changes = VramChanges::all();
+        self.raster_write = raster_write;
+    }
+
+    /// Read like `read8`, but VRAM and OAM even while the PPU uses
+    /// them, and without tracking reads of uninitialized RAM
+    fn peek8(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF | 0xC000..=0xDFFF | 0xFE00..=0xFE9F
+            | 0xFF80..=0xFFFE => self.memory[address as usize],
+            0xE000..=0xFDFF => self.memory[(address - 0x2000) as usize],
+            _ => self.read8(address),
+        }
+    }
+
+    /// Write like `write8`, but into VRAM and OAM even while the PPU
+    /// uses them
+    fn poke8(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => {
+                self.memory[address as usize] = value;
+                self.vram_changes.mark(address);
+                self.note_raster_write();
+            }
+            0xFE00..=0xFE9F => self.memory[address as usize] = value,
+            _ => self.write8(address, value),
         }
     }
 
     fn read8(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0x00FF => { // Boot ROM / ROM Bank 0
-                if let Some(ref boot_rom) = self.boot_rom {
-                    boot_rom[address as usize]
-                } else {
-                    self.cartridge.rom0_read8(address)
-                }
+            0x0000..=0x08FF if self.boot_rom.is_some() => { // Boot ROM
+                let boot_rom = self.boot_rom.as_ref().unwrap();
+                boot_rom.read8(address)
+                        .unwrap_or_else(|| self.cartridge.read8(address))
             }
-            0x0100..=0x7FFF | 0xA000..=0xBFFF => { // Cartridge
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => { // Cartridge
                 // 0x0000–0x3FFF  ROM Bank 0
                 // 0x4000–0x7FFF  ROM X (switchable via Memory Controller)
                 // 0xA000–0xBFFF  SRAM  Cartridge RAM
@@ -276,34 +659,51 @@ impl Memory {
                 // 0x8000–0x9FFF  VRAM
                 // (0x8000–0x97FF  Tile RAM)
                 // (0x9800–0x9FFF  Background Map)
+                if self.is_blocked_by_ppu(address) {
+                    return 0xFF;
+                }
                 self.memory[address as usize]
             }
             0xC000..=0xDFFF => { // Working RAM
                 // 0xC000–0xCFFF  WRAM0  Working RAM
                 // 0xD000–0xDFFF  WRAMX  Working RAM (switchable banks on GBC)
+                self.track_read(address);
                 self.memory[address as usize]
             }
             // 0xE000–0xFDFF  ECHO  echos Working RAM, discouraged to be used
             0xE000..=0xFDFF => {
                 // Remap to 0xC000–0xDDFF.
-                self.memory[(address - 0xE000 + 0xC000) as usize]
+                let address = address - 0xE000 + 0xC000;
+                self.track_read(address);
+                self.memory[address as usize]
             }
             // 0xFE00–0xFE9F  OAM  Object Attribute Memory (description of sprites)
             0xFE00..=0xFE9F => {
+                if self.is_blocked_by_ppu(address) {
+                    return 0xFF;
+                }
                 self.memory[address as usize]
             }
-            // 0xFEA0–0xFEFF  UNUSED  (reading returns 0, writing does nothing)
+            // 0xFEA0–0xFEFF  UNUSED  (reading depends on the model, writing
+            //                        does nothing)
             0xFEA0..=0xFEFF => {
-                unimplemented!("reading from UNUSED {:0>4X} not implemented, yet.",
-                               address);
+                if self.is_blocked_by_ppu(address) {
+                    return 0xFF;
+                }
+                self.model.unused_oam_area_read8(address)
             }
             // 0xFF00–0xFF7F  I/O Registers
             0xFF00 => { // Joypad
                 self.memory[address as usize]
             }
-            0xFF01..=0xFF03 => {
-                unimplemented!("reading from {:0>4X} not implemented, yet.",
-                               address);
+            0xFF01 => { // SB – Serial Transfer Data
+                self.serial.get_data()
+            }
+            0xFF02 => { // SC – Serial Transfer Control
+                self.serial.get_control()
+            }
+            0xFF03 => { // Unmapped
+                0xFF
             }
             0xFF04 => { // DIV – Divider Register
                 self.timer.get_divider()
@@ -317,24 +717,14 @@ impl Memory {
             0xFF07 => { // TAC – Timer Control
                 self.timer.get_control()
             }
-            0xFF08..=0xFF0E => {
-                unimplemented!("reading from {:0>4X} not implemented, yet.",
-                               address);
+            0xFF08..=0xFF0E => { // Unmapped
+                0xFF
             }
             0xFF0F => { // IF – Interrupt Flag
                 self.memory[address as usize]
             }
-            0xFF10..=0xFF26 => { // Sound
-                // TODO: ignoring sound for now
-                0x00
-            }
-            0xFF27..=0xFF2F => {
-                unimplemented!("reading from {:0>4X} not implemented, yet.",
-                               address);
-            }
-            0xFF30..=0xFF3F => { // Wave Form RAM
-                // TODO: ignoring sound for now
-                0x00
+            0xFF10..=0xFF3F => { // Sound and Wave Form RAM
+                self.apu.read8(address)
             }
             0xFF40..=0xFF4B => { // LCD Status
                 // FF40 - LCD Control (R/W)
@@ -344,19 +734,24 @@ impl Memory {
                 // FF44 - LY (LCDC Y-Coordinate) (R)
                 // FF45 - LYC (LY Compare) (R/W)
                 // FF46 - Object Attribute Memory (OAM) DMA Control Register
+                // FF47 - BGP (BG Palette Data) (R/W)
+                // FF48 - OBP0 (Object Palette 0 Data) (R/W)
+                // FF49 - OBP1 (Object Palette 1 Data) (R/W)
                 // FF4A - WY (Window Y Position) (R/W)
                 // FF4B - WX (Window X Position + 7) (R/W)
-                if 0xFF47 <= address && address <= 0xFF49 {
-                    unimplemented!("reading from {:0>4X} not implemented, yet.",
-                                   address);
-                }
                 self.memory[address as usize]
             }
+            0xFF56 if self.model == boot_rom::Model::CGB => {
+                // RP – Infrared Communications Port
+                self.infrared.read()
+            }
             0xFF4C..=0xFF7F => { // I/O Registers
-                unimplemented!("reading from {:0>4X} not implemented, yet.",
-                               address);
+                // Only the CGB has registers here, the boot ROM flag in
+                // FF50 can't be read back.
+                0xFF
             }
             0xFF80..=0xFFFE => { // HRAM
+                self.track_read(address);
                 self.memory[address as usize]
             }
             0xFFFF => { // Interrupt Enabled Register
@@ -373,7 +768,11 @@ impl Memory {
             0x8000..=0x9FFF => { // VRAM
                 // (0x8000–0x97FF  Tile RAM)
                 // (0x9800–0x9FFF  Background Map)
-                self.memory[address as usize] = value;
+                if !self.is_blocked_by_ppu(address) {
+                    self.memory[address as usize] = value;
+                    self.vram_changes.mark(address);
+                    self.note_raster_write();
+                }
             }
             0xA000..=0xBFFF => { // SRAM  Cartridge RAM
                 self.cartridge.write8(address, value);
@@ -381,14 +780,19 @@ impl Memory {
             0xC000..=0xDFFF => { // Working RAM
                 // 0xC000–0xCFFF  WRAM0  Working RAM
                 // 0xD000–0xDFFF  WRAMX  Working RAM (switchable banks on GBC)
+                self.track_write(address);
                 self.memory[address as usize] = value;
             }
             0xE000..=0xFDFF => { // Echo
                 // Remap to 0xC000–0xDDFF.
-                self.memory[(address - 0xE000 + 0xC000) as usize] = value;
+                let address = address - 0xE000 + 0xC000;
+                self.track_write(address);
+                self.memory[address as usize] = value;
             }
             0xFE00..=0xFE9F => { // OAM
-                self.memory[address as usize] = value;
+                if !self.is_blocked_by_ppu(address) {
+                    self.memory[address as usize] = value;
+                }
             }
             0xFEA0..=0xFEFF => { // UNUSED
                 // write does nothing
@@ -396,14 +800,23 @@ impl Memory {
             0xFF00..=0xFF7F => { // I/O Registers
                 match address {
                     0xFF00 => { // Joypad
-                        // highest two bits of joypad register are always 1.
-                        self.memory[address as usize] = 0xC0 | value;
+                        // highest two bits of joypad register are always 1
+                        // and the lowest 4 bits are read-only.
+                        self.memory[address as usize]
+                            = 0xC0 | (value & 0x30)
+                              | (self.memory[address as usize] & 0x0F);
+                        if let Some(sgb) = &mut self.sgb {
+                            sgb.write_joypad(value,
+                                             &self.memory[0x8000..0xA000],
+                                             self.memory[0xFF40]);
+                        }
                         self.update_joypad_register();
                     }
-                    0xFF01..=0xFF02 => { // Serial Transfer
-                        // 0xFF01  SB – Serial Transfer Data
-                        // 0xFF02  SC – Serial Transfer Control
-                        self.memory[address as usize] = value;
+                    0xFF01 => { // SB – Serial Transfer Data
+                        self.serial.set_data(value);
+                    }
+                    0xFF02 => { // SC – Serial Transfer Control
+                        self.serial.set_control(value);
                     }
                     0xFF04 => { // DIV – Divider Register
                         // Writing any value to DIV register resets it to 0.
@@ -432,11 +845,8 @@ impl Memory {
                         // Highest 3 bits are unused and always 1.
                         self.memory[address as usize] = 0xE0 | value;
                     }
-                    0xFF10..=0xFF26 => { // Sound
-                        // TODO: ignoring sound for now
-                    }
-                    0xFF30..=0xFF3F => { // Wave Form RAM
-                        // TODO: ignoring sound for now
+                    0xFF10..=0xFF3F => { // Sound and Wave Form RAM
+                        self.apu.write8(address, value);
                     }
                     0xFF40 => {
                         // LCD Control
@@ -445,21 +855,33 @@ impl Memory {
                         // assert!(value & 0x80 != 0,
                         //         "Switching off LCD not handled.");
                         self.memory[address as usize] = value;
+                        self.note_raster_write();
                         // unimplemented!("LCDC = {:0>4X}", value);
                     }
                     0xFF41 => { // LCD Status
+                        if self.model.has_stat_write_bug() {
+                            // For one M-cycle, all sources are enabled.
+                            self.memory[address as usize] |= 0x78;
+                            self.update_stat_line();
+                        }
                         // lowest 3 bits are read-only
                         let value = value & !0x07;
                         // highest bit is unused and always 1
                         self.memory[address as usize] &= 0x87;
                         self.memory[address as usize] |= value;
+                        self.update_stat_line();
                     }
                     0xFF44 => { // LY (LCDC Y-Coordinate) (R)
                         panic!("Trying to write to LY");
                     }
-                    0xFF42..=0xFF45 => {
+                    0xFF45 => { // LYC (LY Compare)
+                        self.memory[address as usize] = value;
+                        self.compare_lyc();
+                    }
+                    0xFF42..=0xFF43 => {
                         // LCD Position and scrolling
                         self.memory[address as usize] = value;
+                        self.note_raster_write();
                     }
                     0xFF46 => {
                         // Object Attribute Memory (OAM) DMA Control Register
@@ -472,10 +894,12 @@ impl Memory {
                         // 0xFF48: OBP0 (Object Palette 0 Data)
                         // 0xFF49: OBP1 (Object Palette 1 Data)
                         self.memory[address as usize] = value;
+                        self.note_raster_write();
                     }
                     0xFF4A..=0xFF4B => {
                         // LCD Position and scrolling (continued)
                         self.memory[address as usize] = value;
+                        self.note_raster_write();
                     }
                     0xFF50 => { // Disable boot ROM flag
                         if value & 1 != 0 {
@@ -483,25 +907,26 @@ impl Memory {
                         }
                         self.memory[address as usize] = value;
                     }
+                    0xFF56 => { // RP – Infrared Communications Port
+                        if self.model == boot_rom::Model::CGB {
+                            self.infrared.write(value);
+                        }
+                    }
                     0xFF72..=0xFF7F => { // Undocumented I/O registers
                         // TODO: Improve handling of undocumented I/O registers
-                        eprintln!("Writing {:0>2X} to undocumented I/O register {:0>4X}.",
-                                  value, address);
+                        log::warn!("Writing {:0>2X} to undocumented I/O register {:0>4X}.",
+                                   value, address);
                     }
                     _ => unimplemented!("Writing {:0>2X} to I/O register {:0>4X} not implemented.",
                                         value, address),
                 }
             }
             0xFF80..=0xFFFE => { // HRAM
+                self.track_write(address);
                 self.memory[address as usize] = value;
             }
             0xFFFF => { // IE Register
                 // TODO: should I mask value with 0x1F? Only bits 0–4 are used.
-                if value & 0x08 != 0 {
-                    unimplemented!(
-                        "Writing {:0>2X} to IE register not implemented.",
-                        value);
-                }
                 self.memory[address as usize] = value;
             }
         }
@@ -515,11 +940,50 @@ impl Memory {
         LcdStatus{flags: &mut self.memory[0xFF41]}
     }
 
+    fn lcd_mode(&self) -> LcdMode {
+        (self.memory[0xFF41] & 3).into()
+    }
+
+    /// Whether the PPU keeps the CPU from accessing VRAM or OAM
+    ///
+    /// VRAM is in use by the PPU during mode 3 and OAM during modes 2
+    /// and 3, so that reads return 0xFF and writes are ignored.  This
+    /// also covers the unused area after OAM.
+    fn is_blocked_by_ppu(&self, address: u16) -> bool {
+        if !self.lcdc().are_lcd_and_ppu_enabled() {
+            return false;
+        }
+        matches!((address, self.lcd_mode()),
+                 (0x8000..=0x9FFF, LcdMode::TransferringDataToLcdController)
+                 | (0xFE00..=0xFEFF, LcdMode::SearchingOAM
+                                     | LcdMode::TransferringDataToLcdController))
+    }
+
+    /// Remember a write that changes how the current frame looks
+    ///
+    /// Writes during VBlank or while the LCD is off don't count.
+    fn note_raster_write(&mut self) {
+        if self.lcdc().are_lcd_and_ppu_enabled() && self.ly() < 144 {
+            self.raster_write = true;
+        }
+    }
+
     fn set_lcd_mode(&mut self, mode: LcdMode) {
-        if self.lcd_status().set_mode(mode) {
+        self.lcd_status().set_mode(mode);
+        self.update_stat_line();
+    }
+
+    /// Request a STAT interrupt if the STAT interrupt line went high
+    ///
+    /// All enabled STAT conditions share one line, so that a condition
+    /// only raises an interrupt if no other condition held before.
+    fn update_stat_line(&mut self) {
+        let stat_line = self.lcd_status().interrupt_line();
+        if stat_line && !self.stat_line {
             // Request Stat interrupt.
             self.memory[0xFF0F] |= 2;
         }
+        self.stat_line = stat_line;
     }
 
     fn scy(&self) -> u8 {
@@ -536,12 +1000,13 @@ impl Memory {
 
     fn set_ly(&mut self, ly: u8) {
         self.memory[0xFF44] = ly;
-        let equal = ly == self.lyc();
+        self.compare_lyc();
+    }
+
+    fn compare_lyc(&mut self) {
+        let equal = self.ly() == self.lyc();
         self.lcd_status().update_lyc_eq_ly(equal);
-        if equal && self.lcd_status().lyc_eq_ly_interrupt_set() {
-            // Request Stat interrupt.
-            self.memory[0xFF0F] |= 2;
-        }
+        self.update_stat_line();
     }
 
     fn lyc(&self) -> u8 {
@@ -588,23 +1053,39 @@ impl Memory {
         self.update_joypad_register()
     }
 
+    /// Update the button bits P10–P13 of the joypad register
+    ///
+    /// Bit 4 (P14) selects the direction keys and bit 5 (P15) the action
+    /// keys when they are 0. The button lines are pulled up, so if neither
+    /// row is selected, all four bits read as 1 (unpressed). If both rows
+    /// are selected, a bit reads as 0 if a button in either row is pressed.
+    /// https://gbdev.io/pandocs/Joypad_Input.html
+    ///
+    /// On a Super Game Boy, only the first of multiple joypads has
+    /// pressed buttons and if neither row is selected, the bits hold
+    /// 0x0F minus the number of the current joypad.
     fn update_joypad_register(&mut self) -> bool {
         let joypad_register = self.memory[0xFF00];
+        let player = self.sgb.as_ref().map_or(0, |sgb| sgb.player());
+        let presses = if player == 0 { self.joypad } else { 0 };
         // Careful: joypad_register stores pressed buttons as 0,
         //          but joypad stores them as 1.
         let mut joypad = 0;
         if (joypad_register & 0x10) == 0 { // Direction keys
-            joypad |= self.joypad & 0x0F;
+            joypad |= presses & 0x0F;
         }
         if (joypad_register & 0x20) == 0 { // Action keys
-            joypad |= (self.joypad >> 4) & 0x0F;
+            joypad |= (presses >> 4) & 0x0F;
         }
-        self.memory[0xFF00] = (joypad_register & 0xF0) | (!joypad & 0x0F);
+        let id = if (joypad_register & 0x30) == 0x30 { player } else { 0 };
+        self.memory[0xFF00] = (joypad_register & 0xF0)
+                              | ((!joypad & 0x0F) - id);
         if joypad != 0 {
-            eprintln!("Joypad register: {:0>2X}", self.memory[0xFF00]);
+            log::debug!("Joypad register: {:0>2X}", self.memory[0xFF00]);
         }
         // Raise interrupt when "unpressed button" bits become
-        // "pressed button bits"
+        // "pressed button bits", either because a button got pressed
+        // or because the row of an already pressed button got selected.
         if ((joypad_register & 0x0F) & joypad) != 0 {
             // Request Joypad interrupt
             self.memory[0xFF0F] |= 1 << 4;
@@ -614,6 +1095,18 @@ impl Memory {
         }
     }
 
+    fn track_read(&self, address: u16) {
+        if let Some(tracker) = &self.uninitialized_reads {
+            tracker.check_read(address);
+        }
+    }
+
+    fn track_write(&mut self, address: u16) {
+        if let Some(tracker) = &mut self.uninitialized_reads {
+            tracker.record_write(address);
+        }
+    }
+
     fn disable_boot_rom(&mut self) {
         self.boot_rom = None;
     }
@@ -777,15 +1270,22 @@ impl LcdStatus<'_> {
         (*self.flags & 3).into()
     }
 
-    pub fn set_mode(&mut self, mode: LcdMode) -> bool {
+    pub fn set_mode(&mut self, mode: LcdMode) {
         *self.flags &= !0x03;
         *self.flags |= mode as u8;
-        match mode {
+    }
+
+    /// Whether any of the enabled STAT interrupt conditions holds
+    fn interrupt_line(&self) -> bool {
+        let mode_interrupt = match self.mode() {
             LcdMode::HBlank => self.mode0_hblank_interrupt_set(),
             LcdMode::VBlank => self.mode1_vblank_interrupt_set(),
             LcdMode::SearchingOAM => self.mode2_oam_interrupt_set(),
             LcdMode::TransferringDataToLcdController => false,
-        }
+        };
+        let lyc_interrupt = self.lyc_eq_ly_interrupt_set()
+                            && *self.flags & (1 << 2) != 0;
+        mode_interrupt || lyc_interrupt
     }
 }
 
@@ -799,27 +1299,68 @@ pub enum InterruptAddress {
     JOYPAD = 0x60,
 }
 
+/// A transfer of 160 bytes from XX00–XX9F to OAM
+///
+/// Each byte is read when it gets copied, so reads from the cartridge
+/// use the ROM and RAM banks mapped at that time.  As the CPU can only
+/// access HRAM during the transfer, it can't switch banks before the
+/// transfer has finished.
+#[derive(Serialize, Deserialize)]
 struct OamDmaTransfer {
     address: u16,
     pre_transfer_countdown: u8,
     active_transfer: bool,
+    /// Bus that the transfer reads from
+    bus: DmaBus,
+    /// Byte last read by the transfer, which is still on the bus
+    value: u8,
+}
+
+/// The buses of the DMG that OAM DMA can read from
+///
+/// While DMA uses one of them, the CPU reads the byte that DMA has put
+/// on it instead of the addressed one.  The other bus stays accessible.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum DmaBus {
+    /// Cartridge, WRAM and its echo
+    External,
+    /// VRAM
+    Video,
+}
+
+impl DmaBus {
+    fn of(address: u16) -> Option<Self> {
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xFDFF => Some(Self::External),
+            0x8000..=0x9FFF => Some(Self::Video),
+            _ => None,
+        }
+    }
 }
 
 impl OamDmaTransfer {
     fn new(upper_address: u8, restarting: bool) -> Self {
-        eprintln!("OAM transfer from {0:0>2X}00–{0:0>2X}9F requested.",
-                  upper_address);
+        log::debug!("OAM transfer from {0:0>2X}00–{0:0>2X}9F requested.",
+                    upper_address);
         // ECHO RAM, DMA, etc. remap to WRAM.
         let upper_address = if upper_address >= 0xE0 {
             upper_address - 0xE0 + 0xC0
         } else {
             upper_address
         };
+        let address = (upper_address as u16) << 8;
         Self {
-            address: (upper_address as u16) << 8,
-            // Countdown calibrated with Mooneye oam_dma_timing test ROM.
-            pre_transfer_countdown: 3,
+            address,
+            // The M-cycle after the write to 0xFF46 sets up the
+            // transfer, which copies the first byte and blocks OAM in
+            // the M-cycle after that.  Before the bus was stepped per
+            // memory access, the write seemed to happen at the start
+            // of its instruction, so the countdown had to be calibrated
+            // to 3 with the Mooneye oam_dma_timing test ROM.
+            pre_transfer_countdown: 1,
             active_transfer: restarting,
+            bus: DmaBus::of(address).unwrap(),
+            value: 0xFF,
         }
     }
 
@@ -829,14 +1370,13 @@ impl OamDmaTransfer {
     fn step(&mut self, memory: &mut Memory) -> bool {
         if self.pre_transfer_countdown > 0 {
             self.pre_transfer_countdown -= 1;
-            if self.pre_transfer_countdown == 0 {
-                self.active_transfer = true;
-            }
             return false;
         }
-        let value = memory.read8(self.address);
+        self.active_transfer = true;
+        self.value = memory.read8(self.address);
         let target = 0xFE00 | (self.address & 0xFF);
-        memory.write8(target, value);
+        // Unlike the CPU, DMA can write OAM while the PPU uses it.
+        memory.memory[target as usize] = self.value;
         self.address += 1;
         target >= 0xFE9F
     }
@@ -848,6 +1388,12 @@ impl OamDmaTransfer {
 
 trait DmaTestActive {
     fn is_active(&self) -> bool;
+
+    /// What the CPU reads instead of the given address while DMA uses
+    /// the bus or OAM, if anything
+    ///
+    /// Writes to such addresses are ignored.
+    fn conflict(&self, address: u16) -> Option<u8>;
 }
 
 impl DmaTestActive for Option<OamDmaTransfer> {
@@ -855,4 +1401,531 @@ impl DmaTestActive for Option<OamDmaTransfer> {
         self.as_ref().map(|dma| dma.is_active())
                      .unwrap_or(false)
     }
+
+    fn conflict(&self, address: u16) -> Option<u8> {
+        let dma = self.as_ref().filter(|dma| dma.is_active())?;
+        if (0xFE00..=0xFEFF).contains(&address) {
+            Some(0xFF)
+        } else if DmaBus::of(address) == Some(dma.bus) {
+            Some(dma.value)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::cartridge::{HeaderOverrides, MemoryControllerModel,
+                                     LOGO};
+    use crate::game_boy::infrared::Loopback;
+
+    const RIGHT: u8 = 0x01;
+    const DOWN: u8 = 0x08;
+    const A: u8 = 0x10;
+    const START: u8 = 0x80;
+
+    fn memory() -> Memory {
+        Memory::new(Cartridge::from_rom(vec![0; 0x8000]).unwrap(), None)
+    }
+
+    fn joypad_interrupt_requested(memory: &Memory) -> bool {
+        memory.read8(0xFF0F) & (1 << 4) != 0
+    }
+
+    #[test]
+    fn unmapped_addresses_read_open_bus() {
+        let mut memory = memory();
+        for address in [0xFF03, 0xFF08, 0xFF0E, 0xFF4C, 0xFF50, 0xFF56,
+                        0xFF7F, 0xA000, 0xBFFF] {
+            assert_eq!(memory.read8(address), 0xFF, "{:0>4X}", address);
+        }
+        assert_eq!(memory.read8(0xFEA0), 0x00);
+        memory.model = boot_rom::Model::CGB;
+        assert_eq!(memory.read8(0xFED7), 0xDD);
+        // Only the CGB has an IR port.
+        assert_eq!(memory.read8(0xFF56), 0x3E);
+    }
+
+    #[test]
+    fn watchpoints_report_matching_accesses() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut bus = MemoryBus::new(cartridge, [0; 0x100].into());
+        bus.add_watchpoint(Watchpoint::from_spec("w:C0A0").unwrap());
+        bus.add_watchpoint(Watchpoint::from_spec("r:FF80-FF8F").unwrap());
+        bus.write8(0xC0A0, 0x12);
+        bus.write8(0xC0A1, 0x34);
+        assert_eq!(bus.read8(0xC0A0), 0x12);
+        bus.write8(0xFF81, 0x56);
+        assert_eq!(bus.read16(0xFF80), 0x5600);
+        assert_eq!(bus.take_watch_hits(), [
+            WatchHit{address: 0xC0A0, value: 0x12, access: Access::Write},
+            WatchHit{address: 0xFF80, value: 0x00, access: Access::Read},
+            WatchHit{address: 0xFF81, value: 0x56, access: Access::Read},
+        ]);
+        assert!(bus.take_watch_hits().is_empty());
+        assert!(bus.remove_watchpoint(0).is_some());
+        assert!(bus.remove_watchpoint(0).is_some());
+        assert!(bus.watchpoints.is_none());
+    }
+
+    #[test]
+    fn serial_transfer_takes_eight_bit_clocks() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut bus = MemoryBus::new(cartridge, [0; 0x100].into());
+        bus.write8(0xFFFF, 0x08);
+        bus.write8(0xFF01, 0x42);
+        bus.write8(0xFF02, 0x81);
+        bus.step(4 * 512);
+        // Half of the byte has been replaced by the 1s of the
+        // disconnected link.
+        assert_eq!(bus.read8(0xFF01), 0x2F);
+        assert_eq!(bus.read8(0xFF02), 0xFF);
+        bus.step(4 * 512 - 4);
+        assert_eq!(bus.get_requested_interrupts(), 0);
+        bus.step(4);
+        assert_eq!(bus.read8(0xFF01), 0xFF);
+        assert_eq!(bus.read8(0xFF02), 0x7F);
+        assert_eq!(bus.get_requested_interrupts(), 0x08);
+    }
+
+    #[test]
+    fn palettes_can_be_read_back() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut bus = MemoryBus::new(cartridge, [0; 0x100].into());
+        bus.write8(0xFF47, 0xE4);
+        bus.write8(0xFF48, 0x1B);
+        bus.write8(0xFF49, 0xD2);
+        assert_eq!(bus.read8(0xFF47), 0xE4);
+        assert_eq!(bus.read8(0xFF48), 0x1B);
+        assert_eq!(bus.read8(0xFF49), 0xD2);
+        assert_eq!(u8::from(bus.obj_palette1()), 0xD2);
+    }
+
+    #[test]
+    fn no_row_selected_reads_all_buttons_unpressed() {
+        let mut memory = memory();
+        assert_eq!(memory.read8(0xFF00), 0xCF);
+        memory.write8(0xFF00, 0x30);
+        memory.set_key_presses(RIGHT | DOWN | A | START);
+        assert_eq!(memory.read8(0xFF00), 0xFF);
+    }
+
+    #[test]
+    fn direction_row() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x20);
+        memory.set_key_presses(RIGHT | DOWN | A);
+        assert_eq!(memory.read8(0xFF00), 0xE6);
+    }
+
+    #[test]
+    fn action_row() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x10);
+        memory.set_key_presses(RIGHT | A | START);
+        assert_eq!(memory.read8(0xFF00), 0xD6);
+    }
+
+    #[test]
+    fn both_rows_selected_combine_buttons() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x00);
+        memory.set_key_presses(RIGHT | START);
+        assert_eq!(memory.read8(0xFF00), 0xC6);
+    }
+
+    #[test]
+    fn button_bits_are_read_only() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x30);
+        assert_eq!(memory.read8(0xFF00), 0xFF);
+        memory.write8(0xFF00, 0x20);
+        memory.set_key_presses(DOWN);
+        memory.write8(0xFF00, 0x2F);
+        assert_eq!(memory.read8(0xFF00), 0xE7);
+    }
+
+    #[test]
+    fn pressing_button_in_selected_row_requests_interrupt() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x20);
+        assert!(memory.set_key_presses(DOWN));
+        assert!(joypad_interrupt_requested(&memory));
+    }
+
+    #[test]
+    fn pressing_button_in_unselected_row_requests_no_interrupt() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x20);
+        assert!(!memory.set_key_presses(A));
+        assert!(!joypad_interrupt_requested(&memory));
+    }
+
+    #[test]
+    fn selecting_row_of_pressed_button_requests_interrupt() {
+        let mut memory = memory();
+        memory.write8(0xFF00, 0x30);
+        memory.set_key_presses(A);
+        assert!(!joypad_interrupt_requested(&memory));
+        memory.write8(0xFF00, 0x10);
+        assert!(joypad_interrupt_requested(&memory));
+    }
+
+    /// An MBC1 cartridge with 8 ROM banks, filled with their bank
+    /// number, and 4 RAM banks
+    fn mbc1_memory_bus() -> MemoryBus {
+        let mut rom = vec![0; 8 * 0x4000];
+        for (bank, data) in rom.chunks_exact_mut(0x4000).enumerate() {
+            data.fill(bank as u8);
+        }
+        rom[0x0147] = 0x03;  // MBC1+RAM+BATTERY
+        rom[0x0148] = 0x02;  // 128KB ROM
+        rom[0x0149] = 0x03;  // 32KB RAM
+        MemoryBus::new(Cartridge::from_rom(rom).unwrap(), [0; 0x100].into())
+    }
+
+    #[test]
+    fn unsupported_cartridges_are_rejected() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0xFE;  // HuC3
+        let error = Cartridge::from_rom(rom.clone()).err().unwrap();
+        assert_eq!(error.to_string(), "unsupported mapper HuC3");
+        rom[0x0147] = 0x01;  // MBC1
+        rom[0x0149] = 0x06;
+        let error = Cartridge::from_rom(rom).err().unwrap();
+        assert_eq!(error.to_string(), "unknown RAM size 06");
+    }
+
+    #[test]
+    fn two_kib_cartridge_ram_is_mirrored() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x03;  // MBC1+RAM+BATTERY
+        rom[0x0149] = 0x01;  // 2KB RAM
+        let mut bus = MemoryBus::new(Cartridge::from_rom(rom).unwrap(),
+                                     [0; 0x100].into());
+        assert_eq!(bus.cartridge().ram().len(), 0x800);
+        bus.write8(0x0000, 0x0A);
+        bus.write8(0xA123, 0x42);
+        assert_eq!(bus.read8(0xA923), 0x42);
+        assert_eq!(bus.read8(0xB923), 0x42);
+    }
+
+    #[test]
+    fn header_overrides_turn_rom_only_into_mbc5() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x00;  // ROM ONLY
+        rom[0x0148] = 0x01;  // 64KB ROM
+        rom[0x0149] = 0x00;  // No RAM
+        rom[0x8000] = 0x22;
+        let overrides = HeaderOverrides{
+            memory_controller: Some(MemoryControllerModel::MBC5),
+            ram_size: Some(0x2000),
+        };
+        let cartridge = Cartridge::from_rom(rom).unwrap()
+                                  .with_header_overrides(&overrides).unwrap();
+        assert!(cartridge.has_battery());
+        assert!(cartridge.header().is_header_checksum_correct());
+        let mut bus = MemoryBus::new(cartridge, [0; 0x100].into());
+        bus.write8(0x2000, 0x02);
+        assert_eq!(bus.read8(0x4000), 0x22);
+        bus.write8(0x0000, 0x0A);
+        bus.write8(0xA000, 0x42);
+        assert_eq!(bus.read8(0xA000), 0x42);
+    }
+
+    #[test]
+    fn huc1_switches_between_ram_and_infrared() {
+        let mut rom = vec![0; 0x20000];
+        rom[0x0147] = 0xFF;  // HuC1+RAM+BATTERY
+        rom[0x0148] = 0x02;  // 128KB ROM
+        rom[0x0149] = 0x03;  // 32KB RAM
+        let mut bus = MemoryBus::new(Cartridge::from_rom(rom).unwrap(),
+                                     [0; 0x100].into());
+        bus.connect_infrared_device(Box::new(Loopback::default()));
+        bus.write8(0x4000, 0x02);
+        bus.write8(0xA000, 0x42);
+        bus.write8(0x0000, 0x0E);
+        assert_eq!(bus.read8(0xA000), 0xC0);
+        bus.write8(0xA000, 0x01);
+        assert_eq!(bus.read8(0xA000), 0xC1);
+        bus.write8(0x0000, 0x00);
+        assert_eq!(bus.read8(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mmm01_maps_game_chosen_by_menu() {
+        let mut rom = vec![0; 8 * 0x4000];
+        for (bank, data) in rom.chunks_exact_mut(0x4000).enumerate() {
+            data.fill(bank as u8);
+        }
+        let menu = &mut rom[6 * 0x4000..];
+        menu[0x0104..0x0134].copy_from_slice(&LOGO);
+        menu[0x0147] = 0x0B;  // MMM01
+        menu[0x0148] = 0x02;  // 128KB ROM
+        menu[0x0149] = 0x00;  // no RAM
+        let mut bus = MemoryBus::new(Cartridge::from_rom(rom).unwrap(),
+                                     [0; 0x100].into());
+        assert_eq!([bus.read8(0x0200), bus.read8(0x4000)], [0x06, 0x07]);
+        // Map the 32 KiB game in banks 2 and 3.
+        bus.write8(0x2000, 0x02);
+        bus.write8(0x6000, 0x3C);
+        bus.write8(0x0000, 0x40);
+        assert_eq!([bus.read8(0x0200), bus.read8(0x4000)], [0x02, 0x03]);
+        bus.write8(0x2000, 0x04);
+        assert_eq!(bus.read8(0x4000), 0x03);
+        bus.write8(0x6000, 0x00);
+        bus.write8(0x2000, 0x00);
+        assert_eq!([bus.read8(0x0200), bus.read8(0x4000)], [0x02, 0x03]);
+    }
+
+    #[test]
+    fn mbc6_switches_halves_of_rom_and_ram_separately() {
+        let mut rom = vec![0; 0x20000];
+        for (bank, data) in rom.chunks_exact_mut(0x2000).enumerate() {
+            data.fill(bank as u8);
+        }
+        rom[0x0147] = 0x20;  // MBC6
+        rom[0x0148] = 0x02;  // 128KB ROM
+        rom[0x0149] = 0x03;  // 32KB RAM
+        let mut bus = MemoryBus::new(Cartridge::from_rom(rom).unwrap(),
+                                     [0; 0x100].into());
+        bus.write8(0x2000, 0x05);
+        bus.write8(0x3000, 0x0C);
+        assert_eq!([bus.read8(0x4000), bus.read8(0x7FFF)], [0x05, 0x0C]);
+        bus.write8(0x3800, 0x08);
+        assert_eq!(bus.read8(0x6000), 0xFF);
+        bus.write8(0x0000, 0x0A);
+        bus.write8(0x0400, 0x03);
+        bus.write8(0x0800, 0x03);
+        bus.write8(0xA123, 0x42);
+        assert_eq!(bus.read8(0xB123), 0x42);
+        bus.write8(0x0800, 0x04);
+        assert_eq!(bus.read8(0xB123), 0x00);
+    }
+
+    #[test]
+    fn mbc7_latches_tilt_after_erasing() {
+        let mut rom = vec![0; 0x20000];
+        rom[0x0147] = 0x22;  // MBC7+SENSOR+RUMBLE+RAM+BATTERY
+        rom[0x0148] = 0x02;  // 128KB ROM
+        let mut bus = MemoryBus::new(Cartridge::from_rom(rom).unwrap(),
+                                     [0; 0x100].into());
+        bus.write8(0x0000, 0x0A);
+        bus.write8(0x4000, 0x40);
+        bus.set_tilt((1.0, -0.5));
+        bus.write8(0xA010, 0xAA);
+        assert_eq!([bus.read8(0xA020), bus.read8(0xA030)], [0x00, 0x80]);
+        bus.write8(0xA000, 0x55);
+        bus.write8(0xA010, 0xAA);
+        // X: 0x81D0 - 0x70, Y: 0x81D0 + 0x38
+        assert_eq!([bus.read8(0xA020), bus.read8(0xA030),
+                    bus.read8(0xA040), bus.read8(0xA050)],
+                   [0x60, 0x81, 0x08, 0x82]);
+    }
+
+    fn run_oam_dma(bus: &mut MemoryBus, upper_address: u8) {
+        bus.write8(0xFF46, upper_address);
+        // 160 bytes plus the delay before the transfer starts
+        bus.step(4 * 170);
+    }
+
+    fn oam(bus: &MemoryBus) -> Vec<u8> {
+        (0xFE00..0xFEA0).map(|address| bus.read8(address)).collect()
+    }
+
+    #[test]
+    fn oam_dma_starts_two_m_cycles_after_the_write() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x8000, 0x99);
+        bus.poke8(0xFE00, 0x42);
+        bus.write8(0xFF46, 0x80);
+        assert_eq!(bus.read8(0xFE00), 0x42);
+        // Set-up cycle
+        bus.step(4);
+        assert_eq!(bus.read8(0xFE00), 0x42);
+        assert_eq!(bus.peek8(0xFE00), 0x42);
+        // The first byte is copied.
+        bus.step(4);
+        assert_eq!(bus.read8(0xFE00), 0xFF);
+        assert_eq!(bus.peek8(0xFE00), 0x99);
+        assert_eq!(bus.peek8(0xFE01), 0x00);
+        bus.step(4);
+        assert_eq!(bus.peek8(0xFE01), bus.peek8(0x8001));
+    }
+
+    #[test]
+    fn oam_dma_from_switchable_rom_bank() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x2000, 0x05);
+        run_oam_dma(&mut bus, 0x40);
+        assert_eq!(oam(&bus), vec![0x05; 0xA0]);
+    }
+
+    #[test]
+    fn oam_dma_from_banked_cartridge_ram() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x0000, 0x0A);  // enable RAM
+        bus.write8(0x6000, 0x01);  // RAM banking mode
+        for bank in 0..4 {
+            bus.write8(0x4000, bank);
+            for i in 0..0xA0 {
+                bus.write8(0xA000 + i, bank << 4 | (i as u8 & 0x0F));
+            }
+        }
+        bus.write8(0x4000, 0x02);
+        run_oam_dma(&mut bus, 0xA0);
+        let expected: Vec<u8> = (0..0xA0).map(|i| 0x20 | (i & 0x0F))
+                                         .collect();
+        assert_eq!(oam(&bus), expected);
+    }
+
+    #[test]
+    fn oam_dma_from_disabled_cartridge_ram_reads_ff() {
+        let mut bus = mbc1_memory_bus();
+        run_oam_dma(&mut bus, 0xA0);
+        assert_eq!(oam(&bus), vec![0xFF; 0xA0]);
+    }
+
+    #[test]
+    fn bank_switch_during_oam_dma_is_ignored() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x2000, 0x03);
+        bus.write8(0xFF46, 0x40);
+        bus.step(4 * 40);
+        // The CPU can only access HRAM during the transfer.
+        bus.write8(0x2000, 0x06);
+        bus.step(4 * 130);
+        assert_eq!(oam(&bus), vec![0x03; 0xA0]);
+        assert_eq!(bus.read8(0x4000), 0x03);
+    }
+
+    #[test]
+    fn oam_dma_only_blocks_the_bus_it_reads_from() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x2000, 0x05);
+        bus.write8(0x8000, 0x12);
+        bus.write8(0xC000, 0x34);
+        bus.write8(0xFF46, 0x40);
+        bus.step(4 * 10);
+        // ROM and WRAM share the bus with the byte DMA has just read.
+        assert_eq!(bus.read8(0xC000), 0x05);
+        assert_eq!(bus.read8(0x8000), 0x12);
+        assert_eq!(bus.read8(0xFE00), 0xFF);
+        assert_eq!(bus.read8(0xFF46), 0x40);
+        bus.write8(0x8001, 0x56);
+        bus.write8(0xC001, 0x78);
+        bus.step(4 * 160);
+        assert_eq!(bus.read8(0x8001), 0x56);
+        assert_eq!(bus.read8(0xC001), 0x00);
+
+        bus.write8(0xFF46, 0x80);
+        bus.step(4 * 10);
+        assert_eq!(bus.read8(0x9000), 0x00);
+        assert_eq!(bus.read8(0xC000), 0x34);
+    }
+
+    #[test]
+    fn ppu_blocks_cpu_access_to_vram_and_oam() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x8000, 0x12);
+        bus.write8(0xFE00, 0x34);
+        bus.write8(0xFF40, 0x91);
+        bus.set_lcd_mode(LcdMode::SearchingOAM);
+        assert_eq!(bus.read8(0x8000), 0x12);
+        assert_eq!(bus.read8(0xFE00), 0xFF);
+        bus.write8(0xFE00, 0x56);
+        bus.set_lcd_mode(LcdMode::TransferringDataToLcdController);
+        assert_eq!(bus.read8(0x8000), 0xFF);
+        bus.write8(0x8000, 0x78);
+        // The PPU itself still sees the memory.
+        assert_eq!(bus.ppu_read8(0x8000), 0x12);
+        bus.set_lcd_mode(LcdMode::HBlank);
+        assert_eq!(bus.read8(0x8000), 0x12);
+        assert_eq!(bus.read8(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn peek_and_poke_are_not_blocked() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x2000, 0x05);
+        bus.write8(0xC000, 0x34);
+        bus.write8(0xFF40, 0x91);
+        bus.set_lcd_mode(LcdMode::TransferringDataToLcdController);
+        bus.write8(0xFF46, 0x40);
+        bus.step(4 * 10);
+        assert_eq!(bus.read8(0xC000), 0x05);
+        assert_eq!(bus.peek8(0xC000), 0x34);
+        assert_eq!(bus.peek8(0xE000), 0x34);
+        bus.poke8(0xC001, 0x78);
+        bus.poke8(0x8000, 0x12);
+        bus.poke8(0xFE00, 0x56);
+        assert_eq!(bus.read8(0x8000), 0xFF);
+        assert_eq!([bus.peek8(0xC001), bus.peek8(0x8000), bus.peek8(0xFE00)],
+                   [0x78, 0x12, 0x56]);
+    }
+
+    #[test]
+    fn oam_dma_writes_oam_while_blocked() {
+        let mut bus = mbc1_memory_bus();
+        bus.write8(0x2000, 0x05);
+        bus.write8(0xFF40, 0x91);
+        bus.set_lcd_mode(LcdMode::SearchingOAM);
+        run_oam_dma(&mut bus, 0x40);
+        bus.set_lcd_mode(LcdMode::HBlank);
+        assert_eq!(oam(&bus), vec![0x05; 0xA0]);
+    }
+
+    fn stat_interrupt_requested(bus: &mut MemoryBus) -> bool {
+        let requested = bus.read8(0xFF0F) & 2 != 0;
+        bus.write8(0xFF0F, 0);
+        requested
+    }
+
+    #[test]
+    fn stat_interrupt_conditions_share_one_line() {
+        let mut bus = mbc1_memory_bus();
+        // Enable the LYC=LY and HBlank interrupts.
+        bus.write8(0xFF41, 0x48);
+        bus.write8(0xFF45, 5);
+        bus.set_ly(5);
+        bus.set_lcd_mode(LcdMode::SearchingOAM);
+        assert!(stat_interrupt_requested(&mut bus));
+        bus.set_lcd_mode(LcdMode::TransferringDataToLcdController);
+        // The line is still high due to LYC=LY.
+        bus.set_lcd_mode(LcdMode::HBlank);
+        assert!(!stat_interrupt_requested(&mut bus));
+        // HBlank keeps the line high into the next line.
+        bus.set_ly(6);
+        bus.set_lcd_mode(LcdMode::SearchingOAM);
+        assert!(!stat_interrupt_requested(&mut bus));
+        bus.set_lcd_mode(LcdMode::TransferringDataToLcdController);
+        bus.set_lcd_mode(LcdMode::HBlank);
+        assert!(stat_interrupt_requested(&mut bus));
+        // Writing LYC can raise the line as well.
+        bus.set_lcd_mode(LcdMode::SearchingOAM);
+        bus.write8(0xFF45, 6);
+        assert!(stat_interrupt_requested(&mut bus));
+    }
+
+    #[test]
+    fn writing_stat_on_dmg_requests_interrupt() {
+        for (model, requested) in [(boot_rom::Model::DMG, true),
+                                   (boot_rom::Model::CGB, false)] {
+            let mut bus = mbc1_memory_bus();
+            bus.set_model(model);
+            bus.set_lcd_mode(LcdMode::HBlank);
+            bus.write8(0xFF41, 0x00);
+            assert_eq!(stat_interrupt_requested(&mut bus), requested);
+            bus.write8(0xFF41, 0x08);
+            assert!(stat_interrupt_requested(&mut bus));
+            // The line is already high.
+            bus.write8(0xFF41, 0x08);
+            assert!(!stat_interrupt_requested(&mut bus));
+            bus.set_lcd_mode(LcdMode::TransferringDataToLcdController);
+            bus.write8(0xFF41, 0x00);
+            assert!(!stat_interrupt_requested(&mut bus));
+        }
+    }
 }
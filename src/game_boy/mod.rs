@@ -2,33 +2,145 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub mod accuracy;
+pub mod apu;
+pub mod audio;
+#[cfg(feature = "sound")]
+pub mod audio_device;
 pub mod boot_rom;
+pub mod camera;
 pub mod cartridge;
+pub mod colorization;
+#[cfg(feature = "window")]
 pub mod commandline;
+pub mod compat;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
+pub mod determinism;
 pub mod display;
+pub mod eeprom;
+#[cfg(feature = "window")]
 pub mod emulator_window;
+pub mod flags;
+#[cfg(feature = "wgpu")]
+pub mod gpu_window;
 pub mod graphics_data;
+pub mod infrared;
+pub mod input_display;
 pub mod io;
+pub mod link;
 pub mod memory;
+pub mod movie;
 pub mod ppu;
+pub mod profiler;
+pub mod recording;
+pub mod rewind;
+pub mod rom_database;
+pub mod scripting;
+pub mod rtc;
+pub mod save_state;
+pub mod serial;
+pub mod state_dump;
+pub mod sram;
+pub mod sgb;
+pub mod symbols;
+pub mod terminal;
+pub mod threaded;
+pub mod tile_cache;
 pub mod timer;
+pub mod trace_diff;
+pub mod uninitialized;
+pub mod vgm;
+pub mod viewer;
+pub mod web;
 
+use std::error;
+use std::fmt;
 use std::fs::File;
-use std::time::Instant;
-use std::thread::sleep;
+use std::io::{BufWriter, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use crate::frame_pacer::FramePacer;
 
 // TODO
 const FRAMERATE: usize = 60;
 const CPU_CYCLES_PER_SECOND: usize = 4_194_304;
 const CPU_CYCLES_PER_FRAME:  usize = CPU_CYCLES_PER_SECOND / FRAMERATE;
 const CPU_CYCLES_PER_SCANLINE: usize = CPU_CYCLES_PER_FRAME / 154;
+/// Frames between writes of changed cartridge RAM into the save file
+const SAVE_FILE_FLUSH_FRAMES: u64 = 5 * FRAMERATE as u64;
+/// Frames between the states kept for rewinding
+const REWIND_INTERVAL_FRAMES: u64 = 2;
 
 pub struct GameBoy<Window: io::IO> {
     cpu: cpu::CPU,
     ppu: ppu::PPU,
     memory: memory::MemoryBus,
     emulator_window: Window,
+    audio_policy: audio::AudioPolicy,
+    audio_buffer: Vec<i16>,
+    audio_sinks: Vec<audio::ResampledSink>,
+    /// Plays the audio adapted to the emulation speed like the frontend
+    playback: Option<audio::ResampledSink>,
+    /// CPU cycles spent in the current scanline
+    scanline_cycles: usize,
+    /// Number of frames emulated so far
+    frame: u64,
+    tracks_uninitialized_reads: bool,
+    strictness: accuracy::Strictness,
+    /// JoyPad buttons as last passed to the game
+    pressed_keys: u8,
+    /// Buttons held by the script in the current frame
+    script_keys: u8,
+    /// Buttons pressed through `set_buttons`
+    held_buttons: io::Buttons,
+    shows_input_overlay: bool,
+    shows_vram_viewer: bool,
+    logs_input: bool,
+    /// Copy of the screen to draw the input overlay into
+    overlay_buffer: Vec<u8>,
+    /// Super Game Boy frame including the border
+    sgb_frame: Vec<u32>,
+    frame_pacer: FramePacer,
+    /// File that keeps the RAM of a cartridge with battery
+    save_file: Option<PathBuf>,
+    /// ROM file next to which the save state slots are kept
+    state_slots: Option<PathBuf>,
+    /// Slot that the save state hotkeys write and read
+    state_slot: u8,
+    rewind_buffer: Option<rewind::RewindBuffer>,
+    /// Whether the rewind hotkey is held
+    rewinding: bool,
+    paused: bool,
+    /// Whether to emulate the next frame although paused
+    advancing_frame: bool,
+    movie_recorder: Option<movie::MovieRecorder<Box<dyn Write + Send>>>,
+    /// Inputs of the movie being played, in reverse order
+    movie_inputs: Option<Vec<movie::FrameInput>>,
+    frame_hashes: Option<determinism::FrameHashes>,
+    debugger: Option<debugger::Debugger>,
+    /// Whether the debugger has been told to stop the emulation
+    quit_requested: bool,
+    /// Number of frames after which `run` stops, see `quit_after`
+    frame_limit: Option<u64>,
+    script: Option<scripting::Script>,
+    profiler: Option<profiler::Profiler>,
+    coverage: Option<coverage::Coverage>,
+    /// Registers when the CPU last executed LD B,B
+    magic_breakpoint: Option<cpu::CpuState>,
+    trace_divergence: Option<trace_diff::Divergence>,
+    /// ROM file next to which the VRAM dumps are written, and their colors
+    vram_dumps: Option<(PathBuf, [u32; 12])>,
+    /// Frames after which the VRAM is dumped
+    vram_dump_frames: Vec<u64>,
+    /// ROM file next to which clips are written, their colors and format
+    clips: Option<(PathBuf, [u32; 12], recording::ClipFormat)>,
+    /// Clip being recorded with the frame in which it started
+    clip_recorder: Option<(u64, recording::ClipRecorder)>,
+    /// File into which `run` writes a `state_dump::StateDump` at its end
+    state_dump_file: Option<PathBuf>,
 }
 
 impl<Window: io::IO> GameBoy<Window> {
@@ -36,7 +148,7 @@ impl<Window: io::IO> GameBoy<Window> {
         GameBoyBuilder::new()
     }
 
-    pub fn new(boot_rom: [u8;0x100],
+    pub fn new(boot_rom: boot_rom::BootRom,
                cartridge: cartridge::Cartridge,
                window: Window) -> Self {
         let memory = memory::MemoryBus::new(cartridge, boot_rom);
@@ -45,126 +157,1565 @@ impl<Window: io::IO> GameBoy<Window> {
             ppu: ppu::PPU::new(),
             memory,
             emulator_window: window,
+            audio_policy: audio::AudioPolicy::default(),
+            audio_buffer: Vec::new(),
+            audio_sinks: Vec::new(),
+            playback: None,
+            scanline_cycles: 0,
+            frame: 0,
+            tracks_uninitialized_reads: false,
+            strictness: accuracy::Strictness::Lenient,
+            pressed_keys: 0,
+            script_keys: 0,
+            held_buttons: io::Buttons::empty(),
+            shows_input_overlay: false,
+            shows_vram_viewer: false,
+            logs_input: false,
+            overlay_buffer: Vec::new(),
+            sgb_frame: Vec::new(),
+            frame_pacer: FramePacer::new(FRAMERATE as f64),
+            save_file: None,
+            state_slots: None,
+            state_slot: 0,
+            rewind_buffer: None,
+            rewinding: false,
+            paused: false,
+            advancing_frame: false,
+            movie_recorder: None,
+            movie_inputs: None,
+            frame_hashes: None,
+            debugger: None,
+            quit_requested: false,
+            frame_limit: None,
+            script: None,
+            profiler: None,
+            coverage: None,
+            magic_breakpoint: None,
+            trace_divergence: None,
+            vram_dumps: None,
+            vram_dump_frames: Vec::new(),
+            clips: None,
+            clip_recorder: None,
+            state_dump_file: None,
         }
     }
 
-    pub fn run(&mut self) {
-        use std::time::Duration;
-        let frame_time = Duration::from_micros((1_000_000. / FRAMERATE as f64)
-                                               as u64);
-        let mut last_frame_time = Instant::now();
-        let mut scanline_cycles = 0;
-        loop {
-            for scanline in 0..144 {
-                self.memory.set_ly(scanline);
-                self.memory.set_lcd_mode(ppu::LcdMode::SearchingOAM);
-                while scanline_cycles <= 80 {
-                    scanline_cycles += self.step();
+    /// Create a Game Boy that starts directly at the cartridge's
+    /// entry point 0x0100 without running any boot ROM
+    ///
+    /// CPU, I/O registers and VRAM are set up as the boot ROM of the
+    /// given model would have left them.
+    pub fn with_hle_boot(model: boot_rom::Model,
+                         cartridge: cartridge::Cartridge,
+                         window: Window) -> Self {
+        let registers = boot_rom::post_boot_cpu_registers(model,
+                                                          &cartridge.header());
+        let memory = memory::MemoryBus::with_hle_boot(cartridge, model);
+        Self {
+            cpu: cpu::CPU::with_hle_boot(&registers),
+            ppu: ppu::PPU::new(),
+            memory,
+            emulator_window: window,
+            audio_policy: audio::AudioPolicy::default(),
+            audio_buffer: Vec::new(),
+            audio_sinks: Vec::new(),
+            playback: None,
+            scanline_cycles: 0,
+            frame: 0,
+            tracks_uninitialized_reads: false,
+            strictness: accuracy::Strictness::Lenient,
+            pressed_keys: 0,
+            script_keys: 0,
+            held_buttons: io::Buttons::empty(),
+            shows_input_overlay: false,
+            shows_vram_viewer: false,
+            logs_input: false,
+            overlay_buffer: Vec::new(),
+            sgb_frame: Vec::new(),
+            frame_pacer: FramePacer::new(FRAMERATE as f64),
+            save_file: None,
+            state_slots: None,
+            state_slot: 0,
+            rewind_buffer: None,
+            rewinding: false,
+            paused: false,
+            advancing_frame: false,
+            movie_recorder: None,
+            movie_inputs: None,
+            frame_hashes: None,
+            debugger: None,
+            quit_requested: false,
+            frame_limit: None,
+            script: None,
+            profiler: None,
+            coverage: None,
+            magic_breakpoint: None,
+            trace_divergence: None,
+            vram_dumps: None,
+            vram_dump_frames: Vec::new(),
+            clips: None,
+            clip_recorder: None,
+            state_dump_file: None,
+        }
+    }
+
+    /// Replace the frontend of a running Game Boy
+    ///
+    /// Return the previously used frontend.
+    pub fn replace_emulator_window(&mut self, window: Window) -> Window {
+        std::mem::replace(&mut self.emulator_window, window)
+    }
+
+    /// Keep the RAM of a cartridge with battery in a `.sav` file
+    ///
+    /// The RAM is loaded from the file if it exists, and
+    /// `write_save_file` writes it back.  Cartridges without battery
+    /// don't use the file.
+    pub fn use_save_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let cartridge = self.memory.cartridge_mut();
+        if !cartridge.has_battery() {
+            return Ok(());
+        }
+        match File::open(&path) {
+            Ok(file) => {
+                cartridge.load_ram(file)?;
+                log::info!("Loaded save file {}.", path.display());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.save_file = Some(path);
+        Ok(())
+    }
+
+    /// Keep the RAM of a cartridge with battery in a memory-mapped `.sav`
+    ///
+    /// Unlike with `use_save_file`, every write to the RAM goes straight
+    /// into the file, which is created if it doesn't exist.
+    /// `write_save_file` only waits for the changes to be written back.
+    pub fn map_save_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let cartridge = self.memory.cartridge_mut();
+        if !cartridge.has_battery() {
+            return Ok(());
+        }
+        let file = std::fs::OpenOptions::new().read(true)
+                                              .write(true)
+                                              .create(true)
+                                              .truncate(false)
+                                              .open(&path)?;
+        cartridge.map_ram(&file)?;
+        log::info!("Mapped save file {}.", path.display());
+        Ok(())
+    }
+
+    /// Write the cartridge RAM into the file passed to `use_save_file`
+    ///
+    /// The RAM is written into a temporary file first, so that the save
+    /// file stays intact if writing fails.  A RAM mapped by
+    /// `map_save_file` is flushed instead.
+    pub fn write_save_file(&self) -> std::io::Result<()> {
+        self.memory.cartridge().flush_ram()?;
+        if let Some(path) = &self.save_file {
+            let temporary = path.with_extension("sav.tmp");
+            std::fs::write(&temporary, self.memory.cartridge().ram())?;
+            std::fs::rename(&temporary, path)?;
+        }
+        Ok(())
+    }
+
+    /// Save the state of the whole machine
+    ///
+    /// The state contains the CPU, the PPU and everything on the memory
+    /// bus, including the cartridge RAM and the state of its memory
+    /// controller, but neither the cartridge ROM nor the devices
+    /// connected to the Game Boy.  It starts with a `StateInfo`, which
+    /// holds a thumbnail of the screen.
+    pub fn save_state(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        save_state::StateInfo::new(self.screen(), self.frame)?
+            .write(writer)?;
+        self.write_machine_state(writer)
+    }
+
+    fn write_machine_state(&self, writer: &mut impl Write)
+            -> std::io::Result<()> {
+        let state = (self.cartridge_id(), &self.cpu, &self.ppu, &self.memory,
+                     self.scanline_cycles, self.frame, self.pressed_keys);
+        bincode::serialize_into(writer, &state)
+            .map_err(|e| save_state::bincode_error(*e))
+    }
+
+    /// Restore a state written by `save_state`
+    ///
+    /// This fails without changing the Game Boy if the state is of a
+    /// different cartridge.
+    pub fn load_state(&mut self, reader: &mut impl Read)
+            -> std::io::Result<()> {
+        save_state::StateInfo::read(reader)?;
+        self.read_machine_state(reader)
+    }
+
+    fn read_machine_state(&mut self, reader: &mut impl Read)
+            -> std::io::Result<()> {
+        let (cartridge_id, cpu, ppu, memory, scanline_cycles, frame,
+             pressed_keys): ([u8; 0x1C], cpu::CPU, ppu::PPU,
+                             memory::MemoryBus, usize, u64, u8)
+            = bincode::deserialize_from(reader)
+                  .map_err(|e| save_state::bincode_error(*e))?;
+        if cartridge_id != self.cartridge_id() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "save state is of a different cartridge"));
+        }
+        if self.movie_recorder.is_some() {
+            log::warn!("The recorded movie won't repeat the loaded state.");
+        }
+        self.cpu.restore_state(cpu);
+        self.ppu.restore_state(ppu);
+        self.memory.restore_state(memory);
+        self.scanline_cycles = scanline_cycles;
+        self.frame = frame;
+        self.pressed_keys = pressed_keys;
+        Ok(())
+    }
+
+    /// Cartridge header from the title up to the checksums, which tells
+    /// which cartridge a save state belongs to
+    fn cartridge_id(&self) -> [u8; 0x1C] {
+        self.memory.cartridge().rom()[0x134..0x150].try_into().unwrap()
+    }
+
+    /// Save and load the state with the hotkeys in slots next to the
+    /// given ROM file
+    ///
+    /// See `save_state::slot_path` for the file names.
+    pub fn use_state_slots(&mut self, rom_file: PathBuf) {
+        self.state_slots = Some(rom_file);
+    }
+
+    fn state_file(&self) -> Option<PathBuf> {
+        self.state_slots.as_ref().map(|rom_file| {
+            save_state::slot_path(rom_file, self.state_slot)
+        })
+    }
+
+    fn write_state_file(&self) -> std::io::Result<()> {
+        if let Some(path) = self.state_file() {
+            let temporary = path.with_extension("state.tmp");
+            let mut file = BufWriter::new(File::create(&temporary)?);
+            self.save_state(&mut file)?;
+            file.into_inner()?.sync_all()?;
+            std::fs::rename(&temporary, &path)?;
+            log::info!("Saved state to {}.", path.display());
+        }
+        Ok(())
+    }
+
+    fn read_state_file(&mut self) -> std::io::Result<()> {
+        if let Some(path) = self.state_file() {
+            let mut file = std::io::BufReader::new(File::open(&path)?);
+            self.load_state(&mut file)?;
+            log::info!("Loaded state from {}.", path.display());
+        }
+        Ok(())
+    }
+
+    /// Write registers, I/O registers, timer and memory controller as
+    /// JSON, see `state_dump::StateDump`
+    pub fn dump_state(&self, writer: impl Write) -> std::io::Result<()> {
+        state_dump::StateDump::new(self.frame, self.cpu.state(), &self.memory)
+            .write_json(writer)
+    }
+
+    /// Dump the state into the given file when `run` ends, even by a
+    /// panic
+    pub fn dump_state_on_exit(&mut self, path: PathBuf) {
+        self.state_dump_file = Some(path);
+    }
+
+    fn write_state_dump_file(&self) -> std::io::Result<()> {
+        if let Some(path) = &self.state_dump_file {
+            self.dump_state(BufWriter::new(File::create(path)?))?;
+            log::info!("Dumped the state into {}.", path.display());
+        }
+        Ok(())
+    }
+
+    /// Keep the states of the last given seconds to rewind to them
+    ///
+    /// A state is kept every few frames while emulating, see `rewind`.
+    pub fn enable_rewind(&mut self, seconds: usize) {
+        let states = seconds * FRAMERATE / REWIND_INTERVAL_FRAMES as usize;
+        self.rewind_buffer = Some(rewind::RewindBuffer::new(states));
+    }
+
+    /// Go back to the newest state kept for rewinding and forget it
+    ///
+    /// Return whether there was such a state.
+    pub fn rewind(&mut self) -> bool {
+        let state = match self.rewind_buffer.as_mut()
+                                            .and_then(|buffer| buffer.pop()) {
+            Some(state) => state,
+            None => return false,
+        };
+        if let Err(e) = self.read_machine_state(&mut &state[..]) {
+            log::error!("Could not rewind: {}", e);
+            return false;
+        }
+        true
+    }
+
+    fn keep_rewind_state(&mut self) {
+        if self.rewind_buffer.is_none()
+                || !self.frame.is_multiple_of(REWIND_INTERVAL_FRAMES) {
+            return;
+        }
+        let mut state = Vec::new();
+        match self.write_machine_state(&mut state) {
+            Ok(()) => self.rewind_buffer.as_mut().unwrap().push(state),
+            Err(e) => log::error!("Could not keep state for rewinding: {}", e),
+        }
+    }
+
+    /// Record the input of every following frame into a movie
+    ///
+    /// The movie starts with the current state, so that `play_movie`
+    /// repeats the game exactly.
+    pub fn record_movie(&mut self, writer: Box<dyn Write + Send>)
+            -> std::io::Result<()> {
+        let mut state = Vec::new();
+        self.write_machine_state(&mut state)?;
+        self.movie_recorder = Some(movie::MovieRecorder::new(writer,
+                                                             &state)?);
+        Ok(())
+    }
+
+    /// End the movie started by `record_movie` and return its writer
+    pub fn stop_movie_recording(&mut self)
+            -> Option<std::io::Result<Box<dyn Write + Send>>> {
+        let recorder = self.movie_recorder.take()?;
+        log::info!("Recorded a movie of {} frames.", recorder.frames());
+        Some(recorder.finish())
+    }
+
+    /// Restore the state at the start of a movie recorded by
+    /// `record_movie` and take the input from the movie instead of the
+    /// frontend until it ends
+    pub fn play_movie(&mut self, reader: &mut impl Read)
+            -> std::io::Result<()> {
+        let movie = movie::Movie::read(reader)?;
+        self.read_machine_state(&mut &movie.initial_state[..])?;
+        log::info!("Playing a movie of {} frames.", movie.inputs.len());
+        let mut inputs = movie.inputs;
+        inputs.reverse();
+        self.movie_inputs = Some(inputs);
+        Ok(())
+    }
+
+    /// Input of the next frame from the movie being played or else from
+    /// the frontend
+    fn next_input(&mut self) -> movie::FrameInput {
+        if let Some(inputs) = &mut self.movie_inputs {
+            if let Some(input) = inputs.pop() {
+                return input;
+            }
+            log::info!("Movie ended in frame {}.", self.frame);
+            self.movie_inputs = None;
+        }
+        movie::FrameInput{
+            keys: self.emulator_window.get_key_presses()
+                  | self.held_buttons.bits(),
+            tilt: self.emulator_window.get_tilt(),
+        }
+    }
+
+    /// Hash of the screen, the CPU registers and the memory
+    ///
+    /// Unlike a save state, the hash doesn't depend on how the emulator
+    /// keeps the state internally, so that it stays the same across
+    /// refactorings that don't change the emulation.
+    pub fn frame_hash(&self) -> u64 {
+        let mut hasher = determinism::Fnv1a::default();
+        hasher.write(self.screen());
+        let cpu = self.cpu.state();
+        hasher.write(&[cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l,
+                       cpu.ime as u8, cpu.ime_scheduled as u8]);
+        hasher.write(&cpu.sp.to_le_bytes());
+        hasher.write(&cpu.pc.to_le_bytes());
+        hasher.write(self.memory.address_space());
+        hasher.write(self.memory.cartridge().ram());
+        hasher.finish()
+    }
+
+    /// Write the `frame_hash` of every following frame
+    pub fn write_frame_hashes(&mut self, output: Box<dyn Write + Send>) {
+        self.frame_hashes = Some(determinism::FrameHashes::write_to(output));
+    }
+
+    /// Compare the `frame_hash` of the following frames with those
+    /// written by `write_frame_hashes`
+    ///
+    /// `run` ends after the last frame of the reference.
+    pub fn compare_frame_hashes(&mut self, reference: impl std::io::BufRead)
+            -> std::io::Result<()> {
+        self.frame_hashes
+            = Some(determinism::FrameHashes::compare_with(reference)?);
+        Ok(())
+    }
+
+    /// Number of frames compared by `compare_frame_hashes` and how many
+    /// of them differed
+    pub fn frame_hash_mismatches(&self) -> Option<(usize, usize)> {
+        self.frame_hashes.as_ref()
+                         .map(|hashes| (hashes.compared(),
+                                        hashes.mismatches()))
+    }
+
+    fn add_frame_hash(&mut self) {
+        let hash = match self.frame_hashes {
+            Some(_) => self.frame_hash(),
+            None => return,
+        };
+        let hashes = self.frame_hashes.as_mut().unwrap();
+        if let Err(e) = hashes.add(self.frame, hash) {
+            log::error!("Could not check frame hash: {}", e);
+            self.frame_hashes = None;
+        }
+    }
+
+    /// Break into a debugger that reads commands from the given input
+    /// when a watchpoint is hit
+    pub fn attach_debugger(&mut self, debugger: debugger::Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    /// Break into the debugger when the CPU accesses the watched
+    /// addresses
+    ///
+    /// Without an attached debugger, one on stdin and stdout is used.
+    pub fn add_watchpoint(&mut self, watchpoint: debugger::Watchpoint) {
+        self.debugger.get_or_insert_with(debugger::Debugger::default);
+        self.memory.add_watchpoint(watchpoint);
+    }
+
+    /// Break into the debugger before the CPU executes the instruction
+    /// at the breakpoint's address and its condition holds
+    ///
+    /// Without an attached debugger, one on stdin and stdout is used.
+    pub fn add_breakpoint(&mut self, breakpoint: debugger::Breakpoint) {
+        self.debugger.get_or_insert_with(debugger::Debugger::default)
+                     .breakpoints
+                     .push(breakpoint);
+    }
+
+    /// Run the hooked functions of a script while the game runs
+    ///
+    /// This replaces any script loaded before.
+    pub fn load_script(&mut self, script: scripting::Script) {
+        self.memory.watch_for_script(script.watchpoints());
+        self.script = Some(script);
+    }
+
+    fn run_script(&mut self, trigger: scripting::Trigger) {
+        let script = self.script.as_mut().unwrap();
+        let effects = match script.run(&trigger, &self.cpu.state(),
+                                       self.memory.address_space(),
+                                       self.frame, self.pressed_keys) {
+            Ok(effects) => effects,
+            Err(e) => {
+                log::error!("Stopping script: {}", e);
+                self.memory.watch_for_script(Vec::new());
+                self.script = None;
+                return;
+            }
+        };
+        for effect in effects {
+            match effect {
+                scripting::Effect::Poke{address, value} => {
+                    self.memory.write8(address, value);
+                }
+                scripting::Effect::AddBreakpoint(breakpoint) => {
+                    self.add_breakpoint(breakpoint);
                 }
-                self.memory.set_lcd_mode(
-                    ppu::LcdMode::TransferringDataToLcdController);
-                // Approximate mode duration, it actually depends on number
-                // of objects to paint, etc.
-                self.ppu.paint_line(&mut self.memory);
-                while scanline_cycles <= 280 {
-                    scanline_cycles += self.step();
+                scripting::Effect::Break => self.break_into_debugger(),
+                scripting::Effect::Quit => self.quit_requested = true,
+            }
+        }
+    }
+
+    /// Break into the debugger after the next instruction
+    pub fn break_into_debugger(&mut self) {
+        self.debugger.get_or_insert_with(debugger::Debugger::default)
+                     .stepping = true;
+    }
+
+    fn handle_hotkeys(&mut self) {
+        self.rewinding = false;
+        for hotkey in self.emulator_window.get_hotkeys() {
+            let result = match hotkey {
+                io::Hotkey::SaveState => self.write_state_file(),
+                io::Hotkey::LoadState => self.read_state_file(),
+                io::Hotkey::SelectSlot(slot) => {
+                    self.state_slot = slot;
+                    log::info!("Selected save state slot {}.", slot);
+                    Ok(())
+                }
+                io::Hotkey::Rewind => {
+                    self.rewinding = true;
+                    Ok(())
+                }
+                io::Hotkey::DumpVram => self.write_vram_dumps(),
+                io::Hotkey::Pause => {
+                    self.paused = !self.paused;
+                    if self.paused {
+                        log::info!("Paused in frame {}.", self.frame);
+                    } else {
+                        log::info!("Continued.");
+                    }
+                    Ok(())
                 }
-                self.memory.set_lcd_mode(ppu::LcdMode::HBlank);
-                // TODO: This does not add up exactly, as we assume 60FPS
-                //       here, but it are actually slightly less.
-                while scanline_cycles < CPU_CYCLES_PER_SCANLINE {
-                    scanline_cycles += self.step();
+                io::Hotkey::FrameAdvance => {
+                    self.paused = true;
+                    self.advancing_frame = true;
+                    Ok(())
                 }
-                scanline_cycles %= CPU_CYCLES_PER_SCANLINE;
+                io::Hotkey::RecordClip => self.toggle_clip_recording(),
+            };
+            if let Err(e) = result {
+                log::error!("Could not {:?}: {}", hotkey, e);
             }
-            let current_frame_time = Instant::now();
-            let elapsed = current_frame_time.duration_since(last_frame_time);
-            if let Some(sleep_duration) = frame_time.checked_sub(elapsed) {
-                sleep(sleep_duration);
+        }
+    }
+
+    /// Write the save file if the cartridge RAM has been written since
+    /// the last flush
+    fn flush_save_file(&mut self) {
+        if self.memory.cartridge_mut().take_ram_written() {
+            if let Err(e) = self.write_save_file() {
+                log::error!("Could not write save file: {}", e);
             }
-            last_frame_time = current_frame_time;
-            self.ppu.refresh(&mut self.emulator_window);
-            scanline_cycles += self.check_key_presses();
-            if self.emulator_window.is_esc_pressed() {
-                break;
+        }
+    }
+
+    /// Report reads of never-written WRAM and HRAM bytes
+    ///
+    /// Every address is reported once on stderr, together with the
+    /// reading instruction and the frame number.
+    pub fn track_uninitialized_reads(&mut self) {
+        self.tracks_uninitialized_reads = true;
+        self.memory.track_uninitialized_reads();
+    }
+
+    /// All uninitialized reads found since calling
+    /// `track_uninitialized_reads`
+    pub fn uninitialized_reads(&self) -> &[uninitialized::UninitializedRead] {
+        self.memory.uninitialized_reads()
+    }
+
+    /// Describe addresses in logs and traces by the labels of a `.sym`
+    /// file
+    pub fn load_symbols(&mut self, symbols: symbols::SymbolTable) {
+        self.memory.load_symbols(symbols);
+    }
+
+    /// Count the cycles spent at each bank and address from now on,
+    /// see `profile_report`
+    pub fn start_profiling(&mut self) {
+        self.profiler.get_or_insert_with(profiler::Profiler::default);
+    }
+
+    /// Table of the given number of routines that took the most cycles
+    /// since `start_profiling`, named by the loaded symbols
+    pub fn profile_report(&self, count: usize) -> Option<String> {
+        let profiler = self.profiler.as_ref()?;
+        Some(profiler.report(self.memory.symbols(), count))
+    }
+
+    /// Record which bytes of the cartridge ROM get executed from now on
+    pub fn start_coverage(&mut self) {
+        let rom_size = self.memory.cartridge().rom().len();
+        self.coverage.get_or_insert_with(|| coverage::Coverage::new(rom_size));
+    }
+
+    /// ROM bytes executed since `start_coverage`
+    pub fn coverage(&self) -> Option<&coverage::Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Log the registers before each instruction in the format of
+    /// Game Boy Doctor, see `cpu::CPU::start_trace`
+    pub fn start_trace(&mut self, trace: Box<dyn std::io::Write + Send>) {
+        self.cpu.start_trace(trace);
+    }
+
+    /// Compare the registers before each instruction with a trace of
+    /// another emulator, see `trace_diff::parse_registers` for formats
+    ///
+    /// At the first difference, the emulation breaks into the debugger
+    /// if one is attached and stops otherwise.
+    pub fn compare_trace(&mut self,
+                         reference: Box<dyn std::io::BufRead + Send>) {
+        self.cpu.compare_trace(reference);
+    }
+
+    /// The first difference found by `compare_trace`
+    pub fn trace_divergence(&self) -> Option<&trace_diff::Divergence> {
+        self.trace_divergence.as_ref()
+    }
+
+    /// Report when the game runs into a known approximation of the
+    /// emulator
+    ///
+    /// Each kind of approximation is reported the first time it is hit,
+    /// together with the PC, LY and frame number.  With
+    /// `Strictness::Error`, emulation panics at the first one.
+    pub fn set_strictness(&mut self, strictness: accuracy::Strictness) {
+        self.strictness = strictness;
+        if strictness != accuracy::Strictness::Lenient {
+            self.memory.track_approximations();
+        }
+    }
+
+    /// Draw the pressed buttons into the bottom left corner of the screen
+    ///
+    /// The overlay of a frame shows the buttons that the game could
+    /// read while running that frame.  It is only drawn into the frames
+    /// passed to the frontend, not into `screen`.
+    pub fn show_input_overlay(&mut self) {
+        self.shows_input_overlay = true;
+    }
+
+    /// Pass views of the tiles, BG maps and OAM to `IO::show_vram`
+    /// with every frame
+    pub fn show_vram_viewer(&mut self) {
+        self.shows_vram_viewer = true;
+    }
+
+    /// Dump the tiles, BG maps and OAM as PNG files next to the given
+    /// ROM file on the hotkey, drawn in the given colors
+    ///
+    /// See `viewer::dump_path` for the file names.
+    pub fn use_vram_dumps(&mut self, rom_file: PathBuf,
+                          palettes: &colorization::ColorPalettes) {
+        self.vram_dumps = Some((rom_file, palettes.lookup_table()));
+    }
+
+    /// Also dump the VRAM after emulating the given number of frames
+    pub fn dump_vram_after(&mut self, frame: u64) {
+        self.vram_dump_frames.push(frame);
+    }
+
+    fn write_vram_dumps(&self) -> std::io::Result<()> {
+        if let Some((rom_file, colors)) = &self.vram_dumps {
+            let views = viewer::VramViews::render(&self.memory);
+            for (name, view) in views.named() {
+                let path = viewer::dump_path(rom_file, self.frame, name);
+                view.write_png(BufWriter::new(File::create(&path)?),
+                               colors)?;
+                log::info!("Dumped {}.", path.display());
             }
-            for scanline in 144..154 {
-                self.memory.set_ly(scanline);
-                if scanline == 144 {
-                    self.memory.set_lcd_mode(ppu::LcdMode::VBlank);
-                    // request VBlank interrupt
-                    let requests = self.memory.read8(0xFF0F) | 1;
-                    self.memory.write8(0xFF0F, requests);
+        }
+        Ok(())
+    }
+
+    /// Record clips of the screen on the hotkey and write them next to
+    /// the given ROM file in the given colors and format
+    ///
+    /// See `recording::clip_path` for the file names.
+    pub fn use_clip_recording(&mut self, rom_file: PathBuf,
+                              palettes: &colorization::ColorPalettes,
+                              format: recording::ClipFormat) {
+        self.clips = Some((rom_file, palettes.lookup_table(), format));
+    }
+
+    /// Start recording a clip, or write the clip being recorded
+    fn toggle_clip_recording(&mut self) -> std::io::Result<()> {
+        if self.clip_recorder.is_some() {
+            return self.write_clip();
+        }
+        if let Some((_, colors, format)) = &self.clips {
+            let recorder = recording::ClipRecorder::new(*format, *colors);
+            self.clip_recorder = Some((self.frame, recorder));
+            log::info!("Recording a clip from frame {}.", self.frame);
+        }
+        Ok(())
+    }
+
+    /// Stop recording a clip and write it, if one is being recorded
+    fn write_clip(&mut self) -> std::io::Result<()> {
+        let (start, recorder) = match self.clip_recorder.take() {
+            Some(clip) => clip,
+            None => return Ok(()),
+        };
+        let (rom_file, _, format) = self.clips.as_ref().unwrap();
+        let path = recording::clip_path(rom_file, start, *format);
+        recorder.write(BufWriter::new(File::create(&path)?))?;
+        log::info!("Wrote {} frames to {}.", recorder.frame_count(),
+                   path.display());
+        Ok(())
+    }
+
+    /// Print the pressed buttons to stderr whenever they change
+    ///
+    /// Each line contains the first frame in which the game can read
+    /// the buttons, formatted by `input_display::format_buttons`.
+    pub fn log_input(&mut self) {
+        self.logs_input = true;
+    }
+
+    /// Set when the PPU reads the background scroll registers
+    pub fn set_scroll_latch(&mut self, scroll_latch: ppu::ScrollLatch) {
+        self.ppu.set_scroll_latch(scroll_latch);
+    }
+
+    /// Point the IR port of the CGB at a device
+    ///
+    /// Cartridges with their own IR port, like HuC1 cartridges, get the
+    /// device instead.  Without a device, the IR port never receives any
+    /// light.
+    pub fn connect_infrared_device(&mut self,
+                                   device: Box<dyn infrared::InfraredDevice>) {
+        self.memory.connect_infrared_device(device);
+    }
+
+    /// Point the camera of Game Boy Camera cartridges at a source
+    ///
+    /// Without a source, the camera sees a uniformly gray picture.
+    pub fn connect_camera_source(&mut self,
+                                 source: Box<dyn camera::CameraSource>) {
+        self.memory.connect_camera_source(source);
+    }
+
+    /// Connect a device to the link port
+    ///
+    /// Without a connected device, the link port behaves as if no
+    /// cable was plugged in.
+    pub fn connect_serial_device(&mut self,
+                                 device: Box<dyn serial::SerialDevice>) {
+        self.memory.connect_serial_device(device);
+    }
+
+    /// Start recording all writes to the sound registers
+    pub fn start_vgm_recording(&mut self) {
+        self.memory.start_vgm_recording();
+    }
+
+    /// Stop recording writes to the sound registers
+    ///
+    /// The returned recording can be exported as a VGM file.
+    pub fn stop_vgm_recording(&mut self) -> Option<vgm::VgmRecorder> {
+        self.memory.stop_vgm_recording()
+    }
+
+    /// Pass the samples generated by the APU to the given sink, in
+    /// addition to the frontend
+    ///
+    /// They are resampled to the sink's sample rate.  A sink is dropped
+    /// once it fails to take samples.
+    pub fn add_audio_sink(&mut self, sink: Box<dyn audio::AudioSink>) {
+        self.audio_sinks.push(audio::ResampledSink::new(sink));
+    }
+
+    /// Run as fast as possible instead of at 60 frames per second
+    ///
+    /// Audio is muted, as the emulation speed is unknown.
+    pub fn disable_throttle(&mut self) {
+        self.frame_pacer.set_throttled(false);
+        self.audio_policy.set_speed(f64::INFINITY);
+    }
+
+    /// Run at the given multiple of the real speed, e.g. 0.25 for slow
+    /// motion
+    ///
+    /// Audio is resampled to the speed, or muted if it is too far off.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.frame_pacer.set_speed(speed);
+        self.audio_policy.set_speed(speed);
+    }
+
+    /// Make `run` stop once it has emulated the given number of frames
+    pub fn quit_after(&mut self, frames: u64) {
+        self.frame_limit = Some(frames);
+    }
+
+    /// Run until Escape is pressed
+    ///
+    /// Changes of the cartridge RAM are written into the save file every
+    /// few seconds and once the emulation ends, even by a panic.
+    pub fn run(&mut self) {
+        let last_frame = self.frame_limit.map(|frames| self.frame + frames);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            loop {
+                let rewound = self.rewinding && self.rewind();
+                self.audio_policy.set_rewinding(rewound);
+                if rewound {
+                    self.refresh_screen();
+                    self.queue_rewind_audio();
+                } else if self.paused
+                          && !std::mem::take(&mut self.advancing_frame) {
+                    // Keep showing the frame, which also polls the keys.
+                    self.refresh_screen();
+                } else {
+                    self.run_frame();
+                }
+                if self.emulator_window.is_esc_pressed()
+                        || self.quit_requested
+                        || last_frame.is_some_and(|last| self.frame >= last)
+                        || self.frame_hashes.as_ref()
+                               .is_some_and(|hashes| hashes.is_finished()) {
+                    break;
                 }
-                while scanline_cycles < CPU_CYCLES_PER_SCANLINE {
-                    scanline_cycles += self.step();
+                self.handle_hotkeys();
+                if self.frame.is_multiple_of(SAVE_FILE_FLUSH_FRAMES) {
+                    self.flush_save_file();
                 }
-                scanline_cycles %= CPU_CYCLES_PER_SCANLINE;
+                self.frame_pacer.wait_for_next_frame();
             }
+        }));
+        if let Err(e) = self.write_save_file() {
+            log::error!("Could not write save file: {}", e);
+        }
+        if let Some(Err(e)) = self.stop_movie_recording() {
+            log::error!("Could not write movie: {}", e);
+        }
+        if let Some(Err(e)) = self.frame_hashes.as_mut()
+                                              .map(|hashes| hashes.flush()) {
+            log::error!("Could not write frame hashes: {}", e);
         }
+        if let Err(e) = self.write_state_dump_file() {
+            log::error!("Could not dump state: {}", e);
+        }
+        if let Err(e) = self.write_clip() {
+            log::error!("Could not write clip: {}", e);
+        }
+        if let Err(panic) = result {
+            self.print_crash_report();
+            panic::resume_unwind(panic);
+        }
+    }
+
+    /// Print the registers, the call stack and the top of the stack to
+    /// stderr after the emulation panicked
+    fn print_crash_report(&self) {
+        eprintln!("{}", self.describe_cpu_state());
+        eprintln!("Call stack:\n{}",
+                  self.cpu.describe_call_stack(&self.memory));
+        self.cpu.print_stack(&self.memory, 16);
+    }
+
+    /// Read a byte of the address space at any time, e.g. to observe
+    /// the game's RAM from other tools
+    ///
+    /// Unlike reads of the CPU, this isn't blocked by OAM DMA or by the
+    /// PPU using VRAM and OAM, and it doesn't trigger watchpoints.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory.peek8(address)
+    }
+
+    /// Write a byte of the address space at any time, see
+    /// `memory::MemoryBus::poke8`
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory.poke8(address, value);
+    }
+
+    /// The CPU registers
+    pub fn cpu_registers(&self) -> cpu::CpuState {
+        self.cpu.state()
+    }
+
+    /// Overwrite the CPU registers, e.g. to jump to another address
+    pub fn set_cpu_registers(&mut self, registers: &cpu::CpuState) {
+        self.cpu.set_state(registers);
+    }
+
+    /// Registers after the CPU executed LD B,B, if it did since the
+    /// last call
+    ///
+    /// Test ROMs like mooneye's execute this instruction, which does
+    /// nothing, when they are done, see `compat::mooneye_outcome`.
+    pub fn take_magic_breakpoint(&mut self) -> Option<cpu::CpuState> {
+        self.magic_breakpoint.take()
+    }
+
+    /// Whether the game has executed an illegal instruction, which
+    /// hangs the CPU
+    ///
+    /// The screen keeps showing the last frame.
+    pub fn is_locked_up(&self) -> bool {
+        self.cpu.is_locked_up()
+    }
+
+    /// Emulate a single frame and return its pixels, see `screen`
+    ///
+    /// Unlike `run`, this neither waits for the frame to be due nor
+    /// checks for Escape, so that the emulator can be driven by another
+    /// main loop, e.g. of a game engine or a browser.
+    pub fn run_frame(&mut self) -> &[u8] {
+        self.emulate_frame();
+        self.screen()
+    }
+
+    /// Run the given number of frames as fast as possible
+    pub fn run_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.run_frame();
+        }
+    }
+
+    /// The pixels of the last completed frame
+    ///
+    /// Each pixel is one of the four shades 0 (lightest) to 3 (darkest),
+    /// stored line by line.  See `IO::refresh` for the layer bits.
+    pub fn screen(&self) -> &[u8] {
+        self.ppu.screen()
+    }
+
+    fn emulate_frame(&mut self) {
+        for scanline in 0..144 {
+            self.poll_key_presses();
+            self.memory.set_ly(scanline);
+            self.memory.set_lcd_mode(ppu::LcdMode::SearchingOAM);
+            self.ppu.start_oam_scan();
+            while self.scanline_cycles <= ppu::MODE2_DOTS {
+                self.ppu.scan_oam_until(&self.memory, self.scanline_cycles);
+                self.scanline_cycles += self.step();
+            }
+            self.memory.set_lcd_mode(
+                ppu::LcdMode::TransferringDataToLcdController);
+            // Mode 3 lasts until the pixel FIFO has output the whole
+            // line, which depends on scrolling, the window and objects.
+            self.ppu.start_line(&mut self.memory);
+            while !self.ppu.paint_line_until(
+                    &self.memory, self.scanline_cycles - ppu::MODE2_DOTS) {
+                self.scanline_cycles += self.step();
+            }
+            self.memory.set_lcd_mode(ppu::LcdMode::HBlank);
+            // TODO: This does not add up exactly, as we assume 60FPS
+            //       here, but it are actually slightly less.
+            while self.scanline_cycles < CPU_CYCLES_PER_SCANLINE {
+                self.scanline_cycles += self.step();
+            }
+            self.scanline_cycles %= CPU_CYCLES_PER_SCANLINE;
+        }
+        self.refresh_screen();
+        if let Some((_, recorder)) = &mut self.clip_recorder {
+            recorder.add_frame(self.ppu.screen());
+        }
+        self.queue_audio();
+        if self.script.is_some() {
+            self.run_script(scripting::Trigger::Frame);
+        }
+        self.check_key_presses();
+        for scanline in 144..154 {
+            self.poll_key_presses();
+            self.memory.set_ly(scanline);
+            if scanline == 144 {
+                self.memory.set_lcd_mode(ppu::LcdMode::VBlank);
+                // request VBlank interrupt
+                let requests = self.memory.read8(0xFF0F) | 1;
+                self.memory.write8(0xFF0F, requests);
+            }
+            while self.scanline_cycles < CPU_CYCLES_PER_SCANLINE {
+                self.scanline_cycles += self.step();
+            }
+            self.scanline_cycles %= CPU_CYCLES_PER_SCANLINE;
+        }
+        self.frame += 1;
+        if self.vram_dump_frames.contains(&self.frame) {
+            if let Err(e) = self.write_vram_dumps() {
+                log::error!("Could not dump VRAM: {}", e);
+            }
+        }
+        self.add_frame_hash();
+        self.keep_rewind_state();
     }
 
     fn step(&mut self) -> usize {
-        let mut cycles = self.cpu.step(&mut self.memory);
-        self.memory.step(cycles);
-        if self.handle_interrupts() {
-            cycles += 5 * 4;
-            self.memory.step(5 * 4);
+        let pc = self.cpu.pc();
+        if self.script.as_ref().is_some_and(|script| script.has_pc_hooks())
+           && !self.cpu.is_halted() {
+            self.run_script(scripting::Trigger::Pc(pc));
+        }
+        if self.debugger.is_some() && !self.cpu.is_halted() {
+            self.check_breakpoints();
+        }
+        // The instruction may switch the bank it has been read from.
+        let location = (self.profiler.is_some() || self.coverage.is_some())
+            .then(|| profiler::Location{
+                bank: self.memory.bank(pc),
+                address: pc,
+            });
+        let cycles = self.cpu.step(&mut self.memory);
+        if let (Some(coverage), Some(location)) = (&mut self.coverage,
+                                                   location) {
+            if self.cpu.executed_instruction() {
+                coverage.add(location, self.memory.cartridge().rom());
+            }
+        }
+        if let Some(divergence) = self.cpu.take_trace_divergence() {
+            log::error!("{}", divergence);
+            self.trace_divergence = Some(divergence);
+            if self.debugger.is_some() {
+                self.break_into_debugger();
+            } else {
+                self.quit_requested = true;
+            }
+        }
+        if self.cpu.take_magic_breakpoint() {
+            self.magic_breakpoint = Some(self.cpu.state());
+        }
+        if let (Some(profiler), Some(location)) = (&mut self.profiler,
+                                                   location) {
+            profiler.add(location, cycles);
+        }
+        if self.script.is_some() {
+            for hit in self.memory.take_script_watch_hits() {
+                self.run_script(scripting::Trigger::Access(hit));
+            }
+        }
+        if self.debugger.is_some() {
+            self.check_breaks(pc);
+        }
+        if self.tracks_uninitialized_reads {
+            let reads = self.memory.report_uninitialized_reads(pc, self.frame)
+                                   .to_vec();
+            for read in reads {
+                log::info!("Read uninitialized {} at PC {} in frame {}.",
+                           self.describe_address(read.address),
+                           self.describe_address(read.pc), read.frame);
+            }
+        }
+        if self.strictness != accuracy::Strictness::Lenient {
+            self.report_approximations(pc);
         }
         cycles
     }
 
-    fn check_key_presses(&mut self) -> usize {
-        if self.memory.set_key_presses(
-            self.emulator_window.get_key_presses())
-           && self.handle_interrupts() {
-            self.memory.step(5 * 4);
-            5 * 4
+    fn report_approximations(&mut self, pc: u16) {
+        for approximation in self.memory.take_new_approximations() {
+            let message = format!(
+                "Inaccurate emulation: {} at PC {} on line {} in frame {}.",
+                approximation, self.describe_address(pc), self.memory.ly(),
+                self.frame);
+            if self.strictness == accuracy::Strictness::Error {
+                panic!("{}", message);
+            }
+            log::warn!("{}", message);
+        }
+    }
+
+    /// Enter the debugger if a breakpoint is hit by the instruction that
+    /// is executed next
+    fn check_breakpoints(&mut self) {
+        let debugger = self.debugger.as_ref().unwrap();
+        if debugger.breakpoints.is_empty() {
+            return;
+        }
+        let cpu = self.cpu.state();
+        let index = match debugger.breakpoint_hit(
+                &cpu, self.memory.address_space()) {
+            Some(index) => index,
+            None => return,
+        };
+        let messages = [
+            format!("Breakpoint {}: {}", index, debugger.breakpoints[index]),
+            self.describe_cpu_state(),
+        ];
+        if let Err(e) = self.debug_prompt(&messages) {
+            log::error!("Detaching debugger: {}", e);
+            self.debugger = None;
+        }
+    }
+
+    /// Enter the debugger if the instruction at the given address hit a
+    /// watchpoint or if the debugger is stepping
+    fn check_breaks(&mut self, pc: u16) {
+        let hits = self.memory.take_watch_hits();
+        if hits.is_empty() && !self.debugger.as_ref().unwrap().stepping {
+            return;
+        }
+        let mut messages = Vec::new();
+        for hit in hits {
+            let access = match hit.access {
+                debugger::Access::Read => "Read",
+                debugger::Access::Write => "Wrote",
+            };
+            messages.push(format!("{} {:0>2X} at {} by instruction at {}.",
+                                  access, hit.value,
+                                  self.describe_address(hit.address),
+                                  self.describe_address(pc)));
+        }
+        messages.push(self.describe_cpu_state());
+        if let Err(e) = self.debug_prompt(&messages) {
+            log::error!("Detaching debugger: {}", e);
+            self.debugger = None;
+        }
+    }
+
+    fn describe_cpu_state(&self) -> String {
+        let cpu = self.cpu.state();
+        format!("A:{:0>2X} F:{:0>2X} B:{:0>2X} C:{:0>2X} D:{:0>2X} \
+                 E:{:0>2X} H:{:0>2X} L:{:0>2X} SP:{:0>4X} PC:{}\n\
+                 Frame {}, line {}",
+                cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l,
+                cpu.sp, self.describe_address(cpu.pc),
+                self.frame, self.memory.ly())
+    }
+
+    /// Print the messages and execute debugger commands until the
+    /// emulation continues
+    fn debug_prompt(&mut self, messages: &[String])
+            -> std::io::Result<()> {
+        for message in messages {
+            writeln!(self.debugger.as_mut().unwrap().output(), "{}", message)?;
+        }
+        loop {
+            let debugger = self.debugger.as_mut().unwrap();
+            let output = match debugger.read_command()? {
+                debugger::Command::Continue => {
+                    debugger.stepping = false;
+                    return Ok(());
+                }
+                debugger::Command::Step => {
+                    debugger.stepping = true;
+                    return Ok(());
+                }
+                debugger::Command::Registers => self.describe_cpu_state(),
+                debugger::Command::Backtrace => {
+                    self.cpu.describe_call_stack(&self.memory)
+                }
+                debugger::Command::Examine{address, length} => {
+                    self.examine_memory(address, length)
+                }
+                debugger::Command::Objects => {
+                    viewer::oam_entries(&self.memory)
+                        .iter()
+                        .enumerate()
+                        .map(|(i, object)| {
+                            viewer::describe_object(i, object) + "\n"
+                        })
+                        .collect::<String>()
+                        .trim_end()
+                        .to_string()
+                }
+                debugger::Command::Profile(count) => {
+                    self.profile_report(count).unwrap_or_else(|| {
+                        "Profiling is off, start it with --profile."
+                            .to_string()
+                    })
+                }
+                debugger::Command::Watch(watchpoint) => {
+                    let output = format!("Watchpoint {}: {}",
+                                         self.memory.watchpoints().len(),
+                                         watchpoint);
+                    self.memory.add_watchpoint(watchpoint);
+                    output
+                }
+                debugger::Command::ListWatchpoints => {
+                    self.memory.watchpoints()
+                               .iter()
+                               .enumerate()
+                               .map(|(i, watchpoint)| {
+                                   format!("Watchpoint {}: {}\n",
+                                           i, watchpoint)
+                               })
+                               .collect::<String>()
+                               .trim_end()
+                               .to_string()
+                }
+                debugger::Command::Unwatch(index) => {
+                    match self.memory.remove_watchpoint(index) {
+                        Some(watchpoint) => format!("Removed {}.", watchpoint),
+                        None => format!("There is no watchpoint {}.", index),
+                    }
+                }
+                debugger::Command::Break(breakpoint) => {
+                    let output = format!("Breakpoint {}: {}",
+                                         debugger.breakpoints.len(),
+                                         breakpoint);
+                    debugger.breakpoints.push(breakpoint);
+                    output
+                }
+                debugger::Command::ListBreakpoints => {
+                    debugger.breakpoints
+                            .iter()
+                            .enumerate()
+                            .map(|(i, breakpoint)| {
+                                format!("Breakpoint {}: {}\n", i, breakpoint)
+                            })
+                            .collect::<String>()
+                            .trim_end()
+                            .to_string()
+                }
+                debugger::Command::Delete(index) => {
+                    if index < debugger.breakpoints.len() {
+                        let breakpoint = debugger.breakpoints.remove(index);
+                        format!("Removed {}.", breakpoint)
+                    } else {
+                        format!("There is no breakpoint {}.", index)
+                    }
+                }
+                debugger::Command::Help => debugger::HELP.to_string(),
+                debugger::Command::Quit => {
+                    while self.memory.remove_watchpoint(0).is_some() {}
+                    self.debugger = None;
+                    self.quit_requested = true;
+                    return Ok(());
+                }
+            };
+            writeln!(self.debugger.as_mut().unwrap().output(), "{}", output)?;
+        }
+    }
+
+    /// Hex dump of memory as the CPU would read it
+    fn examine_memory(&mut self, address: u16, length: u16) -> String {
+        let mut lines = Vec::new();
+        for line_start in (0..length).step_by(16) {
+            let line_address = address.wrapping_add(line_start);
+            let bytes: Vec<String> = (line_start..length.min(line_start + 16))
+                .map(|offset| address.wrapping_add(offset))
+                .map(|address| format!("{:0>2X}", self.memory.read8(address)))
+                .collect();
+            lines.push(format!("{:0>4X}: {}", line_address, bytes.join(" ")));
+        }
+        // The debugger's reads don't trigger watchpoints.
+        self.memory.take_watch_hits();
+        lines.join("\n")
+    }
+
+    /// Format an address together with its label, if symbols are loaded
+    fn describe_address(&self, address: u16) -> String {
+        match self.memory.label(address) {
+            Some(label) => format!("{:0>4X} ({})", address, label),
+            None => format!("{:0>4X}", address),
+        }
+    }
+
+    fn refresh_screen(&mut self) {
+        if self.shows_vram_viewer {
+            let views = viewer::VramViews::render(&self.memory);
+            self.emulator_window.show_vram(&views);
+        }
+        let screen = if self.shows_input_overlay {
+            self.overlay_buffer.clear();
+            self.overlay_buffer.extend_from_slice(self.ppu.screen());
+            input_display::draw_overlay(&mut self.overlay_buffer,
+                                        self.pressed_keys);
+            &self.overlay_buffer
         } else {
-            0
+            self.ppu.screen()
+        };
+        if let Some(sgb) = self.memory.super_game_boy_mut() {
+            sgb.render(screen, &mut self.sgb_frame);
+            if self.emulator_window.refresh_sgb(&self.sgb_frame) {
+                return;
+            }
+        }
+        self.emulator_window.refresh(screen);
+    }
+
+    fn queue_audio(&mut self) {
+        let samples = self.memory.take_audio_samples();
+        self.audio_sinks.retain_mut(|sink| {
+            sink.push_samples(&samples).map_err(|e| {
+                log::warn!("Stopping audio output: {}", e);
+            }).is_ok()
+        });
+        self.play_samples(&samples);
+    }
+
+    /// Play a frame of silence after rewinding to a state
+    ///
+    /// The audio policy fades out the sound from before instead of
+    /// playing the garbled audio of the restored states, which is
+    /// dropped.
+    fn queue_rewind_audio(&mut self) {
+        self.memory.take_audio_samples();
+        let silence = vec![0; apu::SAMPLE_RATE / FRAMERATE * apu::CHANNELS];
+        self.play_samples(&silence);
+    }
+
+    /// Pass samples through the audio policy to the frontend and the
+    /// audio playback
+    fn play_samples(&mut self, samples: &[i16]) {
+        self.audio_buffer.clear();
+        self.audio_policy.process(samples, &mut self.audio_buffer);
+        self.emulator_window.queue_audio(&self.audio_buffer);
+        if let Some(playback) = &mut self.playback {
+            if let Err(e) = playback.push_samples(&self.audio_buffer) {
+                log::warn!("Stopping audio playback: {}", e);
+                self.playback = None;
+            }
+        }
+    }
+
+    /// Pass the pressed buttons to the game
+    ///
+    /// A resulting joypad interrupt gets dispatched by the next CPU step.
+    fn check_key_presses(&mut self) {
+        let mut input = self.next_input();
+        if let Some(script) = &mut self.script {
+            self.script_keys = script.take_held_keys();
+            input.keys |= self.script_keys;
+        }
+        if let Some(recorder) = &mut self.movie_recorder {
+            if let Err(e) = recorder.record(input) {
+                log::error!("Could not record movie: {}", e);
+                self.movie_recorder = None;
+            }
+        }
+        self.press_keys(input.keys);
+        self.memory.set_tilt(input.tilt);
+    }
+
+    /// Keep the given buttons pressed until they are set again, in
+    /// addition to those pressed in the frontend
+    ///
+    /// The game sees them from the next time it gets the buttons, at the
+    /// latest in the next VBlank.  Unlike the frontend's buttons, they
+    /// are also recorded into movies, but ignored while one is played.
+    pub fn set_buttons(&mut self, buttons: io::Buttons) {
+        self.held_buttons = buttons;
+    }
+
+    /// Pass button changes to the game between the checks at VBlank
+    ///
+    /// Movies only hold the buttons of whole frames, so the buttons
+    /// stay as they are while a movie is played or recorded.
+    fn poll_key_presses(&mut self) {
+        if self.movie_inputs.is_some() || self.movie_recorder.is_some() {
+            return;
+        }
+        if let Some(keys) = self.emulator_window.poll_key_presses() {
+            let keys = keys | self.script_keys | self.held_buttons.bits();
+            if keys != self.pressed_keys {
+                self.press_keys(keys);
+            }
         }
     }
 
-    fn handle_interrupts(&mut self) -> bool {
-        self.cpu.handle_interrupts(&mut self.memory)
+    fn press_keys(&mut self, keys: u8) {
+        if self.logs_input && keys != self.pressed_keys {
+            log::info!("Frame {}: {}", self.frame + 1,
+                       input_display::format_buttons(keys));
+        }
+        self.pressed_keys = keys;
+        self.memory.set_key_presses(keys);
+    }
+}
+
+/// Why `GameBoyBuilder::build` can't set up a Game Boy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    MissingCartridge,
+    /// No boot ROM has been loaded, nor has a built-in boot ROM or
+    /// `use_hle_boot` been chosen
+    MissingBootRom,
+    MissingWindow,
+    /// The cartridge uses a memory controller that isn't emulated
+    UnsupportedMapper(cartridge::MemoryControllerModel),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingCartridge => write!(f, "no cartridge loaded"),
+            Self::MissingBootRom => write!(f, "no boot ROM loaded"),
+            Self::MissingWindow => write!(f, "no emulator window given"),
+            Self::UnsupportedMapper(model) => {
+                write!(f, "unsupported mapper {:?}", model)
+            }
+        }
+    }
+}
+
+impl error::Error for BuildError {}
+
+/// Cartridge ROM given to a `GameBoyBuilder`
+///
+/// ROMs for unsupported memory controllers are kept until `build`, so
+/// that their header can still be inspected.
+enum LoadedCartridge {
+    Supported(cartridge::Cartridge),
+    UnsupportedMapper(cartridge::MemoryControllerModel, Vec<u8>),
+}
+
+impl LoadedCartridge {
+    fn from_rom(rom: Vec<u8>) -> Result<Self, cartridge::CartridgeError> {
+        use cartridge::CartridgeError;
+        match cartridge::Cartridge::from_rom(rom.clone()) {
+            Ok(cartridge) => Ok(Self::Supported(cartridge)),
+            Err(CartridgeError::UnsupportedMapper(model)) => {
+                Ok(Self::UnsupportedMapper(model, rom))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        match self {
+            Self::Supported(cartridge) => cartridge.rom(),
+            Self::UnsupportedMapper(_, rom) => rom,
+        }
     }
 }
 
 pub struct GameBoyBuilder<Window: io::IO> {
-    boot_rom: Option<[u8;0x100]>,
-    cartridge: Option<cartridge::Cartridge>,
+    boot_rom: Option<boot_rom::BootRom>,
+    fast_boot: bool,
+    logo_boot: bool,
+    hle_boot: bool,
+    model: boot_rom::Model,
+    rtc_start: rtc::RtcStart,
+    cartridge: Option<LoadedCartridge>,
     window: Option<Window>,
+    audio_sinks: Vec<audio::ResampledSink>,
+    playback: Option<audio::ResampledSink>,
+    symbols: Option<symbols::SymbolTable>,
 }
 
 impl<Window: io::IO> GameBoyBuilder<Window> {
     pub fn new() -> Self {
         Self {
             boot_rom: None,
+            fast_boot: false,
+            logo_boot: false,
+            hle_boot: false,
+            model: boot_rom::Model::default(),
+            rtc_start: rtc::RtcStart::default(),
             cartridge: None,
             window: None,
+            audio_sinks: Vec::new(),
+            playback: None,
+            symbols: None,
         }
     }
 
-    pub fn build(self) -> GameBoy<Window> {
-        GameBoy::new(self.boot_rom.unwrap(),
-                     self.cartridge.unwrap(),
-                     self.window.unwrap())
+    pub fn build(self) -> Result<GameBoy<Window>, BuildError> {
+        let cartridge = match self.cartridge {
+            Some(LoadedCartridge::Supported(cartridge)) => cartridge,
+            Some(LoadedCartridge::UnsupportedMapper(model, _)) => {
+                return Err(BuildError::UnsupportedMapper(model));
+            }
+            None => return Err(BuildError::MissingCartridge),
+        };
+        let window = self.window.ok_or(BuildError::MissingWindow)?;
+        let uses_sgb_functions = self.model == boot_rom::Model::SGB
+            && sgb::is_enhanced(&cartridge.header());
+        let mut game_boy = if self.hle_boot {
+            GameBoy::with_hle_boot(self.model, cartridge, window)
+        } else if self.fast_boot {
+            let boot_rom = boot_rom::fast_boot_rom(self.model,
+                                                   &cartridge.header());
+            GameBoy::new(boot_rom.into(), cartridge, window)
+        } else if self.logo_boot {
+            let boot_rom = boot_rom::logo_boot_rom(self.model,
+                                                   &cartridge.header());
+            GameBoy::new(boot_rom.into(), cartridge, window)
+        } else {
+            let boot_rom = self.boot_rom.ok_or(BuildError::MissingBootRom)?;
+            GameBoy::new(boot_rom, cartridge, window)
+        };
+        game_boy.memory.set_model(self.model);
+        game_boy.memory.cartridge_mut().set_rtc_start(self.rtc_start);
+        game_boy.audio_sinks = self.audio_sinks;
+        game_boy.playback = self.playback;
+        if let Some(symbols) = self.symbols {
+            game_boy.load_symbols(symbols);
+        }
+        if uses_sgb_functions {
+            game_boy.memory.enable_super_game_boy();
+        }
+        Ok(game_boy)
     }
 
-    pub fn load_boot_rom(mut self, file: File) -> std::io::Result<Self> {
-        let boot_rom = boot_rom::load_boot_rom(file)?;
+    /// Load a boot ROM, see `boot_rom::load_boot_rom`
+    pub fn load_boot_rom(mut self, reader: impl Read)
+            -> std::io::Result<Self> {
+        let boot_rom = boot_rom::load_boot_rom(reader)?;
         self.boot_rom = Some(boot_rom);
+        self.fast_boot = false;
+        self.logo_boot = false;
         Ok(self)
     }
 
+    /// Use a boot ROM that skips the logo animation
+    ///
+    /// It leaves the registers in the post-boot state of the model
+    /// chosen with `use_model`.
     pub fn use_fast_boot_rom(mut self) -> Self {
-        let boot_rom = boot_rom::fast_boot_rom();
-        self.boot_rom = Some(boot_rom);
+        self.fast_boot = true;
+        self
+    }
+
+    /// Use a boot ROM that scrolls in the logo and plays the boot sound
+    ///
+    /// Like the fast boot ROM, it leaves the CPU registers in the
+    /// post-boot state of the model chosen with `use_model`.
+    pub fn use_logo_boot_rom(mut self) -> Self {
+        self.logo_boot = true;
+        self
+    }
+
+    /// Choose the hardware model whose post-boot state is set up by the
+    /// built-in boot ROMs or by `use_hle_boot`
+    ///
+    /// A loaded boot ROM sets up the registers on its own.  Defaults to
+    /// the DMG.  On the SGB, cartridges with SGB support can send
+    /// commands to set palettes and a border.
+    pub fn use_model(mut self, model: boot_rom::Model) -> Self {
+        self.model = model;
         self
     }
 
-    pub fn load_cartridge(mut self, file: File) -> std::io::Result<Self> {
-        self.cartridge = Some(cartridge::Cartridge::load_from_file(file)?);
+    /// Choose the time that the real time clock of MBC3 cartridges
+    /// starts at
+    ///
+    /// Defaults to the current time of the host.
+    pub fn use_rtc_start(mut self, start: rtc::RtcStart) -> Self {
+        self.rtc_start = start;
+        self
+    }
+
+    /// Don't run any boot ROM but start directly at 0x0100
+    ///
+    /// This overrides any previously loaded boot ROM.
+    pub fn use_hle_boot(mut self) -> Self {
+        self.hle_boot = true;
+        self
+    }
+
+    /// Load a cartridge ROM from a file, a byte slice or any other reader
+    ///
+    /// A cartridge with a memory controller that isn't emulated is only
+    /// rejected by `build`.
+    pub fn load_cartridge(mut self, mut reader: impl Read)
+            -> Result<Self, cartridge::CartridgeError> {
+        let mut rom = Vec::new();
+        reader.read_to_end(&mut rom)?;
+        self.cartridge = Some(LoadedCartridge::from_rom(rom)?);
+        Ok(self)
+    }
+
+    /// Correct the header of the loaded cartridge
+    pub fn override_cartridge_header(
+            mut self, overrides: &cartridge::HeaderOverrides)
+            -> Result<Self, cartridge::CartridgeError> {
+        if let Some(cartridge) = self.cartridge.take() {
+            let mut rom = match cartridge {
+                LoadedCartridge::Supported(cartridge) => {
+                    cartridge.rom().to_vec()
+                }
+                LoadedCartridge::UnsupportedMapper(_, rom) => rom,
+            };
+            overrides.apply(&mut rom)?;
+            self.cartridge = Some(LoadedCartridge::from_rom(rom)?);
+        }
+        Ok(self)
+    }
+
+    /// Load the labels of the cartridge from an RGBDS `.sym` file
+    pub fn load_symbols(mut self, file: File) -> std::io::Result<Self> {
+        self.symbols = Some(symbols::SymbolTable::load(file)?);
         Ok(self)
     }
 
@@ -173,7 +1724,847 @@ impl<Window: io::IO> GameBoyBuilder<Window> {
         self
     }
 
+    /// Record the APU output of the whole session as a stereo WAV file
+    pub fn dump_audio(self, file: File) -> std::io::Result<Self> {
+        let writer = audio::WavWriter::new(BufWriter::new(file),
+                                           apu::SAMPLE_RATE as u32,
+                                           apu::CHANNELS as u16)?;
+        Ok(self.add_audio_sink(Box::new(writer)))
+    }
+
+    /// Pass the APU output to the given sink besides the frontend, see
+    /// `GameBoy::add_audio_sink`
+    pub fn add_audio_sink(mut self, sink: Box<dyn audio::AudioSink>)
+            -> Self {
+        self.audio_sinks.push(audio::ResampledSink::new(sink));
+        self
+    }
+
+    /// Play the audio on the given sink, e.g. an
+    /// `audio_device::DeviceSink`
+    ///
+    /// Unlike the sinks of `add_audio_sink`, it gets the audio adapted
+    /// to the emulation speed like the frontend, see
+    /// `audio::AudioPolicy`.
+    pub fn play_audio(mut self, sink: Box<dyn audio::AudioSink>) -> Self {
+        self.playback = Some(audio::ResampledSink::new(sink));
+        self
+    }
+
     pub fn get_cartridge_header(&self) -> Option<cartridge::CartridgeHeader> {
-        self.cartridge.as_ref().map(|c| c.header())
+        self.get_cartridge_rom().map(cartridge::CartridgeHeader::of_rom)
+    }
+
+    pub fn get_cartridge_rom(&self) -> Option<&[u8]> {
+        self.cartridge.as_ref().map(|c| c.rom())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compat::NoWindow;
+
+    /// Presses a different combination of buttons in every frame
+    #[derive(Default)]
+    struct ButtonMasher {
+        frame: std::cell::Cell<u8>,
+    }
+
+    impl io::IO for ButtonMasher {
+        fn refresh(&mut self, _pixels: &[u8]) {}
+
+        fn is_esc_pressed(&self) -> bool {
+            false
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            let frame = self.frame.get().wrapping_add(1);
+            self.frame.set(frame);
+            frame.wrapping_mul(37)
+        }
+    }
+
+    /// Presses Start after being polled a few times, like a frontend
+    /// that reads its input independently of the emulated frames
+    #[derive(Default)]
+    struct LiveButtons {
+        polls: std::cell::Cell<u32>,
+    }
+
+    impl io::IO for LiveButtons {
+        fn refresh(&mut self, _pixels: &[u8]) {}
+
+        fn is_esc_pressed(&self) -> bool {
+            false
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            if self.polls.get() >= 10 { 0x80 } else { 0 }
+        }
+
+        fn poll_key_presses(&self) -> Option<u8> {
+            self.polls.set(self.polls.get() + 1);
+            Some(self.get_key_presses())
+        }
+    }
+
+    /// A cartridge that keeps scrolling the logo horizontally
+    fn scrolling_cartridge(title: &[u8]) -> cartridge::Cartridge {
+        let mut rom = vec![0; 0x8000];
+        // JP 0x0150
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x159].copy_from_slice(&[
+            0x21, 0x00, 0xC0,  // LD HL, 0xC000
+            0x34,              // INC (HL)
+            0x7E,              // LD A, (HL)
+            0xE0, 0x43,        // LDH (SCX), A
+            0x18, 0xFA,        // JR -6
+        ]);
+        rom[0x104..0x134].copy_from_slice(&cartridge::LOGO);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        cartridge::Cartridge::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn state_dump_has_registers_timer_and_mapper() {
+        let mut rom = scrolling_cartridge(b"DUMP").rom().to_vec();
+        rom[0x147] = 0x01;  // MBC1
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, cartridge::Cartridge::from_rom(rom).unwrap(),
+            NoWindow);
+        game_boy.run_frames(2);
+        let mut json = Vec::new();
+        game_boy.dump_state(&mut json).unwrap();
+        let dump: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(dump["frame"], 2);
+        assert_eq!(dump["cpu"]["sp"], 0xFFFE);
+        assert_eq!(dump["io_registers"]["FF40"], 0x91);
+        assert_eq!(dump["io_registers"]["FF43"],
+                   game_boy.memory.read8(0xFF43));
+        assert!(dump["io_registers"]["FFFF"].is_u64());
+        assert!(dump["timer"]["clock"].is_u64());
+        assert!(dump["mapper"]["MBC1"].is_object());
+    }
+
+    #[test]
+    fn magic_breakpoint_reports_mooneye_result() {
+        let mut rom = scrolling_cartridge(b"MOONEYE").rom().to_vec();
+        rom[0x150..0x15F].copy_from_slice(&[
+            0x06, 3, 0x0E, 5, 0x16, 8,    // LD B, 3; LD C, 5; LD D, 8
+            0x1E, 13, 0x26, 21, 0x2E, 34, // LD E, 13; LD H, 21; LD L, 34
+            0x40,                         // LD B, B
+            0x18, 0xFE,                   // JR -2
+        ]);
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, cartridge::Cartridge::from_rom(rom).unwrap(),
+            NoWindow);
+        game_boy.run_frames(1);
+        let cpu = game_boy.take_magic_breakpoint().unwrap();
+        assert_eq!(cpu.pc, 0x15D);
+        assert_eq!(compat::mooneye_outcome(&cpu),
+                   Some(serial::TestOutcome::Passed));
+        game_boy.run_frames(1);
+        assert_eq!(game_boy.take_magic_breakpoint(), None);
+        let failed = cpu::CpuState{b: 0x42, c: 0x42, d: 0x42, e: 0x42,
+                                   h: 0x42, l: 0x42, ..cpu};
+        assert_eq!(compat::mooneye_outcome(&failed),
+                   Some(serial::TestOutcome::Failed));
+        let other = cpu::CpuState{b: 0x42, ..cpu};
+        assert_eq!(compat::mooneye_outcome(&other), None);
+    }
+
+    #[test]
+    fn loading_state_continues_where_it_was_saved() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.run_frames(10);
+        let mut state = Vec::new();
+        game_boy.save_state(&mut state).unwrap();
+        game_boy.run_frames(10);
+        let screen = game_boy.screen().to_vec();
+        let cpu_state = game_boy.cpu.state();
+        game_boy.run_frames(5);
+        game_boy.load_state(&mut &state[..]).unwrap();
+        game_boy.run_frames(10);
+        assert_eq!(game_boy.screen(), &screen[..]);
+        assert_eq!(game_boy.cpu.state(), cpu_state);
+
+        let mut other_game = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"OTHER"), NoWindow);
+        let error = other_game.load_state(&mut &state[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rewinding_returns_to_kept_states() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.enable_rewind(1);
+        game_boy.run_frames(10);
+        let screen = game_boy.screen().to_vec();
+        let cpu_state = game_boy.cpu.state();
+        game_boy.run_frames(10);
+        // States are kept after frames 20, 18, …, 12 and 10.
+        for _ in 0..6 {
+            assert!(game_boy.rewind());
+        }
+        assert_eq!(game_boy.frame, 10);
+        assert_eq!(game_boy.screen(), &screen[..]);
+        assert_eq!(game_boy.cpu.state(), cpu_state);
+        // Only the states of the last second are kept.
+        game_boy.run_frames(100);
+        let mut rewound_frames = 0;
+        while game_boy.rewind() {
+            rewound_frames += 1;
+        }
+        assert_eq!(rewound_frames, FRAMERATE / 2);
+    }
+
+    #[test]
+    fn clip_is_written_when_recording_stops() {
+        let rom_file = std::env::temp_dir().join("emulato-rs-clip-test.gb");
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let palettes = colorization::ColorPalettes::monochrome(
+            [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+        game_boy.use_clip_recording(rom_file.clone(), &palettes,
+                                    recording::ClipFormat::Gif);
+        game_boy.run_frames(3);
+        game_boy.toggle_clip_recording().unwrap();
+        game_boy.run_frames(10);
+        game_boy.toggle_clip_recording().unwrap();
+        game_boy.run_frames(2);
+        let path = recording::clip_path(&rom_file, 3,
+                                        recording::ClipFormat::Gif);
+        let gif = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+        assert!(game_boy.clip_recorder.is_none());
+    }
+
+    #[test]
+    fn playing_movie_repeats_recorded_input() {
+        let path = std::env::temp_dir().join("emulato-rs-movie-test.gbm");
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"),
+            ButtonMasher::default());
+        game_boy.run_frames(5);
+        game_boy.record_movie(Box::new(File::create(&path).unwrap()))
+                .unwrap();
+        game_boy.run_frames(20);
+        game_boy.stop_movie_recording().unwrap().unwrap();
+
+        let mut player = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        player.play_movie(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(player.frame, 5);
+        player.run_frames(20);
+        assert_eq!(player.frame, game_boy.frame);
+        assert_eq!(player.pressed_keys, game_boy.pressed_keys);
+        assert_eq!(player.screen(), game_boy.screen());
+        assert_eq!(player.cpu.state(), game_boy.cpu.state());
+        // After the movie, the input comes from the frontend again.
+        player.run_frames(1);
+        assert_eq!(player.pressed_keys, 0);
+    }
+
+    #[test]
+    fn frame_hashes_match_reference_of_same_run() {
+        let path = std::env::temp_dir().join("emulato-rs-frame-hashes.txt");
+        let mut reference = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        reference.write_frame_hashes(Box::new(File::create(&path).unwrap()));
+        reference.run_frames(10);
+        reference.frame_hashes.as_mut().unwrap().flush().unwrap();
+        reference.frame_hashes = None;
+
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let file = std::io::BufReader::new(File::open(&path).unwrap());
+        game_boy.compare_frame_hashes(file).unwrap();
+        game_boy.run_frames(10);
+        assert_eq!(game_boy.frame_hash_mismatches(), Some((10, 0)));
+
+        let mut diverging = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let file = std::io::BufReader::new(File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        diverging.compare_frame_hashes(file).unwrap();
+        diverging.run_frames(5);
+        diverging.memory.write8(0xC000, 0x80);
+        diverging.run_frames(5);
+        assert_eq!(diverging.frame_hash_mismatches(), Some((10, 5)));
+    }
+
+    /// Output of the debugger that the test can look at
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn take(&self) -> Vec<u8> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn trace_comparison_stops_at_first_difference() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let output = SharedOutput::default();
+        game_boy.start_trace(Box::new(output.clone()));
+        game_boy.run_frames(1);
+        let trace = String::from_utf8(output.take()).unwrap();
+        let mut reference: Vec<&str> = trace.lines().collect();
+        reference[100] = "A:FF PC:0150";
+        let reference = reference.join("\n");
+
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.compare_trace(Box::new(std::io::Cursor::new(reference)));
+        game_boy.run_frames(1);
+        assert!(game_boy.quit_requested);
+        let divergence = game_boy.trace_divergence().unwrap();
+        assert_eq!(divergence.line_number, 101);
+        assert_eq!(divergence.actual, trace.lines().nth(100).unwrap());
+        assert_eq!(divergence.context.len(), 5);
+    }
+
+    #[test]
+    fn watchpoint_breaks_into_debugger() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let output = SharedOutput::default();
+        let input = b"x C000 2\nc\nwatch\nq\n";
+        game_boy.attach_debugger(debugger::Debugger::new(
+            Box::new(&input[..]), Box::new(output.clone())));
+        game_boy.add_watchpoint(
+            debugger::Watchpoint::from_spec("w:C000").unwrap());
+        game_boy.run_frames(1);
+        assert!(game_boy.quit_requested);
+        assert!(game_boy.memory.watchpoints().is_empty());
+        let output = String::from_utf8(output.take()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "Wrote 01 at C000 by instruction at 0153.");
+        assert!(lines[1].ends_with("SP:FFFE PC:0154"), "{}", lines[1]);
+        assert_eq!(lines[3], "(gb) C000: 01 00");
+        assert_eq!(lines[4], "(gb) Wrote 02 at C000 by instruction at 0153.");
+        assert_eq!(lines[7], "(gb) Watchpoint 0: w:C000");
+    }
+
+    #[test]
+    fn conditional_breakpoint_breaks_on_matching_iteration() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let output = SharedOutput::default();
+        game_boy.attach_debugger(debugger::Debugger::new(
+            Box::new(&b"b\nc\nq\n"[..]), Box::new(output.clone())));
+        // LDH (SCX), A after loading the counter into A
+        game_boy.add_breakpoint(
+            debugger::Breakpoint::from_spec("0155 if A==5 || A==0x07")
+                                 .unwrap());
+        game_boy.run_frames(1);
+        assert!(game_boy.quit_requested);
+        let output = String::from_utf8(output.take()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "Breakpoint 0: 0155 if A==0x5 || A==0x7");
+        assert!(lines[1].starts_with("A:05 "), "{}", lines[1]);
+        assert_eq!(lines[3], "(gb) Breakpoint 0: 0155 if A==0x5 || A==0x7");
+        assert_eq!(lines[4], "(gb) Breakpoint 0: 0155 if A==0x5 || A==0x7");
+        assert!(lines[5].starts_with("A:07 "), "{}", lines[5]);
+    }
+
+    #[test]
+    fn script_reacts_to_pc_writes_and_frames() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let mut script = scripting::Script::load(r#"
+            on_pc(0x0150, || press("Start", 2));
+            on_write(0xC000, |address, value| if value == 3 {
+                print(`${peek(address)} in ${frame()}`);
+                quit();
+            });
+            on_frame(|| poke(0xC100, 0x42));
+        "#).unwrap();
+        let output = SharedOutput::default();
+        script.set_output(Box::new(output.clone()));
+        game_boy.load_script(script);
+        game_boy.run_frames(1);
+        assert!(game_boy.quit_requested);
+        assert_eq!(game_boy.memory.read8(0xC100), 0x42);
+        assert_eq!(game_boy.pressed_keys, 0x80);
+        let output = String::from_utf8(output.take()).unwrap();
+        assert_eq!(output.lines().next(), Some("3 in 0"));
+        game_boy.run_frames(2);
+        assert_eq!(game_boy.pressed_keys, 0x00);
+    }
+
+    #[test]
+    fn builder_reports_missing_parts() {
+        let rom = scrolling_cartridge(b"SCROLL").rom().to_vec();
+        let builder = GameBoy::<NoWindow>::builder()
+                              .use_emulator_window(NoWindow);
+        assert_eq!(builder.build().err(), Some(BuildError::MissingCartridge));
+        let builder = GameBoy::<NoWindow>::builder()
+                              .load_cartridge(&rom[..]).unwrap();
+        assert_eq!(builder.build().err(), Some(BuildError::MissingWindow));
+        let builder = GameBoy::builder().load_cartridge(&rom[..]).unwrap()
+                                        .use_emulator_window(NoWindow);
+        assert_eq!(builder.build().err(), Some(BuildError::MissingBootRom));
+        let builder = GameBoy::builder().load_cartridge(&rom[..]).unwrap()
+                                        .use_emulator_window(NoWindow)
+                                        .use_hle_boot();
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn builder_rejects_unsupported_mappers() {
+        let mut rom = scrolling_cartridge(b"HUC3").rom().to_vec();
+        rom[0x147] = 0xFE;  // HuC3
+        let builder = GameBoy::builder().load_cartridge(&rom[..]).unwrap()
+                                        .use_emulator_window(NoWindow)
+                                        .use_hle_boot();
+        let header = builder.get_cartridge_header().unwrap();
+        assert_eq!(&header.title()[..4], b"HUC3");
+        let model = cartridge::MemoryControllerModel::HuC3;
+        assert_eq!(builder.build().err(),
+                   Some(BuildError::UnsupportedMapper(model)));
+
+        // Forcing a supported mapper makes the cartridge usable.
+        let overrides = cartridge::HeaderOverrides{
+            memory_controller: Some(cartridge::MemoryControllerModel::MBC5),
+            ram_size: None,
+        };
+        let builder = GameBoy::builder().load_cartridge(&rom[..]).unwrap()
+                                        .override_cartridge_header(&overrides)
+                                        .unwrap()
+                                        .use_emulator_window(NoWindow)
+                                        .use_hle_boot();
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn registers_and_memory_can_be_changed_from_outside() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.run_frame();
+        let mut registers = game_boy.cpu_registers();
+        assert!((0x0150..0x0159).contains(&registers.pc));
+        // Restart the loop with a counter that wraps in the next INC.
+        registers.pc = 0x0150;
+        game_boy.set_cpu_registers(&registers);
+        game_boy.poke(0xC000, 0xFF);
+        game_boy.cpu.step(&mut game_boy.memory);
+        game_boy.cpu.step(&mut game_boy.memory);
+        assert_eq!(game_boy.peek(0xC000), 0x00);
+        assert_eq!(game_boy.cpu_registers().pc, 0x0154);
+    }
+
+    #[test]
+    fn run_frame_returns_the_new_frame() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        let first = game_boy.run_frame().to_vec();
+        assert_eq!(first.len(), io::WIDTH * io::HEIGHT);
+        assert_eq!(game_boy.frame, 1);
+        let second = game_boy.run_frame().to_vec();
+        assert_eq!(game_boy.frame, 2);
+        // The logo scrolls from frame to frame.
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn buttons_can_be_set_without_the_frontend() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.set_buttons(io::Buttons::A | io::Buttons::START);
+        game_boy.run_frame();
+        assert_eq!(game_boy.pressed_keys, 0x90);
+        game_boy.set_buttons(io::Buttons::empty());
+        game_boy.run_frame();
+        assert_eq!(game_boy.pressed_keys, 0x00);
+        let buttons = io::Buttons::from_bits(0x90);
+        assert!(buttons.contains(io::Buttons::START));
+        assert!(!buttons.contains(io::Buttons::START | io::Buttons::B));
+    }
+
+    #[test]
+    fn run_stops_after_the_frame_limit() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.disable_throttle();
+        game_boy.run_frames(2);
+        game_boy.quit_after(3);
+        game_boy.run();
+        assert_eq!(game_boy.frame, 5);
+    }
+
+    /// Counts the samples that it gets
+    struct SampleCounter(std::sync::Arc<std::sync::Mutex<usize>>);
+
+    impl audio::AudioSink for SampleCounter {
+        fn push_samples(&mut self, samples: &[i16]) -> std::io::Result<()> {
+            *self.0.lock().unwrap() += samples.len();
+            Ok(())
+        }
+
+        fn sample_rate(&self) -> u32 {
+            apu::SAMPLE_RATE as u32
+        }
+    }
+
+    #[test]
+    fn audio_sinks_get_all_samples_even_without_throttle() {
+        let count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"), NoWindow);
+        game_boy.add_audio_sink(Box::new(SampleCounter(count.clone())));
+        game_boy.disable_throttle();
+        game_boy.run_frames(60);
+        // About a second of audio, give or take the samples of the
+        // frame that is still running
+        let per_second = apu::SAMPLE_RATE * apu::CHANNELS;
+        let count = *count.lock().unwrap();
+        assert!(count.abs_diff(per_second) < per_second / FRAMERATE,
+                "{} samples", count);
+    }
+
+    #[test]
+    fn uninitialized_reads_are_attributed_to_their_instruction() {
+        let mut rom = scrolling_cartridge(b"UNINIT").rom().to_vec();
+        rom[0x150..0x162].copy_from_slice(&[
+            0xFA, 0x00, 0xC1,  // LD A, (C100)
+            0xEA, 0x00, 0xE2,  // LD (E200), A
+            0xFA, 0x00, 0xC2,  // LD A, (C200)
+            0xF0, 0x80,        // LDH A, (FF80)
+            0xFA, 0x00, 0xE1,  // LD A, (E100)
+            0x18, 0xFE,        // JR -2
+            0x00, 0x00,
+        ]);
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, cartridge::Cartridge::from_rom(rom).unwrap(),
+            NoWindow);
+        game_boy.track_uninitialized_reads();
+        game_boy.run_frames(2);
+        // Echo RAM is the same memory as WRAM.
+        assert_eq!(game_boy.uninitialized_reads(), [
+            uninitialized::UninitializedRead{address: 0xC100, pc: 0x0150,
+                                             frame: 0},
+            uninitialized::UninitializedRead{address: 0xFF80, pc: 0x0159,
+                                             frame: 0},
+        ]);
+    }
+
+    #[test]
+    fn boxed_frontend_can_be_replaced() {
+        let mut game_boy: GameBoy<Box<dyn io::IO>> = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"),
+            Box::new(LiveButtons::default()));
+        game_boy.run_frames(1);
+        assert_eq!(game_boy.pressed_keys, 0x80);
+        let previous = game_boy.replace_emulator_window(Box::new(NoWindow));
+        game_boy.run_frames(1);
+        assert_eq!(game_boy.pressed_keys, 0x00);
+        // The box passes on the methods of the frontend in it.
+        assert_eq!(previous.get_key_presses(), 0x80);
+        assert_eq!(previous.poll_key_presses(), Some(0x80));
+        assert!(!previous.is_esc_pressed());
+    }
+
+    #[test]
+    fn buttons_are_polled_on_every_scanline() {
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"SCROLL"),
+            LiveButtons::default());
+        game_boy.run_frames(1);
+        assert_eq!(game_boy.emulator_window.polls.get(), 154);
+        assert_eq!(game_boy.pressed_keys, 0x80);
+    }
+
+    /// A Game Boy with a cartridge of the given type with 8 KiB of RAM,
+    /// which is enabled
+    fn game_boy_with_cartridge_ram(cartridge_type: u8) -> GameBoy<NoWindow> {
+        game_boy_with_cartridge_ram_and_window(cartridge_type, NoWindow)
+    }
+
+    fn game_boy_with_cartridge_ram_and_window<Window: io::IO>(
+            cartridge_type: u8, window: Window) -> GameBoy<Window> {
+        let mut rom = scrolling_cartridge(b"SAVE").rom().to_vec();
+        rom[0x147] = cartridge_type;
+        rom[0x149] = 0x02;
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, cartridge::Cartridge::from_rom(rom).unwrap(),
+            window);
+        game_boy.memory.write8(0x0000, 0x0A);
+        game_boy
+    }
+
+    /// MBC1 with RAM and battery
+    const MBC1_RAM_BATTERY: u8 = 0x03;
+
+    #[test]
+    fn save_file_survives_a_round_trip() {
+        let path = std::env::temp_dir().join("emulato-rs-round-trip.sav");
+        let _ = std::fs::remove_file(&path);
+        let mut game_boy = game_boy_with_cartridge_ram(MBC1_RAM_BATTERY);
+        // A missing save file is created on the first write.
+        game_boy.use_save_file(path.clone()).unwrap();
+        game_boy.memory.write8(0xA123, 0x42);
+        game_boy.write_save_file().unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(saved[0x123], 0x42);
+        assert!(!path.with_extension("sav.tmp").exists());
+
+        let mut game_boy = game_boy_with_cartridge_ram(MBC1_RAM_BATTERY);
+        game_boy.use_save_file(path.clone()).unwrap();
+        assert_eq!(game_boy.memory.read8(0xA123), 0x42);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_file_of_wrong_size_is_loaded_as_far_as_it_fits() {
+        let path = std::env::temp_dir().join("emulato-rs-wrong-size.sav");
+        let fresh_ram = game_boy_with_cartridge_ram(MBC1_RAM_BATTERY)
+            .memory.cartridge().ram().to_vec();
+
+        std::fs::write(&path, [0x01, 0x02, 0x03]).unwrap();
+        let mut game_boy = game_boy_with_cartridge_ram(MBC1_RAM_BATTERY);
+        game_boy.use_save_file(path.clone()).unwrap();
+        let ram = game_boy.memory.cartridge().ram();
+        assert_eq!(ram[..3], [0x01, 0x02, 0x03]);
+        assert_eq!(ram[3..], fresh_ram[3..]);
+
+        std::fs::write(&path, vec![0x77; 0x3000]).unwrap();
+        let mut game_boy = game_boy_with_cartridge_ram(MBC1_RAM_BATTERY);
+        game_boy.use_save_file(path.clone()).unwrap();
+        assert!(game_boy.memory.cartridge().ram().iter().all(|&b| b == 0x77));
+        // Writing the save file fixes its size.
+        game_boy.write_save_file().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x77; 0x2000]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cartridge_without_battery_has_no_save_file() {
+        let path = std::env::temp_dir().join("emulato-rs-no-battery.sav");
+        std::fs::write(&path, [0x55; 0x2000]).unwrap();
+        // MBC1 with RAM, but without battery
+        let mut game_boy = game_boy_with_cartridge_ram(0x02);
+        game_boy.use_save_file(path.clone()).unwrap();
+        assert_ne!(game_boy.memory.read8(0xA000), 0x55);
+        std::fs::remove_file(&path).unwrap();
+        game_boy.memory.write8(0xA000, 0x42);
+        game_boy.write_save_file().unwrap();
+        assert!(!path.exists());
+    }
+
+    /// Panics when showing the first frame
+    struct CrashingWindow;
+
+    impl io::IO for CrashingWindow {
+        fn refresh(&mut self, _pixels: &[u8]) {
+            panic!("Frontend crashed.");
+        }
+
+        fn is_esc_pressed(&self) -> bool {
+            false
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn save_file_is_written_when_the_emulator_panics() {
+        let path = std::env::temp_dir().join("emulato-rs-panic.sav");
+        let _ = std::fs::remove_file(&path);
+        let mut game_boy = game_boy_with_cartridge_ram_and_window(
+            MBC1_RAM_BATTERY, CrashingWindow);
+        game_boy.use_save_file(path.clone()).unwrap();
+        game_boy.memory.write8(0xA000, 0x42);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            game_boy.run();
+        }));
+        // The panic is passed on after writing the save file.
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap()[0], 0x42);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Records whether the save file exists in every frame and quits
+    /// after the first periodic flush
+    struct SaveFileWatcher {
+        path: std::path::PathBuf,
+        frames_with_save_file: Vec<bool>,
+    }
+
+    impl io::IO for SaveFileWatcher {
+        fn refresh(&mut self, _pixels: &[u8]) {
+            self.frames_with_save_file.push(self.path.exists());
+        }
+
+        fn is_esc_pressed(&self) -> bool {
+            self.frames_with_save_file.len() as u64
+                > SAVE_FILE_FLUSH_FRAMES
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn written_cartridge_ram_is_flushed_periodically() {
+        let path = std::env::temp_dir().join("emulato-rs-periodic.sav");
+        let _ = std::fs::remove_file(&path);
+        let watcher = SaveFileWatcher{
+            path: path.clone(),
+            frames_with_save_file: Vec::new(),
+        };
+        let mut game_boy = game_boy_with_cartridge_ram_and_window(
+            MBC1_RAM_BATTERY, watcher);
+        game_boy.use_save_file(path.clone()).unwrap();
+        game_boy.disable_throttle();
+        game_boy.memory.write8(0xA000, 0x42);
+        game_boy.run();
+        let frames = &game_boy.emulator_window.frames_with_save_file;
+        let first_frame_with_save_file = frames.iter()
+            .position(|&exists| exists);
+        assert_eq!(first_frame_with_save_file,
+                   Some(SAVE_FILE_FLUSH_FRAMES as usize));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Presses the given hotkeys in one frame after the other and quits
+    /// after the last ones
+    struct HotkeyScript {
+        script: Vec<Vec<io::Hotkey>>,
+        polls: std::cell::Cell<usize>,
+        refreshes: usize,
+    }
+
+    impl io::IO for HotkeyScript {
+        fn refresh(&mut self, _pixels: &[u8]) {
+            self.refreshes += 1;
+        }
+
+        fn is_esc_pressed(&self) -> bool {
+            self.polls.get() == self.script.len()
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            0
+        }
+
+        fn get_hotkeys(&self) -> Vec<io::Hotkey> {
+            let poll = self.polls.get();
+            self.polls.set(poll + 1);
+            self.script[poll].clone()
+        }
+    }
+
+    /// Run with the hotkeys of the script and return the number of
+    /// emulated and shown frames
+    fn run_with_hotkeys(script: Vec<Vec<io::Hotkey>>) -> (u64, usize) {
+        let window = HotkeyScript{
+            script,
+            polls: std::cell::Cell::new(0),
+            refreshes: 0,
+        };
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"PAUSE"), window);
+        game_boy.disable_throttle();
+        game_boy.run();
+        (game_boy.frame, game_boy.emulator_window.refreshes)
+    }
+
+    #[test]
+    fn paused_emulation_only_advances_frame_by_frame() {
+        use io::Hotkey::*;
+
+        // Every loop shows a frame, but paused loops don't emulate one.
+        assert_eq!(run_with_hotkeys(vec![vec![Pause], vec![], vec![],
+                                         vec![Pause], vec![]]),
+                   (3, 6));
+        assert_eq!(run_with_hotkeys(vec![vec![Pause], vec![FrameAdvance],
+                                         vec![], vec![FrameAdvance],
+                                         vec![]]),
+                   (3, 6));
+        // Advancing a running emulation pauses it after the next frame.
+        assert_eq!(run_with_hotkeys(vec![vec![FrameAdvance], vec![],
+                                         vec![], vec![]]),
+                   (2, 5));
+    }
+
+    /// Keeps the batches of samples that it gets
+    struct SampleRecorder(std::sync::Arc<std::sync::Mutex<Vec<Vec<i16>>>>);
+
+    impl audio::AudioSink for SampleRecorder {
+        fn push_samples(&mut self, samples: &[i16]) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(samples.to_vec());
+            Ok(())
+        }
+
+        fn sample_rate(&self) -> u32 {
+            apu::SAMPLE_RATE as u32
+        }
+    }
+
+    #[test]
+    fn rewinding_fades_out_the_playback() {
+        use io::Hotkey::*;
+
+        // Rewind while the hotkey is held in the last 10 frames.
+        let mut script = vec![vec![]; 30];
+        script.extend(vec![vec![Rewind]; 10]);
+        let window = HotkeyScript{
+            script,
+            polls: std::cell::Cell::new(0),
+            refreshes: 0,
+        };
+        let mut game_boy = GameBoy::with_hle_boot(
+            boot_rom::Model::DMG, scrolling_cartridge(b"REWIND"), window);
+        let batches = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        game_boy.playback = Some(audio::ResampledSink::new(
+            Box::new(SampleRecorder(batches.clone()))));
+        game_boy.enable_rewind(1);
+        game_boy.disable_throttle();
+        game_boy.audio_policy.set_speed(1.);
+        // Play a square wave on channel 1 at full volume.
+        for (address, value) in [(0xFF26, 0x80), (0xFF24, 0x77),
+                                 (0xFF25, 0xFF), (0xFF11, 0x80),
+                                 (0xFF12, 0xF0), (0xFF13, 0x00),
+                                 (0xFF14, 0x87)] {
+            game_boy.poke(address, value);
+        }
+        game_boy.run();
+        // The hotkeys of a frame apply from the next one, so 31 frames
+        // are emulated before going back to the state after frame 12.
+        assert_eq!(game_boy.frame, 12);
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 41);
+        assert!(batches[..31].iter().flatten().any(|&sample| sample != 0));
+        // Each rewound frame still plays a frame of audio, which fades
+        // out the sound from before and is silent afterwards.
+        let frame_samples = apu::SAMPLE_RATE / FRAMERATE * apu::CHANNELS;
+        assert!(batches[31..].iter().all(|batch| {
+            batch.len().abs_diff(frame_samples) <= apu::CHANNELS
+        }));
+        assert!(batches[31].iter().any(|&sample| sample != 0));
+        assert!(batches[32..].iter().flatten().all(|&sample| sample == 0));
     }
 }
@@ -0,0 +1,138 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Read, Write};
+
+/// Start of movie files, ending in the version of their format
+const MAGIC: &[u8; 8] = b"GBMOVIE\x01";
+
+/// Input passed to the game in one frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameInput {
+    /// JoyPad buttons, see `IO::get_key_presses`
+    pub keys: u8,
+    /// Accelerometer, see `IO::get_tilt`
+    pub tilt: (f32, f32),
+}
+
+impl FrameInput {
+    const SIZE: usize = 9;
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [self.keys; Self::SIZE];
+        bytes[1..5].copy_from_slice(&self.tilt.0.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.tilt.1.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self{
+            keys: bytes[0],
+            tilt: (f32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+                   f32::from_le_bytes(bytes[5..9].try_into().unwrap())),
+        }
+    }
+}
+
+/// Writes a movie file while the game is played
+///
+/// A movie file starts with the state of the machine when the
+/// recording started, followed by the input of every frame since.
+/// Running the state with the same input repeats the recorded game,
+/// as long as no devices are connected to the Game Boy.
+pub struct MovieRecorder<W: Write> {
+    writer: W,
+    frames: u64,
+}
+
+impl<W: Write> MovieRecorder<W> {
+    /// Start a movie from a state written by `GameBoy::save_state`
+    /// without its `StateInfo`
+    pub fn new(mut writer: W, initial_state: &[u8]) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(initial_state.len() as u64).to_le_bytes())?;
+        writer.write_all(initial_state)?;
+        Ok(Self{
+            writer,
+            frames: 0,
+        })
+    }
+
+    pub fn record(&mut self, input: FrameInput) -> io::Result<()> {
+        self.frames += 1;
+        self.writer.write_all(&input.to_bytes())
+    }
+
+    /// Number of frames recorded so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Flush the movie and return the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// A movie file as written by `MovieRecorder`
+pub struct Movie {
+    pub initial_state: Vec<u8>,
+    pub inputs: Vec<FrameInput>,
+}
+
+impl Movie {
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a movie of this version of the emulator"));
+        }
+        let mut length = [0; 8];
+        reader.read_exact(&mut length)?;
+        let mut initial_state = vec![0; u64::from_le_bytes(length) as usize];
+        reader.read_exact(&mut initial_state)?;
+        let mut inputs = Vec::new();
+        reader.read_to_end(&mut inputs)?;
+        if inputs.len() % FrameInput::SIZE != 0 {
+            log::warn!("Ignoring incomplete input of the last movie frame.");
+        }
+        let inputs = inputs.chunks_exact(FrameInput::SIZE)
+                           .map(|bytes| {
+                               FrameInput::from_bytes(bytes.try_into()
+                                                           .unwrap())
+                           })
+                           .collect();
+        Ok(Self{
+            initial_state,
+            inputs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_movie_is_read_back() {
+        let inputs = [
+            FrameInput{keys: 0x00, tilt: (0.0, 0.0)},
+            FrameInput{keys: 0x81, tilt: (-1.0, 0.5)},
+        ];
+        let mut recorder = MovieRecorder::new(Vec::new(), b"state").unwrap();
+        for input in inputs {
+            recorder.record(input).unwrap();
+        }
+        assert_eq!(recorder.frames(), 2);
+        let mut file = recorder.finish().unwrap();
+        // An interrupted recording may end in the middle of a frame.
+        file.push(0x01);
+        let movie = Movie::read(&mut &file[..]).unwrap();
+        assert_eq!(movie.initial_state, b"state");
+        assert_eq!(movie.inputs, inputs);
+    }
+}
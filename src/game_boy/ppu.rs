@@ -4,195 +4,209 @@
 
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
 use super::display;
-use super::io::IO;
+use super::io::{IO, WIDTH};
 use super::memory::{LcdControl, MemoryBus};
+use super::tile_cache::{TileCache, MAP_SIZE};
 
+#[derive(Serialize, Deserialize)]
 pub struct PPU {
     display: display::Display,
-    tile_buffer: [u8; 21],
-    bg_palette: [u8; 4],
+    scroll_latch: ScrollLatch,
+    /// Mode 2 of the current line
+    oam_scan: Option<OamScan>,
+    /// Mode 3 of the current line
+    line: Option<LineState>,
+    /// Decoded VRAM for `ScrollLatch::PerFrame`
+    #[serde(skip)]
+    tile_cache: Option<TileCache>,
+    /// Whether the current frame is drawn from the tile cache, which
+    /// stops once a raster effect is detected
+    frame_is_cached: bool,
+    /// Line of the window to draw next
+    ///
+    /// It only advances on lines on which the window is drawn, so that
+    /// the window continues where it left off when it is hidden for
+    /// some lines.
+    window_line_counter: u8,
+}
+
+/// When the background is fetched and thereby SCX and SCY are read
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScrollLatch {
+    /// Run the whole line through the pixel FIFO at the start of mode 3
+    ///
+    /// This is faster, but changes to the scroll registers, palettes or
+    /// LCDC in the middle of a line only take effect on the next line.
+    PerLine,
+    /// Fetch each tile during mode 3 after the CPU has run up to it
+    ///
+    /// The coarse scroll position is read at every tile fetch, so that
+    /// wavy-scroll effects render correctly.  Like on the hardware, the
+    /// fine scroll SCX % 8 is only applied at the start of the line.
+    PerFetch,
+    /// Draw lines from a cache of decoded tiles and tile maps
+    ///
+    /// This is much faster than the pixel FIFO, but the length of mode 3
+    /// is only estimated.  The cache is updated at the start of a frame.
+    /// Once VRAM, the palettes or the LCD control, scroll or window
+    /// position registers are written while the frame is drawn, the
+    /// rest of the frame is drawn like with `PerLine`.
+    PerFrame,
 }
 
+/// Length of mode 2, in which OAM is scanned
+pub const MODE2_DOTS: usize = 80;
+const OAM_SCAN_DOTS_PER_ENTRY: usize = 2;
+/// Dots at the start of mode 3 in which the first tile is fetched and
+/// then thrown away
+const STARTUP_DOTS: u8 = 6;
+/// Dots that fetching the tile of an object takes once the background
+/// fetcher is ready
+const OBJ_FETCH_DOTS: u8 = 6;
+
 impl PPU {
     pub fn new() -> Self {
         Self{
             display: display::Display::new(),
-            tile_buffer: [0; 21],
-            bg_palette: [0; 4],
+            scroll_latch: ScrollLatch::PerLine,
+            oam_scan: None,
+            line: None,
+            tile_cache: None,
+            frame_is_cached: false,
+            window_line_counter: 0,
+        }
+    }
+
+    pub fn set_scroll_latch(&mut self, scroll_latch: ScrollLatch) {
+        self.scroll_latch = scroll_latch;
+        self.tile_cache = match scroll_latch {
+            ScrollLatch::PerFrame => Some(TileCache::default()),
+            _ => None,
+        };
+    }
+
+    /// Take over the state of a PPU loaded from a save state
+    ///
+    /// The scroll latch stays as it is.  The tile cache is decoded anew
+    /// at the start of the next frame, until then lines are drawn by the
+    /// pixel FIFO.
+    pub fn restore_state(&mut self, saved: Self) {
+        let scroll_latch = self.scroll_latch;
+        *self = saved;
+        self.set_scroll_latch(scroll_latch);
+        self.frame_is_cached = false;
+    }
+
+    /// Start scanning OAM for the objects on the current line at the
+    /// beginning of mode 2
+    pub fn start_oam_scan(&mut self) {
+        self.oam_scan = Some(OamScan::default());
+    }
+
+    /// Scan OAM up to the given dot of mode 2
+    ///
+    /// Changes to OAM only affect the entries that haven't been checked
+    /// yet.
+    pub fn scan_oam_until(&mut self, memory: &MemoryBus, dot: usize) {
+        if let Some(oam_scan) = &mut self.oam_scan {
+            oam_scan.scan_until(memory, dot);
         }
     }
 
-    pub fn paint_line(&mut self, memory: &mut MemoryBus) {
+    /// Start mode 3 of the current line
+    ///
+    /// This finishes the OAM scan, which is done as a whole if it hasn't
+    /// been started by `start_oam_scan`.  With `ScrollLatch::PerLine`
+    /// and `ScrollLatch::PerFrame`, it also paints the whole line,
+    /// otherwise the line is painted by `paint_line_until`.
+    pub fn start_line(&mut self, memory: &mut MemoryBus) {
         let ly = memory.ly();
+        let mut oam_scan = self.oam_scan.take().unwrap_or_default();
         if ly >= 144 {
-            // VBLANK line
+            self.line = None;
             return;
         }
-        let lcdc = memory.lcdc();
-        if lcdc.is_bg_and_window_enabled() {
-            self.paint_background_line(memory, lcdc, ly);
-            if lcdc.is_window_enabled() {
-                self.paint_window_line(memory, lcdc, ly);
-            }
+        oam_scan.scan_until(memory, MODE2_DOTS);
+        if ly == 0 {
+            self.window_line_counter = 0;
+        } else if self.line.as_ref().is_some_and(LineState::draws_window) {
+            self.window_line_counter = self.window_line_counter
+                                           .wrapping_add(1);
         }
-        if lcdc.is_obj_enabled() {
-            self.paint_obj_line(memory, ly);
-        }
-    }
-
-    fn paint_background_line(&mut self, memory: &mut MemoryBus,
-                             lcdc: LcdControl, ly: u8) {
-        let (y, _) = ly.overflowing_add(memory.scy());
-        let tile_y = y / 8;
-        let in_tile_y = y % 8;
-        let x_offset = memory.scx();
-        let tile_x = x_offset / 8;
-        let mut tile_pixel_index = 7 - (x_offset % 8);
-        self.copy_tile_map_line(memory, lcdc.bg_tilemap_start(),
-                                tile_y, tile_x);
-        self.read_bg_palette(memory);
-        let mut tile_iter = self.tile_buffer.iter();
-        let mut tile_data = fetch_bg_tile_line(
-            memory, lcdc, *tile_iter.next().unwrap(), in_tile_y);
-        for pixel in self.display.line_buffer(ly).iter_mut() {
-            let p = ((tile_data >> (tile_pixel_index + 7)) & 0b10)
-                    | ((tile_data >> tile_pixel_index) & 1);
-            *pixel = self.bg_palette[p as usize];
-            if tile_pixel_index > 0 {
-                tile_pixel_index -= 1;
-            } else {
-                tile_data = fetch_bg_tile_line(
-                    memory, lcdc, *tile_iter.next().unwrap(), in_tile_y);
-                tile_pixel_index = 7;
+        if let Some(tile_cache) = &mut self.tile_cache {
+            if ly == 0 {
+                memory.take_raster_write();
+                tile_cache.update(memory);
+                self.frame_is_cached = true;
+            } else if memory.take_raster_write() {
+                self.frame_is_cached = false;
+            }
+            if self.frame_is_cached {
+                let pixels = self.display.line_buffer(ly);
+                self.line = Some(draw_cached_line(memory, tile_cache, pixels,
+                                                  self.window_line_counter,
+                                                  oam_scan.objects));
+                return;
             }
         }
-    }
-
-    fn paint_window_line(&mut self, memory: &mut MemoryBus,
-                         lcdc: LcdControl, ly: u8) {
-        let wy = memory.wy();
-        let wx = memory.wx();
-        if ly < wy || wx > 166 {
-            return;
-        }
-        if wx < 7 || wx == 166 {
-            eprintln!("Window hardware bugs for WX = {} not implemented.", wx);
-        }
-        let y = ly - wy;
-        let tile_y = y / 8;
-        let in_tile_y = y % 8;
-        let (x_offset, mut tile_pixel_index) = if wx >= 7 {
-            (wx - 7, 7)
-        } else {
-            (0, wx)
+        let latched_scroll = match self.scroll_latch {
+            ScrollLatch::PerFetch => None,
+            _ => Some((memory.scx(), memory.scy())),
         };
-        self.copy_tile_map_line(memory, lcdc.window_tilemap_start(),
-                                tile_y, 0);
-        self.read_bg_palette(memory);
-        let mut tile_iter = self.tile_buffer.iter();
-        let mut tile_data = fetch_bg_tile_line(
-            memory, lcdc, *tile_iter.next().unwrap(), in_tile_y);
-        for pixel in self.display.line_buffer(ly)[x_offset as usize..]
-                                 .iter_mut() {
-            let p = ((tile_data >> (tile_pixel_index + 7)) & 0b10)
-                    | ((tile_data >> tile_pixel_index) & 1);
-            *pixel = self.bg_palette[p as usize];
-            if tile_pixel_index > 0 {
-                tile_pixel_index -= 1;
-            } else {
-                tile_data = fetch_bg_tile_line(
-                    memory, lcdc, *tile_iter.next().unwrap(), in_tile_y);
-                tile_pixel_index = 7;
-            }
+        self.line = Some(LineState::Fifo(
+            LineRenderer::new(memory, latched_scroll,
+                              self.window_line_counter, oam_scan.objects)));
+        if self.scroll_latch != ScrollLatch::PerFetch {
+            self.paint_line_until(memory, usize::MAX);
         }
     }
 
-    fn paint_obj_line(&mut self, memory: &mut MemoryBus, ly: u8) {
-        let lcdc = memory.lcdc();
-        let obj_height = lcdc.obj_height();
-        let mut sprites: Vec<Sprite> = Vec::with_capacity(10);
-        for address in (0xFE00..0xFEA0).step_by(4) {
-            let y = memory.read8(address);
-            if ly + 16 < y || ly + 16 >= y + obj_height {
-                // OBJ outside ly
-                continue;
-            }
-            let obj_line = ly + 16 - y;
-            let x = memory.read8(address+1);
-            let tile_index = memory.read8(address+2);
-            let attribute_flags = memory.read8(address+3);
-            sprites.push(Sprite::new(obj_line, x, tile_index, attribute_flags));
-            if sprites.len() == 10 {
-                break;
-            }
-        }
-        if sprites.is_empty() {
-            return;
-        }
-        let palettes = [memory.obj_palette0().as_array(),
-                        memory.obj_palette1().as_array()];
-        // sort sprites by priority
-        sprites.sort_by(|a, b| {a.x().cmp(&b.x())});
-        let pixels = self.display.line_buffer(ly);
-        for sprite in sprites {
-            // TODO: Correctly handle overlapping sprites
-            let attributes = sprite.attribute_flags();
-            let y = if attributes.y_flip() {
-                obj_height - 1 - sprite.y()
-            } else {
-                sprite.y()
-            };
-            let tile = fetch_obj_tile_line(memory, sprite.tile_index(),
-                                           y, obj_height == 16);
-            let palette = palettes[attributes.palette()];
-            let x = sprite.x();
-            let i_min = if x > 160 {
-                x - 160
-            } else {
-                0
-            };
-            let i_max = std::cmp::min(x, 8);
-            for i in i_min..i_max {
-                let x = (x - 1 - i) as usize;
-                let i = if attributes.x_flip() {
-                    7 - i
-                } else {
-                    i
-                };
-                let p = ((tile >> (i + 7)) & 0b10)
-                        | ((tile >> i) & 1);
-                if p > 0 {
-                    pixels[x] = palette[p as usize];
-                }
+    /// Paint the current line up to the given dot of mode 3
+    ///
+    /// Return true if mode 3 has ended by that dot.
+    pub fn paint_line_until(&mut self, memory: &MemoryBus, dot: usize)
+            -> bool {
+        let line = match &mut self.line {
+            Some(LineState::Fifo(line)) => line,
+            Some(LineState::Cached{mode3_length, ..}) => {
+                return *mode3_length <= dot;
             }
+            None => return true,
+        };
+        let pixels = self.display.line_buffer(line.ly);
+        while !line.is_finished() && line.dot < dot {
+            line.tick(memory, pixels);
         }
+        line.is_finished() && line.dot <= dot
     }
 
-    fn copy_tile_map_line(&mut self, memory: &MemoryBus,
-                          tilemap_start: u16, y: u8, x: u8) {
-        let line_offset = tilemap_start + 32 * y as u16;
-        let mut x = x as u16;
-        for tile in self.tile_buffer.iter_mut() {
-            *tile = memory.read8(line_offset + x);
-            x = (x + 1) % 32;
+    /// Length in dots of mode 3 of the current line, once it has ended
+    pub fn mode3_length(&self) -> Option<usize> {
+        match self.line.as_ref()? {
+            LineState::Fifo(line) => {
+                Some(line.dot).filter(|_| line.is_finished())
+            }
+            LineState::Cached{mode3_length, ..} => Some(*mode3_length),
         }
     }
 
-    fn read_bg_palette(&mut self, memory: &MemoryBus) {
-        self.bg_palette = memory.bg_palette().as_array();
-    }
-
     pub fn refresh<Window: IO>(&self, window: &mut Window) {
         self.display.refresh(window);
     }
+
+    pub fn screen(&self) -> &[u8] {
+        self.display.pixels()
+    }
 }
 
 fn fetch_bg_tile_line(memory: &MemoryBus, lcdc: LcdControl, tile: u8,
                       in_tile_y: u8) -> u16 {
     let tile = lcdc.get_bg_or_window_tile_address(tile);
     let low = tile + (2 * in_tile_y) as u16;
-    memory.read16(low)
+    memory.ppu_read16(low)
 }
 
 fn fetch_obj_tile_line(memory: &MemoryBus, tile: u8,
@@ -207,22 +221,504 @@ fn fetch_obj_tile_line(memory: &MemoryBus, tile: u8,
         (tile, in_tile_y)
     };
     let low = 0x8000 + 16 * tile as u16 + (2 * in_tile_y) as u16;
-    memory.read16(low)
+    memory.ppu_read16(low)
+}
+
+/// Color index of the pixel `index` of a tile line, counting from the
+/// right
+fn tile_pixel(tile_data: u16, index: u8) -> u8 {
+    (((tile_data >> (index + 7)) & 0b10) | ((tile_data >> index) & 1)) as u8
+}
+
+/// How the current line is drawn
+#[derive(Serialize, Deserialize)]
+enum LineState {
+    Fifo(LineRenderer),
+    /// Drawn at once from the tile cache
+    Cached {
+        /// Estimated length of mode 3
+        mode3_length: usize,
+        draws_window: bool,
+    },
+}
+
+impl LineState {
+    fn draws_window(&self) -> bool {
+        match self {
+            Self::Fifo(line) => line.window_line.is_some(),
+            Self::Cached{draws_window, ..} => *draws_window,
+        }
+    }
+}
+
+/// Draw the current line from the tile cache
+///
+/// The background, window and objects are combined like in the pixel
+/// FIFO, but without running the fetcher, so that mode 3 gets the
+/// length that the pixel FIFO would take without mid-line changes.
+fn draw_cached_line(memory: &MemoryBus, tile_cache: &TileCache,
+                    pixels: &mut [u8], window_line: u8,
+                    mut objects: Vec<Sprite>) -> LineState {
+    let lcdc = memory.lcdc();
+    let ly = memory.ly();
+    let scx = memory.scx();
+    let wx = memory.wx();
+    let draws_window = lcdc.is_window_enabled() && ly >= memory.wy()
+                       && wx <= 166;
+    let mut bg_colors = [0; WIDTH];
+    if lcdc.is_bg_and_window_enabled() {
+        let map = tile_cache.map(lcdc.bg_tilemap_start());
+        let row = ly.wrapping_add(memory.scy()) as usize * MAP_SIZE;
+        for (x, color) in bg_colors.iter_mut().enumerate() {
+            *color = map[row + (x + scx as usize) % MAP_SIZE];
+        }
+        if draws_window {
+            let map = tile_cache.map(lcdc.window_tilemap_start());
+            let row = window_line as usize * MAP_SIZE;
+            // The window starts 7 - WX pixels to the left of the screen.
+            let left = wx as usize;
+            for x in left.saturating_sub(7)..WIDTH {
+                bg_colors[x] = map[row + x + 7 - left];
+            }
+        }
+    }
+    let mut mode3_length = match (draws_window, wx) {
+        // A window at the left border replaces the fine scroll of the
+        // background with its own.
+        (true, 0..=7) => 172 + (7 - wx) as usize,
+        (true, _) => 172 + (scx & 7) as usize + OBJ_FETCH_DOTS as usize,
+        (false, _) => 172 + (scx & 7) as usize,
+    };
+    let mut obj_pixels = [None; WIDTH];
+    if lcdc.is_obj_enabled() {
+        objects.retain(|object| object.x() < 168);
+        // Like in the pixel FIFO, objects with a lower X take priority.
+        objects.sort_by_key(|object| object.x());
+        let mut fetched_columns = Vec::new();
+        for object in &objects {
+            // Fetching an object first waits for the background fetcher
+            // to finish the tile under it.  Objects cut off by the left
+            // border are fetched at the first pixel.
+            let x = std::cmp::max(object.x(), 8);
+            // Window tiles start at WX - 7 instead of at -SCX % 8.
+            let offset = if draws_window && x > std::cmp::max(wx, 7) {
+                7_u8.wrapping_sub(wx)
+            } else {
+                scx
+            };
+            let fine_x = x.wrapping_add(offset) & 7;
+            let column = (offset != scx, x.wrapping_add(offset) / 8);
+            if !fetched_columns.contains(&column) {
+                fetched_columns.push(column);
+                mode3_length += 5 - std::cmp::min(5, fine_x) as usize;
+            }
+            mode3_length += OBJ_FETCH_DOTS as usize;
+            draw_cached_object(tile_cache, lcdc, object, &mut obj_pixels);
+        }
+    }
+    for (x, pixel) in pixels.iter_mut().enumerate() {
+        let bg_color = bg_colors[x];
+        *pixel = match obj_pixels[x] {
+            Some(object) if !(object.bg_over_obj && bg_color != 0) => {
+                let palette = match object.palette {
+                    0 => memory.obj_palette0(),
+                    _ => memory.obj_palette1(),
+                };
+                let layer = (1 + object.palette) << 2;
+                palette.as_array()[object.color as usize] | layer
+            }
+            _ => memory.bg_palette().as_array()[bg_color as usize],
+        };
+    }
+    LineState::Cached{mode3_length, draws_window}
+}
+
+/// Draw the non-transparent pixels of an object where no object has
+/// been drawn yet
+fn draw_cached_object(tile_cache: &TileCache, lcdc: LcdControl,
+                      object: &Sprite, obj_pixels: &mut [Option<ObjPixel>]) {
+    let attributes = object.attribute_flags();
+    let obj_height = lcdc.obj_height();
+    let y = if attributes.y_flip() {
+        obj_height - 1 - object.y()
+    } else {
+        object.y()
+    };
+    let (tile, y) = match (obj_height, y) {
+        (16, 0..=7) => (object.tile_index() & 0xFE, y),
+        (16, _) => (object.tile_index() | 1, y - 8),
+        _ => (object.tile_index(), y),
+    };
+    let line = tile_cache.obj_tile_line(tile, y);
+    for column in 0..8 {
+        let x = match (object.x() as usize + column).checked_sub(8) {
+            Some(x) if x < WIDTH => x,
+            _ => continue,
+        };
+        let color = if attributes.x_flip() {
+            line[7 - column]
+        } else {
+            line[column]
+        };
+        if color != 0 && obj_pixels[x].is_none() {
+            obj_pixels[x] = Some(ObjPixel{
+                color,
+                palette: attributes.palette() as u8,
+                bg_over_obj: attributes.bg_and_window_over_obj(),
+            });
+        }
+    }
+}
+
+/// Mode 3 of a line, in which pixels are shifted out of the pixel FIFO
+/// to the LCD one per dot
+///
+/// Fine scrolling, the start of the window and fetching objects stall
+/// the output, which makes mode 3 last between 172 and 289 dots.
+#[derive(Serialize, Deserialize)]
+struct LineRenderer {
+    ly: u8,
+    /// Dots since the start of mode 3
+    dot: usize,
+    /// Next pixel of the line to output
+    x: usize,
+    /// Dots left before the fetcher starts
+    startup_dots: u8,
+    /// Pixels still to drop from the FIFO, SCX % 8 at the start of the
+    /// line
+    discarded_pixels: u8,
+    /// SCX and SCY if they are latched for the whole line
+    latched_scroll: Option<(u8, u8)>,
+    fifo: PixelFifo,
+    fetcher: Fetcher,
+    /// Line of the window to draw if the window starts on this line
+    next_window_line: u8,
+    /// Line of the window being drawn, if the window has started
+    window_line: Option<u8>,
+    /// Objects on the line that haven't been fetched yet, in OAM order
+    objects: Vec<Sprite>,
+    /// Object being fetched and the dots left of its fetch
+    object_fetch: Option<(Sprite, u8)>,
+}
+
+impl LineRenderer {
+    fn new(memory: &MemoryBus, latched_scroll: Option<(u8, u8)>,
+           next_window_line: u8, objects: Vec<Sprite>) -> Self {
+        let ly = memory.ly();
+        let scx = latched_scroll.map_or_else(|| memory.scx(), |(x, _)| x);
+        Self{
+            ly,
+            dot: 0,
+            x: 0,
+            startup_dots: STARTUP_DOTS,
+            discarded_pixels: scx & 7,
+            latched_scroll,
+            fifo: PixelFifo::new(),
+            fetcher: Fetcher::new(false),
+            next_window_line,
+            window_line: None,
+            objects,
+            object_fetch: None,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.x == WIDTH
+    }
+
+    /// Advance by one dot
+    fn tick(&mut self, memory: &MemoryBus, pixels: &mut [u8]) {
+        self.dot += 1;
+        if self.startup_dots > 0 {
+            self.startup_dots -= 1;
+            return;
+        }
+        let lcdc = memory.lcdc();
+        if self.window_line.is_none() && self.object_fetch.is_none() {
+            self.check_window_start(memory, lcdc);
+        }
+        if self.object_fetch.is_none()
+           || self.fetcher.step != FetcherStep::Push {
+            self.fetcher.tick(memory, lcdc, &mut self.fifo, self.ly,
+                              self.latched_scroll, self.window_line);
+        }
+        if let Some((object, dots)) = self.object_fetch {
+            // The background fetcher finishes its current tile first.
+            if self.fetcher.step != FetcherStep::Push {
+                return;
+            }
+            if dots > 1 {
+                self.object_fetch = Some((object, dots - 1));
+                return;
+            }
+            self.object_fetch = None;
+            self.fifo.merge_object(memory, lcdc, &object, self.x);
+        }
+        if self.discarded_pixels == 0 && lcdc.is_obj_enabled() {
+            // Of the objects reached at once at the left border, the one
+            // with the lowest X is fetched first, so that it takes
+            // priority.
+            let x = self.x;
+            let next_object = self.objects.iter().enumerate()
+                .filter(|(_, o)| o.x() as usize <= x + 8)
+                .min_by_key(|&(i, o)| (o.x(), i));
+            if let Some((i, _)) = next_object {
+                self.object_fetch = Some((self.objects.remove(i),
+                                          OBJ_FETCH_DOTS));
+                return;
+            }
+        }
+        let (bg_color, object) = match self.fifo.pop() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.discarded_pixels > 0 {
+            self.discarded_pixels -= 1;
+            return;
+        }
+        let bg_color = if lcdc.is_bg_and_window_enabled() {
+            bg_color
+        } else {
+            0
+        };
+        pixels[self.x] = match object {
+            Some(object) if object.color != 0
+                            && lcdc.is_obj_enabled()
+                            && !(object.bg_over_obj && bg_color != 0) => {
+                let palette = match object.palette {
+                    0 => memory.obj_palette0(),
+                    _ => memory.obj_palette1(),
+                };
+                // Mark the pixels with the object palette they use, so
+                // that frontends can colorize objects differently from
+                // the BG.
+                let layer = (1 + object.palette) << 2;
+                palette.as_array()[object.color as usize] | layer
+            }
+            _ => memory.bg_palette().as_array()[bg_color as usize],
+        };
+        self.x += 1;
+    }
+
+    /// Switch the fetcher to the window once the output reaches WX
+    fn check_window_start(&mut self, memory: &MemoryBus, lcdc: LcdControl) {
+        let wy = memory.wy();
+        let wx = memory.wx();
+        if !lcdc.is_window_enabled() || self.ly < wy
+           || self.x + 7 != std::cmp::max(wx, 7) as usize {
+            return;
+        }
+        if wx == 166 {
+            log::warn!("Window hardware bug for WX = 166 not implemented.");
+        }
+        // The window starts 7 - WX pixels to the left of the screen.
+        self.discarded_pixels = 7_u8.saturating_sub(wx);
+        self.window_line = Some(self.next_window_line);
+        self.fifo.background.clear();
+        self.fetcher = Fetcher::new(true);
+    }
+}
+
+/// Selection of the objects to draw on a line during mode 2
+///
+/// Each of the 40 OAM entries is checked in 2 dots.  The first 10
+/// objects that overlap the line are selected, in OAM order and
+/// regardless of their X coordinate, so that objects that are off
+/// screen horizontally still take up a slot.
+#[derive(Default, Serialize, Deserialize)]
+struct OamScan {
+    /// Next OAM entry to check
+    entry: u16,
+    objects: Vec<Sprite>,
+}
+
+impl OamScan {
+    fn scan_until(&mut self, memory: &MemoryBus, dot: usize) {
+        let end = std::cmp::min(dot / OAM_SCAN_DOTS_PER_ENTRY, 40) as u16;
+        let ly = memory.ly();
+        while self.entry < end && self.objects.len() < 10 {
+            let address = 0xFE00 + 4 * self.entry;
+            self.entry += 1;
+            // The object height is read anew for each entry.
+            let obj_height = memory.lcdc().obj_height() as u16;
+            let y = memory.ppu_read8(address);
+            let obj_line = (ly as u16 + 16).wrapping_sub(y as u16);
+            if obj_line >= obj_height {
+                continue;
+            }
+            let x = memory.ppu_read8(address+1);
+            let tile_index = memory.ppu_read8(address+2);
+            let attribute_flags = memory.ppu_read8(address+3);
+            self.objects.push(Sprite::new(obj_line as u8, x, tile_index,
+                                          attribute_flags));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum FetcherStep {
+    GetTile,
+    GetTileDataLow,
+    GetTileDataHigh,
+    Push,
+}
+
+/// Fetches tiles of the background or window into the pixel FIFO
+///
+/// Each step except for Push takes 2 dots.  Pushing waits until the
+/// background FIFO is empty.
+#[derive(Serialize, Deserialize)]
+struct Fetcher {
+    step: FetcherStep,
+    /// Dots spent in the current step
+    step_dots: u8,
+    /// Number of tiles fetched since the start of the line or window
+    tiles_fetched: u8,
+    fetches_window: bool,
+    tile: u8,
+    in_tile_y: u8,
+    tile_data: u16,
+}
+
+impl Fetcher {
+    fn new(fetches_window: bool) -> Self {
+        Self{
+            step: FetcherStep::GetTile,
+            step_dots: 0,
+            tiles_fetched: 0,
+            fetches_window,
+            tile: 0,
+            in_tile_y: 0,
+            tile_data: 0,
+        }
+    }
+
+    fn tick(&mut self, memory: &MemoryBus, lcdc: LcdControl,
+            fifo: &mut PixelFifo, ly: u8, latched_scroll: Option<(u8, u8)>,
+            window_line: Option<u8>) {
+        use FetcherStep::*;
+        if self.step == Push {
+            if fifo.background.is_empty() {
+                fifo.background.extend((0..8).rev().map(|i| {
+                    tile_pixel(self.tile_data, i)
+                }));
+                self.tiles_fetched = self.tiles_fetched.wrapping_add(1);
+                self.step = GetTile;
+            }
+            return;
+        }
+        self.step_dots += 1;
+        if self.step_dots < 2 {
+            return;
+        }
+        self.step_dots = 0;
+        self.step = match self.step {
+            GetTile => {
+                let (tilemap_start, tile_x, y) = match window_line {
+                    Some(window_line) if self.fetches_window => {
+                        (lcdc.window_tilemap_start(), self.tiles_fetched,
+                         window_line)
+                    }
+                    _ => {
+                        let (scx, scy) = latched_scroll.unwrap_or_else(|| {
+                            (memory.scx(), memory.scy())
+                        });
+                        (lcdc.bg_tilemap_start(),
+                         (scx / 8).wrapping_add(self.tiles_fetched),
+                         ly.wrapping_add(scy))
+                    }
+                };
+                self.tile = memory.ppu_read8(tilemap_start + 32 * (y / 8) as u16
+                                             + (tile_x % 32) as u16);
+                self.in_tile_y = y % 8;
+                GetTileDataLow
+            }
+            GetTileDataLow => GetTileDataHigh,
+            GetTileDataHigh => {
+                self.tile_data = fetch_bg_tile_line(memory, lcdc, self.tile,
+                                                    self.in_tile_y);
+                Push
+            }
+            Push => unreachable!(),
+        };
+    }
 }
 
 /// The Pixel FIFO
 ///
-/// https://gbdev.io/pandocs/#pixel-fifo
+/// https://gbdev.io/pandocs/pixel_fifo.html
+#[derive(Serialize, Deserialize)]
 struct PixelFifo {
+    /// Color indices of background or window pixels
     background: VecDeque<u8>,
-    sprite: VecDeque<u8>,
+    /// Object pixels lined up with the first pixels of `background`
+    sprite: VecDeque<ObjPixel>,
+}
+
+/// A pixel of an object in the pixel FIFO
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+struct ObjPixel {
+    /// Color index, 0 is transparent
+    color: u8,
+    palette: u8,
+    bg_over_obj: bool,
 }
 
 impl PixelFifo {
     pub fn new() -> Self {
         Self{
-            background: VecDeque::new(),
-            sprite: VecDeque::new(),
+            background: VecDeque::with_capacity(8),
+            sprite: VecDeque::with_capacity(8),
+        }
+    }
+
+    /// Shift out the next background pixel and the object pixel on top
+    /// of it
+    fn pop(&mut self) -> Option<(u8, Option<ObjPixel>)> {
+        let background = self.background.pop_front()?;
+        Some((background, self.sprite.pop_front()))
+    }
+
+    /// Mix the line of an object into the object FIFO, starting at
+    /// output pixel `x`
+    ///
+    /// Pixels of objects fetched earlier take priority, unless they are
+    /// transparent.
+    fn merge_object(&mut self, memory: &MemoryBus, lcdc: LcdControl,
+                    object: &Sprite, x: usize) {
+        let attributes = object.attribute_flags();
+        let obj_height = lcdc.obj_height();
+        let y = if attributes.y_flip() {
+            obj_height - 1 - object.y()
+        } else {
+            object.y()
+        };
+        let tile = fetch_obj_tile_line(memory, object.tile_index(), y,
+                                       obj_height == 16);
+        if self.sprite.len() < 8 {
+            self.sprite.resize(8, ObjPixel::default());
+        }
+        let left = object.x() as isize - 8 - x as isize;
+        for column in 0..8 {
+            // Columns left of x have been cut off by the left border of
+            // the screen.
+            let position = left + column;
+            if position < 0 {
+                continue;
+            }
+            let index = if attributes.x_flip() {
+                column
+            } else {
+                7 - column
+            };
+            let pixel = &mut self.sprite[position as usize];
+            if pixel.color == 0 {
+                *pixel = ObjPixel{
+                    color: tile_pixel(tile, index as u8),
+                    palette: attributes.palette() as u8,
+                    bg_over_obj: attributes.bg_and_window_over_obj(),
+                };
+            }
         }
     }
 }
@@ -249,6 +745,7 @@ impl From<u8> for LcdMode {
     }
 }
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Sprite {
     y: u8,
     x: u8,
@@ -279,23 +776,297 @@ impl Sprite {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct ObjAttributeFlags(u8);
 
 impl ObjAttributeFlags {
-    fn palette(self) -> usize {
+    pub fn palette(self) -> usize {
         ((self.0 >> 4) & 1) as usize
     }
 
-    fn x_flip(self) -> bool {
+    pub fn x_flip(self) -> bool {
         (self.0 & 0x20) != 0
     }
 
-    fn y_flip(self) -> bool {
+    pub fn y_flip(self) -> bool {
         (self.0 & 0x40) != 0
     }
 
-    fn bg_and_window_over_obj(self) -> bool {
+    pub fn bg_and_window_over_obj(self) -> bool {
         (self.0 & 0x80) != 0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::cartridge::Cartridge;
+
+    /// Length of mode 3 of line 0 with LCD, BG and objects enabled
+    fn mode3_length(setup: impl FnOnce(&mut MemoryBus)) -> usize {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        setup(&mut memory);
+        let mut ppu = PPU::new();
+        ppu.start_line(&mut memory);
+        ppu.mode3_length().unwrap()
+    }
+
+    #[test]
+    fn mode3_length_depends_on_fine_scroll() {
+        assert_eq!(mode3_length(|_| {}), 172);
+        assert_eq!(mode3_length(|memory| memory.write8(0xFF43, 0x13)), 175);
+        assert_eq!(mode3_length(|memory| memory.write8(0xFF43, 0x17)), 179);
+    }
+
+    #[test]
+    fn mode3_length_depends_on_window() {
+        let length = mode3_length(|memory| {
+            memory.write8(0xFF40, 0xB3);
+            memory.write8(0xFF4A, 0);
+            memory.write8(0xFF4B, 87);
+        });
+        assert_eq!(length, 178);
+    }
+
+    #[test]
+    fn mode3_length_depends_on_objects() {
+        let place_objects = |count: u16, x: u8| move |memory: &mut MemoryBus| {
+            for i in 0..count {
+                memory.write8(0xFE00 + 4 * i, 16);
+                memory.write8(0xFE01 + 4 * i, x);
+            }
+        };
+        let one_object = mode3_length(place_objects(1, 48));
+        assert_eq!(one_object, 183);
+        // The fetcher is almost done with the tile under this one.
+        assert_eq!(mode3_length(place_objects(1, 53)), 178);
+        // Only the first 10 objects on a line are drawn.
+        let ten_objects = mode3_length(place_objects(10, 48));
+        assert_eq!(mode3_length(place_objects(20, 48)), ten_objects);
+        assert_eq!(ten_objects, one_object + 9 * 6);
+        // Objects off the right of the screen don't stall the output.
+        assert_eq!(mode3_length(place_objects(10, 168)), 172);
+    }
+
+    #[test]
+    fn objects_are_drawn_over_background() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        memory.write8(0xFF47, 0xE4);
+        memory.write8(0xFF48, 0xE4);
+        // Tile 1 has a line of color 1 at the top.
+        memory.write8(0x8010, 0xFF);
+        memory.write8(0xFE00, 16);
+        memory.write8(0xFE01, 8 + 10);
+        memory.write8(0xFE02, 1);
+        let mut ppu = PPU::new();
+        ppu.start_line(&mut memory);
+        let line = &ppu.screen()[..WIDTH];
+        // Object pixels are marked with the layer of OBP0.
+        assert!(line[10..18].iter().all(|&pixel| pixel == 1 | 4));
+        assert!(line[..10].iter().chain(&line[18..]).all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn window_skips_lines_on_which_it_is_hidden() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF47, 0xE4);
+        memory.write8(0xFF4A, 0);
+        memory.write8(0xFF4B, 7);
+        // Only line 1 of tile 0 has color 1.
+        memory.write8(0x8002, 0xFF);
+        let mut ppu = PPU::new();
+        for (ly, lcdc) in [(0, 0xB3), (1, 0x93), (2, 0xB3)] {
+            memory.set_ly(ly);
+            memory.write8(0xFF40, lcdc);
+            ppu.start_line(&mut memory);
+        }
+        // LY 2 shows line 1 of the window, not line 2.
+        let line = &ppu.screen()[2 * WIDTH..3 * WIDTH];
+        assert!(line.iter().all(|&pixel| pixel == 1));
+    }
+
+    #[test]
+    fn oam_scan_selects_first_ten_objects_in_oam_order() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        memory.write8(0xFF48, 0xE4);
+        memory.write8(0xFF49, 0xE4);
+        memory.write8(0x8010, 0xFF);
+        memory.write8(0x8021, 0xFF);
+        // Ten objects off the screen, followed by a visible one
+        for entry in 0..11 {
+            memory.write8(0xFE00 + 4 * entry, 16);
+            memory.write8(0xFE01 + 4 * entry, 0);
+            memory.write8(0xFE02 + 4 * entry, 1);
+        }
+        memory.write8(0xFE01 + 4 * 10, 8 + 10);
+        // Entry 11 is on the line but comes too late.
+        memory.write8(0xFE00 + 4 * 11, 16);
+        memory.write8(0xFE01 + 4 * 11, 8 + 20);
+        let mut ppu = PPU::new();
+        ppu.start_oam_scan();
+        ppu.scan_oam_until(&memory, 4);
+        // Moving entry 0 off the line is too late for the scan.
+        memory.write8(0xFE00, 0);
+        ppu.scan_oam_until(&memory, MODE2_DOTS);
+        ppu.start_line(&mut memory);
+        assert!(ppu.screen()[..WIDTH].iter().all(|&pixel| pixel == 0));
+
+        // Once entry 0 is out of the way, entry 10 gets selected.
+        ppu.start_oam_scan();
+        ppu.start_line(&mut memory);
+        let line = &ppu.screen()[..WIDTH];
+        assert!(line[10..18].iter().all(|&pixel| pixel == 1 | 4));
+        assert!(line[20..28].iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn objects_with_lower_x_take_priority() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        memory.write8(0xFF48, 0xE4);
+        memory.write8(0xFF49, 0xE4);
+        // Tile 1 has color 1, tile 2 color 2 on its top line.
+        memory.write8(0x8010, 0xFF);
+        memory.write8(0x8021, 0xFF);
+        for (entry, x, tile, flags) in [(0, 3, 1, 0x00), (1, 1, 2, 0x10),
+                                        (2, 12, 1, 0x00), (3, 10, 2, 0x10)] {
+            memory.write8(0xFE00 + 4 * entry, 16);
+            memory.write8(0xFE01 + 4 * entry, x);
+            memory.write8(0xFE02 + 4 * entry, tile);
+            memory.write8(0xFE03 + 4 * entry, flags);
+        }
+        let mut ppu = PPU::new();
+        ppu.start_line(&mut memory);
+        let line = &ppu.screen()[..WIDTH];
+        // Where objects overlap, the one with the lower X is on top.
+        assert_eq!(line[..3], [2 | 8, 1 | 4, 1 | 4]);
+        assert_eq!(line[3..10], [2 | 8; 7]);
+        assert_eq!(line[10..13], [1 | 4, 1 | 4, 0]);
+    }
+
+    /// Scrolled background with window and overlapping objects
+    fn draw_scene(memory: &mut MemoryBus) {
+        memory.write8(0xFF40, 0xB3);
+        memory.write8(0xFF47, 0xE4);
+        memory.write8(0xFF48, 0xE4);
+        memory.write8(0xFF49, 0x1B);
+        memory.write8(0xFF42, 5);
+        memory.write8(0xFF43, 13);
+        memory.write8(0xFF4A, 2);
+        memory.write8(0xFF4B, 87);
+        for address in 0x8000..0x8040 {
+            memory.write8(address, (address as usize * 37 % 251) as u8);
+        }
+        for entry in 0..0x800 {
+            memory.write8(0x9800 + entry, (entry % 7 % 4) as u8);
+        }
+        for (entry, x, tile, flags) in [(0, 20, 1, 0x00), (1, 24, 2, 0x30),
+                                        (2, 100, 3, 0x40), (3, 4, 1, 0x80),
+                                        (4, 167, 2, 0x00)] {
+            memory.write8(0xFE00 + 4 * entry, 16);
+            memory.write8(0xFE01 + 4 * entry, x);
+            memory.write8(0xFE02 + 4 * entry, tile);
+            memory.write8(0xFE03 + 4 * entry, flags);
+        }
+    }
+
+    #[test]
+    fn cached_lines_match_pixel_fifo() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        draw_scene(&mut memory);
+        let mut accurate = PPU::new();
+        let mut fast = PPU::new();
+        fast.set_scroll_latch(ScrollLatch::PerFrame);
+        for ly in 0..8 {
+            memory.set_ly(ly);
+            accurate.start_line(&mut memory);
+            fast.start_line(&mut memory);
+            assert_eq!(fast.mode3_length(), accurate.mode3_length(),
+                       "mode 3 of line {}", ly);
+        }
+        assert_eq!(fast.screen()[..8 * WIDTH], accurate.screen()[..8 * WIDTH]);
+    }
+
+    /// Draw line 0 with SCX changed to the given value at dot 60,
+    /// when the first few tiles have been fetched
+    ///
+    /// Only tile column 12 of the tile map has color 1.
+    fn draw_with_mid_line_scx(scroll_latch: ScrollLatch, scx: u8) -> Vec<u8> {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        memory.write8(0xFF47, 0xE4);
+        for address in (0x8010..0x8020).step_by(2) {
+            memory.write8(address, 0xFF);
+        }
+        memory.write8(0x9800 + 12, 1);
+        let mut ppu = PPU::new();
+        ppu.set_scroll_latch(scroll_latch);
+        ppu.start_line(&mut memory);
+        ppu.paint_line_until(&memory, 60);
+        memory.write8(0xFF43, scx);
+        assert!(ppu.paint_line_until(&memory, usize::MAX));
+        ppu.screen()[..WIDTH].to_vec()
+    }
+
+    /// Screen columns with color 1
+    fn colored_pixels(line: &[u8]) -> std::ops::Range<usize> {
+        let start = line.iter().position(|&pixel| pixel == 1).unwrap();
+        let length = line[start..].iter()
+                                  .take_while(|&&pixel| pixel == 1)
+                                  .count();
+        start..start + length
+    }
+
+    #[test]
+    fn per_line_latch_ignores_mid_line_scroll() {
+        let line = draw_with_mid_line_scx(ScrollLatch::PerLine, 16);
+        assert_eq!(colored_pixels(&line), 96..104);
+    }
+
+    #[test]
+    fn per_fetch_latch_applies_mid_line_coarse_scroll() {
+        // The tiles fetched after the write come from two columns
+        // further right.
+        let line = draw_with_mid_line_scx(ScrollLatch::PerFetch, 16);
+        assert_eq!(colored_pixels(&line), 80..88);
+        // The fine scroll is only applied at the start of the line.
+        let line = draw_with_mid_line_scx(ScrollLatch::PerFetch, 16 + 3);
+        assert_eq!(colored_pixels(&line), 80..88);
+        // Scrolling left wraps around the tile map.
+        let line = draw_with_mid_line_scx(ScrollLatch::PerFetch, 0xF0);
+        assert_eq!(colored_pixels(&line), 112..120);
+    }
+
+    #[test]
+    fn raster_writes_fall_back_to_pixel_fifo() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x93);
+        memory.write8(0xFF47, 0xE4);
+        // Tile 1 is filled with color 1.
+        for address in (0x8010..0x8020).step_by(2) {
+            memory.write8(address, 0xFF);
+        }
+        let mut ppu = PPU::new();
+        ppu.set_scroll_latch(ScrollLatch::PerFrame);
+        ppu.start_line(&mut memory);
+        // Changing the tile map while the frame is drawn isn't in the
+        // cache, which is only updated at the start of the frame.
+        memory.set_ly(1);
+        memory.write8(0x9800, 1);
+        ppu.start_line(&mut memory);
+        let line = &ppu.screen()[WIDTH..2 * WIDTH];
+        assert!(line[..8].iter().all(|&pixel| pixel == 1));
+        assert!(line[8..].iter().all(|&pixel| pixel == 0));
+    }
+}
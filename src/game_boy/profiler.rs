@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::symbols::SymbolTable;
+
+/// Address of an instruction together with the bank mapped there
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    /// Bank as in `.sym` files, or None for the boot ROM
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:0>2X}:{:0>4X}", bank, self.address),
+            None => write!(f, "boot:{:0>4X}", self.address),
+        }
+    }
+}
+
+/// Counts the CPU cycles spent in the instructions at each location
+///
+/// Cycles in which the CPU halts count for the HALT instruction.
+#[derive(Default)]
+pub struct Profiler {
+    cycles: HashMap<Location, u64>,
+    total_cycles: u64,
+}
+
+impl Profiler {
+    pub fn add(&mut self, location: Location, cycles: usize) {
+        *self.cycles.entry(location).or_default() += cycles as u64;
+        self.total_cycles += cycles as u64;
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Locations with the cycles spent there, the most first
+    pub fn hotspots(&self) -> Vec<(Location, u64)> {
+        let mut hotspots: Vec<_> = self.cycles
+                                       .iter()
+                                       .map(|(&location, &cycles)| {
+                                           (location, cycles)
+                                       })
+                                       .collect();
+        hotspots.sort_by_key(|&(location, cycles)| (Reverse(cycles),
+                                                    location));
+        hotspots
+    }
+
+    /// Cycles spent in each routine, the most first
+    ///
+    /// A routine spans from a label to the next one.  Locations before
+    /// the first label of their memory region stay on their own.
+    pub fn routines<'a>(&self, symbols: &'a SymbolTable)
+            -> Vec<(Location, Option<&'a str>, u64)> {
+        let mut routines = HashMap::new();
+        for (&location, &cycles) in &self.cycles {
+            let routine = location.bank.and_then(|bank| {
+                let (address, name) = symbols.label_before(bank,
+                                                           location.address)?;
+                Some((Location{bank: Some(bank), address}, Some(name)))
+            }).unwrap_or((location, None));
+            *routines.entry(routine).or_default() += cycles;
+        }
+        let mut routines: Vec<_> = routines.into_iter()
+                                           .map(|((location, name), cycles)| {
+                                               (location, name, cycles)
+                                           })
+                                           .collect();
+        routines.sort_by_key(|&(location, _, cycles)| (Reverse(cycles),
+                                                       location));
+        routines
+    }
+
+    /// Table of the given number of hottest routines if symbols are
+    /// given, otherwise of the hottest locations
+    pub fn report(&self, symbols: Option<&SymbolTable>, count: usize)
+            -> String {
+        let rows: Vec<(Location, Option<&str>, u64)> = match symbols {
+            Some(symbols) => self.routines(symbols),
+            None => self.hotspots()
+                        .into_iter()
+                        .map(|(location, cycles)| (location, None, cycles))
+                        .collect(),
+        };
+        let mut report = format!("{:>12}  {:>6}  location", "cycles", "share");
+        for (location, name, cycles) in rows.into_iter().take(count) {
+            let share = 100.0 * cycles as f64 / self.total_cycles as f64;
+            report.push_str(&format!("\n{:>12}  {:>5.1}%  {}", cycles, share,
+                                     location));
+            if let Some(name) = name {
+                report.push_str(&format!(" {}", name));
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_are_summed_per_location_and_routine() {
+        let at = |bank, address| Location{bank, address};
+        let mut profiler = Profiler::default();
+        for (location, cycles) in [(at(Some(0), 0x0150), 8),
+                                   (at(Some(1), 0x4002), 12),
+                                   (at(Some(1), 0x4005), 4),
+                                   (at(Some(0), 0x0152), 4),
+                                   (at(Some(1), 0x4002), 12),
+                                   (at(None, 0x0010), 4)] {
+            profiler.add(location, cycles);
+        }
+        assert_eq!(profiler.total_cycles(), 44);
+        assert_eq!(profiler.hotspots()[..2],
+                   [(at(Some(1), 0x4002), 24), (at(Some(0), 0x0150), 8)]);
+        assert_eq!(profiler.report(None, 2).lines().collect::<Vec<_>>(), [
+            "      cycles   share  location",
+            "          24   54.5%  01:4002",
+            "           8   18.2%  00:0150",
+        ]);
+
+        let symbols = SymbolTable::load("01:4000 Loop\n00:0152 Main\n"
+                                        .as_bytes()).unwrap();
+        let report = profiler.report(Some(&symbols), 10);
+        assert_eq!(report.lines().collect::<Vec<_>>(), [
+            "      cycles   share  location",
+            "          28   63.6%  01:4000 Loop",
+            "           8   18.2%  00:0150",
+            "           4    9.1%  boot:0010",
+            "           4    9.1%  00:0152 Main",
+        ]);
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::io::{HEIGHT, WIDTH};
+
+/// Frame rate of a real Game Boy, at which clips are played back
+const FRAMES_PER_SECOND: f64 = 4_194_304. / 70_224.;
+
+/// Most frames that a single frame of a clip can last
+///
+/// Longer still images are split into several frames, so that their
+/// delay fits into the 16 bits of GIF and APNG at the units used here.
+const MAX_REPEATS: u32 = 3600;
+
+/// Number of pixel values in frames passed to `IO::refresh`
+const COLORS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipFormat {
+    Gif,
+    Apng,
+}
+
+pub const AVAILABLE_CLIP_FORMATS: [&str; 2] = ["gif", "apng"];
+
+impl ClipFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Apng => "png",
+        }
+    }
+}
+
+impl FromStr for ClipFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "gif" => Ok(Self::Gif),
+            "apng" => Ok(Self::Apng),
+            _ => Err(format!("Unknown clip format {}.", name)),
+        }
+    }
+}
+
+/// File name of a clip that started in the given frame, like
+/// `game.1234.gif` next to `game.gb`
+pub fn clip_path(rom_file: &Path, frame: u64, format: ClipFormat) -> PathBuf {
+    rom_file.with_extension(format!("{}.{}", frame, format.extension()))
+}
+
+/// Collects the frames of a gameplay clip until it is written as an
+/// animated GIF or PNG
+///
+/// Identical consecutive frames are kept once and shown for longer,
+/// which keeps still scenes from taking up memory.
+pub struct ClipRecorder {
+    format: ClipFormat,
+    /// Colors indexed by the pixel values
+    colors: [u32; COLORS],
+    /// Distinct frames with the number of frames they are shown for
+    frames: Vec<(Vec<u8>, u32)>,
+}
+
+impl ClipRecorder {
+    pub fn new(format: ClipFormat, colors: [u32; COLORS]) -> Self {
+        Self{
+            format,
+            colors,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Add a frame of `WIDTH` x `HEIGHT` pixels as passed to
+    /// `IO::refresh`
+    pub fn add_frame(&mut self, pixels: &[u8]) {
+        match self.frames.last_mut() {
+            Some((last, repeats)) if last == pixels
+                                     && *repeats < MAX_REPEATS => {
+                *repeats += 1;
+            }
+            _ => self.frames.push((pixels.to_vec(), 1)),
+        }
+    }
+
+    /// Number of frames that have been added
+    pub fn frame_count(&self) -> u64 {
+        self.frames.iter().map(|&(_, repeats)| repeats as u64).sum()
+    }
+
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        match self.format {
+            ClipFormat::Gif => self.write_gif(writer),
+            ClipFormat::Apng => self.write_apng(writer),
+        }
+    }
+
+    /// Delays of the distinct frames in the given units per second
+    ///
+    /// Each frame ends at the time of a real Game Boy rounded to the
+    /// units, so that rounding errors don't add up over the clip.
+    fn delays(&self, units_per_second: f64) -> Vec<u16> {
+        let time = |frame: u64| {
+            (frame as f64 * units_per_second / FRAMES_PER_SECOND).round()
+                as u64
+        };
+        let mut start = 0;
+        self.frames.iter().map(|&(_, repeats)| {
+            let end = start + repeats as u64;
+            let delay = time(end) - time(start);
+            start = end;
+            delay as u16
+        }).collect()
+    }
+
+    fn write_apng(&self, writer: impl Write) -> io::Result<()> {
+        let mut palette = Vec::with_capacity(3 * COLORS);
+        for color in self.colors {
+            palette.extend_from_slice(&color.to_be_bytes()[1..]);
+        }
+        let mut encoder = png::Encoder::new(writer, WIDTH as u32,
+                                            HEIGHT as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(palette);
+        encoder.set_animated(self.frames.len() as u32, 0)
+               .map_err(invalid_data)?;
+        let mut writer = encoder.write_header().map_err(invalid_data)?;
+        for ((pixels, _), delay) in self.frames.iter()
+                                         .zip(self.delays(1000.)) {
+            writer.set_frame_delay(delay, 1000).map_err(invalid_data)?;
+            writer.write_image_data(pixels).map_err(invalid_data)?;
+        }
+        writer.finish().map_err(invalid_data)
+    }
+
+    fn write_gif(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(b"GIF89a")?;
+        writer.write_all(&(WIDTH as u16).to_le_bytes())?;
+        writer.write_all(&(HEIGHT as u16).to_le_bytes())?;
+        // A global color table of 16 colors, 8 bits per channel
+        writer.write_all(&[0xF3, 0, 0])?;
+        let mut palette = [0; 3 * 16];
+        for (index, color) in self.colors.iter().enumerate() {
+            palette[3 * index..3 * index + 3]
+                .copy_from_slice(&color.to_be_bytes()[1..]);
+        }
+        writer.write_all(&palette)?;
+        // Loop forever
+        writer.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        for ((pixels, _), delay) in self.frames.iter()
+                                         .zip(self.delays(100.)) {
+            writer.write_all(&[0x21, 0xF9, 4, 0])?;
+            writer.write_all(&delay.to_le_bytes())?;
+            writer.write_all(&[0, 0])?;
+            writer.write_all(&[0x2C, 0, 0, 0, 0])?;
+            writer.write_all(&(WIDTH as u16).to_le_bytes())?;
+            writer.write_all(&(HEIGHT as u16).to_le_bytes())?;
+            writer.write_all(&[0, GIF_MIN_CODE_SIZE])?;
+            for block in lzw_compress(pixels).chunks(255) {
+                writer.write_all(&[block.len() as u8])?;
+                writer.write_all(block)?;
+            }
+            writer.write_all(&[0])?;
+        }
+        writer.write_all(&[0x3B])?;
+        writer.flush()
+    }
+}
+
+/// Bits of the pixel values in the LZW data of GIF frames
+const GIF_MIN_CODE_SIZE: u8 = 4;
+const LZW_MAX_CODES: u16 = 4096;
+
+/// Compress pixel values below 16 with the variable-length LZW of GIF
+fn lzw_compress(pixels: &[u8]) -> Vec<u8> {
+    let clear = 1 << GIF_MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut output = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut emit = |code: u16, size: u32| {
+        bits |= (code as u32) << bit_count;
+        bit_count += size;
+        while bit_count >= 8 {
+            output.push(bits as u8);
+            bits >>= 8;
+            bit_count -= 8;
+        }
+    };
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    let mut size = GIF_MIN_CODE_SIZE as u32 + 1;
+    emit(clear, size);
+    let mut prefix = match pixels.first() {
+        Some(&pixel) => pixel as u16,
+        None => {
+            emit(end, size);
+            return finish_bits(output, bits, bit_count);
+        }
+    };
+    for &pixel in &pixels[1..] {
+        if let Some(&code) = codes.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+        emit(prefix, size);
+        if next_code == LZW_MAX_CODES {
+            emit(clear, size);
+            codes.clear();
+            next_code = end + 1;
+            size = GIF_MIN_CODE_SIZE as u32 + 1;
+        } else {
+            codes.insert((prefix, pixel), next_code);
+            next_code += 1;
+            if next_code > 1 << size {
+                size += 1;
+            }
+        }
+        prefix = pixel as u16;
+    }
+    emit(prefix, size);
+    emit(end, size);
+    finish_bits(output, bits, bit_count)
+}
+
+fn finish_bits(mut output: Vec<u8>, bits: u32, bit_count: u32) -> Vec<u8> {
+    if bit_count > 0 {
+        output.push(bits as u8);
+    }
+    output
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static)
+        -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(format: ClipFormat) -> ClipRecorder {
+        let mut recorder = ClipRecorder::new(format, [0x123456; COLORS]);
+        let mut frame = vec![0; WIDTH * HEIGHT];
+        for shade in [0, 0, 0, 1, 2, 2] {
+            frame[..WIDTH].fill(shade);
+            recorder.add_frame(&frame);
+        }
+        recorder
+    }
+
+    #[test]
+    fn identical_frames_are_kept_once() {
+        let recorder = clip(ClipFormat::Gif);
+        assert_eq!(recorder.frame_count(), 6);
+        assert_eq!(recorder.frames.len(), 3);
+        // 3, 1 and 2 frames at 16.74 ms each
+        assert_eq!(recorder.delays(1000.), [50, 17, 33]);
+        assert_eq!(recorder.delays(100.), [5, 2, 3]);
+    }
+
+    #[test]
+    fn apng_has_a_frame_per_distinct_frame() {
+        let mut apng = Vec::new();
+        clip(ClipFormat::Apng).write(&mut apng).unwrap();
+        let decoder = png::Decoder::new(apng.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let animation = reader.info().animation_control().unwrap();
+        assert_eq!(animation.num_frames, 3);
+        assert_eq!(animation.num_plays, 0);
+    }
+
+    #[test]
+    fn gif_frames_are_lzw_compressed() {
+        let mut gif = Vec::new();
+        clip(ClipFormat::Gif).write(&mut gif).unwrap();
+        assert!(gif.starts_with(b"GIF89a\xA0\x00\x90\x00"));
+        assert_eq!(gif.last(), Some(&0x3B));
+        // Clear code, then 0, then a run of zeros in a new code
+        assert_eq!(lzw_compress(&[0, 0, 0]), [0x10, 0xC8, 0x08]);
+        assert_eq!(clip_path(Path::new("game.gb"), 12, ClipFormat::Apng),
+                   Path::new("game.12.png"));
+    }
+}
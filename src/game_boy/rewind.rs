@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+/// Ring buffer of recent save states to rewind the emulation
+///
+/// Only the newest state is kept as it is.  Every older state is kept
+/// as its difference to the next newer state, with runs of unchanged
+/// bytes compressed, so that a state costs little more than the bytes
+/// that changed since.  Once the buffer is full, the oldest state is
+/// dropped.
+pub struct RewindBuffer {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    /// Older states, oldest first
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    /// Create a buffer that keeps the given number of states
+    pub fn new(capacity: usize) -> Self {
+        Self{
+            capacity,
+            newest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(older) = self.newest.replace(state) {
+            self.deltas.push_back(Delta::new(&older,
+                                             self.newest.as_ref().unwrap()));
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+    }
+
+    /// Remove the newest state and return it
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.newest = self.deltas.pop_back()
+                                 .map(|delta| delta.apply(&newest));
+        Some(newest)
+    }
+
+    /// Number of states in the buffer
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Number of bytes taken up by the states
+    pub fn size(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len)
+            + self.deltas.iter().map(|delta| delta.runs.len()).sum::<usize>()
+    }
+}
+
+/// A state as the XOR with the next newer state
+///
+/// The XOR is stored as runs of zero bytes, each followed by some
+/// literal bytes, with both lengths as LEB128 numbers.
+struct Delta {
+    /// Length of the state
+    len: usize,
+    runs: Vec<u8>,
+}
+
+impl Delta {
+    fn new(older: &[u8], newer: &[u8]) -> Self {
+        let mut runs = Vec::new();
+        let mut zeros = 0;
+        let mut literals = Vec::new();
+        for (i, &byte) in older.iter().enumerate() {
+            let xor = byte ^ newer.get(i).copied().unwrap_or(0);
+            if xor == 0 {
+                if !literals.is_empty() {
+                    push_run(&mut runs, zeros, &literals);
+                    zeros = 0;
+                    literals.clear();
+                }
+                zeros += 1;
+            } else {
+                literals.push(xor);
+            }
+        }
+        if zeros != 0 || !literals.is_empty() {
+            push_run(&mut runs, zeros, &literals);
+        }
+        Self{
+            len: older.len(),
+            runs,
+        }
+    }
+
+    /// Restore the older state from the newer one
+    fn apply(&self, newer: &[u8]) -> Vec<u8> {
+        let mut older = Vec::with_capacity(self.len);
+        let mut runs = &self.runs[..];
+        while !runs.is_empty() {
+            let zeros = read_leb128(&mut runs);
+            let count = read_leb128(&mut runs);
+            older.resize(older.len() + zeros, 0);
+            older.extend_from_slice(&runs[..count]);
+            runs = &runs[count..];
+        }
+        for (i, byte) in older.iter_mut().enumerate() {
+            *byte ^= newer.get(i).copied().unwrap_or(0);
+        }
+        older
+    }
+}
+
+fn push_run(runs: &mut Vec<u8>, zeros: usize, literals: &[u8]) {
+    write_leb128(runs, zeros);
+    write_leb128(runs, literals.len());
+    runs.extend_from_slice(literals);
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_leb128(bytes: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[0];
+        *bytes = &bytes[1..];
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_are_popped_newest_first_and_oldest_dropped() {
+        let states: Vec<Vec<u8>> = (0..5u8).map(|i| {
+            let mut state = vec![0x42; 1000];
+            state[i as usize * 100] = i;
+            state.resize(1000 + i as usize, i);
+            state
+        }).collect();
+        let mut buffer = RewindBuffer::new(4);
+        for state in &states {
+            buffer.push(state.clone());
+        }
+        assert_eq!(buffer.len(), 4);
+        assert!(buffer.size() < 1100 + 3 * 20);
+        for state in states[1..].iter().rev() {
+            assert_eq!(buffer.pop().as_ref(), Some(state));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop(), None);
+    }
+}
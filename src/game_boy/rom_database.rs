@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use super::cartridge::{CartridgeHeader, MemoryControllerModel};
+
+/// Database that is compiled into the emulator
+const BUNDLED_DATABASE: &str = include_str!("rom_database.toml");
+
+/// Known-good dump of a game
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct RomEntry {
+    /// CRC32 of the whole ROM, written as hexadecimal number
+    #[serde(deserialize_with = "deserialize_crc32")]
+    pub crc32: u32,
+    pub title: String,
+    pub mapper: MemoryControllerModel,
+    pub region: String,
+}
+
+impl RomEntry {
+    /// Whether the header declares the mapper that the game really uses
+    ///
+    /// Bad dumps and ROM hacks sometimes have a wrong cartridge type.
+    pub fn matches_header(&self, header: &CartridgeHeader) -> bool {
+        header.cartridge_type().memory_controller().ok() == Some(self.mapper)
+    }
+}
+
+fn deserialize_crc32<'de, D>(deserializer: D) -> Result<u32, D::Error>
+        where D: Deserializer<'de> {
+    let hex = String::deserialize(deserializer)?;
+    u32::from_str_radix(&hex, 16).map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize)]
+struct DatabaseFile {
+    #[serde(default)]
+    game: Vec<RomEntry>,
+}
+
+/// Titles, mappers and regions of games by the CRC32 of their ROM
+///
+/// The database is kept in TOML files with a `[[game]]` table per game:
+///
+/// ```toml
+/// [[game]]
+/// crc32 = "46DF91AD"
+/// title = "Tetris (World) (Rev 1)"
+/// mapper = "NoController"
+/// region = "World"
+/// ```
+///
+/// The mapper is named like in `MemoryControllerModel`.
+pub struct RomDatabase {
+    games: HashMap<u32, RomEntry>,
+}
+
+impl Default for RomDatabase {
+    /// The database compiled into the emulator
+    fn default() -> Self {
+        let mut database = Self{games: HashMap::new()};
+        database.extend_from_toml(BUNDLED_DATABASE)
+                .expect("bundled ROM database is valid");
+        database
+    }
+}
+
+impl RomDatabase {
+    /// Add the games of a TOML file, replacing known entries with the
+    /// same CRC32
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        self.extend_from_toml(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn extend_from_toml(&mut self, contents: &str)
+            -> Result<(), toml::de::Error> {
+        let file: DatabaseFile = toml::from_str(contents)?;
+        self.games.extend(file.game.into_iter()
+                                   .map(|game| (game.crc32, game)));
+        Ok(())
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomEntry> {
+        self.games.get(&crc32(rom))
+    }
+}
+
+/// CRC-32 as used by ZIP files and No-Intro DAT files
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn games_are_found_by_crc32_of_rom() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x01; // MBC1
+        let mut database = RomDatabase::default();
+        database.extend_from_toml(&format!(
+                "[[game]]\ncrc32 = \"{:0>8X}\"\ntitle = \"Test\"\n\
+                 mapper = \"MBC5\"\nregion = \"World\"\n",
+                crc32(&rom))).unwrap();
+        let game = database.lookup(&rom).unwrap();
+        assert_eq!(game.title, "Test");
+        assert!(!game.matches_header(&CartridgeHeader::of_rom(&rom)));
+        rom[0x147] = 0x19; // MBC5
+        assert!(database.lookup(&rom).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::CPU_CYCLES_PER_SECOND;
+
+/// Time that the real time clock of a cartridge shows at power on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RtcStart {
+    /// The current time of the host
+    #[default]
+    HostClock,
+    /// The given UNIX timestamp, which makes runs reproducible
+    Timestamp(u64),
+}
+
+impl RtcStart {
+    /// Parse "host", "fixed" or a UNIX timestamp
+    ///
+    /// "fixed" starts at the UNIX epoch.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "host" => Some(Self::HostClock),
+            "fixed" => Some(Self::Timestamp(0)),
+            _ => name.parse().ok().map(Self::Timestamp),
+        }
+    }
+
+    fn timestamp(self) -> u64 {
+        match self {
+            Self::HostClock => SystemTime::now().duration_since(UNIX_EPOCH)
+                                                .map(|d| d.as_secs())
+                                                .unwrap_or(0),
+            Self::Timestamp(timestamp) => timestamp,
+        }
+    }
+}
+
+/// Real time clock of MBC3 cartridges
+///
+/// It counts seconds, minutes, hours and 512 days, after which the day
+/// counter sets its carry bit.  The clock advances with the emulated
+/// time, so it doesn't run while the emulator is paused.  Its registers
+/// are read from a copy that is latched by writing 0 and then 1 to
+/// 6000–7FFF.
+///
+/// Register  Contents
+/// 0x08      Seconds (0–59)
+/// 0x09      Minutes (0–59)
+/// 0x0A      Hours (0–23)
+/// 0x0B      Lower 8 bits of the day counter
+/// 0x0C      Bit 0: Bit 8 of the day counter, bit 6: Halt,
+///           bit 7: Day counter carry
+///
+/// https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+#[derive(Serialize, Deserialize)]
+pub struct RealTimeClock {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    /// CPU cycles since the last increment of the seconds
+    cycles: usize,
+    /// Registers 0x08–0x0C as last latched
+    latched: [u8; 5],
+}
+
+impl RealTimeClock {
+    pub fn new(start: RtcStart) -> Self {
+        let timestamp = start.timestamp();
+        let mut rtc = Self{
+            seconds: (timestamp % 60) as u8,
+            minutes: (timestamp / 60 % 60) as u8,
+            hours: (timestamp / 3600 % 24) as u8,
+            days: (timestamp / 86400 % 512) as u16,
+            halted: false,
+            day_carry: false,
+            cycles: 0,
+            latched: [0; 5],
+        };
+        rtc.latch();
+        rtc
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        if self.halted {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CPU_CYCLES_PER_SECOND {
+            self.cycles -= CPU_CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    /// Advance by one second
+    ///
+    /// Counters that have been set to invalid values count up to the
+    /// maximum of their bits and wrap to 0 without carrying over.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    /// Copy the counters into the readable registers
+    pub fn latch(&mut self) {
+        self.latched = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            self.day_high(),
+        ];
+    }
+
+    fn day_high(&self) -> u8 {
+        (self.day_carry as u8) << 7
+            | (self.halted as u8) << 6
+            | (self.days >> 8) as u8
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    /// Set a counter, which restarts the current second when writing
+    /// the seconds
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 1) << 8);
+                self.halted = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+            _ => unreachable!("{:0>2X} is not an RTC register.", register),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_starts_at_given_timestamp() {
+        // 2000-03-01 12:34:56 UTC
+        let rtc = RealTimeClock::new(RtcStart::Timestamp(951914096));
+        let days = 951914096 / 86400 % 512;
+        assert_eq!([rtc.read(0x08), rtc.read(0x09), rtc.read(0x0A),
+                    rtc.read(0x0B), rtc.read(0x0C)],
+                   [56, 34, 12, days as u8, (days >> 8) as u8]);
+        assert_eq!(RtcStart::from_name("fixed"),
+                   Some(RtcStart::Timestamp(0)));
+        assert_eq!(RtcStart::from_name("tomorrow"), None);
+    }
+
+    #[test]
+    fn latched_registers_keep_their_value_until_next_latch() {
+        let mut rtc = RealTimeClock::new(RtcStart::Timestamp(0));
+        rtc.write(0x0A, 23);
+        rtc.write(0x09, 59);
+        rtc.write(0x08, 59);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.step(CPU_CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.latch();
+        assert_eq!([rtc.read(0x08), rtc.read(0x09), rtc.read(0x0A),
+                    rtc.read(0x0B), rtc.read(0x0C)],
+                   [0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn halted_clock_doesnt_advance() {
+        let mut rtc = RealTimeClock::new(RtcStart::Timestamp(0));
+        rtc.write(0x0C, 0x40);
+        rtc.step(10 * CPU_CYCLES_PER_SECOND);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x0C), 0x40);
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::io::{HEIGHT, WIDTH};
+
+/// Start of save state files, ending in the version of their format
+const MAGIC: &[u8; 8] = b"GBSTATE\x03";
+
+/// Number of save state slots, which are selected by the number keys
+pub const NUM_SLOTS: u8 = 10;
+
+pub const THUMBNAIL_WIDTH: usize = WIDTH / 2;
+pub const THUMBNAIL_HEIGHT: usize = HEIGHT / 2;
+
+/// Description of a save state that precedes the state of the machine
+///
+/// It can be read without decoding the rest of the state.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateInfo {
+    /// UNIX timestamp of when the state was saved
+    pub timestamp: u64,
+    /// Number of frames emulated up to the state
+    pub frame: u64,
+    /// PNG image of the screen at half its size in grayscale
+    pub thumbnail: Vec<u8>,
+}
+
+impl StateInfo {
+    /// Describe a state saved now with the given screen, as returned by
+    /// `GameBoy::screen`
+    pub fn new(screen: &[u8], frame: u64) -> io::Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+                                         .map(|d| d.as_secs())
+                                         .unwrap_or(0);
+        Ok(Self{
+            timestamp,
+            frame,
+            thumbnail: encode_thumbnail(screen)?,
+        })
+    }
+
+    /// Time of saving as "YYYY-MM-DD hh:mm:ss UTC"
+    pub fn saved_at(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+
+    /// Write the start of a save state file
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        bincode::serialize_into(writer, self).map_err(|e| bincode_error(*e))
+    }
+
+    /// Read the start of a save state file
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a save state of this version of the emulator"));
+        }
+        bincode::deserialize_from(reader).map_err(|e| bincode_error(*e))
+    }
+}
+
+/// File of a save state slot of the given ROM file
+pub fn slot_path(rom_file: &Path, slot: u8) -> PathBuf {
+    rom_file.with_extension(format!("{}.state", slot))
+}
+
+/// Shrink the screen to a thumbnail, averaging each 2x2 pixels
+fn encode_thumbnail(screen: &[u8]) -> io::Result<Vec<u8>> {
+    let shade = |x: usize, y: usize| (screen[y * WIDTH + x] & 0x03) as u32;
+    let mut pixels = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT);
+    for y in (0..HEIGHT).step_by(2) {
+        for x in (0..WIDTH).step_by(2) {
+            let shades = shade(x, y) + shade(x + 1, y)
+                         + shade(x, y + 1) + shade(x + 1, y + 1);
+            pixels.push((0xFF - shades * 0x55 / 4) as u8);
+        }
+    }
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, THUMBNAIL_WIDTH as u32,
+                                        THUMBNAIL_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(invalid_data)?;
+    writer.write_image_data(&pixels).map_err(invalid_data)?;
+    writer.finish().map_err(invalid_data)?;
+    Ok(png)
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static)
+        -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+pub fn bincode_error(error: bincode::ErrorKind) -> io::Error {
+    match error {
+        bincode::ErrorKind::Io(error) => error,
+        error => invalid_data(error),
+    }
+}
+
+/// Format a UNIX timestamp as date and time in UTC
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_timestamp(timestamp: u64) -> String {
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+    // Days since 0000-03-01, so that leap days end the year
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era
+                      - (365 * year_of_era + year_of_era / 4
+                         - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + 400 * era + (month <= 2) as u64;
+    format!("{:0>4}-{:0>2}-{:0>2} {:0>2}:{:0>2}:{:0>2} UTC",
+            year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_info_is_read_back_with_thumbnail() {
+        let screen: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| (i % 4) as u8)
+                                                 .collect();
+        let info = StateInfo{
+            timestamp: 951914096,
+            frame: 1234,
+            thumbnail: encode_thumbnail(&screen).unwrap(),
+        };
+        let mut file = Vec::new();
+        info.write(&mut file).unwrap();
+        let read = StateInfo::read(&mut &file[..]).unwrap();
+        assert_eq!(read, info);
+        assert_eq!(read.saved_at(), "2000-03-01 12:34:56 UTC");
+        let decoder = png::Decoder::new(&read.thumbnail[..]);
+        let reader = decoder.read_info().unwrap();
+        assert_eq!((reader.info().width, reader.info().height),
+                   (THUMBNAIL_WIDTH as u32, THUMBNAIL_HEIGHT as u32));
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    }
+}
@@ -0,0 +1,473 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, Map, Scope, AST,
+           INT};
+
+use super::cpu::CpuState;
+use super::debugger::{Breakpoint, WatchHit, Watchpoint};
+
+/// Names of the buttons in the bit order of `IO::get_key_presses`
+const BUTTON_NAMES: [&str; 8] = [
+    "right", "left", "up", "down", "a", "b", "select", "start",
+];
+
+/// Something that happened in the emulation, to be passed to the
+/// functions that a script has hooked to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// The end of every frame
+    Frame,
+    /// The CPU is about to execute the instruction at an address
+    Pc(u16),
+    /// The CPU has accessed memory in a watched range
+    Access(WatchHit),
+}
+
+/// Actions that the Game Boy carries out for a script
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Poke{address: u16, value: u8},
+    AddBreakpoint(Breakpoint),
+    Break,
+    Quit,
+}
+
+/// Functions of the script hooked to triggers
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    pc: Vec<(u16, FnPtr)>,
+    access: Vec<(Watchpoint, FnPtr)>,
+}
+
+/// The state of the Game Boy that the functions of a script see, and
+/// what they do to it
+struct Machine {
+    /// Copy of the address space, taken before the hooked functions run
+    memory: Vec<u8>,
+    cpu: CpuState,
+    frame: u64,
+    /// Buttons passed to the game in the current frame
+    buttons: u8,
+    effects: Vec<Effect>,
+    /// Buttons held by `press` with the frames left to hold them
+    presses: Vec<(u8, u32)>,
+    output: Box<dyn Write + Send>,
+    /// First error of writing to `output`, which stops the script
+    output_error: Option<io::Error>,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn to_address(address: INT) -> ScriptResult<u16> {
+    u16::try_from(address).map_err(|_| {
+        format!("{} is not an address", address).into()
+    })
+}
+
+fn to_address_range(first: INT, last: INT)
+        -> ScriptResult<RangeInclusive<u16>> {
+    let (first, last) = (to_address(first)?, to_address(last)?);
+    if first > last {
+        return Err(format!("{:0>4X}-{:0>4X} is an empty range", first, last)
+                   .into());
+    }
+    Ok(first..=last)
+}
+
+/// Parse button names joined by `+`, like `A+Start`
+fn parse_buttons(text: &str) -> ScriptResult<u8> {
+    text.split('+').try_fold(0, |keys, name| {
+        BUTTON_NAMES.iter()
+                    .position(|button| button.eq_ignore_ascii_case(name))
+                    .map(|bit| keys | 1 << bit)
+                    .ok_or_else(|| format!("unknown button {:?}", name).into())
+    })
+}
+
+/// A Rhai script that reacts to the emulation, for bots,
+/// auto-splitters or custom displays of the game's state
+///
+/// The script's top level runs when it is loaded and hooks functions
+/// to what happens in the game.  The functions are closures, which
+/// keep the variables they use from one call to the next:
+///
+/// ```text
+/// // Print the frame in which the level counter at D0A2 changes.
+/// on_write(0xD0A2, |address, level| print(`level ${level} in ${frame()}`));
+/// // Skip the title screen and keep at least 3 lives.
+/// on_pc(0x0150, || press("Start", 2));
+/// on_frame(|| if peek(0xD0A5) < 3 { poke(0xD0A5, 3) });
+/// // Count the frames in which A is held.
+/// let frames_with_a = 0;
+/// on_frame(|| if "a" in buttons() { frames_with_a += 1 });
+/// ```
+///
+/// Hooks are `on_frame(f)`, `on_pc(address, f)` and `on_read` and
+/// `on_write`, which take an address or a first and last address and
+/// call `f(address, value)`.  The functions can use
+///
+/// - `peek(address)` and `poke(address, value)` to read and write memory,
+/// - `cpu()`, a map of the registers `a`, `f`, …, `l`, `sp` and `pc`,
+/// - `frame()`, the number of the current frame,
+/// - `buttons()`, the names of the buttons held in the current frame,
+/// - `press(buttons)` and `press(buttons, frames)` to hold buttons like
+///   `"A+Start"` from the next frame on,
+/// - `add_breakpoint(address)` or `add_breakpoint("0150 if A==3")` and
+///   `debug_break()` to break into the debugger,
+/// - `quit()` and `print(text)`.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    hooks: Hooks,
+    machine: Arc<Mutex<Machine>>,
+}
+
+impl Script {
+    /// Compile a script that prints to stdout and run its top level
+    pub fn load(source: &str) -> Result<Self, String> {
+        let machine = Arc::new(Mutex::new(Machine{
+            memory: vec![0; 0x10000],
+            cpu: CpuState::default(),
+            frame: 0,
+            buttons: 0,
+            effects: Vec::new(),
+            presses: Vec::new(),
+            output: Box::new(io::stdout()),
+            output_error: None,
+        }));
+        // Hooks can only be added while the top level runs.
+        let hooks = Arc::new(Mutex::new(Some(Hooks::default())));
+        let mut engine = Engine::new();
+        register_hooks(&mut engine, &hooks);
+        register_machine(&mut engine, &machine);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        engine.run_ast_with_scope(&mut Scope::new(), &ast)
+              .map_err(|e| e.to_string())?;
+        let hooks = hooks.lock().unwrap().take().unwrap();
+        Ok(Self{engine, ast, hooks, machine})
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.machine.lock().unwrap().output = output;
+    }
+
+    /// Whether any function is hooked to a PC, which has to be checked
+    /// before every instruction
+    pub fn has_pc_hooks(&self) -> bool {
+        !self.hooks.pc.is_empty()
+    }
+
+    /// Memory ranges whose accesses have to be reported as triggers
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.hooks.access.iter()
+                         .map(|(watchpoint, _)| watchpoint.clone())
+                         .collect()
+    }
+
+    /// Call the functions hooked to a trigger, with `memory` being the
+    /// address space and `buttons` the buttons held in the current frame
+    ///
+    /// Return the effects that the Game Boy has to carry out.
+    pub fn run(&mut self, trigger: &Trigger, cpu: &CpuState, memory: &[u8],
+               frame: u64, buttons: u8) -> Result<Vec<Effect>, String> {
+        let (functions, arguments): (Vec<&FnPtr>, _) = match trigger {
+            Trigger::Frame => (self.hooks.frame.iter().collect(), None),
+            Trigger::Pc(pc) => {
+                let functions = self.hooks.pc.iter()
+                                    .filter(|(address, _)| address == pc)
+                                    .map(|(_, function)| function)
+                                    .collect();
+                (functions, None)
+            }
+            Trigger::Access(hit) => {
+                let functions = self.hooks.access.iter()
+                    .filter(|(watchpoint, _)| {
+                        watchpoint.matches(hit.address, hit.access)
+                    })
+                    .map(|(_, function)| function)
+                    .collect();
+                (functions, Some((hit.address as INT, hit.value as INT)))
+            }
+        };
+        if functions.is_empty() {
+            return Ok(Vec::new());
+        }
+        {
+            let mut machine = self.machine.lock().unwrap();
+            machine.memory.copy_from_slice(&memory[..0x10000]);
+            machine.cpu = *cpu;
+            machine.frame = frame;
+            machine.buttons = buttons;
+        }
+        for function in functions {
+            let result = match arguments {
+                Some(arguments) => {
+                    function.call::<Dynamic>(&self.engine, &self.ast,
+                                             arguments)
+                }
+                None => function.call::<Dynamic>(&self.engine, &self.ast, ()),
+            };
+            if let Err(e) = result {
+                return Err(e.to_string());
+            }
+        }
+        let mut machine = self.machine.lock().unwrap();
+        if let Some(e) = machine.output_error.take() {
+            return Err(e.to_string());
+        }
+        Ok(std::mem::take(&mut machine.effects))
+    }
+
+    /// Buttons held in the frame that is passed to the game next
+    pub fn take_held_keys(&mut self) -> u8 {
+        let mut machine = self.machine.lock().unwrap();
+        let keys = machine.presses.iter()
+                                  .fold(0, |keys, (held, _)| keys | held);
+        for (_, frames) in &mut machine.presses {
+            *frames -= 1;
+        }
+        machine.presses.retain(|&(_, frames)| frames > 0);
+        keys
+    }
+}
+
+/// Register the functions that hook functions of the script to triggers
+fn register_hooks(engine: &mut Engine, hooks: &Arc<Mutex<Option<Hooks>>>) {
+    fn add(hooks: &Mutex<Option<Hooks>>, add: impl FnOnce(&mut Hooks))
+            -> ScriptResult<()> {
+        let mut hooks = hooks.lock().unwrap();
+        let hooks = hooks.as_mut().ok_or(
+            "functions can only be hooked when the script is loaded")?;
+        add(hooks);
+        Ok(())
+    }
+
+    let shared = hooks.clone();
+    engine.register_fn("on_frame", move |function: FnPtr| {
+        add(&shared, |hooks| hooks.frame.push(function))
+    });
+    let shared = hooks.clone();
+    engine.register_fn("on_pc", move |address: INT, function: FnPtr| {
+        let address = to_address(address)?;
+        add(&shared, |hooks| hooks.pc.push((address, function)))
+    });
+    for (name, on_read, on_write) in [("on_read", true, false),
+                                      ("on_write", false, true)] {
+        let shared = hooks.clone();
+        let add_range = move |addresses, function| {
+            let watchpoint = Watchpoint{addresses, on_read, on_write};
+            add(&shared, |hooks| hooks.access.push((watchpoint, function)))
+        };
+        let add_address = add_range.clone();
+        engine.register_fn(name, move |address: INT, function: FnPtr| {
+            let address = to_address(address)?;
+            add_address(address..=address, function)
+        });
+        engine.register_fn(name, move |first: INT, last: INT,
+                                       function: FnPtr| {
+            add_range(to_address_range(first, last)?, function)
+        });
+    }
+}
+
+/// Register the functions that let the script see and change the
+/// Game Boy
+fn register_machine(engine: &mut Engine, machine: &Arc<Mutex<Machine>>) {
+    let shared = machine.clone();
+    engine.register_fn("peek", move |address: INT| -> ScriptResult<INT> {
+        let address = to_address(address)?;
+        Ok(shared.lock().unwrap().memory[address as usize] as INT)
+    });
+    let shared = machine.clone();
+    engine.register_fn("poke", move |address: INT, value: INT| {
+        let address = to_address(address)?;
+        let value = u8::try_from(value).map_err(|_| {
+            format!("{} is not a byte", value)
+        })?;
+        let mut machine = shared.lock().unwrap();
+        // Later peeks of the same function see the value, too.
+        machine.memory[address as usize] = value;
+        machine.effects.push(Effect::Poke{address, value});
+        ScriptResult::Ok(())
+    });
+    let shared = machine.clone();
+    engine.register_fn("cpu", move || {
+        let cpu = shared.lock().unwrap().cpu;
+        let registers = [("a", cpu.a as INT), ("f", cpu.f as INT),
+                         ("b", cpu.b as INT), ("c", cpu.c as INT),
+                         ("d", cpu.d as INT), ("e", cpu.e as INT),
+                         ("h", cpu.h as INT), ("l", cpu.l as INT),
+                         ("sp", cpu.sp as INT), ("pc", cpu.pc as INT)];
+        registers.into_iter()
+                 .map(|(name, value)| (name.into(), Dynamic::from(value)))
+                 .collect::<Map>()
+    });
+    let shared = machine.clone();
+    engine.register_fn("frame", move || {
+        shared.lock().unwrap().frame as INT
+    });
+    let shared = machine.clone();
+    engine.register_fn("buttons", move || {
+        let buttons = shared.lock().unwrap().buttons;
+        BUTTON_NAMES.iter()
+                    .enumerate()
+                    .filter(|(bit, _)| buttons & (1 << bit) != 0)
+                    .map(|(_, &name)| Dynamic::from(name))
+                    .collect::<Array>()
+    });
+    let shared = machine.clone();
+    let press = move |buttons: &str, frames: INT| {
+        let keys = parse_buttons(buttons)?;
+        let frames = u32::try_from(frames).ok()
+                                          .filter(|&frames| frames > 0)
+                                          .ok_or_else(|| {
+            format!("{} is not a positive number of frames", frames)
+        })?;
+        shared.lock().unwrap().presses.push((keys, frames));
+        ScriptResult::Ok(())
+    };
+    let press_once = press.clone();
+    engine.register_fn("press", move |buttons: &str| press_once(buttons, 1));
+    engine.register_fn("press", press);
+    let effect = |effect: Effect| {
+        let shared = machine.clone();
+        move || shared.lock().unwrap().effects.push(effect.clone())
+    };
+    engine.register_fn("debug_break", effect(Effect::Break));
+    engine.register_fn("quit", effect(Effect::Quit));
+    let shared = machine.clone();
+    engine.register_fn("add_breakpoint", move |address: INT| {
+        let breakpoint = Breakpoint{
+            address: to_address(address)?,
+            condition: None,
+        };
+        shared.lock().unwrap().effects.push(Effect::AddBreakpoint(breakpoint));
+        ScriptResult::Ok(())
+    });
+    let shared = machine.clone();
+    engine.register_fn("add_breakpoint", move |spec: &str| {
+        let breakpoint = Breakpoint::from_spec(spec)?;
+        shared.lock().unwrap().effects.push(Effect::AddBreakpoint(breakpoint));
+        ScriptResult::Ok(())
+    });
+    let shared = machine.clone();
+    engine.on_print(move |text| {
+        let mut machine = shared.lock().unwrap();
+        if let Err(e) = writeln!(machine.output, "{}", text) {
+            machine.output_error.get_or_insert(e);
+        }
+    });
+    engine.on_debug(|text, _source, position| {
+        log::debug!("Script at {}: {}", position, text);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::debugger::Access;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hooked_functions_run_on_matching_triggers() {
+        let mut script = Script::load(r#"
+            // Closures keep the variables they use between calls.
+            let writes = 0;
+            on_write(0xC000, 0xC0FF, |address, value| {
+                writes += 1;
+                if peek(0xC0A0) >= 2 {
+                    print(`A=${cpu().a} wrote ${value} in ${frame()}`);
+                    debug_break();
+                }
+            });
+            on_pc(0x0150, || {
+                press("A+start", 2);
+                poke(0xFF80, peek(0xC0A0));
+                poke(0xFF81, peek(0xFF80) + 1);
+            });
+            on_frame(|| {
+                if writes == 2 && "start" in buttons() {
+                    add_breakpoint("0150 if A==7");
+                    quit();
+                }
+            });
+        "#).unwrap();
+        let output = SharedOutput::default();
+        script.set_output(Box::new(output.clone()));
+        assert!(script.has_pc_hooks());
+        assert_eq!(script.watchpoints(),
+                   [Watchpoint::from_spec("w:C000-C0FF").unwrap()]);
+
+        let cpu = CpuState{a: 7, pc: 0x150, ..CpuState::default()};
+        let mut memory = vec![0; 0x10000];
+        let write = Trigger::Access(WatchHit{address: 0xC010, value: 1,
+                                             access: Access::Write});
+        assert_eq!(script.run(&write, &cpu, &memory, 11, 0).unwrap(), []);
+        memory[0xC0A0] = 2;
+        assert_eq!(script.run(&write, &cpu, &memory, 12, 0).unwrap(),
+                   [Effect::Break]);
+        let read = Trigger::Access(WatchHit{address: 0xC010, value: 1,
+                                            access: Access::Read});
+        assert_eq!(script.run(&read, &cpu, &memory, 12, 0).unwrap(), []);
+        assert_eq!(script.run(&Trigger::Pc(0x151), &cpu, &memory, 12, 0)
+                         .unwrap(),
+                   []);
+        assert_eq!(script.run(&Trigger::Pc(0x150), &cpu, &memory, 12, 0)
+                         .unwrap(),
+                   [Effect::Poke{address: 0xFF80, value: 2},
+                    Effect::Poke{address: 0xFF81, value: 3}]);
+        assert_eq!(*output.0.lock().unwrap(), b"A=7 wrote 1 in 12\n");
+        assert_eq!(script.take_held_keys(), 0x90);
+        assert_eq!(script.take_held_keys(), 0x90);
+        assert_eq!(script.take_held_keys(), 0);
+        assert_eq!(script.run(&Trigger::Frame, &cpu, &memory, 13, 0x00)
+                         .unwrap(),
+                   []);
+        assert_eq!(script.run(&Trigger::Frame, &cpu, &memory, 14, 0x80)
+                         .unwrap(),
+                   [Effect::AddBreakpoint(Breakpoint::from_spec("0150 if A==7")
+                                                     .unwrap()),
+                    Effect::Quit]);
+    }
+
+    #[test]
+    fn script_errors_are_reported() {
+        let error = Script::load("on_frame(|| press(\"X\"));").ok().unwrap()
+            .run(&Trigger::Frame, &CpuState::default(), &[0; 0x10000], 0, 0)
+            .err().unwrap();
+        assert!(error.contains("unknown button \"X\""), "{}", error);
+        let mut script = Script::load(
+            "on_pc(0x0150, || on_frame(|| quit()));").unwrap();
+        let error = script.run(&Trigger::Pc(0x0150), &CpuState::default(),
+                               &[0; 0x10000], 0, 0).err().unwrap();
+        assert!(error.contains("only be hooked when the script is loaded"),
+                "{}", error);
+        assert!(Script::load("on_frame(|| quit()").is_err());
+        assert!(Script::load("on_pc(0x10000, || quit());").is_err());
+        assert!(Script::load("on_write(0xC0FF, 0xC000, |a, v| quit());")
+                    .is_err());
+        let mut script = Script::load("on_frame(|| poke(0xC000, 256));")
+                                .unwrap();
+        assert!(script.run(&Trigger::Frame, &CpuState::default(),
+                           &[0; 0x10000], 0, 0).is_err());
+    }
+}
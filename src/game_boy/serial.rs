@@ -0,0 +1,653 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::{self, stdout, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A peripheral connected to the Game Boy's link port
+///
+/// Serial transfers shift out one byte of the Game Boy while shifting
+/// in one byte of the connected device.
+pub trait SerialDevice: Send {
+    /// Receive the byte sent by the Game Boy and return the byte that
+    /// the device sends back.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Check whether the device has clocked a transfer
+    ///
+    /// This is called regularly with the byte in SB if the Game Boy
+    /// waits for a transfer with external clock, or with None if no
+    /// transfer has been started.  Return the byte that the device has
+    /// sent, if any.
+    fn external_clock(&mut self, _byte: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+impl<T: SerialDevice + ?Sized> SerialDevice for Box<T> {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        (**self).exchange(byte)
+    }
+
+    fn external_clock(&mut self, byte: Option<u8>) -> Option<u8> {
+        (**self).external_clock(byte)
+    }
+}
+
+/// No link cable connected
+///
+/// The Game Boy receives 0xFF, as its serial input is pulled up.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Print all bytes sent by the Game Boy to stdout
+///
+/// Test ROMs like Blargg's report their results this way.  Towards the
+/// Game Boy it behaves like a disconnected link port.
+pub struct DebugConsole;
+
+impl SerialDevice for DebugConsole {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut out = stdout().lock();
+        // Ignore errors, e.g. when stdout has been closed.
+        let _ = out.write_all(&[byte]);
+        let _ = out.flush();
+        0xFF
+    }
+}
+
+/// Collect all bytes sent by the Game Boy
+///
+/// Clones share the collected bytes, so that a clone can be kept to
+/// inspect the output after connecting the log to the Game Boy.  Blargg's
+/// test ROMs print their results this way, ending with "Passed" or
+/// "Failed".  Towards the Game Boy it behaves like a disconnected link
+/// port.
+#[derive(Clone, Default)]
+pub struct SerialLog {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    /// Whether to also print the bytes to stdout
+    echo: bool,
+}
+
+/// Result reported by a test ROM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+}
+
+impl SerialLog {
+    /// A log that prints the bytes to stdout like `DebugConsole`
+    pub fn with_echo() -> Self {
+        Self{
+            echo: true,
+            ..Self::default()
+        }
+    }
+
+    /// The bytes sent so far, with invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.lock().unwrap()).into_owned()
+    }
+
+    /// Whether a test ROM has reported its result
+    pub fn outcome(&self) -> Option<TestOutcome> {
+        let bytes = self.bytes.lock().unwrap();
+        let contains = |word: &[u8]| {
+            bytes.windows(word.len()).any(|window| window == word)
+        };
+        if contains(b"Failed") {
+            Some(TestOutcome::Failed)
+        } else if contains(b"Passed") {
+            Some(TestOutcome::Passed)
+        } else {
+            None
+        }
+    }
+}
+
+impl SerialDevice for SerialLog {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.bytes.lock().unwrap().push(byte);
+        if self.echo {
+            DebugConsole.exchange(byte)
+        } else {
+            0xFF
+        }
+    }
+}
+
+/// Serial transfer registers SB and SC
+///
+/// With internal clock, one bit is shifted out of SB and one bit of the
+/// connected device in every 512 cycles (8192 Hz), so that a transfer
+/// completes after 4096 cycles.  Transfers with external clock wait for
+/// the connected device to clock them, which only a link cable to
+/// another Game Boy does.
+///
+/// Bit  SC – Serial Transfer Control
+/// ---  ----------------------------
+/// 7    Transfer Start Flag (0=No transfer, 1=Start)
+/// 0    Shift Clock (0=External Clock, 1=Internal Clock)
+///
+/// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+#[derive(Serialize, Deserialize)]
+pub struct SerialPort {
+    data: u8,
+    control: u8,
+    #[serde(skip, default = "default_device")]
+    device: Box<dyn SerialDevice>,
+    /// Transfer in progress with internal clock
+    transfer: Option<Transfer>,
+    /// Cycles until the device is checked for an external clock
+    poll_cycles_left: usize,
+}
+
+/// Cycles per bit of a transfer with internal clock
+const CYCLES_PER_BIT: usize = 512;
+
+#[derive(Serialize, Deserialize)]
+struct Transfer {
+    /// Byte sent by the connected device, of which `bits_left` bits
+    /// still have to be shifted into SB
+    incoming: u8,
+    bits_left: u8,
+    /// Cycles until the next bit is shifted
+    cycles_left: usize,
+}
+
+fn default_device() -> Box<dyn SerialDevice> {
+    Box::new(Disconnected)
+}
+
+impl Default for SerialPort {
+    fn default() -> Self {
+        Self{
+            data: 0,
+            control: 0x7E,  // Unused bits are always 1.
+            device: Box::new(Disconnected),
+            transfer: None,
+            poll_cycles_left: CYCLES_PER_BIT,
+        }
+    }
+}
+
+impl SerialPort {
+    /// Connect a device, replacing the previously connected one
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Take over the state of a port loaded from a save state, keeping
+    /// the connected device
+    pub fn restore_state(&mut self, mut saved: Self) {
+        std::mem::swap(&mut self.device, &mut saved.device);
+        *self = saved;
+    }
+
+    pub fn get_data(&self) -> u8 {
+        self.data
+    }
+
+    pub fn set_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    pub fn get_control(&self) -> u8 {
+        self.control
+    }
+
+    /// Write SC, which might start a transfer
+    pub fn set_control(&mut self, value: u8) {
+        self.control = 0x7E | (value & 0x81);
+        self.transfer = if self.control & 0x81 == 0x81 {
+            // The device sees the whole byte at once, as none of them
+            // care about the timing of single bits.
+            Some(Transfer{
+                incoming: self.device.exchange(self.data),
+                bits_left: 8,
+                cycles_left: CYCLES_PER_BIT,
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Advance a transfer in progress by the given number of cycles
+    ///
+    /// Return true if the transfer has completed and the serial
+    /// interrupt should be requested.
+    pub fn step(&mut self, cycles: usize) -> bool {
+        let transfer = match &mut self.transfer {
+            Some(transfer) => transfer,
+            None => return self.poll_external_clock(cycles),
+        };
+        let mut cycles = cycles;
+        while cycles >= transfer.cycles_left {
+            cycles -= transfer.cycles_left;
+            transfer.cycles_left = CYCLES_PER_BIT;
+            self.data = (self.data << 1) | (transfer.incoming >> 7);
+            transfer.incoming <<= 1;
+            transfer.bits_left -= 1;
+            if transfer.bits_left == 0 {
+                self.transfer = None;
+                self.control &= 0x7F;
+                return true;
+            }
+        }
+        transfer.cycles_left -= cycles;
+        false
+    }
+
+    /// Let the device clock a transfer, which is checked once per bit
+    /// clock of the internal clock
+    fn poll_external_clock(&mut self, cycles: usize) -> bool {
+        if cycles < self.poll_cycles_left {
+            self.poll_cycles_left -= cycles;
+            return false;
+        }
+        self.poll_cycles_left = CYCLES_PER_BIT;
+        let waiting = self.control & 0x81 == 0x80;
+        let byte = Some(self.data).filter(|_| waiting);
+        match self.device.external_clock(byte) {
+            Some(incoming) if waiting => {
+                self.data = incoming;
+                self.control &= 0x7F;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Game Boy Printer
+///
+/// The printer receives image data in packets of the form
+///
+/// Bytes  Content
+/// -----  -------
+/// 2      Magic bytes 0x88 0x33
+/// 1      Command
+/// 1      Compression flag
+/// 2      Data length (little endian)
+/// n      Data
+/// 2      Checksum: sum of command, flag, length and data bytes
+/// 2      Responses of the printer: 0x81 and the status
+///
+/// Printed images are saved as PGM files `printout-N.pgm` in the
+/// output directory.
+///
+/// https://gbdev.io/pandocs/Gameboy_Printer.html
+pub struct Printer {
+    output_dir: PathBuf,
+    num_printouts: usize,
+    state: PrinterState,
+    packet: PrinterPacket,
+    /// Tile data received since the last print or initialization
+    image: Vec<u8>,
+    status: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PrinterState {
+    Magic0,
+    Magic1,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+#[derive(Default)]
+struct PrinterPacket {
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    computed_checksum: u16,
+}
+
+const PRINTER_INITIALIZE: u8 = 0x01;
+const PRINTER_PRINT: u8 = 0x02;
+const PRINTER_DATA: u8 = 0x04;
+
+const PRINTER_CHECKSUM_ERROR: u8 = 0x01;
+const PRINTER_UNPROCESSED_DATA: u8 = 0x08;
+
+/// The printer prints 20 tiles per line.
+const PRINTER_WIDTH: usize = 160;
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Self {
+        Self{
+            output_dir,
+            num_printouts: 0,
+            state: PrinterState::Magic0,
+            packet: PrinterPacket::default(),
+            image: Vec::new(),
+            status: 0,
+        }
+    }
+
+    fn execute_packet(&mut self) {
+        let packet = std::mem::take(&mut self.packet);
+        if packet.checksum != packet.computed_checksum {
+            self.status |= PRINTER_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !PRINTER_CHECKSUM_ERROR;
+        let data = if packet.compressed {
+            decompress_printer_data(&packet.data)
+        } else {
+            packet.data
+        };
+        match packet.command {
+            PRINTER_INITIALIZE => {
+                self.image.clear();
+                self.status = 0;
+            }
+            PRINTER_DATA => {
+                self.image.extend_from_slice(&data);
+                if !self.image.is_empty() {
+                    self.status |= PRINTER_UNPROCESSED_DATA;
+                }
+            }
+            PRINTER_PRINT => {
+                let palette = data.get(2).copied().unwrap_or(0xE4);
+                if let Err(e) = self.save_image(palette) {
+                    log::warn!("Could not save printout: {}", e);
+                }
+                self.image.clear();
+                self.status &= !PRINTER_UNPROCESSED_DATA;
+            }
+            _ => {}  // e.g. status request
+        }
+    }
+
+    fn save_image(&mut self, palette: u8) -> io::Result<()> {
+        let tile_rows = self.image.len() / (16 * PRINTER_WIDTH / 8);
+        let height = tile_rows * 8;
+        let mut pixels = vec![0u8; PRINTER_WIDTH * height];
+        for (tile_index, tile) in self.image.chunks_exact(16).enumerate() {
+            let tile_x = (tile_index % (PRINTER_WIDTH / 8)) * 8;
+            let tile_y = (tile_index / (PRINTER_WIDTH / 8)) * 8;
+            if tile_y >= height {
+                break;
+            }
+            for (y, line) in tile.chunks_exact(2).enumerate() {
+                for x in 0..8 {
+                    let bit = 7 - x;
+                    let color = ((line[1] >> bit) & 1) << 1
+                                | ((line[0] >> bit) & 1);
+                    let shade = (palette >> (2 * color)) & 3;
+                    pixels[(tile_y + y) * PRINTER_WIDTH + tile_x + x]
+                        = 0xFF - shade * 0x55;
+                }
+            }
+        }
+        self.num_printouts += 1;
+        let path = self.output_dir
+                       .join(format!("printout-{}.pgm", self.num_printouts));
+        let mut file = BufWriter::new(File::create(&path)?);
+        write!(file, "P5\n{} {}\n255\n", PRINTER_WIDTH, height)?;
+        file.write_all(&pixels)?;
+        file.flush()?;
+        log::info!("Printed {}.", path.display());
+        Ok(())
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        use PrinterState::*;
+        let mut response = 0x00;
+        if matches!(self.state, Command | Compression | LengthLow | LengthHigh
+                                | Data) {
+            self.packet.computed_checksum
+                = self.packet.computed_checksum.wrapping_add(byte as u16);
+        }
+        self.state = match self.state {
+            Magic0 if byte == 0x88 => Magic1,
+            Magic0 => Magic0,
+            Magic1 if byte == 0x33 => {
+                self.packet = PrinterPacket::default();
+                Command
+            }
+            Magic1 if byte == 0x88 => Magic1,
+            Magic1 => Magic0,
+            Command => {
+                self.packet.command = byte;
+                Compression
+            }
+            Compression => {
+                self.packet.compressed = byte & 1 != 0;
+                LengthLow
+            }
+            LengthLow => {
+                self.packet.length = byte as u16;
+                LengthHigh
+            }
+            LengthHigh => {
+                self.packet.length |= (byte as u16) << 8;
+                if self.packet.length == 0 {
+                    ChecksumLow
+                } else {
+                    Data
+                }
+            }
+            Data => {
+                self.packet.data.push(byte);
+                if self.packet.data.len() == self.packet.length as usize {
+                    ChecksumLow
+                } else {
+                    Data
+                }
+            }
+            ChecksumLow => {
+                self.packet.checksum = byte as u16;
+                ChecksumHigh
+            }
+            ChecksumHigh => {
+                self.packet.checksum |= (byte as u16) << 8;
+                Alive
+            }
+            Alive => {
+                response = 0x81;
+                Status
+            }
+            Status => {
+                self.execute_packet();
+                response = self.status;
+                Magic0
+            }
+        };
+        response
+    }
+}
+
+/// Decompress run-length encoded printer data
+///
+/// A control byte with bit 7 set is followed by one byte that is
+/// repeated (control & 0x7F) + 2 times, otherwise it is followed by
+/// control + 1 literal bytes.
+fn decompress_printer_data(data: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    let mut bytes = data.iter().copied();
+    while let Some(control) = bytes.next() {
+        if control & 0x80 != 0 {
+            if let Some(byte) = bytes.next() {
+                let count = (control & 0x7F) as usize + 2;
+                decompressed.extend(std::iter::repeat_n(byte, count));
+            }
+        } else {
+            decompressed.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    decompressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_log_detects_test_outcome() {
+        let log = SerialLog::default();
+        let mut device = log.clone();
+        for &byte in b"cpu_instrs\n\n01:ok  02:ok\n\nPass" {
+            assert_eq!(device.exchange(byte), 0xFF);
+        }
+        assert_eq!(log.outcome(), None);
+        device.exchange(b'e');
+        device.exchange(b'd');
+        assert_eq!(log.outcome(), Some(TestOutcome::Passed));
+        assert!(log.text().ends_with("01:ok  02:ok\n\nPassed"));
+
+        let log = SerialLog::default();
+        let mut device = log.clone();
+        for &byte in b"03:01\n\nFailed 1 tests" {
+            device.exchange(byte);
+        }
+        assert_eq!(log.outcome(), Some(TestOutcome::Failed));
+    }
+
+    /// A device that answers every byte with the same byte and clocks
+    /// a transfer by itself once the Game Boy is waiting for one
+    struct Answer(u8);
+
+    impl SerialDevice for Answer {
+        fn exchange(&mut self, _byte: u8) -> u8 {
+            self.0
+        }
+
+        fn external_clock(&mut self, byte: Option<u8>) -> Option<u8> {
+            byte.map(|_| self.0)
+        }
+    }
+
+    #[test]
+    fn internal_clock_shifts_in_one_bit_every_512_cycles() {
+        let mut port = SerialPort::default();
+        port.connect(Box::new(Answer(0b1010_0000)));
+        port.set_data(0x0F);
+        port.set_control(0x81);
+        assert!(!port.step(CYCLES_PER_BIT - 1));
+        assert_eq!(port.get_data(), 0x0F);
+        assert!(!port.step(1));
+        assert_eq!(port.get_data(), 0x1F);
+        assert!(!port.step(6 * CYCLES_PER_BIT));
+        assert_eq!(port.get_control(), 0xFF);
+        assert!(port.step(CYCLES_PER_BIT));
+        assert_eq!(port.get_data(), 0b1010_0000);
+        assert_eq!(port.get_control(), 0x7F);
+        // No further interrupt without a new transfer.
+        assert!(!port.step(8 * CYCLES_PER_BIT));
+    }
+
+    #[test]
+    fn external_clock_is_driven_by_the_device() {
+        let mut port = SerialPort::default();
+        port.connect(Box::new(Answer(0x42)));
+        port.set_data(0x99);
+        // Without a transfer started, the device can't send anything.
+        assert!(!port.step(CYCLES_PER_BIT));
+        assert_eq!(port.get_data(), 0x99);
+        port.set_control(0x80);
+        assert!(port.step(CYCLES_PER_BIT));
+        assert_eq!(port.get_data(), 0x42);
+        assert_eq!(port.get_control(), 0x7E);
+    }
+
+    #[test]
+    fn disconnected_port_receives_ones() {
+        let mut port = SerialPort::default();
+        port.set_data(0x00);
+        port.set_control(0x81);
+        assert!(port.step(8 * CYCLES_PER_BIT));
+        assert_eq!(port.get_data(), 0xFF);
+    }
+
+    /// Send a packet to the printer and return its last two responses
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool,
+                   data: &[u8], checksum_error: u16) -> [u8; 2] {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut body = vec![command, compressed as u8, length[0], length[1]];
+        body.extend_from_slice(data);
+        let checksum = body.iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+            .wrapping_add(checksum_error)
+            .to_le_bytes();
+        for byte in [0x88, 0x33].iter().chain(&body).chain(&checksum) {
+            assert_eq!(printer.exchange(*byte), 0x00);
+        }
+        [printer.exchange(0x00), printer.exchange(0x00)]
+    }
+
+    #[test]
+    fn printer_saves_printed_tiles() {
+        let directory = std::env::temp_dir().join("emulato-rs-printer");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(directory.clone());
+        assert_eq!(send_packet(&mut printer, PRINTER_INITIALIZE, false, &[],
+                               0),
+                   [0x81, 0x00]);
+        // One line of 20 tiles of color 1, and a line with color 2 in
+        // the first row of its first tile and color 3 everywhere else,
+        // which is sent compressed as two literal bytes followed by
+        // runs of 129, 129 and 60 bytes.
+        let tiles = [0xFF, 0x00].repeat(8 * 20);
+        assert_eq!(send_packet(&mut printer, PRINTER_DATA, false, &tiles, 0),
+                   [0x81, PRINTER_UNPROCESSED_DATA]);
+        let compressed = [0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                          0x80 | 58, 0xFF];
+        let tiles = [vec![0x00], vec![0xFF; 16 * 20 - 1]].concat();
+        assert_eq!(decompress_printer_data(&compressed), tiles);
+        assert_eq!(send_packet(&mut printer, PRINTER_DATA, true,
+                               &compressed, 0),
+                   [0x81, PRINTER_UNPROCESSED_DATA]);
+        // Print with a palette that swaps the shades of all colors.
+        assert_eq!(send_packet(&mut printer, PRINTER_PRINT, false,
+                               &[0x01, 0x13, 0x1B, 0x40], 0),
+                   [0x81, 0x00]);
+
+        let printout = std::fs::read(directory.join("printout-1.pgm"))
+            .unwrap();
+        let header = b"P5\n160 16\n255\n";
+        assert_eq!(&printout[..header.len()], header);
+        let pixels = &printout[header.len()..];
+        assert_eq!(pixels.len(), 160 * 16);
+        assert!(pixels[..160 * 8].iter().all(|&p| p == 0x55));
+        assert!(pixels[160 * 8..160 * 8 + 8].iter().all(|&p| p == 0xAA));
+        assert!(pixels[160 * 8 + 8..].iter().all(|&p| p == 0xFF));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn printer_reports_checksum_errors() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        assert_eq!(send_packet(&mut printer, PRINTER_DATA, false,
+                               &[0x12; 16], 1),
+                   [0x81, PRINTER_CHECKSUM_ERROR]);
+        // The data of the broken packet is dropped.
+        assert_eq!(send_packet(&mut printer, 0x0F, false, &[], 0),
+                   [0x81, 0x00]);
+    }
+}
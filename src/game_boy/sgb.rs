@@ -0,0 +1,619 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
+use super::cartridge::CartridgeHeader;
+use super::io::{HEIGHT, SGB_HEIGHT, SGB_WIDTH, WIDTH};
+
+/// Position of the Game Boy screen inside the Super Game Boy border
+const SCREEN_X: usize = (SGB_WIDTH - WIDTH) / 2;
+const SCREEN_Y: usize = (SGB_HEIGHT - HEIGHT) / 2;
+
+/// Size of the screen attribute map in 8x8 pixel cells
+const ATTRIBUTE_WIDTH: usize = WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = HEIGHT / 8;
+
+/// Size of the border tile map in tiles
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = SGB_HEIGHT / 8;
+
+const NUM_SYSTEM_PALETTES: usize = 512;
+const NUM_ATTRIBUTE_FILES: usize = 45;
+/// 20x18 cells with 2 bits each
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT / 4;
+
+/// Number of bytes copied from the screen by the *_TRN commands
+const VRAM_TRANSFER_SIZE: usize = 0x1000;
+
+/// SGB palette "1-A", which is used until a game sets its own palettes
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+/// Whether a cartridge uses Super Game Boy functions
+///
+/// The SGB only accepts commands from cartridges that declare SGB support
+/// and use the new licensee code.
+pub fn is_enhanced(header: &CartridgeHeader) -> bool {
+    header.supports_sgb_function() && header.uses_new_licensee_code()
+}
+
+/// How the Game Boy screen is shown, set with MASK_EN
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Mask {
+    None,
+    /// Keep showing the last frame
+    Freeze,
+    Black,
+    /// Fill the screen with color 0
+    Color0,
+}
+
+/// Super Game Boy functions of the Game Boy's host SNES
+///
+/// Games send command packets to the SGB by pulsing the P14 and P15
+/// lines of the joypad register.  Each packet consists of a reset pulse
+/// (P14 and P15 low), 128 data bits sent LSB first (P14 low for 0, P15
+/// low for 1) and a stop bit 0.  The first byte of the first packet of
+/// a command holds the command number in its upper five bits and the
+/// number of packets in its lower three bits.
+///
+/// Commands transferring data to the SNES (*_TRN) copy 4KB from the tiles
+/// currently displayed on the Game Boy screen.
+///
+/// https://gbdev.io/pandocs/SGB_Functions.html
+#[derive(Serialize, Deserialize)]
+pub struct SuperGameBoy {
+    previous_p1: u8,
+    /// Index of the next bit of the packet that is being received
+    next_bit: Option<usize>,
+    packet: [u8; 16],
+    /// Packets received so far of a multi-packet command
+    command: Vec<u8>,
+    num_players: u8,
+    player: u8,
+    /// Colors of the four screen palettes in 15 bit BGR format
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attribute_files: Vec<u8>,
+    /// Screen palette of each 8x8 pixel cell
+    #[serde(with = "BigArray")]
+    attributes: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    /// 4 bit per pixel border tiles in SNES format
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+    frozen_screen: Option<Vec<u8>>,
+}
+
+impl Default for SuperGameBoy {
+    fn default() -> Self {
+        Self{
+            previous_p1: 0x30,
+            next_bit: None,
+            packet: [0; 16],
+            command: Vec::new(),
+            num_players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; NUM_SYSTEM_PALETTES],
+            attribute_files: vec![0; NUM_ATTRIBUTE_FILES
+                                     * ATTRIBUTE_FILE_SIZE],
+            attributes: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            frozen_screen: None,
+        }
+    }
+}
+
+impl SuperGameBoy {
+    /// Joypad whose buttons are currently read, 0 is the first player
+    ///
+    /// When neither button row is selected, the joypad register reads
+    /// 0x0F minus the joypad number, which games use to detect the SGB.
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    /// Receive the P14 and P15 bits of a joypad register write
+    ///
+    /// `vram` is the memory range 0x8000–0x9FFF and `lcdc` the LCD
+    /// control register, which are needed for VRAM transfers.
+    pub fn write_joypad(&mut self, value: u8, vram: &[u8], lcdc: u8) {
+        let p1 = value & 0x30;
+        let previous_p1 = self.previous_p1;
+        self.previous_p1 = p1;
+        match p1 {
+            0x00 => {  // reset pulse
+                self.next_bit = Some(0);
+                self.packet = [0; 16];
+            }
+            0x10 | 0x20 if previous_p1 == 0x30 => {
+                let next_bit = match self.next_bit {
+                    Some(next_bit) => next_bit,
+                    None => return,
+                };
+                if next_bit == 8 * self.packet.len() {
+                    // stop bit
+                    self.next_bit = None;
+                    self.receive_packet(vram, lcdc);
+                    return;
+                }
+                if p1 == 0x10 {
+                    self.packet[next_bit / 8] |= 1 << (next_bit % 8);
+                }
+                self.next_bit = Some(next_bit + 1);
+            }
+            0x30 if self.next_bit.is_none() && previous_p1 & 0x20 == 0 => {
+                // Select the next joypad when P15 goes high.
+                self.player = (self.player + 1) % self.num_players;
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_packet(&mut self, vram: &[u8], lcdc: u8) {
+        self.command.extend_from_slice(&self.packet);
+        let num_packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() < num_packets * self.packet.len() {
+            return;
+        }
+        let command = std::mem::take(&mut self.command);
+        self.execute(&command, vram, lcdc);
+    }
+
+    fn execute(&mut self, data: &[u8], vram: &[u8], lcdc: u8) {
+        let command = data[0] >> 3;
+        log::debug!("SGB command {:0>2X}", command);
+        match command {
+            PAL01 => self.set_palette_colors(0, 1, data),
+            PAL23 => self.set_palette_colors(2, 3, data),
+            PAL03 => self.set_palette_colors(0, 3, data),
+            PAL12 => self.set_palette_colors(1, 2, data),
+            ATTR_BLK => self.set_attribute_blocks(data),
+            PAL_SET => {
+                for i in 0..4 {
+                    let number = u16::from_le_bytes([data[1 + 2 * i],
+                                                     data[2 + 2 * i]]);
+                    self.palettes[i] = self.system_palettes
+                                           [number as usize
+                                            % NUM_SYSTEM_PALETTES];
+                }
+                // All palettes share color 0 of palette 0.
+                for i in 1..4 {
+                    self.palettes[i][0] = self.palettes[0][0];
+                }
+                self.set_attribute_file(data[9]);
+            }
+            PAL_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                for (palette, colors) in self.system_palettes
+                                             .iter_mut()
+                                             .zip(transfer.chunks_exact(8)) {
+                    for (color, bytes) in palette.iter_mut()
+                                                 .zip(colors.chunks_exact(2)) {
+                        *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    }
+                }
+            }
+            MLT_REQ => {
+                self.num_players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                let start = if data[1] & 1 == 0 { 0 } else { 128 * 32 };
+                self.border_tiles[start..start + VRAM_TRANSFER_SIZE]
+                    .copy_from_slice(&transfer);
+            }
+            PCT_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                for (entry, bytes) in self.border_map
+                                          .iter_mut()
+                                          .zip(transfer.chunks_exact(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                for (palette, colors) in self.border_palettes
+                                             .iter_mut()
+                                             .zip(transfer[0x800..]
+                                                  .chunks_exact(32)) {
+                    for (color, bytes) in palette.iter_mut()
+                                                 .zip(colors.chunks_exact(2)) {
+                        *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    }
+                }
+            }
+            ATTR_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&transfer[..size]);
+            }
+            ATTR_SET => self.set_attribute_file(data[1] | 0x80),
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                };
+                self.frozen_screen = None;
+            }
+            _ => log::debug!("Ignoring unimplemented SGB command {:0>2X}.",
+                             command),
+        }
+    }
+
+    /// Set the colors of two palettes with PAL01, PAL23, PAL03 or PAL12
+    ///
+    /// The data contains color 0 of all palettes and colors 1–3
+    /// of the first and the second palette.
+    fn set_palette_colors(&mut self, first: usize, second: usize,
+                          data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + 2 * i],
+                                                   data[2 + 2 * i]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    /// Assign palettes to rectangular blocks of the screen
+    ///
+    /// Each data set consists of 6 bytes:
+    ///
+    /// Byte  Content
+    /// ----  -------
+    /// 0     Control: bit 0 change inside, bit 1 border, bit 2 outside
+    /// 1     Palettes: bits 0–1 inside, 2–3 border, 4–5 outside
+    /// 2–5   Left, top, right and bottom cell of the block border
+    fn set_attribute_blocks(&mut self, data: &[u8]) {
+        let num_data_sets = (data[1] & 0x1F) as usize;
+        for data_set in data[2..].chunks_exact(6).take(num_data_sets) {
+            let control = data_set[0] & 0x07;
+            let inside = data_set[1] & 0x03;
+            // If only the inside or only the outside is changed,
+            // the border is changed as well.
+            let border = match control {
+                0x01 => inside,
+                0x04 => (data_set[1] >> 4) & 0x03,
+                _ => (data_set[1] >> 2) & 0x03,
+            };
+            let control = match control {
+                0x01 | 0x04 => control | 0x02,
+                _ => control,
+            };
+            let outside = (data_set[1] >> 4) & 0x03;
+            let (left, top) = (data_set[2] as usize, data_set[3] as usize);
+            let (right, bottom) = (data_set[4] as usize, data_set[5] as usize);
+            for y in 0..ATTRIBUTE_HEIGHT {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    let is_inside = left < x && x < right
+                                    && top < y && y < bottom;
+                    let is_border = !is_inside
+                                    && left <= x && x <= right
+                                    && top <= y && y <= bottom;
+                    let palette = if is_inside {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else if is_border {
+                        (control & 0x02 != 0).then_some(border)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTRIBUTE_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle the attribute file byte of PAL_SET and ATTR_SET
+    ///
+    /// Bit 7 applies the attribute file given in bits 0–5,
+    /// bit 6 cancels the screen mask.
+    fn set_attribute_file(&mut self, value: u8) {
+        if value & 0x80 != 0 {
+            let number = (value & 0x3F) as usize % NUM_ATTRIBUTE_FILES;
+            let file = &self.attribute_files[number * ATTRIBUTE_FILE_SIZE
+                                             ..(number + 1)
+                                               * ATTRIBUTE_FILE_SIZE];
+            for (i, attribute) in self.attributes.iter_mut().enumerate() {
+                let byte = file[i / 4];
+                *attribute = (byte >> (6 - 2 * (i % 4))) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = Mask::None;
+            self.frozen_screen = None;
+        }
+    }
+
+    /// Draw the Game Boy screen with its palettes into the border
+    ///
+    /// `screen` are the pixels passed to `IO::refresh` and `frame` is
+    /// filled with `SGB_WIDTH` x `SGB_HEIGHT` RGB colors.
+    pub fn render(&mut self, screen: &[u8], frame: &mut Vec<u32>) {
+        frame.clear();
+        frame.resize(SGB_WIDTH * SGB_HEIGHT, to_rgb(self.palettes[0][0]));
+        if self.mask == Mask::Freeze && self.frozen_screen.is_none() {
+            self.frozen_screen = Some(screen.to_vec());
+        }
+        let screen = match self.mask {
+            Mask::None => Some(screen),
+            Mask::Freeze => self.frozen_screen.as_deref(),
+            Mask::Black | Mask::Color0 => None,
+        };
+        for y in 0..HEIGHT {
+            let line = &mut frame[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X..]
+                                 [..WIDTH];
+            match screen {
+                Some(screen) => {
+                    for (x, pixel) in line.iter_mut().enumerate() {
+                        let cell = (y / 8) * ATTRIBUTE_WIDTH + x / 8;
+                        let palette = self.attributes[cell] as usize;
+                        let shade = (screen[y * WIDTH + x] & 0x03) as usize;
+                        *pixel = to_rgb(self.palettes[palette][shade]);
+                    }
+                }
+                None if self.mask == Mask::Black => line.fill(0),
+                None => {}
+            }
+        }
+        self.draw_border(frame);
+    }
+
+    /// Draw the border tiles, whose color 0 is transparent
+    ///
+    /// Each entry of the border map consists of
+    ///
+    /// Bits    Content
+    /// ----    -------
+    /// 0–7     Tile number
+    /// 10–12   Palette number 4–7
+    /// 14      Horizontal flip
+    /// 15      Vertical flip
+    fn draw_border(&self, frame: &mut [u32]) {
+        for (i, entry) in self.border_map.iter().enumerate() {
+            let tile_x = (i % BORDER_MAP_WIDTH) * 8;
+            let tile_y = (i / BORDER_MAP_WIDTH) * 8;
+            let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..]
+                                         [..32];
+            let palette = &self.border_palettes
+                               [((entry >> 10) & 0x03) as usize];
+            let x_flip = entry & (1 << 14) != 0;
+            let y_flip = entry & (1 << 15) != 0;
+            for y in 0..8 {
+                let row = if y_flip { 7 - y } else { y };
+                let planes = [tile[2 * row], tile[2 * row + 1],
+                              tile[16 + 2 * row], tile[16 + 2 * row + 1]];
+                for x in 0..8 {
+                    let bit = if x_flip { x } else { 7 - x };
+                    let color = planes.iter()
+                                      .enumerate()
+                                      .fold(0, |color, (plane, bits)| {
+                                          color | (((bits >> bit) & 1)
+                                                   << plane)
+                                      });
+                    if color != 0 {
+                        frame[(tile_y + y) * SGB_WIDTH + tile_x + x]
+                            = to_rgb(palette[color as usize]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Copy the tile data of the first 256 tiles displayed on the screen
+///
+/// The tiles are read row by row from the top left corner of the
+/// background map, ignoring scrolling.
+fn vram_transfer(vram: &[u8], lcdc: u8) -> Vec<u8> {
+    let map = if lcdc & 0x08 == 0 { 0x1800 } else { 0x1C00 };
+    let mut transfer = Vec::with_capacity(VRAM_TRANSFER_SIZE);
+    for i in 0..VRAM_TRANSFER_SIZE / 16 {
+        let map_entry = vram[map + (i / ATTRIBUTE_WIDTH) * 32
+                             + i % ATTRIBUTE_WIDTH];
+        let tile = if lcdc & 0x10 != 0 {
+            map_entry as usize * 16
+        } else {
+            (0x1000 + (map_entry as i8 as isize) * 16) as usize
+        };
+        transfer.extend_from_slice(&vram[tile..tile + 16]);
+    }
+    transfer
+}
+
+/// Convert a 15 bit BGR color to 24 bit RGB
+fn to_rgb(color: u16) -> u32 {
+    let scale = |c: u16| {
+        let c = (c & 0x1F) as u32;
+        (c << 3) | (c >> 2)
+    };
+    (scale(color) << 16) | (scale(color >> 5) << 8) | scale(color >> 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a command through the P14 and P15 lines, padded to whole
+    /// packets
+    fn send_command(sgb: &mut SuperGameBoy, data: &[u8], vram: &[u8],
+                    lcdc: u8) {
+        for packet in data.chunks(16) {
+            let mut bytes = [0; 16];
+            bytes[..packet.len()].copy_from_slice(packet);
+            sgb.write_joypad(0x00, vram, lcdc);
+            sgb.write_joypad(0x30, vram, lcdc);
+            for i in 0..8 * bytes.len() {
+                let bit = (bytes[i / 8] >> (i % 8)) & 1;
+                sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 }, vram,
+                                 lcdc);
+                sgb.write_joypad(0x30, vram, lcdc);
+            }
+            // stop bit
+            sgb.write_joypad(0x20, vram, lcdc);
+            sgb.write_joypad(0x30, vram, lcdc);
+        }
+    }
+
+    fn send(sgb: &mut SuperGameBoy, data: &[u8]) {
+        send_command(sgb, data, &[0; 0x2000], 0);
+    }
+
+    fn pixel(frame: &[u32], x: usize, y: usize) -> u32 {
+        frame[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x]
+    }
+
+    #[test]
+    fn pal01_sets_colors_of_two_palettes() {
+        let mut sgb = SuperGameBoy::default();
+        send(&mut sgb, &[
+            PAL01 << 3 | 1,
+            0x00, 0x00,  // color 0 of all palettes
+            0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C,  // palette 0
+            0x01, 0x00, 0x02, 0x00, 0x03, 0x00,  // palette 1
+        ]);
+        assert_eq!(sgb.palettes[0], [0x0000, 0x001F, 0x03E0, 0x7C00]);
+        assert_eq!(sgb.palettes[1], [0x0000, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[2], [0x0000, 0x265B, 0x10B5, 0x2866]);
+        assert_eq!(sgb.palettes[3][0], 0x0000);
+
+        let mut frame = Vec::new();
+        sgb.render(&[1; WIDTH * HEIGHT], &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), 0xFF0000);
+        sgb.render(&[3; WIDTH * HEIGHT], &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), 0x0000FF);
+    }
+
+    #[test]
+    fn attr_blk_assigns_palettes_to_blocks() {
+        let mut sgb = SuperGameBoy::default();
+        // The third data set continues in a second packet.
+        send(&mut sgb, &[
+            ATTR_BLK << 3 | 2, 3,
+            // The whole screen outside of an empty block gets palette 1.
+            0x04, 0x10, 30, 30, 30, 30,
+            // Changing only the inside changes the border, too.
+            0x01, 0x02, 1, 1, 4, 3,
+            // Inside, border and outside
+            0x03, 0x07, 10, 10, 12, 12,
+        ]);
+        let attribute = |x: usize, y: usize| {
+            sgb.attributes[y * ATTRIBUTE_WIDTH + x]
+        };
+        assert_eq!(attribute(0, 0), 1);
+        assert_eq!(attribute(1, 1), 2);
+        assert_eq!(attribute(4, 3), 2);
+        assert_eq!(attribute(5, 3), 1);
+        assert_eq!(attribute(10, 10), 1);
+        assert_eq!(attribute(11, 11), 3);
+        assert_eq!(attribute(19, 17), 1);
+    }
+
+    #[test]
+    fn commands_wait_for_all_their_packets() {
+        let mut sgb = SuperGameBoy::default();
+        let mut data = vec![ATTR_BLK << 3 | 2, 3];
+        data.extend([0x01, 0x01, 0, 0, 19, 17]);
+        send(&mut sgb, &data);
+        assert!(sgb.attributes.iter().all(|&palette| palette == 0));
+        send(&mut sgb, &[0; 16]);
+        assert_eq!(sgb.attributes[ATTRIBUTE_WIDTH + 1], 1);
+    }
+
+    #[test]
+    fn mask_en_freezes_and_blanks_the_screen() {
+        let mut sgb = SuperGameBoy::default();
+        let mut frame = Vec::new();
+        let shades = |shade| vec![shade; WIDTH * HEIGHT];
+        let colors: Vec<u32> = DEFAULT_PALETTE.iter()
+                                              .map(|&color| to_rgb(color))
+                                              .collect();
+
+        send(&mut sgb, &[MASK_EN << 3 | 1, 1]);
+        sgb.render(&shades(1), &mut frame);
+        sgb.render(&shades(2), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), colors[1]);
+
+        send(&mut sgb, &[MASK_EN << 3 | 1, 2]);
+        sgb.render(&shades(2), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), 0);
+
+        send(&mut sgb, &[MASK_EN << 3 | 1, 3]);
+        sgb.render(&shades(2), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), colors[0]);
+
+        send(&mut sgb, &[MASK_EN << 3 | 1, 0]);
+        sgb.render(&shades(2), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), colors[2]);
+    }
+
+    /// VRAM that shows the given 4KB on the first 256 tiles of the screen
+    fn vram_showing(transfer: &[u8]) -> Vec<u8> {
+        let mut vram = vec![0; 0x2000];
+        vram[..VRAM_TRANSFER_SIZE].copy_from_slice(transfer);
+        for i in 0..256 {
+            vram[0x1800 + (i / ATTRIBUTE_WIDTH) * 32 + i % ATTRIBUTE_WIDTH]
+                = i as u8;
+        }
+        vram
+    }
+
+    #[test]
+    fn border_is_transferred_from_vram() {
+        let mut sgb = SuperGameBoy::default();
+        let lcdc = 0x91;
+        // Tile 1 has color 1 in the top left pixel.
+        let mut tiles = vec![0; VRAM_TRANSFER_SIZE];
+        tiles[32] = 0x80;
+        send_command(&mut sgb, &[CHR_TRN << 3 | 1, 0],
+                     &vram_showing(&tiles), lcdc);
+        assert_eq!(sgb.border_tiles[32], 0x80);
+
+        // The first two map entries show tile 1 with palette 5,
+        // the second one flipped horizontally.
+        let mut map = vec![0; VRAM_TRANSFER_SIZE];
+        map[..4].copy_from_slice(&[0x01, 0x14, 0x01, 0x54]);
+        map[0x800 + 32 + 2..0x800 + 32 + 4].copy_from_slice(&[0xE0, 0x03]);
+        send_command(&mut sgb, &[PCT_TRN << 3 | 1],
+                     &vram_showing(&map), lcdc);
+        assert_eq!(sgb.border_map[..3], [0x1401, 0x5401, 0x0000]);
+        assert_eq!(sgb.border_palettes[1][1], 0x03E0);
+
+        let mut frame = Vec::new();
+        sgb.render(&[0; WIDTH * HEIGHT], &mut frame);
+        let backdrop = to_rgb(DEFAULT_PALETTE[0]);
+        assert_eq!(frame[..16].iter().filter(|&&c| c != backdrop).count(),
+                   2);
+        assert_eq!(frame[0], 0x00FF00);
+        assert_eq!(frame[15], 0x00FF00);
+    }
+}
@@ -0,0 +1,28 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A curved CRT tube with soft scanlines and darker edges
+
+// How far the glass bulges out; 0.0 is flat
+const CURVATURE: f32 = 0.06;
+const PI: f32 = 3.14159265;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Bend the frame around its center, so that the corners fall
+    // outside of the tube.
+    let centered = in.tex_coord * 2.0 - 1.0;
+    let bent = centered * (1.0 + CURVATURE * dot(centered, centered));
+    let uv = (bent + 1.0) * 0.5;
+    let color = textureSample(screen_texture, screen_sampler, uv).rgb;
+    // Brightest at the center of each pixel row
+    let row = fract(uv.y * screen.size.y);
+    let scanline = 0.7 + 0.3 * sin(row * PI);
+    let vignette = 1.0 - 0.25 * dot(centered, centered);
+    let inside = all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0));
+    if !inside {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    return vec4<f32>(color * scanline * vignette, 1.0);
+}
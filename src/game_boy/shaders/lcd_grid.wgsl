@@ -0,0 +1,17 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Thin gaps between all pixels, like on the DMG's LCD
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(screen_texture, screen_sampler, in.tex_coord);
+    let pixel_size = screen.output_size / screen.size;
+    let within_pixel = fract(in.tex_coord * screen.size);
+    let is_gap = within_pixel >= 1.0 - 1.0 / pixel_size;
+    if min(pixel_size.x, pixel_size.y) >= 2.0 && any(is_gap) {
+        return vec4<f32>(color.rgb * 0.75, color.a);
+    }
+    return color;
+}
@@ -0,0 +1,35 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Declarations that every screen shader is compiled with.  A shader
+// defines `fs_main`, which returns the color of a window pixel.
+
+struct Screen {
+    // Size of the frame in pixels, 160x144 or 256x224 with SGB border
+    size: vec2<f32>,
+    // Size in window pixels that the frame is shown at
+    output_size: vec2<f32>,
+}
+
+@group(0) @binding(0) var screen_texture: texture_2d<f32>;
+// Sampler that picks the nearest frame pixel
+@group(0) @binding(1) var screen_sampler: sampler;
+@group(0) @binding(2) var<uniform> screen: Screen;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    // Position in the frame from (0, 0) at the top left to (1, 1) at
+    // the bottom right
+    @location(0) tex_coord: vec2<f32>,
+}
+
+// A triangle that covers the part of the window showing the frame
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(corner * 2.0 - 1.0, 0.0, 1.0);
+    out.tex_coord = vec2<f32>(corner.x, 1.0 - corner.y);
+    return out;
+}
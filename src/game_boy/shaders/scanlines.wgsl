@@ -0,0 +1,18 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Dark lines between the pixel rows, like on a CRT
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(screen_texture, screen_sampler, in.tex_coord);
+    // Window pixels per frame pixel, which need to be at least 2 to
+    // leave room for the lines
+    let pixel_height = screen.output_size.y / screen.size.y;
+    let row = fract(in.tex_coord.y * screen.size.y);
+    if pixel_height >= 2.0 && row >= 1.0 - 1.0 / pixel_height {
+        return vec4<f32>(color.rgb * 0.5, color.a);
+    }
+    return color;
+}
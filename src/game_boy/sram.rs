@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+
+use memmap2::MmapMut;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Storage of the cartridge RAM
+///
+/// The RAM is either kept in memory and written into the save file
+/// explicitly, or it is a memory-mapped save file, so that the operating
+/// system writes every change back into the file.
+pub enum Sram {
+    Heap(Vec<u8>),
+    Mapped(MmapMut),
+}
+
+impl Sram {
+    /// Map the given file as RAM of the size of this RAM
+    ///
+    /// An empty file is filled with the current contents first, while
+    /// the contents of a non-empty file replace them.  Files of the wrong
+    /// size are refused rather than resized, since that would destroy
+    /// their contents before the game even gets to look at them.
+    pub fn map(&self, mut file: &File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        if size == 0 {
+            file.write_all(self)?;
+        } else if size != self.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("save file has {} bytes, but cartridge RAM has {}",
+                        size, self.len())));
+        }
+        // Safety: The save file must not be modified by anyone else
+        // while it is mapped, like for any other save file in use.
+        let mmap = unsafe { MmapMut::map_mut(file)? };
+        Ok(Self::Mapped(mmap))
+    }
+
+    /// Wait until all changes of a mapped RAM have reached its file
+    pub fn flush(&self) -> io::Result<()> {
+        match self {
+            Self::Heap(_) => Ok(()),
+            Self::Mapped(mmap) => mmap.flush(),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+}
+
+impl Deref for Sram {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Heap(ram) => ram,
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+impl DerefMut for Sram {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(ram) => ram,
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// A RAM is saved as its contents and always restored into memory
+impl Serialize for Sram {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> Result<S::Ok, S::Error> {
+        self[..].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Sram {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> Result<Self, D::Error> {
+        Ok(Self::Heap(Vec::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn mapped_ram_writes_through_to_file() {
+        let path = std::env::temp_dir().join("emulato-rs-sram-test.sav");
+        let _ = std::fs::remove_file(&path);
+        let file = OpenOptions::new().read(true).write(true).create(true)
+                                     .truncate(false).open(&path).unwrap();
+        let mut ram = Sram::Heap(vec![0xFF; 0x2000]).map(&file).unwrap();
+        assert!(ram[..].iter().all(|&b| b == 0xFF));
+        ram[0x1234] = 0x42;
+        ram.flush().unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.len(), 0x2000);
+        assert_eq!(contents[0x1234], 0x42);
+        assert_eq!(contents[0x1233], 0xFF);
+    }
+
+    #[test]
+    fn file_of_wrong_size_is_not_mapped() {
+        let path = std::env::temp_dir().join("emulato-rs-sram-size.sav");
+        std::fs::write(&path, [0x01, 0x02, 0x03]).unwrap();
+        let file = OpenOptions::new().read(true).write(true)
+                                     .open(&path).unwrap();
+        let error = Sram::Heap(vec![0; 0x2000]).map(&file).err().unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(contents, [0x01, 0x02, 0x03]);
+    }
+}
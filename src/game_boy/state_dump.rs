@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use super::cartridge::MapperState;
+use super::cpu::CpuState;
+use super::memory::MemoryBus;
+use super::timer::Timer;
+
+/// State of the machine as written by `GameBoy::dump_state`
+///
+/// Unlike save states, it is meant to be read by people and other tools,
+/// e.g. to look at the state after a crash or to set up a unit test.
+#[derive(Serialize)]
+pub struct StateDump<'a> {
+    pub frame: u64,
+    pub cpu: CpuState,
+    /// The I/O registers and IE by their address in hex, like "FF40"
+    pub io_registers: BTreeMap<String, u8>,
+    pub timer: &'a Timer,
+    /// The registers of the memory controller, named by its model
+    pub mapper: MapperState<'a>,
+}
+
+impl<'a> StateDump<'a> {
+    pub fn new(frame: u64, cpu: CpuState, memory: &'a MemoryBus) -> Self {
+        let io_registers = memory.io_registers()
+                                 .into_iter()
+                                 .map(|(address, value)| {
+                                     (format!("{:0>4X}", address), value)
+                                 })
+                                 .collect();
+        Self{
+            frame,
+            cpu,
+            io_registers,
+            timer: memory.timer(),
+            mapper: memory.cartridge().mapper_state(),
+        }
+    }
+
+    pub fn write_json(&self, mut writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+}
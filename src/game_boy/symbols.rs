@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Labels of a ROM, as written by RGBLINK into a `.sym` file
+///
+/// Each line of the file holds a bank number and an address in hex,
+/// followed by the name of the label:
+///
+/// 00:0150 Main
+/// 01:4000 Main::loop
+///
+/// Everything after a `;` is a comment.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    labels: BTreeMap<(usize, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn load(reader: impl Read) -> io::Result<Self> {
+        let mut table = Self::default();
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = match line.split_once(';') {
+                Some((symbol, _comment)) => symbol,
+                None => &line,
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (bank, address, name) = parse_symbol(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Malformed symbol on line {}: {}",
+                                       number + 1, line))
+            })?;
+            table.labels.insert((bank, address), name.to_string());
+        }
+        Ok(table)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Describe an address by the closest label before it
+    ///
+    /// Only labels in the same bank and memory region are considered,
+    /// e.g. "Main::loop+3" for the third byte after `Main::loop`.
+    /// Addresses outside of ROM and RAM only match exact labels.
+    pub fn lookup(&self, bank: usize, address: u16) -> Option<String> {
+        let (label_address, name) = self.label_before(bank, address)?;
+        Some(match address - label_address {
+            0 => name.to_string(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+
+    /// Address and name of the closest label before an address, as
+    /// used by `lookup`
+    pub fn label_before(&self, bank: usize, address: u16)
+            -> Option<(u16, &str)> {
+        let region_start = match address {
+            0x0000..=0x3FFF => 0x0000,
+            0x4000..=0x7FFF => 0x4000,
+            0x8000..=0x9FFF => 0x8000,
+            0xA000..=0xBFFF => 0xA000,
+            0xC000..=0xCFFF => 0xC000,
+            0xD000..=0xDFFF => 0xD000,
+            0xFF80..=0xFFFE => 0xFF80,
+            _ => address,
+        };
+        let ((_, label_address), name) = self.labels
+            .range((bank, region_start)..=(bank, address))
+            .next_back()?;
+        Some((*label_address, name))
+    }
+}
+
+/// Split a line like "01:4000 Main::loop" into bank, address and name.
+fn parse_symbol(line: &str) -> Option<(usize, u16, &str)> {
+    let (location, name) = line.split_once(char::is_whitespace)?;
+    let (bank, address) = location.split_once(':')?;
+    let bank = usize::from_str_radix(bank, 16).ok()?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((bank, address, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main::loop
+01:4000 Bank1Code
+02:4000 Bank2Code
+00:c000 wCounter
+
+01:d000 wBuffer ; WRAMX
+";
+
+    #[test]
+    fn labels_are_found_by_bank_and_offset() {
+        let symbols = SymbolTable::load(SYMBOLS.as_bytes()).unwrap();
+        assert_eq!(symbols.lookup(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.lookup(0, 0x015B).as_deref(), Some("Main::loop+3"));
+        assert_eq!(symbols.lookup(0, 0x0100), None);
+        assert_eq!(symbols.lookup(2, 0x4010).as_deref(), Some("Bank2Code+16"));
+        assert_eq!(symbols.lookup(3, 0x4010), None);
+        assert_eq!(symbols.lookup(1, 0xD001).as_deref(), Some("wBuffer+1"));
+        // Labels don't extend into the next memory region.
+        assert_eq!(symbols.lookup(0, 0xD000), None);
+    }
+
+    #[test]
+    fn malformed_symbols_are_rejected() {
+        let error = SymbolTable::load("00:0150 Main\n0150 Main::loop\n"
+                                      .as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+    }
+}
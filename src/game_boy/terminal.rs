@@ -0,0 +1,390 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, stdin, stdout, IsTerminal, Write};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Once;
+use std::thread;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind,
+                       KeyModifiers, KeyboardEnhancementFlags,
+                       PopKeyboardEnhancementFlags,
+                       PushKeyboardEnhancementFlags};
+use crossterm::execute;
+use crossterm::terminal::{self, ClearType};
+
+use super::io::{IO, HEIGHT, WIDTH};
+use crate::settings::GameBoyKeyBindings;
+
+/// Frames for which a button stays pressed after its key was read, if
+/// the terminal doesn't report when keys are released
+///
+/// Such terminals only report key presses, which repeat while a key is
+/// held.
+const KEY_HOLD_FRAMES: u8 = 10;
+
+/// Frames left of a button that stays pressed until its key is released
+const UNTIL_RELEASED: u8 = u8::MAX;
+
+/// Whether the keyboard enhancement flags have been pushed to the
+/// terminal, and have to be popped again
+static KEYBOARD_ENHANCED: AtomicBool = AtomicBool::new(false);
+
+/// A frontend that draws the display into the terminal
+///
+/// Each Unicode braille character covers 2x4 pixels, so the whole
+/// 160x144 display fits into 80 columns and 36 lines.
+/// Keyboard input is only read if it has been opened `with_keyboard`.
+pub struct TerminalWindow {
+    frame: String,
+    keyboard: Option<Keyboard>,
+}
+
+impl Default for TerminalWindow {
+    fn default() -> Self {
+        // Clear the screen once, afterwards we only move the cursor
+        // back to the top left corner on every refresh.
+        let _ = execute!(stdout(), terminal::Clear(ClearType::All));
+        Self{
+            frame: String::with_capacity(3 * WIDTH * HEIGHT / 8 + HEIGHT / 2),
+            keyboard: None,
+        }
+    }
+}
+
+impl TerminalWindow {
+    /// Draw into the terminal and read the buttons from its keyboard
+    ///
+    /// Buttons are bound to the keys named like in the settings, but
+    /// only letters, digits, arrow keys, Space, Enter, Tab and
+    /// Backspace can be read from a terminal.  Esc and Ctrl+C quit.
+    ///
+    /// This fails if stdin is not a terminal.
+    pub fn with_keyboard(bindings: &GameBoyKeyBindings) -> io::Result<Self> {
+        let keyboard = Keyboard::open(bindings)?;
+        Ok(Self{
+            keyboard: Some(keyboard),
+            ..Self::default()
+        })
+    }
+}
+
+impl IO for TerminalWindow {
+    fn refresh(&mut self, pixels: &[u8]) {
+        self.frame.clear();
+        self.frame.push_str("\x1B[H");
+        // In raw mode, a line feed doesn't return the cursor to the
+        // start of the line.
+        for line in format_as_braille(pixels).lines() {
+            self.frame.push_str(line);
+            self.frame.push_str("\r\n");
+        }
+        let mut out = stdout().lock();
+        // Ignore errors, e.g. when stdout has been closed.
+        let _ = out.write_all(self.frame.as_bytes());
+        let _ = out.flush();
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        self.keyboard.as_ref().is_some_and(|keyboard| {
+            keyboard.poll();
+            keyboard.escape_pressed.get()
+        })
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        match &self.keyboard {
+            Some(keyboard) => keyboard.buttons(),
+            None => 0,
+        }
+    }
+}
+
+/// Look up a key by the name of its `minifb::Key` variant
+///
+/// Letters are looked up in lower case.
+fn key_code(name: &str) -> Option<KeyCode> {
+    let key = match name {
+        "Up" => KeyCode::Up,
+        "Down" => KeyCode::Down,
+        "Right" => KeyCode::Right,
+        "Left" => KeyCode::Left,
+        "Space" => KeyCode::Char(' '),
+        "Enter" => KeyCode::Enter,
+        "Tab" => KeyCode::Tab,
+        "Backspace" => KeyCode::Backspace,
+        _ => {
+            let name = name.strip_prefix("Key").unwrap_or(name);
+            match name.as_bytes() {
+                &[byte] if byte.is_ascii_alphanumeric() => {
+                    KeyCode::Char(byte.to_ascii_lowercase() as char)
+                }
+                _ => return None,
+            }
+        }
+    };
+    Some(key)
+}
+
+/// JoyPad buttons held by the keys read from a terminal
+struct HeldButtons {
+    /// Keys of the buttons in the bit order of `get_key_presses`
+    bindings: [Option<KeyCode>; 8],
+    /// Frames for which each button stays pressed
+    frames: [u8; 8],
+    /// Whether the terminal reports when keys are released
+    reports_releases: bool,
+}
+
+impl HeldButtons {
+    fn new(bindings: [Option<KeyCode>; 8], reports_releases: bool) -> Self {
+        Self{bindings, frames: [0; 8], reports_releases}
+    }
+
+    fn handle_key(&mut self, key: &KeyEvent) {
+        let code = match key.code {
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
+        };
+        for (binding, frames) in self.bindings.iter()
+                                     .zip(self.frames.iter_mut()) {
+            if *binding != Some(code) {
+                continue;
+            }
+            *frames = match key.kind {
+                KeyEventKind::Release => 0,
+                _ if self.reports_releases => UNTIL_RELEASED,
+                _ => KEY_HOLD_FRAMES,
+            };
+        }
+    }
+
+    /// Bitmap of the pressed buttons, which counts down the frames for
+    /// which they stay pressed
+    fn next_frame(&mut self) -> u8 {
+        let mut presses = 0;
+        for (i, frames) in self.frames.iter_mut().enumerate() {
+            if *frames != 0 {
+                presses |= 1 << i;
+                if *frames != UNTIL_RELEASED {
+                    *frames -= 1;
+                }
+            }
+        }
+        presses
+    }
+}
+
+/// Reads keys from the terminal on stdin in a background thread
+///
+/// The terminal is switched to raw mode until the keyboard is dropped
+/// or the emulator panics.  If the terminal supports it, it reports
+/// key releases, so that buttons are held exactly as long as their keys.
+struct Keyboard {
+    input: Receiver<KeyEvent>,
+    buttons: RefCell<HeldButtons>,
+    escape_pressed: Cell<bool>,
+}
+
+impl Keyboard {
+    fn open(bindings: &GameBoyKeyBindings) -> io::Result<Self> {
+        if !stdin().is_terminal() {
+            return Err(io::Error::other("stdin is not a terminal"));
+        }
+        terminal::enable_raw_mode()?;
+        restore_terminal_on_panic();
+        // Windows consoles always report key releases.
+        let mut reports_releases = cfg!(windows);
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            // Keys that send text only report releases as escape codes.
+            let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES;
+            if execute!(stdout(), PushKeyboardEnhancementFlags(flags))
+                    .is_ok() {
+                KEYBOARD_ENHANCED.store(true, Ordering::SeqCst);
+                reports_releases = true;
+            }
+        }
+        let (sender, input) = channel();
+        thread::spawn(move || {
+            while let Ok(event) = event::read() {
+                if let Event::Key(key) = event {
+                    if sender.send(key).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        let bindings = bindings.in_joypad_order().map(|name| {
+            let key = key_code(name);
+            if key.is_none() {
+                log::warn!("Key {:?} can't be read from a terminal.", name);
+            }
+            key
+        });
+        Ok(Self{
+            input,
+            buttons: RefCell::new(HeldButtons::new(bindings,
+                                                   reports_releases)),
+            escape_pressed: Cell::new(false),
+        })
+    }
+
+    /// Press and release the buttons of the keys read since the last poll
+    fn poll(&self) {
+        let mut buttons = self.buttons.borrow_mut();
+        for key in self.input.try_iter() {
+            let ctrl_c = key.code == KeyCode::Char('c')
+                         && key.modifiers.contains(KeyModifiers::CONTROL);
+            if (key.code == KeyCode::Esc || ctrl_c)
+                    && key.kind != KeyEventKind::Release {
+                self.escape_pressed.set(true);
+            }
+            buttons.handle_key(&key);
+        }
+    }
+
+    fn buttons(&self) -> u8 {
+        self.poll();
+        self.buttons.borrow_mut().next_frame()
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        if let Err(e) = restore_terminal() {
+            log::warn!("Could not restore the terminal: {}", e);
+        }
+    }
+}
+
+/// Leave raw mode and stop reporting key releases
+fn restore_terminal() -> io::Result<()> {
+    if KEYBOARD_ENHANCED.swap(false, Ordering::SeqCst) {
+        execute!(stdout(), PopKeyboardEnhancementFlags)?;
+    }
+    terminal::disable_raw_mode()
+}
+
+/// Restore the terminal before the panic message is printed
+///
+/// Otherwise a crash leaves the shell in raw mode.  Afterwards, the
+/// previously installed panic hook is called.
+fn restore_terminal_on_panic() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let _ = restore_terminal();
+            previous_hook(info);
+        }));
+    });
+}
+
+/// Format the display as lines of Unicode braille characters
+///
+/// Pixels of the two darker shades are drawn as raised dots.
+pub fn format_as_braille(pixels: &[u8]) -> String {
+    let mut braille_bits = vec![0u32; (WIDTH / 2) * (HEIGHT / 4)];
+    for h in 0..(HEIGHT / 4) {
+        for (h2, bit_indices) in [[0, 3], [1, 4], [2, 5], [6, 7]]
+                                 .iter().enumerate() {
+            let input_offset = (4*h + h2) * WIDTH;
+            let output_offset = h * WIDTH / 2;
+            for w in 0..(WIDTH / 2) {
+                for (w2, bit) in bit_indices.iter().enumerate() {
+                    if pixels[input_offset + 2*w + w2] & 3 >= 2 {
+                        braille_bits[output_offset + w] |= 1 << bit;
+                    }
+                }
+            }
+        }
+    }
+    let mut res = String::with_capacity(3 * braille_bits.len() + (HEIGHT / 4));
+    for line in braille_bits.chunks_exact(WIDTH / 2) {
+        for b in line {
+            res.push(char::from_u32(0x2800 + b).unwrap());
+        }
+        res.push('\n');
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, kind: KeyEventKind) -> KeyEvent {
+        KeyEvent::new_with_kind(code, KeyModifiers::NONE, kind)
+    }
+
+    fn bindings() -> [Option<KeyCode>; 8] {
+        let mut bindings = [None; 8];
+        bindings[0] = key_code("Right");
+        bindings[4] = key_code("X");
+        bindings
+    }
+
+    #[test]
+    fn keys_are_looked_up_by_name() {
+        assert_eq!(key_code("Right"), Some(KeyCode::Right));
+        assert_eq!(key_code("W"), Some(KeyCode::Char('w')));
+        assert_eq!(key_code("Key7"), Some(KeyCode::Char('7')));
+        assert_eq!(key_code("Space"), Some(KeyCode::Char(' ')));
+        assert_eq!(key_code("LeftShift"), None);
+    }
+
+    #[test]
+    fn buttons_are_held_until_their_keys_are_released() {
+        let mut buttons = HeldButtons::new(bindings(), true);
+        buttons.handle_key(&key(KeyCode::Char('X'), KeyEventKind::Press));
+        buttons.handle_key(&key(KeyCode::Right, KeyEventKind::Press));
+        for _ in 0..2 * KEY_HOLD_FRAMES {
+            assert_eq!(buttons.next_frame(), 0x11);
+        }
+        buttons.handle_key(&key(KeyCode::Char('x'), KeyEventKind::Release));
+        assert_eq!(buttons.next_frame(), 0x01);
+        buttons.handle_key(&key(KeyCode::Right, KeyEventKind::Release));
+        assert_eq!(buttons.next_frame(), 0);
+    }
+
+    #[test]
+    fn key_presses_hold_buttons_without_release_events() {
+        let mut buttons = HeldButtons::new(bindings(), false);
+        buttons.handle_key(&key(KeyCode::Char('x'), KeyEventKind::Press));
+        buttons.handle_key(&key(KeyCode::Char('y'), KeyEventKind::Press));
+        for _ in 0..KEY_HOLD_FRAMES {
+            assert_eq!(buttons.next_frame(), 0x10);
+        }
+        assert_eq!(buttons.next_frame(), 0);
+    }
+
+    #[test]
+    fn braille_dots_show_the_darker_shades() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        pixels[0] = 3;
+        pixels[3 * WIDTH + 1] = 2;
+        // The lightest two shades are not drawn.
+        pixels[2] = 1;
+        // The last character of the first line
+        pixels[WIDTH - 1] = 3;
+        // The first character of the second line
+        pixels[4 * WIDTH] = 2;
+        let braille = format_as_braille(&pixels);
+        let lines: Vec<Vec<char>> = braille.lines()
+                                           .map(|line| line.chars().collect())
+                                           .collect();
+        assert_eq!(lines.len(), HEIGHT / 4);
+        assert!(lines.iter().all(|line| line.len() == WIDTH / 2));
+        assert_eq!(lines[0][..2], ['\u{2881}', '\u{2800}']);
+        assert_eq!(lines[0][WIDTH / 2 - 1], '\u{2808}');
+        assert_eq!(lines[1][0], '\u{2801}');
+        let dots = braille.chars().filter(|&c| c > '\u{2800}' && c != '\n');
+        assert_eq!(dots.count(), 3);
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError,
+                      Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::io::{Hotkey, IO};
+use super::viewer::VramViews;
+
+/// Frames that can wait for the window before newer ones are dropped
+const QUEUED_FRAMES: usize = 2;
+
+/// How long the window thread waits for a frame before polling the
+/// input again, e.g. while the emulation waits in the debugger
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Output of the emulation that the window thread passes to the window
+enum Output {
+    Screen(Vec<u8>),
+    SgbScreen(Vec<u32>),
+    Vram(Box<VramViews>),
+}
+
+/// Input read from the window, kept until the emulation asks for it
+#[derive(Default)]
+struct Input {
+    esc_pressed: bool,
+    keys: u8,
+    tilt: (f32, f32),
+    hotkeys: Vec<Hotkey>,
+}
+
+/// Connect an emulation thread to a window on the current thread
+///
+/// The emulation uses the returned frontend, which sends its frames to
+/// the `WindowThread`.  If the window falls behind, frames are dropped
+/// instead of stalling the emulation.  Audio samples go through their own
+/// queue and are never dropped, as gaps in the sound are much more
+/// noticeable than a skipped frame.  `shows_sgb_frames` tells whether
+/// the window shows Super Game Boy frames, see `IO::refresh_sgb`.
+pub fn split(shows_sgb_frames: bool) -> (ThreadedFrontend, WindowThread) {
+    let (output, frames) = sync_channel(QUEUED_FRAMES);
+    let (audio, samples) = channel();
+    let input = Arc::new(Mutex::new(Input::default()));
+    let frontend = ThreadedFrontend{
+        output,
+        audio,
+        input: input.clone(),
+        shows_sgb_frames,
+    };
+    (frontend, WindowThread{frames, samples, input})
+}
+
+/// Frontend of an emulation thread that talks to a `WindowThread`
+pub struct ThreadedFrontend {
+    output: SyncSender<Output>,
+    audio: Sender<Vec<i16>>,
+    input: Arc<Mutex<Input>>,
+    shows_sgb_frames: bool,
+}
+
+impl ThreadedFrontend {
+    fn send(&self, output: Output) {
+        // Drop the output if the window is busy or has been closed.
+        if let Err(TrySendError::Disconnected(_))
+                = self.output.try_send(output) {
+            self.input.lock().unwrap().esc_pressed = true;
+        }
+    }
+}
+
+impl IO for ThreadedFrontend {
+    fn refresh(&mut self, pixels: &[u8]) {
+        self.send(Output::Screen(pixels.to_vec()));
+    }
+
+    fn refresh_sgb(&mut self, frame: &[u32]) -> bool {
+        if self.shows_sgb_frames {
+            self.send(Output::SgbScreen(frame.to_vec()));
+        }
+        self.shows_sgb_frames
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        self.input.lock().unwrap().esc_pressed
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        self.input.lock().unwrap().keys
+    }
+
+    /// The window thread passes on the buttons after every frame that
+    /// it shows, which is independent of the emulated frames.
+    fn poll_key_presses(&self) -> Option<u8> {
+        Some(self.get_key_presses())
+    }
+
+    fn get_tilt(&self) -> (f32, f32) {
+        self.input.lock().unwrap().tilt
+    }
+
+    fn get_hotkeys(&self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.input.lock().unwrap().hotkeys)
+    }
+
+    fn queue_audio(&mut self, samples: &[i16]) {
+        if self.audio.send(samples.to_vec()).is_err() {
+            self.input.lock().unwrap().esc_pressed = true;
+        }
+    }
+
+    fn show_vram(&mut self, views: &VramViews) {
+        self.send(Output::Vram(Box::new(views.clone())));
+    }
+}
+
+/// Shows the frames of an emulation thread in a window and passes the
+/// window's input back
+pub struct WindowThread {
+    frames: Receiver<Output>,
+    samples: Receiver<Vec<i16>>,
+    input: Arc<Mutex<Input>>,
+}
+
+impl WindowThread {
+    /// Show frames in the window until the emulation thread drops its
+    /// `ThreadedFrontend`
+    pub fn run(self, window: &mut impl IO) {
+        loop {
+            let output = self.frames.recv_timeout(POLL_INTERVAL);
+            for samples in self.samples.try_iter() {
+                window.queue_audio(&samples);
+            }
+            match output {
+                Ok(Output::Screen(pixels)) => window.refresh(&pixels),
+                Ok(Output::SgbScreen(frame)) => {
+                    window.refresh_sgb(&frame);
+                }
+                Ok(Output::Vram(views)) => window.show_vram(&views),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let mut input = self.input.lock().unwrap();
+            input.esc_pressed |= window.is_esc_pressed();
+            input.keys = window.get_key_presses();
+            input.tilt = window.get_tilt();
+            input.hotkeys.extend(window.get_hotkeys());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Presses Esc after showing the given number of frames
+    #[derive(Default)]
+    struct CountingWindow {
+        frames: usize,
+        esc_after: usize,
+        samples: Vec<i16>,
+    }
+
+    impl IO for CountingWindow {
+        fn refresh(&mut self, _pixels: &[u8]) {
+            self.frames += 1;
+        }
+
+        fn queue_audio(&mut self, samples: &[i16]) {
+            self.samples.extend_from_slice(samples);
+        }
+
+        fn is_esc_pressed(&self) -> bool {
+            self.frames >= self.esc_after
+        }
+
+        fn get_key_presses(&self) -> u8 {
+            0x10
+        }
+
+        fn get_hotkeys(&self) -> Vec<Hotkey> {
+            vec![Hotkey::Pause]
+        }
+    }
+
+    #[test]
+    fn emulation_thread_gets_the_window_input() {
+        let (mut frontend, window_thread) = split(false);
+        let emulation = thread::spawn(move || {
+            let mut frames = 0;
+            while !frontend.is_esc_pressed() {
+                frontend.refresh(&[0; 4]);
+                frames += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            (frames, frontend.get_key_presses(), frontend.get_hotkeys())
+        });
+        let mut window = CountingWindow{esc_after: 3, ..Default::default()};
+        window_thread.run(&mut window);
+        let (frames, keys, hotkeys) = emulation.join().unwrap();
+        assert!(frames >= 3);
+        assert!(window.frames >= 3);
+        assert_eq!(keys, 0x10);
+        assert!(hotkeys.contains(&Hotkey::Pause));
+    }
+
+    #[test]
+    fn audio_is_not_dropped_with_frames() {
+        let (mut frontend, window_thread) = split(false);
+        let emulation = thread::spawn(move || {
+            // Much more output than the window can keep up with
+            for i in 0..1000 {
+                frontend.refresh(&[0; 4]);
+                frontend.queue_audio(&[i, -i]);
+            }
+        });
+        let mut window = CountingWindow{
+            esc_after: usize::MAX,
+            ..Default::default()
+        };
+        window_thread.run(&mut window);
+        emulation.join().unwrap();
+        let expected: Vec<i16> = (0..1000).flat_map(|i| [i, -i]).collect();
+        assert_eq!(window.samples, expected);
+    }
+}
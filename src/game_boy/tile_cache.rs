@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use super::memory::{LcdControl, MemoryBus};
+
+const NUM_TILES: usize = 384;
+const TILE_MAP_ENTRIES: usize = 32 * 32;
+/// Width and height of the background drawn from a tile map
+pub const MAP_SIZE: usize = 256;
+
+/// VRAM writes since the tile cache was last updated
+pub struct VramChanges {
+    tiles: Vec<bool>,
+    /// Entries of the tile maps at 0x9800 and 0x9C00
+    map_entries: Vec<bool>,
+}
+
+impl VramChanges {
+    /// No VRAM writes
+    pub fn none() -> Self {
+        Self{
+            tiles: vec![false; NUM_TILES],
+            map_entries: vec![false; 2 * TILE_MAP_ENTRIES],
+        }
+    }
+
+    /// All of VRAM has changed, as at power on
+    pub fn all() -> Self {
+        Self{
+            tiles: vec![true; NUM_TILES],
+            map_entries: vec![true; 2 * TILE_MAP_ENTRIES],
+        }
+    }
+
+    pub fn mark(&mut self, address: u16) {
+        match address {
+            0x8000..=0x97FF => {
+                self.tiles[(address as usize - 0x8000) / 16] = true;
+            }
+            0x9800..=0x9FFF => {
+                self.map_entries[address as usize - 0x9800] = true;
+            }
+            _ => unreachable!("{:0>4X} is not in VRAM.", address),
+        }
+    }
+}
+
+/// Decoded tiles and both tile maps drawn into a background of 256x256
+/// color indices
+///
+/// Only the tiles and tile map entries that have been written since the
+/// last update are decoded again.
+pub struct TileCache {
+    /// Color indices of the 8x8 pixels of each tile, line by line
+    tiles: Vec<[u8; 64]>,
+    /// Backgrounds drawn from the tile maps at 0x9800 and 0x9C00
+    maps: [Vec<u8>; 2],
+    /// Address of tile 0 that the tile maps have been drawn with, which
+    /// LCDC switches between 0x8000 and 0x9000
+    tile0_address: u16,
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        Self{
+            tiles: vec![[0; 64]; NUM_TILES],
+            maps: [vec![0; MAP_SIZE * MAP_SIZE], vec![0; MAP_SIZE * MAP_SIZE]],
+            tile0_address: 0,
+        }
+    }
+}
+
+impl TileCache {
+    /// Decode the tiles and tile map entries that have changed
+    pub fn update(&mut self, memory: &mut MemoryBus) {
+        let changes = memory.take_vram_changes();
+        for (tile, _) in changes.tiles.iter().enumerate()
+                                .filter(|(_, &changed)| changed) {
+            let address = 0x8000 + 16 * tile as u16;
+            for row in 0..8 {
+                let data = memory.ppu_read16(address + 2 * row as u16);
+                for column in 0..8 {
+                    let bit = 7 - column;
+                    self.tiles[tile][8 * row + column]
+                        = (((data >> (bit + 7)) & 0b10)
+                           | ((data >> bit) & 1)) as u8;
+                }
+            }
+        }
+        let lcdc = memory.lcdc();
+        let tile0_address = lcdc.get_bg_or_window_tile_address(0);
+        let addressing_changed = tile0_address != self.tile0_address;
+        self.tile0_address = tile0_address;
+        for (map, pixels) in self.maps.iter_mut().enumerate() {
+            let map_start = 0x9800 + (map * TILE_MAP_ENTRIES) as u16;
+            for entry in 0..TILE_MAP_ENTRIES {
+                let tile = tile_number(lcdc, memory.ppu_read8(
+                                           map_start + entry as u16));
+                if !addressing_changed
+                   && !changes.map_entries[map * TILE_MAP_ENTRIES + entry]
+                   && !changes.tiles[tile] {
+                    continue;
+                }
+                let top_left = (entry / 32) * 8 * MAP_SIZE + (entry % 32) * 8;
+                for row in 0..8 {
+                    let start = top_left + row * MAP_SIZE;
+                    pixels[start..start + 8]
+                        .copy_from_slice(&self.tiles[tile][8 * row..8 * row + 8]);
+                }
+            }
+        }
+    }
+
+    /// Color indices of the background drawn from the tile map at the
+    /// given address
+    pub fn map(&self, tilemap_start: u16) -> &[u8] {
+        match tilemap_start {
+            0x9800 => &self.maps[0],
+            _ => &self.maps[1],
+        }
+    }
+
+    /// Color indices of the given line of an object tile
+    pub fn obj_tile_line(&self, tile: u8, in_tile_y: u8) -> &[u8] {
+        let tile = &self.tiles[tile as usize];
+        &tile[8 * in_tile_y as usize..8 * in_tile_y as usize + 8]
+    }
+}
+
+/// Number of the tile that a tile map entry refers to
+fn tile_number(lcdc: LcdControl, entry: u8) -> usize {
+    (lcdc.get_bg_or_window_tile_address(entry) as usize - 0x8000) / 16
+}
@@ -2,6 +2,9 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
     clock: u16,
     timer_counter: u16,
@@ -31,6 +34,7 @@ impl Default for Timer {
 }
 
 impl Timer {
+    /// Advance the timer by the given number of cycles
     pub fn step(&mut self, cycles: usize) -> bool {
         if self.stopped {
             return false;
@@ -77,19 +81,35 @@ impl Timer {
         interrupt
     }
 
+    /// Enter STOP mode
+    ///
+    /// STOP resets the divider, which can increment TIMA like a write to
+    /// DIV does, and pauses divider and timer until `start_clock` is
+    /// called.  Return true if the timer interrupt should be requested.
     pub fn stop_clock(&mut self) -> bool {
+        let interrupt = self.reset_divider();
         self.stopped = true;
-        self.reset_divider()
+        interrupt
     }
 
+    /// Leave STOP mode
     pub fn start_clock(&mut self) {
         self.stopped = false;
     }
 
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
     pub fn get_divider(&self) -> u8 {
         (self.clock >> 8) as u8
     }
 
+    /// Set the internal 16 bit counter whose upper 8 bits form DIV
+    pub fn set_divider_clock(&mut self, clock: u16) {
+        self.clock = clock;
+    }
+
     pub fn reset_divider(&mut self) -> bool {
         self.clock = 0;
         self.timer_counter = 0;
@@ -140,3 +160,69 @@ impl Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A timer with TAC = 0x05, i.e. enabled and incrementing TIMA
+    /// every 16 cycles
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::default();
+        timer.set_control(0x05);
+        timer
+    }
+
+    #[test]
+    fn stop_resets_and_pauses_divider() {
+        let mut timer = Timer::default();
+        timer.step(0x1234);
+        assert_eq!(timer.get_divider(), 0x12);
+        timer.stop_clock();
+        assert!(timer.is_stopped());
+        assert_eq!(timer.get_divider(), 0);
+        timer.step(0x1000);
+        assert_eq!(timer.get_divider(), 0);
+        timer.start_clock();
+        timer.step(0x300);
+        assert_eq!(timer.get_divider(), 0x03);
+    }
+
+    #[test]
+    fn tima_is_paused_during_stop() {
+        let mut timer = fast_timer();
+        timer.step(16 * 3);
+        assert_eq!(timer.get_timer(), 3);
+        timer.stop_clock();
+        timer.step(16 * 10);
+        assert_eq!(timer.get_timer(), 3);
+        timer.start_clock();
+        timer.step(16 * 2);
+        assert_eq!(timer.get_timer(), 5);
+    }
+
+    #[test]
+    fn stop_increments_tima_on_falling_edge() {
+        let mut timer = fast_timer();
+        // Set bit 3 of the divider, which clocks TIMA at TAC = 0x05.
+        timer.step(8);
+        assert_eq!(timer.get_timer(), 0);
+        timer.stop_clock();
+        assert_eq!(timer.get_timer(), 1);
+    }
+
+    #[test]
+    fn stop_can_overflow_tima() {
+        let mut timer = fast_timer();
+        timer.set_modulo(0xAB);
+        timer.set_timer(0xFF);
+        timer.step(8);
+        timer.stop_clock();
+        assert_eq!(timer.get_timer(), 0x00);
+        // The reload from TMA is delayed by one M-cycle, which
+        // passes even though the clock is stopped.
+        timer.start_clock();
+        assert!(timer.step(4));
+        assert_eq!(timer.get_timer(), 0xAB);
+    }
+}
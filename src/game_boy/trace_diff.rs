@@ -0,0 +1,236 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+
+use super::cpu::CpuState;
+
+/// Registers that a reference trace can give
+const REGISTERS: [&str; 14] = [
+    "A", "F", "B", "C", "D", "E", "H", "L", "AF", "BC", "DE", "HL", "SP", "PC",
+];
+
+/// Matching lines shown before a divergence
+const CONTEXT_LINES: usize = 5;
+
+/// Values of the registers named in a line of a trace
+///
+/// Registers are written as `NAME:VALUE` or `NAME=VALUE` with a hex
+/// value that may start with `$` or `0x`, like `A:01 F:B0` in Game Boy
+/// Doctor logs or `AF = $01B0` in SameBoy's and BGB's notation.  Other
+/// fields like `PCMEM` and everything after a `;` are ignored.
+pub fn parse_registers(line: &str) -> Vec<(&'static str, u16)> {
+    let mut registers = Vec::new();
+    let mut rest = line.split(';').next().unwrap();
+    while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic()) {
+        rest = &rest[start..];
+        let name_end = rest.find(|c: char| !c.is_ascii_alphanumeric())
+                           .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        let value = match rest.strip_prefix(':')
+                              .or_else(|| rest.strip_prefix('=')) {
+            Some(value) => value.trim_start(),
+            None => continue,
+        };
+        let value = value.strip_prefix('$')
+                         .or_else(|| value.strip_prefix("0x"))
+                         .unwrap_or(value);
+        let digits_end = value.find(|c: char| !c.is_ascii_hexdigit())
+                              .unwrap_or(value.len());
+        rest = &value[digits_end..];
+        let register = REGISTERS.iter()
+                                .find(|register| {
+                                    register.eq_ignore_ascii_case(name)
+                                });
+        if let (Some(register), Ok(value))
+                = (register, u16::from_str_radix(&value[..digits_end], 16)) {
+            registers.push((*register, value));
+        }
+    }
+    registers
+}
+
+fn register_value(state: &CpuState, register: &str) -> u16 {
+    let pair = |high: u8, low: u8| u16::from_be_bytes([high, low]);
+    match register {
+        "A" => state.a as u16,
+        "F" => state.f as u16,
+        "B" => state.b as u16,
+        "C" => state.c as u16,
+        "D" => state.d as u16,
+        "E" => state.e as u16,
+        "H" => state.h as u16,
+        "L" => state.l as u16,
+        "AF" => pair(state.a, state.f),
+        "BC" => pair(state.b, state.c),
+        "DE" => pair(state.d, state.e),
+        "HL" => pair(state.h, state.l),
+        "SP" => state.sp,
+        "PC" => state.pc,
+        _ => unreachable!("unknown register {}", register),
+    }
+}
+
+/// The first instruction at which the emulation differs from the
+/// reference trace
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Line of the reference trace, counted from 1
+    pub line_number: usize,
+    pub expected: String,
+    pub actual: String,
+    /// Registers that differ, with the expected and the actual value
+    pub registers: Vec<(&'static str, u16, u16)>,
+    /// Trace of the instructions before, which matched the reference
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Trace diverges from line {} of the reference:",
+                 self.line_number)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "- {}", self.expected)?;
+        writeln!(f, "+ {}", self.actual)?;
+        let registers: Vec<String> = self.registers
+            .iter()
+            .map(|&(register, expected, actual)| {
+                let width = if register.len() == 1 { 2 } else { 4 };
+                format!("{} is {:0>3$X} instead of {:0>3$X}", register, actual,
+                        expected, width)
+            })
+            .collect();
+        write!(f, "{}", registers.join(", "))
+    }
+}
+
+pub enum Comparison {
+    Matched,
+    Diverged(Divergence),
+    /// The reference trace has no more lines
+    Finished,
+}
+
+/// Compares the registers before each instruction with a reference
+/// trace of another emulator, see `parse_registers` for its format
+///
+/// Lines without registers are skipped.  Only the registers that the
+/// reference gives are compared.
+pub struct TraceDiff {
+    reference: Box<dyn BufRead + Send>,
+    line_number: usize,
+    /// Trace of the last instructions that matched the reference
+    context: VecDeque<String>,
+}
+
+impl TraceDiff {
+    pub fn new(reference: Box<dyn BufRead + Send>) -> Self {
+        Self{
+            reference,
+            line_number: 0,
+            context: VecDeque::with_capacity(CONTEXT_LINES),
+        }
+    }
+
+    /// Number of reference lines read so far
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Compare the registers before an instruction, as written in
+    /// `trace_line`, with the next line of the reference
+    pub fn check(&mut self, state: &CpuState, trace_line: &str)
+            -> io::Result<Comparison> {
+        let (line, expected) = loop {
+            let mut line = String::new();
+            if self.reference.read_line(&mut line)? == 0 {
+                return Ok(Comparison::Finished);
+            }
+            self.line_number += 1;
+            let registers = parse_registers(&line);
+            if !registers.is_empty() {
+                break (line, registers);
+            }
+        };
+        let registers: Vec<_> = expected
+            .into_iter()
+            .map(|(register, value)| {
+                (register, value, register_value(state, register))
+            })
+            .filter(|&(_, expected, actual)| expected != actual)
+            .collect();
+        if !registers.is_empty() {
+            return Ok(Comparison::Diverged(Divergence{
+                line_number: self.line_number,
+                expected: line.trim_end().to_string(),
+                actual: trace_line.to_string(),
+                registers,
+                context: self.context.drain(..).collect(),
+            }));
+        }
+        if self.context.len() == CONTEXT_LINES {
+            self.context.pop_front();
+        }
+        self.context.push_back(trace_line.to_string());
+        Ok(Comparison::Matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_are_parsed_in_several_notations() {
+        assert_eq!(parse_registers(
+                       "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE \
+                        PC:0100 PCMEM:00,C3,13,02 ; Start"),
+                   [("A", 0x01), ("F", 0xB0), ("B", 0x00), ("C", 0x13),
+                    ("D", 0x00), ("E", 0xD8), ("H", 0x01), ("L", 0x4D),
+                    ("SP", 0xFFFE), ("PC", 0x0100)]);
+        assert_eq!(parse_registers("af = $01B0, bc=0x0013 pc: 0150"),
+                   [("AF", 0x01B0), ("BC", 0x0013), ("PC", 0x0150)]);
+        assert_eq!(parse_registers("Frame 3"), []);
+    }
+
+    #[test]
+    fn first_differing_instruction_is_reported() {
+        let reference = "\
+            header\n\
+            A:01 PC:0100\n\
+            A:01 PC:0101\n\
+            AF:02B0 PC:0104\n\
+            A:03 PC:0106\n";
+        let mut diff = TraceDiff::new(Box::new(reference.as_bytes()));
+        let mut state = CpuState{a: 1, f: 0xB0, pc: 0x100,
+                                 ..CpuState::default()};
+        assert!(matches!(diff.check(&state, "first").unwrap(),
+                         Comparison::Matched));
+        state.pc = 0x101;
+        assert!(matches!(diff.check(&state, "second").unwrap(),
+                         Comparison::Matched));
+        state.pc = 0x104;
+        let divergence = match diff.check(&state, "third").unwrap() {
+            Comparison::Diverged(divergence) => divergence,
+            _ => panic!("trace didn't diverge"),
+        };
+        assert_eq!(divergence.line_number, 4);
+        assert_eq!(divergence.to_string(), "\
+            Trace diverges from line 4 of the reference:\n  \
+              first\n  \
+              second\n\
+            - AF:02B0 PC:0104\n\
+            + third\n\
+            AF is 01B0 instead of 02B0");
+        assert!(matches!(diff.check(&state, "fourth").unwrap(),
+                         Comparison::Diverged(_)));
+        assert!(matches!(diff.check(&state, "fifth").unwrap(),
+                         Comparison::Finished));
+    }
+}
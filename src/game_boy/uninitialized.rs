@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+
+const WRAM_START: u16 = 0xC000;
+const WRAM_SIZE: usize = 0x2000;
+const HRAM_START: u16 = 0xFF80;
+const HRAM_SIZE: usize = 0x7F;
+
+/// A read of a RAM byte that has never been written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UninitializedRead {
+    pub address: u16,
+    /// Address of the instruction that read the byte
+    pub pc: u16,
+    /// Number of the frame during which the byte was read
+    pub frame: u64,
+}
+
+/// Tracks reads of never-written bytes in WRAM and HRAM
+///
+/// On hardware, RAM contains random values after power-on, so code
+/// that reads RAM before initializing it might behave differently than
+/// in an emulator that starts with zeroed memory.
+///
+/// Echo RAM accesses have to be remapped to WRAM by the caller.
+/// Each address is only reported once.
+pub struct UninitializedReadTracker {
+    written: Vec<bool>,
+    reported: Vec<bool>,
+    /// Addresses of uninitialized reads of the current instruction
+    pending: RefCell<Vec<u16>>,
+    reads: Vec<UninitializedRead>,
+}
+
+impl Default for UninitializedReadTracker {
+    fn default() -> Self {
+        Self{
+            written: vec![false; WRAM_SIZE + HRAM_SIZE],
+            reported: vec![false; WRAM_SIZE + HRAM_SIZE],
+            pending: RefCell::new(Vec::new()),
+            reads: Vec::new(),
+        }
+    }
+}
+
+impl UninitializedReadTracker {
+    fn index(address: u16) -> Option<usize> {
+        match address {
+            0xC000..=0xDFFF => Some((address - WRAM_START) as usize),
+            0xFF80..=0xFFFE => Some(WRAM_SIZE
+                                    + (address - HRAM_START) as usize),
+            _ => None,
+        }
+    }
+
+    pub fn record_write(&mut self, address: u16) {
+        if let Some(index) = Self::index(address) {
+            self.written[index] = true;
+        }
+    }
+
+    pub fn check_read(&self, address: u16) {
+        if let Some(index) = Self::index(address) {
+            if !self.written[index] && !self.reported[index] {
+                self.pending.borrow_mut().push(address);
+            }
+        }
+    }
+
+    /// Attribute uninitialized reads since the last call to an instruction
+    ///
+    /// Return the reads that have been found.
+    pub fn report(&mut self, pc: u16, frame: u64) -> &[UninitializedRead] {
+        let first_new = self.reads.len();
+        for address in self.pending.get_mut().drain(..) {
+            let index = Self::index(address).unwrap();
+            if self.reported[index] {
+                continue;
+            }
+            self.reported[index] = true;
+            self.reads.push(UninitializedRead{address, pc, frame});
+        }
+        &self.reads[first_new..]
+    }
+
+    /// All uninitialized reads found so far
+    pub fn reads(&self) -> &[UninitializedRead] {
+        &self.reads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_written_bytes_are_reported_once() {
+        let mut tracker = UninitializedReadTracker::default();
+        tracker.record_write(0xC000);
+        tracker.check_read(0xC000);
+        tracker.check_read(0xDFFF);
+        tracker.check_read(0xFF80);
+        // Neither WRAM nor HRAM
+        tracker.check_read(0x8000);
+        tracker.check_read(0xFFFF);
+        let reads = tracker.report(0x0150, 3);
+        assert_eq!(reads, [
+            UninitializedRead{address: 0xDFFF, pc: 0x0150, frame: 3},
+            UninitializedRead{address: 0xFF80, pc: 0x0150, frame: 3},
+        ]);
+        // Reading the same byte twice in an instruction counts once,
+        // and reading it again later not at all.
+        tracker.check_read(0xFFFE);
+        tracker.check_read(0xFFFE);
+        tracker.check_read(0xDFFF);
+        assert_eq!(tracker.report(0x0153, 4), [
+            UninitializedRead{address: 0xFFFE, pc: 0x0153, frame: 4},
+        ]);
+        assert!(tracker.report(0x0154, 4).is_empty());
+        assert_eq!(tracker.reads().len(), 3);
+    }
+}
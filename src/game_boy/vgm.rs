@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Write};
+
+use super::CPU_CYCLES_PER_SECOND;
+
+/// Sample rate that all wait commands in a VGM file refer to
+const VGM_SAMPLE_RATE: u64 = 44_100;
+
+/// Size of the VGM 1.61 header, the music data starts right after it
+const HEADER_SIZE: usize = 0x100;
+
+/// Records writes to the sound registers 0xFF10–0xFF3F
+///
+/// The recorded writes can be exported as a VGM file, which can be
+/// played back by chiptune players that emulate the Game Boy's APU.
+/// https://vgmrips.net/wiki/VGM_Specification
+pub struct VgmRecorder {
+    start_cycle: u64,
+    end_cycle: u64,
+    /// Register writes as (CPU cycle, register address, value)
+    writes: Vec<(u64, u16, u8)>,
+}
+
+impl VgmRecorder {
+    /// Start a recording at the given CPU cycle
+    pub fn new(start_cycle: u64) -> Self {
+        Self{
+            start_cycle,
+            end_cycle: start_cycle,
+            writes: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, cycle: u64, address: u16, value: u8) {
+        debug_assert!((0xFF10..=0xFF3F).contains(&address));
+        self.writes.push((cycle, address, value));
+        self.end_cycle = cycle;
+    }
+
+    /// End the recording at the given CPU cycle
+    pub fn finish(&mut self, cycle: u64) {
+        self.end_cycle = cycle;
+    }
+
+    pub fn num_writes(&self) -> usize {
+        self.writes.len()
+    }
+
+    fn cycle_to_sample(&self, cycle: u64) -> u64 {
+        (cycle - self.start_cycle) * VGM_SAMPLE_RATE
+            / CPU_CYCLES_PER_SECOND as u64
+    }
+
+    pub fn write_vgm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut data = Vec::with_capacity(3 * self.writes.len() + 1);
+        let mut sample = 0;
+        for &(cycle, address, value) in &self.writes {
+            let write_sample = self.cycle_to_sample(cycle);
+            encode_wait(&mut data, write_sample - sample);
+            sample = write_sample;
+            // Game Boy DMG write: register offset from 0xFF10, value
+            data.extend_from_slice(&[0xB3, (address - 0xFF10) as u8, value]);
+        }
+        let total_samples = self.cycle_to_sample(self.end_cycle);
+        encode_wait(&mut data, total_samples - sample);
+        data.push(0x66);  // end of sound data
+
+        let mut header = [0u8; HEADER_SIZE];
+        let mut put_u32 = |offset: usize, value: u32| {
+            header[offset..offset+4].copy_from_slice(&value.to_le_bytes());
+        };
+        put_u32(0x00, u32::from_le_bytes(*b"Vgm "));
+        put_u32(0x04, (HEADER_SIZE + data.len() - 0x04) as u32);  // EOF offset
+        put_u32(0x08, 0x161);  // version 1.61
+        put_u32(0x18, total_samples as u32);
+        put_u32(0x34, (HEADER_SIZE - 0x34) as u32);  // VGM data offset
+        put_u32(0x80, CPU_CYCLES_PER_SECOND as u32);  // GB DMG clock
+        writer.write_all(&header)?;
+        writer.write_all(&data)?;
+        writer.flush()
+    }
+}
+
+/// Append wait commands for the given number of samples.
+fn encode_wait(data: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        match samples {
+            1..=16 => {
+                data.push(0x70 + (samples - 1) as u8);
+                samples = 0;
+            }
+            735 => {  // one frame at 60 Hz
+                data.push(0x62);
+                samples = 0;
+            }
+            882 => {  // one frame at 50 Hz
+                data.push(0x63);
+                samples = 0;
+            }
+            _ => {
+                let wait = samples.min(0xFFFF);
+                data.push(0x61);
+                data.extend_from_slice(&(wait as u16).to_le_bytes());
+                samples -= wait;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(vgm: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(vgm[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn vgm_file_has_header_waits_and_end_marker() {
+        let start = 1000;
+        let mut recorder = VgmRecorder::new(start);
+        recorder.record(start, 0xFF26, 0x80);
+        // 10 samples later
+        recorder.record(start + 952, 0xFF12, 0xF3);
+        // one second after the start
+        recorder.record(start + 4_194_304, 0xFF14, 0x87);
+        // and one frame at 60 Hz later
+        recorder.finish(start + 4_194_304 + 69_906);
+        assert_eq!(recorder.num_writes(), 3);
+        let mut vgm = Vec::new();
+        recorder.write_vgm(&mut vgm).unwrap();
+
+        assert_eq!(&vgm[..4], b"Vgm ");
+        assert_eq!(read_u32(&vgm, 0x04) as usize, vgm.len() - 4);
+        assert_eq!(read_u32(&vgm, 0x08), 0x161);
+        assert_eq!(read_u32(&vgm, 0x18), 44_100 + 735);
+        assert_eq!(0x34 + read_u32(&vgm, 0x34) as usize, HEADER_SIZE);
+        assert_eq!(read_u32(&vgm, 0x80), 4_194_304);
+        assert_eq!(vgm[HEADER_SIZE..], [
+            0xB3, 0x16, 0x80,
+            0x79,              // wait 10 samples
+            0xB3, 0x02, 0xF3,
+            0x61, 0x3A, 0xAC,  // wait 44090 samples
+            0xB3, 0x04, 0x87,
+            0x62,              // wait 735 samples
+            0x66,
+        ]);
+    }
+
+    #[test]
+    fn long_waits_are_split() {
+        let mut data = Vec::new();
+        encode_wait(&mut data, 70_000);
+        assert_eq!(data, [0x61, 0xFF, 0xFF, 0x61, 0x71, 0x11]);
+        data.clear();
+        encode_wait(&mut data, 882);
+        encode_wait(&mut data, 0);
+        encode_wait(&mut data, 1);
+        assert_eq!(data, [0x63, 0x70]);
+    }
+}
@@ -0,0 +1,303 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::graphics_data::MonochromePalette;
+use super::memory::MemoryBus;
+use super::ppu::Sprite;
+
+/// Pixel value that marks e.g. the visible part of a BG map
+///
+/// All other pixels are encoded like those passed to `IO::refresh`.
+pub const MARKER: u8 = 0x10;
+
+/// Color in which `MARKER` pixels are shown
+pub const MARKER_COLOR: u32 = 0xFF0000;
+
+const TILES_PER_ROW: usize = 16;
+const NUM_TILES: usize = 384;
+const NUM_OBJECTS: usize = 40;
+const OBJECTS_PER_ROW: usize = 8;
+
+/// An image of the graphics data in VRAM or OAM
+#[derive(Clone)]
+pub struct View {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl View {
+    fn new(width: usize, height: usize) -> Self {
+        Self{
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, pixel: u8) {
+        self.pixels[y * self.width + x] = pixel;
+    }
+
+    /// Write the view as an indexed PNG image
+    ///
+    /// The PNG palette holds the colors of the given lookup table at the
+    /// indices of the pixel values, followed by the marker color.
+    pub fn write_png(&self, writer: impl Write, colors: &[u32; 12])
+            -> io::Result<()> {
+        let mut palette = vec![0; 3 * (MARKER as usize + 1)];
+        for (index, color) in colors.iter().enumerate() {
+            palette[3 * index..3 * index + 3]
+                .copy_from_slice(&color.to_be_bytes()[1..]);
+        }
+        palette[3 * MARKER as usize..]
+            .copy_from_slice(&MARKER_COLOR.to_be_bytes()[1..]);
+        let mut encoder = png::Encoder::new(writer, self.width as u32,
+                                            self.height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(palette);
+        let mut writer = encoder.write_header().map_err(invalid_data)?;
+        writer.write_image_data(&self.pixels).map_err(invalid_data)?;
+        writer.finish().map_err(invalid_data)
+    }
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static)
+        -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Views of what the PPU draws from, shown while the game runs
+#[derive(Clone)]
+pub struct VramViews {
+    /// All tiles, drawn with BGP, OBP0 and OBP1 side by side
+    pub tiles: View,
+    /// The BG maps at 0x9800 and 0x9C00 one above the other, with the
+    /// part visible on the screen outlined in the one used for the BG
+    pub bg_maps: View,
+    /// The 40 objects in OAM in rows of 8, each 8x16 pixels
+    pub objects: View,
+}
+
+impl VramViews {
+    pub fn render(memory: &MemoryBus) -> Self {
+        Self{
+            tiles: tile_data(memory),
+            bg_maps: bg_maps(memory),
+            objects: objects(memory),
+        }
+    }
+
+    /// The views with the names used in the files of `dump_path`
+    pub fn named(&self) -> [(&'static str, &View); 3] {
+        [("tiles", &self.tiles), ("bg", &self.bg_maps), ("oam", &self.objects)]
+    }
+}
+
+/// File of a view dumped in the given frame, next to the given ROM file
+pub fn dump_path(rom_file: &Path, frame: u64, name: &str) -> PathBuf {
+    rom_file.with_extension(format!("{}.{}.png", frame, name))
+}
+
+/// Color indices of a line of the tile at the given address
+fn tile_line(memory: &MemoryBus, tile_address: u16, line: u16) -> [u8; 8] {
+    let data = memory.ppu_read16(tile_address + 2 * line);
+    let mut indices = [0; 8];
+    for (i, index) in indices.iter_mut().enumerate() {
+        *index = (((data >> (14 - i)) & 0b10) | ((data >> (7 - i)) & 1)) as u8;
+    }
+    indices
+}
+
+pub fn tile_data(memory: &MemoryBus) -> View {
+    let palettes = [memory.bg_palette(), memory.obj_palette0(),
+                    memory.obj_palette1()];
+    let width = TILES_PER_ROW * 8;
+    let mut view = View::new(palettes.len() * width,
+                             NUM_TILES / TILES_PER_ROW * 8);
+    for tile in 0..NUM_TILES {
+        let x = tile % TILES_PER_ROW * 8;
+        let y = tile / TILES_PER_ROW * 8;
+        for line in 0..8 {
+            let indices = tile_line(memory, 0x8000 + 16 * tile as u16, line);
+            for (layer, palette) in palettes.iter().enumerate() {
+                for (i, &index) in indices.iter().enumerate() {
+                    let pixel = palette.color(index) | (layer as u8) << 2;
+                    view.set(layer * width + x + i, y + line as usize, pixel);
+                }
+            }
+        }
+    }
+    view
+}
+
+pub fn bg_maps(memory: &MemoryBus) -> View {
+    let lcdc = memory.lcdc();
+    let palette = memory.bg_palette();
+    let mut view = View::new(256, 512);
+    for (map, map_start) in [0x9800, 0x9C00].into_iter().enumerate() {
+        for tile in 0..32 * 32 {
+            let tile_index = memory.ppu_read8(map_start + tile as u16);
+            let tile_address = lcdc.get_bg_or_window_tile_address(tile_index);
+            let x = tile % 32 * 8;
+            let y = map * 256 + tile / 32 * 8;
+            for line in 0..8 {
+                let indices = tile_line(memory, tile_address, line);
+                for (i, &index) in indices.iter().enumerate() {
+                    view.set(x + i, y + line as usize, palette.color(index));
+                }
+            }
+        }
+    }
+    // Outline the screen, which wraps around at the edges of the map.
+    let map_y = if lcdc.bg_tilemap_start() == 0x9800 { 0 } else { 256 };
+    let (scx, scy) = (memory.scx() as usize, memory.scy() as usize);
+    let mut mark = |x: usize, y: usize| {
+        view.set((scx + x) % 256, map_y + (scy + y) % 256, MARKER);
+    };
+    for x in 0..160 {
+        mark(x, 0);
+        mark(x, 143);
+    }
+    for y in 0..144 {
+        mark(0, y);
+        mark(159, y);
+    }
+    view
+}
+
+pub fn oam_entries(memory: &MemoryBus) -> Vec<Sprite> {
+    (0..NUM_OBJECTS as u16).map(|i| {
+        let address = 0xFE00 + 4 * i;
+        Sprite::new(memory.ppu_read8(address),
+                    memory.ppu_read8(address + 1),
+                    memory.ppu_read8(address + 2),
+                    memory.ppu_read8(address + 3))
+    }).collect()
+}
+
+/// Describe an OAM entry with its screen position
+pub fn describe_object(index: usize, object: &Sprite) -> String {
+    let attributes = object.attribute_flags();
+    let mut description = format!(
+        "{:>2}: X {:>3} Y {:>3} tile {:0>2X} OBP{}",
+        index, object.x() as i16 - 8, object.y() as i16 - 16,
+        object.tile_index(), attributes.palette());
+    if attributes.x_flip() {
+        description.push_str(" x-flip");
+    }
+    if attributes.y_flip() {
+        description.push_str(" y-flip");
+    }
+    if attributes.bg_and_window_over_obj() {
+        description.push_str(" behind BG");
+    }
+    description
+}
+
+pub fn objects(memory: &MemoryBus) -> View {
+    let tall = memory.lcdc().obj_height() == 16;
+    let palettes = [memory.obj_palette0(), memory.obj_palette1()];
+    let mut view = View::new(OBJECTS_PER_ROW * 8,
+                             NUM_OBJECTS / OBJECTS_PER_ROW * 16);
+    for (i, object) in oam_entries(memory).iter().enumerate() {
+        let attributes = object.attribute_flags();
+        let palette: MonochromePalette = palettes[attributes.palette()];
+        let (tile, height) = if tall {
+            (object.tile_index() & 0xFE, 16)
+        } else {
+            (object.tile_index(), 8)
+        };
+        let x = i % OBJECTS_PER_ROW * 8;
+        let y = i / OBJECTS_PER_ROW * 16;
+        for line in 0..height {
+            let tile_line_index = if attributes.y_flip() {
+                height - 1 - line
+            } else {
+                line
+            };
+            let indices = tile_line(memory, 0x8000 + 16 * tile as u16,
+                                    tile_line_index);
+            let layer = 1 + attributes.palette() as u8;
+            for (column, &index) in indices.iter().enumerate() {
+                let column = if attributes.x_flip() {
+                    7 - column
+                } else {
+                    column
+                };
+                view.set(x + column, y + line as usize,
+                         palette.color(index) | layer << 2);
+            }
+        }
+    }
+    view
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::cartridge::Cartridge;
+
+    #[test]
+    fn views_show_tiles_maps_and_objects() {
+        let cartridge = Cartridge::from_rom(vec![0; 0x8000]).unwrap();
+        let mut memory = MemoryBus::new(cartridge, [0; 0x100].into());
+        memory.write8(0xFF40, 0x11);  // LCD off, tiles at 0x8000
+        memory.write8(0xFF47, 0xE4);
+        memory.write8(0xFF48, 0x1B);
+        memory.write8(0xFF49, 0x00);
+        // Tile 1 starts with a line of color index 1.
+        memory.write8(0x8010, 0xFF);
+        memory.write8(0x9800, 1);
+        memory.write8(0xFF42, 4);  // SCY
+        // Object 1 shows tile 1 flipped vertically with OBP0.
+        memory.write8(0xFE05, 8);
+        memory.write8(0xFE06, 1);
+        memory.write8(0xFE07, 0x40);
+
+        let views = VramViews::render(&memory);
+        assert_eq!(views.tiles.pixels[8], 1);
+        assert_eq!(views.tiles.pixels[128 + 8], 2 | 1 << 2);
+        assert_eq!(views.tiles.pixels[2 * 128 + 8], 2 << 2);
+        let bg = &views.bg_maps;
+        assert_eq!(bg.pixels[0], 1);
+        assert_eq!(bg.pixels[4 * 256 + 10], MARKER);
+        assert_eq!(bg.pixels[(4 + 143) * 256 + 159], MARKER);
+        assert_eq!(bg.pixels[5 * 256 + 10], 0);
+        let objects = &views.objects;
+        assert_eq!(objects.pixels[8], 3 | 1 << 2);
+        assert_eq!(objects.pixels[7 * 64 + 8], 2 | 1 << 2);
+        assert_eq!(describe_object(1, &oam_entries(&memory)[1]),
+                   " 1: X   0 Y -16 tile 01 OBP0 y-flip");
+    }
+
+    #[test]
+    fn views_are_written_as_indexed_png() {
+        let mut view = View::new(3, 1);
+        view.set(1, 0, 1 | 2 << 2);
+        view.set(2, 0, MARKER);
+        let mut colors = [0; 12];
+        colors[0] = 0xFFFFFF;
+        colors[9] = 0x123456;
+        let mut png = Vec::new();
+        view.write_png(&mut png, &colors).unwrap();
+
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let palette = reader.info().palette.as_ref().unwrap();
+        assert_eq!(palette.len(), 3 * 17);
+        assert_eq!(palette[..3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palette[27..30], [0x12, 0x34, 0x56]);
+        assert_eq!(palette[48..], [0xFF, 0x00, 0x00]);
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, [0, 9, MARKER]);
+        assert_eq!(dump_path(Path::new("roms/game.gb"), 42, "oam"),
+                   Path::new("roms/game.42.oam.png"));
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::boot_rom::Model;
+use super::cartridge::Cartridge;
+use super::colorization::ColorPalettes;
+use super::io::{Buttons, IO, HEIGHT, WIDTH};
+use super::GameBoy;
+
+/// Shades from lightest to darkest of the frames drawn for the browser
+const PALETTE: [u32; 4] = [0xE0F8D0, 0x88C070, 0x346856, 0x081820];
+
+/// A frontend that draws RGBA frames for a `WebGameBoy`
+///
+/// The page passes the buttons to `WebGameBoy::set_buttons`.
+struct Canvas {
+    colors: [u32; 12],
+    /// Last frame as RGBA bytes, as taken by a canvas' `ImageData`
+    frame: Rc<RefCell<Vec<u8>>>,
+}
+
+impl IO for Canvas {
+    fn refresh(&mut self, pixels: &[u8]) {
+        let mut frame = self.frame.borrow_mut();
+        for (rgba, &pixel) in frame.chunks_exact_mut(4).zip(pixels) {
+            let [_, r, g, b] = self.colors[pixel as usize].to_be_bytes();
+            rgba.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+
+    fn is_esc_pressed(&self) -> bool {
+        false
+    }
+
+    fn get_key_presses(&self) -> u8 {
+        0
+    }
+}
+
+/// A Game Boy for web pages, which draw its frames into a canvas
+///
+/// In JavaScript, the game is loaded from a `Uint8Array` with
+/// `loadRom`, and the page calls `runFrame` in `requestAnimationFrame`
+/// and shows the RGBA pixels that `frame` returns.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub struct WebGameBoy {
+    game_boy: Option<GameBoy<Canvas>>,
+    frame: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Default for WebGameBoy {
+    fn default() -> Self {
+        Self{
+            game_boy: None,
+            frame: Rc::new(RefCell::new(vec![0; 4 * WIDTH * HEIGHT])),
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+impl WebGameBoy {
+    /// Create a Game Boy without a cartridge, which shows a blank frame
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(constructor))]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a Game Boy with the given ROM, right at the cartridge's
+    /// entry point
+    ///
+    /// This fails, e.g. if the ROM's memory controller isn't supported.
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = loadRom))]
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), String> {
+        let cartridge = Cartridge::from_rom(rom).map_err(|e| e.to_string())?;
+        let canvas = Canvas{
+            colors: ColorPalettes::monochrome(PALETTE).lookup_table(),
+            frame: self.frame.clone(),
+        };
+        self.game_boy = Some(GameBoy::with_hle_boot(Model::DMG, cartridge,
+                                                    canvas));
+        Ok(())
+    }
+
+    /// Emulate a frame, unless no ROM has been loaded yet
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = runFrame))]
+    pub fn run_frame(&mut self) {
+        if let Some(game_boy) = &mut self.game_boy {
+            game_boy.run_frame();
+        }
+    }
+
+    /// Press the buttons whose bits are set, see `IO::get_key_presses`
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = setButtons))]
+    pub fn set_buttons(&mut self, buttons: u8) {
+        if let Some(game_boy) = &mut self.game_boy {
+            game_boy.set_buttons(Buttons::from_bits(buttons));
+        }
+    }
+
+    /// The last frame as `WIDTH` x `HEIGHT` RGBA pixels
+    pub fn frame(&self) -> Vec<u8> {
+        self.frame.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM that draws a solid black tile at the top left corner
+    fn tile_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // JP 0150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x169].copy_from_slice(&[
+            0xAF,              // XOR A
+            0xE0, 0x40,        // LDH (40),A (LCD off)
+            0x21, 0x10, 0x80,  // LD HL,8010 (tile 1)
+            0x3E, 0xFF,        // LD A,FF
+            0x0E, 0x10,        // LD C,16
+            0x22,              // LD (HL+),A
+            0x0D,              // DEC C
+            0x20, 0xFC,        // JR NZ,-4
+            0x3E, 0x01,        // LD A,1
+            0xEA, 0x00, 0x98,  // LD (9800),A
+            0x3E, 0x91,        // LD A,91
+            0xE0, 0x40,        // LDH (40),A (LCD on)
+            0x18, 0xFE,        // JR -2
+        ]);
+        rom
+    }
+
+    #[test]
+    fn loaded_rom_draws_frames() {
+        let mut web_game_boy = WebGameBoy::new();
+        web_game_boy.run_frame();
+        assert!(web_game_boy.frame().iter().all(|&byte| byte == 0));
+
+        web_game_boy.load_rom(tile_rom()).unwrap();
+        for _ in 0..3 {
+            web_game_boy.run_frame();
+        }
+        let frame = web_game_boy.frame();
+        assert_eq!(frame.len(), 4 * WIDTH * HEIGHT);
+        let pixel = |x: usize, y: usize| &frame[4 * (y*WIDTH + x)..][..4];
+        assert_eq!(pixel(0, 0), [0x08, 0x18, 0x20, 0xFF]);
+        assert_eq!(pixel(7, 7), [0x08, 0x18, 0x20, 0xFF]);
+        assert_eq!(pixel(8, 0), [0xE0, 0xF8, 0xD0, 0xFF]);
+
+        // Cartridges with an unsupported memory controller, here HuC3, fail.
+        let mut rom = tile_rom();
+        rom[0x147] = 0xFE;
+        assert!(web_game_boy.load_rom(rom).is_err());
+    }
+}
@@ -2,7 +2,12 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+#[cfg(feature = "window")]
 extern crate minifb;
 
 pub mod chip8;
+pub mod frame_pacer;
 pub mod game_boy;
+pub mod logging;
+pub mod settings;
+pub mod window_scale;
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::backtrace::Backtrace;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::panic;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Target of panic reports, which the default panic hook already
+/// prints to stderr
+const PANIC_TARGET: &str = "panic";
+
+/// Lines describing what was running, added to crash reports
+static CRASH_CONTEXT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Logger printing diagnostics to stderr and optionally to a log file
+///
+/// Messages up to `Level::Info` are printed to stderr as they are.  The
+/// log file additionally receives debug messages, and each line is
+/// prefixed with the time since startup, the level and the module.
+pub struct Logger {
+    start: Instant,
+    file: Option<Mutex<LineWriter<File>>>,
+}
+
+impl Logger {
+    /// Install the logger, writing to the given log file if any.
+    pub fn init(log_file: Option<&Path>) -> io::Result<()> {
+        let file = log_file.map(File::create)
+                           .transpose()?
+                           .map(|f| Mutex::new(LineWriter::new(f)));
+        let max_level = if file.is_some() {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        };
+        let logger = Self{
+            start: Instant::now(),
+            file,
+        };
+        log::set_boxed_logger(Box::new(logger))
+            .map_err(io::Error::other)?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || self.file.is_some()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= Level::Info && record.target() != PANIC_TARGET {
+            eprintln!("{}", record.args());
+        }
+        if let Some(file) = &self.file {
+            let elapsed = self.start.elapsed();
+            let mut file = file.lock().unwrap();
+            // Ignore errors, there's nowhere left to report them.
+            let _ = writeln!(file, "[{:>5}.{:03}] {:<5} {}: {}",
+                             elapsed.as_secs(), elapsed.subsec_millis(),
+                             record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Add a line identifying the running emulator to crash reports,
+/// e.g. the loaded ROM.
+pub fn add_crash_context(line: String) {
+    CRASH_CONTEXT.lock().unwrap().push(line);
+}
+
+/// Log panics together with the crash context and a backtrace
+///
+/// The report is only written to the log file, not to stderr.
+/// Afterwards, the previously installed panic hook is called, which
+/// by default prints the panic message to stderr.
+pub fn install_crash_logger() {
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = if let Some(s) = info.payload().downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = info.payload().downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        let location = info.location()
+                           .map(|l| format!("{}:{}", l.file(), l.line()))
+                           .unwrap_or_else(|| "unknown location".to_string());
+        let mut report = format!("Panic at {}: {}\n", location, message);
+        // Don't block forever if the panic happened while holding the lock.
+        if let Ok(context) = CRASH_CONTEXT.try_lock() {
+            for line in context.iter() {
+                report.push_str(line);
+                report.push('\n');
+            }
+        }
+        report.push_str(&format!("Backtrace:\n{}", Backtrace::force_capture()));
+        log::error!(target: PANIC_TARGET, "{}", report);
+        log::logger().flush();
+        previous_hook(info);
+    }));
+}
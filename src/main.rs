@@ -2,10 +2,14 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use clap::{crate_name, crate_version, Command};
+use std::path::Path;
+
+use clap::{crate_name, crate_version, Arg, Command};
 
 use emulato_rs::chip8;
 use emulato_rs::game_boy;
+use emulato_rs::logging::{self, Logger};
+use emulato_rs::settings::Settings;
 
 fn main() {
     let matches = Command::new(crate_name!())
@@ -13,21 +17,57 @@ fn main() {
         .version(crate_version!())
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("config")
+                .help("settings file to use instead of the default one")
+                .takes_value(true)
+                .long("config")
+        )
+        .arg(
+            Arg::new("log-file")
+                .help("write all diagnostics and crash reports to a file")
+                .takes_value(true)
+                .value_name("emulato-rs.log")
+                .long("log-file")
+        )
         .subcommand(chip8::commandline::chip_8_subcommand())
         .subcommand(game_boy::commandline::game_boy_subcommand())
         .get_matches();
+    let log_file = matches.value_of("log-file").map(Path::new);
+    if let Err(e) = Logger::init(log_file) {
+        eprintln!("Could not open log file: {}", e);
+        Logger::init(None).unwrap();
+    }
+    if log_file.is_some() {
+        logging::add_crash_context(format!("{} {}", crate_name!(),
+                                           crate_version!()));
+        let args: Vec<String> = std::env::args().collect();
+        logging::add_crash_context(format!("Command line: {:?}", args));
+        logging::install_crash_logger();
+    }
+    let settings = match matches.value_of("config") {
+        Some(path) => Settings::load(Path::new(path)),
+        None => Settings::load_from_default_path(),
+    };
+    let settings = settings.unwrap_or_else(|e| {
+        log::warn!("Could not load settings: {}", e);
+        log::warn!("Using default settings.");
+        Settings::default()
+    });
     match matches.subcommand() {
         Some(("chip8", matches)) => {
-            chip8::commandline::run_chip_8_from_subcommand(matches);
+            chip8::commandline::run_chip_8_from_subcommand(matches,
+                                                       &settings.chip8);
         }
         Some(("gameboy", matches)) => {
-            game_boy::commandline::run_game_boy_from_subcommand(matches);
+            game_boy::commandline::run_game_boy_from_subcommand(matches,
+                                                           &settings.game_boy);
         }
         Some((s, _)) => {
-            eprintln!("Unknown emulator: {}", s);
+            log::error!("Unknown emulator: {}", s);
         }
         None => {
-            eprintln!("Missing emulator argument.");
+            log::error!("Missing emulator argument.");
         }
     }
 }
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Persistent settings of all emulators
+///
+/// Settings are stored as a TOML file with one table per emulator.
+/// Missing entries take their default values, so a settings file
+/// only needs to contain the settings that differ from the defaults:
+///
+/// ```toml
+/// [gameboy]
+/// boot_rom = "/path/to/dmg_boot.bin"
+/// palette = [0xE0F8D0, 0x88C070, 0x346856, 0x081820]
+///
+/// [gameboy.key_bindings]
+/// a = "S"
+/// b = "A"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub chip8: Chip8Settings,
+    #[serde(rename = "gameboy")]
+    pub game_boy: GameBoySettings,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Chip8Settings {
+    /// Display dimensions, e.g. one of `chip8::AVAILABLE_DISPLAY_SIZES`
+    pub display: String,
+    /// Font name, one of `chip8::AVAILABLE_FONTS`
+    pub font: String,
+    /// Shift VX instead of VY in 8XY6 and 8XYE
+    pub shift_x: bool,
+    /// Connect a second keypad for two-player COSMAC VIP games
+    pub two_keypads: bool,
+    /// RGB colors of unset and set pixels
+    pub palette: [u32; 2],
+    /// Size of the window pixels showing a display pixel, by default the
+    /// largest that fits the screen, see `window_scale::default_scale`
+    pub scale: Option<usize>,
+}
+
+impl Default for Chip8Settings {
+    fn default() -> Self {
+        Self{
+            display: "64x32".to_string(),
+            font: "chip48".to_string(),
+            shift_x: false,
+            two_keypads: false,
+            palette: [0x000000, 0xFFFFFF],
+            scale: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameBoySettings {
+    /// Boot ROM to use instead of the built-in logo boot ROM
+    pub boot_rom: Option<PathBuf>,
+    /// RGB colors of the four shades from lightest to darkest
+    pub palette: [u32; 4],
+    /// Colorize games like a Game Boy Color instead of using `palette`
+    ///
+    /// Either "auto" to choose the palettes by cartridge title or one
+    /// of the names in `colorization::MANUAL_PALETTES`.
+    pub colorization: Option<String>,
+    /// Size of the window pixels showing a screen pixel, by default the
+    /// largest that fits the screen, see `window_scale::default_scale`
+    pub scale: Option<usize>,
+    /// Blend each frame with the one before like the DMG's slow LCD
+    pub ghosting: bool,
+    /// Milliseconds of audio that are queued before they are played
+    ///
+    /// A higher latency avoids crackling sound on slow machines.
+    pub audio_latency_ms: u32,
+    /// Frames that the sound device plays per buffer, by default chosen
+    /// by the sound device
+    pub audio_buffer_size: Option<u32>,
+    pub key_bindings: GameBoyKeyBindings,
+}
+
+impl Default for GameBoySettings {
+    fn default() -> Self {
+        Self{
+            boot_rom: None,
+            palette: [0xFFFFFF, 0x808080, 0x404040, 0x000000],
+            colorization: None,
+            scale: None,
+            ghosting: false,
+            audio_latency_ms: 50,
+            audio_buffer_size: None,
+            key_bindings: GameBoyKeyBindings::default(),
+        }
+    }
+}
+
+/// Keyboard keys of the Game Boy's buttons
+///
+/// The tilt keys tilt the Game Boy for cartridges with an accelerometer,
+/// like Kirby Tilt 'n' Tumble.
+///
+/// Keys are named like the variants of `minifb::Key`, e.g. "A", "Key1",
+/// "Space" or "LeftShift".
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameBoyKeyBindings {
+    pub right: String,
+    pub left: String,
+    pub up: String,
+    pub down: String,
+    pub a: String,
+    pub b: String,
+    pub select: String,
+    pub start: String,
+    pub tilt_right: String,
+    pub tilt_left: String,
+    /// Tilt the top of the Game Boy away from the player
+    pub tilt_up: String,
+    /// Tilt the top of the Game Boy towards the player
+    pub tilt_down: String,
+}
+
+impl Default for GameBoyKeyBindings {
+    fn default() -> Self {
+        Self{
+            right: "Right".to_string(),
+            left: "Left".to_string(),
+            up: "Up".to_string(),
+            down: "Down".to_string(),
+            a: "X".to_string(),
+            b: "Z".to_string(),
+            select: "Q".to_string(),
+            start: "W".to_string(),
+            tilt_right: "L".to_string(),
+            tilt_left: "J".to_string(),
+            tilt_up: "I".to_string(),
+            tilt_down: "K".to_string(),
+        }
+    }
+}
+
+impl GameBoyKeyBindings {
+    /// Key names in the bit order of `IO::get_key_presses`
+    pub fn in_joypad_order(&self) -> [&str; 8] {
+        [
+            &self.right,
+            &self.left,
+            &self.up,
+            &self.down,
+            &self.a,
+            &self.b,
+            &self.select,
+            &self.start,
+        ]
+    }
+
+    /// Key names of tilting right, left, up and down
+    pub fn tilt_keys(&self) -> [&str; 4] {
+        [
+            &self.tilt_right,
+            &self.tilt_left,
+            &self.tilt_up,
+            &self.tilt_down,
+        ]
+    }
+}
+
+impl Settings {
+    /// Load settings from a TOML file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Save settings to a TOML file, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(path, contents)
+    }
+
+    /// Location of the user's settings file
+    ///
+    /// This is `emulato-rs/settings.toml` in `$XDG_CONFIG_HOME`,
+    /// `$HOME/.config` or `%APPDATA%`, whichever is found first.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME")
+                         .map(|home| Path::new(&home).join(".config")))
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+        Some(config_dir.join("emulato-rs").join("settings.toml"))
+    }
+
+    /// Load settings from the default location
+    ///
+    /// If there is no settings file yet, default settings are returned.
+    pub fn load_from_default_path() -> io::Result<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_survive_a_round_trip() {
+        let mut settings = Settings::default();
+        settings.chip8.two_keypads = true;
+        settings.chip8.scale = Some(12);
+        settings.game_boy.boot_rom = Some(PathBuf::from("/roms/dmg.bin"));
+        settings.game_boy.palette = [0xE0F8D0, 0x88C070, 0x346856, 0x081820];
+        settings.game_boy.colorization = Some("auto".to_string());
+        settings.game_boy.audio_buffer_size = Some(512);
+        settings.game_boy.key_bindings.a = "S".to_string();
+        // The file is created in a new directory.
+        let directory = std::env::temp_dir().join("emulato-rs-settings");
+        let _ = fs::remove_dir_all(&directory);
+        let path = directory.join("settings.toml");
+        settings.save(&path).unwrap();
+        assert_eq!(Settings::load(&path).unwrap(), settings);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let settings: Settings = toml::from_str("").unwrap();
+        assert_eq!(settings, Settings::default());
+
+        let settings: Settings = toml::from_str(
+            "[gameboy]\n\
+             audio_latency_ms = 100\n\
+             \n\
+             [gameboy.key_bindings]\n\
+             b = \"A\"\n").unwrap();
+        let mut expected = Settings::default();
+        expected.game_boy.audio_latency_ms = 100;
+        expected.game_boy.key_bindings.b = "A".to_string();
+        assert_eq!(settings, expected);
+    }
+
+    #[test]
+    fn invalid_settings_are_an_error() {
+        let path = std::env::temp_dir().join("emulato-rs-invalid.toml");
+        fs::write(&path, "[gameboy]\nscale = \"big\"\n").unwrap();
+        let error = Settings::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
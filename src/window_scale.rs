@@ -0,0 +1,81 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/// Screen size that the default scale of a window has to fit into
+///
+/// minifb can't tell the size of the monitor on every platform, so the
+/// default assumes a small laptop screen with room for the window
+/// decorations and the task bar.
+pub const DEFAULT_SCREEN_SIZE: (usize, usize) = (1280, 720);
+
+/// Largest scale that any window can be opened with
+pub const MAX_SCALE: usize = 16;
+
+/// Largest integer scale at which a window showing `width` x `height`
+/// pixels fits on the `DEFAULT_SCREEN_SIZE`, but at least 1
+pub fn default_scale(width: usize, height: usize) -> usize {
+    let (screen_width, screen_height) = DEFAULT_SCREEN_SIZE;
+    (screen_width / width).min(screen_height / height).clamp(1, MAX_SCALE)
+}
+
+/// Parse the scale of a window, a number between 1 and `MAX_SCALE`
+pub fn parse_scale(scale: &str) -> Result<usize, String> {
+    let value: usize = scale.parse().map_err(|_| {
+        format!("Scale {} is not a number.", scale)
+    })?;
+    check_scale(value)?;
+    Ok(value)
+}
+
+pub fn check_scale(scale: usize) -> Result<(), String> {
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(format!("Scale {} is not between 1 and {}.", scale,
+                           MAX_SCALE));
+    }
+    Ok(())
+}
+
+/// Scale from the command line, the settings or the default for the
+/// given size, in this order of precedence
+///
+/// Command line arguments have been validated by clap, so an invalid
+/// scale can only come from the settings, in which case it is ignored.
+pub fn choose_scale(argument: Option<&str>, setting: Option<usize>,
+                    width: usize, height: usize) -> usize {
+    if let Some(scale) = argument {
+        return parse_scale(scale).unwrap();
+    }
+    match setting.map(|scale| check_scale(scale).map(|_| scale)) {
+        Some(Ok(scale)) => scale,
+        Some(Err(e)) => {
+            log::warn!("{} Using the default scale.", e);
+            default_scale(width, height)
+        }
+        None => default_scale(width, height),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_scale_fits_the_screen() {
+        assert_eq!(default_scale(160, 144), 5);
+        assert_eq!(default_scale(256, 224), 3);
+        assert_eq!(default_scale(64, 32), 16);
+        assert_eq!(default_scale(128, 64), 10);
+        assert_eq!(default_scale(2000, 2000), 1);
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_settings() {
+        assert_eq!(choose_scale(Some("2"), Some(3), 160, 144), 2);
+        assert_eq!(choose_scale(None, Some(3), 160, 144), 3);
+        assert_eq!(choose_scale(None, Some(0), 160, 144), 5);
+        assert_eq!(choose_scale(None, None, 160, 144), 5);
+        assert!(parse_scale("17").is_err());
+        assert!(parse_scale("x").is_err());
+    }
+}
@@ -9,7 +9,6 @@ use emulato_rs::game_boy;
 use game_boy::io::{HEIGHT, WIDTH};
 
 const MOONEYE_DIR: &'static str = "/home/felix/games/roms/gameboy/test_roms/mooneye";
-const TEST_OK: &'static str = "88413F205E3822FF44203C4338422049224124FF44214422463C4A224128FF44213E4330422049224138FF44214B202049224124FF44203C423843304A3822FF";
 const TEST_DE_OK: &'static str = "BFA1513C543822523E543822FF512253224124522053224124FF5122452048224128523C452048224128FF512253224138522053224138FF512253224124522053224124FF513C4520493822523E4520493822FF";
 const TEST_BCD_OK: &'static str = "BF99513C5438225338533822FF512253224124522253224124FF513C4520482241285120462048224128FF512253224138512054224138FF512253224124522253224124FF513C452049382253384420493822FF82513C543822FF512253224124FF5122452048224128FF512253224138FF512253224124FF513C4520493822FF";
 const TEST_CE_OK: &'static str = "BF997F4B38533822FF7F4A2253224124FF7F4920462048224128FF7F492054224138FF7F4A2253224124FF7F4B384420493822FF827F4A3E543822FF7F4A2053224124FF7F4A3C452048224128FF7F4A2053224138FF7F4A2053224124FF7F4A3E4520493822FF";
@@ -286,16 +285,18 @@ fn mooneye_test_rom(dir: &str) -> io::Result<File> {
     File::open(MOONEYE_DIR.to_owned() + dir)
 }
 
+/// Run one of mooneye's test ROMs and check that it passes, as told by
+/// the registers when it executes LD B,B
+fn run_mooneye_test_rom(rom: &str) {
+    let f = mooneye_test_rom(rom).unwrap();
+    let result = game_boy::compat::run_test_rom(f, 10_000, false).unwrap();
+    assert_eq!(result.outcome, Some(game_boy::serial::TestOutcome::Passed),
+               "after {} frames", result.frames);
+}
+
 #[test]
 fn mooneye_bits_mem_oam() {
-    let f = mooneye_test_rom("/acceptance/bits/mem_oam.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/bits/mem_oam.gb");
 }
 
 #[test]
@@ -306,34 +307,20 @@ fn mooneye_bits_reg_f() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
 #[test]
 fn mooneye_bits_unused_hwio_gs() {
-    let f = mooneye_test_rom("/acceptance/bits/unused_hwio-GS.gb").unwrap();
     // TODO: Not sure if this is correct, as this test even fails on Sameboy
     //       when writing to register 0xFF4F.
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/bits/unused_hwio-GS.gb");
 }
 
 #[test]
 fn mooneye_halt_ime0_ei() {
-    let f = mooneye_test_rom("/acceptance/halt_ime0_ei.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/halt_ime0_ei.gb");
 }
 
 #[test]
@@ -344,7 +331,7 @@ fn mooneye_halt_ime0_nointr_timing() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -356,7 +343,7 @@ fn mooneye_halt_ime1_timing() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -368,46 +355,23 @@ fn mooneye_halt_ime1_timing2_gs() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
 #[test]
 fn mooneye_instr_daa() {
-    let f = mooneye_test_rom("/acceptance/instr/daa.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/instr/daa.gb");
 }
 
 #[test]
 fn mooneye_interrupts_ie_push() {
-    let f = mooneye_test_rom("/acceptance/interrupts/ie_push.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/interrupts/ie_push.gb");
 }
 
 #[test]
 fn mooneye_oam_dma_restart() {
-    let f = mooneye_test_rom("/acceptance/oam_dma_restart.gb").unwrap();
-    const TEST_OK_FULL: &'static str = "88413C5520FF4122433C53384220413C4B38FF413C42214334442020423C4121432C4220FF4128423F42264520413042203F43324330FF41244220422245204320202043204520FF4122433C42344420384330413C42204238FF5520FF5238FF885330523043204F3E53384330FF533051244330502052224324FF52244420472145204F3C452046204321FF523C512145204F2051204321FF5121532444204F2052224324FF51214520493042385020452048384330FF82513C543042305138513E423EFF512253244224512252204220FF513C45204721422151204620473C423CFF512252214221512053204220FF512253244224512252204220FF513C4520493042305138442047204220FF82513C543043204F3E5430413CFF512253244330502053244121FF51224520472145204F3C45204721433CFF5122522145204F2052214221FF5122532444204F2053244121FF513C452049304238503E45204930413CFF825121523E423E522054304230FF512152204220522053244224FF513F4520473C423C5220452047214221FF512152204220522052214221FF512152204220522053244224FF512145204720423E523F452049304230FF8A43306B20FF433042384238413C4B205A38FF4224422042204121432C423C44203C422E4220FF423C433042303F4332432043214423422120FF412146204222472043204321442242204120FF412143384238423C4220433043203C42224238FF927F4B38533822FF7F4A2253224124FF7F4920462048224128FF7F492054224138FF7F4A2253224124FF7F4B384420493822FF82513C543822FF512253224124FF5122452048224128FF512253224138FF512253224124FF513C4520493822FF";
-    let window = TestEmulatorWindow::with_reference_and_min_frames(
-        TEST_OK_FULL, 12);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/oam_dma_restart.gb");
 }
 
 #[test]
@@ -418,58 +382,28 @@ fn mooneye_oam_dma_start() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
 #[test]
 fn mooneye_oam_dma_timing() {
-    let f = mooneye_test_rom("/acceptance/oam_dma_timing.gb").unwrap();
-    const TEST_OK_FULL: &'static str = "88413C5520FF4122433C53384220413C4B38FF413C42214334442020423C4121432C4220FF4128423F42264520413042203F43324330FF41244220422245204320202043204520FF4122433C42344420384330413C42204238FF5520FF5238FF885330523043204F3E53384330FF533051244330502052224324FF52244420472145204F3C452046204321FF523C512145204F2051204321FF5121532444204F2052224324FF51214520493042385020452048384330FF82513C543042305138513E423EFF512253244224512252204220FF513C45204721422151204620473C423CFF512252214221512053204220FF512253244224512252204220FF513C4520493042305138442047204220FF82513C543043204F3E5430413CFF512253244330502053244121FF51224520472145204F3C45204721433CFF5122522145204F2052214221FF5122532444204F2053244121FF513C452049304238503E45204930413CFF825121523E423E522054304230FF512152204220522053244224FF513F4520473C423C5220452047214221FF512152204220522052214221FF512152204220522053244224FF512145204720423E523F452049304230FF8A43306B20FF433042384238413C4B205A38FF4224422042204121432C423C44203C422E4220FF423C433042303F4332432043214423422120FF412146204222472043204321442242204120FF412143384238423C4220433043203C42224238FF927F4B38533822FF7F4A2253224124FF7F4920462048224128FF7F492054224138FF7F4A2253224124FF7F4B384420493822FF82513C543822FF512253224124FF5122452048224128FF512253224138FF512253224124FF513C4520493822FF";
-    let window = TestEmulatorWindow::with_reference_and_min_frames(
-        TEST_OK_FULL, 12);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/oam_dma_timing.gb");
 }
 
 #[test]
 fn mooneye_oam_dma_basic() {
-    let f = mooneye_test_rom("/acceptance/oam_dma/basic.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/oam_dma/basic.gb");
 }
 
 #[test]
 fn mooneye_oam_dma_reg_read() {
-    let f = mooneye_test_rom("/acceptance/oam_dma/reg_read.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/oam_dma/reg_read.gb");
 }
 
 #[test]
 fn mooneye_oam_dma_sources_gs() {
-    let f = mooneye_test_rom("/acceptance/oam_dma/sources-GS.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/oam_dma/sources-GS.gb");
 }
 
 #[test]
@@ -480,20 +414,13 @@ fn mooneye_div_timing() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
 #[test]
 fn mooneye_timer_div_write() {
-    let f = mooneye_test_rom("/acceptance/timer/div_write.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/acceptance/timer/div_write.gb");
 }
 
 #[test]
@@ -505,7 +432,7 @@ fn mooneye_timer_rapid_toggle() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -517,7 +444,7 @@ fn mooneye_timer_tim00() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -529,7 +456,7 @@ fn mooneye_timer_tim00_div_trigger() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -541,7 +468,7 @@ fn mooneye_timer_tim01() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -553,7 +480,7 @@ fn mooneye_timer_tim01_div_trigger() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -565,7 +492,7 @@ fn mooneye_timer_tim10() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -577,7 +504,7 @@ fn mooneye_timer_tim10_div_trigger() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -589,7 +516,7 @@ fn mooneye_timer_tim11() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -601,7 +528,7 @@ fn mooneye_timer_tim11_div_trigger() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -613,7 +540,7 @@ fn mooneye_timer_tima_reload() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -625,7 +552,7 @@ fn mooneye_timer_tima_write_reloading() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
@@ -637,342 +564,167 @@ fn mooneye_timer_tma_write_reloading() {
         .use_emulator_window(window)
         .use_fast_boot_rom()
         .load_cartridge(f).unwrap()
-        .build();
+        .build().unwrap();
     gameboy.run();
 }
 
 #[test]
 fn mooneye_mbc1_bits_bank1() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/bits_bank1.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/bits_bank1.gb");
 }
 
 #[test]
 fn mooneye_mbc1_bits_bank2() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/bits_bank2.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/bits_bank2.gb");
 }
 
 #[test]
 fn mooneye_mbc1_bits_mode() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/bits_mode.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/bits_mode.gb");
 }
 
 #[test]
 fn mooneye_mbc1_bits_ramg() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/bits_ramg.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/bits_ramg.gb");
 }
 
 #[test]
 fn mooneye_mbc1_multicart_rom_8mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/multicart_rom_8Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/multicart_rom_8Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_ram_64kb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/ram_64kb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference_and_min_frames(TEST_OK, 55);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/ram_64kb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_ram_256kb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/ram_256kb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/ram_256kb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_512kb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_512kb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_512kb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_1mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_1Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_1Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_2mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_2Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_2Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_4mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_4Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_4Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_8mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_8Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_8Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc1_rom_16mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc1/rom_16Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc1/rom_16Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc2_bits_ramg() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/bits_ramg.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/bits_ramg.gb");
 }
 
 #[test]
 fn mooneye_mbc2_bits_romb() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/bits_romb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/bits_romb.gb");
 }
 
 #[test]
 fn mooneye_mbc2_bits_unused() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/bits_unused.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/bits_unused.gb");
 }
 
 #[test]
 fn mooneye_mbc2_ram() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/ram.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/ram.gb");
 }
 
 #[test]
 fn mooneye_mbc2_rom_512kb() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/rom_512kb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/rom_512kb.gb");
 }
 
 #[test]
 fn mooneye_mbc2_rom_1mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/rom_1Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/rom_1Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc2_rom_2mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc2/rom_2Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc2/rom_2Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_512kb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_512kb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_512kb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_1mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_1Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_1Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_2mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_2Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_2Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_4mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_4Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_4Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_8mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_8Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_8Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_16mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_16Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_16Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_32mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_32Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_32Mb.gb");
 }
 
 #[test]
 fn mooneye_mbc5_rom_64mb() {
-    let f = mooneye_test_rom("/emulator-only/mbc5/rom_64Mb.gb").unwrap();
-    let window = TestEmulatorWindow::with_reference(TEST_OK);
-    let mut gameboy = game_boy::GameBoy::<TestEmulatorWindow>::builder()
-        .use_emulator_window(window)
-        .use_fast_boot_rom()
-        .load_cartridge(f).unwrap()
-        .build();
-    gameboy.run();
+    run_mooneye_test_rom("/emulator-only/mbc5/rom_64Mb.gb");
+}
+
+const BLARGG_DIR: &'static str = "/home/felix/games/roms/gameboy/test_roms/blargg";
+
+/// Run one of Blargg's test ROMs and check that it reports "Passed"
+fn run_blargg_test_rom(rom: &str) {
+    let f = File::open(BLARGG_DIR.to_owned() + rom).unwrap();
+    let result = game_boy::compat::run_test_rom(f, 10_000, false).unwrap();
+    println!("{}", result.output);
+    assert_eq!(result.outcome, Some(game_boy::serial::TestOutcome::Passed),
+               "after {} frames", result.frames);
+}
+
+#[test]
+fn blargg_cpu_instrs() {
+    run_blargg_test_rom("/cpu_instrs/cpu_instrs.gb");
+}
+
+#[test]
+fn blargg_instr_timing() {
+    run_blargg_test_rom("/instr_timing/instr_timing.gb");
 }
@@ -0,0 +1,281 @@
+// SPDX-FileCopyrightText: 2022 Felix Gruber
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Run single instructions against the SM83 JSON test vectors
+//!
+//! The vectors from https://github.com/SingleStepTests/sm83 give the
+//! registers and memory before and after an instruction and the memory
+//! accesses of each of its M-cycles.  Point `SM83_TEST_DIR` to the
+//! directory containing the JSON files to run them; they are skipped if
+//! the directory doesn't exist.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use emulato_rs::game_boy::cpu::{Bus, CpuState, CPU};
+use emulato_rs::game_boy::memory::InterruptAddress;
+
+const SM83_DIR: &str = "/home/felix/games/roms/gameboy/test_roms/sm83/v1";
+
+#[derive(Debug, Deserialize)]
+struct Sm83Test {
+    name: String,
+    initial: Sm83State,
+    #[serde(rename = "final")]
+    final_: Sm83State,
+    /// One entry per M-cycle: address, data and pins like "r-m" for a
+    /// read or "-wm" for a write; internal cycles have no data.
+    cycles: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sm83State {
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    sp: u16,
+    pc: u16,
+    ime: u8,
+    #[serde(default)]
+    ie: Option<u8>,
+    /// IME gets set after the next instruction.
+    #[serde(default)]
+    ei: Option<u8>,
+    ram: Vec<(u16, u8)>,
+}
+
+impl Sm83State {
+    fn cpu_state(&self) -> CpuState {
+        CpuState{
+            a: self.a,
+            f: self.f,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime != 0,
+            ime_scheduled: self.ei.unwrap_or(0) != 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// 64 KiB of RAM without any I/O registers that records all accesses
+struct FlatBus {
+    memory: Vec<u8>,
+    accesses: RefCell<Vec<Access>>,
+}
+
+impl FlatBus {
+    fn new() -> Self {
+        Self{
+            memory: vec![0; 0x10000],
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read8(&self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.accesses.borrow_mut().push(Access::Read(address, value));
+        value
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        self.accesses.borrow_mut().push(Access::Write(address, value));
+        self.memory[address as usize] = value;
+    }
+
+    fn step(&mut self, _cycles: usize) {}
+
+    fn get_requested_interrupts(&self) -> u8 {
+        self.memory[0xFF0F] & self.memory[0xFFFF] & 0x1F
+    }
+
+    fn handle_interrupts(&mut self) -> Option<InterruptAddress> {
+        let interrupts = self.get_requested_interrupts();
+        if interrupts == 0 {
+            return None;
+        }
+        let next_interrupt = interrupts.trailing_zeros();
+        self.memory[0xFF0F] ^= 1 << next_interrupt;
+        use InterruptAddress::*;
+        Some(match next_interrupt {
+            0 => VBLANK,
+            1 => LCD_STAT,
+            2 => TIMER,
+            3 => SERIAL,
+            _ => JOYPAD,
+        })
+    }
+}
+
+/// The reads and writes among the M-cycles of a test
+fn expected_accesses(cycles: &[Value]) -> Result<Vec<Access>, String> {
+    let mut accesses = Vec::new();
+    for cycle in cycles {
+        let (address, data, pins) = match cycle.as_array().map(Vec::as_slice) {
+            Some([address, data, pins]) => (address, data, pins),
+            _ => continue,
+        };
+        let pins = pins.as_str().unwrap_or("---");
+        let access = |value: &Value| value.as_u64()
+            .ok_or_else(|| format!("Malformed cycle {}.", cycle));
+        if pins.starts_with('r') {
+            accesses.push(Access::Read(access(address)? as u16,
+                                       access(data)? as u8));
+        } else if pins.get(1..2) == Some("w") {
+            accesses.push(Access::Write(access(address)? as u16,
+                                        access(data)? as u8));
+        }
+    }
+    Ok(accesses)
+}
+
+/// Run the instruction of a test and compare the outcome
+fn run_test(test: &Sm83Test) -> Result<(), String> {
+    let mut bus = FlatBus::new();
+    for &(address, value) in &test.initial.ram {
+        bus.memory[address as usize] = value;
+    }
+    if let Some(ie) = test.initial.ie {
+        bus.memory[0xFFFF] = ie;
+    }
+    let mut cpu = CPU::new();
+    cpu.set_state(&test.initial.cpu_state());
+    let cycles = cpu.step(&mut bus);
+
+    let mut expected_state = test.final_.cpu_state();
+    if test.final_.ei.is_none() {
+        // The vectors don't tell whether IME is about to be set.
+        expected_state.ime_scheduled = cpu.state().ime_scheduled;
+    }
+    if cpu.state() != expected_state {
+        return Err(format!("{}: registers are\n{:?}\ninstead of\n{:?}",
+                           test.name, cpu.state(), expected_state));
+    }
+    for &(address, value) in &test.final_.ram {
+        let actual = bus.memory[address as usize];
+        if actual != value {
+            return Err(format!("{}: {:0>4X} is {:0>2X} instead of {:0>2X}",
+                               test.name, address, actual, value));
+        }
+    }
+    let expected = expected_accesses(&test.cycles)?;
+    let accesses = bus.accesses.into_inner();
+    if accesses != expected {
+        return Err(format!("{}: memory accesses are\n{:X?}\ninstead of\n{:X?}",
+                           test.name, accesses, expected));
+    }
+    if cycles != 4 * test.cycles.len() {
+        return Err(format!("{}: took {} cycles instead of {}",
+                           test.name, cycles, 4 * test.cycles.len()));
+    }
+    Ok(())
+}
+
+/// Vectors in the format of the SM83 tests
+const SAMPLE_TESTS: &str = r#"[
+    {
+        "name": "00 NOP",
+        "initial": {"pc": 49152, "sp": 65534, "a": 1, "b": 2, "c": 3,
+                    "d": 4, "e": 5, "f": 176, "h": 6, "l": 7, "ime": 0,
+                    "ie": 0, "ram": [[49152, 0]]},
+        "final": {"pc": 49153, "sp": 65534, "a": 1, "b": 2, "c": 3,
+                  "d": 4, "e": 5, "f": 176, "h": 6, "l": 7, "ime": 0,
+                  "ram": [[49152, 0]]},
+        "cycles": [[49152, 0, "r-m"]]
+    },
+    {
+        "name": "E5 PUSH HL",
+        "initial": {"pc": 49152, "sp": 53248, "a": 0, "b": 0, "c": 0,
+                    "d": 0, "e": 0, "f": 0, "h": 18, "l": 52, "ime": 0,
+                    "ie": 0, "ram": [[49152, 229]]},
+        "final": {"pc": 49153, "sp": 53246, "a": 0, "b": 0, "c": 0,
+                  "d": 0, "e": 0, "f": 0, "h": 18, "l": 52, "ime": 0,
+                  "ram": [[49152, 229], [53246, 52], [53247, 18]]},
+        "cycles": [[49152, 229, "r-m"], [53248, null, "---"],
+                   [53247, 18, "-wm"], [53246, 52, "-wm"]]
+    },
+    {
+        "name": "CB 7E BIT 7,(HL)",
+        "initial": {"pc": 49152, "sp": 65534, "a": 0, "b": 0, "c": 0,
+                    "d": 0, "e": 0, "f": 16, "h": 192, "l": 16, "ime": 0,
+                    "ie": 0, "ram": [[49152, 203], [49153, 126],
+                                     [49168, 127]]},
+        "final": {"pc": 49154, "sp": 65534, "a": 0, "b": 0, "c": 0,
+                  "d": 0, "e": 0, "f": 176, "h": 192, "l": 16, "ime": 0,
+                  "ram": [[49168, 127]]},
+        "cycles": [[49152, 203, "r-m"], [49153, 126, "r-m"],
+                   [49168, 127, "r-m"]]
+    }
+]"#;
+
+#[test]
+fn sm83_sample_vectors_pass() {
+    let tests: Vec<Sm83Test> = serde_json::from_str(SAMPLE_TESTS).unwrap();
+    for test in &tests {
+        run_test(test).unwrap();
+    }
+}
+
+#[test]
+fn sm83_harness_detects_wrong_results() {
+    let mut tests: Vec<Sm83Test>
+        = serde_json::from_str(SAMPLE_TESTS).unwrap();
+    let push = &mut tests[1];
+    push.final_.ram[1].1 = 0x56;
+    assert!(run_test(push).is_err());
+    push.final_.ram[1].1 = 0x34;
+    push.cycles.swap(2, 3);
+    assert!(run_test(push).is_err());
+}
+
+#[test]
+fn sm83_test_vectors() {
+    let dir = std::env::var("SM83_TEST_DIR")
+                       .unwrap_or_else(|_| SM83_DIR.to_string());
+    let dir = Path::new(&dir);
+    if !dir.is_dir() {
+        eprintln!("Skipping SM83 tests, {} not found.", dir.display());
+        return;
+    }
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    // Report the first failing test of each opcode.
+    let mut failures = Vec::new();
+    for path in &paths {
+        let reader = BufReader::new(File::open(path).unwrap());
+        let tests: Vec<Sm83Test> = serde_json::from_reader(reader).unwrap();
+        if let Some(failure) = tests.iter().find_map(|t| run_test(t).err()) {
+            failures.push(failure);
+        }
+    }
+    assert!(failures.is_empty(),
+            "{} of {} opcodes failed:\n{}",
+            failures.len(), paths.len(), failures.join("\n"));
+}